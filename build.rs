@@ -0,0 +1,50 @@
+//! Compiles the C shim of the `dpdk` feature, `csrc/dpdk_shim.c`, against the DPDK pkg-config finds,
+//! and links DPDK. Nothing to do without the feature.
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "dpdk")]
+    dpdk();
+}
+
+#[cfg(feature = "dpdk")]
+fn dpdk() {
+    use std::process::Command;
+
+    println!("cargo:rerun-if-changed=csrc/dpdk_shim.c");
+    println!("cargo:rerun-if-env-changed=PKG_CONFIG_PATH");
+
+    let pkg_config = |flag: &str| {
+        let output = Command::new("pkg-config")
+            .args([flag, "libdpdk"])
+            .output()
+            .expect("the dpdk feature runs pkg-config to find DPDK");
+        if !output.status.success() {
+            panic!(
+                "the dpdk feature needs DPDK, pkg-config didn't find libdpdk: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        String::from_utf8(output.stdout).expect("pkg-config output is utf-8")
+    };
+
+    let mut build = cc::Build::new();
+    build.file("csrc/dpdk_shim.c");
+    for flag in pkg_config("--cflags").split_whitespace() {
+        match flag.strip_prefix("-I") {
+            Some(dir) => build.include(dir),
+            None => build.flag(flag),
+        };
+    }
+    build.compile("mt_dpdk_shim");
+
+    for flag in pkg_config("--libs").split_whitespace() {
+        if let Some(dir) = flag.strip_prefix("-L") {
+            println!("cargo:rustc-link-search=native={dir:}");
+        } else if let Some(lib) = flag.strip_prefix("-l") {
+            println!("cargo:rustc-link-lib={lib:}");
+        } else {
+            println!("cargo:rustc-link-arg={flag:}");
+        }
+    }
+}
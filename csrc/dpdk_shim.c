@@ -0,0 +1,91 @@
+/*
+ * The DPDK calls of the `dpdk` feature. The burst functions and the mbuf accessors are inline in DPDK's
+ * headers, the Rust side calls them through these.
+ */
+#include <string.h>
+
+#include <rte_eal.h>
+#include <rte_ethdev.h>
+#include <rte_mbuf.h>
+
+int mt_dpdk_init(int argc, char **argv)
+{
+	return rte_eal_init(argc, argv);
+}
+
+struct rte_mempool *mt_dpdk_pool(const char *name, unsigned int mbufs)
+{
+	return rte_pktmbuf_pool_create(name, mbufs, 256, 0, RTE_MBUF_DEFAULT_BUF_SIZE, rte_socket_id());
+}
+
+/* Sets the port up with a rx and a tx queue and starts it, returns a negated errno on failure */
+int mt_dpdk_port_start(uint16_t port, struct rte_mempool *pool, uint16_t rx_desc, uint16_t tx_desc)
+{
+	struct rte_eth_conf conf = {0};
+	int ret = rte_eth_dev_configure(port, 1, 1, &conf);
+	if (ret < 0)
+		return ret;
+	ret = rte_eth_dev_adjust_nb_rx_tx_desc(port, &rx_desc, &tx_desc);
+	if (ret < 0)
+		return ret;
+	int socket = rte_eth_dev_socket_id(port);
+	ret = rte_eth_rx_queue_setup(port, 0, rx_desc, socket, NULL, pool);
+	if (ret < 0)
+		return ret;
+	ret = rte_eth_tx_queue_setup(port, 0, tx_desc, socket, NULL);
+	if (ret < 0)
+		return ret;
+	return rte_eth_dev_start(port);
+}
+
+void mt_dpdk_port_stop(uint16_t port)
+{
+	rte_eth_dev_stop(port);
+	rte_eth_dev_close(port);
+}
+
+int mt_dpdk_mac(uint16_t port, uint8_t mac[6])
+{
+	struct rte_ether_addr addr;
+	int ret = rte_eth_macaddr_get(port, &addr);
+	if (ret == 0)
+		memcpy(mac, addr.addr_bytes, 6);
+	return ret;
+}
+
+uint16_t mt_dpdk_rx(uint16_t port, struct rte_mbuf **mbufs, uint16_t n)
+{
+	return rte_eth_rx_burst(port, 0, mbufs, n);
+}
+
+uint16_t mt_dpdk_tx(uint16_t port, struct rte_mbuf **mbufs, uint16_t n)
+{
+	return rte_eth_tx_burst(port, 0, mbufs, n);
+}
+
+/* The data of the first segment of the mbuf, its length in `len` */
+const uint8_t *mt_dpdk_data(const struct rte_mbuf *mbuf, uint16_t *len)
+{
+	*len = rte_pktmbuf_data_len(mbuf);
+	return rte_pktmbuf_mtod(mbuf, const uint8_t *);
+}
+
+/* An mbuf holding a copy of `len` octets of `data`, NULL when the pool is empty */
+struct rte_mbuf *mt_dpdk_copy(struct rte_mempool *pool, const uint8_t *data, uint16_t len)
+{
+	struct rte_mbuf *mbuf = rte_pktmbuf_alloc(pool);
+	if (mbuf == NULL)
+		return NULL;
+	char *dst = rte_pktmbuf_append(mbuf, len);
+	if (dst == NULL) {
+		rte_pktmbuf_free(mbuf);
+		return NULL;
+	}
+	memcpy(dst, data, len);
+	return mbuf;
+}
+
+void mt_dpdk_free(struct rte_mbuf *mbuf)
+{
+	rte_pktmbuf_free(mbuf);
+}
@@ -0,0 +1,76 @@
+//! Shared by the `send-file` and `recv-file` examples: the integrity hash and the progress display.
+//!
+//! The transfer is framed as the file length (8 octets, big endian), the file, then its FNV-1a hash
+//! (8 octets, big endian), so the receiver knows where the file ends without connection close.
+
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+/// How often the progress line is redrawn
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The 64 bit FNV-1a hash, good enough to catch corruption, not tampering.
+pub struct Fnv64(u64);
+
+impl Fnv64 {
+    pub fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// A progress line on stderr with the throughput so far.
+pub struct Progress {
+    total: u64,
+    done: u64,
+    start: Instant,
+    drawn: Instant,
+}
+
+impl Progress {
+    pub fn new(total: u64) -> Self {
+        let now = Instant::now();
+        Self {
+            total,
+            done: 0,
+            start: now,
+            drawn: now,
+        }
+    }
+
+    pub fn add(&mut self, n: usize) {
+        self.done += n as u64;
+        if self.drawn.elapsed() >= PROGRESS_INTERVAL || self.done == self.total {
+            self.drawn = Instant::now();
+            self.draw();
+        }
+    }
+
+    /// Ends the progress line, returns the time taken.
+    pub fn finish(&self) -> Duration {
+        self.draw();
+        eprintln!();
+        self.start.elapsed()
+    }
+
+    fn draw(&self) {
+        let percent = (self.done * 100).checked_div(self.total).unwrap_or(100);
+        let secs = self.start.elapsed().as_secs_f64().max(f64::EPSILON);
+        let mbps = self.done as f64 / secs / (1 << 20) as f64;
+        eprint!(
+            "\r{:} / {:} bytes ({percent:}%), {mbps:.2} MiB/s",
+            self.done, self.total
+        );
+        let _ = std::io::stderr().flush();
+    }
+}
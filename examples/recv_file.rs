@@ -0,0 +1,163 @@
+//! Receives a single file sent by `send-file` through mini-tcp, verifies its hash and reports the
+//! throughput. It runs its own event loop on the tun device, so the `mini-tcp` binary must not be
+//! running; the device is set up with `run.sh` like for the binary:
+//!     cargo run --release --example recv-file -- <path>
+//!
+//! Only ACKs are ever sent, nothing is retransmitted, so the loop blocks on the nic without timers.
+
+mod common;
+
+use anyhow::{anyhow, Result};
+use common::{Fnv64, Progress};
+use mini_tcp::builder::DEFAULT_DEVICE;
+use mini_tcp::stats::Stats;
+use mini_tcp::tcp::state::{Established, SynRecv};
+use mini_tcp::tcp::{nic_mss, Connection, ConnectionID, Tunables};
+use mini_tcp::wire::SegmentView;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+enum Peer {
+    Handshake(Connection<SynRecv>),
+    Established(Connection<Established>),
+}
+
+/// Splits the framed stream back into the length, the file and the hash, see `common`.
+struct Receiver {
+    file: BufWriter<File>,
+    /// The length header or the hash trailer, while incomplete
+    frame: Vec<u8>,
+    /// The file length once the header is complete, with the octets left to receive
+    remaining: Option<u64>,
+    hash: Fnv64,
+    progress: Option<Progress>,
+}
+
+impl Receiver {
+    fn new(file: File) -> Self {
+        Self {
+            file: BufWriter::new(file),
+            frame: Vec::with_capacity(8),
+            remaining: None,
+            hash: Fnv64::new(),
+            progress: None,
+        }
+    }
+
+    /// Consumes the data received, returns whether the whole file and its hash arrived.
+    fn on_data(&mut self, mut data: &[u8]) -> Result<bool> {
+        while !data.is_empty() {
+            match self.remaining {
+                Some(0) | None => {
+                    let n = (8 - self.frame.len()).min(data.len());
+                    self.frame.extend_from_slice(&data[..n]);
+                    data = &data[n..];
+                    if self.frame.len() < 8 {
+                        continue;
+                    }
+                    let value = u64::from_be_bytes(self.frame[..].try_into()?);
+                    self.frame.clear();
+                    if self.remaining.is_some() {
+                        self.verify(value)?;
+                        return Ok(true);
+                    }
+                    eprintln!("receiving {value:} bytes");
+                    self.remaining = Some(value);
+                    self.progress = Some(Progress::new(value));
+                }
+                Some(remaining) => {
+                    let n = (remaining as usize).min(data.len());
+                    self.file.write_all(&data[..n])?;
+                    self.hash.update(&data[..n]);
+                    if let Some(progress) = self.progress.as_mut() {
+                        progress.add(n);
+                    }
+                    self.remaining = Some(remaining - n as u64);
+                    data = &data[n..];
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    fn verify(&mut self, expected: u64) -> Result<()> {
+        self.file.flush()?;
+        let elapsed = self
+            .progress
+            .as_ref()
+            .map(|p| p.finish())
+            .unwrap_or_default();
+        let hash = self.hash.finish();
+        if hash != expected {
+            return Err(anyhow!(
+                "hash mismatch, received {hash:016x} but {expected:016x} was sent"
+            ));
+        }
+        eprintln!("received in {elapsed:?}, hash {hash:016x} verified");
+        Ok(())
+    }
+}
+
+fn main() -> Result<()> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("warn"));
+    let path = std::env::args()
+        .nth(1)
+        .ok_or_else(|| anyhow!("usage: recv-file <path>"))?;
+
+    let nic = tun_tap::Iface::without_packet_info(DEFAULT_DEVICE, tun_tap::Mode::Tun)?;
+    let tunables = Tunables {
+        mss: nic_mss(&nic)?,
+        ..Default::default()
+    };
+    let mut stats = Stats::default();
+    let mut receiver = Receiver::new(File::create(&path)?);
+    let mut peer: Option<(ConnectionID, Peer)> = None;
+
+    let mut buf = [0u8; 1500];
+    let mut data = vec![0u8; 64 << 10];
+    loop {
+        let nbytes = nic.recv(&mut buf)?;
+        let seg = match SegmentView::parse(&buf[..nbytes]) {
+            Ok(seg) => seg,
+            Err(_) => continue,
+        };
+        let id = seg.id();
+
+        peer = match peer.take() {
+            None if seg.tcp.syn() => {
+                let conn = Connection::new(seg).syn_ack(&nic, &tunables, &mut stats)?;
+                Some((id, Peer::Handshake(conn)))
+            }
+            None => None,
+            Some((expected, conn)) if expected != id => Some((expected, conn)),
+            Some((id, Peer::Handshake(conn))) => {
+                let mut conn = conn.check_ack(&nic, &seg)?;
+                conn.on_segment(&seg, &mut stats)?;
+                Some((id, Peer::Established(conn)))
+            }
+            Some((id, Peer::Established(mut conn))) => {
+                conn.on_segment(&seg, &mut stats)?;
+                Some((id, Peer::Established(conn)))
+            }
+        };
+
+        if let Some((_, Peer::Established(conn))) = peer.as_mut() {
+            let mut done = false;
+            loop {
+                let n = conn.read(&mut data);
+                if n == 0 {
+                    break;
+                }
+                done |= receiver.on_data(&data[..n])?;
+            }
+            conn.transmit(&nic)?;
+            if done {
+                eprintln!(
+                    "written to {path:}, stats: {:}",
+                    stats.diff(&Stats::default())
+                );
+                return Ok(());
+            }
+        }
+    }
+}
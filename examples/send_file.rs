@@ -0,0 +1,46 @@
+//! Sends a file to `recv-file` through the kernel tcp stack, i.e. to mini-tcp on the other end of the
+//! tun device:
+//!     cargo run --release --example send-file -- <path> [192.167.1.1:80]
+
+mod common;
+
+use anyhow::{anyhow, Result};
+use common::{Fnv64, Progress};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+const DEFAULT_ADDR: &str = "192.167.1.1:80";
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let path = args
+        .next()
+        .ok_or_else(|| anyhow!("usage: send-file <path> [addr]"))?;
+    let addr = args.next().unwrap_or_else(|| DEFAULT_ADDR.to_string());
+
+    let mut file = File::open(&path)?;
+    let len = file.metadata()?.len();
+    let mut stream = TcpStream::connect(&addr)?;
+    eprintln!("sending {path:} ({len:} bytes) to {addr:}");
+
+    stream.write_all(&len.to_be_bytes())?;
+    let mut hash = Fnv64::new();
+    let mut progress = Progress::new(len);
+    let mut buf = vec![0u8; 64 << 10];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hash.update(&buf[..n]);
+        stream.write_all(&buf[..n])?;
+        progress.add(n);
+    }
+    stream.write_all(&hash.finish().to_be_bytes())?;
+    stream.flush()?;
+
+    let elapsed = progress.finish();
+    eprintln!("sent in {elapsed:?}, hash {:016x}", hash.finish());
+    Ok(())
+}
@@ -0,0 +1,103 @@
+//! Compares the syscalls spent reading packets with poll and read, as the main loop does, and with
+//! io_uring, as with `--io-uring`. A unix datagram socket stands in for the tun device, it keeps the
+//! packet boundaries as well:
+//!     cargo run --release --features io-uring --example uring-bench -- [packets]
+
+use anyhow::{anyhow, Result};
+use mini_tcp::uring::Uring;
+use std::os::unix::io::RawFd;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const PACKET: usize = 1400;
+const READS: usize = 32;
+const TIMEOUT: u64 = u64::MAX;
+
+fn main() -> Result<()> {
+    let packets: usize = match std::env::args().nth(1) {
+        Some(n) => n.parse()?,
+        None => 200_000,
+    };
+    let (syscalls, elapsed) = measure(packets, poll_read)?;
+    report("poll + read", packets, syscalls, elapsed);
+    let (syscalls, elapsed) = measure(packets, uring)?;
+    report("io_uring", packets, syscalls, elapsed);
+    Ok(())
+}
+
+fn report(name: &str, packets: usize, syscalls: u64, elapsed: Duration) {
+    println!(
+        "{name:>12}: {syscalls:>8} syscalls for {packets:} packets, {:.2} per packet, {:.0} packets/s",
+        syscalls as f64 / packets as f64,
+        packets as f64 / elapsed.as_secs_f64()
+    );
+}
+
+/// Sends `packets` datagrams from another thread and reads them with `read`, which returns the syscalls
+/// it made.
+fn measure(packets: usize, read: fn(RawFd, usize) -> Result<u64>) -> Result<(u64, Duration)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::socketpair(libc::AF_UNIX, libc::SOCK_DGRAM, 0, fds.as_mut_ptr()) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let [rx, tx] = fds;
+    let sender = thread::spawn(move || {
+        let packet = [7u8; PACKET];
+        for _ in 0..packets {
+            unsafe { libc::send(tx, packet.as_ptr().cast(), PACKET, 0) };
+        }
+    });
+    let start = Instant::now();
+    let syscalls = read(rx, packets);
+    let elapsed = start.elapsed();
+    sender.join().map_err(|_| anyhow!("the sender panicked"))?;
+    unsafe {
+        libc::close(rx);
+        libc::close(tx);
+    }
+    Ok((syscalls?, elapsed))
+}
+
+fn poll_read(fd: RawFd, packets: usize) -> Result<u64> {
+    let mut buf = [0u8; 1500];
+    let mut syscalls = 0;
+    for _ in 0..packets {
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut pollfd, 1, 100) };
+        let n = unsafe { libc::read(fd, buf.as_mut_ptr().cast(), buf.len()) };
+        syscalls += 2;
+        if n < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    Ok(syscalls)
+}
+
+fn uring(fd: RawFd, packets: usize) -> Result<u64> {
+    let mut bufs = vec![[0u8; 1500]; READS];
+    let mut ring = Uring::new(2 * READS as u32)?;
+    for (i, buf) in bufs.iter_mut().enumerate() {
+        unsafe { ring.read(fd, buf, i as u64)? };
+    }
+    let mut received = 0;
+    while received < packets {
+        ring.timeout(Duration::from_millis(100), TIMEOUT)?;
+        ring.submit_and_wait(1)?;
+        for completion in ring.completions() {
+            if completion.user_data == TIMEOUT {
+                continue;
+            }
+            if completion.res < 0 {
+                return Err(std::io::Error::from_raw_os_error(-completion.res).into());
+            }
+            received += 1;
+            let i = completion.user_data as usize;
+            unsafe { ring.read(fd, &mut bufs[i], i as u64)? };
+        }
+    }
+    Ok(ring.enters())
+}
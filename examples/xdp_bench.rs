@@ -0,0 +1,72 @@
+//! Compares the syscalls spent sending packets through the tun device, a write each, as the stack does
+//! on it, and through an AF_XDP socket, a wakeup of the driver per batch. Takes root, and a NIC the
+//! stack runs on as `addr`, one end of a veth pair does:
+//!     cargo run --release --features af-xdp --example xdp-bench -- <nic> <addr> [packets]
+//! The packets are UDP datagrams broadcast to the discard port, sent without resolving a peer.
+
+use anyhow::{anyhow, Result};
+use etherparse::PacketBuilder;
+use mini_tcp::device::{NetworkDevice, BATCH_SIZE};
+use mini_tcp::TcpStackBuilder;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+const PAYLOAD: usize = 1400;
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let usage = || anyhow!("usage: xdp-bench <nic> <addr> [packets]");
+    let nic = args.next().ok_or_else(usage)?;
+    let addr: Ipv4Addr = args.next().ok_or_else(usage)?.parse()?;
+    let packets: usize = match args.next() {
+        Some(n) => n.parse()?,
+        None => 200_000,
+    };
+
+    let (tun, _) = TcpStackBuilder::new()
+        .device("mini-tcp-bench")
+        .address(
+            Ipv4Addr::new(10, 254, 0, 1),
+            Ipv4Addr::new(255, 255, 255, 0),
+        )
+        .build()?
+        .into_parts();
+    let packet = datagram(Ipv4Addr::new(10, 254, 0, 2))?;
+    let start = Instant::now();
+    for _ in 0..packets {
+        tun.send(&packet)?;
+    }
+    report("tun", packets, packets as u64, start.elapsed());
+
+    let (socket, _) = TcpStackBuilder::new().device(&nic).build_xdp(addr, 0)?;
+    let batch = vec![datagram(addr)?; BATCH_SIZE];
+    let before = socket.wakeups();
+    let start = Instant::now();
+    let mut sent = 0;
+    while sent < packets {
+        match socket.send_batch(&batch) {
+            Ok(()) => sent += batch.len(),
+            // the frames come back once the driver sent them
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    report("AF_XDP", sent, socket.wakeups() - before, start.elapsed());
+    Ok(())
+}
+
+fn datagram(src: Ipv4Addr) -> Result<Vec<u8>> {
+    let mut packet = vec![];
+    PacketBuilder::ipv4(src.octets(), [255, 255, 255, 255], 64)
+        .udp(9, 9)
+        .write(&mut packet, &[7u8; PAYLOAD])?;
+    Ok(packet)
+}
+
+fn report(name: &str, packets: usize, syscalls: u64, elapsed: Duration) {
+    println!(
+        "{name:>8}: {syscalls:>8} syscalls for {packets:} packets, {:.2} per packet, {:.0} packets/s",
+        syscalls as f64 / packets as f64,
+        packets as f64 / elapsed.as_secs_f64()
+    );
+}
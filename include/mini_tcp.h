@@ -0,0 +1,56 @@
+/*
+ * The C API of mini-tcp, built into libmini_tcp.so with
+ * `cargo rustc --release --lib --features ffi --crate-type cdylib`.
+ * The calls returning an int or a ssize_t fail with a negated errno, -EAGAIN when they would block.
+ */
+#ifndef MINI_TCP_H
+#define MINI_TCP_H
+
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A stack running on a tun device */
+typedef struct MtStack mt_stack;
+
+/* A connection, the addresses in host byte order */
+typedef struct {
+    uint32_t src_addr;
+    uint16_t src_port;
+    uint32_t dst_addr;
+    uint16_t dst_port;
+} mt_conn;
+
+/* A connection whose readiness changed */
+typedef struct {
+    mt_conn conn;
+    uint8_t readable;
+    uint8_t writable;
+} mt_event;
+
+/* Runs a stack on the tun device `device`, mini-tcp-tun when NULL. NULL when it can't be opened. */
+mt_stack *mt_stack_new(const char *device);
+void mt_stack_free(mt_stack *stack);
+
+int mt_listen(mt_stack *stack, uint16_t port);
+/* Takes the next connection established on `port`, -EAGAIN while there is none */
+int mt_accept(mt_stack *stack, uint16_t port, mt_conn *conn);
+/* Waits at most `timeout_ms`, forever when negative, returns how many of `events` are filled */
+ssize_t mt_poll(mt_stack *stack, int64_t timeout_ms, mt_event *events, size_t max);
+/* Returns 0 once the peer closed its side */
+ssize_t mt_read(mt_stack *stack, const mt_conn *conn, uint8_t *buf, size_t len);
+ssize_t mt_write(mt_stack *stack, const mt_conn *conn, const uint8_t *data, size_t len);
+int mt_close(mt_stack *stack, const mt_conn *conn);
+/* Makes mt_read and mt_write fail with -ETIMEDOUT once they would block for `read_ms` and
+ * `write_ms`, 0 waiting forever */
+int mt_set_timeouts(mt_stack *stack, const mt_conn *conn, uint64_t read_ms, uint64_t write_ms);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
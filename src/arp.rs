@@ -0,0 +1,223 @@
+//! ARP for ipv4 over Ethernet, https://www.ietf.org/rfc/rfc826.txt: the requests for the address of
+//! the stack on a tap device are answered, and the MACs of the peers are resolved into a neighbor
+//! cache, the packets to a peer not resolved yet waiting for the reply.
+
+use crate::ethernet::{MacAddr, BROADCAST};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// The length of an ARP packet for ipv4 over Ethernet
+pub const ARP_LEN: usize = 28;
+
+pub const ARP_REQUEST: u16 = 1;
+pub const ARP_REPLY: u16 = 2;
+
+/// How long a MAC is used before it's resolved again
+pub const NEIGHBOR_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often an unanswered request is repeated while packets are waiting
+pub const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The packets kept per peer while it's resolved, the older ones dropped beyond, as Linux's
+/// `unres_qlen`
+pub const MAX_PENDING: usize = 3;
+
+/// Hardware type Ethernet, protocol type ipv4
+const HEADER: [u8; 6] = [0, 1, 0x08, 0x00, 6, 4];
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Arp {
+    pub op: u16,
+    pub sender_mac: MacAddr,
+    pub sender_addr: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_addr: Ipv4Addr,
+}
+
+impl Arp {
+    /// Asks who has `target`, from `mac` at `addr`.
+    pub fn request(mac: MacAddr, addr: Ipv4Addr, target: Ipv4Addr) -> Self {
+        Self {
+            op: ARP_REQUEST,
+            sender_mac: mac,
+            sender_addr: addr,
+            target_mac: [0; 6],
+            target_addr: target,
+        }
+    }
+
+    /// Announces that `addr` is at `mac`, so the caches of the peers are updated,
+    /// https://www.ietf.org/rfc/rfc5227.txt 3.
+    pub fn gratuitous(mac: MacAddr, addr: Ipv4Addr) -> Self {
+        Self::request(mac, addr, addr)
+    }
+
+    /// The answer to this request, the target being at `mac`.
+    pub fn reply(&self, mac: MacAddr) -> Self {
+        Self {
+            op: ARP_REPLY,
+            sender_mac: mac,
+            sender_addr: self.target_addr,
+            target_mac: self.sender_mac,
+            target_addr: self.sender_addr,
+        }
+    }
+
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < ARP_LEN || data[..6] != HEADER {
+            return None;
+        }
+        let mac = |at: usize| -> MacAddr { data[at..at + 6].try_into().unwrap() };
+        let addr = |at: usize| Ipv4Addr::new(data[at], data[at + 1], data[at + 2], data[at + 3]);
+        Some(Self {
+            op: u16::from_be_bytes([data[6], data[7]]),
+            sender_mac: mac(8),
+            sender_addr: addr(14),
+            target_mac: mac(18),
+            target_addr: addr(24),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; ARP_LEN] {
+        let mut bytes = [0u8; ARP_LEN];
+        bytes[..6].copy_from_slice(&HEADER);
+        bytes[6..8].copy_from_slice(&self.op.to_be_bytes());
+        bytes[8..14].copy_from_slice(&self.sender_mac);
+        bytes[14..18].copy_from_slice(&self.sender_addr.octets());
+        bytes[18..24].copy_from_slice(&self.target_mac);
+        bytes[24..28].copy_from_slice(&self.target_addr.octets());
+        bytes
+    }
+}
+
+#[derive(Debug)]
+enum Neighbor {
+    Reachable {
+        mac: MacAddr,
+        updated: Instant,
+    },
+    /// Being resolved, with the packets waiting for the reply
+    Incomplete {
+        /// When the last request was sent
+        requested: Option<Instant>,
+        pending: Vec<Vec<u8>>,
+    },
+}
+
+/// The MACs of the peers
+#[derive(Debug, Default)]
+pub struct Neighbors {
+    entries: HashMap<Ipv4Addr, Neighbor>,
+}
+
+impl Neighbors {
+    /// The MAC of `addr`, none when it's unknown or older than `NEIGHBOR_TIMEOUT`.
+    pub fn lookup(&self, addr: Ipv4Addr, now: Instant) -> Option<MacAddr> {
+        if addr.is_broadcast() {
+            return Some(BROADCAST);
+        }
+        match self.entries.get(&addr)? {
+            Neighbor::Reachable { mac, updated }
+                if now.saturating_duration_since(*updated) < NEIGHBOR_TIMEOUT =>
+            {
+                Some(*mac)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether `addr` is resolved or being resolved.
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        self.entries.contains_key(&addr)
+    }
+
+    /// Records `mac` as the MAC of `addr`, and returns the packets waiting for it.
+    pub fn update(&mut self, addr: Ipv4Addr, mac: MacAddr, now: Instant) -> Vec<Vec<u8>> {
+        self.entries.retain(|_, neighbor| match neighbor {
+            Neighbor::Reachable { updated, .. } => {
+                now.saturating_duration_since(*updated) < NEIGHBOR_TIMEOUT
+            }
+            Neighbor::Incomplete { requested, .. } => {
+                requested.is_some_and(|at| now.saturating_duration_since(at) < NEIGHBOR_TIMEOUT)
+            }
+        });
+        let before = self
+            .entries
+            .insert(addr, Neighbor::Reachable { mac, updated: now });
+        match before {
+            Some(Neighbor::Incomplete { pending, .. }) => pending,
+            _ => vec![],
+        }
+    }
+
+    /// Keeps `packet` until `addr` is resolved. Returns whether a request is to be sent, none being
+    /// sent in the last `REQUEST_INTERVAL`.
+    pub fn queue(&mut self, addr: Ipv4Addr, packet: &[u8], now: Instant) -> bool {
+        let incomplete = || Neighbor::Incomplete {
+            requested: None,
+            pending: vec![],
+        };
+        let neighbor = self.entries.entry(addr).or_insert_with(incomplete);
+        if let Neighbor::Reachable { .. } = neighbor {
+            // expired, resolved again
+            *neighbor = incomplete();
+        }
+        let Neighbor::Incomplete { requested, pending } = neighbor else {
+            unreachable!("the neighbor was just made incomplete")
+        };
+        if pending.len() == MAX_PENDING {
+            pending.remove(0);
+        }
+        pending.push(packet.to_vec());
+        if requested.is_some_and(|at| now.saturating_duration_since(at) < REQUEST_INTERVAL) {
+            return false;
+        }
+        *requested = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arp::{Arp, Neighbors, ARP_REPLY, MAX_PENDING, NEIGHBOR_TIMEOUT};
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_arp() {
+        let request = Arp::request(
+            [2, 0, 0, 0, 0, 2],
+            Ipv4Addr::new(192, 167, 1, 2),
+            Ipv4Addr::new(192, 167, 1, 1),
+        );
+        assert_eq!(Arp::parse(&request.to_bytes()), Some(request));
+        assert_eq!(Arp::parse(&request.to_bytes()[..27]), None);
+
+        let reply = request.reply([2, 0, 0, 0, 0, 1]);
+        assert_eq!(reply.op, ARP_REPLY);
+        assert_eq!(reply.sender_addr, Ipv4Addr::new(192, 167, 1, 1));
+        assert_eq!(reply.target_mac, [2, 0, 0, 0, 0, 2]);
+    }
+
+    #[test]
+    fn test_neighbors() {
+        let mut neighbors = Neighbors::default();
+        let peer = Ipv4Addr::new(192, 167, 1, 2);
+        let now = Instant::now();
+        assert_eq!(neighbors.lookup(peer, now), None);
+
+        // a request for the first packet, the next ones wait for it
+        assert!(neighbors.queue(peer, b"1", now));
+        for i in 2..=MAX_PENDING + 1 {
+            assert!(!neighbors.queue(peer, i.to_string().as_bytes(), now));
+        }
+        assert!(neighbors.queue(peer, b"5", now + Duration::from_secs(1)));
+        let pending = neighbors.update(peer, [2, 0, 0, 0, 0, 2], now);
+        assert_eq!(pending, vec![b"3".to_vec(), b"4".to_vec(), b"5".to_vec()]);
+
+        assert_eq!(neighbors.lookup(peer, now), Some([2, 0, 0, 0, 0, 2]));
+        assert_eq!(neighbors.lookup(peer, now + NEIGHBOR_TIMEOUT), None);
+        assert!(neighbors.queue(peer, b"6", now + NEIGHBOR_TIMEOUT));
+    }
+}
@@ -0,0 +1,377 @@
+//! The configuration of a `TcpStack` in one place: the device it runs on, tun or tap, and how it's set up, and the
+//! tunables the connections start from. Without an address the device is expected to be set up
+//! outside, as `run.sh` does.
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! use std::net::Ipv4Addr;
+//! let stack = mini_tcp::TcpStackBuilder::new()
+//!     .device("tun0")
+//!     .address(Ipv4Addr::new(192, 167, 1, 0), Ipv4Addr::new(255, 255, 255, 0))
+//!     .mtu(1400)
+//!     .build()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::config::Route;
+use crate::device::Nic;
+#[cfg(feature = "dpdk")]
+use crate::dpdk::{self, DpdkPort};
+use crate::ethernet::{MacAddr, VLAN_ID_MAX};
+use crate::multiqueue::{self, Queue};
+use crate::netlink::{self, Applied};
+use crate::stack::TcpStack;
+use crate::tcp::congestion::Algorithm;
+use crate::tcp::iss::IssPolicy;
+use crate::tcp::{device_mss, nic_mss, Tunables};
+#[cfg(feature = "af-xdp")]
+use crate::xdp::{self, XdpSocket};
+use anyhow::{anyhow, Result};
+#[cfg(feature = "af-xdp")]
+use std::io;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// The name of the tun device unless another is given
+pub const DEFAULT_DEVICE: &str = "mini-tcp-tun";
+
+#[derive(Debug, Clone)]
+pub struct TcpStackBuilder {
+    device: String,
+    /// The address and netmask assigned to the device, which is brought up
+    address: Option<(Ipv4Addr, Ipv4Addr)>,
+    mtu: Option<u16>,
+    /// The routes added through the device
+    routes: Vec<Route>,
+    /// The MAC and the address of the stack on a tap device, a tun device without them
+    tap: Option<(MacAddr, Ipv4Addr)>,
+    /// The VLAN the stack is on, on a tap device
+    vlan: Option<u16>,
+    tunables: Tunables,
+}
+
+impl Default for TcpStackBuilder {
+    fn default() -> Self {
+        Self {
+            device: DEFAULT_DEVICE.to_string(),
+            address: None,
+            mtu: None,
+            routes: vec![],
+            tap: None,
+            vlan: None,
+            tunables: Tunables::default(),
+        }
+    }
+}
+
+impl TcpStackBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The tunables to start from, the other settings apply on top of them.
+    pub fn tunables(mut self, tunables: Tunables) -> Self {
+        self.tunables = tunables;
+        self
+    }
+
+    /// The name of the tun device, created if it doesn't exist, or of the NIC for `build_xdp`.
+    pub fn device(mut self, name: &str) -> Self {
+        self.device = name.to_string();
+        self
+    }
+
+    /// Assigns `addr` with `netmask` to the device and brings it up, which takes `CAP_NET_ADMIN`. The
+    /// address is removed once the device is dropped, see `netlink`.
+    pub fn address(mut self, addr: Ipv4Addr, netmask: Ipv4Addr) -> Self {
+        self.address = Some((addr, netmask));
+        self
+    }
+
+    /// Adds `route` through the device, which is brought up, until it's dropped.
+    pub fn route(mut self, route: Route) -> Self {
+        self.routes.push(route);
+        self
+    }
+
+    /// Sets the MTU of the device, the MSS follows from it.
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    /// Runs on a tap device instead of a tun device, the stack answering to `mac` and to the ARP
+    /// requests for `addr` on it, and framing its packets in Ethernet, see `ethernet`. `addr` is the
+    /// stack's own, not the one of the device set with `address`.
+    pub fn tap(mut self, mac: MacAddr, addr: Ipv4Addr) -> Self {
+        self.tap = Some((mac, addr));
+        self
+    }
+
+    /// Puts the stack on the VLAN `id` of the trunk the tap device is on, see `ethernet`.
+    pub fn vlan(mut self, id: u16) -> Self {
+        self.vlan = Some(id);
+        self
+    }
+
+    /// The window advertised in the SYN-ACK, see `Tunables::window_size`.
+    pub fn window_size(mut self, window_size: u16) -> Self {
+        self.tunables.window_size = window_size;
+        self
+    }
+
+    pub fn iss_policy(mut self, policy: IssPolicy) -> Self {
+        self.tunables.iss_policy = policy;
+        self
+    }
+
+    /// The Maximum Segment Lifetime, TIME-WAIT lasting twice as long.
+    pub fn msl(mut self, msl: Duration) -> Self {
+        self.tunables.msl = msl;
+        self
+    }
+
+    /// The bounds of the retransmission timeout.
+    pub fn rto(mut self, min: Duration, max: Duration) -> Self {
+        self.tunables.rto_min = min;
+        self.tunables.rto_max = max;
+        self
+    }
+
+    pub fn congestion_control(mut self, algorithm: Algorithm) -> Self {
+        self.tunables.congestion_control = algorithm;
+        self
+    }
+
+    /// The accept queue of the ports bound without a backlog of their own.
+    pub fn listen_backlog(mut self, backlog: u32) -> Self {
+        self.tunables.listen_backlog = backlog;
+        self
+    }
+
+    /// The bounds of the receive buffer of a connection and the cap of all of them together.
+    pub fn rcv_buf(mut self, min: u32, max: u32, global_max: u64) -> Self {
+        self.tunables.rcv_buf_min = min;
+        self.tunables.rcv_buf_max = max;
+        self.tunables.rcv_mem_global_max = global_max;
+        self
+    }
+
+    /// The bounds of the send buffer of a connection and the cap of all of them together.
+    pub fn snd_buf(mut self, min: u32, max: u32, global_max: u64) -> Self {
+        self.tunables.snd_buf_min = min;
+        self.tunables.snd_buf_max = max;
+        self.tunables.snd_buf_global_max = global_max;
+        self
+    }
+
+    /// Checks the settings fit together, before anything is done to the device.
+    fn validate(&self) -> Result<()> {
+        let t = &self.tunables;
+        if t.rto_min.is_zero() || t.rto_min > t.rto_max {
+            return Err(anyhow!(
+                "rto bounds {:?}..={:?} are empty",
+                t.rto_min,
+                t.rto_max
+            ));
+        }
+        if t.rcv_buf_min > t.rcv_buf_max || t.snd_buf_min > t.snd_buf_max {
+            return Err(anyhow!("buffer bounds with min above max"));
+        }
+        if self.mtu.is_some_and(|mtu| mtu < 68) {
+            return Err(anyhow!("mtu below the 68 octets of ipv4"));
+        }
+        if self.vlan.is_some() && self.tap.is_none() {
+            return Err(anyhow!("a vlan needs a tap device"));
+        }
+        if self.vlan.is_some_and(|id| id == 0 || id > VLAN_ID_MAX) {
+            return Err(anyhow!("vlan id is not within 1..={VLAN_ID_MAX:}"));
+        }
+        if self.device.is_empty() || self.device.len() >= libc::IFNAMSIZ {
+            return Err(anyhow!("device name {:?} is not valid", self.device));
+        }
+        Ok(())
+    }
+
+    /// Opens the device, sets it up and runs a stack on it.
+    pub fn build(self) -> Result<TcpStack> {
+        self.validate()?;
+        let mut nic = match self.tap {
+            Some((mac, addr)) => Nic::tap(
+                tun_tap::Iface::without_packet_info(&self.device, tun_tap::Mode::Tap)?,
+                mac,
+                Some(addr),
+            ),
+            None => Nic::tun(tun_tap::Iface::without_packet_info(
+                &self.device,
+                tun_tap::Mode::Tun,
+            )?),
+        };
+        nic.set_vlan(self.vlan)?;
+        nic.hold(self.set_up(nic.name())?);
+        nic.set_nonblocking(true)?;
+        // the device may not be up yet when it's set up outside
+        if let Err(e) = nic.announce() {
+            log::warn!("gratuitous ARP on {:} failed: {e:}", nic.name());
+        }
+        let tunables = Tunables {
+            mss: nic_mss(nic.iface())?,
+            ..self.tunables
+        };
+        let mut stack = TcpStack::with_tunables(nic, tunables);
+        // the connections opened on a tap device come from the address it answers ARP for
+        if let Some((_, addr)) = self.tap {
+            stack.stack_mut().set_address(addr);
+        }
+        Ok(stack)
+    }
+
+    /// Opens `queues` queues of a multiqueue tun device and sets it up, for a stack sharded over them,
+    /// see `multiqueue`. Returns the queues and the tunables of the shards.
+    pub fn build_multiqueue(self, queues: usize) -> Result<(Vec<Queue>, Tunables)> {
+        self.validate()?;
+        if self.tap.is_some() {
+            return Err(anyhow!("multiqueue only runs on a tun device"));
+        }
+        if queues == 0 {
+            return Err(anyhow!("expect at least one queue"));
+        }
+        let mut queues = multiqueue::open(&self.device, queues)?;
+        let applied = self.set_up(queues[0].name())?;
+        queues[0].hold(applied);
+        let tunables = Tunables {
+            mss: device_mss(queues[0].name())?,
+            ..self.tunables
+        };
+        Ok((queues, tunables))
+    }
+
+    /// Binds an AF_XDP socket to the queue `queue` of the NIC set with `device`, the stack answering
+    /// to the NIC's MAC and to `addr` on it, see `xdp`. Returns the socket and the tunables of the
+    /// stack, the MSS bounded by the frames of the UMEM.
+    #[cfg(feature = "af-xdp")]
+    pub fn build_xdp(self, addr: Ipv4Addr, queue: u32) -> Result<(XdpSocket, Tunables)> {
+        self.validate()?;
+        if self.tap.is_some() {
+            return Err(anyhow!("AF_XDP runs on a NIC, not on a tap device"));
+        }
+        let applied = self.set_up(&self.device)?;
+        let hwaddr = ioctl(&self.device, libc::SIOCGIFHWADDR, |_| {})?;
+        let sa_data = unsafe { hwaddr.ifr_ifru.ifru_hwaddr.sa_data };
+        let mac = std::array::from_fn(|i| sa_data[i] as u8);
+        let mut socket = XdpSocket::open(&self.device, queue, mac, addr)?;
+        socket.hold(applied);
+        if let Err(e) = socket.announce() {
+            log::warn!("gratuitous ARP on {:} failed: {e:}", socket.name());
+        }
+        let tunables = Tunables {
+            mss: device_mss(&self.device)?.min(xdp::MAX_MSS),
+            ..self.tunables
+        };
+        Ok((socket, tunables))
+    }
+
+    /// Sets up the DPDK EAL with the arguments `eal` and starts the port `port`, the stack answering to
+    /// the port's MAC and to `addr` on it, see `dpdk`. Returns the port and the tunables of the stack,
+    /// the MSS following from the MTU set, 1500 by default.
+    #[cfg(feature = "dpdk")]
+    pub fn build_dpdk(
+        self,
+        eal: &[&str],
+        port: u16,
+        addr: Ipv4Addr,
+    ) -> Result<(DpdkPort, Tunables)> {
+        self.validate()?;
+        if self.tap.is_some() {
+            return Err(anyhow!(
+                "DPDK runs on a port of its own, not on a tap device"
+            ));
+        }
+        dpdk::init(eal)?;
+        let port = DpdkPort::open(port, addr)?;
+        port.announce()?;
+        let tunables = Tunables {
+            // the ip and the tcp headers
+            mss: self.mtu.unwrap_or(1500).saturating_sub(40),
+            ..self.tunables
+        };
+        Ok((port, tunables))
+    }
+
+    /// Sets the MTU, the address and the routes of the device `name`, bringing it up. Returns what
+    /// was added, to remove with the device.
+    fn set_up(&self, name: &str) -> Result<Applied> {
+        Ok(netlink::apply(name, self.address, self.mtu, &self.routes)?)
+    }
+}
+
+/// Runs the interface `request` on the device `name`, the request filled in by `fill`, and returns it.
+#[cfg(feature = "af-xdp")]
+fn ioctl(
+    name: &str,
+    request: libc::c_ulong,
+    fill: impl FnOnce(&mut libc::ifreq),
+) -> io::Result<libc::ifreq> {
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in req
+        .ifr_name
+        .iter_mut()
+        .zip(name.as_bytes())
+        .take(libc::IFNAMSIZ - 1)
+    {
+        *dst = *src as libc::c_char;
+    }
+    fill(&mut req);
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ret = unsafe { libc::ioctl(fd, request, &mut req) };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if ret < 0 {
+        return Err(err);
+    }
+    Ok(req)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::builder::TcpStackBuilder;
+    use crate::ethernet::DEFAULT_MAC;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_validate() {
+        let builder = TcpStackBuilder::new()
+            .window_size(1024)
+            .rto(Duration::from_millis(200), Duration::from_secs(10));
+        assert!(builder.validate().is_ok());
+        assert_eq!(builder.tunables.window_size, 1024);
+
+        let secs = Duration::from_secs;
+        assert!(builder.clone().rto(secs(2), secs(1)).build().is_err());
+        assert!(builder
+            .clone()
+            .rto(Duration::ZERO, secs(1))
+            .build()
+            .is_err());
+        assert!(builder.clone().mtu(60).build().is_err());
+        assert!(builder
+            .clone()
+            .rcv_buf(8192, 4096, 1 << 20)
+            .build()
+            .is_err());
+        assert!(builder.clone().vlan(10).build().is_err());
+        let tap = builder
+            .clone()
+            .tap(DEFAULT_MAC, Ipv4Addr::new(192, 167, 1, 1));
+        assert!(tap.clone().vlan(10).validate().is_ok());
+        assert!(tap.clone().vlan(0).validate().is_err());
+        assert!(tap.vlan(4095).validate().is_err());
+        assert!(builder.device("a-name-far-too-long").build().is_err());
+    }
+}
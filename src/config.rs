@@ -0,0 +1,220 @@
+//! The configuration file of the binary, one `<name> = <value>` per line, `#` starting a comment:
+//!
+//! ```text
+//! # the device, only read at start
+//! iface = mini-tcp-tun
+//! cidr = 192.167.1.0/24
+//! mtu = 1500
+//! # the routes through the device, one per line, via a gateway or not
+//! route = 10.1.0.0/16
+//! route = 10.2.0.0/16 via 192.167.1.1
+//! # the ports accepting connections, all of them when absent
+//! listen = 80, 443
+//! # any tunable, as listed by the `tunables` command
+//! listen_backlog = 256
+//! rcv_buf_max = 8388608
+//! ```
+//!
+//! The file is read again on SIGHUP or the `reload` command: the listeners and the tunables change
+//! without touching the connections established, the device settings only take effect on a restart.
+//! A tunable removed from the file keeps its current value.
+
+use anyhow::{anyhow, Context, Result};
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Config {
+    pub iface: Option<String>,
+    /// The address and netmask of the device
+    pub cidr: Option<(Ipv4Addr, Ipv4Addr)>,
+    pub mtu: Option<u16>,
+    /// The routes added through the device
+    pub routes: Vec<Route>,
+    /// The ports bound, the stack accepting connections to any port when none is
+    pub listen: Vec<u16>,
+    /// The tunables by name, in the order of the file
+    pub tunables: Vec<(String, u64)>,
+}
+
+/// A route through the device the stack runs on, as `ip route add <dst>/<len> [via <gateway>]` adds
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Route {
+    pub dst: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+}
+
+/// What a reload changed, and what it couldn't
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Reloaded {
+    /// The settings whose new value is in effect
+    pub applied: Vec<String>,
+    /// The settings changed in the file which only take effect on a restart
+    pub restart: Vec<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("reading {:}", path.display()))?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut config = Config::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            config
+                .parse_line(line)
+                .with_context(|| format!("line {:}: {line:}", n + 1))?;
+        }
+        Ok(config)
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<()> {
+        let (name, value) = line
+            .split_once('=')
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .ok_or_else(|| anyhow!("expect <name> = <value>"))?;
+        match name {
+            "iface" => self.iface = Some(value.to_string()),
+            "cidr" => self.cidr = Some(parse_cidr(value)?),
+            "mtu" => self.mtu = Some(value.parse()?),
+            "route" => self.routes.push(parse_route(value)?),
+            "listen" => {
+                self.listen = value
+                    .split(',')
+                    .map(|port| port.trim().parse())
+                    .collect::<Result<_, _>>()?
+            }
+            _ => self.tunables.push((name.to_string(), value.parse()?)),
+        }
+        Ok(())
+    }
+
+    /// The settings of `other` differing from these that can't be changed while running.
+    pub fn restart_required(&self, other: &Config) -> Vec<String> {
+        let mut restart = vec![];
+        if self.iface != other.iface {
+            restart.push("iface".to_string());
+        }
+        if self.cidr != other.cidr {
+            restart.push("cidr".to_string());
+        }
+        if self.mtu != other.mtu {
+            restart.push("mtu".to_string());
+        }
+        if self.routes != other.routes {
+            restart.push("route".to_string());
+        }
+        restart
+    }
+}
+
+/// Parses `<addr>/<prefix len>` into the address and its netmask.
+pub fn parse_cidr(cidr: &str) -> Result<(Ipv4Addr, Ipv4Addr)> {
+    let (addr, len) = cidr
+        .split_once('/')
+        .ok_or_else(|| anyhow!("{cidr:} is not <addr>/<len>"))?;
+    let len: u32 = len.parse()?;
+    if len > 32 {
+        return Err(anyhow!("prefix length {len:} exceeds 32"));
+    }
+    let netmask = u32::MAX.checked_shl(32 - len).unwrap_or(0);
+    Ok((addr.parse()?, Ipv4Addr::from(netmask)))
+}
+
+/// Parses `<addr>/<prefix len>`, followed by `via <gateway>` for a route through a gateway.
+pub fn parse_route(route: &str) -> Result<Route> {
+    let mut words = route.split_whitespace();
+    let (dst, netmask) = parse_cidr(words.next().unwrap_or_default())?;
+    let gateway = match (words.next(), words.next(), words.next()) {
+        (None, _, _) => None,
+        (Some("via"), Some(gateway), None) => Some(gateway.parse()?),
+        _ => return Err(anyhow!("{route:} is not <addr>/<len> [via <gateway>]")),
+    };
+    Ok(Route {
+        dst,
+        netmask,
+        gateway,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{parse_cidr, parse_route, Config, Route};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            "# the device\n\
+             iface = tun1\n\
+             cidr = 10.0.0.1/24 # trailing comment\n\
+             \n\
+             listen = 80, 443\n\
+             listen_backlog = 256\n",
+        )
+        .unwrap();
+        assert_eq!(config.iface.as_deref(), Some("tun1"));
+        assert_eq!(
+            config.cidr,
+            Some((Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(255, 255, 255, 0)))
+        );
+        assert_eq!(config.listen, vec![80, 443]);
+        assert_eq!(config.tunables, vec![("listen_backlog".to_string(), 256)]);
+
+        let err = Config::parse("mtu = 1500\nlisten 80\n").unwrap_err();
+        assert!(err.to_string().starts_with("line 2"));
+        assert!(Config::parse("mtu = big").is_err());
+
+        let other = Config {
+            mtu: Some(9000),
+            ..config.clone()
+        };
+        assert_eq!(config.restart_required(&other), vec!["mtu"]);
+        assert!(config.restart_required(&config).is_empty());
+    }
+
+    #[test]
+    fn test_parse_cidr() {
+        assert_eq!(
+            parse_cidr("192.167.1.0/32").unwrap().1,
+            Ipv4Addr::new(255, 255, 255, 255)
+        );
+        assert_eq!(parse_cidr("0.0.0.0/0").unwrap().1, Ipv4Addr::UNSPECIFIED);
+        assert!(parse_cidr("192.167.1.0/33").is_err());
+        assert!(parse_cidr("192.167.1.0").is_err());
+    }
+
+    #[test]
+    fn test_parse_route() {
+        let config = Config::parse(
+            "route = 10.1.0.0/16
+route = 10.2.0.0/16 via 192.167.1.1
+",
+        )
+        .unwrap();
+        assert_eq!(
+            config.routes,
+            vec![
+                Route {
+                    dst: Ipv4Addr::new(10, 1, 0, 0),
+                    netmask: Ipv4Addr::new(255, 255, 0, 0),
+                    gateway: None,
+                },
+                Route {
+                    dst: Ipv4Addr::new(10, 2, 0, 0),
+                    netmask: Ipv4Addr::new(255, 255, 0, 0),
+                    gateway: Some(Ipv4Addr::new(192, 167, 1, 1)),
+                },
+            ]
+        );
+        assert!(parse_route("10.1.0.0/16 192.167.1.1").is_err());
+        assert!(parse_route("10.1.0.0/16 via 192.167.1.1 dev tun0").is_err());
+    }
+}
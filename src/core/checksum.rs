@@ -0,0 +1,57 @@
+//! The internet checksum of the ip header and of tcp segments, the ones' complement of the ones'
+//! complement sum of their 16-bit words, see https://www.ietf.org/rfc/rfc1071.txt
+
+/// Adds the 16-bit words of `data` to `sum`, an odd last octet padded with a zero. The carries are kept
+/// in the high half until `fold`, so sums of consecutive buffers of even lengths add up.
+pub fn sum(data: &[u8], mut sum: u32) -> u32 {
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum = sum.wrapping_add(u16::from_be_bytes([word[0], word[1]]) as u32);
+    }
+    if let [last] = words.remainder() {
+        sum = sum.wrapping_add(u16::from_be_bytes([*last, 0]) as u32);
+    }
+    sum
+}
+
+/// Folds the carries of `sum` back into 16 bits, the ones' complement sum.
+pub fn fold(mut sum: u32) -> u16 {
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// The sum of the pseudo header of a tcp segment of `len` octets from `src` to `dst`, see
+/// https://www.ietf.org/rfc/rfc793.txt page 17.
+pub fn pseudo_header(src: [u8; 4], dst: [u8; 4], protocol: u8, len: u16) -> u32 {
+    let sum = self::sum(&src, 0);
+    let sum = self::sum(&dst, sum);
+    sum + protocol as u32 + len as u32
+}
+
+/// Whether data summed with its checksum in it, `sum`, is intact: all ones.
+pub fn is_valid(sum: u32) -> bool {
+    fold(sum) == 0xFFFF
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::checksum::{fold, is_valid, pseudo_header, sum};
+
+    #[test]
+    fn test_checksum() {
+        // the example of https://www.ietf.org/rfc/rfc1071.txt 3
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(fold(sum(&data, 0)), 0xddf2);
+        assert_eq!(fold(sum(&data[4..], sum(&data[..4], 0))), 0xddf2);
+        assert_eq!(sum(&[0xab], 0), 0xab00);
+
+        let checksum = !fold(sum(&data, 0));
+        assert!(is_valid(sum(&checksum.to_be_bytes(), sum(&data, 0))));
+        assert!(!is_valid(sum(&data, 0)));
+
+        let pseudo = pseudo_header([10, 0, 0, 1], [10, 0, 0, 2], 6, 20);
+        assert_eq!(fold(pseudo), 0x0a00 + 0x0001 + 0x0a00 + 0x0002 + 6 + 20);
+    }
+}
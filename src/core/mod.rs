@@ -0,0 +1,15 @@
+//! The parts of the stack depending on neither std nor the device: the connection identifiers, the
+//! sequence spaces making up the TCB, the sequence arithmetic, the checks of an arriving segment in
+//! `segment` and the internet checksum. The handshake, the timers, retransmission, congestion control
+//! and the options are in `tcp`, which needs std.
+//! Without the default `std` feature the crate is `no_std` and only this module is built, for
+//! embedded targets to run their own I/O around it:
+//!
+//! ```toml
+//! mini-tcp = { version = "0.1", default-features = false }
+//! ```
+
+pub mod checksum;
+pub mod segment;
+pub mod seq;
+pub mod tcb;
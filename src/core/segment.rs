@@ -0,0 +1,224 @@
+//! The checks of a segment arriving on a synchronized connection, https://www.ietf.org/rfc/rfc9293.txt
+//! 3.10.7.4 with the challenges of https://www.ietf.org/rfc/rfc5961.txt, decided on the TCB alone.
+//! What a verdict leads to, the retransmission queue, congestion control, the options and the
+//! buffers, is up to the caller, as are the timers: none of the checks reads a clock.
+
+use crate::core::seq::{is_ack_in_window, is_seq_acceptable, wrapping_lt};
+use crate::core::tcb::{ReceiveSequenceSpace, SendSequenceSpace};
+
+/// The header fields of an arriving segment the checks look at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// SEG.SEQ
+    pub seq: u32,
+    /// SEG.ACK, `None` without the ACK bit
+    pub ack: Option<u32>,
+    /// The octets of data carried
+    pub len: u32,
+    pub syn: bool,
+    pub fin: bool,
+    pub rst: bool,
+}
+
+impl Segment {
+    /// SEG.LEN counting SYN and FIN, `None` without data as `is_seq_acceptable` takes it
+    fn seg_len(&self) -> Option<u32> {
+        (self.len > 0).then(|| self.len + self.syn as u32 + self.fin as u32)
+    }
+}
+
+/// The outcome of the sequence number check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Acceptance {
+    /// In the window, on to the RST, SYN and ACK checks
+    Accept,
+    /// Out of the window, answered with an ACK of RCV.NXT
+    Ack,
+    /// A SYN out of the window, challenged whatever its sequence number,
+    /// https://www.ietf.org/rfc/rfc5961.txt 4.2
+    Challenge,
+    /// A RST out of the window, dropped silently
+    Drop,
+}
+
+/// First, check the sequence number, https://www.ietf.org/rfc/rfc9293.txt 3.10.7.4
+pub fn check_sequence(rcv: &ReceiveSequenceSpace, seg: &Segment) -> Acceptance {
+    if is_seq_acceptable(rcv, seg.seq, seg.seg_len()) {
+        Acceptance::Accept
+    } else if seg.syn {
+        Acceptance::Challenge
+    } else if seg.rst {
+        Acceptance::Drop
+    } else {
+        Acceptance::Ack
+    }
+}
+
+/// The outcome of the RST, SYN and ACK checks of a segment in the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// A RST at exactly RCV.NXT, the connection is reset
+    Reset,
+    /// A RST elsewhere in the window, a SYN, or an ACK below SND.UNA - MAX.SND.WND, answered with a
+    /// challenge ACK and dropped
+    Challenge,
+    /// No ACK bit, dropped
+    Drop,
+    /// SND.UNA < SEG.ACK =< SND.NXT, that many octets newly acknowledged
+    Acked(u32),
+    /// SEG.ACK =< SND.UNA, nothing new acknowledged, the segment is processed further
+    Old,
+    /// SEG.ACK > SND.NXT, something not yet sent is acknowledged, answered with an ACK and dropped
+    Unsent,
+}
+
+/// Second to fifth, check the RST bit, the SYN bit and the ACK field of a segment `check_sequence`
+/// accepted. `max_snd_wnd` is the largest window the peer advertised, MAX.SND.WND of
+/// https://www.ietf.org/rfc/rfc5961.txt 5.2.
+pub fn check_control(
+    snd: &SendSequenceSpace,
+    rcv: &ReceiveSequenceSpace,
+    max_snd_wnd: u32,
+    seg: &Segment,
+) -> Verdict {
+    // only a RST at RCV.NXT resets the connection, another in the window is challenged,
+    // https://www.ietf.org/rfc/rfc5961.txt 3.2
+    if seg.rst {
+        return if seg.seq == rcv.nxt {
+            Verdict::Reset
+        } else {
+            Verdict::Challenge
+        };
+    }
+    // the peer resets the connection with the RST answering the challenge ACK if it restarted,
+    // https://www.ietf.org/rfc/rfc5961.txt 4.2
+    if seg.syn {
+        return Verdict::Challenge;
+    }
+    let Some(ack) = seg.ack else {
+        return Verdict::Drop;
+    };
+    if wrapping_lt(ack, snd.una.wrapping_sub(max_snd_wnd)) {
+        Verdict::Challenge
+    } else if is_ack_in_window(snd, ack) {
+        Verdict::Acked(ack.wrapping_sub(snd.una))
+    } else if wrapping_lt(snd.nxt, ack) {
+        Verdict::Unsent
+    } else {
+        Verdict::Old
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::segment::{check_control, check_sequence, Acceptance, Segment, Verdict};
+    use crate::core::tcb::{ReceiveSequenceSpace, SendSequenceSpace};
+
+    fn snd(una: u32, nxt: u32) -> SendSequenceSpace {
+        SendSequenceSpace {
+            up: false,
+            up_seq: 0,
+            wnd: 1000,
+            wnd_shift: 0,
+            una,
+            nxt,
+            wl1: 0,
+            wl2: 0,
+            iss: 0,
+        }
+    }
+
+    fn rcv(nxt: u32, wnd: u32) -> ReceiveSequenceSpace {
+        ReceiveSequenceSpace {
+            up: false,
+            up_seq: 0,
+            wnd,
+            wnd_shift: 0,
+            nxt,
+            irs: 0,
+        }
+    }
+
+    fn seg(seq: u32, ack: Option<u32>, len: u32) -> Segment {
+        Segment {
+            seq,
+            ack,
+            len,
+            syn: false,
+            fin: false,
+            rst: false,
+        }
+    }
+
+    #[test]
+    fn test_check_sequence() {
+        let rcv = rcv(100, 10);
+        assert_eq!(
+            check_sequence(&rcv, &seg(105, None, 20)),
+            Acceptance::Accept
+        );
+        assert_eq!(check_sequence(&rcv, &seg(110, None, 0)), Acceptance::Ack);
+        let rst = Segment {
+            rst: true,
+            ..seg(110, None, 0)
+        };
+        assert_eq!(check_sequence(&rcv, &rst), Acceptance::Drop);
+        let syn = Segment {
+            syn: true,
+            rst: true,
+            ..seg(u32::MAX, None, 0)
+        };
+        assert_eq!(check_sequence(&rcv, &syn), Acceptance::Challenge);
+        // a zero window only takes a segment without data at RCV.NXT
+        let closed = self::rcv(100, 0);
+        assert_eq!(
+            check_sequence(&closed, &seg(100, None, 0)),
+            Acceptance::Accept
+        );
+        assert_eq!(check_sequence(&closed, &seg(100, None, 1)), Acceptance::Ack);
+    }
+
+    #[test]
+    fn test_check_control() {
+        let (snd, rcv) = (snd(u32::MAX - 10, 20), rcv(100, 10));
+        let reset = Segment {
+            rst: true,
+            ..seg(100, Some(0), 0)
+        };
+        assert_eq!(check_control(&snd, &rcv, 1000, &reset), Verdict::Reset);
+        let challenged = Segment { seq: 101, ..reset };
+        assert_eq!(
+            check_control(&snd, &rcv, 1000, &challenged),
+            Verdict::Challenge
+        );
+        let syn = Segment {
+            syn: true,
+            ..seg(100, Some(0), 0)
+        };
+        assert_eq!(check_control(&snd, &rcv, 1000, &syn), Verdict::Challenge);
+        assert_eq!(
+            check_control(&snd, &rcv, 1000, &seg(100, None, 5)),
+            Verdict::Drop
+        );
+        // the window wraps around zero
+        assert_eq!(
+            check_control(&snd, &rcv, 1000, &seg(100, Some(4), 0)),
+            Verdict::Acked(15)
+        );
+        assert_eq!(
+            check_control(&snd, &rcv, 1000, &seg(100, Some(21), 0)),
+            Verdict::Unsent
+        );
+        assert_eq!(
+            check_control(&snd, &rcv, 1000, &seg(100, Some(u32::MAX - 10), 0)),
+            Verdict::Old
+        );
+        // MAX.SND.WND bounds how old an ACK can be
+        let ancient = seg(100, Some(u32::MAX - 1011), 0);
+        assert_eq!(
+            check_control(&snd, &rcv, 1000, &ancient),
+            Verdict::Challenge
+        );
+        assert_eq!(check_control(&snd, &rcv, 2000, &ancient), Verdict::Old);
+    }
+}
@@ -0,0 +1,210 @@
+//! Sequence number arithmetic, modulo 2^32 as https://www.ietf.org/rfc/rfc793.txt page 24 has it,
+//! and the acceptability tests of a segment built on it.
+
+use crate::core::tcb::{ReceiveSequenceSpace, SendSequenceSpace};
+
+/// Checks the segment starting at `seq` is acceptable, `seg_len` being `None` without data.
+/// See https://www.ietf.org/rfc/rfc793.txt page 24.
+///
+/// When data is received the following comparisons are needed:
+///     RCV.NXT = next sequence number expected on an incoming segments, and is the left or lower edge of the receive window
+///     RCV.NXT+RCV.WND-1 = last sequence number expected on an incoming segment, and is the right or upper edge of the receive window
+///     SEG.SEQ = first sequence number occupied by the incoming segment
+///     SEG.SEQ+SEG.LEN-1 = last sequence number occupied by the incoming segment
+///
+/// Due to zero windows and zero length segments, we have four cases for the acceptability of an incoming segment:
+///
+/// ```text
+///     Segment Receive  Test
+///     Length  Window
+///     ------- -------  -------------------------------------------
+///        0       0     SEG.SEQ = RCV.NXT
+///        0      >0     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+///       >0       0     not acceptable
+///       >0      >0     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+///                      or RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
+/// ```
+///
+/// A segment is judged to occupy a portion of valid receive sequence space if
+///     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+/// or
+///     RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
+/// Note that the above is a *OR* condition.
+pub fn is_seq_acceptable(rcv: &ReceiveSequenceSpace, seq: u32, seg_len: Option<u32>) -> bool {
+    // Case 1:
+    if seg_len.is_none() && rcv.wnd == 0 && seq == rcv.nxt {
+        return true;
+    }
+
+    // Case 3:
+    if seg_len.is_some() && rcv.wnd == 0 {
+        return false;
+    }
+
+    // Checking Case 2 and part of Case 4
+    let wnd_edge = rcv.nxt.wrapping_add(rcv.wnd);
+
+    // wrapping check: RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
+    if is_wrapping_lte_ls(rcv.nxt, seq, wnd_edge) {
+        return true;
+    }
+
+    // Case 4:
+    if let Some(seg_len) = seg_len {
+        // wrapping check: RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
+        let seg_last_seq = seq.wrapping_add(seg_len).wrapping_sub(1);
+        return is_wrapping_lte_ls(rcv.nxt, seg_last_seq, wnd_edge);
+    }
+
+    false
+}
+
+/// Checks if the three numbers a, b, c are: a <= b < c with wrapping
+pub fn is_wrapping_lte_ls<N: PartialOrd>(a: N, b: N, c: N) -> bool {
+    // case 1:  >>>> a >>>> b >>>> c
+    if a <= b && b < c {
+        return true;
+    }
+
+    // case 2:  >>>> c >>>> a >>>> b
+    if c < a && a <= b {
+        return true;
+    }
+
+    // case 3:  >>>> b >>>> c >>>> a
+    if b < c && c < a {
+        return true;
+    }
+
+    false
+}
+
+/// Checks a < b in sequence number space, i.e. b is at most 2^31 ahead of a, see https://www.ietf.org/rfc/rfc1982.txt
+pub fn wrapping_lt(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) > 0
+}
+
+/// Checks the ack number is actually within the send window. This also considers the case of usigned int wrapping.
+pub fn is_ack_in_window(snd: &SendSequenceSpace, ack: u32) -> bool {
+    // SND.UNA < SEG.ACK =< SND.NXT
+
+    // case 1:   >>>> una >>>> ack >>>> nxt
+    if snd.una < ack && ack <= snd.nxt {
+        return true;
+    }
+
+    // case 2:   >>>> nxt >>>> una >>>> ack
+    if snd.nxt < snd.una && snd.una < ack {
+        return true;
+    }
+
+    // case 3:   >>>> ack >>>> nxt >>>> una
+    if ack <= snd.nxt && snd.nxt < snd.una {
+        return true;
+    }
+
+    false
+}
+
+/// Updates the send window from the segment, following https://www.ietf.org/rfc/rfc793.txt page 72:
+///
+/// If SND.UNA =< SEG.ACK =< SND.NXT, the send window should be updated. If
+/// (SND.WL1 < SEG.SEQ or (SND.WL1 = SEG.SEQ and SND.WL2 =< SEG.ACK)), set
+/// SND.WND <- SEG.WND, set SND.WL1 <- SEG.SEQ, and set SND.WL2 <- SEG.ACK.
+///
+/// SND.WL1 records the sequence number of the last segment used to update SND.WND, and SND.WL2 the
+/// acknowledgment number, which prevents old segments from updating the window.
+/// Returns whether the window was updated.
+pub fn update_send_window(snd: &mut SendSequenceSpace, seq: u32, ack: u32, wnd: u16) -> bool {
+    // SND.UNA =< SEG.ACK =< SND.NXT
+    if wrapping_lt(ack, snd.una) || wrapping_lt(snd.nxt, ack) {
+        return false;
+    }
+
+    if wrapping_lt(snd.wl1, seq) || (snd.wl1 == seq && !wrapping_lt(ack, snd.wl2)) {
+        snd.wnd = (wnd as u32) << snd.wnd_shift;
+        snd.wl1 = seq;
+        snd.wl2 = ack;
+        return true;
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::seq::{is_seq_acceptable, update_send_window};
+    use crate::core::tcb::{ReceiveSequenceSpace, SendSequenceSpace};
+
+    fn snd(una: u32, nxt: u32, wl1: u32, wl2: u32) -> SendSequenceSpace {
+        SendSequenceSpace {
+            up: false,
+            up_seq: 0,
+            wnd: 1000,
+            wnd_shift: 0,
+            una,
+            nxt,
+            wl1,
+            wl2,
+            iss: una,
+        }
+    }
+
+    #[test]
+    fn test_update_send_window() {
+        // newer segment
+        let mut s = snd(100, 200, 50, 100);
+        assert!(update_send_window(&mut s, 60, 150, 2000));
+        assert_eq!((s.wnd, s.wl1, s.wl2), (2000, 60, 150));
+
+        // same segment sequence, the ack moved forward, e.g. a pure window update
+        assert!(update_send_window(&mut s, 60, 150, 500));
+        assert_eq!(s.wnd, 500);
+
+        // stale segment reordered behind the last update must not shrink the window
+        assert!(!update_send_window(&mut s, 55, 160, 0));
+        assert!(!update_send_window(&mut s, 60, 140, 0));
+        assert_eq!(s.wnd, 500);
+
+        // ack outside of SND.UNA =< SEG.ACK =< SND.NXT
+        assert!(!update_send_window(&mut s, 70, 99, 0));
+        assert!(!update_send_window(&mut s, 70, 201, 0));
+        assert_eq!(s.wnd, 500);
+
+        // the window is scaled by the shift the peer announced
+        s.wnd_shift = 7;
+        assert!(update_send_window(&mut s, 70, 150, 65535));
+        assert_eq!(s.wnd, 65535 << 7);
+    }
+
+    #[test]
+    fn test_is_seq_acceptable() {
+        let mut rcv = ReceiveSequenceSpace {
+            up: false,
+            up_seq: 0,
+            wnd: 100,
+            wnd_shift: 0,
+            nxt: u32::MAX - 10,
+            irs: 0,
+        };
+        assert!(is_seq_acceptable(&rcv, u32::MAX, Some(10)));
+        assert!(is_seq_acceptable(&rcv, 50, None));
+        assert!(!is_seq_acceptable(&rcv, 90, None));
+        // the last octet falls in the window
+        assert!(is_seq_acceptable(&rcv, u32::MAX - 20, Some(11)));
+        assert!(!is_seq_acceptable(&rcv, u32::MAX - 20, Some(10)));
+
+        rcv.wnd = 0;
+        assert!(is_seq_acceptable(&rcv, u32::MAX - 10, None));
+        assert!(!is_seq_acceptable(&rcv, u32::MAX - 10, Some(1)));
+    }
+
+    #[test]
+    fn test_update_send_window_wrapping() {
+        let mut s = snd(u32::MAX - 10, 20, u32::MAX - 5, u32::MAX - 10);
+        assert!(update_send_window(&mut s, 3, 5, 42));
+        assert_eq!((s.wnd, s.wl1, s.wl2), (42, 3, 5));
+        assert!(!update_send_window(&mut s, u32::MAX, 10, 0));
+        assert_eq!(s.wnd, 42);
+    }
+}
@@ -0,0 +1,165 @@
+//! The transmission control block, https://www.ietf.org/rfc/rfc793.txt page 19: what names a
+//! connection and the sequence variables of each side, which `SynRecv` and `Established` hold, as
+//! does `SynSent` of the active open.
+
+use core::net::Ipv4Addr;
+
+#[derive(PartialEq, Eq, Debug, Clone, Hash)]
+pub struct ConnectionID {
+    pub src_addr: Ipv4Addr,
+    pub src_port: u16,
+    pub dst_addr: Ipv4Addr,
+    pub dst_port: u16,
+}
+
+/// Send Sequence Variables
+///
+/// SND.UNA - send unacknowledged
+/// SND.NXT - send next
+/// SND.WND - send window
+/// SND.UP  - send urgent pointer
+/// SND.WL1 - segment sequence number used for last window update
+/// SND.WL2 - segment acknowledgment number used for last window update
+/// ISS     - initial send sequence number
+///
+/// 1         2          3          4
+/// ----------|----------|----------|----------
+///         SND.UNA    SND.NXT    SND.UNA
+///                              +SND.WND
+/// 1 - old sequence numbers which have been acknowledged
+/// 2 - sequence numbers of unacknowledged data
+/// 3 - sequence numbers allowed for new data transmission
+/// 4 - future sequence numbers which are not yet allowed
+#[derive(PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct SendSequenceSpace {
+    /// Whether urgent data was sent and SND.UP is not acknowledged yet
+    pub up: bool,
+    /// SND.UP, the sequence number following the last urgent octet, only valid when `up` is set
+    pub up_seq: u32,
+    pub wnd: u32,
+    /// Snd.Wind.Shift, the scale of the windows advertised by the peer, see
+    /// https://www.ietf.org/rfc/rfc7323.txt 2.3
+    pub wnd_shift: u8,
+    pub una: u32,
+    pub nxt: u32,
+    pub wl1: u32,
+    pub wl2: u32,
+    pub iss: u32,
+}
+
+/// 1          2          3
+/// ----------|----------|----------
+///        RCV.NXT    RCV.NXT
+///                  +RCV.WND
+/// 1 - old sequence numbers which have been acknowledged
+/// 2 - sequence numbers allowed for new reception
+/// 3 - future sequence numbers which are not yet allowed
+#[derive(PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct ReceiveSequenceSpace {
+    /// Whether the peer signalled urgent data that is not delivered yet
+    pub up: bool,
+    /// RCV.UP, the sequence number following the last urgent octet, only valid when `up` is set
+    pub up_seq: u32,
+    pub wnd: u32,
+    /// Rcv.Wind.Shift, the scale of the windows we advertise
+    pub wnd_shift: u8,
+    pub nxt: u32,
+    pub irs: u32,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct SynRecv {
+    pub snd: SendSequenceSpace,
+    pub rcv: ReceiveSequenceSpace,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct Established {
+    pub snd: SendSequenceSpace,
+    pub rcv: ReceiveSequenceSpace,
+}
+
+impl AsRef<SendSequenceSpace> for SynRecv {
+    fn as_ref(&self) -> &SendSequenceSpace {
+        &self.snd
+    }
+}
+
+impl AsRef<SendSequenceSpace> for Established {
+    fn as_ref(&self) -> &SendSequenceSpace {
+        &self.snd
+    }
+}
+
+impl AsRef<ReceiveSequenceSpace> for SynRecv {
+    fn as_ref(&self) -> &ReceiveSequenceSpace {
+        &self.rcv
+    }
+}
+
+impl AsRef<ReceiveSequenceSpace> for Established {
+    fn as_ref(&self) -> &ReceiveSequenceSpace {
+        &self.rcv
+    }
+}
+
+/// How far the handshake went in a state: whether our SYN is acknowledged, and whether the SYN of the
+/// peer was received, RCV.NXT and IRS meaning nothing before. The sequence spaces can't tell the
+/// former: SND.UNA is back at ISS once 2^32 octets were acknowledged.
+pub trait Synchronized {
+    const SYN_ACKED: bool;
+    const SYN_RECEIVED: bool = true;
+}
+
+impl Synchronized for SynRecv {
+    const SYN_ACKED: bool = false;
+}
+
+impl Synchronized for Established {
+    const SYN_ACKED: bool = true;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::tcb::{Established, ReceiveSequenceSpace, SendSequenceSpace, SynRecv};
+
+    #[test]
+    fn test_transmute() {
+        let sr = SynRecv {
+            snd: SendSequenceSpace {
+                up: true,
+                up_seq: 100,
+                wnd: 10,
+                wnd_shift: 0,
+                una: 20,
+                nxt: 30,
+                wl1: 40,
+                wl2: 50,
+                iss: 60,
+            },
+            rcv: ReceiveSequenceSpace {
+                up: true,
+                up_seq: 110,
+                wnd: 70,
+                wnd_shift: 0,
+                nxt: 80,
+                irs: 90,
+            },
+        };
+
+        let tr = unsafe { core::mem::transmute::<SynRecv, Established>(sr) };
+
+        assert!(tr.snd.up);
+        assert_eq!(tr.snd.up_seq, 100);
+        assert_eq!(tr.snd.wnd, 10);
+        assert_eq!(tr.snd.una, 20);
+        assert_eq!(tr.snd.nxt, 30);
+        assert_eq!(tr.snd.wl1, 40);
+        assert_eq!(tr.snd.wl2, 50);
+        assert_eq!(tr.snd.iss, 60);
+    }
+}
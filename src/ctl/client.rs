@@ -0,0 +1,159 @@
+//! The typed side of the command channel, for applications embedding the stack. Every call sends a
+//! command to the event loop running the stack and waits for its reply, so the connections are only
+//! ever touched by the loop.
+
+use crate::config::Reloaded;
+use crate::ctl::{Command, ConnectionSummary, Reply, Request};
+use crate::tcp::info::TcpInfo;
+use crate::tcp::ConnectionID;
+use std::io;
+use std::net::SocketAddrV4;
+use std::sync::mpsc::{self, Sender};
+
+#[derive(Debug, Clone)]
+pub struct Client {
+    ctl: Sender<Request>,
+}
+
+impl Client {
+    /// A client of the event loop receiving the requests of `ctl`.
+    pub fn new(ctl: Sender<Request>) -> Self {
+        Self { ctl }
+    }
+
+    /// Accepts the connections to `port`.
+    pub fn bind(&self, port: u16) -> io::Result<()> {
+        self.call(Command::Bind(port)).map(|_| ())
+    }
+
+    /// Opens a connection to `remote`, returns it once the SYN is sent. `send` fails with
+    /// `WouldBlock` until the handshake completes, `query` tells the state.
+    pub fn connect(&self, remote: SocketAddrV4) -> io::Result<ConnectionID> {
+        match self.call(Command::Connect(remote))? {
+            Reply::Connected(id) => Ok(id),
+            _ => Err(unexpected()),
+        }
+    }
+
+    /// Queues `data` on the connection `id`, returns the bytes the send buffer took. Fails with
+    /// `WouldBlock` when it's full.
+    pub fn send(&self, id: &ConnectionID, data: &[u8]) -> io::Result<usize> {
+        match self.call(Command::Send(id.clone(), data.to_vec()))? {
+            Reply::Sent(0) if !data.is_empty() => Err(io::ErrorKind::WouldBlock.into()),
+            Reply::Sent(n) => Ok(n),
+            _ => Err(unexpected()),
+        }
+    }
+
+    /// Closes the connection `id`.
+    pub fn close(&self, id: &ConnectionID) -> io::Result<()> {
+        self.call(Command::Close(id.clone())).map(|_| ())
+    }
+
+    /// The state of the connection `id`.
+    pub fn query(&self, id: &ConnectionID) -> io::Result<ConnectionSummary> {
+        match self.call(Command::Query(id.clone()))? {
+            Reply::Connections(mut conns) if conns.len() == 1 => Ok(conns.remove(0)),
+            _ => Err(unexpected()),
+        }
+    }
+
+    /// The state and the measurements of the connection `id`.
+    pub fn info(&self, id: &ConnectionID) -> io::Result<TcpInfo> {
+        match self.call(Command::Info(id.clone()))? {
+            Reply::Info(info) => Ok(info),
+            _ => Err(unexpected()),
+        }
+    }
+
+    /// Reads the configuration file of the stack again, see `config`.
+    pub fn reload(&self) -> io::Result<Reloaded> {
+        match self.call(Command::Reload)? {
+            Reply::Reloaded(reloaded) => Ok(reloaded),
+            _ => Err(unexpected()),
+        }
+    }
+
+    /// Sends `command` to the event loop and waits for the reply, an error reply failing the call.
+    pub fn call(&self, command: Command) -> io::Result<Reply> {
+        let (reply, rx) = mpsc::channel();
+        self.ctl
+            .send(Request { command, reply })
+            .map_err(|_| stopped())?;
+        match rx.recv().map_err(|_| stopped())? {
+            Reply::Error(e) => Err(io::Error::other(e)),
+            reply => Ok(reply),
+        }
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the stack is not running")
+}
+
+fn unexpected() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "unexpected reply")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ctl::client::Client;
+    use crate::ctl::{Command, Reply};
+    use crate::tcp::ConnectionID;
+    use std::io::ErrorKind;
+    use std::net::Ipv4Addr;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn test_client() {
+        let (tx, rx) = mpsc::channel();
+        let client = Client::new(tx);
+        // stands in for the event loop: the send buffer is full after 4 bytes
+        let stack = thread::spawn(move || {
+            let mut queued = 0;
+            for request in rx {
+                let reply = match request.command {
+                    Command::Bind(80) => Reply::Ok,
+                    Command::Connect(remote) => Reply::Connected(ConnectionID {
+                        src_addr: *remote.ip(),
+                        src_port: remote.port(),
+                        dst_addr: Ipv4Addr::new(192, 167, 1, 1),
+                        dst_port: 40000,
+                    }),
+                    Command::Send(_, data) => {
+                        let n = data.len().min(4 - queued);
+                        queued += n;
+                        Reply::Sent(n)
+                    }
+                    _ => Reply::Error("no connection".to_string()),
+                };
+                request.reply.send(reply).unwrap();
+            }
+        });
+
+        let id = ConnectionID {
+            src_addr: Ipv4Addr::new(192, 167, 1, 2),
+            src_port: 4000,
+            dst_addr: Ipv4Addr::new(192, 167, 1, 1),
+            dst_port: 80,
+        };
+        client.bind(80).unwrap();
+        let remote = "192.167.1.2:4000".parse().unwrap();
+        let connected = client.connect(remote).unwrap();
+        assert_eq!(
+            (connected.src_addr, connected.src_port),
+            (*remote.ip(), 4000)
+        );
+        assert_eq!(client.send(&id, b"abc").unwrap(), 3);
+        assert_eq!(client.send(&id, b"def").unwrap(), 1);
+        assert_eq!(
+            client.send(&id, b"g").unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        assert!(client.query(&id).is_err());
+
+        drop(client);
+        stack.join().unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+//! A tiny local HTTP JSON API on top of the control plane, the same commands as the admin socket but
+//! reachable over the network namespace so that test orchestration can drive the stack. Only the bits
+//! of HTTP/1.1 needed by curl-like clients are handled, every response closes the connection.
+//!
+//!   GET  /connections                                  list connections, states and fingerprints
+//!   POST /connections/kill?src=<ip:port>&dst=<ip:port>  abort a connection with RST
+//!   GET  /connections/diagnose?src=<ip:port>&dst=<ip:port> explain what limits a connection
+//!   GET  /connections/info?src=<ip:port>&dst=<ip:port> the state and the measurements of a connection
+//!   GET  /stats                                        all the counters of the stack
+//!   GET  /tunables                                     current values of the tunables
+//!   POST /tunables?<name>=<value>                      update tunables
+//!   POST /config/reload                                read the configuration file again
+//!   POST /md5?addr=<ip>[&key=<key>]                    set or remove the MD5 signature key of a peer
+//!   POST /ao?addr=<ip>&send_id=<id>&recv_id=<id>&key=<key> add a TCP-AO MKT for a peer
+//!   POST /ao/remove?addr=<ip>&send_id=<id>             remove the TCP-AO MKT of a peer
+
+use crate::ctl::{Command, Reply, Request};
+use crate::tcp::ao::Mkt;
+use crate::tcp::ConnectionID;
+use anyhow::{anyhow, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, SocketAddrV4, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+pub const DEFAULT_HTTP_ADDR: &str = "127.0.0.1:7878";
+/// Overrides the address the API listens on
+pub const HTTP_ADDR_ENV: &str = "MINI_TCP_HTTP_ADDR";
+
+/// Binds the API listener and serves it from a background thread, commands are forwarded to `ctl`.
+pub fn spawn(ctl: Sender<Request>) -> Result<()> {
+    let addr: SocketAddr = std::env::var(HTTP_ADDR_ENV)
+        .unwrap_or_else(|_| DEFAULT_HTTP_ADDR.to_string())
+        .parse()?;
+    let listener = TcpListener::bind(addr)?;
+    log::info!("http api listening on {addr:}");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream
+                .map_err(anyhow::Error::from)
+                .and_then(|s| serve(s, &ctl));
+            if let Err(e) = result {
+                log::debug!("http api request failed: {e:}");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(mut stream: TcpStream, ctl: &Sender<Request>) -> Result<()> {
+    let mut request_line = String::new();
+    let mut reader = BufReader::new(stream.try_clone()?);
+    reader.read_line(&mut request_line)?;
+    // drain the headers, the API never reads a body
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header == "\r\n" || header == "\n" {
+            break;
+        }
+    }
+
+    let (status, body) = match route(&request_line) {
+        Ok(command) => {
+            let (tx, rx) = mpsc::channel();
+            ctl.send(Request { command, reply: tx })?;
+            match rx.recv()? {
+                Reply::Error(e) => ("400 Bad Request", error_json(&e)),
+                reply => ("200 OK", reply_json(reply)),
+            }
+        }
+        Err(e) => ("404 Not Found", error_json(&e.to_string())),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {status:}\r\nContent-Type: application/json\r\nContent-Length: {:}\r\nConnection: close\r\n\r\n{body:}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+/// Maps the request line, e.g. `GET /stats HTTP/1.1`, to the control command.
+fn route(request_line: &str) -> Result<Command> {
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().ok_or_else(|| anyhow!("empty request"))?;
+    let target = parts.next().ok_or_else(|| anyhow!("no request target"))?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let params = query
+        .split('&')
+        .filter_map(|kv| kv.split_once('='))
+        .collect::<Vec<_>>();
+
+    match (method, path) {
+        ("GET", "/connections") => Ok(Command::ListConnections),
+        ("GET", "/stats") => Ok(Command::Stats),
+        ("GET", "/tunables") => Ok(Command::Tunables),
+        ("POST", "/config/reload") => Ok(Command::Reload),
+        ("POST", "/connections/kill") => Ok(Command::Kill(id_param(&params)?)),
+        ("GET", "/connections/diagnose") => Ok(Command::Diagnose(id_param(&params)?)),
+        ("GET", "/connections/info") => Ok(Command::Info(id_param(&params)?)),
+        ("POST", "/tunables") => {
+            let (name, value) = params
+                .first()
+                .ok_or_else(|| anyhow!("expect a single <name>=<value> query param"))?;
+            Ok(Command::SetTunable(name.to_string(), value.parse()?))
+        }
+        ("POST", "/md5") => {
+            let key = find_param(&params, "key").map(|key| key.as_bytes().to_vec());
+            Ok(Command::SetMd5Key(param(&params, "addr")?.parse()?, key))
+        }
+        ("POST", "/ao") => Ok(Command::AddAoKey(
+            param(&params, "addr")?.parse()?,
+            Mkt {
+                send_id: param(&params, "send_id")?.parse()?,
+                recv_id: param(&params, "recv_id")?.parse()?,
+                key: param(&params, "key")?.as_bytes().to_vec(),
+            },
+        )),
+        ("POST", "/ao/remove") => Ok(Command::RemoveAoKey(
+            param(&params, "addr")?.parse()?,
+            param(&params, "send_id")?.parse()?,
+        )),
+        _ => Err(anyhow!("no route for {method:} {path:}")),
+    }
+}
+
+/// The connection given by the `src` and `dst` query params.
+fn id_param(params: &[(&str, &str)]) -> Result<ConnectionID> {
+    let src: SocketAddrV4 = param(params, "src")?.parse()?;
+    let dst: SocketAddrV4 = param(params, "dst")?.parse()?;
+    Ok(ConnectionID {
+        src_addr: *src.ip(),
+        src_port: src.port(),
+        dst_addr: *dst.ip(),
+        dst_port: dst.port(),
+    })
+}
+
+/// The value of the query param `name`.
+fn find_param<'a>(params: &[(&str, &'a str)], name: &str) -> Option<&'a str> {
+    params.iter().find(|(k, _)| *k == name).map(|(_, v)| *v)
+}
+
+/// Like `find_param`, the param being required.
+fn param<'a>(params: &[(&str, &'a str)], name: &str) -> Result<&'a str> {
+    find_param(params, name).ok_or_else(|| anyhow!("missing query param: {name:}"))
+}
+
+fn reply_json(reply: Reply) -> String {
+    match reply {
+        Reply::Connections(conns) => {
+            let items = conns
+                .iter()
+                .map(|c| {
+                    let fingerprint = c
+                        .fingerprint
+                        .as_ref()
+                        .map(|f| format!(r#""{f:}""#))
+                        .unwrap_or_else(|| "null".to_string());
+                    format!(
+                        r#"{{"src":"{:}:{:}","dst":"{:}:{:}","state":"{:}","fingerprint":{:}}}"#,
+                        c.id.src_addr,
+                        c.id.src_port,
+                        c.id.dst_addr,
+                        c.id.dst_port,
+                        c.state,
+                        fingerprint
+                    )
+                })
+                .collect::<Vec<_>>();
+            format!("[{:}]", items.join(","))
+        }
+        Reply::Stats(stats) => object_json(&stats.counters()),
+        Reply::Tunables(tunables) => object_json(&tunables.values()),
+        Reply::Diagnosis(diagnosis) => {
+            let limits = diagnosis
+                .limits
+                .iter()
+                .map(|l| format!(r#""{l:}""#))
+                .collect::<Vec<_>>();
+            let values = object_json(&diagnosis.values());
+            // splice the limits into the object
+            format!(
+                r#"{:},"limits":[{:}]}}"#,
+                &values[..values.len() - 1],
+                limits.join(",")
+            )
+        }
+        Reply::Info(info) => {
+            let values = object_json(&info.values());
+            // splice the state into the object
+            format!(r#"{{"state":"{:}",{:}"#, info.state, &values[1..])
+        }
+        Reply::Reloaded(reloaded) => format!(
+            r#"{{"applied":[{:}],"restart":[{:}]}}"#,
+            list_json(&reloaded.applied),
+            list_json(&reloaded.restart)
+        ),
+        Reply::Sent(n) => format!(r#"{{"sent":{n:}}}"#),
+        Reply::Connected(id) => format!(
+            r#"{{"src":"{:}:{:}","dst":"{:}:{:}"}}"#,
+            id.src_addr, id.src_port, id.dst_addr, id.dst_port
+        ),
+        Reply::Ok => r#"{"ok":true}"#.to_string(),
+        Reply::Error(e) => error_json(&e),
+    }
+}
+
+fn object_json(fields: &[(&str, u64)]) -> String {
+    let fields = fields
+        .iter()
+        .map(|(k, v)| format!(r#""{k:}":{v:}"#))
+        .collect::<Vec<_>>();
+    format!("{{{:}}}", fields.join(","))
+}
+
+fn list_json(names: &[String]) -> String {
+    names
+        .iter()
+        .map(|name| format!(r#""{name:}""#))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn error_json(e: &str) -> String {
+    format!(
+        r#"{{"error":"{:}"}}"#,
+        e.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ctl::http::{object_json, route};
+    use crate::ctl::Command;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_route() {
+        assert!(matches!(
+            route("GET /stats HTTP/1.1\r\n").unwrap(),
+            Command::Stats
+        ));
+        assert!(route("DELETE /stats HTTP/1.1\r\n").is_err());
+        assert!(matches!(
+            route("POST /config/reload HTTP/1.1\r\n").unwrap(),
+            Command::Reload
+        ));
+
+        match route("POST /connections/kill?src=192.167.1.2:4000&dst=192.167.1.1:80 HTTP/1.1")
+            .unwrap()
+        {
+            Command::Kill(id) => {
+                assert_eq!(id.src_addr, Ipv4Addr::new(192, 167, 1, 2));
+                assert_eq!(id.src_port, 4000);
+                assert_eq!(id.dst_addr, Ipv4Addr::new(192, 167, 1, 1));
+                assert_eq!(id.dst_port, 80);
+            }
+            _ => panic!("expect kill"),
+        }
+        assert!(route("POST /connections/kill?src=192.167.1.2:4000 HTTP/1.1").is_err());
+        assert!(matches!(
+            route("GET /connections/diagnose?src=192.167.1.2:4000&dst=192.167.1.1:80 HTTP/1.1")
+                .unwrap(),
+            Command::Diagnose(_)
+        ));
+
+        match route("POST /tunables?window_size=1024 HTTP/1.1").unwrap() {
+            Command::SetTunable(name, value) => {
+                assert_eq!(name, "window_size");
+                assert_eq!(value, 1024);
+            }
+            _ => panic!("expect set tunable"),
+        }
+        assert!(matches!(
+            route("POST /md5?addr=192.167.1.2&key=secret HTTP/1.1").unwrap(),
+            Command::SetMd5Key(_, Some(_))
+        ));
+        assert!(route("POST /md5?key=secret HTTP/1.1").is_err());
+        assert!(matches!(
+            route("POST /ao?addr=192.167.1.2&send_id=1&recv_id=2&key=secret HTTP/1.1").unwrap(),
+            Command::AddAoKey(_, _)
+        ));
+        assert!(matches!(
+            route("POST /ao/remove?addr=192.167.1.2&send_id=1 HTTP/1.1").unwrap(),
+            Command::RemoveAoKey(_, 1)
+        ));
+    }
+
+    #[test]
+    fn test_object_json() {
+        assert_eq!(object_json(&[]), "{}");
+        assert_eq!(object_json(&[("a", 1), ("b", 2)]), r#"{"a":1,"b":2}"#);
+    }
+}
@@ -0,0 +1,80 @@
+//! The control plane of the stack. Commands are submitted from other threads through an mpsc channel
+//! and executed by the main loop in between packets, so the connection map is never shared.
+//! The commands can be issued through the unix admin socket or, with the `http-api` feature,
+//! through a local HTTP JSON API. Embedders drive the connections through the same channel with a
+//! `client::Client`, instead of touching the connection map.
+
+pub mod client;
+#[cfg(feature = "http-api")]
+pub mod http;
+#[cfg(unix)]
+pub mod unix;
+
+use crate::config::Reloaded;
+use crate::stats::Stats;
+use crate::tcp::ao::Mkt;
+use crate::tcp::diagnostics::Diagnosis;
+use crate::tcp::info::TcpInfo;
+use crate::tcp::{ConnectionID, Tunables};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::mpsc::Sender;
+
+pub enum Command {
+    ListConnections,
+    Stats,
+    /// Aborts the connection with a RST and removes it from the connection map
+    Kill(ConnectionID),
+    Tunables,
+    SetTunable(String, u64),
+    /// Sets or, without a key, removes the MD5 signature key of a peer
+    SetMd5Key(Ipv4Addr, Option<Vec<u8>>),
+    /// Adds a TCP-AO MKT for a peer, replacing the one with the same SendID
+    AddAoKey(Ipv4Addr, Mkt),
+    /// Removes the TCP-AO MKT of a peer with the SendID
+    RemoveAoKey(Ipv4Addr, u8),
+    /// Explains what limits the throughput of the connection
+    Diagnose(ConnectionID),
+    /// The state and the measurements of the connection, like `TCP_INFO`
+    Info(ConnectionID),
+    /// Reads the configuration file again, see `config`
+    Reload,
+    /// Accepts the connections to the port. Until a port is bound, every port accepts connections.
+    Bind(u16),
+    /// Opens a connection to the address, replied with the connection in SYN-SENT
+    Connect(SocketAddrV4),
+    /// Queues the data on the connection, replied with the bytes the send buffer took
+    Send(ConnectionID, Vec<u8>),
+    /// Closes the connection once the data queued is sent
+    Close(ConnectionID),
+    /// The state of the connection
+    Query(ConnectionID),
+}
+
+pub enum Reply {
+    Connections(Vec<ConnectionSummary>),
+    Stats(Stats),
+    Tunables(Tunables),
+    Diagnosis(Diagnosis),
+    Info(TcpInfo),
+    Reloaded(Reloaded),
+    /// The bytes queued by a `Send`, none when the send buffer is full
+    Sent(usize),
+    /// The connection opened by a `Connect`, writable once the handshake completes
+    Connected(ConnectionID),
+    Ok,
+    Error(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionSummary {
+    pub id: ConnectionID,
+    pub state: &'static str,
+    /// The p0f like signature of the peer SYN
+    pub fingerprint: Option<String>,
+}
+
+/// A command together with the channel the main loop replies on.
+pub struct Request {
+    pub command: Command,
+    pub reply: Sender<Reply>,
+}
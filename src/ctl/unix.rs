@@ -0,0 +1,233 @@
+//! The line based admin socket, always available. One command per line, e.g. with
+//! `socat - UNIX-CONNECT:/tmp/mini-tcp.sock`:
+//!
+//!   connections                        list connections, their states and peer fingerprints
+//!   kill <src ip:port> <dst ip:port>   abort a connection with RST
+//!   stats                              all the counters of the stack
+//!   tunables                           current values of the tunables
+//!   set <name> <value>                 update a tunable
+//!   md5 <ip> [key]                     set the MD5 signature key of a peer, remove it without a key
+//!   ao <ip> <send id> <recv id> <key>  add a TCP-AO MKT for a peer
+//!   ao <ip> <send id>                  remove the TCP-AO MKT of a peer
+//!   diagnose <src ip:port> <dst ip:port> explain what limits the throughput of a connection
+//!   info <src ip:port> <dst ip:port>   the state and the measurements of a connection
+//!   query <src ip:port> <dst ip:port>  the state of a connection
+//!   bind <port>                        accept connections to the port, refuse the ports not bound
+//!   reload                             read the configuration file again, as SIGHUP does
+
+use crate::ctl::{Command, Reply, Request};
+use crate::tcp::ao::Mkt;
+use crate::tcp::ConnectionID;
+use anyhow::{anyhow, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::SocketAddrV4;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+pub const DEFAULT_CTL_SOCK: &str = "/tmp/mini-tcp.sock";
+/// Overrides the path of the admin socket
+pub const CTL_SOCK_ENV: &str = "MINI_TCP_CTL_SOCK";
+
+/// Binds the admin socket and serves it from a background thread, commands are forwarded to `ctl`.
+pub fn spawn(ctl: Sender<Request>) -> Result<()> {
+    let path = std::env::var(CTL_SOCK_ENV).unwrap_or_else(|_| DEFAULT_CTL_SOCK.to_string());
+    // a stale socket from the previous run would fail the bind
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    log::info!("admin socket listening on {path:}");
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let ctl = ctl.clone();
+            let result = stream.map_err(anyhow::Error::from).map(|s| {
+                thread::spawn(move || {
+                    if let Err(e) = serve(s, &ctl) {
+                        log::debug!("admin socket session failed: {e:}");
+                    }
+                })
+            });
+            if let Err(e) = result {
+                log::debug!("admin socket accept failed: {e:}");
+            }
+        }
+    });
+    Ok(())
+}
+
+fn serve(stream: UnixStream, ctl: &Sender<Request>) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let out = match parse(&line) {
+            Ok(command) => {
+                let (tx, rx) = mpsc::channel();
+                ctl.send(Request { command, reply: tx })?;
+                render(rx.recv()?)
+            }
+            Err(e) => format!("error: {e:}\n"),
+        };
+        writer.write_all(out.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn parse(line: &str) -> Result<Command> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    match words.as_slice() {
+        ["connections"] => Ok(Command::ListConnections),
+        ["stats"] => Ok(Command::Stats),
+        ["tunables"] => Ok(Command::Tunables),
+        ["reload"] => Ok(Command::Reload),
+        ["kill", src, dst] => Ok(Command::Kill(parse_id(src, dst)?)),
+        ["diagnose", src, dst] => Ok(Command::Diagnose(parse_id(src, dst)?)),
+        ["info", src, dst] => Ok(Command::Info(parse_id(src, dst)?)),
+        ["query", src, dst] => Ok(Command::Query(parse_id(src, dst)?)),
+        ["bind", port] => Ok(Command::Bind(port.parse()?)),
+        ["set", name, value] => Ok(Command::SetTunable(name.to_string(), value.parse()?)),
+        ["md5", addr] => Ok(Command::SetMd5Key(addr.parse()?, None)),
+        ["md5", addr, key] => Ok(Command::SetMd5Key(
+            addr.parse()?,
+            Some(key.as_bytes().to_vec()),
+        )),
+        ["ao", addr, send_id] => Ok(Command::RemoveAoKey(addr.parse()?, send_id.parse()?)),
+        ["ao", addr, send_id, recv_id, key] => Ok(Command::AddAoKey(
+            addr.parse()?,
+            Mkt {
+                send_id: send_id.parse()?,
+                recv_id: recv_id.parse()?,
+                key: key.as_bytes().to_vec(),
+            },
+        )),
+        _ => Err(anyhow!("unknown command: {line:}")),
+    }
+}
+
+fn parse_id(src: &str, dst: &str) -> Result<ConnectionID> {
+    let src: SocketAddrV4 = src.parse()?;
+    let dst: SocketAddrV4 = dst.parse()?;
+    Ok(ConnectionID {
+        src_addr: *src.ip(),
+        src_port: src.port(),
+        dst_addr: *dst.ip(),
+        dst_port: dst.port(),
+    })
+}
+
+fn render(reply: Reply) -> String {
+    match reply {
+        Reply::Connections(conns) => conns
+            .iter()
+            .map(|c| {
+                format!(
+                    "{:}:{:} {:}:{:} {:} {:}\n",
+                    c.id.src_addr,
+                    c.id.src_port,
+                    c.id.dst_addr,
+                    c.id.dst_port,
+                    c.state,
+                    c.fingerprint.as_deref().unwrap_or("-")
+                )
+            })
+            .collect(),
+        Reply::Stats(stats) => render_values(&stats.counters()),
+        Reply::Tunables(tunables) => render_values(&tunables.values()),
+        Reply::Diagnosis(diagnosis) => {
+            let limits = diagnosis
+                .limits
+                .iter()
+                .map(|l| l.to_string())
+                .collect::<Vec<_>>();
+            let limits = if limits.is_empty() {
+                "-".to_string()
+            } else {
+                limits.join(",")
+            };
+            format!("{:}limits {limits:}\n", render_values(&diagnosis.values()))
+        }
+        Reply::Info(info) => format!("state {:}\n{:}", info.state, render_values(&info.values())),
+        Reply::Reloaded(reloaded) => format!(
+            "applied {:}\nrestart {:}\n",
+            render_list(&reloaded.applied),
+            render_list(&reloaded.restart)
+        ),
+        Reply::Sent(n) => format!("sent {n:}\n"),
+        Reply::Connected(id) => format!(
+            "connected {:}:{:} {:}:{:}\n",
+            id.src_addr, id.src_port, id.dst_addr, id.dst_port
+        ),
+        Reply::Ok => "ok\n".to_string(),
+        Reply::Error(e) => format!("error: {e:}\n"),
+    }
+}
+
+fn render_list(names: &[String]) -> String {
+    if names.is_empty() {
+        return "-".to_string();
+    }
+    names.join(",")
+}
+
+fn render_values(values: &[(&str, u64)]) -> String {
+    values.iter().map(|(k, v)| format!("{k:} {v:}\n")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ctl::unix::parse;
+    use crate::ctl::Command;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_parse() {
+        assert!(matches!(parse("stats").unwrap(), Command::Stats));
+        assert!(matches!(
+            parse(" connections ").unwrap(),
+            Command::ListConnections
+        ));
+        assert!(matches!(
+            parse("kill 192.167.1.2:4000 192.167.1.1:80").unwrap(),
+            Command::Kill(_)
+        ));
+        assert!(parse("kill 192.167.1.2:4000").is_err());
+        assert!(matches!(
+            parse("diagnose 192.167.1.2:4000 192.167.1.1:80").unwrap(),
+            Command::Diagnose(_)
+        ));
+        assert!(matches!(
+            parse("info 192.167.1.2:4000 192.167.1.1:80").unwrap(),
+            Command::Info(_)
+        ));
+        assert!(matches!(parse("bind 80").unwrap(), Command::Bind(80)));
+        assert!(matches!(parse("reload").unwrap(), Command::Reload));
+        assert!(parse("bind 65536").is_err());
+        assert!(parse("set window_size abc").is_err());
+        match parse("md5 192.167.1.2 secret").unwrap() {
+            Command::SetMd5Key(addr, key) => {
+                assert_eq!(addr, Ipv4Addr::new(192, 167, 1, 2));
+                assert_eq!(key.as_deref(), Some(&b"secret"[..]));
+            }
+            _ => panic!("expect md5 key"),
+        }
+        assert!(matches!(
+            parse("md5 192.167.1.2").unwrap(),
+            Command::SetMd5Key(_, None)
+        ));
+        match parse("ao 192.167.1.2 1 2 secret").unwrap() {
+            Command::AddAoKey(_, mkt) => {
+                assert_eq!((mkt.send_id, mkt.recv_id), (1, 2));
+                assert_eq!(mkt.key, b"secret");
+            }
+            _ => panic!("expect ao key"),
+        }
+        assert!(matches!(
+            parse("ao 192.167.1.2 1").unwrap(),
+            Command::RemoveAoKey(_, 1)
+        ));
+        assert!(parse("ao 192.167.1.2 256 2 secret").is_err());
+        assert!(parse("reboot").is_err());
+    }
+}
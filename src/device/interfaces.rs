@@ -0,0 +1,439 @@
+//! A stack on several devices at once, each an interface with its own address, instead of a single
+//! point-to-point tun device. `Interfaces` is a `NetworkDevice` itself, so the stack runs on it as on
+//! any other: the packets sent leave through the interface their route picks, see `ip::route`, and
+//! those received are read from the interfaces in turn, for a busy one not to starve the others.
+//!
+//! The packets the stack sends carry the address the peer reached it at as their source; the
+//! connections it opens take theirs from the route to the peer, see `source_address`. A packet is
+//! received when addressed to a local address, https://www.ietf.org/rfc/rfc1122.txt 3.2.1.3: any of
+//! them in the weak host model, the default, only the receiving interface's in the strong one, which
+//! also sends the packets only through the interface of their source address, RFC 1122 3.3.4.2. The
+//! others are dropped.
+//!
+//! ```
+//! use mini_tcp::device::{Interfaces, MemoryDevice, NetworkDevice};
+//! use mini_tcp::ip::route::Route;
+//! use std::net::Ipv4Addr;
+//! # fn main() -> anyhow::Result<()> {
+//! let mut interfaces = Interfaces::new();
+//! let lan = interfaces.add("lan0", MemoryDevice::new(), Ipv4Addr::new(192, 168, 1, 2), 24);
+//! let wan = interfaces.add("wan0", MemoryDevice::new(), Ipv4Addr::new(203, 0, 113, 5), 30);
+//! interfaces.add_route(Route::default_route(wan))?;
+//! assert_eq!(interfaces.route(Ipv4Addr::new(192, 168, 1, 9)).unwrap().interface, lan);
+//! assert_eq!(
+//!     interfaces.source_address(Ipv4Addr::new(8, 8, 8, 8)),
+//!     Some(Ipv4Addr::new(203, 0, 113, 5))
+//! );
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::device::{ChecksumOffload, NetworkDevice};
+use crate::ip::route::{Route, RoutingTable};
+use crate::ETH_HEADER_OFFSET;
+use anyhow::{anyhow, Result};
+use std::cell::Cell;
+use std::io::{self, ErrorKind};
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// How long `wait_readable` waits on an interface before checking the others
+const POLL_SLICE: Duration = Duration::from_millis(1);
+
+/// Which packets an interface receives, and which it sends, https://www.ietf.org/rfc/rfc1122.txt 3.3.4.2
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum HostModel {
+    /// The packets to any local address, the packets sent leaving through the route to their
+    /// destination whatever their source
+    #[default]
+    Weak,
+    /// The packets to the interface's address, the packets sent leaving through the interface of their
+    /// source address
+    Strong,
+}
+
+/// A device of the stack and its address
+#[derive(Debug)]
+pub struct Interface<D> {
+    name: String,
+    addr: Ipv4Addr,
+    prefix_len: u8,
+    device: D,
+}
+
+impl<D> Interface<D> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn device(&self) -> &D {
+        &self.device
+    }
+}
+
+/// The interfaces of a stack and the routes through them. The devices are expected to be
+/// non-blocking, a device without a packet failing `recv` with `WouldBlock`, as `Nic`s set up by
+/// `TcpStackBuilder` are; devices of different kinds are boxed, `Interfaces<Box<dyn NetworkDevice>>`.
+#[derive(Debug)]
+pub struct Interfaces<D> {
+    interfaces: Vec<Interface<D>>,
+    routes: RoutingTable,
+    host_model: HostModel,
+    /// The interface `recv` reads first
+    next: Cell<usize>,
+    /// The packets received dropped for a destination not local
+    dropped: Cell<u64>,
+}
+
+impl<D> Default for Interfaces<D> {
+    fn default() -> Self {
+        Self {
+            interfaces: vec![],
+            routes: RoutingTable::default(),
+            host_model: HostModel::default(),
+            next: Cell::new(0),
+            dropped: Cell::new(0),
+        }
+    }
+}
+
+impl<D: NetworkDevice> Interfaces<D> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the interface `name` on `device`, with the address `addr`/`prefix_len`, and the route to
+    /// its subnet. Returns its index, which its routes refer to.
+    pub fn add(&mut self, name: &str, device: D, addr: Ipv4Addr, prefix_len: u8) -> usize {
+        let index = self.interfaces.len();
+        let prefix_len = prefix_len.min(32);
+        self.interfaces.push(Interface {
+            name: name.to_string(),
+            addr,
+            prefix_len,
+            device,
+        });
+        let subnet = Route::new(addr, prefix_len, index).expect("a prefix of 32 bits at most");
+        self.routes.add(subnet);
+        index
+    }
+
+    /// Adds `route`, replacing the one of the same prefix. Fails when its interface doesn't exist.
+    pub fn add_route(&mut self, route: Route) -> Result<()> {
+        if route.interface >= self.interfaces.len() {
+            return Err(anyhow!("no interface {:}", route.interface));
+        }
+        self.routes.add(route);
+        Ok(())
+    }
+
+    /// Removes the route of `dst`/`prefix_len`, returns whether there was one.
+    pub fn remove_route(&mut self, dst: Ipv4Addr, prefix_len: u8) -> bool {
+        self.routes.remove(dst, prefix_len)
+    }
+
+    /// The route the packets to `dst` take, none when no route matches.
+    pub fn route(&self, dst: Ipv4Addr) -> Option<&Route> {
+        self.routes.lookup(dst)
+    }
+
+    pub fn routes(&self) -> &RoutingTable {
+        &self.routes
+    }
+
+    pub fn interface(&self, index: usize) -> Option<&Interface<D>> {
+        self.interfaces.get(index)
+    }
+
+    pub fn interfaces(&self) -> &[Interface<D>] {
+        &self.interfaces
+    }
+
+    pub fn host_model(&self) -> HostModel {
+        self.host_model
+    }
+
+    pub fn set_host_model(&mut self, host_model: HostModel) {
+        self.host_model = host_model;
+    }
+
+    /// The packets received dropped for a destination which isn't local, or not the receiving
+    /// interface's in the strong host model.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.get()
+    }
+
+    /// The interface with the address `addr`.
+    fn owner(&self, addr: Ipv4Addr) -> Option<usize> {
+        self.interfaces.iter().position(|i| i.addr == addr)
+    }
+
+    /// The index of the interface `packet` leaves through, by the route to its destination, among the
+    /// routes through the interface of its source in the strong host model.
+    fn output(&self, packet: &[u8]) -> io::Result<usize> {
+        let (src, dst) = addresses(packet)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "not an ipv4 packet"))?;
+        let route = match (self.host_model, self.owner(src)) {
+            (HostModel::Strong, Some(owner)) => self.routes.lookup_via(dst, owner),
+            _ => self.routes.lookup(dst),
+        };
+        let route = route.ok_or_else(|| {
+            io::Error::new(ErrorKind::NetworkUnreachable, format!("no route to {dst:}"))
+        })?;
+        Ok(route.interface)
+    }
+
+    /// Whether the interface `index` receives `packet`, by its destination.
+    fn accepts(&self, index: usize, packet: &[u8]) -> bool {
+        let Some((_, dst)) = addresses(packet) else {
+            return false;
+        };
+        match self.host_model {
+            HostModel::Weak => self.owner(dst).is_some(),
+            HostModel::Strong => self.interfaces[index].addr == dst,
+        }
+    }
+}
+
+/// The source and the destination of the ip packet `packet`, none when it's too short.
+fn addresses(packet: &[u8]) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    let header = packet.get(ETH_HEADER_OFFSET..ETH_HEADER_OFFSET + 20)?;
+    let addr =
+        |at: usize| Ipv4Addr::new(header[at], header[at + 1], header[at + 2], header[at + 3]);
+    Some((addr(12), addr(16)))
+}
+
+impl<D: NetworkDevice> NetworkDevice for Interfaces<D> {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.interfaces.len();
+        for i in 0..count {
+            let index = (self.next.get() + i) % count;
+            match self.interfaces[index].device.recv(buf) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                // a frame the device dropped
+                Ok(0) => return Ok(0),
+                Ok(n) if !self.accepts(index, &buf[..n]) => {
+                    self.dropped.set(self.dropped.get() + 1);
+                    self.next.set(index + 1);
+                    return Ok(0);
+                }
+                read => {
+                    self.next.set(index + 1);
+                    return read;
+                }
+            }
+        }
+        Err(ErrorKind::WouldBlock.into())
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.interfaces[self.output(packet)?].device.send(packet)
+    }
+
+    /// Sends the packets in order through their interfaces, each interface sending its share at once.
+    fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<()> {
+        let mut queues = vec![vec![]; self.interfaces.len()];
+        for packet in packets {
+            queues[self.output(packet)?].push(packet.clone());
+        }
+        for (interface, queue) in self.interfaces.iter().zip(queues) {
+            if !queue.is_empty() {
+                interface.device.send_batch(&queue)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until an interface has a packet, checking them all and then waiting on each in turn for
+    /// `POLL_SLICE`, as a device can't wait on another's file descriptor.
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        if let [interface] = self.interfaces.as_slice() {
+            return interface.device.wait_readable(timeout);
+        }
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        // the first round only checks
+        let mut wait = Duration::ZERO;
+        loop {
+            for interface in &self.interfaces {
+                if interface.device.wait_readable(Some(wait))? {
+                    return Ok(true);
+                }
+            }
+            let left = deadline.map(|at| at.saturating_duration_since(Instant::now()));
+            if left == Some(Duration::ZERO) {
+                return Ok(false);
+            }
+            wait = left.map_or(POLL_SLICE, |left| left.min(POLL_SLICE));
+            if self.interfaces.is_empty() {
+                std::thread::sleep(wait);
+            }
+        }
+    }
+
+    /// The preferred source of the route to `dst`, or the address of its interface.
+    fn source_address(&self, dst: Ipv4Addr) -> Option<Ipv4Addr> {
+        let route = self.routes.lookup(dst)?;
+        Some(route.src.unwrap_or(self.interfaces[route.interface].addr))
+    }
+
+    /// The least MTU of the interfaces, as the packet to send isn't known here.
+    fn mtu(&self) -> Option<usize> {
+        self.interfaces
+            .iter()
+            .filter_map(|interface| interface.device.mtu())
+            .min()
+    }
+
+    /// The checksums every interface handles, as the interface of a packet isn't known here.
+    fn checksum_offload(&self) -> ChecksumOffload {
+        let all = |handles: fn(ChecksumOffload) -> bool| {
+            !self.interfaces.is_empty()
+                && self
+                    .interfaces
+                    .iter()
+                    .all(|interface| handles(interface.device.checksum_offload()))
+        };
+        ChecksumOffload {
+            rx: all(|offload| offload.rx),
+            tx: all(|offload| offload.tx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{HostModel, Interfaces, MemoryDevice, NetworkDevice};
+    use crate::ip::route::Route;
+    use crate::TCP_PROTOCOL;
+    use etherparse::Ipv4Header;
+    use std::io::ErrorKind;
+    use std::net::Ipv4Addr;
+
+    fn packet(dst: [u8; 4]) -> Vec<u8> {
+        let ip = Ipv4Header::new(0, 64, TCP_PROTOCOL, [192, 168, 1, 2], dst);
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        packet
+    }
+
+    fn interfaces() -> Interfaces<MemoryDevice> {
+        let mut interfaces = Interfaces::new();
+        interfaces.add(
+            "lan0",
+            MemoryDevice::new(),
+            Ipv4Addr::new(192, 168, 1, 2),
+            24,
+        );
+        interfaces.add(
+            "wan0",
+            MemoryDevice::with_mtu(1400),
+            Ipv4Addr::new(203, 0, 113, 5),
+            30,
+        );
+        interfaces
+    }
+
+    #[test]
+    fn test_send() {
+        let mut interfaces = interfaces();
+        let sent = |interfaces: &Interfaces<MemoryDevice>, index: usize| {
+            interfaces
+                .interface(index)
+                .unwrap()
+                .device()
+                .take_sent()
+                .len()
+        };
+        interfaces.send(&packet([192, 168, 1, 9])).unwrap();
+        assert_eq!((sent(&interfaces, 0), sent(&interfaces, 1)), (1, 0));
+        let err = interfaces.send(&packet([8, 8, 8, 8])).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NetworkUnreachable);
+
+        assert!(interfaces.add_route(Route::default_route(2)).is_err());
+        interfaces.add_route(Route::default_route(1)).unwrap();
+        let batch = [
+            packet([8, 8, 8, 8]),
+            packet([192, 168, 1, 9]),
+            packet([1, 1, 1, 1]),
+        ];
+        interfaces.send_batch(&batch).unwrap();
+        assert_eq!((sent(&interfaces, 0), sent(&interfaces, 1)), (1, 2));
+        assert_eq!(interfaces.mtu(), Some(1400));
+    }
+
+    #[test]
+    fn test_recv() {
+        let interfaces = interfaces();
+        let device = |index: usize| interfaces.interface(index).unwrap().device();
+        device(0).push(&packet([192, 168, 1, 2]));
+        device(0).push(&packet([192, 168, 1, 2]));
+        device(1).push(&packet([203, 0, 113, 5]));
+        assert!(interfaces.wait_readable(None).unwrap());
+        let mut buf = [0; 20];
+        let mut read = vec![];
+        while let Ok(n) = interfaces.recv(&mut buf) {
+            read.push((n, buf[16]));
+        }
+        // in turn
+        assert_eq!(read, [(20, 192), (20, 203), (20, 192)]);
+        assert!(!interfaces
+            .wait_readable(Some(std::time::Duration::ZERO))
+            .unwrap());
+    }
+
+    #[test]
+    fn test_host_model() {
+        let mut interfaces = interfaces();
+        interfaces.add_route(Route::default_route(1)).unwrap();
+        fn lan(interfaces: &Interfaces<MemoryDevice>) -> &MemoryDevice {
+            interfaces.interface(0).unwrap().device()
+        }
+        let mut buf = [0; 20];
+        // the wan address reached through the lan
+        lan(&interfaces).push(&packet([203, 0, 113, 5]));
+        lan(&interfaces).push(&packet([10, 0, 0, 1]));
+        assert_eq!(interfaces.recv(&mut buf).unwrap(), 20);
+        assert_eq!(interfaces.recv(&mut buf).unwrap(), 0);
+        assert_eq!(interfaces.dropped(), 1);
+
+        interfaces.set_host_model(HostModel::Strong);
+        lan(&interfaces).push(&packet([203, 0, 113, 5]));
+        assert_eq!(interfaces.recv(&mut buf).unwrap(), 0);
+        assert_eq!(interfaces.dropped(), 2);
+        // from the wan address to the lan, through the wan
+        let mut from_wan = packet([192, 168, 1, 9]);
+        from_wan[12..16].copy_from_slice(&[203, 0, 113, 5]);
+        interfaces.send(&from_wan).unwrap();
+        assert!(lan(&interfaces).take_sent().is_empty());
+        assert_eq!(
+            interfaces.interface(1).unwrap().device().take_sent().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_source_address() {
+        let mut interfaces = interfaces();
+        assert_eq!(interfaces.source_address(Ipv4Addr::new(8, 8, 8, 8)), None);
+        assert_eq!(
+            interfaces.source_address(Ipv4Addr::new(192, 168, 1, 9)),
+            Some(Ipv4Addr::new(192, 168, 1, 2))
+        );
+        let alias = Ipv4Addr::new(203, 0, 113, 6);
+        interfaces
+            .add_route(Route::default_route(1).with_src(alias))
+            .unwrap();
+        assert_eq!(
+            interfaces.source_address(Ipv4Addr::new(8, 8, 8, 8)),
+            Some(alias)
+        );
+    }
+}
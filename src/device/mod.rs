@@ -0,0 +1,518 @@
+//! What the stack sends its packets through and reads them from. The connections and `Stack` only
+//! see a `NetworkDevice`: a `Nic`, the tun or tap device, in production, a `MemoryDevice` in tests,
+//! which is fed the packets of the peer and keeps those sent to it, or a `LoopbackDevice` of a pair
+//! connecting two stacks in the same process.
+
+use crate::core::seq::wrapping_lt;
+use crate::tcp::ConnectionID;
+use crate::wire::SegmentView;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+mod interfaces;
+#[cfg(unix)]
+mod nic;
+pub use interfaces::{HostModel, Interface, Interfaces};
+#[cfg(unix)]
+pub use nic::{set_nonblocking, Nic};
+
+/// How many packets are read per wakeup, at most
+pub const BATCH_SIZE: usize = 32;
+
+/// The size of the buffers the packets are read into when the device doesn't tell its MTU, Ethernet's
+pub const DEFAULT_PACKET_SIZE: usize = 1500;
+
+/// The largest ipv4 packet, which a jumbo frame may carry whole
+pub const MAX_PACKET_SIZE: usize = 65535;
+
+/// The size of the buffers the packets of `device` are read into: its MTU, so the packets of a device
+/// with jumbo frames aren't truncated, from 1500 up to the largest ipv4 packet. A packet read truncated
+/// all the same is dropped by the stack, see `Stats::truncated_packets`.
+pub fn recv_buffer_size<D: NetworkDevice + ?Sized>(device: &D) -> usize {
+    device.mtu().map_or(DEFAULT_PACKET_SIZE, |mtu| {
+        mtu.clamp(DEFAULT_PACKET_SIZE, MAX_PACKET_SIZE)
+    })
+}
+
+/// The checksums a device computes for the stack, as virtio-net or a tun device with the offloads on
+/// do. The ip header checksum is always the stack's, as Linux's is, the device only taking the tcp one.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct ChecksumOffload {
+    /// The device drops the segments received with a bad tcp checksum, the stack checking it no more,
+    /// but in the datagrams it reassembles
+    pub rx: bool,
+    /// The device fills in the tcp checksum of the segments sent, the stack leaving the sum of their
+    /// pseudo header in it as CHECKSUM_PARTIAL does, but in the packets it fragments
+    pub tx: bool,
+}
+
+/// A device carrying ipv4 packets. The methods take `&self`, as tun_tap's do, so the stack can send
+/// while the device is shared with the thread reading it.
+pub trait NetworkDevice {
+    /// Reads a packet into `buf` and returns its length, 0 for a frame the device dropped. Fails with
+    /// `WouldBlock` when none is waiting on a non-blocking device.
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Sends the packet and returns its length.
+    fn send(&self, packet: &[u8]) -> io::Result<usize>;
+
+    /// Reads the packets waiting into `batch`, the first as `recv` does, the next ones until the device
+    /// would block or the batch is full. Returns how many were read, the device being non-blocking for
+    /// the reads after the first not to block.
+    fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        batch.read_from(self, usize::MAX)
+    }
+
+    /// Sends the packets in order. A device writing several with a syscall overrides it, the tun driver
+    /// takes one per write.
+    fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<()> {
+        for packet in packets {
+            self.send(packet)?;
+        }
+        Ok(())
+    }
+
+    /// Waits until a packet can be read or the timeout elapses, forever without one. Returns whether
+    /// one can.
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool>;
+
+    /// The largest packet the device sends, larger ones being fragmented, see `ip::send`. None when
+    /// unknown, the packets being sent whole.
+    fn mtu(&self) -> Option<usize> {
+        None
+    }
+
+    /// The source address of the connections opened to `dst`, by the route to it. None when the device
+    /// doesn't know its addresses, the stack then leaving the choice to the application.
+    fn source_address(&self, _dst: Ipv4Addr) -> Option<Ipv4Addr> {
+        None
+    }
+
+    /// The checksums the device handles, none by default, the stack computing and checking them all.
+    fn checksum_offload(&self) -> ChecksumOffload {
+        ChecksumOffload::default()
+    }
+}
+
+/// The buffers the packets of a wakeup are read into, see `NetworkDevice::recv_batch`. They are
+/// allocated once and reused by every batch.
+pub struct RecvBatch {
+    bufs: Vec<Vec<u8>>,
+    lens: Vec<usize>,
+}
+
+impl fmt::Debug for RecvBatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // the buffers are no use in a dump of the stack
+        f.debug_struct("RecvBatch")
+            .field("capacity", &self.bufs.len())
+            .field("len", &self.lens.len())
+            .finish()
+    }
+}
+
+impl RecvBatch {
+    /// A batch of `count` packets of `size` octets at most.
+    pub fn new(count: usize, size: usize) -> Self {
+        Self {
+            bufs: vec![vec![0u8; size]; count.max(1)],
+            lens: Vec::with_capacity(count.max(1)),
+        }
+    }
+
+    /// How many packets the last read got, the frames a device dropped included.
+    pub fn len(&self) -> usize {
+        self.lens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lens.is_empty()
+    }
+
+    /// The packets of the last read, in order.
+    pub fn packets(&self) -> impl Iterator<Item = &[u8]> {
+        self.bufs
+            .iter()
+            .zip(&self.lens)
+            .map(|(buf, n)| &buf[..*n])
+            .filter(|packet| !packet.is_empty())
+    }
+
+    /// Reads up to `limit` packets of `device`, until it would block. An error after the first packet
+    /// ends the batch, the next read returning it.
+    pub fn read_from<D>(&mut self, device: &D, limit: usize) -> io::Result<usize>
+    where
+        D: NetworkDevice + ?Sized,
+    {
+        self.lens.clear();
+        while self.lens.len() < self.bufs.len().min(limit) {
+            match device.recv(&mut self.bufs[self.lens.len()]) {
+                Ok(n) => self.lens.push(n),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(_) if !self.lens.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.lens.len())
+    }
+}
+
+/// A boxed device, for devices of different kinds to be held together, see `Interfaces`.
+impl<D: NetworkDevice + ?Sized> NetworkDevice for Box<D> {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).recv(buf)
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        (**self).send(packet)
+    }
+
+    fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        (**self).recv_batch(batch)
+    }
+
+    fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<()> {
+        (**self).send_batch(packets)
+    }
+
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        (**self).wait_readable(timeout)
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        (**self).mtu()
+    }
+
+    fn source_address(&self, dst: Ipv4Addr) -> Option<Ipv4Addr> {
+        (**self).source_address(dst)
+    }
+
+    fn checksum_offload(&self) -> ChecksumOffload {
+        (**self).checksum_offload()
+    }
+}
+
+/// A device keeping the packets sent until `flush`, so the answers to a batch of packets received are
+/// written together once it's processed. A pure ACK followed in the batch by a segment of the same
+/// connection acknowledging more is dropped, the later one acknowledging its data too, as the ACKs of
+/// the segments GRO merges are, https://www.ietf.org/rfc/rfc5681.txt 4.2. The duplicate ACKs are
+/// kept, for the peer's fast retransmit.
+pub struct SendBatch<'a> {
+    device: &'a dyn NetworkDevice,
+    queued: RefCell<Vec<Vec<u8>>>,
+}
+
+impl<'a> SendBatch<'a> {
+    pub fn new(device: &'a dyn NetworkDevice) -> Self {
+        Self {
+            device,
+            queued: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Sends the packets queued, but the ACKs superseded.
+    pub fn flush(&self) -> io::Result<()> {
+        let mut packets = std::mem::take(&mut *self.queued.borrow_mut());
+        let mut keep = vec![true; packets.len()];
+        // the highest ACK sent after the packet, per connection
+        let mut acked_later: HashMap<ConnectionID, u32> = HashMap::new();
+        for (i, packet) in packets.iter().enumerate().rev() {
+            let Ok(seg) = SegmentView::parse(packet) else {
+                continue;
+            };
+            if !seg.tcp.ack() {
+                continue;
+            }
+            let ack = seg.tcp.acknowledgment_number();
+            let later = acked_later.entry(seg.id()).or_insert(ack);
+            let pure_ack =
+                seg.payload.is_empty() && !(seg.tcp.syn() || seg.tcp.fin() || seg.tcp.rst());
+            if pure_ack && wrapping_lt(ack, *later) {
+                keep[i] = false;
+            } else if wrapping_lt(*later, ack) {
+                *later = ack;
+            }
+        }
+        let mut keep = keep.into_iter();
+        packets.retain(|_| keep.next().unwrap_or(true));
+        self.device.send_batch(&packets)
+    }
+}
+
+impl NetworkDevice for SendBatch<'_> {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.device.recv(buf)
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.queued.borrow_mut().push(packet.to_vec());
+        Ok(packet.len())
+    }
+
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        self.device.wait_readable(timeout)
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        self.device.mtu()
+    }
+
+    fn source_address(&self, dst: Ipv4Addr) -> Option<Ipv4Addr> {
+        self.device.source_address(dst)
+    }
+
+    fn checksum_offload(&self) -> ChecksumOffload {
+        self.device.checksum_offload()
+    }
+}
+
+/// A device in memory: the packets pushed are received in order, the packets sent are kept until
+/// taken. It never blocks.
+#[derive(Debug, Default)]
+pub struct MemoryDevice {
+    inbound: RefCell<VecDeque<Vec<u8>>>,
+    sent: RefCell<Vec<Vec<u8>>>,
+    mtu: Option<usize>,
+    offload: ChecksumOffload,
+}
+
+impl MemoryDevice {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A device fragmenting the packets sent larger than `mtu`.
+    pub fn with_mtu(mtu: usize) -> Self {
+        Self {
+            mtu: Some(mtu),
+            ..Self::default()
+        }
+    }
+
+    /// The device handling the checksums of `offload`: the segments received taken as checked, the
+    /// segments sent with a partial checksum.
+    pub fn offloading(self, offload: ChecksumOffload) -> Self {
+        Self { offload, ..self }
+    }
+
+    /// Queues a packet to be received.
+    pub fn push(&self, packet: &[u8]) {
+        self.inbound.borrow_mut().push_back(packet.to_vec());
+    }
+
+    /// The packets sent since the last call, oldest first.
+    pub fn take_sent(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.sent.borrow_mut())
+    }
+}
+
+impl NetworkDevice for MemoryDevice {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = self
+            .inbound
+            .borrow_mut()
+            .pop_front()
+            .ok_or(ErrorKind::WouldBlock)?;
+        // truncated as a read of a tun device into a short buffer is
+        let n = packet.len().min(buf.len());
+        buf[..n].copy_from_slice(&packet[..n]);
+        Ok(n)
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.sent.borrow_mut().push(packet.to_vec());
+        Ok(packet.len())
+    }
+
+    fn wait_readable(&self, _timeout: Option<Duration>) -> io::Result<bool> {
+        Ok(!self.inbound.borrow().is_empty())
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        self.mtu
+    }
+
+    fn checksum_offload(&self) -> ChecksumOffload {
+        self.offload
+    }
+}
+
+/// An end of a pair of devices in memory, what is sent on one being received on the other, for two
+/// stacks to talk to each other in the same process, without root or a tun device. The ends are
+/// `Send`, a stack can run on a thread of its own. A packet sent once the other end is dropped is
+/// lost, as on an unplugged wire.
+#[derive(Debug)]
+pub struct LoopbackDevice {
+    tx: Sender<Vec<u8>>,
+    rx: Mutex<Receiver<Vec<u8>>>,
+    /// A packet received while waiting for one, read by the next `recv`
+    pending: Mutex<Option<Vec<u8>>>,
+}
+
+impl LoopbackDevice {
+    /// Two devices connected to each other.
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        (Self::new(a_tx, a_rx), Self::new(b_tx, b_rx))
+    }
+
+    fn new(tx: Sender<Vec<u8>>, rx: Receiver<Vec<u8>>) -> Self {
+        Self {
+            tx,
+            rx: Mutex::new(rx),
+            pending: Mutex::new(None),
+        }
+    }
+
+    fn pending(&self) -> MutexGuard<'_, Option<Vec<u8>>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn rx(&self) -> MutexGuard<'_, Receiver<Vec<u8>>> {
+        self.rx.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl NetworkDevice for LoopbackDevice {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let packet = match self.pending().take() {
+            Some(packet) => packet,
+            None => match self.rx().try_recv() {
+                Ok(packet) => packet,
+                Err(TryRecvError::Empty) => return Err(ErrorKind::WouldBlock.into()),
+                Err(TryRecvError::Disconnected) => return Err(ErrorKind::NotConnected.into()),
+            },
+        };
+        let n = packet.len().min(buf.len());
+        buf[..n].copy_from_slice(&packet[..n]);
+        Ok(n)
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        // lost when the other end is dropped
+        let _ = self.tx.send(packet.to_vec());
+        Ok(packet.len())
+    }
+
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut pending = self.pending();
+        if pending.is_some() {
+            return Ok(true);
+        }
+        let rx = self.rx();
+        let packet = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout),
+            None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match packet {
+            Ok(packet) => {
+                *pending = Some(packet);
+                Ok(true)
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(false),
+            Err(RecvTimeoutError::Disconnected) => Err(ErrorKind::NotConnected.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::{
+        recv_buffer_size, LoopbackDevice, MemoryDevice, NetworkDevice, RecvBatch, SendBatch,
+        DEFAULT_PACKET_SIZE, MAX_PACKET_SIZE,
+    };
+    use etherparse::PacketBuilder;
+    use std::time::Duration;
+
+    fn segment(src_port: u16, ack: u32, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![];
+        PacketBuilder::ipv4([192, 167, 1, 1], [192, 167, 1, 2], 64)
+            .tcp(80, src_port, 1000, 64240)
+            .ack(ack)
+            .write(&mut packet, payload)
+            .unwrap();
+        packet
+    }
+
+    #[test]
+    fn test_recv_batch() {
+        let nic = MemoryDevice::new();
+        for packet in [b"1", b"2", b"3"] {
+            nic.push(packet);
+        }
+        let mut batch = RecvBatch::new(2, 1500);
+        assert_eq!(nic.recv_batch(&mut batch).unwrap(), 2);
+        assert_eq!(batch.packets().collect::<Vec<_>>(), [b"1", b"2"]);
+        assert_eq!(nic.recv_batch(&mut batch).unwrap(), 1);
+        assert_eq!(batch.packets().collect::<Vec<_>>(), [b"3"]);
+        assert_eq!(nic.recv_batch(&mut batch).unwrap(), 0);
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn test_recv_buffer_size() {
+        assert_eq!(recv_buffer_size(&MemoryDevice::new()), DEFAULT_PACKET_SIZE);
+        assert_eq!(
+            recv_buffer_size(&MemoryDevice::with_mtu(576)),
+            DEFAULT_PACKET_SIZE
+        );
+        assert_eq!(
+            recv_buffer_size(&MemoryDevice::with_mtu(1 << 20)),
+            MAX_PACKET_SIZE
+        );
+
+        let jumbo = MemoryDevice::with_mtu(9000);
+        let packet = segment(40000, 1, &[7; 8960]);
+        jumbo.push(&packet);
+        let mut batch = RecvBatch::new(1, recv_buffer_size(&jumbo));
+        jumbo.recv_batch(&mut batch).unwrap();
+        assert_eq!(batch.packets().next(), Some(&packet[..]));
+    }
+
+    #[test]
+    fn test_send_batch() {
+        let nic = MemoryDevice::new();
+        let tx = SendBatch::new(&nic);
+        let packets = [
+            segment(40000, 100, &[]),
+            segment(40001, 100, &[]),
+            // duplicate ACKs are kept
+            segment(40000, 200, &[]),
+            segment(40000, 200, &[]),
+            segment(40000, 200, b"data"),
+        ];
+        for packet in &packets {
+            tx.send(packet).unwrap();
+        }
+        assert!(nic.take_sent().is_empty());
+        tx.flush().unwrap();
+        assert_eq!(nic.take_sent(), packets[1..]);
+    }
+
+    #[test]
+    fn test_loopback_pair() {
+        let (a, b) = LoopbackDevice::pair();
+        assert!(!b.wait_readable(Some(Duration::from_millis(1))).unwrap());
+        a.send(b"1").unwrap();
+        a.send(b"2").unwrap();
+        b.send(b"3").unwrap();
+        assert!(b.wait_readable(None).unwrap());
+        let mut batch = RecvBatch::new(4, 1500);
+        assert_eq!(b.recv_batch(&mut batch).unwrap(), 2);
+        assert_eq!(batch.packets().collect::<Vec<_>>(), [b"1", b"2"]);
+        let mut buf = [0u8; 1500];
+        assert_eq!(a.recv(&mut buf).unwrap(), 1);
+        assert_eq!(&buf[..1], b"3");
+
+        drop(a);
+        assert!(b.send(b"lost").is_ok());
+        assert!(b.wait_readable(None).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+//! The tun and tap devices, unix only.
+
+use crate::device::{NetworkDevice, RecvBatch};
+use crate::ethernet::{Ethernet, Input, MacAddr, ETH_HEADER_LEN, VLAN_TAG_LEN};
+use crate::netlink::Applied;
+use crate::stack;
+use crate::tcp::device_mtu;
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+impl NetworkDevice for tun_tap::Iface {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        tun_tap::Iface::recv(self, buf)
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        tun_tap::Iface::send(self, packet)
+    }
+
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        stack::wait_readable(self, timeout)
+    }
+}
+
+/// The device the stack runs on: a tun device carrying ip packets, or a tap device carrying Ethernet
+/// frames, which are framed and stripped here so the stack only sees the ip packets.
+#[derive(Debug)]
+pub struct Nic {
+    iface: tun_tap::Iface,
+    ethernet: Option<Ethernet>,
+    /// Whether `recv_batch` reads until the device would block, a packet per call otherwise
+    nonblocking: AtomicBool,
+    applied: Option<Applied>,
+    /// The MTU of the device, read by the first send needing it
+    mtu: OnceLock<Option<usize>>,
+}
+
+impl Nic {
+    pub fn tun(iface: tun_tap::Iface) -> Self {
+        Self {
+            iface,
+            ethernet: None,
+            nonblocking: AtomicBool::new(false),
+            applied: None,
+            mtu: OnceLock::new(),
+        }
+    }
+
+    /// A tap device, the stack answering to `mac` on it, and to the ARP requests for `addr`.
+    pub fn tap(iface: tun_tap::Iface, mac: MacAddr, addr: Option<Ipv4Addr>) -> Self {
+        Self {
+            iface,
+            ethernet: Some(Ethernet::new(mac, addr)),
+            nonblocking: AtomicBool::new(false),
+            applied: None,
+            mtu: OnceLock::new(),
+        }
+    }
+
+    /// Keeps the set up of the device until the nic is dropped, see `netlink::apply`.
+    pub fn hold(&mut self, applied: Applied) {
+        self.applied = Some(applied);
+    }
+
+    pub fn iface(&self) -> &tun_tap::Iface {
+        &self.iface
+    }
+
+    pub fn name(&self) -> &str {
+        self.iface.name()
+    }
+
+    /// Makes the reads of the device fail with `WouldBlock` instead of waiting for a packet, so
+    /// `recv_batch` reads all those waiting. A blocking device is read a packet per wakeup.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        set_nonblocking(&self.iface, nonblocking)?;
+        self.nonblocking.store(nonblocking, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// The framing of a tap device.
+    pub fn ethernet(&self) -> Option<&Ethernet> {
+        self.ethernet.as_ref()
+    }
+
+    /// Puts the stack on the VLAN `vlan` of a tap device, see `Ethernet::set_vlan`. Fails on a tun
+    /// device, which carries no frames.
+    pub fn set_vlan(&mut self, vlan: Option<u16>) -> io::Result<()> {
+        match &mut self.ethernet {
+            Some(ethernet) => ethernet.set_vlan(vlan),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a vlan needs a tap device",
+            )),
+        }
+    }
+
+    /// The ip packet in what was read from the device into `frame` by other means than `recv`, none
+    /// for a frame handled here or dropped.
+    pub fn packet<'a>(&self, frame: &'a [u8]) -> io::Result<Option<&'a [u8]>> {
+        let Some(ethernet) = &self.ethernet else {
+            return Ok(Some(frame));
+        };
+        match ethernet.input(frame) {
+            Input::Packet(packet) => Ok(Some(packet)),
+            Input::Send(frames) => {
+                for frame in frames {
+                    self.iface.send(&frame)?;
+                }
+                Ok(None)
+            }
+            Input::Drop => Ok(None),
+        }
+    }
+
+    /// Announces the address of the stack on a tap device with a gratuitous ARP.
+    pub fn announce(&self) -> io::Result<()> {
+        if let Some(frame) = self.ethernet.as_ref().and_then(Ethernet::announcement) {
+            self.iface.send(&frame)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<tun_tap::Iface> for Nic {
+    fn from(iface: tun_tap::Iface) -> Self {
+        match iface.mode() {
+            tun_tap::Mode::Tun => Nic::tun(iface),
+            tun_tap::Mode::Tap => Nic::tap(iface, crate::ethernet::DEFAULT_MAC, None),
+        }
+    }
+}
+
+impl AsRawFd for Nic {
+    fn as_raw_fd(&self) -> RawFd {
+        self.iface.as_raw_fd()
+    }
+}
+
+impl NetworkDevice for Nic {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.ethernet.is_none() {
+            return self.iface.recv(buf);
+        }
+        let mut frame = vec![0u8; ETH_HEADER_LEN + VLAN_TAG_LEN + buf.len()];
+        let n = self.iface.recv(&mut frame)?;
+        let Some(packet) = self.packet(&frame[..n])? else {
+            return Ok(0);
+        };
+        buf[..packet.len()].copy_from_slice(packet);
+        Ok(packet.len())
+    }
+
+    fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+        let limit = match self.nonblocking.load(Ordering::Relaxed) {
+            true => usize::MAX,
+            false => 1,
+        };
+        batch.read_from(self, limit)
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        match &self.ethernet {
+            Some(ethernet) => {
+                // queued while the destination is resolved
+                if let Some(frame) = ethernet.output(packet) {
+                    self.iface.send(&frame)?;
+                }
+                Ok(packet.len())
+            }
+            None => self.iface.send(packet),
+        }
+    }
+
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        stack::wait_readable(&self.iface, timeout)
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        *self.mtu.get_or_init(|| match device_mtu(self.name()) {
+            Ok(mtu) => Some(mtu as usize),
+            Err(e) => {
+                log::warn!("mtu of {:} unknown, not fragmenting: {e:}", self.name());
+                None
+            }
+        })
+    }
+}
+
+/// Sets or clears `O_NONBLOCK` on `fd`.
+pub fn set_nonblocking(fd: &impl AsRawFd, nonblocking: bool) -> io::Result<()> {
+    let fd = fd.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let flags = match nonblocking {
+        true => flags | libc::O_NONBLOCK,
+        false => flags & !libc::O_NONBLOCK,
+    };
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,237 @@
+//! A DPDK poll-mode backend, https://doc.dpdk.org/guides/prog_guide/poll_mode_drv.html, for kernel
+//! bypass experiments: a port bound to a DPDK driver is read and written in bursts of mbufs, from a
+//! pool of DPDK's, with no syscall at all. Being poll mode, waiting for a packet spins a core.
+//!
+//! The `dpdk` feature compiles the C shim `csrc/dpdk_shim.c` against the DPDK pkg-config finds, the
+//! burst functions being inline in DPDK's headers, and links DPDK; see `build.rs`. The EAL is set up
+//! once per process with `init`, taking the usual EAL arguments, `-l 1 -a 0000:01:00.0` for instance.
+
+use crate::device::{NetworkDevice, BATCH_SIZE};
+use crate::ethernet::{Ethernet, Input, MacAddr};
+use std::collections::VecDeque;
+use std::ffi::{c_char, c_int, CString};
+use std::io::{self, ErrorKind};
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The mbufs of the pool of a port
+const POOL_SIZE: u32 = 8191;
+
+/// The descriptors of the rx and the tx queues, adjusted to what the driver takes
+const RING_SIZE: u16 = 1024;
+
+#[repr(C)]
+struct RteMbuf {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct RteMempool {
+    _private: [u8; 0],
+}
+
+extern "C" {
+    fn mt_dpdk_init(argc: c_int, argv: *mut *mut c_char) -> c_int;
+    fn mt_dpdk_pool(name: *const c_char, mbufs: u32) -> *mut RteMempool;
+    fn mt_dpdk_port_start(port: u16, pool: *mut RteMempool, rx_desc: u16, tx_desc: u16) -> c_int;
+    fn mt_dpdk_port_stop(port: u16);
+    fn mt_dpdk_mac(port: u16, mac: *mut u8) -> c_int;
+    fn mt_dpdk_rx(port: u16, mbufs: *mut *mut RteMbuf, n: u16) -> u16;
+    fn mt_dpdk_tx(port: u16, mbufs: *mut *mut RteMbuf, n: u16) -> u16;
+    fn mt_dpdk_data(mbuf: *const RteMbuf, len: *mut u16) -> *const u8;
+    fn mt_dpdk_copy(pool: *mut RteMempool, data: *const u8, len: u16) -> *mut RteMbuf;
+    fn mt_dpdk_free(mbuf: *mut RteMbuf);
+}
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Sets up the EAL with the arguments `args`, the program name aside. Does nothing once it's set up.
+pub fn init(args: &[&str]) -> io::Result<()> {
+    if INITIALIZED.load(Ordering::Acquire) {
+        return Ok(());
+    }
+    let args = std::iter::once("mini-tcp")
+        .chain(args.iter().copied())
+        .map(CString::new)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+    // the EAL keeps pointers into the arguments, they live as long as the process
+    let mut argv: Vec<*mut c_char> = args.into_iter().map(CString::into_raw).collect();
+    if unsafe { mt_dpdk_init(argv.len() as c_int, argv.as_mut_ptr()) } < 0 {
+        return Err(io::Error::other("rte_eal_init failed, see the EAL log"));
+    }
+    std::mem::forget(argv);
+    INITIALIZED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// A port of a DPDK driver, carrying the Ethernet frames of the stack as `Nic` does on a tap device. It
+/// never blocks, but `wait_readable` spins.
+#[derive(Debug)]
+pub struct DpdkPort {
+    port: u16,
+    pool: *mut RteMempool,
+    ethernet: Ethernet,
+    /// The frames of the last burst not read yet
+    received: Mutex<VecDeque<*mut RteMbuf>>,
+}
+
+// the mbufs are only touched with the lock held, or owned by the call sending them
+unsafe impl Send for DpdkPort {}
+unsafe impl Sync for DpdkPort {}
+
+impl DpdkPort {
+    /// Starts the port `port` with a queue each way, the stack answering to its MAC and to `addr`.
+    /// `init` sets up the EAL first.
+    pub fn open(port: u16, addr: Ipv4Addr) -> io::Result<Self> {
+        if !INITIALIZED.load(Ordering::Acquire) {
+            return Err(io::Error::other("the EAL isn't set up, see dpdk::init"));
+        }
+        let name = CString::new(format!("mini_tcp_{port:}"))?;
+        let pool = unsafe { mt_dpdk_pool(name.as_ptr(), POOL_SIZE) };
+        if pool.is_null() {
+            return Err(io::Error::other("the mbuf pool couldn't be created"));
+        }
+        let ret = unsafe { mt_dpdk_port_start(port, pool, RING_SIZE, RING_SIZE) };
+        if ret < 0 {
+            return Err(io::Error::from_raw_os_error(-ret));
+        }
+        let mut mac: MacAddr = [0; 6];
+        let ret = unsafe { mt_dpdk_mac(port, mac.as_mut_ptr()) };
+        if ret < 0 {
+            unsafe { mt_dpdk_port_stop(port) };
+            return Err(io::Error::from_raw_os_error(-ret));
+        }
+        Ok(Self {
+            port,
+            pool,
+            ethernet: Ethernet::new(mac, Some(addr)),
+            received: Mutex::new(VecDeque::with_capacity(BATCH_SIZE)),
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn ethernet(&self) -> &Ethernet {
+        &self.ethernet
+    }
+
+    /// Announces the address of the stack with a gratuitous ARP.
+    pub fn announce(&self) -> io::Result<()> {
+        if let Some(frame) = self.ethernet.announcement() {
+            self.transmit(&[frame]);
+        }
+        Ok(())
+    }
+
+    fn received(&self) -> MutexGuard<'_, VecDeque<*mut RteMbuf>> {
+        self.received.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Receives a burst when the last one was read. Returns whether a frame is waiting.
+    fn poll_rx(&self, received: &mut VecDeque<*mut RteMbuf>) -> bool {
+        if received.is_empty() {
+            let mut mbufs = [std::ptr::null_mut(); BATCH_SIZE];
+            let n = unsafe { mt_dpdk_rx(self.port, mbufs.as_mut_ptr(), BATCH_SIZE as u16) };
+            received.extend(&mbufs[..n as usize]);
+        }
+        !received.is_empty()
+    }
+
+    /// Sends the frames in a burst. Those the tx queue has no room for are dropped, as a full queue of
+    /// a kernel device does, the connections retransmit them.
+    fn transmit(&self, frames: &[Vec<u8>]) {
+        let mut mbufs: Vec<*mut RteMbuf> = frames
+            .iter()
+            .filter(|frame| frame.len() <= u16::MAX as usize)
+            .map(|frame| unsafe { mt_dpdk_copy(self.pool, frame.as_ptr(), frame.len() as u16) })
+            .filter(|mbuf| !mbuf.is_null())
+            .collect();
+        let mut sent = 0;
+        while sent < mbufs.len() {
+            let burst = (mbufs.len() - sent).min(u16::MAX as usize) as u16;
+            let n = unsafe { mt_dpdk_tx(self.port, mbufs[sent..].as_mut_ptr(), burst) };
+            if n == 0 {
+                break;
+            }
+            sent += n as usize;
+        }
+        if sent < frames.len() {
+            log::debug!("{:} frames dropped, tx queue full", frames.len() - sent);
+        }
+        for mbuf in &mbufs[sent..] {
+            unsafe { mt_dpdk_free(*mbuf) };
+        }
+    }
+}
+
+impl Drop for DpdkPort {
+    fn drop(&mut self) {
+        for mbuf in self.received().drain(..) {
+            unsafe { mt_dpdk_free(mbuf) };
+        }
+        unsafe { mt_dpdk_port_stop(self.port) };
+    }
+}
+
+impl NetworkDevice for DpdkPort {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut received = self.received();
+        if !self.poll_rx(&mut received) {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let mbuf = received.pop_front().expect("a frame was received");
+        drop(received);
+        let mut len = 0u16;
+        let data = unsafe { mt_dpdk_data(mbuf, &mut len) };
+        let frame = unsafe { std::slice::from_raw_parts(data, len as usize) };
+        let n = match self.ethernet.input(frame) {
+            Input::Packet(packet) => {
+                let n = packet.len().min(buf.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                n
+            }
+            Input::Send(frames) => {
+                self.transmit(&frames);
+                0
+            }
+            Input::Drop => 0,
+        };
+        unsafe { mt_dpdk_free(mbuf) };
+        Ok(n)
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        // queued while the destination is resolved
+        if let Some(frame) = self.ethernet.output(packet) {
+            self.transmit(&[frame]);
+        }
+        Ok(packet.len())
+    }
+
+    fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<()> {
+        let frames: Vec<Vec<u8>> = packets
+            .iter()
+            .filter_map(|packet| self.ethernet.output(packet))
+            .collect();
+        self.transmit(&frames);
+        Ok(())
+    }
+
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let until = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if self.poll_rx(&mut self.received()) {
+                return Ok(true);
+            }
+            if until.is_some_and(|until| Instant::now() >= until) {
+                return Ok(false);
+            }
+            std::hint::spin_loop();
+        }
+    }
+}
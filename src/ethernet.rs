@@ -0,0 +1,335 @@
+//! Ethernet II framing for a TAP device, https://www.ietf.org/rfc/rfc894.txt: the stack only handles
+//! ip packets, the frames read are stripped of their header and the packets sent are framed to the
+//! MAC of their destination, resolved with ARP, see `arp`.
+//!
+//! On a trunk the stack lives on a VLAN, https://standards.ieee.org/ieee/802.1Q/: with `set_vlan`, only
+//! the frames tagged with its ID are received and the frames sent are tagged with it. Without one,
+//! the frames tagged for a VLAN are dropped, those only carrying a priority, VLAN ID 0, received.
+
+use crate::arp::{Arp, Neighbors, ARP_REPLY, ARP_REQUEST};
+use etherparse::{ether_type, Ethernet2Header, Ethernet2HeaderSlice, Ipv4HeaderSlice};
+use std::io::{self, ErrorKind};
+use std::net::Ipv4Addr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Instant;
+
+pub type MacAddr = [u8; 6];
+
+/// The length of an Ethernet II header: destination, source and EtherType
+pub const ETH_HEADER_LEN: usize = 14;
+
+/// The length of an 802.1Q tag: the TPID and the tag control information, the priority, the DEI and
+/// the VLAN ID
+pub const VLAN_TAG_LEN: usize = 4;
+
+/// The largest VLAN ID, 4095 being reserved
+pub const VLAN_ID_MAX: u16 = 4094;
+
+pub const BROADCAST: MacAddr = [0xff; 6];
+
+/// The MAC of the stack unless another is given, locally administered so it clashes with no vendor's
+pub const DEFAULT_MAC: MacAddr = [0x02, 0x6d, 0x74, 0x63, 0x70, 0x01];
+
+/// What a frame read from the device holds for the stack
+#[derive(PartialEq, Eq, Debug)]
+pub enum Input<'a> {
+    /// An ipv4 packet
+    Packet(&'a [u8]),
+    /// Frames to send in answer: an ARP reply, or the packets which were waiting for the sender
+    Send(Vec<Vec<u8>>),
+    /// A frame to another host or of another protocol
+    Drop,
+}
+
+#[derive(Debug)]
+pub struct Ethernet {
+    mac: MacAddr,
+    /// The address the ARP requests are answered for, none answered without one
+    addr: Option<Ipv4Addr>,
+    /// The ID of the VLAN the stack is on, the frames untagged without one
+    vlan: Option<u16>,
+    neighbors: Mutex<Neighbors>,
+}
+
+impl Ethernet {
+    pub fn new(mac: MacAddr, addr: Option<Ipv4Addr>) -> Self {
+        Self {
+            mac,
+            addr,
+            vlan: None,
+            neighbors: Mutex::new(Neighbors::default()),
+        }
+    }
+
+    /// Puts the stack on the VLAN `vlan`, off any with none. Fails unless the ID is within
+    /// 1..=`VLAN_ID_MAX`.
+    pub fn set_vlan(&mut self, vlan: Option<u16>) -> io::Result<()> {
+        if vlan.is_some_and(|id| id == 0 || id > VLAN_ID_MAX) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "vlan id {:} is not within 1..={VLAN_ID_MAX:}",
+                    vlan.unwrap_or(0)
+                ),
+            ));
+        }
+        self.vlan = vlan;
+        Ok(())
+    }
+
+    pub fn vlan(&self) -> Option<u16> {
+        self.vlan
+    }
+
+    pub fn mac(&self) -> MacAddr {
+        self.mac
+    }
+
+    pub fn addr(&self) -> Option<Ipv4Addr> {
+        self.addr
+    }
+
+    /// The MAC resolved for `addr`.
+    pub fn neighbor(&self, addr: Ipv4Addr) -> Option<MacAddr> {
+        self.neighbors().lookup(addr, Instant::now())
+    }
+
+    fn neighbors(&self) -> MutexGuard<'_, Neighbors> {
+        self.neighbors.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn frame(&self, destination: MacAddr, ether_type: u16, payload: &[u8]) -> Vec<u8> {
+        let header = Ethernet2Header {
+            destination,
+            source: self.mac,
+            ether_type,
+        };
+        let mut frame = Vec::with_capacity(ETH_HEADER_LEN + VLAN_TAG_LEN + payload.len());
+        frame.extend_from_slice(&header.to_bytes());
+        if let Some(vlan) = self.vlan {
+            // the tag goes before the EtherType, with the priority and DEI clear
+            let at = ETH_HEADER_LEN - 2;
+            frame.splice(
+                at..at,
+                ether_type::VLAN_TAGGED_FRAME
+                    .to_be_bytes()
+                    .into_iter()
+                    .chain(vlan.to_be_bytes()),
+            );
+        }
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Processes a frame read from the device: an ipv4 packet is for the stack, ARP is handled here.
+    pub fn input<'a>(&self, frame: &'a [u8]) -> Input<'a> {
+        let Ok(header) = Ethernet2HeaderSlice::from_slice(frame) else {
+            return Input::Drop;
+        };
+        let dst = header.destination();
+        // multicast and broadcast have the group bit set
+        if dst != self.mac && dst[0] & 1 == 0 {
+            return Input::Drop;
+        }
+        let mut ether_type = header.ether_type();
+        let mut payload = &frame[ETH_HEADER_LEN..];
+        let mut vlan = None;
+        if ether_type == ether_type::VLAN_TAGGED_FRAME {
+            let [tci_high, tci_low, type_high, type_low, ..] = *payload else {
+                return Input::Drop;
+            };
+            // a VLAN ID of 0 only carries a priority
+            vlan = Some(u16::from_be_bytes([tci_high, tci_low]) & 0x0fff).filter(|id| *id != 0);
+            ether_type = u16::from_be_bytes([type_high, type_low]);
+            payload = &payload[VLAN_TAG_LEN..];
+        }
+        if vlan != self.vlan {
+            return Input::Drop;
+        }
+        match ether_type {
+            ether_type::IPV4 => Input::Packet(payload),
+            ether_type::ARP => match Arp::parse(payload) {
+                Some(arp) => self.on_arp(&arp),
+                None => Input::Drop,
+            },
+            _ => Input::Drop,
+        }
+    }
+
+    /// Learns the MAC of the sender of a request for our address, of a reply to us or of a peer
+    /// already known, and answers the request, https://www.ietf.org/rfc/rfc826.txt "Packet Reception".
+    fn on_arp(&self, arp: &Arp) -> Input<'static> {
+        let for_us = self.addr == Some(arp.target_addr)
+            || (arp.op == ARP_REPLY && arp.target_mac == self.mac);
+        let mut neighbors = self.neighbors();
+        if !for_us && !neighbors.contains(arp.sender_addr) {
+            return Input::Drop;
+        }
+        let mut send = vec![];
+        if for_us && arp.op == ARP_REQUEST {
+            let reply = arp.reply(self.mac).to_bytes();
+            send.push(self.frame(arp.sender_mac, ether_type::ARP, &reply));
+        }
+        let pending = neighbors.update(arp.sender_addr, arp.sender_mac, Instant::now());
+        send.extend(
+            pending
+                .iter()
+                .map(|packet| self.frame(arp.sender_mac, ether_type::IPV4, packet)),
+        );
+        Input::Send(send)
+    }
+
+    /// The frame sending the ipv4 `packet`: the packet framed to the MAC of its destination, or an ARP
+    /// request for it while the packet waits for the reply, none while a request is outstanding.
+    pub fn output(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let ip = Ipv4HeaderSlice::from_slice(packet).ok()?;
+        let now = Instant::now();
+        let mut neighbors = self.neighbors();
+        if let Some(mac) = neighbors.lookup(ip.destination_addr(), now) {
+            return Some(self.frame(mac, ether_type::IPV4, packet));
+        }
+        if !neighbors.queue(ip.destination_addr(), packet, now) {
+            return None;
+        }
+        let sender = self.addr.unwrap_or(ip.source_addr());
+        let request = Arp::request(self.mac, sender, ip.destination_addr()).to_bytes();
+        Some(self.frame(BROADCAST, ether_type::ARP, &request))
+    }
+
+    /// The gratuitous ARP announcing our address, sent once the device is up so the peers drop what
+    /// they had for it.
+    pub fn announcement(&self) -> Option<Vec<u8>> {
+        let arp = Arp::gratuitous(self.mac, self.addr?).to_bytes();
+        Some(self.frame(BROADCAST, ether_type::ARP, &arp))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::arp::{Arp, ARP_REPLY};
+    use crate::ethernet::{Ethernet, Input, BROADCAST, DEFAULT_MAC, ETH_HEADER_LEN, VLAN_TAG_LEN};
+    use crate::TCP_PROTOCOL;
+    use etherparse::{ether_type, Ethernet2Header, Ipv4Header};
+    use std::net::Ipv4Addr;
+
+    const PEER: [u8; 6] = [0x02, 0, 0, 0, 0, 2];
+
+    fn frame(destination: [u8; 6], ether_type: u16, payload: &[u8]) -> Vec<u8> {
+        let header = Ethernet2Header {
+            destination,
+            source: PEER,
+            ether_type,
+        };
+        let mut frame = header.to_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    fn packet(src: [u8; 4], dst: [u8; 4]) -> Vec<u8> {
+        let mut packet = vec![];
+        Ipv4Header::new(0, 64, TCP_PROTOCOL, src, dst)
+            .write(&mut packet)
+            .unwrap();
+        packet
+    }
+
+    fn arp(frame: &[u8]) -> Arp {
+        assert_eq!(frame[12..14], ether_type::ARP.to_be_bytes());
+        Arp::parse(&frame[ETH_HEADER_LEN..]).unwrap()
+    }
+
+    #[test]
+    fn test_framing() {
+        let addr = Ipv4Addr::new(192, 167, 1, 1);
+        let peer = Ipv4Addr::new(192, 167, 1, 2);
+        let eth = Ethernet::new(DEFAULT_MAC, Some(addr));
+        let announcement = eth.announcement().unwrap();
+        assert_eq!(announcement[..6], BROADCAST);
+        assert_eq!(arp(&announcement), Arp::gratuitous(DEFAULT_MAC, addr));
+
+        // the packets wait for the peer to be resolved
+        let reply = packet([192, 167, 1, 1], [192, 167, 1, 2]);
+        let request = eth.output(&reply).unwrap();
+        assert_eq!(request[..6], BROADCAST);
+        assert_eq!(arp(&request), Arp::request(DEFAULT_MAC, addr, peer));
+        assert_eq!(eth.output(&reply), None);
+
+        let resolved = arp(&request).reply(PEER).to_bytes();
+        let Input::Send(sent) = eth.input(&frame(DEFAULT_MAC, ether_type::ARP, &resolved)) else {
+            panic!("the pending packets are sent");
+        };
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0][..6], PEER);
+        assert_eq!(sent[0][6..12], DEFAULT_MAC);
+        assert_eq!(sent[0][12..14], [0x08, 0x00]);
+        assert_eq!(sent[0][ETH_HEADER_LEN..], reply);
+        assert_eq!(eth.output(&reply).unwrap()[..6], PEER);
+
+        // the requests for our address are answered
+        let other = Ipv4Addr::new(192, 167, 1, 3);
+        let request = Arp::request(PEER, other, addr).to_bytes();
+        let Input::Send(sent) = eth.input(&frame(BROADCAST, ether_type::ARP, &request)) else {
+            panic!("the request is answered");
+        };
+        let answer = arp(&sent[0]);
+        assert_eq!((answer.op, answer.sender_mac), (ARP_REPLY, DEFAULT_MAC));
+        assert_eq!(answer.target_addr, other);
+        assert_eq!(eth.neighbor(other), Some(PEER));
+        let request = Arp::request(PEER, Ipv4Addr::new(192, 167, 1, 4), other).to_bytes();
+        assert_eq!(
+            eth.input(&frame(BROADCAST, ether_type::ARP, &request)),
+            Input::Drop
+        );
+
+        let received = frame(DEFAULT_MAC, ether_type::IPV4, &reply);
+        assert_eq!(
+            eth.input(&received),
+            Input::Packet(&received[ETH_HEADER_LEN..])
+        );
+        let to_other = frame([0x02, 0, 0, 0, 0, 3], ether_type::IPV4, &reply);
+        assert_eq!(eth.input(&to_other), Input::Drop);
+        let ipv6 = frame(DEFAULT_MAC, ether_type::IPV6, &reply);
+        assert_eq!(eth.input(&ipv6), Input::Drop);
+    }
+
+    #[test]
+    fn test_vlan() {
+        let mut eth = Ethernet::new(DEFAULT_MAC, Some(Ipv4Addr::new(192, 167, 1, 1)));
+        let packet = packet([192, 167, 1, 2], [192, 167, 1, 1]);
+        let tagged = |tci: u16| {
+            let mut tag = tci.to_be_bytes().to_vec();
+            tag.extend_from_slice(&ether_type::IPV4.to_be_bytes());
+            tag.extend_from_slice(&packet);
+            frame(DEFAULT_MAC, ether_type::VLAN_TAGGED_FRAME, &tag)
+        };
+        let payload = |frame: &[u8]| frame[ETH_HEADER_LEN + VLAN_TAG_LEN..].to_vec();
+
+        // untagged, only priority tags are taken
+        let priority = tagged(0x6000);
+        assert_eq!(eth.input(&priority), Input::Packet(&payload(&priority)));
+        assert_eq!(eth.input(&tagged(10)), Input::Drop);
+
+        eth.set_vlan(Some(10)).unwrap();
+        assert_eq!(eth.vlan(), Some(10));
+        // the priority bits are not part of the VLAN ID
+        let on_vlan = tagged(0x2000 | 10);
+        assert_eq!(eth.input(&on_vlan), Input::Packet(&payload(&on_vlan)));
+        assert_eq!(eth.input(&tagged(11)), Input::Drop);
+        assert_eq!(eth.input(&priority), Input::Drop);
+        let untagged = frame(DEFAULT_MAC, ether_type::IPV4, &packet);
+        assert_eq!(eth.input(&untagged), Input::Drop);
+        assert_eq!(eth.input(&tagged(10)[..ETH_HEADER_LEN + 2]), Input::Drop);
+
+        // the frames sent are tagged, the EtherType after the tag
+        let announcement = eth.announcement().unwrap();
+        assert_eq!(announcement[12..14], [0x81, 0x00]);
+        assert_eq!(announcement[14..16], 10u16.to_be_bytes());
+        assert_eq!(announcement[16..18], ether_type::ARP.to_be_bytes());
+
+        assert!(eth.set_vlan(Some(0)).is_err());
+        assert!(eth.set_vlan(Some(4095)).is_err());
+        assert_eq!(eth.vlan(), Some(10));
+        eth.set_vlan(None).unwrap();
+        assert_eq!(eth.input(&untagged), Input::Packet(&packet[..]));
+    }
+}
@@ -0,0 +1,331 @@
+//! The C API of the stack, with the `ffi` feature, for programs and test harnesses in other languages to
+//! embed it. `include/mini_tcp.h` declares it. The stack is an opaque `mt_stack` handle, a connection
+//! is named by its 4-tuple, `mt_conn`, as `TcpStack` does. The calls returning an `int` or an
+//! `ssize_t` fail with a negated errno: `-EAGAIN` when a read or a write would block or no connection
+//! is waiting to be accepted, `-ENOENT` for a connection gone.
+//!
+//! ```c
+//! mt_stack *stack = mt_stack_new(NULL);
+//! mt_listen(stack, 80);
+//! mt_event events[16];
+//! for (;;) {
+//!     ssize_t n = mt_poll(stack, -1, events, 16);
+//!     ...
+//! }
+//! ```
+
+use crate::stack::TcpStack;
+use crate::tcp::ConnectionID;
+use std::ffi::{c_char, c_int, CStr};
+use std::io::{self, ErrorKind};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+
+/// The opaque stack handle of the C API
+pub struct MtStack(TcpStack);
+
+/// A connection, the addresses in host byte order
+#[repr(C)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct MtConn {
+    pub src_addr: u32,
+    pub src_port: u16,
+    pub dst_addr: u32,
+    pub dst_port: u16,
+}
+
+/// A connection whose readiness changed, see `mt_poll`
+#[repr(C)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct MtEvent {
+    pub conn: MtConn,
+    pub readable: u8,
+    pub writable: u8,
+}
+
+impl From<&ConnectionID> for MtConn {
+    fn from(id: &ConnectionID) -> Self {
+        Self {
+            src_addr: id.src_addr.into(),
+            src_port: id.src_port,
+            dst_addr: id.dst_addr.into(),
+            dst_port: id.dst_port,
+        }
+    }
+}
+
+impl From<&MtConn> for ConnectionID {
+    fn from(conn: &MtConn) -> Self {
+        Self {
+            src_addr: Ipv4Addr::from(conn.src_addr),
+            src_port: conn.src_port,
+            dst_addr: Ipv4Addr::from(conn.dst_addr),
+            dst_port: conn.dst_port,
+        }
+    }
+}
+
+/// The negated errno of `e`.
+fn errno(e: &io::Error) -> c_int {
+    let errno = e.raw_os_error().unwrap_or(match e.kind() {
+        ErrorKind::WouldBlock => libc::EAGAIN,
+        ErrorKind::NotFound => libc::ENOENT,
+        ErrorKind::InvalidInput => libc::EINVAL,
+        ErrorKind::AddrInUse => libc::EADDRINUSE,
+        ErrorKind::BrokenPipe => libc::EPIPE,
+        ErrorKind::TimedOut => libc::ETIMEDOUT,
+        ErrorKind::Unsupported => libc::EOPNOTSUPP,
+        ErrorKind::OutOfMemory => libc::ENOMEM,
+        _ => libc::EIO,
+    });
+    -errno
+}
+
+fn status(result: io::Result<()>) -> c_int {
+    result.map_or_else(|e| errno(&e), |_| 0)
+}
+
+/// Runs a stack on the tun device `device`, `mini-tcp-tun` when NULL. Returns NULL when the device
+/// can't be opened.
+///
+/// # Safety
+/// `device` is NULL or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn mt_stack_new(device: *const c_char) -> *mut MtStack {
+    let mut builder = TcpStack::builder();
+    if !device.is_null() {
+        match CStr::from_ptr(device).to_str() {
+            Ok(device) => builder = builder.device(device),
+            Err(_) => return std::ptr::null_mut(),
+        }
+    }
+    match builder.build() {
+        Ok(stack) => Box::into_raw(Box::new(MtStack(stack))),
+        Err(e) => {
+            log::error!("mt_stack_new: {e:}");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Closes the device and frees the stack, the connections are dropped.
+///
+/// # Safety
+/// `stack` is NULL or was returned by `mt_stack_new`, and isn't used anymore.
+#[no_mangle]
+pub unsafe extern "C" fn mt_stack_free(stack: *mut MtStack) {
+    if !stack.is_null() {
+        drop(Box::from_raw(stack));
+    }
+}
+
+/// Accepts the connections to `port`.
+///
+/// # Safety
+/// `stack` was returned by `mt_stack_new`.
+#[no_mangle]
+pub unsafe extern "C" fn mt_listen(stack: *mut MtStack, port: u16) -> c_int {
+    match stack.as_mut() {
+        Some(stack) => status(stack.0.listen(port)),
+        None => -libc::EINVAL,
+    }
+}
+
+/// Takes the next connection established on `port` into `conn`, fails with `-EAGAIN` while there is
+/// none.
+///
+/// # Safety
+/// `stack` was returned by `mt_stack_new`, `conn` points to a `mt_conn`.
+#[no_mangle]
+pub unsafe extern "C" fn mt_accept(stack: *mut MtStack, port: u16, conn: *mut MtConn) -> c_int {
+    let (Some(stack), Some(conn)) = (stack.as_mut(), conn.as_mut()) else {
+        return -libc::EINVAL;
+    };
+    match stack.0.accept(port) {
+        Some(id) => {
+            *conn = MtConn::from(&id);
+            0
+        }
+        None => -libc::EAGAIN,
+    }
+}
+
+/// Waits for a packet or a timer for at most `timeout_ms`, forever when negative, and fills `events`
+/// with up to `max` connections whose readiness changed. Returns how many.
+///
+/// # Safety
+/// `stack` was returned by `mt_stack_new`, `events` points to `max` `mt_event`s.
+#[no_mangle]
+pub unsafe extern "C" fn mt_poll(
+    stack: *mut MtStack,
+    timeout_ms: i64,
+    events: *mut MtEvent,
+    max: usize,
+) -> isize {
+    let Some(stack) = stack.as_mut() else {
+        return -libc::EINVAL as isize;
+    };
+    if events.is_null() && max > 0 {
+        return -libc::EINVAL as isize;
+    }
+    let timeout = u64::try_from(timeout_ms).ok().map(Duration::from_millis);
+    let ready = match stack.0.poll(timeout) {
+        Ok(ready) => ready,
+        Err(e) => return errno(&e) as isize,
+    };
+    // the connections beyond `max` are reported by the next calls, their readiness not being seen
+    let n = ready.len().min(max);
+    for (i, (id, readiness)) in ready.iter().take(n).enumerate() {
+        *events.add(i) = MtEvent {
+            conn: MtConn::from(id),
+            readable: readiness.readable as u8,
+            writable: readiness.writable as u8,
+        };
+    }
+    n as isize
+}
+
+/// Reads up to `len` octets of the connection into `buf`. Returns how many, 0 once the peer closed its
+/// side, `-EAGAIN` while nothing is received.
+///
+/// # Safety
+/// `stack` was returned by `mt_stack_new`, `conn` points to a `mt_conn` and `buf` to `len` octets.
+#[no_mangle]
+pub unsafe extern "C" fn mt_read(
+    stack: *mut MtStack,
+    conn: *const MtConn,
+    buf: *mut u8,
+    len: usize,
+) -> isize {
+    let (Some(stack), Some(conn)) = (stack.as_mut(), conn.as_ref()) else {
+        return -libc::EINVAL as isize;
+    };
+    if buf.is_null() && len > 0 {
+        return -libc::EINVAL as isize;
+    }
+    let buf = match len {
+        0 => &mut [][..],
+        _ => std::slice::from_raw_parts_mut(buf, len),
+    };
+    match stack.0.read(&ConnectionID::from(conn), buf) {
+        Ok(n) => n as isize,
+        Err(e) => errno(&e) as isize,
+    }
+}
+
+/// Queues up to `len` octets of `data` on the connection. Returns how many, `-EAGAIN` while the send
+/// buffer is full.
+///
+/// # Safety
+/// `stack` was returned by `mt_stack_new`, `conn` points to a `mt_conn` and `data` to `len` octets.
+#[no_mangle]
+pub unsafe extern "C" fn mt_write(
+    stack: *mut MtStack,
+    conn: *const MtConn,
+    data: *const u8,
+    len: usize,
+) -> isize {
+    let (Some(stack), Some(conn)) = (stack.as_mut(), conn.as_ref()) else {
+        return -libc::EINVAL as isize;
+    };
+    if data.is_null() && len > 0 {
+        return -libc::EINVAL as isize;
+    }
+    let data = match len {
+        0 => &[][..],
+        _ => std::slice::from_raw_parts(data, len),
+    };
+    match stack.0.write(&ConnectionID::from(conn), data) {
+        Ok(n) => n as isize,
+        Err(e) => errno(&e) as isize,
+    }
+}
+
+/// Closes the connection, the data written is sent before the FIN.
+///
+/// # Safety
+/// `stack` was returned by `mt_stack_new`, `conn` points to a `mt_conn`.
+#[no_mangle]
+pub unsafe extern "C" fn mt_close(stack: *mut MtStack, conn: *const MtConn) -> c_int {
+    match (stack.as_mut(), conn.as_ref()) {
+        (Some(stack), Some(conn)) => status(stack.0.close(&ConnectionID::from(conn))),
+        _ => -libc::EINVAL,
+    }
+}
+
+/// Makes `mt_read` fail with `-ETIMEDOUT` once reads found nothing for `read_ms`, and `mt_write` once
+/// the send buffer stayed full for `write_ms`, 0 waiting forever, like `SO_RCVTIMEO` and
+/// `SO_SNDTIMEO`.
+///
+/// # Safety
+/// `stack` was returned by `mt_stack_new`, `conn` points to a `mt_conn`.
+#[no_mangle]
+pub unsafe extern "C" fn mt_set_timeouts(
+    stack: *mut MtStack,
+    conn: *const MtConn,
+    read_ms: u64,
+    write_ms: u64,
+) -> c_int {
+    let (Some(stack), Some(conn)) = (stack.as_mut(), conn.as_ref()) else {
+        return -libc::EINVAL;
+    };
+    let timeout = |ms| (ms > 0).then(|| Duration::from_millis(ms));
+    let id = ConnectionID::from(conn);
+    status(
+        stack
+            .0
+            .set_read_timeout(&id, timeout(read_ms))
+            .and_then(|_| stack.0.set_write_timeout(&id, timeout(write_ms))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ffi::{errno, mt_accept, mt_listen, mt_read, mt_set_timeouts, MtConn};
+    use crate::tcp::ConnectionID;
+    use std::io::{self, ErrorKind};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_conn() {
+        let id = ConnectionID {
+            src_addr: Ipv4Addr::new(192, 167, 1, 2),
+            src_port: 40000,
+            dst_addr: Ipv4Addr::new(192, 167, 1, 1),
+            dst_port: 80,
+        };
+        let conn = MtConn::from(&id);
+        assert_eq!(conn.src_addr, 0xc0a70102);
+        assert_eq!(ConnectionID::from(&conn), id);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(errno(&ErrorKind::WouldBlock.into()), -libc::EAGAIN);
+        assert_eq!(
+            errno(&io::Error::from_raw_os_error(libc::EPERM)),
+            -libc::EPERM
+        );
+        let mut conn = MtConn {
+            src_addr: 0,
+            src_port: 0,
+            dst_addr: 0,
+            dst_port: 0,
+        };
+        unsafe {
+            assert_eq!(mt_listen(std::ptr::null_mut(), 80), -libc::EINVAL);
+            assert_eq!(
+                mt_accept(std::ptr::null_mut(), 80, &mut conn),
+                -libc::EINVAL
+            );
+            assert_eq!(
+                mt_read(std::ptr::null_mut(), &conn, std::ptr::null_mut(), 0),
+                -libc::EINVAL as isize
+            );
+            assert_eq!(
+                mt_set_timeouts(std::ptr::null_mut(), &conn, 100, 0),
+                -libc::EINVAL
+            );
+        }
+    }
+}
@@ -0,0 +1,512 @@
+//! The reassembly of the datagrams arriving in fragments, https://www.ietf.org/rfc/rfc791.txt page 26
+//! and https://www.ietf.org/rfc/rfc815.txt: the fragments of a datagram, the ones with the same source,
+//! destination, protocol and identification, are held until they all arrived, then handed on as one
+//! packet with the header of the first fragment.
+//!
+//! A datagram not complete within the reassembly timeout is dropped, as are the fragments held beyond
+//! the memory cap, the oldest datagrams first, so a flood of fragments which never complete can't
+//! exhaust the memory. Overlapping fragments drop their datagram, as the overlaps only serve to evade
+//! firewalls, see https://www.ietf.org/rfc/rfc1858.txt and https://www.ietf.org/rfc/rfc5722.txt; an
+//! exact duplicate is ignored.
+//!
+//! The packets sent exceeding the MTU of the nic are split into fragments instead, `fragment`, unless
+//! they don't allow it with DF, which fail with `FragmentationNeeded` as the nic would drop them.
+
+use crate::wire::{Options, OPT_EOL};
+use crate::ETH_HEADER_OFFSET;
+use anyhow::{anyhow, Result};
+use etherparse::{Ipv4Header, Ipv4HeaderSlice};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How long the fragments of a datagram are held, the 30 seconds of Linux' `ipfrag_time`
+pub const DEFAULT_FRAGMENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The cap of the octets held in fragments, the 4 MiB of Linux' `ipfrag_high_thresh`
+pub const DEFAULT_FRAGMENT_MEM_MAX: usize = 4 << 20;
+
+/// The largest datagram, as its total length is 16 bits
+const MAX_DATAGRAM: usize = 0xFFFF;
+
+/// The MTU every link carries without fragmenting, RFC 791 page 25
+pub const MIN_MTU: usize = 68;
+
+/// The bit of the option type telling the option is copied into all the fragments, RFC 791 page 15
+const COPIED: u8 = 0x80;
+
+/// Whether the packet is a fragment of a larger datagram: more follow, or it's not the first.
+pub fn is_fragment(ip: &Ipv4HeaderSlice) -> bool {
+    ip.more_fragments() || ip.fragments_offset() != 0
+}
+
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy)]
+struct DatagramKey {
+    src: [u8; 4],
+    dst: [u8; 4],
+    protocol: u8,
+    identification: u16,
+}
+
+#[derive(Debug)]
+struct Datagram {
+    /// The header of the first fragment, once it arrived
+    header: Option<Ipv4Header>,
+    data: Vec<u8>,
+    /// The ranges of `data` received, sorted
+    fragments: Vec<(usize, usize)>,
+    /// The length of the data, known once the last fragment arrived
+    len: Option<usize>,
+    /// When the first fragment arrived, the datagram is dropped a timeout later
+    since: Instant,
+}
+
+impl Datagram {
+    fn is_complete(&self) -> bool {
+        let Some(len) = self.len else {
+            return false;
+        };
+        let mut end = 0;
+        for (start, fragment_end) in &self.fragments {
+            if *start > end {
+                return false;
+            }
+            end = end.max(*fragment_end);
+        }
+        self.header.is_some() && end == len
+    }
+}
+
+/// The datagrams being reassembled.
+#[derive(Debug, Default)]
+pub struct Fragments {
+    datagrams: HashMap<DatagramKey, Datagram>,
+    /// The octets held in all the datagrams
+    held: usize,
+}
+
+impl Fragments {
+    /// Adds the fragment `packet`, whose header is `ip`, received at `now`. Returns the datagram once
+    /// complete, fails when the fragment is invalid or its datagram is dropped for it, which is then
+    /// forgotten.
+    pub fn on_fragment(
+        &mut self,
+        ip: &Ipv4HeaderSlice,
+        packet: &[u8],
+        now: Instant,
+        mem_max: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        let key = DatagramKey {
+            src: ip.source(),
+            dst: ip.destination(),
+            protocol: ip.protocol(),
+            identification: ip.identification(),
+        };
+        let header_len = ip.slice().len();
+        let total_len = (ip.total_len() as usize).min(packet.len() - ETH_HEADER_OFFSET);
+        if total_len < header_len {
+            return Err(anyhow!("fragment shorter than its header"));
+        }
+        let data = &packet[ETH_HEADER_OFFSET + header_len..ETH_HEADER_OFFSET + total_len];
+        let start = ip.fragments_offset() as usize * 8;
+        let end = start + data.len();
+        let last = !ip.more_fragments();
+        // all but the last fragment carry a multiple of 8 octets, RFC 791 page 12
+        if !last && !data.len().is_multiple_of(8) {
+            self.drop_datagram(&key);
+            return Err(anyhow!(
+                "fragment of {:} octets, not a multiple of 8",
+                data.len()
+            ));
+        }
+        if header_len + end > MAX_DATAGRAM {
+            self.drop_datagram(&key);
+            return Err(anyhow!("fragments exceed the largest datagram"));
+        }
+
+        let datagram = self.datagrams.entry(key).or_insert_with(|| Datagram {
+            header: None,
+            data: vec![],
+            fragments: vec![],
+            len: None,
+            since: now,
+        });
+        if datagram.fragments.contains(&(start, end)) {
+            log::debug!("duplicate fragment {start:}..{end:} of {key:?}");
+            return Ok(None);
+        }
+        let overlaps = datagram
+            .fragments
+            .iter()
+            .any(|(s, e)| *s < end && start < *e);
+        let beyond_len = datagram.len.is_some_and(|len| end > len);
+        let len_mismatch =
+            last && (datagram.len.is_some_and(|len| len != end) || datagram.data.len() > end);
+        if overlaps || beyond_len || len_mismatch {
+            self.drop_datagram(&key);
+            return Err(anyhow!(
+                "fragment {start:}..{end:} inconsistent with its datagram"
+            ));
+        }
+
+        if start == 0 {
+            datagram.header = Some(ip.to_header());
+        }
+        if last {
+            datagram.len = Some(end);
+        }
+        if datagram.data.len() < end {
+            self.held += end - datagram.data.len();
+            datagram.data.resize(end, 0);
+        }
+        datagram.data[start..end].copy_from_slice(data);
+        let at = datagram.fragments.partition_point(|(s, _)| *s < start);
+        datagram.fragments.insert(at, (start, end));
+
+        if datagram.is_complete() {
+            let datagram = self
+                .datagrams
+                .remove(&key)
+                .expect("the datagram just updated");
+            self.held -= datagram.data.len();
+            return reassemble(datagram).map(Some);
+        }
+        self.evict(mem_max);
+        Ok(None)
+    }
+
+    /// Drops the datagrams not complete within `timeout` of `now`. Returns how many were dropped.
+    pub fn expire(&mut self, now: Instant, timeout: Duration) -> usize {
+        let expired: Vec<DatagramKey> = self
+            .datagrams
+            .iter()
+            .filter(|(_, datagram)| now >= datagram.since + timeout)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &expired {
+            log::debug!("reassembly of {key:?} timed out");
+            self.drop_datagram(key);
+        }
+        expired.len()
+    }
+
+    /// When the oldest datagram expires, none when nothing is being reassembled.
+    pub fn next_deadline(&self, timeout: Duration) -> Option<Instant> {
+        self.datagrams
+            .values()
+            .map(|datagram| datagram.since + timeout)
+            .min()
+    }
+
+    /// The datagrams being reassembled
+    pub fn len(&self) -> usize {
+        self.datagrams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.datagrams.is_empty()
+    }
+
+    /// The octets held
+    pub fn held(&self) -> usize {
+        self.held
+    }
+
+    /// Drops the oldest datagrams until the octets held are within `mem_max`.
+    fn evict(&mut self, mem_max: usize) {
+        while self.held > mem_max {
+            let Some(oldest) = self
+                .datagrams
+                .iter()
+                .min_by_key(|(_, datagram)| datagram.since)
+                .map(|(key, _)| *key)
+            else {
+                return;
+            };
+            log::debug!(
+                "reassembly of {oldest:?} dropped, {:} octets held",
+                self.held
+            );
+            self.drop_datagram(&oldest);
+        }
+    }
+
+    fn drop_datagram(&mut self, key: &DatagramKey) {
+        if let Some(datagram) = self.datagrams.remove(key) {
+            self.held -= datagram.data.len();
+        }
+    }
+}
+
+/// A packet exceeding the MTU could not be sent as it doesn't allow fragmentation, DF being set.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct FragmentationNeeded {
+    pub len: usize,
+    pub mtu: usize,
+}
+
+impl fmt::Display for FragmentationNeeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "packet of {:} octets exceeds the mtu {:} and DF is set",
+            self.len, self.mtu
+        )
+    }
+}
+
+impl std::error::Error for FragmentationNeeded {}
+
+/// Splits the ip packet `packet` into fragments of at most `mtu` octets, identified by
+/// `identification`, which must be unique among the fragmented datagrams to the same destination
+/// in flight, https://www.ietf.org/rfc/rfc6864.txt. A fragment is fragmented further, its offset and
+/// MF kept. The first fragment carries all the options, the others those copied, RFC 791 page 26.
+pub fn fragment(packet: &[u8], mtu: usize, identification: u16) -> Result<Vec<Vec<u8>>> {
+    let (mut header, payload) =
+        Ipv4Header::from_slice(&packet[ETH_HEADER_OFFSET..]).map_err(|e| anyhow!("{e:}"))?;
+    if header.header_len() + payload.len() <= mtu {
+        return Ok(vec![packet.to_vec()]);
+    }
+    if header.dont_fragment {
+        return Err(FragmentationNeeded {
+            len: header.header_len() + payload.len(),
+            mtu,
+        }
+        .into());
+    }
+    if mtu < MIN_MTU {
+        return Err(anyhow!("mtu {mtu:} below the minimum {MIN_MTU:}"));
+    }
+    let payload = &payload[..(header.payload_len as usize).min(payload.len())];
+    let options = header.options().to_vec();
+    let mut copied: Vec<u8> = Options::new(&options)
+        .filter_map(|option| option.ok())
+        .filter(|option| option.kind & COPIED != 0)
+        .flat_map(|option| {
+            [option.kind, option.data.len() as u8 + 2]
+                .into_iter()
+                .chain(option.data.iter().copied())
+        })
+        .collect();
+    copied.resize(copied.len().next_multiple_of(4), OPT_EOL);
+
+    let offset = header.fragments_offset as usize * 8;
+    let more = header.more_fragments;
+    header.identification = identification;
+    let mut fragments = vec![];
+    let mut start = 0;
+    while start < payload.len() {
+        if start > 0 {
+            header.set_options(&copied).map_err(|e| anyhow!("{e:?}"))?;
+        }
+        // all but the last fragment carry a multiple of 8 octets
+        let room = (mtu - header.header_len()) / 8 * 8;
+        let end = (start + room).min(payload.len());
+        header.fragments_offset = ((offset + start) / 8) as u16;
+        header.more_fragments = more || end < payload.len();
+        header.payload_len = (end - start) as u16;
+        let mut fragment = packet[..ETH_HEADER_OFFSET].to_vec();
+        header.write(&mut fragment)?;
+        fragment.extend_from_slice(&payload[start..end]);
+        fragments.push(fragment);
+        start = end;
+    }
+    Ok(fragments)
+}
+
+/// The packet of the complete `datagram`: the header of its first fragment, no longer a fragment, and
+/// its data.
+fn reassemble(datagram: Datagram) -> Result<Vec<u8>> {
+    let mut header = datagram.header.expect("a complete datagram");
+    header.more_fragments = false;
+    header.fragments_offset = 0;
+    header.payload_len = datagram.data.len() as u16;
+    let mut packet = Vec::with_capacity(header.header_len() + datagram.data.len());
+    header.write(&mut packet)?;
+    packet.extend_from_slice(&datagram.data);
+    Ok(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ip::fragment::{fragment, is_fragment, FragmentationNeeded, Fragments};
+    use crate::ip::options::{IPOPT_RA, IPOPT_RR};
+    use etherparse::{Ipv4Header, Ipv4HeaderSlice};
+    use std::time::{Duration, Instant};
+
+    fn datagram_fragment(identification: u16, offset: usize, data: &[u8], more: bool) -> Vec<u8> {
+        let mut ip = Ipv4Header::new(data.len() as u16, 64, 6, [10, 0, 0, 2], [10, 0, 0, 1]);
+        ip.identification = identification;
+        ip.fragments_offset = (offset / 8) as u16;
+        ip.more_fragments = more;
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        packet.extend_from_slice(data);
+        packet
+    }
+
+    fn add(
+        fragments: &mut Fragments,
+        packet: &[u8],
+        now: Instant,
+    ) -> anyhow::Result<Option<Vec<u8>>> {
+        let ip = Ipv4HeaderSlice::from_slice(packet).unwrap();
+        assert!(is_fragment(&ip));
+        fragments.on_fragment(&ip, packet, now, 1 << 20)
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let now = Instant::now();
+        let data: Vec<u8> = (0..40).collect();
+        let mut fragments = Fragments::default();
+        // out of order, with a duplicate
+        assert_eq!(
+            add(
+                &mut fragments,
+                &datagram_fragment(1, 32, &data[32..], false),
+                now
+            )
+            .unwrap(),
+            None
+        );
+        assert_eq!(
+            add(
+                &mut fragments,
+                &datagram_fragment(1, 0, &data[..16], true),
+                now
+            )
+            .unwrap(),
+            None
+        );
+        assert_eq!(
+            add(
+                &mut fragments,
+                &datagram_fragment(1, 0, &data[..16], true),
+                now
+            )
+            .unwrap(),
+            None
+        );
+        assert_eq!(fragments.held(), 40);
+        let packet = add(
+            &mut fragments,
+            &datagram_fragment(1, 16, &data[16..32], true),
+            now,
+        )
+        .unwrap()
+        .unwrap();
+        let ip = Ipv4HeaderSlice::from_slice(&packet).unwrap();
+        assert!(!is_fragment(&ip));
+        assert_eq!(ip.total_len(), 60);
+        assert_eq!(&packet[20..], &data[..]);
+        assert_eq!(
+            ip.to_header().calc_header_checksum().unwrap(),
+            ip.header_checksum()
+        );
+        assert!(fragments.is_empty());
+        assert_eq!(fragments.held(), 0);
+    }
+
+    #[test]
+    fn test_invalid_fragments() {
+        let now = Instant::now();
+        let mut fragments = Fragments::default();
+        add(
+            &mut fragments,
+            &datagram_fragment(1, 0, &[0; 16], true),
+            now,
+        )
+        .unwrap();
+        assert!(add(
+            &mut fragments,
+            &datagram_fragment(1, 8, &[1; 16], true),
+            now
+        )
+        .is_err());
+        assert!(fragments.is_empty());
+        assert!(add(
+            &mut fragments,
+            &datagram_fragment(2, 0, &[0; 12], true),
+            now
+        )
+        .is_err());
+        assert!(add(
+            &mut fragments,
+            &datagram_fragment(3, 65528, &[0; 16], false),
+            now
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_fragment() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut ip = Ipv4Header::new(100, 64, 6, [10, 0, 0, 1], [10, 0, 0, 2]);
+        ip.set_options(&[IPOPT_RR, 7, 4, 0, 0, 0, 0, IPOPT_RA, 4, 0, 0, 0])
+            .unwrap();
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        packet.extend_from_slice(&data);
+        let err = fragment(&packet, 68, 7).unwrap_err();
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&FragmentationNeeded { len: 132, mtu: 68 })
+        );
+        assert_eq!(fragment(&packet, 132, 7).unwrap(), vec![packet.clone()]);
+
+        ip.dont_fragment = false;
+        packet.clear();
+        ip.write(&mut packet).unwrap();
+        packet.extend_from_slice(&data);
+        let fragments = fragment(&packet, 68, 7).unwrap();
+        assert!(fragments.iter().all(|f| f.len() <= 68));
+        let first = Ipv4HeaderSlice::from_slice(&fragments[0]).unwrap();
+        assert_eq!(first.options().len(), 12);
+        assert_eq!(first.payload_len(), 32);
+        // only the router alert is copied
+        let second = Ipv4HeaderSlice::from_slice(&fragments[1]).unwrap();
+        assert_eq!(second.options(), [IPOPT_RA, 4, 0, 0]);
+        assert_eq!((second.identification(), second.fragments_offset()), (7, 4));
+
+        let mut fragments_held = Fragments::default();
+        let now = Instant::now();
+        let mut reassembled = None;
+        for fragment in fragments.iter().rev() {
+            reassembled = add(&mut fragments_held, fragment, now).unwrap();
+        }
+        assert_eq!(&reassembled.unwrap()[32..], &data[..]);
+    }
+
+    #[test]
+    fn test_limits() {
+        let now = Instant::now();
+        let timeout = Duration::from_secs(30);
+        let mut fragments = Fragments::default();
+        add(
+            &mut fragments,
+            &datagram_fragment(1, 0, &[0; 16], true),
+            now,
+        )
+        .unwrap();
+        add(
+            &mut fragments,
+            &datagram_fragment(2, 0, &[0; 16], true),
+            now + Duration::from_secs(1),
+        )
+        .unwrap();
+        assert_eq!(fragments.next_deadline(timeout), Some(now + timeout));
+        assert_eq!(fragments.expire(now + timeout, timeout), 1);
+        assert_eq!(fragments.len(), 1);
+
+        // the oldest datagram makes room
+        let ip_packet = datagram_fragment(3, 0, &[0; 16], true);
+        let ip = Ipv4HeaderSlice::from_slice(&ip_packet).unwrap();
+        fragments
+            .on_fragment(&ip, &ip_packet, now + timeout, 16)
+            .unwrap();
+        assert_eq!(fragments.len(), 1);
+        assert_eq!(fragments.held(), 16);
+        assert_eq!(fragments.expire(now + timeout + timeout, timeout), 1);
+    }
+}
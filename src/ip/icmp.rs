@@ -0,0 +1,323 @@
+//! ICMP, https://www.ietf.org/rfc/rfc792.txt: the errors the stack reports about the datagrams it
+//! can't deliver. A datagram for a protocol the stack doesn't handle is answered with a destination
+//! unreachable, protocol unreachable, or port unreachable for UDP as no port is open,
+//! https://www.ietf.org/rfc/rfc1122.txt 3.2.2.1, so the peer fails fast instead of timing out.
+//!
+//! No error answers an ICMP message, a datagram to a broadcast or multicast address, a fragment but the
+//! first, or one from an address which isn't a single host, RFC 1122 3.2.2; the stack rate limits the
+//! rest, see `Tunables::icmp_rate_limit`.
+//!
+//! The errors received about a segment of a connection, `IcmpError`, are acted upon as RFC 1122 4.2.3.9
+//! has it: a hard error aborts the connection, a soft one is only reported. They count once they quote
+//! a sequence number in flight, https://www.ietf.org/rfc/rfc5927.txt 4.1, the others being forged or
+//! stale.
+
+use crate::core::checksum;
+use crate::ip::IpParams;
+use crate::tcp::ConnectionID;
+use crate::{ETH_HEADER_OFFSET, TCP_PROTOCOL};
+use anyhow::{anyhow, Result};
+use etherparse::{Ipv4Header, Ipv4HeaderSlice, SerializedSize};
+use std::io::ErrorKind;
+use std::net::Ipv4Addr;
+
+pub const ICMP_PROTOCOL: u8 = 1;
+pub const UDP_PROTOCOL: u8 = 17;
+
+pub const TYPE_DEST_UNREACHABLE: u8 = 3;
+pub const TYPE_TIME_EXCEEDED: u8 = 11;
+pub const TYPE_PARAMETER_PROBLEM: u8 = 12;
+
+pub const CODE_NET_UNREACHABLE: u8 = 0;
+pub const CODE_HOST_UNREACHABLE: u8 = 1;
+pub const CODE_PROTOCOL_UNREACHABLE: u8 = 2;
+pub const CODE_PORT_UNREACHABLE: u8 = 3;
+pub const CODE_FRAGMENTATION_NEEDED: u8 = 4;
+
+/// The ICMP errors sent per second at most, as Linux's `icmp_msgs_per_sec`
+pub const DEFAULT_ICMP_RATE_LIMIT: u64 = 1000;
+/// The ICMP errors sent at once after a quiet period, as Linux's `icmp_msgs_burst`
+pub const ICMP_BURST: u64 = 50;
+
+/// The type, the code, the checksum and the 4 octets unused by a destination unreachable
+pub const ICMP_HEADER_LEN: usize = 8;
+
+/// The largest error sent, quoting as much of the datagram as fits, https://www.ietf.org/rfc/rfc1812.txt
+/// 4.3.2.3
+const MAX_ERROR_LEN: usize = 576;
+
+/// The destination unreachable answering `packet`, a datagram for a protocol the stack doesn't handle,
+/// sent with the fields of `ip`. None when no error may answer it.
+pub fn unreachable(packet: &[u8], ip: IpParams) -> Option<Vec<u8>> {
+    let datagram = &packet[ETH_HEADER_OFFSET..];
+    let header = Ipv4HeaderSlice::from_slice(datagram).ok()?;
+    if !may_answer(&header) {
+        return None;
+    }
+    let code = match header.protocol() {
+        UDP_PROTOCOL => CODE_PORT_UNREACHABLE,
+        _ => CODE_PROTOCOL_UNREACHABLE,
+    };
+    let len = (header.total_len() as usize).min(datagram.len());
+    let quoted =
+        &datagram[..len.min(MAX_ERROR_LEN - Ipv4Header::SERIALIZED_SIZE - ICMP_HEADER_LEN)];
+
+    let mut message = vec![TYPE_DEST_UNREACHABLE, code, 0, 0, 0, 0, 0, 0];
+    message.extend_from_slice(quoted);
+    let sum = !checksum::fold(checksum::sum(&message, 0));
+    message[2..4].copy_from_slice(&sum.to_be_bytes());
+
+    let mut reply = Ipv4Header::new(
+        message.len() as u16,
+        ip.ttl,
+        ICMP_PROTOCOL,
+        header.destination(),
+        header.source(),
+    );
+    reply.differentiated_services_code_point = ip.dscp;
+    reply.dont_fragment = ip.dont_fragment;
+    let mut packet = packet[..ETH_HEADER_OFFSET].to_vec();
+    reply.write(&mut packet).ok()?;
+    packet.extend_from_slice(&message);
+    Some(packet)
+}
+
+/// An ICMP error about a tcp segment the stack sent
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct IcmpError {
+    pub icmp_type: u8,
+    pub code: u8,
+    /// The connection of the segment quoted
+    pub id: ConnectionID,
+    /// The sequence number of the segment quoted
+    pub seq: u32,
+}
+
+impl IcmpError {
+    /// The error in `packet`, an ICMP message. Fails unless it's a destination unreachable, a time
+    /// exceeded or a parameter problem quoting the ip header and the first 8 octets of a tcp segment,
+    /// with a valid checksum; a source quench is ignored, https://www.ietf.org/rfc/rfc6633.txt.
+    pub fn parse(packet: &[u8]) -> Result<Self> {
+        let datagram = &packet[ETH_HEADER_OFFSET..];
+        let ip = Ipv4HeaderSlice::from_slice(datagram)?;
+        if ip.protocol() != ICMP_PROTOCOL {
+            return Err(anyhow!("not an icmp message"));
+        }
+        let end = (ip.total_len() as usize).min(datagram.len());
+        let message = datagram.get(ip.slice().len()..end).unwrap_or_default();
+        if message.len() < ICMP_HEADER_LEN {
+            return Err(anyhow!("icmp message of {:} octets", message.len()));
+        }
+        if !checksum::is_valid(checksum::sum(message, 0)) {
+            return Err(anyhow!("bad icmp checksum"));
+        }
+        let (icmp_type, code) = (message[0], message[1]);
+        if !matches!(
+            icmp_type,
+            TYPE_DEST_UNREACHABLE | TYPE_TIME_EXCEEDED | TYPE_PARAMETER_PROBLEM
+        ) {
+            return Err(anyhow!("icmp type {icmp_type:} is no error"));
+        }
+        let quoted = Ipv4HeaderSlice::from_slice(&message[ICMP_HEADER_LEN..])?;
+        if quoted.protocol() != TCP_PROTOCOL {
+            return Err(anyhow!("icmp error quoting no tcp segment"));
+        }
+        // the ports and the sequence number
+        let tcp = &message[ICMP_HEADER_LEN + quoted.slice().len()..];
+        let Some(tcp) = tcp.get(..8) else {
+            return Err(anyhow!("icmp error quoting {:} octets of tcp", tcp.len()));
+        };
+        Ok(Self {
+            icmp_type,
+            code,
+            id: ConnectionID {
+                src_addr: quoted.destination_addr(),
+                src_port: u16::from_be_bytes([tcp[2], tcp[3]]),
+                dst_addr: quoted.source_addr(),
+                dst_port: u16::from_be_bytes([tcp[0], tcp[1]]),
+            },
+            seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+        })
+    }
+
+    /// Whether the error aborts the connection: protocol and port unreachable, RFC 1122 4.2.3.9, and the
+    /// destinations unknown or administratively prohibited, as Linux does. Fragmentation needed is soft
+    /// with no path MTU discovery to act on it, like the other unreachables, the time exceeded and the
+    /// parameter problems.
+    pub fn is_hard(&self) -> bool {
+        self.icmp_type == TYPE_DEST_UNREACHABLE
+            && matches!(
+                self.code,
+                CODE_PROTOCOL_UNREACHABLE | CODE_PORT_UNREACHABLE | 6 | 7 | 9 | 10 | 13
+            )
+    }
+
+    /// The error the application gets, `ECONNREFUSED`, `ENETUNREACH` or `EHOSTUNREACH`.
+    pub fn kind(&self) -> ErrorKind {
+        match (self.icmp_type, self.code) {
+            (TYPE_DEST_UNREACHABLE, CODE_PROTOCOL_UNREACHABLE | CODE_PORT_UNREACHABLE) => {
+                ErrorKind::ConnectionRefused
+            }
+            (TYPE_DEST_UNREACHABLE, CODE_NET_UNREACHABLE | 6 | 9 | 11) => {
+                ErrorKind::NetworkUnreachable
+            }
+            _ => ErrorKind::HostUnreachable,
+        }
+    }
+}
+
+/// Whether an ICMP error may answer the datagram of `header`, RFC 1122 3.2.2.
+fn may_answer(header: &Ipv4HeaderSlice) -> bool {
+    let src = header.source_addr();
+    let dst = header.destination_addr();
+    let single_host = |addr: Ipv4Addr| {
+        !(addr.is_unspecified()
+            || addr.is_broadcast()
+            || addr.is_multicast()
+            || addr.is_loopback()
+            // class E
+            || addr.octets()[0] >= 240)
+    };
+    header.protocol() != ICMP_PROTOCOL
+        && header.fragments_offset() == 0
+        && single_host(src)
+        && !dst.is_broadcast()
+        && !dst.is_multicast()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::checksum;
+    use crate::ip::icmp::{
+        unreachable, IcmpError, CODE_FRAGMENTATION_NEEDED, CODE_HOST_UNREACHABLE,
+        CODE_PORT_UNREACHABLE, CODE_PROTOCOL_UNREACHABLE, ICMP_PROTOCOL, TYPE_DEST_UNREACHABLE,
+        TYPE_TIME_EXCEEDED, UDP_PROTOCOL,
+    };
+    use crate::ip::IpParams;
+    use crate::tcp::ConnectionID;
+    use crate::TCP_PROTOCOL;
+    use etherparse::{Ipv4Header, Ipv4HeaderSlice, TcpHeader};
+    use std::io::ErrorKind;
+
+    fn datagram(protocol: u8, src: [u8; 4], dst: [u8; 4], len: usize) -> Vec<u8> {
+        let ip = Ipv4Header::new(len as u16, 64, protocol, src, dst);
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        packet.extend((0..len).map(|i| i as u8));
+        packet
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let udp = datagram(UDP_PROTOCOL, [10, 0, 0, 2], [10, 0, 0, 1], 1000);
+        let reply = unreachable(&udp, IpParams::default()).unwrap();
+        let ip = Ipv4HeaderSlice::from_slice(&reply).unwrap();
+        assert_eq!(ip.protocol(), ICMP_PROTOCOL);
+        assert_eq!(
+            (ip.source(), ip.destination()),
+            ([10, 0, 0, 1], [10, 0, 0, 2])
+        );
+        assert_eq!(reply.len(), 576);
+        let message = &reply[20..];
+        assert_eq!(
+            &message[..2],
+            [TYPE_DEST_UNREACHABLE, CODE_PORT_UNREACHABLE]
+        );
+        assert!(checksum::is_valid(checksum::sum(message, 0)));
+        assert_eq!(&message[8..], &udp[..548]);
+
+        let sctp = datagram(132, [10, 0, 0, 2], [10, 0, 0, 1], 8);
+        let reply = unreachable(&sctp, IpParams::default()).unwrap();
+        assert_eq!(reply[21], CODE_PROTOCOL_UNREACHABLE);
+        assert_eq!(&reply[28..], &sctp[..]);
+    }
+
+    #[test]
+    fn test_no_error() {
+        let answered = |protocol, src, dst| {
+            unreachable(&datagram(protocol, src, dst, 8), IpParams::default()).is_some()
+        };
+        assert!(!answered(ICMP_PROTOCOL, [10, 0, 0, 2], [10, 0, 0, 1]));
+        assert!(!answered(UDP_PROTOCOL, [0, 0, 0, 0], [10, 0, 0, 1]));
+        assert!(!answered(UDP_PROTOCOL, [10, 0, 0, 2], [255, 255, 255, 255]));
+        assert!(!answered(UDP_PROTOCOL, [10, 0, 0, 2], [224, 0, 0, 1]));
+
+        let mut ip = Ipv4Header::new(8, 64, UDP_PROTOCOL, [10, 0, 0, 2], [10, 0, 0, 1]);
+        ip.dont_fragment = false;
+        ip.fragments_offset = 2;
+        let mut fragment = vec![];
+        ip.write(&mut fragment).unwrap();
+        fragment.extend_from_slice(&[0; 8]);
+        assert!(unreachable(&fragment, IpParams::default()).is_none());
+    }
+
+    /// An ICMP error from 10.0.0.2 quoting `quoted`.
+    fn icmp(icmp_type: u8, code: u8, quoted: &[u8]) -> Vec<u8> {
+        let mut message = vec![icmp_type, code, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(quoted);
+        let sum = !checksum::fold(checksum::sum(&message, 0));
+        message[2..4].copy_from_slice(&sum.to_be_bytes());
+        let ip = Ipv4Header::new(
+            message.len() as u16,
+            64,
+            ICMP_PROTOCOL,
+            [10, 0, 0, 2],
+            [10, 0, 0, 1],
+        );
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        packet.extend_from_slice(&message);
+        packet
+    }
+
+    #[test]
+    fn test_icmp_error() {
+        let tcp = TcpHeader::new(80, 40000, 1000, 64240);
+        let ip = Ipv4Header::new(
+            tcp.header_len(),
+            64,
+            TCP_PROTOCOL,
+            [10, 0, 0, 1],
+            [10, 0, 0, 2],
+        );
+        let mut segment = vec![];
+        ip.write(&mut segment).unwrap();
+        tcp.write(&mut segment).unwrap();
+        let id = ConnectionID {
+            src_addr: [10, 0, 0, 2].into(),
+            src_port: 40000,
+            dst_addr: [10, 0, 0, 1].into(),
+            dst_port: 80,
+        };
+
+        let error = IcmpError::parse(&icmp(
+            TYPE_DEST_UNREACHABLE,
+            CODE_PORT_UNREACHABLE,
+            &segment[..28],
+        ));
+        let error = error.unwrap();
+        assert_eq!((error.id.clone(), error.seq), (id, 1000));
+        assert!(error.is_hard());
+        assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+
+        let soft = |icmp_type, code| {
+            let error = IcmpError::parse(&icmp(icmp_type, code, &segment)).unwrap();
+            assert_eq!(error.kind(), ErrorKind::HostUnreachable);
+            !error.is_hard()
+        };
+        assert!(soft(TYPE_DEST_UNREACHABLE, CODE_HOST_UNREACHABLE));
+        assert!(soft(TYPE_DEST_UNREACHABLE, CODE_FRAGMENTATION_NEEDED));
+        assert!(soft(TYPE_TIME_EXCEEDED, 0));
+        assert!(!soft(TYPE_DEST_UNREACHABLE, 13));
+
+        // a source quench, too short a quote, a bad checksum
+        assert!(IcmpError::parse(&icmp(4, 0, &segment)).is_err());
+        assert!(IcmpError::parse(&icmp(TYPE_DEST_UNREACHABLE, 0, &segment[..27])).is_err());
+        let mut corrupt = icmp(TYPE_DEST_UNREACHABLE, 0, &segment);
+        corrupt[30] ^= 1;
+        assert!(IcmpError::parse(&corrupt).is_err());
+        // an error about a datagram of another protocol
+        let udp = datagram(UDP_PROTOCOL, [10, 0, 0, 1], [10, 0, 0, 2], 8);
+        assert!(IcmpError::parse(&icmp(TYPE_DEST_UNREACHABLE, 0, &udp)).is_err());
+    }
+}
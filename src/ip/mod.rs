@@ -0,0 +1,82 @@
+//! The ip layer under the connections, https://www.ietf.org/rfc/rfc791.txt: what the stack does with
+//! the ipv4 header of the packets it receives beyond parsing them in `wire`, and the fields it puts
+//! in the header of those it sends.
+
+pub mod fragment;
+pub mod icmp;
+pub mod options;
+pub mod route;
+
+use crate::device::NetworkDevice;
+use crate::ip::options::IpOptions;
+use crate::tcp::ecn::NOT_ECT;
+use crate::tcp::DEFAULT_TTL;
+use anyhow::Result;
+use std::sync::atomic::{AtomicU16, Ordering};
+
+/// The DSCP of the lower effort traffic, https://www.ietf.org/rfc/rfc8622.txt
+pub const DSCP_LE: u8 = 1;
+/// The DSCP of the multimedia conferencing class, https://www.ietf.org/rfc/rfc2597.txt
+pub const DSCP_AF41: u8 = 34;
+/// The DSCP of the Expedited Forwarding of latency sensitive traffic, https://www.ietf.org/rfc/rfc3246.txt
+pub const DSCP_EF: u8 = 46;
+/// The largest DSCP, the 6 high bits of the TOS octet
+pub const DSCP_MAX: u8 = 63;
+
+/// The identification of the next datagram fragmented, the others don't need one, see
+/// https://www.ietf.org/rfc/rfc6864.txt
+static IDENTIFICATION: AtomicU16 = AtomicU16::new(1);
+
+/// The fields of the ip header of the packets a connection sends which aren't fixed by its 4-tuple
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct IpParams {
+    /// The Differentiated Services codepoint, the class of the packets, https://www.ietf.org/rfc/rfc2474.txt
+    pub dscp: u8,
+    /// The ECN codepoint, see `tcp::ecn`
+    pub ecn: u8,
+    /// The time to live, the hops the packets may take, https://www.ietf.org/rfc/rfc1122.txt 3.2.1.7
+    pub ttl: u8,
+    /// The options following the header, none by default
+    pub options: IpOptions,
+    /// Set DF, forbidding the fragmentation of the packets, for the path MTU discovery of
+    /// https://www.ietf.org/rfc/rfc1191.txt
+    pub dont_fragment: bool,
+}
+
+impl Default for IpParams {
+    fn default() -> Self {
+        Self {
+            dscp: 0,
+            ecn: NOT_ECT,
+            ttl: DEFAULT_TTL,
+            options: IpOptions::default(),
+            dont_fragment: true,
+        }
+    }
+}
+
+/// Whether the nic fills in the tcp checksum of a packet of `len` octets, one it sends whole.
+pub fn offloads_checksum(nic: &dyn NetworkDevice, len: usize) -> bool {
+    nic.checksum_offload().tx && nic.mtu().is_none_or(|mtu| len <= mtu)
+}
+
+/// Writes the ip packet `packet` to the nic, in fragments when it exceeds the MTU of the nic. Fails
+/// with `fragment::FragmentationNeeded` when it does with DF set.
+pub fn send(nic: &dyn NetworkDevice, packet: &[u8]) -> Result<()> {
+    match nic.mtu() {
+        Some(mtu) if packet.len() > mtu => {
+            let identification = IDENTIFICATION.fetch_add(1, Ordering::Relaxed);
+            let fragments = fragment::fragment(packet, mtu, identification)?;
+            log::debug!(
+                "{:} octets sent in {:} fragments",
+                packet.len(),
+                fragments.len()
+            );
+            nic.send_batch(&fragments)?;
+        }
+        _ => {
+            nic.send(packet)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,243 @@
+//! The options of the ipv4 header, https://www.ietf.org/rfc/rfc791.txt page 15, framed as tcp options
+//! are, by `wire::Options`. A host ignores the options it doesn't know,
+//! https://www.ietf.org/rfc/rfc1122.txt 3.2.1.8, which the stack does with all of them by default: the
+//! `IpOptionsPolicy` tunable drops the packets with source route options instead, as Linux does unless
+//! `accept_source_route` is set, or the packets with any option. A source route received is never
+//! reversed for the replies, which RFC 1122 4.2.3.8 allows.
+//!
+//! The packets of a connection carry the options of its `SocketOption::IpOptions`, e.g. a router alert
+//! of https://www.ietf.org/rfc/rfc2113.txt, as `IP_OPTIONS` of `setsockopt(2)` sets them.
+
+use crate::wire::{OptionError, Options, RawOption, OPT_EOL, OPT_NOP};
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+/// The room for options in the header, which is at most 15 words
+pub const MAX_OPTIONS_LEN: usize = 40;
+
+pub const IPOPT_RR: u8 = 7;
+pub const IPOPT_TS: u8 = 68;
+pub const IPOPT_SEC: u8 = 130;
+pub const IPOPT_LSRR: u8 = 131;
+pub const IPOPT_SID: u8 = 136;
+pub const IPOPT_SSRR: u8 = 137;
+pub const IPOPT_RA: u8 = 148;
+
+/// An ip option, the data of those routing or timestamping being the pointer and the slots after it
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum IpOption<'a> {
+    /// https://www.ietf.org/rfc/rfc1108.txt
+    Security(&'a [u8]),
+    /// RFC 791 page 18
+    LooseSourceRoute(&'a [u8]),
+    /// RFC 791 page 19
+    StrictSourceRoute(&'a [u8]),
+    /// RFC 791 page 20
+    RecordRoute(&'a [u8]),
+    /// RFC 791 page 21
+    StreamId(u16),
+    /// RFC 791 page 22
+    Timestamp(&'a [u8]),
+    /// The value, 0 asking the routers to examine the packet, https://www.ietf.org/rfc/rfc2113.txt
+    RouterAlert(u16),
+    Unknown(RawOption<'a>),
+}
+
+impl<'a> IpOption<'a> {
+    fn parse(raw: RawOption<'a>) -> Result<Self, OptionError> {
+        let value = |data: &[u8]| match data {
+            [high, low] => Ok(u16::from_be_bytes([*high, *low])),
+            _ => Err(OptionError::BadLength(raw.kind)),
+        };
+        Ok(match raw.kind {
+            IPOPT_SEC => IpOption::Security(raw.data),
+            IPOPT_LSRR => IpOption::LooseSourceRoute(raw.data),
+            IPOPT_SSRR => IpOption::StrictSourceRoute(raw.data),
+            IPOPT_RR => IpOption::RecordRoute(raw.data),
+            IPOPT_SID => IpOption::StreamId(value(raw.data)?),
+            IPOPT_TS => IpOption::Timestamp(raw.data),
+            IPOPT_RA => IpOption::RouterAlert(value(raw.data)?),
+            _ => IpOption::Unknown(raw),
+        })
+    }
+
+    pub fn is_source_route(&self) -> bool {
+        matches!(
+            self,
+            IpOption::LooseSourceRoute(_) | IpOption::StrictSourceRoute(_)
+        )
+    }
+}
+
+/// The options in `bytes`, the options of an ip header, the NOPs and the end of option list skipped.
+pub fn iter(bytes: &[u8]) -> impl Iterator<Item = Result<IpOption<'_>, OptionError>> {
+    Options::new(bytes)
+        .filter(|option| !matches!(option, Ok(raw) if raw.kind == OPT_EOL || raw.kind == OPT_NOP))
+        .map(|option| option.and_then(IpOption::parse))
+}
+
+/// What to do with the packets received with ip options
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum IpOptionsPolicy {
+    /// Process the segment, the options ignored
+    Ignore,
+    /// Drop the packets with a source route, or options too malformed to tell
+    RejectSourceRoute,
+    /// Drop the packets with any option
+    Reject,
+}
+
+impl IpOptionsPolicy {
+    /// Why the packet with the options `bytes` is dropped, none when it's processed.
+    pub fn rejects(self, bytes: &[u8]) -> Option<&'static str> {
+        match self {
+            IpOptionsPolicy::Ignore => None,
+            IpOptionsPolicy::RejectSourceRoute => iter(bytes).find_map(|option| match option {
+                Ok(option) if option.is_source_route() => Some("source route"),
+                Ok(_) => None,
+                Err(_) => Some("malformed ip options"),
+            }),
+            IpOptionsPolicy::Reject => {
+                // a header with only padding has no option
+                iter(bytes).next().map(|_| "ip options")
+            }
+        }
+    }
+}
+
+impl TryFrom<u64> for IpOptionsPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(IpOptionsPolicy::Ignore),
+            1 => Ok(IpOptionsPolicy::RejectSourceRoute),
+            2 => Ok(IpOptionsPolicy::Reject),
+            _ => Err(anyhow!(
+                "unknown ip options policy {value:}, expect 0 (ignore), 1 (reject source route) or 2 (reject)"
+            )),
+        }
+    }
+}
+
+impl From<IpOptionsPolicy> for u64 {
+    fn from(policy: IpOptionsPolicy) -> Self {
+        match policy {
+            IpOptionsPolicy::Ignore => 0,
+            IpOptionsPolicy::RejectSourceRoute => 1,
+            IpOptionsPolicy::Reject => 2,
+        }
+    }
+}
+
+/// The options of the ip header of the packets sent, kept inline so the socket options stay `Copy`
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub struct IpOptions {
+    len: u8,
+    bytes: [u8; MAX_OPTIONS_LEN],
+}
+
+impl Default for IpOptions {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            bytes: [0; MAX_OPTIONS_LEN],
+        }
+    }
+}
+
+impl fmt::Debug for IpOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("IpOptions").field(&self.as_bytes()).finish()
+    }
+}
+
+impl IpOptions {
+    /// The options of `bytes`, padded with the end of option list to a multiple of 4 octets. Fails
+    /// unless they are well formed and fit in the header.
+    pub fn new(bytes: &[u8]) -> Result<Self> {
+        let len = bytes.len().next_multiple_of(4);
+        if len > MAX_OPTIONS_LEN {
+            return Err(anyhow!(
+                "ip options of {len:} octets exceed {MAX_OPTIONS_LEN:}"
+            ));
+        }
+        if let Some(Err(e)) = iter(bytes).find(Result::is_err) {
+            return Err(anyhow!("malformed ip options: {e:?}"));
+        }
+        let mut options = Self::default();
+        options.bytes[..bytes.len()].copy_from_slice(bytes);
+        options.len = len as u8;
+        Ok(options)
+    }
+
+    /// The router alert option asking every router on the path to examine the packets
+    pub fn router_alert() -> Self {
+        Self::new(&[IPOPT_RA, 4, 0, 0]).expect("a well formed option")
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ip::options::{iter, IpOption, IpOptions, IpOptionsPolicy, IPOPT_LSRR, IPOPT_RA};
+    use crate::wire::{OptionError, OPT_EOL, OPT_NOP};
+
+    #[test]
+    fn test_iter() {
+        let bytes = [
+            OPT_NOP, IPOPT_RA, 4, 0, 0, IPOPT_LSRR, 7, 4, 10, 0, 0, 1, OPT_EOL,
+        ];
+        let options: Vec<_> = iter(&bytes).collect();
+        assert_eq!(
+            options,
+            vec![
+                Ok(IpOption::RouterAlert(0)),
+                Ok(IpOption::LooseSourceRoute(&[4, 10, 0, 0, 1])),
+            ]
+        );
+        assert_eq!(
+            iter(&[IPOPT_RA, 3, 0]).next(),
+            Some(Err(OptionError::BadLength(IPOPT_RA)))
+        );
+    }
+
+    #[test]
+    fn test_policy() {
+        let source_routed = [IPOPT_LSRR, 7, 4, 10, 0, 0, 1, OPT_EOL];
+        let alert = IpOptions::router_alert();
+        assert_eq!(IpOptionsPolicy::Ignore.rejects(&source_routed), None);
+        assert_eq!(
+            IpOptionsPolicy::RejectSourceRoute.rejects(&source_routed),
+            Some("source route")
+        );
+        assert_eq!(
+            IpOptionsPolicy::RejectSourceRoute.rejects(alert.as_bytes()),
+            None
+        );
+        assert_eq!(
+            IpOptionsPolicy::Reject.rejects(alert.as_bytes()),
+            Some("ip options")
+        );
+        assert_eq!(
+            IpOptionsPolicy::Reject.rejects(&[OPT_NOP, OPT_EOL, 0, 0]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_ip_options() {
+        let options = IpOptions::new(&[IPOPT_RA, 4, 0, 0, OPT_NOP]).unwrap();
+        assert_eq!(options.as_bytes(), [IPOPT_RA, 4, 0, 0, OPT_NOP, 0, 0, 0]);
+        assert!(IpOptions::default().is_empty());
+        assert!(IpOptions::new(&[IPOPT_RA, 9, 0]).is_err());
+        assert!(IpOptions::new(&[OPT_NOP; 41]).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+//! The routing table of a stack on several interfaces, see `device::Interfaces`: a packet leaves
+//! through the interface of the longest prefix matching its destination, the default route being the
+//! prefix of length 0, https://www.ietf.org/rfc/rfc1812.txt 5.2.4.3. The interfaces are point-to-point
+//! or on link, their next hop is the device's business, so a route has no gateway. The route to a peer
+//! also picks the source address of the connections to it, the address of the route's interface unless
+//! the route names another, https://www.ietf.org/rfc/rfc1122.txt 3.3.4.3.
+
+use anyhow::{anyhow, Result};
+use std::net::Ipv4Addr;
+
+/// The destinations in `dst`/`prefix_len` go out through the interface `interface`
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Route {
+    pub dst: Ipv4Addr,
+    pub prefix_len: u8,
+    /// The index of the interface, see `Interfaces::add`
+    pub interface: usize,
+    /// The source address of the connections to its destinations, the interface's when none, as
+    /// `ip route add ... src` sets
+    pub src: Option<Ipv4Addr>,
+}
+
+impl Route {
+    /// The route of the prefix of `dst`, its host bits cleared. Fails when the prefix is longer than 32.
+    pub fn new(dst: Ipv4Addr, prefix_len: u8, interface: usize) -> Result<Self> {
+        if prefix_len > 32 {
+            return Err(anyhow!("prefix length {prefix_len:} exceeds 32"));
+        }
+        Ok(Self {
+            dst: Ipv4Addr::from(u32::from(dst) & mask(prefix_len)),
+            prefix_len,
+            interface,
+            src: None,
+        })
+    }
+
+    /// The route of every destination, the one taken when no other matches.
+    pub fn default_route(interface: usize) -> Self {
+        Self {
+            dst: Ipv4Addr::UNSPECIFIED,
+            prefix_len: 0,
+            interface,
+            src: None,
+        }
+    }
+
+    /// The route with `src` as the preferred source address.
+    pub fn with_src(self, src: Ipv4Addr) -> Self {
+        Self {
+            src: Some(src),
+            ..self
+        }
+    }
+
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & mask(self.prefix_len) == u32::from(self.dst)
+    }
+}
+
+/// The netmask of a prefix of `prefix_len` bits.
+fn mask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+/// The routes, the longest prefixes first so the first one matching is the one taken
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct RoutingTable {
+    routes: Vec<Route>,
+}
+
+impl RoutingTable {
+    /// Adds `route`, replacing the one of the same prefix.
+    pub fn add(&mut self, route: Route) {
+        self.remove(route.dst, route.prefix_len);
+        let at = self
+            .routes
+            .partition_point(|r| r.prefix_len >= route.prefix_len);
+        self.routes.insert(at, route);
+    }
+
+    /// Removes the route of `dst`/`prefix_len`, returns whether there was one.
+    pub fn remove(&mut self, dst: Ipv4Addr, prefix_len: u8) -> bool {
+        let before = self.routes.len();
+        self.routes
+            .retain(|r| (r.dst, r.prefix_len) != (dst, prefix_len));
+        self.routes.len() != before
+    }
+
+    /// The route the packets to `dst` take, none when no route matches.
+    pub fn lookup(&self, dst: Ipv4Addr) -> Option<&Route> {
+        self.routes.iter().find(|r| r.contains(dst))
+    }
+
+    /// The route the packets to `dst` take among those through the interface `interface`.
+    pub fn lookup_via(&self, dst: Ipv4Addr, interface: usize) -> Option<&Route> {
+        self.routes
+            .iter()
+            .find(|r| r.interface == interface && r.contains(dst))
+    }
+
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ip::route::{Route, RoutingTable};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_lookup() {
+        let mut table = RoutingTable::default();
+        assert_eq!(table.lookup(Ipv4Addr::new(10, 0, 0, 1)), None);
+        table.add(Route::default_route(0));
+        table.add(Route::new(Ipv4Addr::new(10, 0, 0, 0), 8, 1).unwrap());
+        table.add(Route::new(Ipv4Addr::new(10, 1, 2, 3), 16, 2).unwrap());
+
+        let interface = |table: &RoutingTable, dst| table.lookup(dst).unwrap().interface;
+        assert_eq!(interface(&table, Ipv4Addr::new(10, 1, 0, 9)), 2);
+        assert_eq!(interface(&table, Ipv4Addr::new(10, 2, 0, 9)), 1);
+        assert_eq!(interface(&table, Ipv4Addr::new(192, 168, 0, 1)), 0);
+
+        // replaced, then removed
+        table.add(Route::new(Ipv4Addr::new(10, 0, 0, 0), 8, 3).unwrap());
+        assert_eq!(interface(&table, Ipv4Addr::new(10, 2, 0, 9)), 3);
+        assert_eq!(table.routes().len(), 3);
+        assert!(table.remove(Ipv4Addr::new(10, 1, 0, 0), 16));
+        assert_eq!(interface(&table, Ipv4Addr::new(10, 1, 0, 9)), 3);
+        let via = table.lookup_via(Ipv4Addr::new(10, 1, 0, 9), 0).unwrap();
+        assert_eq!(via.prefix_len, 0);
+        assert_eq!(table.lookup_via(Ipv4Addr::new(10, 1, 0, 9), 1), None);
+
+        assert!(Route::new(Ipv4Addr::new(10, 0, 0, 1), 33, 0).is_err());
+        assert!(Route::new(Ipv4Addr::new(10, 0, 0, 1), 32, 0)
+            .unwrap()
+            .contains(Ipv4Addr::new(10, 0, 0, 1)));
+    }
+}
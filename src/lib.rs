@@ -0,0 +1,91 @@
+//! A TCP stack in user space on a TUN device, https://www.ietf.org/rfc/rfc793.txt and the RFCs after it.
+//!
+//! `TcpStack` runs the stack on a device from the application's own loop:
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! let mut stack = mini_tcp::TcpStackBuilder::new().build()?;
+//! stack.listen(80)?;
+//! let mut buf = [0u8; 1500];
+//! loop {
+//!     for (id, readiness) in stack.poll(None)? {
+//!         while readiness.readable {
+//!             match stack.read(&id, &mut buf) {
+//!                 Ok(n) => println!("{:?}", &buf[..n]),
+//!                 Err(_) => break,
+//!             }
+//!         }
+//!     }
+//! }
+//! # }
+//! ```
+//!
+//! `TcpStackBuilder` names and sets up the device and picks the tunables to start from. The
+//! `stack::Stack` alone is driven by any event loop: `threaded` runs it on a packet thread of its own,
+//! `runtime` as a tokio task with the `tokio` feature. The connections, their sequence spaces and
+//! every algorithm they run are in `tcp`, on top of the sequence arithmetic, the TCB types and the
+//! segment checks of `core`, which build without std when the default `std` feature is off.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[cfg(feature = "std")]
+pub mod arp;
+#[cfg(all(feature = "std", unix))]
+pub mod builder;
+#[cfg(feature = "std")]
+pub mod config;
+pub mod core;
+#[cfg(feature = "std")]
+pub mod ctl;
+#[cfg(feature = "std")]
+pub mod device;
+#[cfg(feature = "dpdk")]
+pub mod dpdk;
+#[cfg(feature = "std")]
+pub mod ethernet;
+#[cfg(all(feature = "ffi", unix))]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod ip;
+#[cfg(all(feature = "std", unix))]
+pub mod multiqueue;
+#[cfg(all(feature = "std", unix))]
+pub mod netlink;
+#[cfg(feature = "std")]
+pub mod observer;
+#[cfg(all(feature = "tokio", unix))]
+pub mod runtime;
+#[cfg(feature = "std")]
+pub mod stack;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod tcp;
+#[cfg(all(feature = "std", unix))]
+pub mod threaded;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+#[cfg(all(feature = "wintun", windows))]
+pub mod wintun;
+#[cfg(feature = "std")]
+pub mod wire;
+#[cfg(feature = "af-xdp")]
+pub mod xdp;
+
+pub use crate::core::tcb::ConnectionID;
+#[cfg(all(feature = "std", unix))]
+pub use builder::TcpStackBuilder;
+#[cfg(all(feature = "std", unix))]
+pub use stack::TcpStack;
+#[cfg(feature = "std")]
+pub use stack::{Readiness, Stack};
+#[cfg(feature = "std")]
+pub use tcp::Connection;
+#[cfg(all(feature = "std", unix))]
+pub use threaded::{TcpListener, TcpStream};
+
+/// Refer to: https://en.wikipedia.org/wiki/List_of_IP_protocol_numbers
+pub const TCP_PROTOCOL: u8 = 6;
+/// Where the ip header starts in the packets the stack processes, the Ethernet header of a tap device
+/// being stripped by `device::Nic`
+pub const ETH_HEADER_OFFSET: usize = 0;
@@ -1,72 +1,568 @@
-mod tcp;
+use anyhow::{anyhow, Result};
+use mini_tcp::config::{parse_cidr, parse_route, Config, Route};
+use mini_tcp::ctl;
+use mini_tcp::device::{recv_buffer_size, NetworkDevice, RecvBatch, SendBatch, BATCH_SIZE};
+#[cfg(unix)]
+use mini_tcp::ethernet::DEFAULT_MAC;
+#[cfg(unix)]
+use mini_tcp::multiqueue;
+use mini_tcp::stack::Stack;
+#[cfg(unix)]
+use mini_tcp::stack::TcpStack;
+use mini_tcp::tcp::state::{Established, SynRecv};
+#[cfg(all(windows, feature = "wintun"))]
+use mini_tcp::tcp::Tunables;
+use mini_tcp::tcp::{Connection, ConnectionID};
+#[cfg(all(windows, feature = "wintun"))]
+use mini_tcp::wintun::WintunAdapter;
+#[cfg(unix)]
+use mini_tcp::TcpStackBuilder;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 
-use crate::tcp::state::{Established, SynRecv};
-use crate::tcp::{parse_connection_id, ConnectionID};
-use anyhow::Result;
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
-use tcp::Connection;
+/// How long the main loop waits for packets or the next timer before checking the control plane again
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-/// Refer to: https://en.wikipedia.org/wiki/List_of_IP_protocol_numbers
-const TCP_PROTOCOL: u8 = 6;
-const ETH_HEADER_OFFSET: usize = 0;
+/// Set by SIGHUP, the main loop reloads the configuration file
+static RELOAD: AtomicBool = AtomicBool::new(false);
 
-fn main() -> Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+/// Set by SIGINT and SIGTERM, the main loop returns and the set up of the device is removed with it
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
 
-    let mut connections = HashMap::new();
-    let nic = tun_tap::Iface::without_packet_info("mini-tcp-tun", tun_tap::Mode::Tun)?;
+const USAGE: &str = "usage: mini-tcp [options]
+  --config <path>       the configuration file, read again on SIGHUP, the options below take precedence
+  --iface <name>        the tun device, mini-tcp-tun by default
+  --cidr <addr>/<len>   the address of the device, brought up with it, e.g. 192.167.1.0/24
+  --mtu <mtu>           the MTU of the device
+  --route <addr>/<len>  add a route through the device, followed by 'via <addr>' through a gateway,
+                        repeat for more; removed on exit, as the address
+  --tap <addr>          run on a tap device, the packets framed in Ethernet, instead of a tun device,
+                        answering ARP for <addr>
+  --vlan <id>           tag the frames of the tap device with the 802.1Q VLAN <id>, for a trunk port
+  --xdp <addr>          run on a queue of the NIC --iface through AF_XDP as <addr>, with the af-xdp
+                        feature
+  --xdp-queue <n>       the queue of the NIC, 0 by default
+  --dpdk <addr>         run on a port of a DPDK driver as <addr>, with the dpdk feature
+  --dpdk-port <n>       the DPDK port, 0 by default
+  --dpdk-eal <args>     the arguments of the DPDK EAL, space separated, e.g. '-l 1 -a 0000:01:00.0'
+  --queues <n>          shard the connections over n queues of a multiqueue tun device, a thread each,
+                        without the control plane, SIGHUP reload, signal shutdown or --soak
+  --listen-port <port>  accept connections on this port only, repeat for more, all ports by default
+  --log-level <level>   the log filter, as RUST_LOG, info by default
+  --soak                check the invariants on every iteration, abort on the first violation
+  --io-uring            read the device through io_uring, with the io-uring feature
+  --help                print this";
 
-    loop {
-        let mut buf = [0u8; 1500];
-        let nbytes = nic.recv(&mut buf)?;
+/// The command line of the binary. On Windows the options of the unix devices are read but unused.
+/// Parsed by hand, not with clap, which isn't among the dependencies the build is restricted to.
+#[derive(PartialEq, Eq, Debug)]
+#[cfg_attr(windows, allow(dead_code))]
+struct Args {
+    config: Option<PathBuf>,
+    iface: Option<String>,
+    /// The address and netmask of the device
+    cidr: Option<(Ipv4Addr, Ipv4Addr)>,
+    mtu: Option<u16>,
+    routes: Vec<Route>,
+    /// The address of the stack on a tap device
+    tap: Option<Ipv4Addr>,
+    /// The VLAN of the stack on a tap device
+    vlan: Option<u16>,
+    /// The address of the stack on the NIC it runs on through AF_XDP
+    xdp: Option<Ipv4Addr>,
+    xdp_queue: u32,
+    /// The address of the stack on the DPDK port it runs on
+    dpdk: Option<Ipv4Addr>,
+    dpdk_port: u16,
+    dpdk_eal: Vec<String>,
+    queues: usize,
+    listen_ports: Vec<u16>,
+    log_level: Option<String>,
+    soak: bool,
+    io_uring: bool,
+    help: bool,
+}
 
-        let (id, ip_header, tcp_header) = match parse_connection_id(&buf) {
-            Ok(v) => v,
-            Err(e) => {
-                log::debug!("not processing due to {:}", e);
-                continue;
+impl Args {
+    /// Parses the arguments after the program name, the values of the options following them either
+    /// as the next argument or after a `=`.
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut parsed = Args {
+            config: None,
+            iface: None,
+            cidr: None,
+            mtu: None,
+            routes: vec![],
+            tap: None,
+            vlan: None,
+            xdp: None,
+            xdp_queue: 0,
+            dpdk: None,
+            dpdk_port: 0,
+            dpdk_eal: vec![],
+            queues: 1,
+            listen_ports: vec![],
+            log_level: None,
+            soak: false,
+            io_uring: false,
+            help: false,
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg, None),
+            };
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| anyhow!("{name:} expects a value"))
+            };
+            match name.as_str() {
+                "--config" => parsed.config = Some(value()?.into()),
+                "--iface" => parsed.iface = Some(value()?),
+                "--cidr" => parsed.cidr = Some(parse_cidr(&value()?)?),
+                "--mtu" => parsed.mtu = Some(value()?.parse()?),
+                "--route" => parsed.routes.push(parse_route(&value()?)?),
+                "--tap" => parsed.tap = Some(value()?.parse()?),
+                "--vlan" => parsed.vlan = Some(value()?.parse()?),
+                "--xdp" => parsed.xdp = Some(value()?.parse()?),
+                "--xdp-queue" => parsed.xdp_queue = value()?.parse()?,
+                "--dpdk" => parsed.dpdk = Some(value()?.parse()?),
+                "--dpdk-port" => parsed.dpdk_port = value()?.parse()?,
+                "--dpdk-eal" => {
+                    parsed.dpdk_eal = value()?.split_whitespace().map(str::to_string).collect()
+                }
+                "--queues" => parsed.queues = value()?.parse()?,
+                "--listen-port" => parsed.listen_ports.push(value()?.parse()?),
+                "--log-level" => parsed.log_level = Some(value()?),
+                "--soak" => parsed.soak = true,
+                "--io-uring" => parsed.io_uring = true,
+                "--help" | "-h" => parsed.help = true,
+                _ => return Err(anyhow!("unknown argument {name:}\n{USAGE:}")),
             }
+        }
+        // the queue threads run the packets only, see `run_multiqueue`
+        if parsed.soak && parsed.queues > 1 {
+            return Err(anyhow!("--soak is not supported with --queues"));
+        }
+        Ok(parsed)
+    }
+}
+
+#[cfg(unix)]
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD.store(true, Ordering::Relaxed);
+}
+
+#[cfg(unix)]
+extern "C" fn on_shutdown(_: libc::c_int) {
+    SHUTDOWN.store(true, Ordering::Relaxed);
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse(std::env::args().skip(1))?;
+    if args.help {
+        println!("{USAGE:}");
+        return Ok(());
+    }
+    let env = env_logger::Env::new().default_filter_or("info");
+    match &args.log_level {
+        Some(level) => env_logger::Builder::new().parse_filters(level).init(),
+        None => env_logger::init_from_env(env),
+    }
+
+    // soak mode checks the internal invariants on every iteration and aborts on the first violation,
+    // meant for multi-hour stability runs
+    if args.soak {
+        log::info!("soak mode enabled");
+    }
+
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    serve(&args, config)
+}
+
+/// Runs the stack on the device of the command line: a tun or tap device, multiqueue or not, or a NIC
+/// bypassing the kernel.
+#[cfg(unix)]
+fn serve(args: &Args, config: Config) -> Result<()> {
+    let soak = args.soak;
+    let mut builder = TcpStack::builder();
+    if let Some(iface) = args.iface.as_ref().or(config.iface.as_ref()) {
+        builder = builder.device(iface);
+    }
+    if let Some((addr, netmask)) = args.cidr.or(config.cidr) {
+        builder = builder.address(addr, netmask);
+    }
+    if let Some(mtu) = args.mtu.or(config.mtu) {
+        builder = builder.mtu(mtu);
+    }
+    let routes = match args.routes.is_empty() {
+        true => &config.routes,
+        false => &args.routes,
+    };
+    for route in routes {
+        builder = builder.route(route.clone());
+    }
+    if let Some(addr) = args.tap {
+        builder = builder.tap(DEFAULT_MAC, addr);
+    }
+    if let Some(id) = args.vlan {
+        builder = builder.vlan(id);
+    }
+    if args.queues > 1 {
+        return run_multiqueue(builder, args, config);
+    }
+    if let Some(addr) = args.xdp {
+        #[cfg(feature = "af-xdp")]
+        {
+            let (socket, tunables) = builder.build_xdp(addr, args.xdp_queue)?;
+            log::info!("running on queue {:} of {:}", args.xdp_queue, socket.name());
+            let mut stack = Stack::new(tunables);
+            stack.set_address(addr);
+            let ctl_rx = start(&mut stack, args, config)?;
+            return run(&socket, &mut stack, &ctl_rx, soak);
+        }
+        #[cfg(not(feature = "af-xdp"))]
+        return Err(anyhow!("--xdp {addr:} takes the af-xdp feature"));
+    }
+    if let Some(addr) = args.dpdk {
+        #[cfg(feature = "dpdk")]
+        {
+            let eal: Vec<&str> = args.dpdk_eal.iter().map(String::as_str).collect();
+            let (port, tunables) = builder.build_dpdk(&eal, args.dpdk_port, addr)?;
+            log::info!("running on DPDK port {:}", port.port());
+            let mut stack = Stack::new(tunables);
+            stack.set_address(addr);
+            let ctl_rx = start(&mut stack, args, config)?;
+            return run(&port, &mut stack, &ctl_rx, soak);
+        }
+        #[cfg(not(feature = "dpdk"))]
+        return Err(anyhow!("--dpdk {addr:} takes the dpdk feature"));
+    }
+    let (nic, mut stack) = builder.build()?.into_parts();
+    let ctl_rx = start(&mut stack, args, config)?;
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if args.io_uring {
+        log::info!("reading the nic through io_uring");
+        // the reads queued wait for the packets
+        nic.set_nonblocking(false)?;
+        return run_uring(&nic, &mut stack, &ctl_rx, soak);
+    }
+
+    run(&nic, &mut stack, &ctl_rx, soak)
+}
+
+/// Runs the stack on the wintun adapter `--iface`, created when there is none, see `wintun`.
+#[cfg(windows)]
+fn serve(args: &Args, config: Config) -> Result<()> {
+    #[cfg(feature = "wintun")]
+    {
+        let name = args.iface.as_ref().or(config.iface.as_ref());
+        let adapter = WintunAdapter::open(name.map_or("mini-tcp", String::as_str))?;
+        log::info!("running on the wintun adapter {:}", adapter.name());
+        let mut stack = Stack::new(Tunables::default());
+        let ctl_rx = start(&mut stack, args, config)?;
+        return run(&adapter, &mut stack, &ctl_rx, args.soak);
+    }
+    #[cfg(not(feature = "wintun"))]
+    return Err(anyhow!("running on Windows takes the wintun feature"));
+}
+
+/// Applies the configuration file and the ports of the command line to `stack`, and serves the
+/// control plane. Returns the commands received.
+fn start(stack: &mut Stack, args: &Args, config: Config) -> Result<mpsc::Receiver<ctl::Request>> {
+    if let Some(path) = &args.config {
+        stack.configure(path.clone(), config)?;
+        #[cfg(unix)]
+        unsafe {
+            libc::signal(libc::SIGHUP, on_sighup as *const () as libc::sighandler_t)
         };
+    }
+    for port in &args.listen_ports {
+        stack.bind(*port)?;
+    }
+    #[cfg(unix)]
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        unsafe { libc::signal(signal, on_shutdown as *const () as libc::sighandler_t) };
+    }
 
-        log::debug!("received {nbytes:} bytes from id: {id:?}");
+    let (ctl_tx, ctl_rx) = mpsc::channel();
+    #[cfg(feature = "http-api")]
+    ctl::http::spawn(ctl_tx.clone())?;
+    #[cfg(unix)]
+    ctl::unix::spawn(ctl_tx)?;
+    Ok(ctl_rx)
+}
 
-        match connections.entry(id.clone()) {
-            Entry::Vacant(e) => {
-                // there are attacks called SYN flood, modern kernel actually protects against this
-                // attack, but we don't really care about this here.
-                let handshake = Connection::new(id, ip_header, tcp_header);
-                let next = handshake.syn_ack(&nic)?;
-                e.insert(ConnectionWrapper::SynRecv(next));
-            }
-            Entry::Occupied(e) => {
-                log::debug!("connection: {id:?} already exists");
-                log::info!(
-                    "received tcp header, ack: {:}, seq: {:}, syn: {:}",
-                    tcp_header.ack(),
-                    tcp_header.sequence_number(),
-                    tcp_header.syn()
-                );
-                match e.remove() {
-                    ConnectionWrapper::SynRecv(conn) => match conn.check_ack(&nic, &tcp_header) {
-                        Ok(conn) => {
-                            connections.insert(id, ConnectionWrapper::Established(conn));
-                        }
-                        Err(e) => {
-                            log::error!("error: {e:}");
-                        }
-                    },
-                    _ => {
-                        log::error!("invalid state for id: {id:?}");
-                    }
-                }
+/// The main loop: the packets waiting are read in a batch on each wakeup, and the answers sent once
+/// it's processed.
+fn run(
+    nic: &dyn NetworkDevice,
+    stack: &mut Stack,
+    ctl_rx: &mpsc::Receiver<ctl::Request>,
+    soak: bool,
+) -> Result<()> {
+    let mut batch = RecvBatch::new(BATCH_SIZE, recv_buffer_size(nic));
+    while !SHUTDOWN.load(Ordering::Relaxed) {
+        let wait = run_once(nic, stack, ctl_rx, soak);
+        if !nic.wait_readable(Some(wait))? {
+            continue;
+        }
+
+        nic.recv_batch(&mut batch)?;
+        let tx = SendBatch::new(nic);
+        for packet in batch.packets() {
+            on_packet(&tx, stack, packet);
+        }
+        tx.flush()?;
+    }
+    log::info!("shutting down");
+    Ok(())
+}
+
+/// Runs a stack sharded over `args.queues` queues of a multiqueue device, a thread each. The threads
+/// only run the packets and the timers: the control plane isn't served, the configuration file isn't
+/// reloaded on SIGHUP, SIGINT and SIGTERM kill the process as by default, and `--soak` is refused by
+/// `Args::parse`.
+#[cfg(unix)]
+fn run_multiqueue(builder: TcpStackBuilder, args: &Args, config: Config) -> Result<()> {
+    let (queues, tunables) = builder.build_multiqueue(args.queues)?;
+    log::info!("sharding the connections over {:} queues", queues.len());
+    log::warn!("the control plane, SIGHUP reload and signal shutdown are off with --queues");
+    let new_stack = |_| {
+        let mut stack = Stack::new(tunables.clone());
+        if let Some(path) = &args.config {
+            stack
+                .configure(path.clone(), config.clone())
+                .map_err(std::io::Error::other)?;
+        }
+        for port in &args.listen_ports {
+            stack.bind(*port)?;
+        }
+        Ok(stack)
+    };
+    for thread in multiqueue::spawn(queues, new_stack, on_packet)? {
+        thread
+            .join()
+            .map_err(|_| anyhow!("a queue thread panicked"))??;
+    }
+    Ok(())
+}
+
+/// The work of an iteration of the main loop besides the packets: checks the invariants in soak mode,
+/// runs the commands of the control plane and fires the timers due. Returns how long to wait for
+/// packets.
+fn run_once(
+    nic: &dyn NetworkDevice,
+    stack: &mut Stack,
+    ctl_rx: &mpsc::Receiver<ctl::Request>,
+    soak: bool,
+) -> Duration {
+    if soak {
+        if let Err(e) = stack.check_invariants() {
+            log::error!("invariant violated: {e:}\nstack: {stack:#?}");
+            std::process::abort();
+        }
+    }
+
+    if RELOAD.swap(false, Ordering::Relaxed) {
+        match stack.reload() {
+            Ok(reloaded) => log::info!(
+                "configuration reloaded, applied {:?}, restart required for {:?}",
+                reloaded.applied,
+                reloaded.restart
+            ),
+            Err(e) => log::error!("configuration not reloaded: {e:#}"),
+        }
+    }
+
+    for request in ctl_rx.try_iter() {
+        let reply = stack.handle_command(request.command, nic);
+        // the requester might have given up already, nothing to do about it
+        let _ = request.reply.send(reply);
+    }
+
+    let now = Instant::now();
+    stack.on_timers(nic, now);
+    stack
+        .next_deadline()
+        .map_or(POLL_INTERVAL, |at| at.saturating_duration_since(now))
+        .min(POLL_INTERVAL)
+}
+
+/// Processes a packet read from the nic and logs the data it carried.
+fn on_packet(nic: &dyn NetworkDevice, stack: &mut Stack, packet: &[u8]) {
+    let id = match stack.on_packet(nic, packet) {
+        Some(id) => id,
+        None => return,
+    };
+    if let Some(conn) = stack.syn_recv_mut(&id) {
+        drain_fast_open(&id, conn);
+    }
+    // reading opens the window, the update goes out right away
+    if stack
+        .established_mut(&id)
+        .is_some_and(|conn| drain_received(&id, conn) > 0)
+    {
+        let _ = stack.transmit(nic, &id);
+    }
+}
+
+/// The main loop with the nic read through io_uring: a batch of reads stays queued, and a single
+/// `io_uring_enter` per iteration waits for packets or the next timer.
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn run_uring(
+    nic: &mini_tcp::device::Nic,
+    stack: &mut Stack,
+    ctl_rx: &mpsc::Receiver<ctl::Request>,
+    soak: bool,
+) -> Result<()> {
+    use mini_tcp::ethernet::{ETH_HEADER_LEN, VLAN_TAG_LEN};
+    use mini_tcp::uring::Uring;
+    use std::os::unix::io::AsRawFd;
+
+    /// The reads kept queued
+    const READS: usize = 32;
+    /// The `user_data` of the timeout, the reads have the index of their buffer
+    const TIMEOUT: u64 = u64::MAX;
+
+    let fd = nic.as_raw_fd();
+    // declared first, the buffers are dropped after the ring
+    let mut bufs = vec![vec![0u8; recv_buffer_size(nic) + ETH_HEADER_LEN + VLAN_TAG_LEN]; READS];
+    let mut ring = Uring::new(2 * READS as u32)?;
+    for (i, buf) in bufs.iter_mut().enumerate() {
+        // the buffers outlive the ring, the reads still queued are cancelled when it's closed
+        unsafe { ring.read(fd, buf, i as u64)? };
+    }
+    while !SHUTDOWN.load(Ordering::Relaxed) {
+        let wait = run_once(nic, stack, ctl_rx, soak);
+        ring.timeout(wait, TIMEOUT)?;
+        ring.submit_and_wait(1)?;
+        for completion in ring.completions() {
+            if completion.user_data == TIMEOUT {
                 continue;
             }
+            if completion.res < 0 {
+                return Err(std::io::Error::from_raw_os_error(-completion.res).into());
+            }
+            let i = completion.user_data as usize;
+            if let Some(packet) = nic.packet(&bufs[i][..completion.res as usize])? {
+                on_packet(nic, stack, packet);
+            }
+            unsafe { ring.read(fd, &mut bufs[i], i as u64)? };
+        }
+    }
+    log::info!("shutting down");
+    Ok(())
+}
+
+/// There is no application on top of the stack in the binary, the data received is just logged. Returns
+/// the bytes read.
+fn drain_received(id: &ConnectionID, conn: &mut Connection<Established>) -> usize {
+    let mut buf = [0u8; 1500];
+    let mut read = 0;
+    loop {
+        let n = conn.read_urgent(&mut buf);
+        if n == 0 {
+            break;
+        }
+        log::info!("urgent data from {id:?}: {:?}", &buf[..n]);
+    }
+    loop {
+        let n = conn.read(&mut buf);
+        if n == 0 {
+            break;
         }
+        read += n;
+        log::info!("data from {id:?}: {:}", String::from_utf8_lossy(&buf[..n]));
     }
+    read
 }
 
-enum ConnectionWrapper {
-    SynRecv(Connection<SynRecv>),
-    Established(Connection<Established>),
+/// The data of a Fast Open SYN is delivered before the handshake completes.
+fn drain_fast_open(id: &ConnectionID, conn: &mut Connection<SynRecv>) {
+    let mut buf = [0u8; 1500];
+    loop {
+        let n = conn.read(&mut buf);
+        if n == 0 {
+            break;
+        }
+        log::info!(
+            "fast open data from {id:?}: {:}",
+            String::from_utf8_lossy(&buf[..n])
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Args;
+    use std::net::Ipv4Addr;
+
+    fn parse(args: &[&str]) -> anyhow::Result<Args> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.iface, None);
+        assert_eq!(args.cidr, None);
+
+        let args = parse(&[
+            "--iface",
+            "tun1",
+            "--cidr=10.0.0.1/24",
+            "--mtu",
+            "1400",
+            "--listen-port",
+            "80",
+            "--listen-port=443",
+            "--route",
+            "10.1.0.0/16 via 10.0.0.2",
+            "--soak",
+            "--tap",
+            "192.167.1.1",
+            "--vlan=10",
+            "--xdp",
+            "192.167.1.3",
+            "--xdp-queue=2",
+            "--dpdk-eal",
+            "-l 1 -a 0000:01:00.0",
+        ])
+        .unwrap();
+        assert_eq!(args.iface.as_deref(), Some("tun1"));
+        assert_eq!(
+            args.cidr,
+            Some((Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(255, 255, 255, 0)))
+        );
+        assert_eq!(args.mtu, Some(1400));
+        assert_eq!(args.routes[0].gateway, Some(Ipv4Addr::new(10, 0, 0, 2)));
+        assert_eq!(args.listen_ports, vec![80, 443]);
+        assert_eq!(args.tap, Some(Ipv4Addr::new(192, 167, 1, 1)));
+        assert_eq!(args.vlan, Some(10));
+        assert_eq!(args.dpdk_eal, ["-l", "1", "-a", "0000:01:00.0"]);
+        assert_eq!(
+            (args.xdp, args.xdp_queue),
+            (Some(Ipv4Addr::new(192, 167, 1, 3)), 2)
+        );
+        assert!(args.soak && !args.io_uring);
+
+        assert!(parse(&["--mtu"]).is_err());
+        assert!(parse(&["--mtu", "70000"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+
+        assert_eq!(parse(&["--queues=4"]).unwrap().queues, 4);
+        assert!(parse(&["--queues=4", "--soak"]).is_err());
+        assert!(parse(&["--queues=1", "--soak"]).is_ok());
+    }
 }
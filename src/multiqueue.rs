@@ -0,0 +1,282 @@
+//! A stack sharded over the queues of a multiqueue tun device, a packet thread per queue, so the
+//! packets are processed on as many cores. Each thread runs a `Stack` of its own, holding the
+//! connections whose 4-tuple hashes to it. The kernel picks the queue of a packet by its own flow hash,
+//! so the packets reaching the wrong thread are handed to the right one, and once a thread answered
+//! on its queue the kernel steers the rest of the flow to it. The packets handed over wait in an input
+//! queue of their connection, bounded, see `tcp::queue`.
+//!
+//! ```no_run
+//! # fn main() -> anyhow::Result<()> {
+//! let (queues, tunables) = mini_tcp::TcpStackBuilder::new().build_multiqueue(4)?;
+//! let threads = mini_tcp::multiqueue::spawn(
+//!     queues,
+//!     |_| {
+//!         let mut stack = mini_tcp::Stack::new(tunables.clone());
+//!         stack.bind(80)?;
+//!         Ok(stack)
+//!     },
+//!     |nic, stack, packet| {
+//!         stack.on_packet(nic, packet);
+//!     },
+//! )?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::device::{recv_buffer_size, NetworkDevice, RecvBatch, SendBatch, BATCH_SIZE};
+use crate::netlink::Applied;
+use crate::stack::{self, Stack};
+use crate::tcp::queue::{Backlog, INPUT_QUEUE_CAPACITY};
+use crate::tcp::{device_mtu, ConnectionID};
+use crate::wire::SegmentView;
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// `_IOW('T', 202, int)`, missing from libc
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+
+/// A queue of a multiqueue tun device, carrying its share of the packets
+#[derive(Debug)]
+pub struct Queue {
+    fd: File,
+    name: String,
+    applied: Option<Applied>,
+}
+
+impl Queue {
+    /// Keeps the set up of the device until the queue is dropped, see `netlink::apply`.
+    pub fn hold(&mut self, applied: Applied) {
+        self.applied = Some(applied);
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Opens `count` queues of the tun device `name`, created if it doesn't exist. A device created
+/// without multiqueue, as `ip tuntap add` does by default, can't be opened so.
+pub fn open(name: &str, count: usize) -> io::Result<Vec<Queue>> {
+    (0..count).map(|_| open_queue(name)).collect()
+}
+
+fn open_queue(name: &str) -> io::Result<Queue> {
+    let fd = OpenOptions::new()
+        .read(true)
+        .write(true)
+        // read in batches, until it would block
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/net/tun")?;
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    for (dst, src) in req
+        .ifr_name
+        .iter_mut()
+        .zip(name.as_bytes())
+        .take(libc::IFNAMSIZ - 1)
+    {
+        *dst = *src as libc::c_char;
+    }
+    req.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI | libc::IFF_MULTI_QUEUE) as _;
+    if unsafe { libc::ioctl(fd.as_raw_fd(), TUNSETIFF, &mut req) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let name = req
+        .ifr_name
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8 as char)
+        .collect();
+    Ok(Queue {
+        fd,
+        name,
+        applied: None,
+    })
+}
+
+impl AsRawFd for Queue {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl NetworkDevice for Queue {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        (&self.fd).read(buf)
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        (&self.fd).write(packet)
+    }
+
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        stack::wait_readable(self, timeout)
+    }
+
+    fn mtu(&self) -> Option<usize> {
+        device_mtu(&self.name).ok().map(usize::from)
+    }
+}
+
+/// The shard among `shards` holding the connection `id`.
+pub fn shard(id: &ConnectionID, shards: usize) -> usize {
+    // the keys of DefaultHasher::new are fixed, every thread agrees on the shard
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// The way to hand a packet to a shard: the input queues of its connections, and the eventfd waking
+/// its thread.
+struct Inbox {
+    backlog: Mutex<Backlog>,
+    wake_fd: RawFd,
+}
+
+impl Drop for Inbox {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.wake_fd) };
+    }
+}
+
+impl Inbox {
+    fn deliver(&self, id: ConnectionID, packet: &[u8]) {
+        self.lock().push(Some(id), packet.to_vec());
+        let one = 1u64;
+        unsafe { libc::write(self.wake_fd, (&one as *const u64).cast(), 8) };
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Backlog> {
+        self.backlog.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Runs a packet thread per queue, the stack of shard `i` made by `new_stack(i)`, and returns them.
+/// The threads process the packets with `on_packet`, `Stack::on_packet` and whatever the application
+/// does with the connection after, and run until their queue fails.
+pub fn spawn<F>(
+    queues: Vec<Queue>,
+    new_stack: impl Fn(usize) -> io::Result<Stack>,
+    on_packet: F,
+) -> io::Result<Vec<JoinHandle<io::Result<()>>>>
+where
+    F: Fn(&dyn NetworkDevice, &mut Stack, &[u8]) + Send + Sync + 'static,
+{
+    let mut inboxes = vec![];
+    for _ in &queues {
+        let wake_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if wake_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let backlog = Mutex::new(Backlog::new(INPUT_QUEUE_CAPACITY));
+        inboxes.push(Inbox { backlog, wake_fd });
+    }
+    let inboxes = Arc::new(inboxes);
+    let on_packet = Arc::new(on_packet);
+    let mut threads = vec![];
+    for (i, queue) in queues.into_iter().enumerate() {
+        let stack = new_stack(i)?;
+        let inboxes = inboxes.clone();
+        let on_packet = on_packet.clone();
+        let thread = thread::Builder::new()
+            .name(format!("mini-tcp-queue-{i:}"))
+            .spawn(move || run(i, &queue, stack, &inboxes, &*on_packet))?;
+        threads.push(thread);
+    }
+    Ok(threads)
+}
+
+/// The loop of the packet thread of shard `me`
+fn run<F>(
+    me: usize,
+    queue: &Queue,
+    mut stack: Stack,
+    inboxes: &[Inbox],
+    on_packet: &F,
+) -> io::Result<()>
+where
+    F: Fn(&dyn NetworkDevice, &mut Stack, &[u8]),
+{
+    let mut batch = RecvBatch::new(BATCH_SIZE, recv_buffer_size(queue));
+    loop {
+        let now = Instant::now();
+        stack.on_timers(queue, now);
+        let timeout = stack.next_deadline().map_or(-1, |at| {
+            // rounded up, the timers aren't due before
+            let wait = at.saturating_duration_since(now);
+            (wait.as_micros().div_ceil(1000)).min(i32::MAX as u128) as libc::c_int
+        });
+
+        let mut fds = [
+            libc::pollfd {
+                fd: queue.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: inboxes[me].wake_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if fds[1].revents & libc::POLLIN != 0 {
+            let mut count = 0u64;
+            unsafe { libc::read(inboxes[me].wake_fd, (&mut count as *mut u64).cast(), 8) };
+            let (packets, shed) = inboxes[me].lock().drain();
+            stack.on_input_queues(&shed);
+            for packet in packets {
+                on_packet(queue, &mut stack, &packet);
+            }
+        }
+        if fds[0].revents & libc::POLLIN != 0 {
+            queue.recv_batch(&mut batch)?;
+            let tx = SendBatch::new(queue);
+            for packet in batch.packets() {
+                // the packets not parsed are dropped by any shard
+                let id = SegmentView::parse(packet).ok().map(|seg| seg.id());
+                match id.map(|id| (shard(&id, inboxes.len()), id)) {
+                    Some((owner, id)) if owner != me => inboxes[owner].deliver(id, packet),
+                    _ => on_packet(&tx, &mut stack, packet),
+                }
+            }
+            tx.flush()?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::multiqueue::shard;
+    use crate::tcp::ConnectionID;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_shard() {
+        let id = |src_port| ConnectionID {
+            src_addr: Ipv4Addr::new(192, 167, 1, 2),
+            src_port,
+            dst_addr: Ipv4Addr::new(192, 167, 1, 1),
+            dst_port: 80,
+        };
+        assert_eq!(shard(&id(40000), 4), shard(&id(40000), 4));
+        assert_eq!(shard(&id(40000), 1), 0);
+        let mut counts = [0; 4];
+        for port in 40000..41000 {
+            counts[shard(&id(port), 4)] += 1;
+        }
+        assert!(counts.iter().all(|n| (150..350).contains(n)), "{counts:?}");
+    }
+}
@@ -0,0 +1,330 @@
+//! The set up of the device the stack runs on through rtnetlink, https://www.rfc-editor.org/rfc/rfc3549,
+//! as `ip addr add`, `ip link set up` and `ip route add` do: its address and prefix, its MTU, and the
+//! routes through it. What is added is removed again once the `Applied` returned is dropped, with
+//! the device, an address or a route already there being left as it was.
+
+use crate::config::Route;
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+const NLMSG_HDR_LEN: usize = 16;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_LINK: u8 = 253;
+const RTN_UNICAST: u8 = 1;
+
+/// The prefix length of `netmask`, the ones it starts with.
+pub fn prefix_len(netmask: Ipv4Addr) -> u8 {
+    u32::from(netmask).leading_ones() as u8
+}
+
+/// A rtnetlink socket, each request waiting for its acknowledgment.
+#[derive(Debug)]
+pub struct Netlink {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Netlink {
+    pub fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+            seq: 0,
+        })
+    }
+
+    /// Adds `addr`/`prefix` to the device `index`. Fails with `AlreadyExists` when it's there.
+    pub fn add_address(&mut self, index: u32, addr: Ipv4Addr, prefix: u8) -> io::Result<()> {
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        self.request(libc::RTM_NEWADDR, flags, &address(index, addr, prefix))
+    }
+
+    pub fn del_address(&mut self, index: u32, addr: Ipv4Addr, prefix: u8) -> io::Result<()> {
+        self.request(libc::RTM_DELADDR, 0, &address(index, addr, prefix))
+    }
+
+    /// Brings the device `index` up or leaves it as it is, setting its MTU when there is one.
+    pub fn set_link(&mut self, index: u32, up: bool, mtu: Option<u16>) -> io::Result<()> {
+        self.request(libc::RTM_NEWLINK, 0, &link(index, up, mtu))
+    }
+
+    /// Routes `route` through the device `index`. Fails with `AlreadyExists` when it's there.
+    pub fn add_route(&mut self, index: u32, route: &Route) -> io::Result<()> {
+        let flags = libc::NLM_F_CREATE | libc::NLM_F_EXCL;
+        self.request(libc::RTM_NEWROUTE, flags, &self::route(index, route))
+    }
+
+    pub fn del_route(&mut self, index: u32, route: &Route) -> io::Result<()> {
+        self.request(libc::RTM_DELROUTE, 0, &self::route(index, route))
+    }
+
+    /// Sends the message `kind` with the body `body` and waits for its acknowledgment.
+    fn request(&mut self, kind: u16, flags: libc::c_int, body: &[u8]) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        let flags = (libc::NLM_F_REQUEST | libc::NLM_F_ACK | flags) as u16;
+        let message = message(kind, flags, self.seq, body);
+        let mut kernel: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        kernel.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let ret = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+                &kernel as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = unsafe {
+                libc::recv(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(result) = acknowledgment(&buf[..n as usize], self.seq) {
+                return result;
+            }
+        }
+    }
+}
+
+/// A netlink message: its header, then `body`.
+fn message(kind: u16, flags: u16, seq: u32, body: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(NLMSG_HDR_LEN + body.len());
+    message.extend_from_slice(&((NLMSG_HDR_LEN + body.len()) as u32).to_ne_bytes());
+    message.extend_from_slice(&kind.to_ne_bytes());
+    message.extend_from_slice(&flags.to_ne_bytes());
+    message.extend_from_slice(&seq.to_ne_bytes());
+    // the port id, the kernel's
+    message.extend_from_slice(&0u32.to_ne_bytes());
+    message.extend_from_slice(body);
+    message
+}
+
+/// Appends the attribute `kind` holding `value`, padded to 4 octets.
+fn attribute(body: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    body.extend_from_slice(&(4 + value.len() as u16).to_ne_bytes());
+    body.extend_from_slice(&kind.to_ne_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+/// The ifaddrmsg of `addr`/`prefix` on the device `index`, with its attributes.
+fn address(index: u32, addr: Ipv4Addr, prefix: u8) -> Vec<u8> {
+    let mut body = vec![libc::AF_INET as u8, prefix, 0, RT_SCOPE_UNIVERSE];
+    body.extend_from_slice(&index.to_ne_bytes());
+    attribute(&mut body, libc::IFA_LOCAL, &addr.octets());
+    attribute(&mut body, libc::IFA_ADDRESS, &addr.octets());
+    body
+}
+
+/// The ifinfomsg of the device `index`, brought up or not, with its MTU.
+fn link(index: u32, up: bool, mtu: Option<u16>) -> Vec<u8> {
+    let flags = match up {
+        true => libc::IFF_UP as u32,
+        false => 0,
+    };
+    let mut body = vec![libc::AF_UNSPEC as u8, 0, 0, 0];
+    body.extend_from_slice(&(index as i32).to_ne_bytes());
+    // the flags, and the mask of those changed
+    body.extend_from_slice(&flags.to_ne_bytes());
+    body.extend_from_slice(&flags.to_ne_bytes());
+    if let Some(mtu) = mtu {
+        attribute(&mut body, libc::IFLA_MTU, &(mtu as u32).to_ne_bytes());
+    }
+    body
+}
+
+/// The rtmsg of `route` through the device `index`, in the main table.
+fn route(index: u32, route: &Route) -> Vec<u8> {
+    let scope = match route.gateway {
+        Some(_) => RT_SCOPE_UNIVERSE,
+        None => RT_SCOPE_LINK,
+    };
+    let mut body = vec![
+        libc::AF_INET as u8,
+        prefix_len(route.netmask),
+        0,
+        0,
+        libc::RT_TABLE_MAIN,
+        libc::RTPROT_STATIC,
+        scope,
+        RTN_UNICAST,
+    ];
+    body.extend_from_slice(&0u32.to_ne_bytes());
+    attribute(&mut body, libc::RTA_DST, &route.dst.octets());
+    attribute(&mut body, libc::RTA_OIF, &index.to_ne_bytes());
+    if let Some(gateway) = route.gateway {
+        attribute(&mut body, libc::RTA_GATEWAY, &gateway.octets());
+    }
+    body
+}
+
+/// The result of the request `seq` if `messages` acknowledge it, none for other messages.
+fn acknowledgment(mut messages: &[u8], seq: u32) -> Option<io::Result<()>> {
+    while messages.len() >= NLMSG_HDR_LEN {
+        let len = u32::from_ne_bytes(messages[0..4].try_into().unwrap()) as usize;
+        let kind = u16::from_ne_bytes(messages[4..6].try_into().unwrap());
+        let message_seq = u32::from_ne_bytes(messages[8..12].try_into().unwrap());
+        if len < NLMSG_HDR_LEN || len > messages.len() {
+            return Some(Err(ErrorKind::InvalidData.into()));
+        }
+        if kind == libc::NLMSG_ERROR as u16 && message_seq == seq && len >= NLMSG_HDR_LEN + 4 {
+            let error = i32::from_ne_bytes(messages[16..20].try_into().unwrap());
+            return Some(match error {
+                0 => Ok(()),
+                error => Err(io::Error::from_raw_os_error(-error)),
+            });
+        }
+        messages = &messages[len.next_multiple_of(4).min(messages.len())..];
+    }
+    None
+}
+
+/// The index of the device `name`.
+pub fn index(name: &str) -> io::Result<u32> {
+    let name = CString::new(name)?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+/// The address and the routes added to a device by `apply`, removed when dropped.
+#[derive(Debug)]
+pub struct Applied {
+    index: u32,
+    address: Option<(Ipv4Addr, u8)>,
+    routes: Vec<Route>,
+}
+
+/// Sets up the device `name`: sets the MTU `mtu`, adds the address `address`, a netmask with it, and
+/// the routes `routes` through the device, brought up with them.
+pub fn apply(
+    name: &str,
+    address: Option<(Ipv4Addr, Ipv4Addr)>,
+    mtu: Option<u16>,
+    routes: &[Route],
+) -> io::Result<Applied> {
+    let mut netlink = Netlink::open()?;
+    let mut applied = Applied {
+        index: index(name)?,
+        address: None,
+        routes: vec![],
+    };
+    if let Some((addr, netmask)) = address {
+        let prefix = prefix_len(netmask);
+        match netlink.add_address(applied.index, addr, prefix) {
+            Ok(()) => applied.address = Some((addr, prefix)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let up = address.is_some() || !routes.is_empty();
+    if up || mtu.is_some() {
+        netlink.set_link(applied.index, up, mtu)?;
+    }
+    for route in routes {
+        match netlink.add_route(applied.index, route) {
+            Ok(()) => applied.routes.push(route.clone()),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(applied)
+}
+
+impl Drop for Applied {
+    fn drop(&mut self) {
+        if self.address.is_none() && self.routes.is_empty() {
+            return;
+        }
+        let mut netlink = match Netlink::open() {
+            Ok(netlink) => netlink,
+            Err(e) => {
+                log::warn!("the set up of the device isn't removed: {e:}");
+                return;
+            }
+        };
+        // the device may be gone already, and what was added with it
+        for route in self.routes.drain(..).rev() {
+            if let Err(e) = netlink.del_route(self.index, &route) {
+                log::debug!("removing the route to {:}: {e:}", route.dst);
+            }
+        }
+        if let Some((addr, prefix)) = self.address.take() {
+            if let Err(e) = netlink.del_address(self.index, addr, prefix) {
+                log::debug!("removing the address {addr:}/{prefix:}: {e:}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Route;
+    use crate::netlink::{acknowledgment, address, message, prefix_len, route};
+    use std::io::ErrorKind;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_messages() {
+        assert_eq!(prefix_len(Ipv4Addr::new(255, 255, 255, 0)), 24);
+        assert_eq!(prefix_len(Ipv4Addr::UNSPECIFIED), 0);
+
+        let body = address(7, Ipv4Addr::new(192, 167, 1, 0), 24);
+        assert_eq!(body.len(), 8 + 2 * 8);
+        assert_eq!(&body[..2], &[libc::AF_INET as u8, 24]);
+        assert_eq!(&body[12..16], &[192, 167, 1, 0]);
+        let message = message(libc::RTM_NEWADDR, 5, 9, &body);
+        assert_eq!(u32::from_ne_bytes(message[..4].try_into().unwrap()), 40);
+
+        let body = route(
+            7,
+            &Route {
+                dst: Ipv4Addr::new(10, 0, 0, 0),
+                netmask: Ipv4Addr::new(255, 0, 0, 0),
+                gateway: Some(Ipv4Addr::new(192, 167, 1, 1)),
+            },
+        );
+        assert_eq!(body[1], 8);
+        assert_eq!(body.len(), 12 + 3 * 8);
+    }
+
+    #[test]
+    fn test_acknowledgment() {
+        let error = |seq: u32, error: i32| {
+            let mut body = error.to_ne_bytes().to_vec();
+            body.extend_from_slice(&[0; 16]);
+            message(libc::NLMSG_ERROR as u16, 0, seq, &body)
+        };
+        assert!(acknowledgment(&error(3, 0), 3).unwrap().is_ok());
+        assert!(acknowledgment(&error(2, 0), 3).is_none());
+        let mut messages = error(2, 0);
+        messages.extend(error(3, -libc::EEXIST));
+        let err = acknowledgment(&messages, 3).unwrap().unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    }
+}
@@ -0,0 +1,170 @@
+//! Callbacks on the events of the connections, for an embedder to log, measure or act on them without
+//! patching the loop driving the stack. The observers are registered with `Stack::add_observer` and
+//! called in the order they were, from the thread processing the packets: they should return quickly.
+
+use crate::tcp::state::Established;
+use crate::tcp::{Connection, ConnectionID};
+use std::fmt;
+
+/// The events of the connections of a stack. Every method does nothing by default, an observer only
+/// implements the ones it's interested in.
+pub trait Observer: Send {
+    /// The handshake completed, the connection is queued to be accepted, or writable for an active open.
+    fn on_established(&mut self, _id: &ConnectionID) {}
+
+    /// `len` octets were received in order, the application can read them.
+    fn on_data_received(&mut self, _id: &ConnectionID, _len: u64) {}
+
+    /// The connection went from the state `from` to `to`, named as in RFC 793.
+    fn on_state_change(&mut self, _id: &ConnectionID, _from: &'static str, _to: &'static str) {}
+
+    /// `segments` were retransmitted, after a timeout or deemed lost.
+    fn on_retransmit(&mut self, _id: &ConnectionID, _segments: u64) {}
+
+    /// The connection is gone from the stack, closed, reset or dropped.
+    fn on_closed(&mut self, _id: &ConnectionID) {}
+}
+
+/// What the observers are told about once it changed, taken before a connection processes a segment
+/// or a timer.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Progress {
+    state: &'static str,
+    bytes_received: u64,
+    retransmits: u64,
+}
+
+impl Progress {
+    pub fn of(conn: &Connection<Established>) -> Self {
+        let info = conn.info();
+        Self {
+            state: info.state,
+            bytes_received: info.bytes_received,
+            retransmits: info.retransmits,
+        }
+    }
+
+    /// A connection still in SYN-RECEIVED, the data of a Fast Open SYN is reported once established.
+    pub fn syn_received() -> Self {
+        Self {
+            state: "SYN-RECEIVED",
+            bytes_received: 0,
+            retransmits: 0,
+        }
+    }
+}
+
+/// The observers registered on a stack.
+#[derive(Default)]
+pub struct Observers(Vec<Box<dyn Observer>>);
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:} observers", self.0.len())
+    }
+}
+
+impl Observers {
+    pub fn push(&mut self, observer: Box<dyn Observer>) {
+        self.0.push(observer);
+    }
+
+    pub fn on_established(&mut self, id: &ConnectionID) {
+        self.0.iter_mut().for_each(|o| o.on_established(id));
+    }
+
+    pub fn on_state_change(&mut self, id: &ConnectionID, from: &'static str, to: &'static str) {
+        self.0
+            .iter_mut()
+            .for_each(|o| o.on_state_change(id, from, to));
+    }
+
+    pub fn on_closed(&mut self, id: &ConnectionID) {
+        self.0.iter_mut().for_each(|o| o.on_closed(id));
+    }
+
+    /// Tells what changed on the connection `id` since `before`.
+    pub fn on_progress(
+        &mut self,
+        id: &ConnectionID,
+        before: Progress,
+        conn: &Connection<Established>,
+    ) {
+        if self.0.is_empty() {
+            return;
+        }
+        let after = Progress::of(conn);
+        if after.state != before.state {
+            self.on_state_change(id, before.state, after.state);
+        }
+        let received = after.bytes_received - before.bytes_received;
+        if received > 0 {
+            self.0
+                .iter_mut()
+                .for_each(|o| o.on_data_received(id, received));
+        }
+        let retransmits = after.retransmits - before.retransmits;
+        if retransmits > 0 {
+            self.0
+                .iter_mut()
+                .for_each(|o| o.on_retransmit(id, retransmits));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::observer::{Observer, Observers, Progress};
+    use crate::tcp::{established, ConnectionID};
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl Observer for Recorder {
+        fn on_data_received(&mut self, _id: &ConnectionID, len: u64) {
+            self.0.lock().unwrap().push(format!("data {len:}"));
+        }
+
+        fn on_state_change(&mut self, _id: &ConnectionID, from: &'static str, to: &'static str) {
+            self.0.lock().unwrap().push(format!("{from:} -> {to:}"));
+        }
+
+        fn on_retransmit(&mut self, _id: &ConnectionID, segments: u64) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("retransmit {segments:}"));
+        }
+    }
+
+    #[test]
+    fn test_on_progress() {
+        let id = ConnectionID {
+            src_addr: Ipv4Addr::new(192, 167, 1, 2),
+            src_port: 40000,
+            dst_addr: Ipv4Addr::new(192, 167, 1, 1),
+            dst_port: 80,
+        };
+        let mut conn = established(1000, 1000, 500);
+        let events = Arc::new(Mutex::new(vec![]));
+        let mut observers = Observers::default();
+        observers.push(Box::new(Recorder(events.clone())));
+
+        // nothing changed, nothing is reported
+        let before = Progress::of(&conn);
+        observers.on_progress(&id, before, &conn);
+        assert!(events.lock().unwrap().is_empty());
+
+        observers.on_progress(&id, Progress::syn_received(), &conn);
+        let before = Progress::of(&conn);
+        conn.write(&[7u8; 30]);
+        conn.close();
+        observers.on_progress(&id, before, &conn);
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["SYN-RECEIVED -> ESTABLISHED", "ESTABLISHED -> FIN-WAIT-1"]
+        );
+    }
+}
@@ -0,0 +1,375 @@
+//! Runs the stack as a task of a tokio runtime instead of the blocking loop of the binary. The fd of the
+//! TUN device is registered with the reactor, the `Driver` task processes the packets as they become
+//! readable and fires the timers of the stack once their delay is over. Other tasks get at the
+//! connections through a `Handle`, which sends what a read or a write owes right away.
+//!
+//! A `Stream` is a connection read and written from a task, it implements `AsyncRead` and `AsyncWrite`
+//! so async protocol libraries run on top of it. A task finding nothing to read, or the send buffer
+//! full, is woken once a segment arrived on the connection, or once the read or write timeout of the
+//! connection is over.
+//!
+//! Built on tokio 0.1, the one tun-tap already depends on, so the sync build doesn't pull in another
+//! runtime: `PollEvented2` plays the part of `AsyncFd` there.
+
+use crate::ctl::{Command, Reply};
+use crate::device::{recv_buffer_size, NetworkDevice, Nic, RecvBatch, BATCH_SIZE};
+use crate::stack::Stack;
+use crate::tcp::ConnectionID;
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll};
+use mio::unix::EventedFd;
+use mio::{Evented, PollOpt, Ready, Token};
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::reactor::PollEvented2;
+use tokio::timer::Delay;
+
+/// The stack and the nic, shared by the driver and the handles
+#[derive(Debug)]
+struct Shared {
+    stack: Mutex<Stack>,
+    nic: Nic,
+    /// The driver task, woken when a handle may have moved the next timer
+    driver: Mutex<Option<Task>>,
+    /// The tasks waiting on a connection, only touched with the stack locked so none misses a segment
+    waiting: Mutex<HashMap<ConnectionID, Vec<Task>>>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Stack> {
+        // a panic with the stack locked leaves it as it was, the connections carry on
+        self.stack.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Parks the current task until a segment arrives on the connection `id`.
+    fn park(&self, id: &ConnectionID) {
+        let mut waiting = self.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        let tasks = waiting.entry(id.clone()).or_default();
+        let task = task::current();
+        if !tasks.iter().any(|parked| parked.will_notify_current()) {
+            tasks.push(task);
+        }
+    }
+
+    /// Wakes the tasks waiting on the connection `id`.
+    fn wake(&self, id: &ConnectionID) {
+        let tasks = self
+            .waiting
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(id);
+        for task in tasks.into_iter().flatten() {
+            task.notify();
+        }
+    }
+
+    /// Wakes the tasks waiting on connections gone, they fail with `NotFound`.
+    fn wake_closed(&self, stack: &Stack) {
+        let mut waiting = self.waiting.lock().unwrap_or_else(|e| e.into_inner());
+        waiting.retain(|id, tasks| {
+            if stack.contains(id) {
+                return true;
+            }
+            tasks.iter().for_each(Task::notify);
+            false
+        });
+    }
+
+    fn wake_driver(&self) {
+        if let Some(task) = self
+            .driver
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .as_ref()
+        {
+            task.notify();
+        }
+    }
+}
+
+/// The nic fd as the reactor sees it
+#[derive(Debug)]
+struct NicFd(RawFd);
+
+impl Evented for NicFd {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.0).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        EventedFd(&self.0).deregister(poll)
+    }
+}
+
+/// Creates the task driving `stack` on `nic` and a handle to it. The task is to be spawned on a tokio
+/// runtime, it runs until the nic fails.
+pub fn driver(nic: impl Into<Nic>, stack: Stack) -> io::Result<(Driver, Handle)> {
+    let nic: Nic = nic.into();
+    let fd = nic.as_raw_fd();
+    // the driver reads it until it would block
+    nic.set_nonblocking(true)?;
+    let shared = Arc::new(Shared {
+        stack: Mutex::new(stack),
+        nic,
+        driver: Mutex::new(None),
+        waiting: Mutex::new(HashMap::new()),
+    });
+    let driver = Driver {
+        shared: shared.clone(),
+        fd: PollEvented2::new(NicFd(fd)),
+        delay: None,
+        batch: RecvBatch::new(BATCH_SIZE, recv_buffer_size(&shared.nic)),
+    };
+    Ok((driver, Handle { shared }))
+}
+
+/// The task processing the packets and the timers of the stack
+#[derive(Debug)]
+pub struct Driver {
+    shared: Arc<Shared>,
+    fd: PollEvented2<NicFd>,
+    /// Until the next timer of the stack is due
+    delay: Option<Delay>,
+    batch: RecvBatch,
+}
+
+impl Driver {
+    /// Processes the packets read from the nic until it would block, the task is woken when it's
+    /// readable again.
+    fn read_packets(&mut self, stack: &mut Stack) -> io::Result<()> {
+        let nic = &self.shared.nic;
+        while let Async::Ready(_) = self.fd.poll_read_ready(Ready::readable())? {
+            if nic.recv_batch(&mut self.batch)? == 0 {
+                self.fd.clear_read_ready(Ready::readable())?;
+                continue;
+            }
+            for id in stack.on_batch(nic, &self.batch)? {
+                self.shared.wake(&id);
+            }
+        }
+        self.shared.wake_closed(stack);
+        Ok(())
+    }
+}
+
+impl Future for Driver {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        let shared = self.shared.clone();
+        *shared.driver.lock().unwrap_or_else(|e| e.into_inner()) = Some(task::current());
+        loop {
+            let mut stack = shared.lock();
+            stack.on_timers(&shared.nic, Instant::now());
+            self.read_packets(&mut stack)?;
+
+            let at = match stack.next_deadline() {
+                Some(at) => at,
+                None => return Ok(Async::NotReady),
+            };
+            drop(stack);
+            let delay = self.delay.get_or_insert_with(|| Delay::new(at));
+            delay.reset(at);
+            match delay.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                // due already, the timers are fired right away
+                Ok(Async::Ready(())) => continue,
+                Err(e) => return Err(io::Error::other(e)),
+            }
+        }
+    }
+}
+
+/// Gets at the connections of a stack run by a `Driver`, from any task or thread.
+#[derive(Debug, Clone)]
+pub struct Handle {
+    shared: Arc<Shared>,
+}
+
+impl Handle {
+    /// Runs `f` with the stack locked.
+    pub fn with_stack<R>(&self, f: impl FnOnce(&mut Stack) -> R) -> R {
+        let r = f(&mut self.shared.lock());
+        self.shared.wake_driver();
+        r
+    }
+
+    /// A stream reading and writing the connection `id` from a task.
+    pub fn stream(&self, id: ConnectionID) -> Stream {
+        Stream {
+            handle: self.clone(),
+            id,
+            timeout: None,
+        }
+    }
+
+    /// Reads the data received on the connection `id`, fails with `WouldBlock` when there is none yet.
+    /// The window the read opened is advertised right away.
+    pub fn read(&self, id: &ConnectionID, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_or_park(id, buf, false)
+    }
+
+    /// Queues `data` on the connection `id` and sends what the windows allow, fails with `WouldBlock`
+    /// when the send buffer is full.
+    pub fn write(&self, id: &ConnectionID, data: &[u8]) -> io::Result<usize> {
+        self.write_or_park(id, data, false)
+    }
+
+    /// Closes the connection `id`, the data written is sent and followed by a FIN, see `Stack::close`.
+    pub fn close(&self, id: &ConnectionID) -> io::Result<()> {
+        let closed = self.shared.lock().close(&self.shared.nic, id);
+        self.shared.wake_driver();
+        closed
+    }
+
+    /// Like `read`, the current task being parked when `park` is set and there is nothing to read.
+    fn read_or_park(&self, id: &ConnectionID, buf: &mut [u8], park: bool) -> io::Result<usize> {
+        let mut stack = self.shared.lock();
+        let read = stack.try_read(&self.shared.nic, id, buf);
+        self.on_io(id, &read, park);
+        read
+    }
+
+    /// Like `write`, the current task being parked when `park` is set and the send buffer is full.
+    fn write_or_park(&self, id: &ConnectionID, data: &[u8], park: bool) -> io::Result<usize> {
+        let mut stack = self.shared.lock();
+        let written = stack.try_write(&self.shared.nic, id, data);
+        self.on_io(id, &written, park);
+        written
+    }
+
+    /// After a read or a write with the stack locked: parks the task when `park` is set and it would
+    /// block, otherwise wakes the driver as the next timer may have moved.
+    fn on_io(&self, id: &ConnectionID, result: &io::Result<usize>, park: bool) {
+        match result {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                if park {
+                    self.shared.park(id);
+                }
+            }
+            _ => self.shared.wake_driver(),
+        }
+    }
+
+    /// Makes the reads of the connection `id` fail with `TimedOut` once nothing arrived for `timeout`,
+    /// see `Stack::set_read_timeout`. A `Stream` parked on the read is woken then.
+    pub fn set_read_timeout(&self, id: &ConnectionID, timeout: Option<Duration>) -> io::Result<()> {
+        self.shared.lock().set_read_timeout(id, timeout)
+    }
+
+    /// Makes the writes of the connection `id` fail with `TimedOut` once the send buffer stayed full
+    /// for `timeout`.
+    pub fn set_write_timeout(
+        &self,
+        id: &ConnectionID,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        self.shared.lock().set_write_timeout(id, timeout)
+    }
+
+    /// Runs a command of the control plane.
+    pub fn command(&self, command: Command) -> Reply {
+        self.shared.lock().handle_command(command, &self.shared.nic)
+    }
+}
+
+/// A connection of a stack run by a `Driver`, read and written from a task. `WouldBlock` parks the
+/// task until a segment arrives on the connection, or the read or write timeout is over, it's not to
+/// be used outside a task.
+#[derive(Debug)]
+pub struct Stream {
+    handle: Handle,
+    id: ConnectionID,
+    /// Until the timeout of the read or the write parked is over
+    timeout: Option<Delay>,
+}
+
+impl Stream {
+    pub fn id(&self) -> &ConnectionID {
+        &self.id
+    }
+
+    /// After a read or a write: a task parked on it is also woken at the `deadline` of the stack,
+    /// when it fails with `TimedOut`.
+    fn on_io<T>(
+        &mut self,
+        result: io::Result<T>,
+        deadline: fn(&Stack, &ConnectionID) -> Option<Instant>,
+    ) -> io::Result<T> {
+        let at = match &result {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                deadline(&self.handle.shared.lock(), &self.id)
+            }
+            _ => None,
+        };
+        let Some(at) = at else {
+            self.timeout = None;
+            return result;
+        };
+        let timeout = self.timeout.get_or_insert_with(|| Delay::new(at));
+        timeout.reset(at);
+        match timeout.poll() {
+            Ok(Async::NotReady) => {}
+            // over already, the task tries again right away
+            Ok(Async::Ready(())) => task::current().notify(),
+            Err(e) => return Err(io::Error::other(e)),
+        }
+        result
+    }
+}
+
+impl Clone for Stream {
+    fn clone(&self) -> Self {
+        self.handle.stream(self.id.clone())
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.handle.read_or_park(&self.id, buf, true);
+        self.on_io(read, Stack::read_deadline)
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let written = self.handle.write_or_park(&self.id, data, true);
+        self.on_io(written, Stack::write_deadline)
+    }
+
+    /// The data written is sent as soon as the windows allow, there is nothing to flush.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncRead for Stream {}
+
+impl AsyncWrite for Stream {
+    /// Closes the connection, the FIN follows the data written in the background, see `Handle::close`.
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.handle.close(&self.id)?;
+        Ok(Async::Ready(()))
+    }
+}
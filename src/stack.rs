@@ -0,0 +1,2604 @@
+//! The connections of the stack and the processing of the packets they receive. The stack is driven by
+//! an event loop, the blocking loop of the binary or a task of a tokio runtime, see `runtime`: the loop
+//! hands it the packets read from the nic, fires its timers when they are due and runs the commands of
+//! the control plane in between.
+//!
+//! A single threaded application drives the stack itself instead: `poll_readiness` waits for a packet
+//! or the next timer, processes it and returns the connections whose readiness changed, which are then
+//! read with `try_read` and written with `try_write` until they would block.
+
+#[cfg(unix)]
+use crate::builder::TcpStackBuilder;
+use crate::config::{Config, Reloaded};
+use crate::core::checksum;
+use crate::ctl::{Command, ConnectionSummary, Reply};
+#[cfg(unix)]
+use crate::device::Nic;
+use crate::device::{recv_buffer_size, NetworkDevice, RecvBatch, SendBatch, BATCH_SIZE};
+use crate::ip::fragment::{self, Fragments};
+use crate::ip::icmp::{self, IcmpError, ICMP_BURST, ICMP_PROTOCOL};
+use crate::ip::{self, IpParams};
+use crate::observer::{Observer, Observers, Progress};
+use crate::stats::Stats;
+use crate::tcp::anomaly::{AnomalyPolicy, FlagAnomaly};
+use crate::tcp::diagnostics::Diagnosis;
+use crate::tcp::error::TcpError;
+use crate::tcp::established::OptimisticAck;
+use crate::tcp::fingerprint::Fingerprint;
+use crate::tcp::handshake::cookie::SynCookies;
+use crate::tcp::info::TcpInfo;
+use crate::tcp::listener::{Listener, OverflowPolicy};
+#[cfg(unix)]
+use crate::tcp::nic_mss;
+use crate::tcp::ports::Ports;
+use crate::tcp::queue::QueueStats;
+use crate::tcp::ratelimit::TokenBucket;
+use crate::tcp::sockopt::{OptionName, SocketOption};
+use crate::tcp::state::{Established, SynRecv, SynSent};
+use crate::tcp::timer::{TimerKind, TimerWheel};
+use crate::tcp::{send_reset, Connection, ConnectionID, Tunables};
+use crate::wire::SegmentView;
+use crate::ETH_HEADER_OFFSET;
+use anyhow::{anyhow, Result};
+use etherparse::Ipv4HeaderSlice;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddrV4};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+pub struct Stack {
+    connections: HashMap<ConnectionID, ConnectionWrapper>,
+    /// The ports bound, none accepting connections to any port
+    listening: HashSet<u16>,
+    /// The accept queues of the ports bound
+    listeners: HashMap<u16, Listener>,
+    /// The local ports bound, to listen or to connect from, and the ephemeral ones
+    ports: Ports,
+    /// The address of the stack, the source of the connections it opens when the device doesn't know
+    /// one, see `set_address`
+    address: Option<Ipv4Addr>,
+    /// The readiness of the connections last returned by `poll_readiness`
+    readiness: HashMap<ConnectionID, Readiness>,
+    timers: TimerWheel,
+    stats: Stats,
+    tunables: Tunables,
+    observers: Observers,
+    /// The configuration file and what it held when last applied
+    config: Option<(PathBuf, Config)>,
+    /// The buffers `poll_readiness` reads the packets into, kept from a call to the next
+    batch: Option<RecvBatch>,
+    /// The datagrams arriving in fragments, see `ip::fragment`
+    fragments: Fragments,
+    /// The tokens of the ICMP errors sent, see `Tunables::icmp_rate_limit`
+    icmp_limit: Option<TokenBucket>,
+    /// The rate limit of the challenge ACKs of all the connections, see `challenge_ack_limit`
+    challenge_limit: Option<TokenBucket>,
+    /// The errors of the connections aborted by an ICMP hard error, returned once to the application
+    errors: HashMap<ConnectionID, ErrorKind>,
+    /// The handshakes in progress, the oldest first, evicted first once `max_connections` are held.
+    /// Those completed or gone since are skipped
+    half_open: VecDeque<ConnectionID>,
+}
+
+impl Stack {
+    pub fn new(tunables: Tunables) -> Self {
+        Self {
+            tunables,
+            ..Default::default()
+        }
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub fn tunables(&self) -> &Tunables {
+        &self.tunables
+    }
+
+    pub fn tunables_mut(&mut self) -> &mut Tunables {
+        &mut self.tunables
+    }
+
+    /// Applies the configuration read from `path`, which `reload` reads again.
+    pub fn configure(&mut self, path: PathBuf, config: Config) -> Result<()> {
+        self.apply_config(&config, &[])?;
+        self.config = Some((path, config));
+        Ok(())
+    }
+
+    /// Reads the configuration file again and applies the listeners and the tunables, the connections
+    /// are kept. Nothing is applied when any setting is invalid.
+    pub fn reload(&mut self) -> Result<Reloaded> {
+        let (path, current) = self
+            .config
+            .take()
+            .ok_or_else(|| anyhow!("no configuration file"))?;
+        let config = match Config::load(&path) {
+            Ok(config) => config,
+            Err(e) => {
+                self.config = Some((path, current));
+                return Err(e);
+            }
+        };
+        let applied = match self.apply_config(&config, &current.listen) {
+            Ok(applied) => applied,
+            Err(e) => {
+                self.config = Some((path, current));
+                return Err(e);
+            }
+        };
+        let restart = current.restart_required(&config);
+        // the device keeps the settings it started with until the restart
+        let config = Config {
+            iface: current.iface,
+            cidr: current.cidr,
+            mtu: current.mtu,
+            routes: current.routes,
+            ..config
+        };
+        self.config = Some((path, config));
+        Ok(Reloaded { applied, restart })
+    }
+
+    /// Applies the tunables and the listeners of `config`, the ports it bound before being `bound`.
+    /// Returns the names of the settings changed.
+    fn apply_config(&mut self, config: &Config, bound: &[u16]) -> Result<Vec<String>> {
+        let mut tunables = self.tunables.clone();
+        for (name, value) in &config.tunables {
+            tunables.set(name, *value)?;
+        }
+        let mut applied = tunables
+            .values()
+            .into_iter()
+            .zip(self.tunables.values())
+            .filter(|(new, old)| new != old)
+            .map(|((name, _), _)| name.to_string())
+            .collect::<Vec<_>>();
+        self.tunables = tunables;
+
+        let mut listen_changed = false;
+        for port in bound.iter().filter(|port| !config.listen.contains(port)) {
+            self.unbind(*port);
+            listen_changed = true;
+        }
+        for port in &config.listen {
+            if !self.listening.contains(port) {
+                self.bind(*port)?;
+                listen_changed = true;
+            }
+        }
+        if listen_changed {
+            applied.push("listen".to_string());
+        }
+        Ok(applied)
+    }
+
+    /// Registers an observer of the events of the connections, called after the ones registered before.
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.observers.push(observer);
+    }
+
+    /// Accepts the connections to `port`, queued until accepted, `listen_backlog` of them at most. Once
+    /// a port is bound, the SYNs to the others are refused.
+    pub fn bind(&mut self, port: u16) -> io::Result<()> {
+        self.ports.bind(port)?;
+        self.listening.insert(port);
+        self.listeners
+            .insert(port, Listener::new(self.tunables.listen_backlog));
+        Ok(())
+    }
+
+    /// Bounds the accept queue of the bound `port` to `backlog` connections.
+    pub fn set_backlog(&mut self, port: u16, backlog: u32) -> io::Result<()> {
+        self.listener_mut(port)?.set_backlog(backlog);
+        Ok(())
+    }
+
+    /// Only accepts the connections to the bound `port` once they received data, like
+    /// `TCP_DEFER_ACCEPT`.
+    pub fn set_defer_accept(&mut self, port: u16, defer: bool) -> io::Result<()> {
+        self.listener_mut(port)?.set_defer_accept(defer);
+        Ok(())
+    }
+
+    fn listener_mut(&mut self, port: u16) -> io::Result<&mut Listener> {
+        self.listeners
+            .get_mut(&port)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("port {port:} not bound")))
+    }
+
+    /// Binds `port` to open connections from, see `connect`.
+    pub fn bind_local(&mut self, port: u16) -> io::Result<()> {
+        self.ports.bind(port)
+    }
+
+    /// Releases `port`, bound to listen or to connect from. A listener stops accepting connections, the
+    /// ones established and not accepted yet are kept.
+    pub fn unbind(&mut self, port: u16) {
+        self.ports.unbind(port);
+        self.listening.remove(&port);
+        self.listeners.remove(&port);
+    }
+
+    /// The next connection established on the bound `port`, none until one is.
+    pub fn accept(&mut self, port: u16) -> Option<ConnectionID> {
+        let connections = &self.connections;
+        self.listeners
+            .get_mut(&port)?
+            .accept(|id| connections.contains_key(id))
+    }
+
+    /// Sets the address of the stack, the source of the connections it opens when the device doesn't
+    /// know the route to the peer, see `NetworkDevice::source_address`. On a tun device any address the
+    /// kernel routes into the device will do.
+    pub fn set_address(&mut self, addr: Ipv4Addr) {
+        self.address = Some(addr);
+    }
+
+    /// Opens a connection to `remote` from `local_port`, bound with `bind_local` beforehand, or from an
+    /// ephemeral port, and from the source address of the route to `remote` when `nic` knows it, see
+    /// `NetworkDevice::source_address`, or else from the address of the stack. The SYN is sent right
+    /// away, the connection is writable once the handshake completes, see `handshake::active`. The
+    /// 4-tuple is taken until the connection is gone, and for the TIME-WAIT it ends in.
+    pub fn connect(
+        &mut self,
+        nic: &dyn NetworkDevice,
+        local_port: Option<u16>,
+        remote: SocketAddrV4,
+    ) -> io::Result<ConnectionID> {
+        let src = nic
+            .source_address(*remote.ip())
+            .or(self.address)
+            .ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::AddrNotAvailable,
+                    format!("no source address to connect to {remote:} from, see set_address"),
+                )
+            })?;
+        // the local ports of the connections with `remote` from `src`
+        let used = self
+            .connections
+            .keys()
+            .filter(|id| id.src_addr == *remote.ip() && id.src_port == remote.port())
+            .filter(|id| id.dst_addr == src)
+            .map(|id| id.dst_port)
+            .collect::<HashSet<_>>();
+        let in_use = |port| used.contains(&port);
+        let now = Instant::now();
+        let port = match local_port {
+            Some(port) if !self.ports.is_bound(port) || self.listening.contains(&port) => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("port {port:} isn't bound to connect from"),
+                ));
+            }
+            Some(port) => {
+                self.ports.check(remote, port, in_use, now)?;
+                port
+            }
+            None => self.ports.allocate(remote, in_use, now)?,
+        };
+        if !self.make_room() {
+            return Err(io::Error::new(
+                ErrorKind::OutOfMemory,
+                format!("{:} connections held", self.connections.len()),
+            ));
+        }
+        let id = ConnectionID {
+            src_addr: *remote.ip(),
+            src_port: remote.port(),
+            dst_addr: src,
+            dst_port: port,
+        };
+        let iss = self.tunables.iss_policy.iss(&id);
+        let conn = Connection::connect(nic, id.clone(), &self.tunables, iss).map_err(|e| {
+            io::Error::other(format!(
+                "can't connect to {remote:} from {src:}:{port:}: {e:}"
+            ))
+        })?;
+        log::info!("connecting to {remote:} from {src:}:{port:}");
+        self.stats.connections_opened += 1;
+        self.stats.active_opens += 1;
+        self.observers.on_state_change(&id, "CLOSED", "SYN-SENT");
+        self.connections
+            .insert(id.clone(), ConnectionWrapper::SynSent(conn));
+        self.schedule_timers(&id);
+        Ok(id)
+    }
+
+    pub fn contains(&self, id: &ConnectionID) -> bool {
+        self.connections.contains_key(id)
+    }
+
+    /// The connection `id` once it's established.
+    pub fn established_mut(&mut self, id: &ConnectionID) -> Option<&mut Connection<Established>> {
+        match self.connections.get_mut(id) {
+            Some(ConnectionWrapper::Established(conn)) => Some(conn),
+            _ => None,
+        }
+    }
+
+    /// The connection `id` while it's in the handshake, the data of a Fast Open SYN may be read already.
+    pub fn syn_recv_mut(&mut self, id: &ConnectionID) -> Option<&mut Connection<SynRecv>> {
+        match self.connections.get_mut(id) {
+            Some(ConnectionWrapper::SynRecv(conn)) => Some(conn),
+            _ => None,
+        }
+    }
+
+    /// Processes a batch of packets read from the nic, the answers written together once all are, see
+    /// `SendBatch`. Returns the connections they were for, as `on_packet` does.
+    pub fn on_batch(
+        &mut self,
+        nic: &dyn NetworkDevice,
+        batch: &RecvBatch,
+    ) -> io::Result<Vec<ConnectionID>> {
+        let tx = SendBatch::new(nic);
+        let ids = batch
+            .packets()
+            .filter_map(|packet| self.on_packet(&tx, packet))
+            .collect();
+        tx.flush()?;
+        Ok(ids)
+    }
+
+    /// Counts what the input queues in front of the stack shed since they were last drained, see
+    /// `tcp::queue`.
+    pub fn on_input_queues(&mut self, queued: &QueueStats) {
+        let stats = &mut self.stats;
+        stats.input_shed += queued.shed;
+        stats.input_evicted += queued.evicted;
+        stats.input_coalesced += queued.coalesced;
+        stats.input_dropped += queued.dropped;
+        stats.input_queue_high_watermark = stats
+            .input_queue_high_watermark
+            .max(queued.high_watermark as u64);
+    }
+
+    /// Processes a packet read from the nic. Returns the connection it was for, there may be data to
+    /// read.
+    pub fn on_packet(&mut self, nic: &dyn NetworkDevice, packet: &[u8]) -> Option<ConnectionID> {
+        let stats = &mut self.stats;
+        stats.packets_received += 1;
+
+        let reassembled;
+        // the device doesn't check the segments of fragments
+        let mut offloaded = nic.checksum_offload().rx;
+        let packet = match Ipv4HeaderSlice::from_slice(&packet[ETH_HEADER_OFFSET..]) {
+            Ok(ip) if ip.total_len() as usize > packet.len() - ETH_HEADER_OFFSET => {
+                stats.truncated_packets += 1;
+                stats.packets_dropped += 1;
+                // the rest are only counted
+                if stats.truncated_packets == 1 {
+                    log::warn!(
+                        "packet of {:} octets truncated to {:}, the receive buffer is smaller than the mtu",
+                        ip.total_len(),
+                        packet.len() - ETH_HEADER_OFFSET
+                    );
+                }
+                return None;
+            }
+            Ok(ip) if fragment::is_fragment(&ip) => {
+                stats.fragments_received += 1;
+                offloaded = false;
+                if !checksum::is_valid(checksum::sum(ip.slice(), 0)) {
+                    log::debug!("not processing due to a bad fragment header checksum");
+                    stats.checksum_errors += 1;
+                    stats.packets_dropped += 1;
+                    return None;
+                }
+                let now = Instant::now();
+                let tunables = &self.tunables;
+                stats.reassembly_failures +=
+                    self.fragments.expire(now, tunables.frag_timeout) as u64;
+                let mem_max = tunables.frag_mem_max.try_into().unwrap_or(usize::MAX);
+                match self.fragments.on_fragment(&ip, packet, now, mem_max) {
+                    Ok(Some(datagram)) => {
+                        stats.datagrams_reassembled += 1;
+                        reassembled = datagram;
+                        &reassembled
+                    }
+                    Ok(None) => return None,
+                    Err(e) => {
+                        log::debug!("not processing due to {:}", e);
+                        stats.reassembly_failures += 1;
+                        stats.packets_dropped += 1;
+                        return None;
+                    }
+                }
+            }
+            _ => packet,
+        };
+
+        let seg = match SegmentView::parse(packet) {
+            Ok(v) => v,
+            // the protocol field of the ip header
+            Err(TcpError::NotTcp) if packet[ETH_HEADER_OFFSET + 9] == ICMP_PROTOCOL => {
+                self.on_icmp(packet);
+                return None;
+            }
+            Err(TcpError::NotTcp) => {
+                stats.packets_dropped += 1;
+                self.on_unhandled_protocol(nic, packet);
+                return None;
+            }
+            Err(e) => {
+                log::debug!("not processing due to {:}", e);
+                stats.packets_dropped += 1;
+                return None;
+            }
+        };
+        let verified = match offloaded {
+            true => seg.verify_ip_checksum(),
+            false => seg.verify_checksums(),
+        };
+        if let Err(e) = verified {
+            log::debug!("not processing due to {:}", e);
+            stats.checksum_errors += 1;
+            stats.packets_dropped += 1;
+            return None;
+        }
+        if let Some(reason) = self.tunables.ip_options_policy.rejects(seg.ip.options()) {
+            log::debug!("not processing due to {reason:}");
+            stats.ip_options_rejected += 1;
+            stats.packets_dropped += 1;
+            return None;
+        }
+        let id = seg.id();
+
+        if let Some(anomaly) = FlagAnomaly::classify(&seg.tcp) {
+            let ip = self.tunables.ip_params();
+            handle_anomaly(anomaly, nic, &seg, self.tunables.anomaly_policy, ip, stats);
+            return None;
+        }
+
+        log::debug!("received {:} bytes from id: {id:?}", packet.len());
+
+        // the final ACK of a handshake answered with a cookie creates its connection, see
+        // `handshake::cookie`; one without a valid cookie is answered as in LISTEN
+        let listening = self.listening.is_empty() || self.listening.contains(&id.dst_port);
+        let cookies = self.tunables.syn_cookies != SynCookies::Off
+            && listening
+            && !has_keys(&self.tunables, &id);
+        let tcp = &seg.tcp;
+        if cookies && tcp.ack() && !(tcp.syn() || tcp.rst()) && !self.connections.contains_key(&id)
+        {
+            match Connection::from_cookie(seg.clone(), &self.tunables) {
+                Some(_) if !self.make_room() => {
+                    self.refuse(nic, &seg);
+                    return None;
+                }
+                Some(conn) => {
+                    let stats = &mut self.stats;
+                    stats.syn_cookies_accepted += 1;
+                    stats.connections_opened += 1;
+                    self.observers
+                        .on_state_change(&id, "LISTEN", "SYN-RECEIVED");
+                    self.connections
+                        .insert(id.clone(), ConnectionWrapper::SynRecv(conn));
+                }
+                None => self.stats.syn_cookies_rejected += 1,
+            }
+        }
+        // a SYN for a connection in TIME-WAIT reopens it when it's of a new incarnation, the old one
+        // giving way to a new handshake, and is dropped otherwise, https://www.ietf.org/rfc/rfc6191.txt 2
+        let time_wait = match self.connections.get(&id) {
+            Some(ConnectionWrapper::Established(conn)) if tcp.syn() && !tcp.ack() => {
+                conn.time_wait()
+            }
+            _ => None,
+        };
+        if let Some(time_wait) = time_wait {
+            let tsval = seg.timestamps().map(|(tsval, _)| tsval);
+            if !time_wait.accepts_syn(tcp.sequence_number(), tsval) {
+                log::debug!(
+                    "syn {:} for {id:?} in TIME-WAIT dropped",
+                    tcp.sequence_number()
+                );
+                return None;
+            }
+            log::info!("connection: {id:?} in TIME-WAIT reopened");
+            self.connections.remove(&id);
+            self.timers.cancel_all(&id);
+            self.stats.time_wait_reopened += 1;
+            self.stats.connections_closed += 1;
+            self.observers.on_closed(&id);
+        }
+        if let Some(ConnectionWrapper::SynSent(_)) = self.connections.get(&id) {
+            return self.on_syn_sent(nic, &seg);
+        }
+        let stats = &mut self.stats;
+        // the handshakes in progress are only counted for a SYN
+        let with_cookie = cookies
+            && tcp.syn()
+            && !tcp.ack()
+            && match self.tunables.syn_cookies {
+                SynCookies::Overflow => {
+                    let backlog = self
+                        .listeners
+                        .get(&id.dst_port)
+                        .map_or(self.tunables.listen_backlog, Listener::backlog);
+                    let half_open = self
+                        .connections
+                        .iter()
+                        .filter(|(other, conn)| {
+                            other.dst_port == id.dst_port
+                                && matches!(conn, ConnectionWrapper::SynRecv(_))
+                        })
+                        .count();
+                    half_open >= backlog as usize
+                }
+                mode => mode == SynCookies::Always,
+            };
+
+        match self.connections.entry(id.clone()) {
+            Entry::Vacant(_) => {
+                // no listener, the connection is CLOSED: https://www.ietf.org/rfc/rfc793.txt page 65
+                if !(self.listening.is_empty() || self.listening.contains(&id.dst_port)) {
+                    if !seg.tcp.rst() {
+                        stats.connections_refused += 1;
+                        if let Err(e) = send_reset(nic, &seg, self.tunables.ip_params()) {
+                            log::error!("error: {e:}");
+                        }
+                    }
+                    return None;
+                }
+                if self
+                    .listeners
+                    .get(&id.dst_port)
+                    .is_some_and(Listener::is_full)
+                {
+                    stats.accept_overflows += 1;
+                    log::debug!(
+                        "accept queue of port {:} full, SYN from {id:?}",
+                        id.dst_port
+                    );
+                    if self.tunables.accept_overflow == OverflowPolicy::Reset && !seg.tcp.rst() {
+                        if let Err(e) = send_reset(nic, &seg, self.tunables.ip_params()) {
+                            log::error!("error: {e:}");
+                        }
+                    }
+                    return None;
+                }
+                // a SYN flood fills the connections with handshakes that never complete, the cookies
+                // answer them without creating any
+                let handshake = Connection::new(seg.clone());
+                if with_cookie {
+                    match handshake.syn_ack_cookie(nic, &self.tunables, stats) {
+                        Ok(()) => stats.syn_cookies_sent += 1,
+                        Err(e) => {
+                            stats.handshake_errors += 1;
+                            on_handshake_error(&e, nic, &seg, self.tunables.ip_params());
+                        }
+                    }
+                    return None;
+                }
+                if !self.make_room() {
+                    self.refuse(nic, &seg);
+                    return None;
+                }
+                let stats = &mut self.stats;
+                match handshake.syn_ack(nic, &self.tunables, stats) {
+                    Ok(next) => {
+                        stats.connections_opened += 1;
+                        self.observers
+                            .on_state_change(&id, "LISTEN", "SYN-RECEIVED");
+                        self.connections
+                            .insert(id.clone(), ConnectionWrapper::SynRecv(next));
+                        self.on_half_open(id.clone());
+                        Some(id)
+                    }
+                    Err(e) => {
+                        stats.handshake_errors += 1;
+                        on_handshake_error(&e, nic, &seg, self.tunables.ip_params());
+                        None
+                    }
+                }
+            }
+            Entry::Occupied(e) => {
+                log::debug!("connection: {id:?} already exists");
+                log::info!(
+                    "received tcp header, ack: {:}, seq: {:}, syn: {:}",
+                    seg.tcp.ack(),
+                    seg.tcp.sequence_number(),
+                    seg.tcp.syn()
+                );
+                // the queue filled up since the SYN: the final ACK is dropped, the peer retransmits it
+                // and may find room by then, or the handshake is aborted
+                let overflow = matches!(e.get(), ConnectionWrapper::SynRecv(_))
+                    && self
+                        .listeners
+                        .get(&id.dst_port)
+                        .is_some_and(Listener::is_full);
+                if overflow {
+                    stats.accept_overflows += 1;
+                    if self.tunables.accept_overflow == OverflowPolicy::Reset {
+                        stats.connections_closed += 1;
+                        self.observers.on_closed(&id);
+                        if let Err(e) = e.remove().reset(nic) {
+                            log::error!("error: {e:}");
+                        }
+                    }
+                    return None;
+                }
+                let established = matches!(e.get(), ConnectionWrapper::SynRecv(_));
+                let mut conn = match e.remove() {
+                    ConnectionWrapper::SynRecv(conn) => match conn.check_ack(nic, &seg) {
+                        Ok(conn) => {
+                            stats.connections_established += 1;
+                            conn
+                        }
+                        Err(e) => {
+                            stats.handshake_errors += 1;
+                            stats.connections_closed += 1;
+                            self.observers.on_closed(&id);
+                            on_handshake_error(&e, nic, &seg, self.tunables.ip_params());
+                            return None;
+                        }
+                    },
+                    ConnectionWrapper::Established(conn) => conn,
+                    ConnectionWrapper::SynSent(_) => unreachable!("handled by on_syn_sent"),
+                };
+                let before = if established {
+                    self.observers.on_established(&id);
+                    Progress::syn_received()
+                } else {
+                    Progress::of(&conn)
+                };
+
+                // the final ACK of the handshake may already carry data, so it's processed as well
+                let processed = conn.on_segment(&seg, stats);
+                // a global limit, so the challenge ACKs don't reveal whether a guess fell in a window,
+                // https://www.ietf.org/rfc/rfc5961.txt 7
+                if conn.take_challenge_ack() {
+                    let rate = self.tunables.challenge_ack_limit;
+                    if take_token(&mut self.challenge_limit, rate, rate) {
+                        stats.challenge_acks += 1;
+                        conn.ack_now();
+                    } else {
+                        stats.challenge_acks_limited += 1;
+                    }
+                }
+                self.observers.on_progress(&id, before, &conn);
+                if let Err(e) = processed {
+                    self.timers.cancel_all(&id);
+                    stats.connections_closed += 1;
+                    self.observers.on_closed(&id);
+                    log::info!("connection: {id:?} closed due to {e:}");
+                    if e.is::<OptimisticAck>() {
+                        stats.optimistic_ack_resets += 1;
+                        if let Err(e) = conn.reset(nic) {
+                            log::error!("error: {e:}");
+                        }
+                    }
+                    return None;
+                }
+                // queued once established, or once it has data when the listener defers the accept
+                if let Some(listener) = self.listeners.get_mut(&id.dst_port) {
+                    let has_data = conn.available() > 0;
+                    if established {
+                        listener.push(id.clone(), has_data);
+                    } else if has_data {
+                        listener.on_data(&id);
+                    }
+                }
+                self.connections
+                    .insert(id.clone(), ConnectionWrapper::Established(conn));
+                self.transmit(nic, &id).ok().map(|_| id)
+            }
+        }
+    }
+
+    /// Processes `seg` for the connection in SYN-SENT it's for: the SYN-ACK of the peer establishes it
+    /// and the ACK completing the handshake is sent, a RST acknowledging our SYN refuses it, the
+    /// application getting `ConnectionRefused`. The rest is dropped, an unacceptable ACK answered with a
+    /// RST, see `handshake::active`.
+    fn on_syn_sent(&mut self, nic: &dyn NetworkDevice, seg: &SegmentView) -> Option<ConnectionID> {
+        let id = seg.id();
+        let Some(ConnectionWrapper::SynSent(conn)) = self.connections.get_mut(&id) else {
+            return None;
+        };
+        match conn.check_syn_ack(seg) {
+            Ok(()) => {}
+            Err(TcpError::RstReceived) => {
+                self.connections.remove(&id);
+                self.timers.cancel_all(&id);
+                self.stats.connections_closed += 1;
+                self.observers.on_closed(&id);
+                log::info!("connection: {id:?} refused");
+                self.errors.insert(id, ErrorKind::ConnectionRefused);
+                return None;
+            }
+            Err(e) => {
+                self.stats.handshake_errors += 1;
+                on_handshake_error(&e, nic, seg, self.tunables.ip_params());
+                return None;
+            }
+        }
+        let Some(ConnectionWrapper::SynSent(conn)) = self.connections.remove(&id) else {
+            return None;
+        };
+        let conn = conn.establish(seg, &self.tunables);
+        self.stats.connections_established += 1;
+        self.observers.on_established(&id);
+        self.observers
+            .on_state_change(&id, "SYN-SENT", "ESTABLISHED");
+        self.connections
+            .insert(id.clone(), ConnectionWrapper::Established(conn));
+        self.transmit(nic, &id).ok().map(|_| id)
+    }
+
+    /// Reads the data received on the connection `id`, fails with `WouldBlock` when there is none yet,
+    /// or with `TimedOut` once that lasted the read timeout, see `set_read_timeout`. The window the
+    /// read opened is advertised right away.
+    pub fn try_read(
+        &mut self,
+        nic: &dyn NetworkDevice,
+        id: &ConnectionID,
+        buf: &mut [u8],
+    ) -> io::Result<usize> {
+        let Some(conn) = self.established_mut(id) else {
+            return Err(self.unavailable(id));
+        };
+        let read = conn.try_read(buf, Instant::now());
+        self.on_io(id);
+        if matches!(read, Ok(n) if n > 0) {
+            self.transmit(nic, id).map_err(io::Error::other)?;
+        }
+        read
+    }
+
+    /// Closes the connection `id`: the data written so far is sent and followed by a FIN, in the
+    /// background unless a `Linger` time bounds it, see `close`. With a `Linger` of 0 the connection is
+    /// aborted with a RST instead, the data not acknowledged is lost.
+    pub fn close(&mut self, nic: &dyn NetworkDevice, id: &ConnectionID) -> io::Result<()> {
+        // an open still in SYN-SENT is given up, nothing was sent but the SYN
+        if let Some(ConnectionWrapper::SynSent(_)) = self.connections.get(id) {
+            self.connections.remove(id);
+            self.timers.cancel_all(id);
+            self.stats.connections_closed += 1;
+            self.observers.on_closed(id);
+            log::info!("connection: {id:?} closed in SYN-SENT");
+            return Ok(());
+        }
+        let Some(ConnectionWrapper::Established(conn)) = self.connections.get_mut(id) else {
+            return Err(self.gone(id));
+        };
+        if conn.option(OptionName::Linger) == SocketOption::Linger(Some(Duration::ZERO)) {
+            if let Some(conn) = self.connections.remove(id) {
+                self.timers.cancel_all(id);
+                self.stats.connections_closed += 1;
+                self.observers.on_closed(id);
+                log::info!("connection: {id:?} aborted");
+                conn.reset(nic).map_err(io::Error::other)?;
+            }
+            return Ok(());
+        }
+        let before = Progress::of(conn);
+        conn.close();
+        self.observers.on_progress(id, before, conn);
+        self.transmit(nic, id).map_err(io::Error::other)
+    }
+
+    /// Whether the data and the FIN of the connection `id` closed were acknowledged, a connection gone
+    /// is.
+    pub fn is_flushed(&self, id: &ConnectionID) -> bool {
+        match self.connections.get(id) {
+            Some(ConnectionWrapper::Established(conn)) => conn.is_flushed(),
+            _ => true,
+        }
+    }
+
+    /// Sets a socket option of the connection `id`, see `sockopt`. The data Nagle held back goes out
+    /// once `NoDelay` is switched on, and the keep-alive timer is moved.
+    pub fn set_option(
+        &mut self,
+        nic: &dyn NetworkDevice,
+        id: &ConnectionID,
+        option: SocketOption,
+    ) -> io::Result<()> {
+        let conn = self.established_mut(id).ok_or_else(|| not_found(id))?;
+        conn.set_option(option)?;
+        self.transmit(nic, id).map_err(io::Error::other)
+    }
+
+    /// The state and the measurements of the connection `id`, like `TCP_INFO`.
+    pub fn info(&self, id: &ConnectionID) -> io::Result<TcpInfo> {
+        match self.connections.get(id) {
+            Some(ConnectionWrapper::Established(conn)) => Ok(conn.info()),
+            _ => Err(not_found(id)),
+        }
+    }
+
+    /// The current value of the socket option `name` of the connection `id`.
+    pub fn option(&self, id: &ConnectionID, name: OptionName) -> io::Result<SocketOption> {
+        match self.connections.get(id) {
+            Some(ConnectionWrapper::Established(conn)) => Ok(conn.option(name)),
+            _ => Err(not_found(id)),
+        }
+    }
+
+    /// Sets how long the reads of the connection `id` find nothing before `try_read` fails with
+    /// `TimedOut`, `None` waits forever. A blocking handle waits until `read_deadline` at most.
+    pub fn set_read_timeout(
+        &mut self,
+        id: &ConnectionID,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        let conn = self.established_mut(id).ok_or_else(|| not_found(id))?;
+        conn.set_read_timeout(timeout)
+    }
+
+    /// Sets how long the send buffer of the connection `id` stays full before `try_write` fails with
+    /// `TimedOut`, `None` waits forever.
+    pub fn set_write_timeout(
+        &mut self,
+        id: &ConnectionID,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        let conn = self.established_mut(id).ok_or_else(|| not_found(id))?;
+        conn.set_write_timeout(timeout)
+    }
+
+    pub fn read_timeout(&self, id: &ConnectionID) -> io::Result<Option<Duration>> {
+        self.established(id).map(Connection::read_timeout)
+    }
+
+    pub fn write_timeout(&self, id: &ConnectionID) -> io::Result<Option<Duration>> {
+        self.established(id).map(Connection::write_timeout)
+    }
+
+    /// When a read of the connection `id` finding nothing since fails with `TimedOut`, none without a
+    /// read timeout or once the connection is gone.
+    pub fn read_deadline(&self, id: &ConnectionID) -> Option<Instant> {
+        self.established(id).ok()?.read_deadline()
+    }
+
+    /// When a write of the connection `id` finding the send buffer full since fails with `TimedOut`.
+    pub fn write_deadline(&self, id: &ConnectionID) -> Option<Instant> {
+        self.established(id).ok()?.write_deadline()
+    }
+
+    fn established(&self, id: &ConnectionID) -> io::Result<&Connection<Established>> {
+        match self.connections.get(id) {
+            Some(ConnectionWrapper::Established(conn)) => Ok(conn),
+            _ => Err(not_found(id)),
+        }
+    }
+
+    /// Queues `data` on the connection `id` and sends what the windows allow, fails with `WouldBlock`
+    /// when the send buffer is full, with `TimedOut` once it stayed full for the write timeout, see
+    /// `set_write_timeout`, and with `BrokenPipe` after `close`.
+    pub fn try_write(
+        &mut self,
+        nic: &dyn NetworkDevice,
+        id: &ConnectionID,
+        data: &[u8],
+    ) -> io::Result<usize> {
+        let Some(conn) = self.established_mut(id) else {
+            return Err(self.unavailable(id));
+        };
+        let written = conn.try_write(data, Instant::now());
+        self.on_io(id);
+        if written.is_ok() {
+            self.transmit(nic, id).map_err(io::Error::other)?;
+        }
+        written
+    }
+
+    /// Whether a read or a write of the connection `id` would go on without blocking. Both do once it's
+    /// gone, they fail right away.
+    pub fn readiness(&self, id: &ConnectionID) -> Readiness {
+        match self.connections.get(id) {
+            // the end of the stream reads and a write after the close fails, neither blocks
+            Some(ConnectionWrapper::Established(conn)) => Readiness {
+                readable: conn.available() > 0 || conn.is_read_closed(),
+                writable: conn.send_room() > 0 || conn.is_write_closed(),
+            },
+            // the handshake isn't done, neither reads nor writes go through yet
+            Some(ConnectionWrapper::SynRecv(_) | ConnectionWrapper::SynSent(_)) => {
+                Readiness::default()
+            }
+            None => Readiness {
+                readable: true,
+                writable: true,
+            },
+        }
+    }
+
+    /// Waits for a packet or the next timer, `timeout` at most, processes what is due and returns the
+    /// connections whose readiness changed since the last call. The readiness is edge triggered: a
+    /// connection still readable after a read isn't returned again, it's to be read until it would
+    /// block. A connection established is returned as writable, one gone as readable and writable.
+    pub fn poll_readiness(
+        &mut self,
+        nic: &dyn NetworkDevice,
+        timeout: Option<Duration>,
+    ) -> io::Result<Vec<(ConnectionID, Readiness)>> {
+        let now = Instant::now();
+        self.on_timers(nic, now);
+        let changed = self.readiness_changes();
+        if !changed.is_empty() {
+            return Ok(changed);
+        }
+
+        let until_timer = self
+            .next_deadline()
+            .map(|at| at.saturating_duration_since(now));
+        let wait = until_timer.into_iter().chain(timeout).min();
+        if nic.wait_readable(wait)? {
+            let mut batch = self
+                .batch
+                .take()
+                .unwrap_or_else(|| RecvBatch::new(BATCH_SIZE, recv_buffer_size(nic)));
+            nic.recv_batch(&mut batch)?;
+            self.on_batch(nic, &batch)?;
+            self.batch = Some(batch);
+        }
+        self.on_timers(nic, Instant::now());
+        Ok(self.readiness_changes())
+    }
+
+    /// The connections whose readiness changed since the last call, the connections gone included.
+    fn readiness_changes(&mut self) -> Vec<(ConnectionID, Readiness)> {
+        let gone = Readiness {
+            readable: true,
+            writable: true,
+        };
+        let connections = &self.connections;
+        let mut changed = Vec::new();
+        self.readiness.retain(|id, _| {
+            let alive = connections.contains_key(id);
+            if !alive {
+                changed.push((id.clone(), gone));
+            }
+            alive
+        });
+        for id in self.connections.keys() {
+            let readiness = self.readiness(id);
+            let before = self.readiness.insert(id.clone(), readiness);
+            if before.unwrap_or_default() != readiness {
+                changed.push((id.clone(), readiness));
+            }
+        }
+        changed
+    }
+
+    /// After a read or a write of the connection `id` by the application, which knows its readiness
+    /// changed: the next data is a change again.
+    fn on_io(&mut self, id: &ConnectionID) {
+        let readiness = self.readiness(id);
+        // only tracked once `poll_readiness` returned the connection
+        if let Some(seen) = self.readiness.get_mut(id) {
+            *seen = readiness;
+        }
+    }
+
+    /// Processes `packet`, an ICMP message: an error about a segment in flight aborts its connection when
+    /// it's hard, the application getting the error on its next read or write, and is recorded in
+    /// `TcpInfo::icmp_error` otherwise, see `ip::icmp`.
+    fn on_icmp(&mut self, packet: &[u8]) {
+        let error = match IcmpError::parse(packet) {
+            Ok(error) => error,
+            Err(e) => {
+                log::debug!("not processing due to {:}", e);
+                self.stats.packets_dropped += 1;
+                return;
+            }
+        };
+        let id = &error.id;
+        let in_flight = match self.connections.get(id) {
+            Some(ConnectionWrapper::SynRecv(conn)) => conn.is_in_flight(error.seq),
+            Some(ConnectionWrapper::SynSent(conn)) => conn.is_in_flight(error.seq),
+            Some(ConnectionWrapper::Established(conn)) => conn.is_in_flight(error.seq),
+            None => false,
+        };
+        if !in_flight {
+            log::debug!("icmp error quoting {:} of {id:?} dropped", error.seq);
+            self.stats.icmp_errors_rejected += 1;
+            self.stats.packets_dropped += 1;
+            return;
+        }
+        self.stats.icmp_errors_received += 1;
+        if !error.is_hard() {
+            match self.connections.get_mut(id) {
+                Some(ConnectionWrapper::SynRecv(conn)) => {
+                    conn.on_icmp_error(error.icmp_type, error.code)
+                }
+                Some(ConnectionWrapper::SynSent(conn)) => {
+                    conn.on_icmp_error(error.icmp_type, error.code)
+                }
+                Some(ConnectionWrapper::Established(conn)) => {
+                    conn.on_icmp_error(error.icmp_type, error.code)
+                }
+                None => {}
+            }
+            return;
+        }
+        self.connections.remove(id);
+        self.timers.cancel_all(id);
+        self.stats.icmp_aborts += 1;
+        self.stats.connections_closed += 1;
+        self.observers.on_closed(id);
+        log::info!(
+            "connection: {id:?} aborted due to icmp type {:} code {:}",
+            error.icmp_type,
+            error.code
+        );
+        self.errors.insert(error.id.clone(), error.kind());
+    }
+
+    /// The error of a read or a write of the connection `id`, not established: `WouldBlock` while its
+    /// handshake is in progress, see `gone` once it's gone.
+    fn unavailable(&mut self, id: &ConnectionID) -> io::Error {
+        match self.connections.contains_key(id) {
+            true => ErrorKind::WouldBlock.into(),
+            false => self.gone(id),
+        }
+    }
+
+    /// The error of the call of the application on the connection `id`, gone: the error it was aborted
+    /// with the first time, not found after.
+    fn gone(&mut self, id: &ConnectionID) -> io::Error {
+        match self.errors.remove(id) {
+            Some(kind) => io::Error::new(kind, format!("connection: {id:?} aborted")),
+            None => not_found(id),
+        }
+    }
+
+    /// Makes room for another connection when `max_connections` are held already, evicting the oldest
+    /// handshakes in progress when `evict_half_open` allows. Returns whether there is room.
+    fn make_room(&mut self) -> bool {
+        let max = self.tunables.max_connections;
+        while max > 0 && self.connections.len() as u64 >= max {
+            if !self.tunables.evict_half_open {
+                return false;
+            }
+            let Some(id) = self.half_open.pop_front() else {
+                return false;
+            };
+            if !matches!(
+                self.connections.get(&id),
+                Some(ConnectionWrapper::SynRecv(_))
+            ) {
+                continue;
+            }
+            log::debug!("handshake {id:?} evicted, {max:} connections held");
+            self.connections.remove(&id);
+            self.timers.cancel_all(&id);
+            self.stats.half_open_evicted += 1;
+            self.stats.connections_closed += 1;
+            self.observers.on_closed(&id);
+        }
+        true
+    }
+
+    /// Refuses the segment `seg` opening a connection when `make_room` found none, with a RST when
+    /// `connections_overflow` says so.
+    fn refuse(&mut self, nic: &dyn NetworkDevice, seg: &SegmentView) {
+        self.stats.connections_overflows += 1;
+        log::debug!(
+            "{:} connections held, {:?} refused",
+            self.connections.len(),
+            seg.id()
+        );
+        if self.tunables.connections_overflow == OverflowPolicy::Reset {
+            if let Err(e) = send_reset(nic, seg, self.tunables.ip_params()) {
+                log::error!("error: {e:}");
+            }
+        }
+    }
+
+    /// Remembers the handshake `id` is in progress, for `make_room`. The ones over since are dropped
+    /// once they outnumber the connections.
+    fn on_half_open(&mut self, id: ConnectionID) {
+        self.half_open.push_back(id);
+        if self.half_open.len() > 2 * self.connections.len() {
+            let connections = &self.connections;
+            self.half_open
+                .retain(|id| matches!(connections.get(id), Some(ConnectionWrapper::SynRecv(_))));
+        }
+    }
+
+    /// Answers `packet`, a datagram for a protocol the stack doesn't handle, with an ICMP destination
+    /// unreachable unless it exceeds the rate limit, see `ip::icmp`.
+    fn on_unhandled_protocol(&mut self, nic: &dyn NetworkDevice, packet: &[u8]) {
+        if !self.tunables.icmp_unreachable {
+            return;
+        }
+        let Some(reply) = icmp::unreachable(packet, self.tunables.ip_params()) else {
+            return;
+        };
+        let rate = self.tunables.icmp_rate_limit;
+        if !take_token(&mut self.icmp_limit, rate, ICMP_BURST) {
+            self.stats.icmp_rate_limited += 1;
+            return;
+        }
+        match ip::send(nic, &reply) {
+            Ok(()) => self.stats.icmp_unreachable_sent += 1,
+            Err(e) => log::error!("failed to send an icmp destination unreachable: {e:}"),
+        }
+    }
+
+    /// Sends what the connection `id` owes, after a segment or the application wrote or read. The
+    /// connection is dropped when it fails.
+    pub fn transmit(&mut self, nic: &dyn NetworkDevice, id: &ConnectionID) -> Result<()> {
+        let conn = self
+            .established_mut(id)
+            .ok_or_else(|| anyhow!("no connection: {id:?}"))?;
+        if let Err(e) = conn.transmit(nic) {
+            self.connections.remove(id);
+            self.timers.cancel_all(id);
+            self.stats.connections_closed += 1;
+            self.observers.on_closed(id);
+            log::error!("connection: {id:?} closed due to {e:}");
+            return Err(e);
+        }
+        if conn.is_done() {
+            self.connections.remove(id);
+            self.timers.cancel_all(id);
+            self.stats.connections_closed += 1;
+            self.observers.on_closed(id);
+            log::info!("connection: {id:?} closed");
+            return Ok(());
+        }
+        self.schedule_timers(id);
+        Ok(())
+    }
+
+    /// Fires the timers due at `now`.
+    pub fn on_timers(&mut self, nic: &dyn NetworkDevice, now: Instant) {
+        self.stats.reassembly_failures +=
+            self.fragments.expire(now, self.tunables.frag_timeout) as u64;
+        for (id, _) in self.timers.expire(now) {
+            if let Some(ConnectionWrapper::SynSent(conn)) = self.connections.get_mut(&id) {
+                if conn.is_timed_out(now) {
+                    self.connections.remove(&id);
+                    self.timers.cancel_all(&id);
+                    self.stats.connections_closed += 1;
+                    self.observers.on_closed(&id);
+                    log::info!("connection: {id:?} timed out in SYN-SENT");
+                    self.errors.insert(id, ErrorKind::TimedOut);
+                    continue;
+                }
+                if conn.syn_deadline() <= now {
+                    if let Err(e) = conn.on_timer(nic, now) {
+                        self.connections.remove(&id);
+                        self.timers.cancel_all(&id);
+                        self.stats.connections_closed += 1;
+                        self.observers.on_closed(&id);
+                        log::error!("connection: {id:?} closed due to {e:}");
+                        continue;
+                    }
+                    self.stats.retransmissions += 1;
+                }
+                self.schedule_timers(&id);
+                continue;
+            }
+            if let Some(ConnectionWrapper::Established(conn)) = self.connections.get_mut(&id) {
+                let before = Progress::of(conn);
+                let fired = conn.on_timer(nic, conn.now(), &mut self.stats);
+                self.observers.on_progress(&id, before, conn);
+                if let Err(e) = fired {
+                    self.connections.remove(&id);
+                    self.timers.cancel_all(&id);
+                    self.stats.connections_closed += 1;
+                    self.observers.on_closed(&id);
+                    log::error!("connection: {id:?} closed due to {e:}");
+                    continue;
+                }
+                // TIME-WAIT is over
+                if conn.is_done() {
+                    self.connections.remove(&id);
+                    self.timers.cancel_all(&id);
+                    self.stats.connections_closed += 1;
+                    self.observers.on_closed(&id);
+                    log::info!("connection: {id:?} closed");
+                    continue;
+                }
+                self.schedule_timers(&id);
+            }
+        }
+    }
+
+    /// When the next timer is due, or a datagram being reassembled expires, none when no timer is
+    /// scheduled.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        let fragments = self.fragments.next_deadline(self.tunables.frag_timeout);
+        self.timers
+            .next_deadline()
+            .into_iter()
+            .chain(fragments)
+            .min()
+    }
+
+    pub fn handle_command(&mut self, command: Command, nic: &dyn NetworkDevice) -> Reply {
+        let stats = &mut self.stats;
+        let tunables = &mut self.tunables;
+        match command {
+            Command::ListConnections => Reply::Connections(
+                self.connections
+                    .iter()
+                    .map(|(id, conn)| ConnectionSummary {
+                        id: id.clone(),
+                        state: conn.state_name(),
+                        fingerprint: conn.fingerprint().map(|f| f.signature()),
+                    })
+                    .collect(),
+            ),
+            Command::Stats => Reply::Stats(stats.clone()),
+            Command::Kill(id) => match self.connections.remove(&id) {
+                Some(conn) => {
+                    self.timers.cancel_all(&id);
+                    stats.connections_killed += 1;
+                    self.observers.on_closed(&id);
+                    match conn.reset(nic) {
+                        Ok(_) => Reply::Ok,
+                        Err(e) => Reply::Error(e.to_string()),
+                    }
+                }
+                None => Reply::Error(format!("no connection: {id:?}")),
+            },
+            Command::Tunables => Reply::Tunables(tunables.clone()),
+            Command::Diagnose(id) => match self.connections.get(&id) {
+                Some(conn) => Reply::Diagnosis(conn.diagnose()),
+                None => Reply::Error(format!("no connection: {id:?}")),
+            },
+            Command::Info(id) => match self.connections.get(&id) {
+                Some(ConnectionWrapper::Established(conn)) => Reply::Info(conn.info()),
+                Some(_) => Reply::Error(format!("{id:?} not established yet")),
+                None => Reply::Error(format!("no connection: {id:?}")),
+            },
+            Command::SetTunable(name, value) => match tunables.set(&name, value) {
+                Ok(_) => Reply::Ok,
+                Err(e) => Reply::Error(e.to_string()),
+            },
+            Command::SetMd5Key(addr, key) => {
+                tunables.md5_keys.set(addr, key);
+                Reply::Ok
+            }
+            Command::AddAoKey(addr, mkt) => {
+                tunables.ao_keys.add(addr, mkt);
+                Reply::Ok
+            }
+            Command::RemoveAoKey(addr, send_id) => match tunables.ao_keys.remove(addr, send_id) {
+                true => Reply::Ok,
+                false => Reply::Error(format!("no mkt with send id {send_id:} for {addr:}")),
+            },
+            Command::Reload => match self.reload() {
+                Ok(reloaded) => Reply::Reloaded(reloaded),
+                Err(e) => Reply::Error(format!("{e:#}")),
+            },
+            Command::Bind(port) => match self.bind(port) {
+                Ok(_) => Reply::Ok,
+                Err(e) => Reply::Error(e.to_string()),
+            },
+            Command::Connect(remote) => match self.connect(nic, None, remote) {
+                Ok(id) => Reply::Connected(id),
+                Err(e) => Reply::Error(e.to_string()),
+            },
+            Command::Send(id, data) => match self.try_write(nic, &id, &data) {
+                Ok(n) => Reply::Sent(n),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => Reply::Sent(0),
+                Err(e) => Reply::Error(e.to_string()),
+            },
+            Command::Close(id) => match self.close(nic, &id) {
+                Ok(_) => Reply::Ok,
+                Err(e) => Reply::Error(e.to_string()),
+            },
+            Command::Query(id) => match self.connections.get(&id) {
+                Some(conn) => Reply::Connections(vec![ConnectionSummary {
+                    id,
+                    state: conn.state_name(),
+                    fingerprint: conn.fingerprint().map(|f| f.signature()),
+                }]),
+                None => Reply::Error(format!("no connection: {id:?}")),
+            },
+        }
+    }
+
+    /// Checks every connection is internally consistent and none is leaked, i.e. the connection map
+    /// holds exactly the connections opened and not torn down yet.
+    pub fn check_invariants(&self) -> Result<()> {
+        for (id, conn) in &self.connections {
+            conn.check_invariants()
+                .map_err(|e| anyhow!("connection {id:?}: {e:}"))?;
+        }
+        if self.stats.connections_alive() != self.connections.len() as u64 {
+            return Err(anyhow!(
+                "{:} connections alive according to stats, but {:} in the map",
+                self.stats.connections_alive(),
+                self.connections.len()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Moves the timers of the connection to its deadlines, after anything that may have changed them.
+    fn schedule_timers(&mut self, id: &ConnectionID) {
+        let conn = match self.connections.get(id) {
+            Some(ConnectionWrapper::Established(conn)) => conn,
+            Some(ConnectionWrapper::SynSent(conn)) => {
+                let at = conn.syn_deadline();
+                self.timers
+                    .schedule(id.clone(), TimerKind::Retransmission, at);
+                return;
+            }
+            _ => return,
+        };
+        // the 4-tuple stays taken for the TIME-WAIT, even if the connection goes before it ends
+        if let Some(time_wait) = conn.time_wait() {
+            let remote = SocketAddrV4::new(id.src_addr, id.src_port);
+            self.ports.hold(remote, id.dst_port, time_wait.deadline());
+        }
+        let deadlines = [
+            (TimerKind::Retransmission, conn.rto_deadline()),
+            (TimerKind::Transmit, conn.timer_deadline()),
+            (TimerKind::KeepAlive, conn.keepalive_deadline()),
+            (TimerKind::TimeWait, conn.close_deadline()),
+        ];
+        for (kind, deadline) in deadlines {
+            match deadline {
+                Some(at) => self.timers.schedule(id.clone(), kind, at),
+                None => {
+                    self.timers.cancel(id, kind);
+                }
+            }
+        }
+    }
+}
+
+/// A stack together with the device it runs on, for the applications driving it from their own loop:
+/// `listen` on a port, `poll` for the connections ready and `read` and `write` them. The device is a
+/// `Nic` but for the tests and the embedders running it on another `NetworkDevice`, a
+/// `device::LoopbackDevice` for instance.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct TcpStack<D = Nic> {
+    nic: D,
+    stack: Stack,
+}
+
+#[cfg(unix)]
+impl TcpStack {
+    /// The device and the tunables to run a stack with, see `builder`.
+    pub fn builder() -> TcpStackBuilder {
+        TcpStackBuilder::new()
+    }
+
+    /// Runs a stack on `nic`, the MSS derived from the MTU of the device.
+    pub fn from_device(nic: tun_tap::Iface) -> Result<Self> {
+        let tunables = Tunables {
+            mss: nic_mss(&nic)?,
+            ..Default::default()
+        };
+        Ok(Self::with_tunables(nic, tunables))
+    }
+
+    pub fn with_tunables(nic: impl Into<Nic>, tunables: Tunables) -> Self {
+        Self::on_device(nic.into(), tunables)
+    }
+}
+
+#[cfg(unix)]
+impl<D: NetworkDevice> TcpStack<D> {
+    /// Runs a stack on `device`.
+    pub fn on_device(device: D, tunables: Tunables) -> Self {
+        Self {
+            nic: device,
+            stack: Stack::new(tunables),
+        }
+    }
+
+    pub fn nic(&self) -> &D {
+        &self.nic
+    }
+
+    pub fn stack(&self) -> &Stack {
+        &self.stack
+    }
+
+    pub fn stack_mut(&mut self) -> &mut Stack {
+        &mut self.stack
+    }
+
+    /// See `Stack::add_observer`.
+    pub fn add_observer(&mut self, observer: Box<dyn Observer>) {
+        self.stack.add_observer(observer);
+    }
+
+    /// The device and the stack, for a loop of its own.
+    pub fn into_parts(self) -> (D, Stack) {
+        (self.nic, self.stack)
+    }
+
+    /// Accepts the connections to `port`, see `Stack::bind`.
+    pub fn listen(&mut self, port: u16) -> io::Result<()> {
+        self.stack.bind(port)
+    }
+
+    /// Bounds the accept queue of the bound `port`, see `Stack::set_backlog`.
+    pub fn set_backlog(&mut self, port: u16, backlog: u32) -> io::Result<()> {
+        self.stack.set_backlog(port, backlog)
+    }
+
+    /// Defers the accept of the connections to the bound `port`, see `Stack::set_defer_accept`.
+    pub fn set_defer_accept(&mut self, port: u16, defer: bool) -> io::Result<()> {
+        self.stack.set_defer_accept(port, defer)
+    }
+
+    /// The next connection established on the bound `port`, see `Stack::accept`.
+    pub fn accept(&mut self, port: u16) -> Option<ConnectionID> {
+        self.stack.accept(port)
+    }
+
+    /// Sets the address the connections are opened from, see `Stack::set_address`. The builder sets the
+    /// one of a tap device.
+    pub fn set_address(&mut self, addr: Ipv4Addr) {
+        self.stack.set_address(addr);
+    }
+
+    /// Opens a connection to `remote` from an ephemeral port, see `Stack::connect`. The SYN is sent
+    /// right away, the connection is writable once `poll` reports it so.
+    pub fn connect(&mut self, remote: SocketAddrV4) -> io::Result<ConnectionID> {
+        self.stack.connect(&self.nic, None, remote)
+    }
+
+    /// Waits for the connections whose readiness changed, see `Stack::poll_readiness`.
+    pub fn poll(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> io::Result<Vec<(ConnectionID, Readiness)>> {
+        self.stack.poll_readiness(&self.nic, timeout)
+    }
+
+    /// Reads the connection `id`, fails with `WouldBlock` when there is nothing to read.
+    pub fn read(&mut self, id: &ConnectionID, buf: &mut [u8]) -> io::Result<usize> {
+        self.stack.try_read(&self.nic, id, buf)
+    }
+
+    /// Writes the connection `id`, fails with `WouldBlock` when the send buffer is full.
+    pub fn write(&mut self, id: &ConnectionID, data: &[u8]) -> io::Result<usize> {
+        self.stack.try_write(&self.nic, id, data)
+    }
+
+    /// Closes the connection `id`, see `Stack::close`. `is_flushed` tells once the peer acknowledged
+    /// the data and the FIN.
+    pub fn close(&mut self, id: &ConnectionID) -> io::Result<()> {
+        self.stack.close(&self.nic, id)
+    }
+
+    pub fn is_flushed(&self, id: &ConnectionID) -> bool {
+        self.stack.is_flushed(id)
+    }
+
+    /// Sets a socket option of the connection `id`.
+    pub fn set_option(&mut self, id: &ConnectionID, option: SocketOption) -> io::Result<()> {
+        self.stack.set_option(&self.nic, id, option)
+    }
+
+    /// Makes `read` fail with `TimedOut` once nothing arrived for `timeout`, see
+    /// `Stack::set_read_timeout`.
+    pub fn set_read_timeout(
+        &mut self,
+        id: &ConnectionID,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        self.stack.set_read_timeout(id, timeout)
+    }
+
+    /// Makes `write` fail with `TimedOut` once the send buffer stayed full for `timeout`.
+    pub fn set_write_timeout(
+        &mut self,
+        id: &ConnectionID,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        self.stack.set_write_timeout(id, timeout)
+    }
+
+    /// The current value of the socket option `name` of the connection `id`.
+    pub fn option(&self, id: &ConnectionID, name: OptionName) -> io::Result<SocketOption> {
+        self.stack.option(id, name)
+    }
+
+    /// The state and the measurements of the connection `id`.
+    pub fn info(&self, id: &ConnectionID) -> io::Result<TcpInfo> {
+        self.stack.info(id)
+    }
+
+    /// Runs a command of the control plane.
+    pub fn command(&mut self, command: Command) -> Reply {
+        self.stack.handle_command(command, &self.nic)
+    }
+}
+
+/// What a connection is ready for, see `Stack::readiness`
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Readiness {
+    pub readable: bool,
+    pub writable: bool,
+}
+
+/// Waits until the nic has a packet to read or the timeout elapses, forever without one. Returns whether
+/// it's readable, a signal interrupting the wait is no packet.
+#[cfg(unix)]
+pub fn wait_readable(nic: &impl AsRawFd, timeout: Option<Duration>) -> io::Result<bool> {
+    let mut fd = libc::pollfd {
+        fd: nic.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // rounded up, the timers aren't due before
+    let timeout = timeout.map_or(-1, |wait| {
+        wait.as_micros().div_ceil(1000).min(i32::MAX as u128) as libc::c_int
+    });
+    let n = unsafe { libc::poll(&mut fd, 1, timeout) };
+    if n < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == ErrorKind::Interrupted {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+    Ok(n > 0 && fd.revents & libc::POLLIN != 0)
+}
+
+/// Takes a token of the bucket `limit` refilling at `rate` per second, none when the rate is 0. Returns
+/// whether there was one.
+fn take_token(limit: &mut Option<TokenBucket>, rate: u64, burst: u64) -> bool {
+    if rate == 0 {
+        return true;
+    }
+    let limit = limit.get_or_insert_with(|| TokenBucket::new(rate, burst));
+    // the tunable changed since
+    if limit.rate() != rate {
+        *limit = TokenBucket::new(rate, burst);
+    }
+    limit.refill(Instant::now());
+    if !limit.can_send(1) {
+        return false;
+    }
+    limit.on_send(1);
+    true
+}
+
+/// Whether the peer of `id` has MD5 keys or TCP-AO MKTs, its handshakes never going through cookies:
+/// the connection created from the final ACK wouldn't have them.
+fn has_keys(tunables: &Tunables, id: &ConnectionID) -> bool {
+    tunables.md5_keys.get(id.src_addr).is_some() || !tunables.ao_keys.get(id.src_addr).is_empty()
+}
+
+fn not_found(id: &ConnectionID) -> io::Error {
+    io::Error::new(ErrorKind::NotFound, format!("no connection: {id:?}"))
+}
+
+/// What happens to a segment failing a handshake, https://www.ietf.org/rfc/rfc793.txt pages 65 and 69:
+/// an unacceptable ACK is answered with a RST, the rest dropped.
+fn on_handshake_error(e: &TcpError, nic: &dyn NetworkDevice, seg: &SegmentView, ip: IpParams) {
+    match e {
+        TcpError::UnexpectedAck | TcpError::UnacceptableAck => {
+            log::debug!("{:?} reset: {e:}", seg.id());
+            if let Err(e) = send_reset(nic, seg, ip) {
+                log::error!("error: {e:}");
+            }
+        }
+        // a RST in LISTEN, or a segment that isn't a SYN without an ACK either
+        TcpError::NoSyn | TcpError::NoAck | TcpError::OutOfWindow | TcpError::RstReceived => {
+            log::debug!("{:?} dropped: {e:}", seg.id())
+        }
+        // counted along with the signatures rejected
+        TcpError::BadSignature(_) => log::info!("{:?} dropped: {e:}", seg.id()),
+        _ => log::error!("error: {e:}"),
+    }
+}
+
+/// Drops the segment with the flag anomaly, answering it with a RST when the policy says so.
+fn handle_anomaly(
+    anomaly: FlagAnomaly,
+    nic: &dyn NetworkDevice,
+    seg: &SegmentView,
+    policy: AnomalyPolicy,
+    ip: IpParams,
+    stats: &mut Stats,
+) {
+    match anomaly {
+        FlagAnomaly::Null => stats.anomalies_null += 1,
+        FlagAnomaly::Xmas => stats.anomalies_xmas += 1,
+        FlagAnomaly::SynFin => stats.anomalies_syn_fin += 1,
+        FlagAnomaly::SynRst => stats.anomalies_syn_rst += 1,
+    }
+    match policy {
+        AnomalyPolicy::Drop => {}
+        AnomalyPolicy::LogAndDrop => {
+            log::info!(
+                "dropped segment from {:?} with flag anomaly {anomaly:?}",
+                seg.id()
+            );
+        }
+        AnomalyPolicy::Reset => {
+            if seg.tcp.rst() {
+                return;
+            }
+            match send_reset(nic, seg, ip) {
+                Ok(_) => stats.anomaly_resets += 1,
+                Err(e) => log::error!("error: {e:}"),
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConnectionWrapper {
+    SynSent(Connection<SynSent>),
+    SynRecv(Connection<SynRecv>),
+    Established(Connection<Established>),
+}
+
+impl ConnectionWrapper {
+    pub fn state_name(&self) -> &'static str {
+        match self {
+            ConnectionWrapper::SynSent(_) => "SYN-SENT",
+            ConnectionWrapper::SynRecv(_) => "SYN-RECEIVED",
+            ConnectionWrapper::Established(conn) => conn.close_state().name(),
+        }
+    }
+
+    pub fn fingerprint(&self) -> Option<&Fingerprint> {
+        match self {
+            ConnectionWrapper::SynSent(conn) => conn.fingerprint(),
+            ConnectionWrapper::SynRecv(conn) => conn.fingerprint(),
+            ConnectionWrapper::Established(conn) => conn.fingerprint(),
+        }
+    }
+
+    pub fn check_invariants(&self) -> Result<()> {
+        match self {
+            ConnectionWrapper::SynSent(conn) => conn.check_invariants(),
+            ConnectionWrapper::SynRecv(conn) => conn.check_invariants(),
+            ConnectionWrapper::Established(conn) => conn.check_invariants(),
+        }
+    }
+
+    pub fn diagnose(&self) -> Diagnosis {
+        match self {
+            ConnectionWrapper::SynSent(conn) => conn.diagnose(),
+            ConnectionWrapper::SynRecv(conn) => conn.diagnose(),
+            ConnectionWrapper::Established(conn) => conn.diagnose(),
+        }
+    }
+
+    pub fn reset(self, nic: &dyn NetworkDevice) -> Result<()> {
+        match self {
+            ConnectionWrapper::SynSent(conn) => conn.reset(nic),
+            ConnectionWrapper::SynRecv(conn) => conn.reset(nic),
+            ConnectionWrapper::Established(conn) => conn.reset(nic),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::core::checksum;
+    use crate::ctl::{Command, Reply};
+    use crate::device::{ChecksumOffload, Interfaces, LoopbackDevice, MemoryDevice, NetworkDevice};
+    use crate::ip::fragment::Fragments;
+    use crate::ip::icmp::{ICMP_BURST, ICMP_PROTOCOL, UDP_PROTOCOL};
+    use crate::ip::options::{IpOptions, IpOptionsPolicy, IPOPT_LSRR};
+    use crate::ip::route::Route;
+    use crate::ip::{DSCP_AF41, DSCP_EF};
+    use crate::stack::{Stack, TcpStack};
+    use crate::tcp::close::CloseState;
+    use crate::tcp::handshake::active::SYN_RETRIES;
+    use crate::tcp::handshake::cookie::{self, SynCookies};
+    use crate::tcp::listener::OverflowPolicy;
+    use crate::tcp::queue::{Admission, Backlog};
+    use crate::tcp::sockopt::{OptionName, SocketOption};
+    use crate::tcp::ConnectionID;
+    use crate::tcp::Tunables;
+    use crate::wire::SegmentView;
+    use crate::TCP_PROTOCOL;
+    use etherparse::{Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpOptionElement};
+    use std::io::ErrorKind;
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    fn packet(tcp: TcpHeader) -> Vec<u8> {
+        segment(tcp, &[])
+    }
+
+    fn segment(mut tcp: TcpHeader, payload: &[u8]) -> Vec<u8> {
+        let ip = Ipv4Header::new(
+            tcp.header_len() + payload.len() as u16,
+            64,
+            TCP_PROTOCOL,
+            [192, 167, 1, 2],
+            [192, 167, 1, 1],
+        );
+        tcp.checksum = tcp.calc_checksum_ipv4(&ip, payload).unwrap();
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    /// Completes the handshake opened by `syn`, returns the connection accepted and the SYN-ACK.
+    fn handshake(stack: &mut Stack, nic: &MemoryDevice, syn: TcpHeader) -> (ConnectionID, Vec<u8>) {
+        stack.on_packet(nic, &packet(syn.clone()));
+        let syn_ack = nic.take_sent().remove(0);
+        let iss = SegmentView::parse(&syn_ack).unwrap().tcp.sequence_number();
+        let seq = syn.sequence_number.wrapping_add(1);
+        let mut ack = TcpHeader::new(syn.source_port, syn.destination_port, seq, syn.window_size);
+        ack.ack = true;
+        ack.acknowledgment_number = iss.wrapping_add(1);
+        stack.on_packet(nic, &packet(ack));
+        (stack.accept(syn.destination_port).unwrap(), syn_ack)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        stack.on_packet(&nic, &packet(syn));
+
+        let sent = nic.take_sent();
+        assert_eq!(sent.len(), 1);
+        let syn_ack = SegmentView::parse(&sent[0]).unwrap();
+        assert!(syn_ack.tcp.syn() && syn_ack.tcp.ack());
+        assert_eq!(syn_ack.tcp.acknowledgment_number(), 101);
+        assert_eq!(stack.accept(80), None);
+
+        let mut ack = TcpHeader::new(40000, 80, 101, 1000);
+        ack.ack = true;
+        ack.acknowledgment_number = syn_ack.tcp.sequence_number().wrapping_add(1);
+        stack.on_packet(&nic, &packet(ack));
+        let id = stack.accept(80).unwrap();
+        assert_eq!((id.src_port, id.dst_port), (40000, 80));
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        let mut packet = packet(syn);
+        // the sequence number
+        packet[27] ^= 1;
+        stack.on_packet(&nic, &packet);
+        assert!(nic.take_sent().is_empty());
+        assert_eq!(stack.stats().checksum_errors, 1);
+        assert_eq!(stack.stats().packets_dropped, 1);
+    }
+
+    #[test]
+    fn test_checksum_offload() {
+        let offload = ChecksumOffload { rx: true, tx: true };
+        let nic = MemoryDevice::new().offloading(offload);
+        let mut stack = Stack::default();
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        let mut corrupted = packet(syn.clone());
+        // the tcp checksum, the device having checked it
+        corrupted[36] ^= 1;
+        stack.on_packet(&nic, &corrupted);
+        assert_eq!(stack.stats().checksum_errors, 0);
+
+        // the device completes the sum of the pseudo header
+        let syn_ack = nic.take_sent().remove(0);
+        let seg = SegmentView::parse(&syn_ack).unwrap();
+        assert!(seg.tcp.syn() && seg.tcp.ack());
+        let len = seg.tcp.slice().len() as u16;
+        let pseudo = checksum::pseudo_header([192, 167, 1, 1], [192, 167, 1, 2], TCP_PROTOCOL, len);
+        assert_eq!(seg.tcp.checksum(), checksum::fold(pseudo));
+        assert!(seg.verify_checksums().is_err());
+
+        // the ip header is still checked
+        let mut corrupted = packet(syn);
+        corrupted[8] ^= 1;
+        stack.on_packet(&nic, &corrupted);
+        assert_eq!(stack.stats().checksum_errors, 1);
+
+        // without offload, the checksums are complete
+        let nic = MemoryDevice::new().offloading(ChecksumOffload {
+            rx: true,
+            tx: false,
+        });
+        let mut syn = TcpHeader::new(40001, 80, 100, 1000);
+        syn.syn = true;
+        stack.on_packet(&nic, &packet(syn));
+        let syn_ack = nic.take_sent().remove(0);
+        assert!(SegmentView::parse(&syn_ack)
+            .unwrap()
+            .verify_checksums()
+            .is_ok());
+    }
+
+    /// The connection from port `port` of the peer to port 80.
+    fn peer(port: u16) -> ConnectionID {
+        ConnectionID {
+            src_addr: Ipv4Addr::new(192, 167, 1, 2),
+            src_port: port,
+            dst_addr: Ipv4Addr::new(192, 167, 1, 1),
+            dst_port: 80,
+        }
+    }
+
+    #[test]
+    fn test_syn_cookies() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::new(Tunables {
+            syn_cookies: SynCookies::Always,
+            mss: 1460,
+            ..Tunables::default()
+        });
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        syn.set_options(&[
+            TcpOptionElement::MaximumSegmentSize(1400),
+            TcpOptionElement::WindowScale(7),
+            TcpOptionElement::SelectiveAcknowledgementPermitted,
+        ])
+        .unwrap();
+
+        // answered without a connection, nor the options a cookie doesn't keep
+        stack.on_packet(&nic, &packet(syn));
+        let id = peer(40000);
+        assert!(!stack.contains(&id));
+        let sent = nic.take_sent();
+        let seg = SegmentView::parse(&sent[0]).unwrap();
+        assert!(seg.tcp.syn() && seg.tcp.ack());
+        assert_eq!((seg.window_scale(), seg.sack_permitted()), (None, false));
+        let syn_ack = seg.tcp.sequence_number();
+        assert_eq!(stack.stats().syn_cookies_sent, 1);
+        assert_eq!(cookie::decode(&id, 100, syn_ack), Some(1400));
+
+        // a forged ACK is answered with a RST
+        let mut forged = TcpHeader::new(40001, 80, 101, 1000);
+        forged.ack = true;
+        forged.acknowledgment_number = syn_ack.wrapping_add(1);
+        stack.on_packet(&nic, &packet(forged));
+        assert_eq!(stack.stats().syn_cookies_rejected, 1);
+        assert!(SegmentView::parse(&nic.take_sent()[0]).unwrap().tcp.rst());
+
+        // the final ACK creates the connection, the data it carries read
+        let mut ack = TcpHeader::new(40000, 80, 101, 1000);
+        ack.ack = true;
+        ack.acknowledgment_number = syn_ack.wrapping_add(1);
+        stack.on_packet(&nic, &segment(ack, b"hello"));
+        assert_eq!(stack.accept(80), Some(id.clone()));
+        assert_eq!(stack.stats().syn_cookies_accepted, 1);
+        assert_eq!(stack.stats().connections_established, 1);
+        let mut buf = [0u8; 16];
+        assert_eq!(stack.try_read(&nic, &id, &mut buf).unwrap(), 5);
+        assert_eq!(stack.info(&id).unwrap().mss, 1400);
+    }
+
+    #[test]
+    fn test_syn_cookies_on_overflow() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::new(Tunables {
+            syn_cookies: SynCookies::Overflow,
+            ..Tunables::default()
+        });
+        stack.bind(80).unwrap();
+        stack.set_backlog(80, 2).unwrap();
+        for port in 40000..40004 {
+            let mut syn = TcpHeader::new(port, 80, 100, 1000);
+            syn.syn = true;
+            stack.on_packet(&nic, &packet(syn));
+        }
+        // the handshakes in progress up to the backlog, cookies beyond
+        assert!(stack.contains(&peer(40001)) && !stack.contains(&peer(40002)));
+        assert_eq!(stack.stats().syn_cookies_sent, 2);
+        assert_eq!(nic.take_sent().len(), 4);
+    }
+
+    #[test]
+    fn test_challenge_ack_limit() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::new(Tunables {
+            challenge_ack_limit: 2,
+            ..Tunables::default()
+        });
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        let (id, _) = handshake(&mut stack, &nic, syn);
+        nic.take_sent();
+
+        // blind RSTs in the window, the challenge ACKs limited to 2 a second
+        for _ in 0..3 {
+            let mut rst = TcpHeader::new(40000, 80, 150, 1000);
+            rst.rst = true;
+            stack.on_packet(&nic, &packet(rst));
+        }
+        let sent = nic.take_sent();
+        assert_eq!(sent.len(), 2);
+        let ack = SegmentView::parse(&sent[0]).unwrap();
+        assert!(ack.tcp.ack() && !ack.tcp.rst());
+        assert_eq!(ack.tcp.acknowledgment_number(), 101);
+        assert_eq!(
+            (
+                stack.stats().challenge_acks,
+                stack.stats().challenge_acks_limited
+            ),
+            (2, 1)
+        );
+        assert!(stack.established_mut(&id).is_some());
+
+        // the RST at RCV.NXT resets it
+        let mut rst = TcpHeader::new(40000, 80, 101, 1000);
+        rst.rst = true;
+        stack.on_packet(&nic, &packet(rst));
+        assert!(!stack.contains(&id));
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::new(Tunables {
+            max_connections: 2,
+            ..Tunables::default()
+        });
+        stack.bind(80).unwrap();
+        let syn = |port| {
+            let mut syn = TcpHeader::new(port, 80, 100, 1000);
+            syn.syn = true;
+            syn
+        };
+        let (established, _) = handshake(&mut stack, &nic, syn(40000));
+        stack.on_packet(&nic, &packet(syn(40001)));
+        nic.take_sent();
+
+        // the oldest handshake in progress makes room, the established connections stay
+        stack.on_packet(&nic, &packet(syn(40002)));
+        assert!(stack.contains(&established) && stack.contains(&peer(40002)));
+        assert!(!stack.contains(&peer(40001)));
+        assert_eq!(stack.stats().half_open_evicted, 1);
+        let sent = nic.take_sent();
+        let syn_ack = SegmentView::parse(&sent[0]).unwrap();
+        assert!(syn_ack.tcp.syn() && syn_ack.tcp.ack());
+
+        // without evicting, the SYN is refused, with a RST if so configured
+        let tunables = stack.tunables_mut();
+        tunables.evict_half_open = false;
+        tunables.connections_overflow = OverflowPolicy::Reset;
+        stack.on_packet(&nic, &packet(syn(40003)));
+        assert!(!stack.contains(&peer(40003)));
+        assert_eq!(stack.stats().connections_overflows, 1);
+        assert!(SegmentView::parse(&nic.take_sent()[0]).unwrap().tcp.rst());
+
+        // nor is there room with only established connections
+        stack.tunables_mut().evict_half_open = true;
+        stack.tunables_mut().max_connections = 1;
+        stack.on_packet(&nic, &packet(syn(40004)));
+        assert!(stack.contains(&established) && !stack.contains(&peer(40004)));
+        assert_eq!(stack.stats().half_open_evicted, 2);
+        assert_eq!(stack.stats().connections_overflows, 2);
+    }
+
+    #[test]
+    fn test_time_wait_reopened() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        let (id, syn_ack) = handshake(&mut stack, &nic, syn);
+        let iss = SegmentView::parse(&syn_ack).unwrap().tcp.sequence_number();
+
+        // our FIN goes first, the peer's follows its ACK
+        stack.close(&nic, &id).unwrap();
+        nic.take_sent();
+        let mut fin = TcpHeader::new(40000, 80, 101, 1000);
+        fin.ack = true;
+        fin.fin = true;
+        fin.acknowledgment_number = iss.wrapping_add(2);
+        stack.on_packet(&nic, &packet(fin));
+        nic.take_sent();
+        let state = stack.established_mut(&id).unwrap().close_state();
+        assert_eq!(state, CloseState::TimeWait);
+
+        // an old duplicate SYN is dropped, TIME-WAIT goes on
+        let mut syn = TcpHeader::new(40000, 80, 50, 1000);
+        syn.syn = true;
+        stack.on_packet(&nic, &packet(syn.clone()));
+        assert!(nic.take_sent().is_empty());
+        let state = stack.established_mut(&id).unwrap().close_state();
+        assert_eq!(state, CloseState::TimeWait);
+
+        // beyond what the previous incarnation received, it opens a new one
+        syn.sequence_number = 5000;
+        stack.on_packet(&nic, &packet(syn));
+        let sent = nic.take_sent();
+        let syn_ack = SegmentView::parse(&sent[0]).unwrap();
+        assert!(syn_ack.tcp.syn() && syn_ack.tcp.ack());
+        assert_eq!(syn_ack.tcp.acknowledgment_number(), 5001);
+        assert!(stack.syn_recv_mut(&id).is_some());
+        assert_eq!(stack.stats().time_wait_reopened, 1);
+        assert_eq!(stack.stats().connections_alive(), 1);
+    }
+
+    /// Opens a connection to port 80 of the peer, returns it and the SYN sent.
+    fn connect(stack: &mut Stack, nic: &MemoryDevice) -> (ConnectionID, Vec<u8>) {
+        stack.set_address(Ipv4Addr::new(192, 167, 1, 1));
+        let id = stack
+            .connect(nic, None, "192.167.1.2:80".parse().unwrap())
+            .unwrap();
+        (id, nic.take_sent().remove(0))
+    }
+
+    #[test]
+    fn test_connect() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        let remote = "192.167.1.2:80".parse().unwrap();
+        let err = stack.connect(&nic, None, remote).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrNotAvailable);
+
+        let (id, syn) = connect(&mut stack, &nic);
+        let syn = SegmentView::parse(&syn).unwrap();
+        assert!(syn.tcp.syn() && !syn.tcp.ack());
+        assert_eq!(syn.tcp.destination_port(), 80);
+        assert_eq!(syn.tcp.source_port(), id.dst_port);
+        assert!(syn.mss().is_some() && syn.sack_permitted());
+        assert!(syn.window_scale().is_some() && syn.timestamps().is_some());
+        assert_eq!(stack.stats().active_opens, 1);
+        let err = stack.try_write(&nic, &id, b"hello").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert!(!stack.readiness(&id).writable);
+
+        // a second connection to the peer takes another port
+        let Reply::Connected(other) = stack.handle_command(Command::Connect(remote), &nic) else {
+            panic!("expect the connection opened");
+        };
+        assert_ne!(other.dst_port, id.dst_port);
+        let Reply::Connections(conns) = stack.handle_command(Command::Query(other), &nic) else {
+            panic!("expect the connection");
+        };
+        assert_eq!(conns[0].state, "SYN-SENT");
+        nic.take_sent();
+
+        // <SEQ=300><ACK=ISS+1><CTL=SYN,ACK>
+        let iss = syn.tcp.sequence_number();
+        let mut syn_ack = TcpHeader::new(80, id.dst_port, 300, 1000);
+        syn_ack.syn = true;
+        syn_ack.ack = true;
+        syn_ack.acknowledgment_number = iss.wrapping_add(1);
+        stack.on_packet(&nic, &packet(syn_ack));
+        let sent = nic.take_sent();
+        let ack = SegmentView::parse(&sent[0]).unwrap();
+        assert!(ack.tcp.ack() && !ack.tcp.syn());
+        assert_eq!(ack.tcp.sequence_number(), iss.wrapping_add(1));
+        assert_eq!(ack.tcp.acknowledgment_number(), 301);
+        assert!(stack.established_mut(&id).is_some());
+        assert!(stack.readiness(&id).writable);
+        assert_eq!(stack.stats().connections_established, 1);
+        assert_eq!(stack.try_write(&nic, &id, b"hello").unwrap(), 5);
+        let data = nic.take_sent().remove(0);
+        assert_eq!(SegmentView::parse(&data).unwrap().payload, b"hello");
+    }
+
+    #[test]
+    fn test_connect_refused() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        let (id, syn) = connect(&mut stack, &nic);
+        let iss = SegmentView::parse(&syn).unwrap().tcp.sequence_number();
+
+        // an ACK of something never sent is reset, <SEQ=SEG.ACK><CTL=RST>
+        let mut ack = TcpHeader::new(80, id.dst_port, 300, 1000);
+        ack.ack = true;
+        ack.acknowledgment_number = iss.wrapping_add(100);
+        stack.on_packet(&nic, &packet(ack.clone()));
+        let rst = nic.take_sent().remove(0);
+        let rst = SegmentView::parse(&rst).unwrap();
+        assert!(rst.tcp.rst());
+        assert_eq!(rst.tcp.sequence_number(), iss.wrapping_add(100));
+        // a RST of it is dropped
+        ack.rst = true;
+        stack.on_packet(&nic, &packet(ack.clone()));
+        assert!(nic.take_sent().is_empty());
+        assert!(stack.contains(&id));
+
+        // a RST acknowledging the SYN refuses the connection
+        ack.acknowledgment_number = iss.wrapping_add(1);
+        stack.on_packet(&nic, &packet(ack));
+        assert!(!stack.contains(&id));
+        assert_eq!(stack.stats().connections_alive(), 0);
+        let err = stack.try_read(&nic, &id, &mut [0; 8]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn test_connect_timed_out() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        let (id, syn) = connect(&mut stack, &nic);
+        let iss = SegmentView::parse(&syn).unwrap().tcp.sequence_number();
+
+        let mut now = Instant::now();
+        for _ in 0..SYN_RETRIES {
+            now += Duration::from_secs(1000);
+            stack.on_timers(&nic, now);
+            let syn = nic.take_sent().remove(0);
+            let syn = SegmentView::parse(&syn).unwrap();
+            assert!(syn.tcp.syn() && syn.tcp.sequence_number() == iss);
+        }
+        assert_eq!(stack.stats().retransmissions, SYN_RETRIES as u64);
+        now += Duration::from_secs(1000);
+        stack.on_timers(&nic, now);
+        assert!(nic.take_sent().is_empty());
+        assert!(!stack.contains(&id));
+        let err = stack.try_read(&nic, &id, &mut [0; 8]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_connect_time_wait() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        stack.set_address(Ipv4Addr::new(192, 167, 1, 1));
+        stack.bind_local(5000).unwrap();
+        let remote = "192.167.1.2:80".parse().unwrap();
+        let id = stack.connect(&nic, Some(5000), remote).unwrap();
+        let syn = nic.take_sent().remove(0);
+        let iss = SegmentView::parse(&syn).unwrap().tcp.sequence_number();
+        let mut syn_ack = TcpHeader::new(80, 5000, 300, 1000);
+        syn_ack.syn = true;
+        syn_ack.ack = true;
+        syn_ack.acknowledgment_number = iss.wrapping_add(1);
+        stack.on_packet(&nic, &packet(syn_ack));
+        let err = stack.connect(&nic, Some(5000), remote).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+
+        // our FIN goes first, the peer's acknowledges it
+        stack.close(&nic, &id).unwrap();
+        let mut fin = TcpHeader::new(80, 5000, 301, 1000);
+        fin.ack = true;
+        fin.fin = true;
+        fin.acknowledgment_number = iss.wrapping_add(2);
+        stack.on_packet(&nic, &packet(fin));
+        let state = stack.established_mut(&id).unwrap().close_state();
+        assert_eq!(state, CloseState::TimeWait);
+
+        // killed in TIME-WAIT, the port is still held with the peer
+        stack.handle_command(Command::Kill(id.clone()), &nic);
+        assert!(!stack.contains(&id));
+        let err = stack.connect(&nic, Some(5000), remote).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        let other = "192.167.1.3:80".parse().unwrap();
+        assert!(stack.connect(&nic, Some(5000), other).is_ok());
+    }
+
+    /// An ICMP error from the peer about `quoted`, a segment sent.
+    fn icmp(icmp_type: u8, code: u8, quoted: &[u8]) -> Vec<u8> {
+        let mut message = vec![icmp_type, code, 0, 0, 0, 0, 0, 0];
+        message.extend_from_slice(&quoted[..28]);
+        let sum = !checksum::fold(checksum::sum(&message, 0));
+        message[2..4].copy_from_slice(&sum.to_be_bytes());
+        let ip = Ipv4Header::new(
+            message.len() as u16,
+            64,
+            ICMP_PROTOCOL,
+            [192, 167, 1, 2],
+            [192, 167, 1, 1],
+        );
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        packet.extend_from_slice(&message);
+        packet
+    }
+
+    #[test]
+    fn test_icmp_errors() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        let (id, syn_ack) = handshake(&mut stack, &nic, syn);
+        stack.try_write(&nic, &id, b"hello").unwrap();
+        let data = nic.take_sent().remove(0);
+
+        // the SYN-ACK is acknowledged already
+        stack.on_packet(&nic, &icmp(3, 3, &syn_ack));
+        assert_eq!(stack.stats().icmp_errors_rejected, 1);
+        assert!(stack.contains(&id));
+
+        // time exceeded is soft
+        stack.on_packet(&nic, &icmp(11, 0, &data));
+        assert_eq!(stack.info(&id).unwrap().icmp_error, Some((11, 0)));
+        assert!(stack.contains(&id));
+
+        stack.on_packet(&nic, &icmp(3, 3, &data));
+        assert!(!stack.contains(&id));
+        assert_eq!(stack.stats().icmp_errors_received, 2);
+        assert_eq!(stack.stats().icmp_aborts, 1);
+        let err = stack.try_read(&nic, &id, &mut [0; 8]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+        let err = stack.try_read(&nic, &id, &mut [0; 8]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_interfaces() {
+        let mut interfaces = Interfaces::new();
+        interfaces.add("lan0", MemoryDevice::new(), Ipv4Addr::new(10, 0, 0, 1), 24);
+        let wan = interfaces.add(
+            "wan0",
+            MemoryDevice::new(),
+            Ipv4Addr::new(192, 167, 1, 1),
+            32,
+        );
+        interfaces.add_route(Route::default_route(wan)).unwrap();
+        let mut stack = Stack::default();
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        // from 192.167.1.2, off the subnets, through the default route
+        stack.on_packet(&interfaces, &packet(syn));
+        let device = |index: usize| interfaces.interface(index).unwrap().device();
+        assert!(device(0).take_sent().is_empty());
+        let sent = device(wan).take_sent();
+        let syn_ack = SegmentView::parse(&sent[0]).unwrap();
+        assert!(syn_ack.tcp.syn() && syn_ack.tcp.ack());
+        assert_eq!(syn_ack.ip.source_addr(), Ipv4Addr::new(192, 167, 1, 1));
+
+        // the source address of the route
+        let remote = "8.8.8.8:80".parse().unwrap();
+        let id = stack.connect(&interfaces, None, remote).unwrap();
+        assert_eq!(id.dst_addr, Ipv4Addr::new(192, 167, 1, 1));
+        assert!(device(0).take_sent().is_empty());
+        let sent = device(wan).take_sent();
+        let syn = SegmentView::parse(&sent[0]).unwrap();
+        assert!(syn.tcp.syn() && !syn.tcp.ack());
+        assert_eq!(syn.ip.source_addr(), Ipv4Addr::new(192, 167, 1, 1));
+        assert_eq!(syn.ip.destination_addr(), Ipv4Addr::new(8, 8, 8, 8));
+    }
+
+    #[test]
+    fn test_unhandled_protocol() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        let ip = Ipv4Header::new(8, 64, UDP_PROTOCOL, [192, 167, 1, 2], [192, 167, 1, 1]);
+        let mut udp = vec![];
+        ip.write(&mut udp).unwrap();
+        udp.extend_from_slice(&[0x9c, 0x40, 0, 53, 0, 8, 0, 0]);
+        for _ in 0..=ICMP_BURST {
+            assert_eq!(stack.on_packet(&nic, &udp), None);
+        }
+        let sent = nic.take_sent();
+        assert_eq!(sent.len(), ICMP_BURST as usize);
+        let reply = Ipv4HeaderSlice::from_slice(&sent[0]).unwrap();
+        assert_eq!(reply.protocol(), ICMP_PROTOCOL);
+        assert_eq!(reply.destination(), [192, 167, 1, 2]);
+        assert_eq!(&sent[0][28..], &udp[..]);
+        assert_eq!(stack.stats().icmp_unreachable_sent, ICMP_BURST);
+        assert_eq!(stack.stats().icmp_rate_limited, 1);
+        assert_eq!(stack.stats().packets_dropped, ICMP_BURST + 1);
+
+        let tunables = Tunables {
+            icmp_unreachable: false,
+            ..Tunables::default()
+        };
+        let mut stack = Stack::new(tunables);
+        stack.on_packet(&nic, &udp);
+        assert!(nic.take_sent().is_empty());
+    }
+
+    #[test]
+    fn test_truncated_packet() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        let packet = segment(syn, &[7; 100]);
+        stack.on_packet(&nic, &packet[..packet.len() - 1]);
+        assert!(nic.take_sent().is_empty());
+        assert_eq!(stack.stats().truncated_packets, 1);
+        assert_eq!(stack.stats().checksum_errors, 0);
+    }
+
+    #[test]
+    fn test_fragmented_segment() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        let packet = segment(syn, &[7; 24]);
+        let (header, data) = packet.split_at(20);
+        let fragment = |offset: usize, data: &[u8], more: bool| {
+            let mut ip = Ipv4HeaderSlice::from_slice(header).unwrap().to_header();
+            ip.payload_len = data.len() as u16;
+            ip.fragments_offset = (offset / 8) as u16;
+            ip.more_fragments = more;
+            let mut fragment = vec![];
+            ip.write(&mut fragment).unwrap();
+            fragment.extend_from_slice(data);
+            fragment
+        };
+        stack.on_packet(&nic, &fragment(16, &data[16..], false));
+        assert!(nic.take_sent().is_empty());
+        stack.on_packet(&nic, &fragment(0, &data[..16], true));
+        let sent = nic.take_sent();
+        let syn_ack = SegmentView::parse(&sent[0]).unwrap();
+        assert!(syn_ack.tcp.syn() && syn_ack.tcp.ack());
+        assert_eq!(stack.stats().fragments_received, 2);
+        assert_eq!(stack.stats().datagrams_reassembled, 1);
+
+        // the rest never arrives
+        stack.on_packet(&nic, &fragment(0, &data[..16], true));
+        let deadline = stack.next_deadline().unwrap();
+        stack.on_timers(&nic, deadline);
+        assert_eq!(stack.stats().reassembly_failures, 1);
+        assert_eq!(stack.next_deadline(), None);
+    }
+
+    #[test]
+    fn test_fragmented_send() {
+        let nic = MemoryDevice::with_mtu(576);
+        let mut stack = Stack::new(Tunables {
+            mss: 1460,
+            ip_dont_fragment: false,
+            ..Tunables::default()
+        });
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 8192);
+        syn.syn = true;
+        // the segments exceed the mtu of the nic
+        syn.set_options(&[TcpOptionElement::MaximumSegmentSize(1460)])
+            .unwrap();
+        let (id, syn_ack) = handshake(&mut stack, &nic, syn);
+        assert!(!SegmentView::parse(&syn_ack).unwrap().ip.dont_fragment());
+
+        stack.established_mut(&id).unwrap().write(&[7; 1000]);
+        stack.transmit(&nic, &id).unwrap();
+        let sent = nic.take_sent();
+        assert_eq!(sent.len(), 2);
+        assert!(sent.iter().all(|fragment| fragment.len() <= 576));
+        let mut fragments = Fragments::default();
+        let now = Instant::now();
+        let reassembled = sent
+            .iter()
+            .filter_map(|packet| {
+                let ip = Ipv4HeaderSlice::from_slice(packet).unwrap();
+                fragments.on_fragment(&ip, packet, now, usize::MAX).unwrap()
+            })
+            .next()
+            .unwrap();
+        let seg = SegmentView::parse(&reassembled).unwrap();
+        assert_eq!(seg.payload, [7; 1000]);
+        assert!(seg.verify_checksums().is_ok());
+    }
+
+    #[test]
+    fn test_ttl() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::new(Tunables {
+            ttl: 32,
+            ..Tunables::default()
+        });
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 81, 100, 1000);
+        syn.syn = true;
+        stack.on_packet(&nic, &packet(syn.clone()));
+        let sent = nic.take_sent();
+        let rst = SegmentView::parse(&sent[0]).unwrap();
+        assert!(rst.tcp.rst());
+        assert_eq!(rst.ip.ttl(), 32);
+
+        syn.destination_port = 80;
+        let (id, syn_ack) = handshake(&mut stack, &nic, syn);
+        assert_eq!(SegmentView::parse(&syn_ack).unwrap().ip.ttl(), 32);
+        assert_eq!(
+            stack.option(&id, OptionName::Ttl).unwrap(),
+            SocketOption::Ttl(32)
+        );
+        assert!(stack.set_option(&nic, &id, SocketOption::Ttl(0)).is_err());
+        stack.set_option(&nic, &id, SocketOption::Ttl(5)).unwrap();
+        stack.established_mut(&id).unwrap().write(b"hi");
+        stack.transmit(&nic, &id).unwrap();
+        let sent = nic.take_sent();
+        assert_eq!(SegmentView::parse(&sent[0]).unwrap().ip.ttl(), 5);
+    }
+
+    #[test]
+    fn test_dscp() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        let (id, _) = handshake(&mut stack, &nic, syn);
+        assert_eq!(stack.info(&id).unwrap().received_tos, Some(0));
+        assert!(stack.set_option(&nic, &id, SocketOption::Dscp(64)).is_err());
+        stack
+            .set_option(&nic, &id, SocketOption::Dscp(DSCP_EF))
+            .unwrap();
+        stack.established_mut(&id).unwrap().write(b"hi");
+        stack.transmit(&nic, &id).unwrap();
+        let sent = nic.take_sent();
+        let seg = SegmentView::parse(&sent[0]).unwrap();
+        assert_eq!((seg.ip.dcp(), seg.ip.ecn()), (DSCP_EF, 0));
+
+        let mut ack = TcpHeader::new(40000, 80, 101, 1000);
+        ack.ack = true;
+        ack.acknowledgment_number = seg.tcp.sequence_number().wrapping_add(2);
+        let mut packet = packet(ack);
+        // AF41, ECT(0), the header checksum updated
+        packet[1] = DSCP_AF41 << 2 | 2;
+        packet[10..12].copy_from_slice(&[0, 0]);
+        let checksum = !checksum::fold(checksum::sum(&packet[..20], 0));
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        stack.on_packet(&nic, &packet);
+        assert_eq!(
+            stack.info(&id).unwrap().received_tos,
+            Some(DSCP_AF41 << 2 | 2)
+        );
+    }
+
+    #[test]
+    fn test_ip_options_policy() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::new(Tunables {
+            ip_options_policy: IpOptionsPolicy::RejectSourceRoute,
+            ..Tunables::default()
+        });
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        let mut ip = Ipv4Header::new(
+            syn.header_len(),
+            64,
+            TCP_PROTOCOL,
+            [192, 167, 1, 2],
+            [192, 167, 1, 1],
+        );
+        ip.set_options(&[IPOPT_LSRR, 7, 4, 10, 0, 0, 1, 0]).unwrap();
+        syn.checksum = syn.calc_checksum_ipv4(&ip, &[]).unwrap();
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        syn.write(&mut packet).unwrap();
+        stack.on_packet(&nic, &packet);
+        assert!(nic.take_sent().is_empty());
+        assert_eq!(stack.stats().ip_options_rejected, 1);
+        assert_eq!(stack.stats().packets_dropped, 1);
+
+        // the segments of a connection carry the ip options set on it
+        let (id, _) = handshake(&mut stack, &nic, syn);
+        let alert = IpOptions::router_alert();
+        stack
+            .set_option(&nic, &id, SocketOption::IpOptions(alert))
+            .unwrap();
+        stack.established_mut(&id).unwrap().write(b"hi");
+        stack.transmit(&nic, &id).unwrap();
+        let sent = nic.take_sent();
+        let seg = SegmentView::parse(&sent[0]).unwrap();
+        assert_eq!(seg.ip.options(), alert.as_bytes());
+        assert_eq!(seg.payload, b"hi");
+        assert!(seg.verify_checksums().is_ok());
+    }
+
+    #[test]
+    fn test_loopback() {
+        let (device, peer) = LoopbackDevice::pair();
+        let mut stack = TcpStack::on_device(device, Tunables::default());
+        stack.listen(80).unwrap();
+        let wait = Some(Duration::from_millis(10));
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        peer.send(&packet(syn)).unwrap();
+        stack.poll(wait).unwrap();
+
+        let mut buf = [0u8; 1500];
+        assert!(peer.wait_readable(wait).unwrap());
+        let n = peer.recv(&mut buf).unwrap();
+        let syn_ack = SegmentView::parse(&buf[..n]).unwrap();
+        assert!(syn_ack.tcp.syn() && syn_ack.tcp.ack());
+        let mut ack = TcpHeader::new(40000, 80, 101, 1000);
+        ack.ack = true;
+        ack.acknowledgment_number = syn_ack.tcp.sequence_number().wrapping_add(1);
+        peer.send(&packet(ack.clone())).unwrap();
+        stack.poll(wait).unwrap();
+        let id = stack.accept(80).unwrap();
+
+        peer.send(&segment(ack, b"hello")).unwrap();
+        let ready = stack.poll(wait).unwrap();
+        assert!(ready
+            .iter()
+            .any(|(ready, readiness)| *ready == id && readiness.readable));
+        assert_eq!(stack.read(&id, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+    }
+
+    #[test]
+    fn test_loopback_connect() {
+        let (device, peer) = LoopbackDevice::pair();
+        let mut stack = TcpStack::on_device(device, Tunables::default());
+        stack.set_address(Ipv4Addr::new(192, 167, 1, 1));
+        let wait = Some(Duration::from_millis(10));
+        let id = stack.connect("192.167.1.2:80".parse().unwrap()).unwrap();
+
+        let mut buf = [0u8; 1500];
+        assert!(peer.wait_readable(wait).unwrap());
+        let n = peer.recv(&mut buf).unwrap();
+        let syn = SegmentView::parse(&buf[..n]).unwrap();
+        assert!(syn.tcp.syn() && !syn.tcp.ack());
+        let mut syn_ack = TcpHeader::new(80, id.dst_port, 300, 1000);
+        syn_ack.syn = true;
+        syn_ack.ack = true;
+        syn_ack.acknowledgment_number = syn.tcp.sequence_number().wrapping_add(1);
+        peer.send(&packet(syn_ack)).unwrap();
+        let ready = stack.poll(wait).unwrap();
+        assert!(ready
+            .iter()
+            .any(|(ready, readiness)| *ready == id && readiness.writable));
+        assert_eq!(stack.write(&id, b"hello").unwrap(), 5);
+    }
+
+    #[test]
+    fn test_input_queues() {
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        stack.bind(80).unwrap();
+        let mut backlog = Backlog::new(1);
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        let mut data = TcpHeader::new(40000, 80, 101, 1000);
+        data.ack = true;
+        let id = Some(peer(40000));
+        assert_eq!(backlog.push(id.clone(), packet(syn)), Admission::Queued);
+        assert_eq!(backlog.push(id, segment(data, b"hi")), Admission::Shed);
+
+        let (packets, shed) = backlog.drain();
+        stack.on_input_queues(&shed);
+        for packet in packets {
+            stack.on_packet(&nic, &packet);
+        }
+        assert!(stack.syn_recv_mut(&peer(40000)).is_some());
+        let counters = stack.stats().counters();
+        assert!(counters.contains(&("input_shed", 1)));
+        assert!(counters.contains(&("input_queue_high_watermark", 1)));
+    }
+
+    #[test]
+    fn test_reload() {
+        let path = std::env::temp_dir().join(format!("mini-tcp-{:}.conf", std::process::id()));
+        std::fs::write(&path, "mtu = 1500\nlisten = 80\nlisten_backlog = 16\n").unwrap();
+        let mut stack = Stack::default();
+        assert!(stack.reload().is_err());
+        stack
+            .configure(path.clone(), Config::load(&path).unwrap())
+            .unwrap();
+        assert_eq!(stack.tunables().listen_backlog, 16);
+
+        std::fs::write(&path, "mtu = 9000\nlisten = 443\nlisten_backlog = 16\n").unwrap();
+        let reloaded = stack.reload().unwrap();
+        assert_eq!(reloaded.applied, vec!["listen"]);
+        assert_eq!(reloaded.restart, vec!["mtu"]);
+        assert!(stack.listening.contains(&443) && !stack.listening.contains(&80));
+
+        // nothing is applied from an invalid file
+        std::fs::write(&path, "listen = 8080\nlisten_backlog = -1\n").unwrap();
+        assert!(stack.reload().is_err());
+        assert!(stack.listening.contains(&443));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,268 @@
+use std::fmt::{Display, Formatter};
+
+/// Counters of the whole stack, exposed through the control plane.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    /// Packets read from the nic
+    pub packets_received: u64,
+    /// Packets read from the nic but not processed, i.e. not tcp or malformed
+    pub packets_dropped: u64,
+    /// Packets read shorter than their ip total length, the receive buffer being smaller than the MTU
+    /// of the device, see `device::recv_buffer_size`
+    pub truncated_packets: u64,
+    /// Data segments shed by the input queue of their connection under overload, see `tcp::queue`
+    pub input_shed: u64,
+    /// Queued data segments dropped to make room for a control segment
+    pub input_evicted: u64,
+    /// Pure ACKs replaced by a later one in a full input queue
+    pub input_coalesced: u64,
+    /// Control segments dropped past the hard cap of the input queue, RSTs never are
+    pub input_dropped: u64,
+    /// The longest an input queue has been
+    pub input_queue_high_watermark: u64,
+    /// SYN received and SYN-ACK replied, or SYN sent by an active open
+    pub connections_opened: u64,
+    /// Connections opened by sending a SYN, see `Stack::connect`
+    pub active_opens: u64,
+    /// Handshakes completed with the final ACK, or with the SYN-ACK of an active open
+    pub connections_established: u64,
+    /// Connections torn down through the control plane
+    pub connections_killed: u64,
+    /// Connections dropped for any other reason, e.g. reset by the peer or a failed handshake
+    pub connections_closed: u64,
+    /// Segments rejected while processing a handshake
+    pub handshake_errors: u64,
+    /// SYNs to a port not bound, answered with a RST
+    pub connections_refused: u64,
+    /// SYNs and final ACKs of handshakes overflowing the accept queue of their port
+    pub accept_overflows: u64,
+    /// SYNs answered with a cookie, no connection created, see `handshake::cookie`
+    pub syn_cookies_sent: u64,
+    /// Final ACKs returning a valid cookie, their connection created
+    pub syn_cookies_accepted: u64,
+    /// ACKs for no connection returning no valid cookie, while the cookies are on
+    pub syn_cookies_rejected: u64,
+    /// Challenge ACKs sent for a RST or a SYN in the window, or an ACK too old, RFC 5961
+    pub challenge_acks: u64,
+    /// Challenge ACKs held back by `challenge_ack_limit`
+    pub challenge_acks_limited: u64,
+    /// SYNs refused for finding the stack holding `max_connections` already
+    pub connections_overflows: u64,
+    /// Handshakes in progress evicted to make room for a SYN
+    pub half_open_evicted: u64,
+    /// Connections in TIME-WAIT replaced by the handshake of a SYN of a new incarnation, RFC 6191
+    pub time_wait_reopened: u64,
+    /// Segments without any flag, see `tcp::anomaly`
+    pub anomalies_null: u64,
+    /// Segments with FIN, PSH and URG but no ACK
+    pub anomalies_xmas: u64,
+    /// Segments with both SYN and FIN
+    pub anomalies_syn_fin: u64,
+    /// Segments with both SYN and RST
+    pub anomalies_syn_rst: u64,
+    /// Resets sent in response to flag anomalies
+    pub anomaly_resets: u64,
+    /// ACKs acknowledging part of a segment, a sign of a receiver trying to inflate our sending rate
+    pub ack_divisions: u64,
+    /// ACKs of data not sent yet
+    pub optimistic_acks: u64,
+    /// Connections reset for acknowledging data not sent yet
+    pub optimistic_ack_resets: u64,
+    /// Segments whose text was cut short because the receive buffers of all the connections are full
+    pub rcv_mem_declined: u64,
+    /// Segments sent again after the retransmission timer expired
+    pub retransmissions: u64,
+    /// Segments sent again after three duplicate ACKs, without waiting for the retransmission timer
+    pub fast_retransmissions: u64,
+    /// Segments RACK deemed lost, a segment sent after them being delivered, retransmitted without
+    /// waiting for the retransmission timer
+    pub rack_losses: u64,
+    /// Retransmissions the peer reported in a D-SACK as received twice, the timer expired too early
+    pub spurious_retransmissions: u64,
+    /// Retransmission timeouts F-RTO found spurious, the ACKs were only late
+    pub spurious_timeouts: u64,
+    /// Loss recoveries undone, the timestamps showing the ACK was for the original transmission
+    pub spurious_recoveries: u64,
+    /// Segments rejected by PAWS as old duplicates, their timestamp is behind the latest one
+    pub paws_rejected: u64,
+    /// SYNs with a valid Fast Open cookie, their data accepted before the handshake completes
+    pub fast_open_accepted: u64,
+    /// SYNs with a Fast Open cookie we didn't give out, their data is ignored
+    pub fast_open_rejected: u64,
+    /// Segments received with the CE mark of a congested router
+    pub ecn_ce_received: u64,
+    /// Send rate reductions in response to the peer echoing CE marks
+    pub ecn_reductions: u64,
+    /// Segments dropped for a missing or wrong MD5 signature, or an unexpected one
+    pub md5_rejected: u64,
+    /// Segments dropped for a missing or wrong TCP-AO MAC, or an unknown KeyID
+    pub ao_rejected: u64,
+    /// Packets dropped for a wrong ip header or tcp checksum
+    pub checksum_errors: u64,
+    /// Packets dropped for their ip options, see `Tunables::ip_options_policy`
+    pub ip_options_rejected: u64,
+    /// Fragments of ip datagrams received, see `ip::fragment`
+    pub fragments_received: u64,
+    /// Datagrams reassembled from their fragments
+    pub datagrams_reassembled: u64,
+    /// Datagrams dropped before their fragments were all received: invalid, overlapping or timed out
+    pub reassembly_failures: u64,
+    /// ICMP destination unreachables sent for the datagrams of protocols the stack doesn't handle
+    pub icmp_unreachable_sent: u64,
+    /// ICMP errors not sent for exceeding `Tunables::icmp_rate_limit`
+    pub icmp_rate_limited: u64,
+    /// ICMP errors received about a segment in flight of a connection
+    pub icmp_errors_received: u64,
+    /// ICMP errors dropped for quoting no connection or a sequence number not in flight
+    pub icmp_errors_rejected: u64,
+    /// Connections aborted by an ICMP hard error
+    pub icmp_aborts: u64,
+}
+
+impl Stats {
+    /// All the counters with their names, in a stable order.
+    pub fn counters(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("packets_received", self.packets_received),
+            ("packets_dropped", self.packets_dropped),
+            ("truncated_packets", self.truncated_packets),
+            ("input_shed", self.input_shed),
+            ("input_evicted", self.input_evicted),
+            ("input_coalesced", self.input_coalesced),
+            ("input_dropped", self.input_dropped),
+            (
+                "input_queue_high_watermark",
+                self.input_queue_high_watermark,
+            ),
+            ("connections_opened", self.connections_opened),
+            ("active_opens", self.active_opens),
+            ("connections_established", self.connections_established),
+            ("connections_killed", self.connections_killed),
+            ("connections_closed", self.connections_closed),
+            ("handshake_errors", self.handshake_errors),
+            ("connections_refused", self.connections_refused),
+            ("accept_overflows", self.accept_overflows),
+            ("syn_cookies_sent", self.syn_cookies_sent),
+            ("syn_cookies_accepted", self.syn_cookies_accepted),
+            ("syn_cookies_rejected", self.syn_cookies_rejected),
+            ("challenge_acks", self.challenge_acks),
+            ("challenge_acks_limited", self.challenge_acks_limited),
+            ("connections_overflows", self.connections_overflows),
+            ("half_open_evicted", self.half_open_evicted),
+            ("time_wait_reopened", self.time_wait_reopened),
+            ("anomalies_null", self.anomalies_null),
+            ("anomalies_xmas", self.anomalies_xmas),
+            ("anomalies_syn_fin", self.anomalies_syn_fin),
+            ("anomalies_syn_rst", self.anomalies_syn_rst),
+            ("anomaly_resets", self.anomaly_resets),
+            ("ack_divisions", self.ack_divisions),
+            ("optimistic_acks", self.optimistic_acks),
+            ("optimistic_ack_resets", self.optimistic_ack_resets),
+            ("rcv_mem_declined", self.rcv_mem_declined),
+            ("retransmissions", self.retransmissions),
+            ("fast_retransmissions", self.fast_retransmissions),
+            ("rack_losses", self.rack_losses),
+            ("spurious_retransmissions", self.spurious_retransmissions),
+            ("spurious_timeouts", self.spurious_timeouts),
+            ("spurious_recoveries", self.spurious_recoveries),
+            ("paws_rejected", self.paws_rejected),
+            ("fast_open_accepted", self.fast_open_accepted),
+            ("fast_open_rejected", self.fast_open_rejected),
+            ("ecn_ce_received", self.ecn_ce_received),
+            ("ecn_reductions", self.ecn_reductions),
+            ("md5_rejected", self.md5_rejected),
+            ("ao_rejected", self.ao_rejected),
+            ("checksum_errors", self.checksum_errors),
+            ("ip_options_rejected", self.ip_options_rejected),
+            ("fragments_received", self.fragments_received),
+            ("datagrams_reassembled", self.datagrams_reassembled),
+            ("reassembly_failures", self.reassembly_failures),
+            ("icmp_unreachable_sent", self.icmp_unreachable_sent),
+            ("icmp_rate_limited", self.icmp_rate_limited),
+            ("icmp_errors_received", self.icmp_errors_received),
+            ("icmp_errors_rejected", self.icmp_errors_rejected),
+            ("icmp_aborts", self.icmp_aborts),
+        ]
+    }
+
+    /// How much every counter moved since the `before` snapshot, e.g. to assert a scenario caused
+    /// exactly one retransmission and no reset.
+    pub fn diff(&self, before: &Stats) -> StatsDiff {
+        let deltas = self
+            .counters()
+            .into_iter()
+            .zip(before.counters())
+            .map(|((name, after), (_, before))| (name, after.saturating_sub(before)))
+            .collect();
+        StatsDiff { deltas }
+    }
+
+    /// Connections opened but not torn down yet, this should match the size of the connection map.
+    pub fn connections_alive(&self) -> u64 {
+        self.connections_opened
+            .saturating_sub(self.connections_killed + self.connections_closed)
+    }
+}
+
+/// The change of the counters between two snapshots, see `Stats::diff`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsDiff {
+    deltas: Vec<(&'static str, u64)>,
+}
+
+impl StatsDiff {
+    /// The change of the counter by its name, as listed by `Stats::counters`, none for an unknown name.
+    pub fn get(&self, name: &str) -> Option<u64> {
+        self.deltas
+            .iter()
+            .find(|(counter, _)| *counter == name)
+            .map(|(_, delta)| *delta)
+    }
+
+    /// The counters that moved, in the order of `Stats::counters`.
+    pub fn changed(&self) -> Vec<(&'static str, u64)> {
+        self.deltas
+            .iter()
+            .filter(|(_, delta)| *delta > 0)
+            .cloned()
+            .collect()
+    }
+}
+
+impl Display for StatsDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let changed = self
+            .changed()
+            .iter()
+            .map(|(name, delta)| format!("{name:} +{delta:}"))
+            .collect::<Vec<_>>();
+        write!(f, "{:}", changed.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::Stats;
+
+    #[test]
+    fn test_diff() {
+        let mut stats = Stats {
+            packets_received: 10,
+            retransmissions: 2,
+            ..Default::default()
+        };
+        let before = stats.clone();
+        stats.packets_received += 3;
+        stats.retransmissions += 1;
+
+        let diff = stats.diff(&before);
+        assert_eq!(
+            diff.changed(),
+            vec![("packets_received", 3), ("retransmissions", 1)]
+        );
+        assert_eq!(diff.get("retransmissions"), Some(1));
+        assert_eq!(diff.get("anomaly_resets"), Some(0));
+        assert_eq!(diff.get("no_such_counter"), None);
+        assert_eq!(diff.to_string(), "packets_received +3, retransmissions +1");
+    }
+}
@@ -0,0 +1,139 @@
+//! Flag combinations no legitimate stack sends, typically crafted by port scanners, e.g. nmap's null,
+//! FIN/PSH/URG ("XMAS") and SYN/FIN scans. They are caught before any connection processing and
+//! handled according to the configured `AnomalyPolicy`, so the stack answers scanners predictably.
+
+use anyhow::{anyhow, Result};
+use etherparse::TcpHeaderSlice;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum FlagAnomaly {
+    /// No flag at all, the null scan
+    Null,
+    /// FIN, PSH and URG without ACK, the XMAS scan
+    Xmas,
+    /// SYN and FIN together, opening and closing at once
+    SynFin,
+    /// SYN and RST together
+    SynRst,
+}
+
+impl FlagAnomaly {
+    /// The anomaly of the segment flags, none for a sane combination.
+    pub fn classify(tcp_header: &TcpHeaderSlice) -> Option<Self> {
+        let h = tcp_header;
+        if h.syn() && h.fin() {
+            return Some(FlagAnomaly::SynFin);
+        }
+        if h.syn() && h.rst() {
+            return Some(FlagAnomaly::SynRst);
+        }
+        if h.fin() && h.psh() && h.urg() && !h.ack() {
+            return Some(FlagAnomaly::Xmas);
+        }
+        let any = h.fin() || h.syn() || h.rst() || h.psh() || h.ack() || h.urg();
+        if !any && !h.ece() && !h.cwr() && !h.ns() {
+            return Some(FlagAnomaly::Null);
+        }
+        None
+    }
+}
+
+/// What to do with a segment carrying a flag anomaly. In every case the segment itself is dropped.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum AnomalyPolicy {
+    /// Drop without a trace besides the counters
+    Drop,
+    /// Answer with a RST as for a segment to a closed port, never in response to a RST
+    Reset,
+    /// Drop and log the segment
+    LogAndDrop,
+}
+
+impl TryFrom<u64> for AnomalyPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(AnomalyPolicy::Drop),
+            1 => Ok(AnomalyPolicy::Reset),
+            2 => Ok(AnomalyPolicy::LogAndDrop),
+            _ => Err(anyhow!(
+                "unknown anomaly policy {value:}, expect 0 (drop), 1 (reset) or 2 (log and drop)"
+            )),
+        }
+    }
+}
+
+impl From<AnomalyPolicy> for u64 {
+    fn from(policy: AnomalyPolicy) -> Self {
+        match policy {
+            AnomalyPolicy::Drop => 0,
+            AnomalyPolicy::Reset => 1,
+            AnomalyPolicy::LogAndDrop => 2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::anomaly::{AnomalyPolicy, FlagAnomaly};
+    use etherparse::{TcpHeader, TcpHeaderSlice};
+
+    fn classify(set: impl FnOnce(&mut TcpHeader)) -> Option<FlagAnomaly> {
+        let mut header = TcpHeader::new(40000, 80, 0, 1024);
+        set(&mut header);
+        let mut bytes = vec![];
+        header.write(&mut bytes).unwrap();
+        FlagAnomaly::classify(&TcpHeaderSlice::from_slice(&bytes).unwrap())
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(classify(|_| {}), Some(FlagAnomaly::Null));
+        assert_eq!(
+            classify(|h| {
+                h.fin = true;
+                h.psh = true;
+                h.urg = true;
+            }),
+            Some(FlagAnomaly::Xmas)
+        );
+        assert_eq!(
+            classify(|h| {
+                h.syn = true;
+                h.fin = true;
+            }),
+            Some(FlagAnomaly::SynFin)
+        );
+        assert_eq!(
+            classify(|h| {
+                h.syn = true;
+                h.rst = true;
+            }),
+            Some(FlagAnomaly::SynRst)
+        );
+
+        assert_eq!(classify(|h| h.syn = true), None);
+        assert_eq!(
+            classify(|h| {
+                h.fin = true;
+                h.psh = true;
+                h.urg = true;
+                h.ack = true;
+            }),
+            None
+        );
+    }
+
+    #[test]
+    fn test_policy_codes() {
+        for policy in [
+            AnomalyPolicy::Drop,
+            AnomalyPolicy::Reset,
+            AnomalyPolicy::LogAndDrop,
+        ] {
+            assert_eq!(AnomalyPolicy::try_from(u64::from(policy)).unwrap(), policy);
+        }
+        assert!(AnomalyPolicy::try_from(3).is_err());
+    }
+}
@@ -0,0 +1,556 @@
+//! The TCP Authentication Option of https://www.ietf.org/rfc/rfc5925.txt, which supersedes the MD5
+//! signatures of `md5`. The peers share Master Key Tuples (MKTs), each with a SendID and a RecvID, and
+//! every connection derives traffic keys from them and the ISNs of both sides, so a key is never used
+//! as such and a segment of one connection can't be replayed into another one. Every segment carries
+//! a MAC over the segment computed with the traffic key of the MKT given by its KeyID, and asks the peer
+//! which MKT to send with next in its RNextKeyID, which is how the keys are rolled over.
+//!
+//! Only the mandatory HMAC-SHA-1-96 MAC and KDF_HMAC_SHA1 of https://www.ietf.org/rfc/rfc5926.txt are
+//! implemented, options are always included in the MAC.
+
+use crate::tcp::options::{self, TcpOption};
+use crate::tcp::wrapping_lt;
+use crate::wire::{SegmentView, OPT_AO, OPT_EOL, OPT_NOP};
+use crate::TCP_PROTOCOL;
+use anyhow::{anyhow, Result};
+use etherparse::{Ipv4Header, TcpHeader, TCP_MINIMUM_HEADER_SIZE};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::net::Ipv4Addr;
+
+/// HMAC-SHA-1 truncated to 96 bits
+pub const MAC_LEN: usize = 12;
+/// The octets of the option, aligned as it is
+pub const OPTION_LEN: usize = 4 + MAC_LEN;
+
+const SHA1_LEN: usize = 20;
+const BLOCK_LEN: usize = 64;
+/// The offset of the checksum within the tcp header, zeroed for the MAC
+const CHECKSUM: std::ops::Range<usize> = 16..18;
+
+/// A Master Key Tuple, RFC 5925 3.1. The SendID goes in the KeyID of the segments sent with it, the
+/// RecvID is the KeyID of the segments the peer sends with it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Mkt {
+    pub send_id: u8,
+    pub recv_id: u8,
+    pub key: Vec<u8>,
+}
+
+/// The master key is a secret
+impl Debug for Mkt {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Mkt")
+            .field("send_id", &self.send_id)
+            .field("recv_id", &self.recv_id)
+            .finish()
+    }
+}
+
+/// The MKTs by remote address, connections with a peer that has any must be authenticated.
+#[derive(Default, Clone, PartialEq, Eq, Debug)]
+pub struct AoKeys {
+    keys: HashMap<Ipv4Addr, Vec<Mkt>>,
+}
+
+impl AoKeys {
+    /// Adds an MKT for the peer at `addr`, replacing the one with the same SendID. Only new connections
+    /// are affected.
+    pub fn add(&mut self, addr: Ipv4Addr, mkt: Mkt) {
+        let mkts = self.keys.entry(addr).or_default();
+        mkts.retain(|m| m.send_id != mkt.send_id);
+        mkts.push(mkt);
+    }
+
+    /// Removes the MKT of the peer with `send_id`, returns whether there was one.
+    pub fn remove(&mut self, addr: Ipv4Addr, send_id: u8) -> bool {
+        let mkts = match self.keys.get_mut(&addr) {
+            Some(mkts) => mkts,
+            None => return false,
+        };
+        let len = mkts.len();
+        mkts.retain(|m| m.send_id != send_id);
+        let removed = mkts.len() < len;
+        if mkts.is_empty() {
+            self.keys.remove(&addr);
+        }
+        removed
+    }
+
+    pub fn get(&self, addr: Ipv4Addr) -> &[Mkt] {
+        self.keys.get(&addr).map_or(&[], |mkts| mkts.as_slice())
+    }
+}
+
+/// The traffic keys of an MKT for one connection, RFC 5925 5.2
+struct TrafficKeys {
+    send_id: u8,
+    recv_id: u8,
+    send: [u8; SHA1_LEN],
+    receive: [u8; SHA1_LEN],
+}
+
+/// The Sequence Number Extension, the high-order 32 bits of the 64 bit sequence numbers of one
+/// direction, RFC 5925 6.2. It's tracked at SND.UNA or RCV.NXT, which only move forward.
+#[derive(Debug)]
+struct Sne {
+    high: u32,
+    low: u32,
+}
+
+impl Sne {
+    fn new(isn: u32) -> Self {
+        Self { high: 0, low: isn }
+    }
+
+    /// Moves forward to `seq`, the extension ticks when the sequence numbers wrap around.
+    fn advance(&mut self, seq: u32) {
+        if seq < self.low {
+            self.high = self.high.wrapping_add(1);
+        }
+        self.low = seq;
+    }
+
+    /// The extension of `seq`, which is close to where the extension is tracked.
+    fn of(&self, seq: u32) -> u32 {
+        if wrapping_lt(self.low, seq) && seq < self.low {
+            self.high.wrapping_add(1)
+        } else if wrapping_lt(seq, self.low) && seq > self.low {
+            self.high.wrapping_sub(1)
+        } else {
+            self.high
+        }
+    }
+}
+
+/// The TCP-AO state of a connection.
+pub struct Ao {
+    keys: Vec<TrafficKeys>,
+    /// The MKT the segments are sent with
+    current: usize,
+    snd: Sne,
+    rcv: Sne,
+}
+
+impl Debug for Ao {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let current = &self.keys[self.current];
+        f.debug_struct("Ao")
+            .field("send_id", &current.send_id)
+            .field("recv_id", &current.recv_id)
+            .field("snd_sne", &self.snd.high)
+            .field("rcv_sne", &self.rcv.high)
+            .finish()
+    }
+}
+
+impl Ao {
+    /// Authenticates the SYN with the MKTs of the peer, then derives the traffic keys of all of them for
+    /// the connection with our ISN `iss`. The MKT the SYN asks for in its RNextKeyID is sent with, if
+    /// there is one, else the one the SYN was sent with.
+    pub fn accept(syn: &SegmentView, mkts: &[Mkt], iss: u32) -> Result<Self> {
+        let (key_id, rnext_key_id, _) = syn
+            .authentication()
+            .ok_or_else(|| anyhow!("syn without tcp-ao"))?;
+        let mkt = mkts
+            .iter()
+            .position(|m| m.recv_id == key_id)
+            .ok_or_else(|| anyhow!("syn with an unknown tcp-ao key id {key_id:}"))?;
+        let (src, dst) = (syn.ip.source(), syn.ip.destination());
+        let (sport, dport) = (syn.tcp.source_port(), syn.tcp.destination_port());
+        let irs = syn.tcp.sequence_number();
+        // a SYN only knows the ISN of its sender, the other one is zero
+        let syn_key = kdf(&mkts[mkt].key, src, dst, sport, dport, irs, 0);
+        if !check(syn, &syn_key, 0) {
+            return Err(anyhow!("syn with a wrong tcp-ao mac"));
+        }
+
+        let keys = mkts
+            .iter()
+            .map(|m| TrafficKeys {
+                send_id: m.send_id,
+                recv_id: m.recv_id,
+                send: kdf(&m.key, dst, src, dport, sport, iss, irs),
+                receive: kdf(&m.key, src, dst, sport, dport, irs, iss),
+            })
+            .collect::<Vec<_>>();
+        let current = keys
+            .iter()
+            .position(|k| k.send_id == rnext_key_id)
+            .unwrap_or(mkt);
+        Ok(Self {
+            keys,
+            current,
+            snd: Sne::new(iss),
+            rcv: Sne::new(irs),
+        })
+    }
+
+    /// Appends the option, with a zero MAC, to those of `header`, which must be aligned to 4 octets.
+    /// RNextKeyID asks the peer to keep sending with the current MKT.
+    pub fn add_option(&self, header: &mut TcpHeader) -> Result<()> {
+        let current = &self.keys[self.current];
+        let mut options = header.options().to_vec();
+        options.extend(options::build(&[TcpOption::Ao {
+            key_id: current.send_id,
+            rnext_key_id: current.recv_id,
+            mac: &[0; MAC_LEN],
+        }]));
+        header
+            .set_options_raw(&options)
+            .map_err(|e| anyhow!("{e:?}"))
+    }
+
+    /// Fills in the MAC of the option `add_option` added to `tcp`, sent in `ip` with `payload`.
+    pub fn sign(&self, ip: &Ipv4Header, tcp: &mut TcpHeader, payload: &[&[u8]]) -> Result<()> {
+        let mut options = tcp.options().to_vec();
+        let at = find_mac(&options).ok_or_else(|| anyhow!("no tcp-ao option to sign"))?;
+        options[at..at + MAC_LEN].fill(0);
+        let mut unsigned = tcp.clone();
+        unsigned.checksum = 0;
+        unsigned
+            .set_options_raw(&options)
+            .map_err(|e| anyhow!("{e:?}"))?;
+        let mut header = Vec::with_capacity(tcp.header_len() as usize);
+        unsigned.write(&mut header)?;
+
+        let sne = self.snd.of(tcp.sequence_number);
+        let traffic_key = &self.keys[self.current].send;
+        let mac = mac(
+            traffic_key,
+            sne,
+            ip.source,
+            ip.destination,
+            &header,
+            payload,
+        );
+        options[at..at + MAC_LEN].copy_from_slice(&mac);
+        tcp.set_options_raw(&options).map_err(|e| anyhow!("{e:?}"))
+    }
+
+    /// Whether the segment carries the right MAC for the MKT given by its KeyID. The MKT the peer asks
+    /// for in RNextKeyID is sent with from then on, RFC 5925 7.5.2.
+    pub fn verify(&mut self, seg: &SegmentView) -> bool {
+        let (key_id, rnext_key_id, _) = match seg.authentication() {
+            Some(option) => option,
+            None => return false,
+        };
+        let keys = match self.keys.iter().find(|k| k.recv_id == key_id) {
+            Some(keys) => keys,
+            None => return false,
+        };
+        if !check(seg, &keys.receive, self.rcv.of(seg.tcp.sequence_number())) {
+            return false;
+        }
+        if let Some(next) = self.keys.iter().position(|k| k.send_id == rnext_key_id) {
+            self.current = next;
+        }
+        true
+    }
+
+    /// Tracks the sequence number extensions at SND.UNA and RCV.NXT.
+    pub fn advance(&mut self, una: u32, rcv_nxt: u32) {
+        self.snd.advance(una);
+        self.rcv.advance(rcv_nxt);
+    }
+}
+
+/// Whether the MAC of `seg` was computed with `traffic_key`, its sequence number extended by `sne`.
+fn check(seg: &SegmentView, traffic_key: &[u8], sne: u32) -> bool {
+    let mut header = seg.tcp.slice().to_vec();
+    header[CHECKSUM].fill(0);
+    let at = match find_mac(&header[TCP_MINIMUM_HEADER_SIZE..]) {
+        Some(at) => TCP_MINIMUM_HEADER_SIZE + at,
+        None => return false,
+    };
+    let received = header[at..at + MAC_LEN].to_vec();
+    header[at..at + MAC_LEN].fill(0);
+    let mac = mac(
+        traffic_key,
+        sne,
+        seg.ip.source(),
+        seg.ip.destination(),
+        &header,
+        &[seg.payload],
+    );
+    received == mac
+}
+
+/// The offset of the MAC within the raw options.
+fn find_mac(options: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i + 1 < options.len() {
+        match options[i] {
+            OPT_EOL => return None,
+            OPT_NOP => i += 1,
+            OPT_AO => return Some(i + 4).filter(|at| at + MAC_LEN <= options.len()),
+            _ => i += (options[i + 1] as usize).max(2),
+        }
+    }
+    None
+}
+
+/// KDF_HMAC_SHA1 of RFC 5926 3.1.1, the traffic key of the segments from `src` to `dst` whose ISNs
+/// are `src_isn` and `dst_isn`, the connection context of RFC 5925 5.2.
+fn kdf(
+    master_key: &[u8],
+    src: [u8; 4],
+    dst: [u8; 4],
+    sport: u16,
+    dport: u16,
+    src_isn: u32,
+    dst_isn: u32,
+) -> [u8; SHA1_LEN] {
+    hmac_sha1(
+        master_key,
+        &[
+            // i, the counter of the only iteration needed for 160 bits
+            &[1],
+            b"TCP-AO",
+            &src,
+            &dst,
+            &sport.to_be_bytes(),
+            &dport.to_be_bytes(),
+            &src_isn.to_be_bytes(),
+            &dst_isn.to_be_bytes(),
+            // the output length in bits
+            &160u16.to_be_bytes(),
+        ],
+    )
+}
+
+/// HMAC-SHA-1-96 over the SNE, the pseudo header, the tcp header with the checksum and the MAC zeroed
+/// and the segment text, RFC 5925 5.1.
+fn mac(
+    traffic_key: &[u8],
+    sne: u32,
+    src: [u8; 4],
+    dst: [u8; 4],
+    header: &[u8],
+    payload: &[&[u8]],
+) -> [u8; MAC_LEN] {
+    let len = header.len() + payload.iter().map(|p| p.len()).sum::<usize>();
+    let pseudo = [
+        src[0],
+        src[1],
+        src[2],
+        src[3],
+        dst[0],
+        dst[1],
+        dst[2],
+        dst[3],
+        0,
+        TCP_PROTOCOL,
+        (len >> 8) as u8,
+        len as u8,
+    ];
+    let sne = sne.to_be_bytes();
+    let mut parts = vec![&sne[..], &pseudo[..], header];
+    parts.extend_from_slice(payload);
+    let mut mac = [0u8; MAC_LEN];
+    mac.copy_from_slice(&hmac_sha1(traffic_key, &parts)[..MAC_LEN]);
+    mac
+}
+
+/// HMAC of https://www.ietf.org/rfc/rfc2104.txt with SHA-1, over the concatenated `parts`.
+fn hmac_sha1(key: &[u8], parts: &[&[u8]]) -> [u8; SHA1_LEN] {
+    let mut block = [0u8; BLOCK_LEN];
+    if key.len() > BLOCK_LEN {
+        block[..SHA1_LEN].copy_from_slice(&sha1(&[key]));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let ipad = block.map(|b| b ^ 0x36);
+    let opad = block.map(|b| b ^ 0x5c);
+    let mut inner = vec![&ipad[..]];
+    inner.extend_from_slice(parts);
+    sha1(&[&opad, &sha1(&inner)])
+}
+
+/// SHA-1 of the concatenated `parts`, see https://www.ietf.org/rfc/rfc3174.txt.
+fn sha1(parts: &[&[u8]]) -> [u8; SHA1_LEN] {
+    let len = parts.iter().map(|p| p.len()).sum::<usize>();
+    let mut message = Vec::with_capacity(len + 72);
+    for part in parts {
+        message.extend_from_slice(part);
+    }
+    message.push(0x80);
+    while message.len() % BLOCK_LEN != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((len as u64).wrapping_mul(8)).to_be_bytes());
+
+    let mut state: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    for chunk in message.chunks_exact(BLOCK_LEN) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, w) in w.iter().enumerate() {
+            let (f, k) = match i / 20 {
+                0 => ((b & c) | (!b & d), 0x5a827999),
+                1 => (b ^ c ^ d, 0x6ed9eba1),
+                2 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*w);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+
+    let mut digest = [0u8; SHA1_LEN];
+    for (out, word) in digest.chunks_exact_mut(4).zip(state) {
+        out.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::ao::{hmac_sha1, kdf, sha1, Ao, Mkt, Sne, TrafficKeys};
+    use crate::wire::SegmentView;
+    use crate::TCP_PROTOCOL;
+    use etherparse::{Ipv4Header, TcpHeader};
+
+    const PEER: [u8; 4] = [192, 167, 1, 2];
+    const US: [u8; 4] = [192, 167, 1, 1];
+
+    fn hex(digest: [u8; 20]) -> String {
+        digest.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn test_sha1() {
+        // the test vectors of RFC 3174 and RFC 2202
+        assert_eq!(hex(sha1(&[])), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+        assert_eq!(
+            hex(sha1(&[b"a", b"bc"])),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        assert_eq!(
+            hex(sha1(&[
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            ])),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+        assert_eq!(
+            hex(hmac_sha1(&[0x0b; 20], &[b"Hi There"])),
+            "b617318655057264e28bc0b6fb378c8ef146be00"
+        );
+        assert_eq!(
+            hex(hmac_sha1(b"Jefe", &[b"what do ya want for nothing?"])),
+            "effcdf6ae5eb2fa2d27416d5f184df9c259a7c79"
+        );
+        assert_eq!(
+            hex(hmac_sha1(
+                &[0xaa; 80],
+                &[b"Test Using Larger Than Block-Size Key - Hash Key First"]
+            )),
+            "aa4ae5e15272d00e95705637ce8a3b55ed402112"
+        );
+    }
+
+    #[test]
+    fn test_sne() {
+        let mut sne = Sne::new(u32::MAX - 10);
+        assert_eq!(sne.of(5), 1);
+        sne.advance(5);
+        assert_eq!(sne.of(u32::MAX), 0);
+        assert_eq!(sne.of(100), 1);
+        sne.advance(100);
+        assert_eq!(sne.high, 1);
+    }
+
+    /// The state of the peer at 192.167.1.2:40000, whose ISN is 1000, sending with its first MKT
+    fn peer(mkts: &[Mkt], iss: u32) -> Ao {
+        let keys = mkts
+            .iter()
+            .map(|m| TrafficKeys {
+                send_id: m.recv_id,
+                recv_id: m.send_id,
+                send: kdf(&m.key, PEER, US, 40000, 179, 1000, iss),
+                receive: [0; 20],
+            })
+            .collect();
+        Ao {
+            keys,
+            current: 0,
+            snd: Sne::new(1000),
+            rcv: Sne::new(iss),
+        }
+    }
+
+    fn packet(ao: &Ao, mut tcp: TcpHeader, payload: &[u8]) -> Vec<u8> {
+        ao.add_option(&mut tcp).unwrap();
+        let len = tcp.header_len() + payload.len() as u16;
+        let ip = Ipv4Header::new(len, 64, TCP_PROTOCOL, PEER, US);
+        ao.sign(&ip, &mut tcp, &[payload]).unwrap();
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_accept_verify() {
+        let mkts = [
+            Mkt {
+                send_id: 1,
+                recv_id: 2,
+                key: b"old".to_vec(),
+            },
+            Mkt {
+                send_id: 3,
+                recv_id: 4,
+                key: b"new".to_vec(),
+            },
+        ];
+
+        // the SYN only knows the ISN of the peer, and asks for KeyID 3 with RNextKeyID
+        let mut syn = TcpHeader::new(40000, 179, 1000, 1000);
+        syn.syn = true;
+        let mut peer_syn = peer(&mkts, 0);
+        peer_syn.keys[0].recv_id = 3;
+        let seg = packet(&peer_syn, syn, &[]);
+        let view = SegmentView::parse(&seg).unwrap();
+        assert!(Ao::accept(&view, &mkts[1..], 5000).is_err());
+        let mut ao = Ao::accept(&view, &mkts, 5000).unwrap();
+        assert_eq!(ao.keys[ao.current].send_id, 3);
+
+        // once both ISNs are known, the SYN traffic key doesn't do, and the peer asks for KeyID 1
+        let mut tcp = TcpHeader::new(40000, 179, 1001, 1000);
+        tcp.ack = true;
+        let mut peer = peer(&mkts, 5000);
+        assert!(!ao.verify(&SegmentView::parse(&packet(&peer_syn, tcp.clone(), b"OPEN")).unwrap()));
+        let seg = packet(&peer, tcp.clone(), b"OPEN");
+        assert!(ao.verify(&SegmentView::parse(&seg).unwrap()));
+        assert_eq!(ao.keys[ao.current].send_id, 1);
+
+        // tampered with on the way
+        let mut bad = seg.clone();
+        let last = bad.len() - 1;
+        bad[last] ^= 1;
+        assert!(!ao.verify(&SegmentView::parse(&bad).unwrap()));
+        // an MKT we don't have
+        peer.keys[0].send_id = 9;
+        let seg = packet(&peer, tcp, b"OPEN");
+        assert!(!ao.verify(&SegmentView::parse(&seg).unwrap()));
+    }
+}
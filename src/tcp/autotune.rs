@@ -0,0 +1,534 @@
+//! Receive and send buffer auto-tuning.
+//!
+//! The receive side is similar to Linux's dynamic right sizing (`tcp_rcv_space_adjust`).
+//! The window advertised is the free space of the receive buffer. The buffer starts at the configured
+//! window and grows towards the bandwidth-delay product: once per RTT the octets the application read
+//! during that RTT are measured, and the buffer is grown to twice that, so the peer can keep a full
+//! RTT of data in flight while the application drains the previous one.
+//!
+//! Without timestamps the receiver has no RTT sample of its own, it's measured as in Linux's
+//! `tcp_rcv_rtt_measure`: the time between advertising a window and receiving the data up to its right
+//! edge. This is at least one RTT, more when the sender is not limited by the window.
+//!
+//! The octets buffered by all the receive buffers of the process are accounted against a global cap, so
+//! a peer can't exhaust the memory by not reading or by many connections each filling its window. The
+//! window advertised shrinks to the memory left as the cap gets close, and text beyond it is dropped.
+//!
+//! The send side mirrors it: while the application is limited by a full send buffer, the buffer is
+//! grown once per RTT to twice the octets acknowledged during that RTT, i.e. twice the window the
+//! sender actually achieves (the congestion window once there is one, bounded by SND.WND). The RTT
+//! comes from the retransmission queue. Besides the per connection cap, the send buffers of all the
+//! connections share a global cap, and the memory of an idle connection is given back.
+//!
+//! An application setting the size of a buffer, as with `SO_RCVBUF` and `SO_SNDBUF`, gets it within the
+//! min and max of the stack, and the auto-tuning of that buffer stops.
+
+use crate::tcp::{wrapping_lt, MAX_WND_SHIFT};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_RCV_BUF_MIN: u32 = 4096;
+pub const DEFAULT_RCV_BUF_MAX: u32 = 4 << 20;
+pub const DEFAULT_RCV_MEM_GLOBAL_MAX: u64 = 64 << 20;
+pub const DEFAULT_SND_BUF: u32 = 16 << 10;
+pub const DEFAULT_SND_BUF_MIN: u32 = 4096;
+pub const DEFAULT_SND_BUF_MAX: u32 = 4 << 20;
+pub const DEFAULT_SND_BUF_GLOBAL_MAX: u64 = 64 << 20;
+
+/// The octets of send buffer reserved by all the connections of the process
+static SND_BUF_RESERVED: AtomicU64 = AtomicU64::new(0);
+/// The octets received but not read yet by all the connections of the process
+static RCV_MEM_BUFFERED: AtomicU64 = AtomicU64::new(0);
+
+/// The size of the receive buffer, and the octets buffered in it which are accounted in a global pool
+/// until read or until the buffer is dropped.
+#[derive(Debug)]
+pub struct ReceiveBuffer {
+    size: u32,
+    min: u32,
+    max: u32,
+    /// The size was set by the application, it's no longer auto-tuned
+    locked: bool,
+    global_max: u64,
+    pool: &'static AtomicU64,
+    /// The octets accounted in the pool
+    buffered: u64,
+    /// The smoothed RTT measured by the receiver
+    rtt: Option<Duration>,
+    /// The right edge of the window advertised and when, until RCV.NXT reaches it
+    probe: Option<(u32, Instant)>,
+    /// Octets read by the application since `since`
+    copied: u64,
+    since: Option<Instant>,
+}
+
+impl Default for ReceiveBuffer {
+    fn default() -> Self {
+        Self::new(
+            crate::tcp::DEFAULT_WINDOW_SIZE as u32,
+            DEFAULT_RCV_BUF_MIN,
+            DEFAULT_RCV_BUF_MAX,
+            DEFAULT_RCV_MEM_GLOBAL_MAX,
+        )
+    }
+}
+
+impl Drop for ReceiveBuffer {
+    fn drop(&mut self) {
+        self.pool.fetch_sub(self.buffered, Ordering::Relaxed);
+    }
+}
+
+impl ReceiveBuffer {
+    /// A buffer of `initial` octets, clamped to `min..=max`. It only ever grows, up to `max`. The octets
+    /// buffered by all the receive buffers of the process are capped at `global_max`.
+    pub fn new(initial: u32, min: u32, max: u32, global_max: u64) -> Self {
+        Self::with_pool(initial, min, max, global_max, &RCV_MEM_BUFFERED)
+    }
+
+    fn with_pool(
+        initial: u32,
+        min: u32,
+        max: u32,
+        global_max: u64,
+        pool: &'static AtomicU64,
+    ) -> Self {
+        Self {
+            size: initial.max(min).min(max),
+            min,
+            max,
+            locked: false,
+            global_max,
+            pool,
+            buffered: 0,
+            rtt: None,
+            probe: None,
+            copied: 0,
+            since: None,
+        }
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Sets the size to `size` within the min and max, but no less than `floor`, the octets buffered
+    /// and the window already advertised. The size isn't auto-tuned anymore, returns it.
+    pub fn set_size(&mut self, size: u32, floor: u32) -> u32 {
+        self.size = size.max(self.min).min(self.max).max(floor);
+        self.locked = true;
+        self.size
+    }
+
+    /// The window to advertise with `buffered` octets not read by the application yet, no more than the
+    /// memory left in the global pool.
+    pub fn window(&self, buffered: usize) -> u32 {
+        let free = (self.size as usize)
+            .saturating_sub(buffered)
+            .min(self.budget());
+        free.min(u32::MAX as usize) as u32
+    }
+
+    /// The shift count to advertise in the window scale option, the smallest one that lets the window
+    /// cover the buffer grown to its max.
+    pub fn window_shift(&self) -> u8 {
+        let mut shift = 0;
+        while shift < MAX_WND_SHIFT && (self.max >> shift) > u16::MAX as u32 {
+            shift += 1;
+        }
+        shift
+    }
+
+    /// Gives `n` octets no longer buffered back to the global pool.
+    pub fn release(&mut self, n: usize) {
+        let released = (n as u64).min(self.buffered);
+        self.buffered -= released;
+        self.pool.fetch_sub(released, Ordering::Relaxed);
+    }
+
+    /// The octets buffered and accounted in the global pool.
+    pub fn buffered(&self) -> u64 {
+        self.buffered
+    }
+
+    /// The octets all the receive buffers can still take before reaching the global cap.
+    pub fn budget(&self) -> usize {
+        let buffered = self.pool.load(Ordering::Relaxed);
+        self.global_max.saturating_sub(buffered) as usize
+    }
+
+    /// Accounts `n` octets received and buffered until the application reads them.
+    pub fn on_buffer(&mut self, n: usize) {
+        self.buffered += n as u64;
+        self.pool.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Records the window advertised from RCV.NXT, which starts an RTT measurement unless one is
+    /// running already.
+    pub fn on_advertise(&mut self, nxt: u32, wnd: u32, now: Instant) {
+        if self.probe.is_none() && wnd > 0 {
+            self.probe = Some((nxt.wrapping_add(wnd), now));
+        }
+    }
+
+    /// Records RCV.NXT advanced, completing the RTT measurement once it reaches the advertised edge.
+    pub fn on_receive(&mut self, nxt: u32, now: Instant) {
+        let (edge, at) = match self.probe {
+            Some(probe) => probe,
+            None => return,
+        };
+        if wrapping_lt(nxt, edge) {
+            return;
+        }
+        let sample = now.duration_since(at);
+        // srtt = 7/8 srtt + 1/8 sample
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+        self.probe = None;
+    }
+
+    /// Records `n` octets read by the application, once per RTT the buffer is grown to twice what was
+    /// read during the RTT. Returns whether the buffer grew.
+    pub fn on_read(&mut self, n: usize, now: Instant) -> bool {
+        self.release(n);
+        if self.locked {
+            return false;
+        }
+
+        let rtt = match self.rtt {
+            Some(rtt) => rtt,
+            None => return false,
+        };
+        self.copied += n as u64;
+        let since = *self.since.get_or_insert(now);
+        if now.duration_since(since) < rtt {
+            return false;
+        }
+
+        let target = (self.copied * 2).min(self.max as u64) as u32;
+        self.copied = 0;
+        self.since = Some(now);
+        if target <= self.size {
+            return false;
+        }
+        self.size = target;
+        true
+    }
+}
+
+/// The size of the send buffer, i.e. how much the application can write ahead of SND.UNA. The size is
+/// reserved from a global pool until the buffer is dropped.
+#[derive(Debug)]
+pub struct SendBuffer {
+    size: u32,
+    min: u32,
+    max: u32,
+    /// The size was set by the application, it's no longer auto-tuned
+    locked: bool,
+    global_max: u64,
+    pool: &'static AtomicU64,
+    /// The smoothed RTT measured by the sender
+    rtt: Option<Duration>,
+    /// Whether a write was cut short by the buffer being full since `since`
+    limited: bool,
+    /// Octets acknowledged since `since`
+    acked: u64,
+    since: Option<Instant>,
+}
+
+impl Default for SendBuffer {
+    fn default() -> Self {
+        Self::new(
+            DEFAULT_SND_BUF,
+            DEFAULT_SND_BUF_MIN,
+            DEFAULT_SND_BUF_MAX,
+            DEFAULT_SND_BUF_GLOBAL_MAX,
+        )
+    }
+}
+
+impl Drop for SendBuffer {
+    fn drop(&mut self) {
+        self.pool.fetch_sub(self.size as u64, Ordering::Relaxed);
+    }
+}
+
+impl SendBuffer {
+    /// A buffer of `initial` octets, clamped to `min..=max`. The buffer is only grown while all the
+    /// send buffers of the process fit in `global_max`, but it never gets below `min`.
+    pub fn new(initial: u32, min: u32, max: u32, global_max: u64) -> Self {
+        Self::with_pool(initial, min, max, global_max, &SND_BUF_RESERVED)
+    }
+
+    fn with_pool(
+        initial: u32,
+        min: u32,
+        max: u32,
+        global_max: u64,
+        pool: &'static AtomicU64,
+    ) -> Self {
+        let mut buf = Self {
+            size: 0,
+            min,
+            max,
+            locked: false,
+            global_max,
+            pool,
+            rtt: None,
+            limited: false,
+            acked: 0,
+            since: None,
+        };
+        buf.reserve(min.min(max), true);
+        buf.reserve(initial.min(max), false);
+        buf
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Sets the size to `size` within the min and max, growing within the global cap. The size isn't
+    /// auto-tuned anymore. Returns whether the size fit, the data buffered beyond a smaller size stays
+    /// until acknowledged.
+    pub fn set_size(&mut self, size: u32) -> bool {
+        let target = size.max(self.min).min(self.max);
+        self.locked = true;
+        if target >= self.size {
+            return target == self.size || self.reserve(target, false);
+        }
+        self.pool
+            .fetch_sub((self.size - target) as u64, Ordering::Relaxed);
+        self.size = target;
+        true
+    }
+
+    /// The octets the application can still write with `buffered` octets in the buffer.
+    pub fn free(&self, buffered: usize) -> usize {
+        (self.size as usize).saturating_sub(buffered)
+    }
+
+    /// Records a write was cut short because the buffer is full.
+    pub fn on_write_limited(&mut self) {
+        self.limited = true;
+    }
+
+    /// Records `n` octets acknowledged, with the RTT measured by the ACK if any. Once per RTT the
+    /// buffer is grown to twice what was acknowledged during the RTT when the application was limited
+    /// by the buffer. Returns whether the buffer grew.
+    pub fn on_ack(&mut self, n: usize, rtt: Option<Duration>, now: Instant) -> bool {
+        if let Some(sample) = rtt {
+            // srtt = 7/8 srtt + 1/8 sample
+            self.rtt = Some(match self.rtt {
+                Some(rtt) => (rtt * 7 + sample) / 8,
+                None => sample,
+            });
+        }
+        let rtt = match self.rtt {
+            Some(rtt) if !self.locked => rtt,
+            _ => return false,
+        };
+        self.acked += n as u64;
+        let since = *self.since.get_or_insert(now);
+        if now.duration_since(since) < rtt {
+            return false;
+        }
+
+        let target = (self.acked * 2).min(self.max as u64) as u32;
+        let limited = self.limited;
+        self.acked = 0;
+        self.limited = false;
+        self.since = Some(now);
+        limited && self.reserve(target, false)
+    }
+
+    /// Grows the buffer to `target` octets, within the global cap unless `force`d.
+    fn reserve(&mut self, target: u32, force: bool) -> bool {
+        if target <= self.size {
+            return false;
+        }
+        let delta = (target - self.size) as u64;
+        let global_max = if force { u64::MAX } else { self.global_max };
+        let reserved = self
+            .pool
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |reserved| {
+                (reserved + delta <= global_max).then_some(reserved + delta)
+            });
+        if reserved.is_err() {
+            return false;
+        }
+        self.size = target;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::autotune::{ReceiveBuffer, SendBuffer};
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_window() {
+        let buf = ReceiveBuffer::new(1000, 4096, 1 << 20, 1 << 30);
+        assert_eq!(buf.size(), 4096);
+        assert_eq!(buf.window(96), 4000);
+        assert_eq!(buf.window(5000), 0);
+
+        let buf = ReceiveBuffer::new(1 << 19, 4096, 1 << 20, 1 << 30);
+        assert_eq!(buf.window(0), 1 << 19);
+        assert_eq!(buf.window_shift(), 5);
+        assert_eq!(
+            ReceiveBuffer::new(1000, 4096, 65535, 1 << 30).window_shift(),
+            0
+        );
+        assert_eq!(
+            ReceiveBuffer::new(1000, 4096, u32::MAX, 1 << 30).window_shift(),
+            14
+        );
+    }
+
+    #[test]
+    fn test_rtt_measure() {
+        let now = Instant::now();
+        let mut buf = ReceiveBuffer::new(4096, 4096, 1 << 20, 1 << 30);
+
+        buf.on_advertise(u32::MAX - 99, 1000, now);
+        // a later advertisement doesn't restart the running measurement
+        buf.on_advertise(u32::MAX, 1000, now + Duration::from_millis(10));
+        buf.on_receive(800, now + Duration::from_millis(50));
+        assert_eq!(buf.rtt(), None);
+        buf.on_receive(900, now + Duration::from_millis(80));
+        assert_eq!(buf.rtt(), Some(Duration::from_millis(80)));
+    }
+
+    #[test]
+    fn test_grow() {
+        let mut now = Instant::now();
+        let mut buf = ReceiveBuffer::new(4096, 4096, 10000, 1 << 30);
+
+        // nothing happens before the RTT is known
+        assert!(!buf.on_read(3000, now));
+        buf.on_advertise(0, 4096, now);
+        buf.on_receive(4096, now + Duration::from_millis(100));
+
+        now += Duration::from_millis(100);
+        assert!(!buf.on_read(1000, now));
+        now += Duration::from_millis(50);
+        assert!(!buf.on_read(1000, now));
+        // 5000 octets read within the RTT
+        now += Duration::from_millis(60);
+        assert!(buf.on_read(3000, now));
+        assert_eq!(buf.size(), 10000);
+
+        // bounded by the max and never shrinks
+        now += Duration::from_millis(200);
+        assert!(!buf.on_read(100, now));
+        assert_eq!(buf.size(), 10000);
+    }
+
+    #[test]
+    fn test_send_buffer_grow() {
+        static POOL: AtomicU64 = AtomicU64::new(0);
+        let mut now = Instant::now();
+        let mut buf = SendBuffer::with_pool(4096, 2048, 10000, 1 << 20, &POOL);
+        assert_eq!(buf.size(), 4096);
+        assert_eq!(buf.free(1000), 3096);
+
+        // not limited by the buffer, nothing to grow
+        assert!(!buf.on_ack(4000, Some(Duration::from_millis(100)), now));
+        now += Duration::from_millis(100);
+        assert!(!buf.on_ack(4000, None, now));
+
+        buf.on_write_limited();
+        now += Duration::from_millis(50);
+        assert!(!buf.on_ack(3000, None, now));
+        now += Duration::from_millis(60);
+        assert!(buf.on_ack(3000, None, now));
+        // twice the 6000 octets acknowledged within the RTT, up to the max
+        assert_eq!(buf.size(), 10000);
+        assert_eq!(POOL.load(Ordering::Relaxed), 10000);
+
+        drop(buf);
+        assert_eq!(POOL.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_send_buffer_global_max() {
+        static POOL: AtomicU64 = AtomicU64::new(0);
+        let now = Instant::now();
+        let a = SendBuffer::with_pool(8000, 1000, 1 << 20, 10000, &POOL);
+        // only the min fits besides the first buffer
+        let mut b = SendBuffer::with_pool(8000, 1000, 1 << 20, 10000, &POOL);
+        assert_eq!(a.size(), 8000);
+        assert_eq!(b.size(), 1000);
+        let mut c = SendBuffer::with_pool(8000, 1000, 1 << 20, 10000, &POOL);
+        assert_eq!(c.size(), 1000);
+        assert_eq!(POOL.load(Ordering::Relaxed), 10000);
+
+        c.on_write_limited();
+        assert!(!c.on_ack(5000, Some(Duration::ZERO), now));
+        drop(a);
+        b.on_write_limited();
+        assert!(b.on_ack(4500, Some(Duration::ZERO), now));
+        assert_eq!(b.size(), 9000);
+    }
+
+    #[test]
+    fn test_set_size() {
+        static POOL: AtomicU64 = AtomicU64::new(0);
+        let now = Instant::now();
+        let mut buf = ReceiveBuffer::new(4096, 4096, 10000, 1 << 30);
+        assert_eq!(buf.set_size(1 << 20, 0), 10000);
+        assert_eq!(buf.set_size(100, 0), 4096);
+        // the window advertised isn't taken back
+        assert_eq!(buf.set_size(5000, 6000), 6000);
+        buf.on_advertise(0, 4096, now);
+        buf.on_receive(4096, now);
+        assert!(!buf.on_read(6000, now + Duration::from_secs(1)));
+
+        let mut buf = SendBuffer::with_pool(4096, 2048, 10000, 8000, &POOL);
+        assert!(buf.set_size(6000));
+        assert_eq!(POOL.load(Ordering::Relaxed), 6000);
+        assert!(!buf.set_size(9000));
+        assert!(buf.set_size(1000));
+        assert_eq!(buf.size(), 2048);
+        assert_eq!(POOL.load(Ordering::Relaxed), 2048);
+        // no longer grown
+        buf.on_write_limited();
+        assert!(!buf.on_ack(5000, Some(Duration::ZERO), now));
+    }
+
+    #[test]
+    fn test_receive_memory() {
+        static POOL: AtomicU64 = AtomicU64::new(0);
+        let now = Instant::now();
+        let mut a = ReceiveBuffer::with_pool(4096, 4096, 1 << 20, 6000, &POOL);
+        let mut b = ReceiveBuffer::with_pool(4096, 4096, 1 << 20, 6000, &POOL);
+
+        a.on_buffer(4000);
+        assert_eq!(a.window(4000), 96);
+        // under pressure the window shrinks to the memory left
+        assert_eq!(b.budget(), 2000);
+        assert_eq!(b.window(0), 2000);
+        b.on_buffer(2000);
+        assert_eq!(a.budget(), 0);
+        assert_eq!(b.window(2000), 0);
+
+        // reading more than accounted doesn't release the memory of the others
+        assert!(!a.on_read(5000, now));
+        assert_eq!(POOL.load(Ordering::Relaxed), 2000);
+        drop(b);
+        assert_eq!(a.budget(), 6000);
+    }
+}
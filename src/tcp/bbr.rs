@@ -0,0 +1,368 @@
+//! BBR, https://datatracker.ietf.org/doc/html/draft-cardwell-iccrg-bbr-congestion-control-00, in the
+//! shape of its first version. Instead of reacting to losses, it models the path from the ACKs: the
+//! bottleneck bandwidth is the highest delivery rate sampled over the last 10 round trips, the
+//! propagation delay the lowest RTT over the last 10 seconds. The segments are paced out at that
+//! bandwidth times a gain, and the window is a couple of bandwidth-delay products, so the queue at the
+//! bottleneck stays short.
+//!
+//! The sender goes through the modes of the draft:
+//! * Startup doubles the rate every round trip, until the bandwidth stops growing by 25% for 3 rounds
+//! * Drain then empties the queue Startup built
+//! * ProbeBW cycles the pacing gain, probing for more bandwidth for a round trip and draining what the
+//!   probe queued the next, then cruising for 6 round trips
+//! * ProbeRTT drops to 4 segments in flight for 200ms when the lowest RTT is 10 seconds old, to measure
+//!   the propagation delay again
+//!
+//! Losses aren't taken as congestion, the window only stops growing beyond what is in flight until the
+//! recovery is over.
+
+use crate::tcp::congestion::{initial_window, CongestionControl};
+use crate::tcp::retransmit::RateSample;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// 2/ln(2), the least gain that doubles the delivery rate every round trip
+const HIGH_GAIN: f64 = 2.885;
+/// The pacing gains of ProbeBW, a round trip each
+const PACING_GAINS: [f64; 8] = [1.25, 0.75, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+/// The window in ProbeBW, in bandwidth-delay products
+const CWND_GAIN: f64 = 2.0;
+/// The round trips the bandwidth samples are kept
+const BW_ROUNDS: u64 = 10;
+/// The age of the lowest RTT that triggers ProbeRTT
+const MIN_RTT_WINDOW: Duration = Duration::from_secs(10);
+const PROBE_RTT_DURATION: Duration = Duration::from_millis(200);
+/// The least window, in segments
+const MIN_CWND: u32 = 4;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Mode {
+    Startup,
+    Drain,
+    ProbeBw,
+    ProbeRtt,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Bbr {
+    cwnd: u32,
+    ssthresh: u32,
+    mode: Mode,
+    /// The highest delivery rate of each of the latest round trips, with the round it's from
+    bw: VecDeque<(u64, u64)>,
+    /// The lowest RTT, and when it was measured
+    min_rtt: Option<(Duration, Instant)>,
+    /// The round trips counted so far
+    round: u64,
+    /// The octets delivered when the current round ends
+    round_end: u64,
+    /// The bandwidth Startup last grew to, and the rounds since then
+    full_bw: u64,
+    full_bw_rounds: u32,
+    filled_pipe: bool,
+    /// The phase of ProbeBW, and when it started
+    cycle: usize,
+    cycle_start: Option<Instant>,
+    /// When ProbeRTT is over, none until the data in flight is down to the least window
+    probe_rtt_done: Option<Instant>,
+}
+
+impl Bbr {
+    pub fn new(mss: u32) -> Self {
+        Self {
+            cwnd: initial_window(mss),
+            ssthresh: u32::MAX,
+            mode: Mode::Startup,
+            bw: VecDeque::new(),
+            min_rtt: None,
+            round: 0,
+            round_end: 0,
+            full_bw: 0,
+            full_bw_rounds: 0,
+            filled_pipe: false,
+            cycle: 0,
+            cycle_start: None,
+            probe_rtt_done: None,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// The estimated bottleneck bandwidth in octets per second
+    pub fn btl_bw(&self) -> u64 {
+        self.bw.iter().map(|(_, bw)| *bw).max().unwrap_or(0)
+    }
+
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.min_rtt.map(|(rtt, _)| rtt)
+    }
+
+    /// Records an RTT sample, the lowest is kept until it's 10 seconds old. Once it expired, ProbeRTT
+    /// looks for a lower one.
+    fn record_rtt(&mut self, rtt: Duration, now: Instant) {
+        let expired = self
+            .min_rtt
+            .is_some_and(|(_, at)| now.duration_since(at) > MIN_RTT_WINDOW);
+        if expired && self.mode != Mode::ProbeRtt {
+            self.mode = Mode::ProbeRtt;
+            self.probe_rtt_done = None;
+        }
+        if expired || self.min_rtt().is_none_or(|min| rtt <= min) {
+            self.min_rtt = Some((rtt, now));
+        }
+    }
+
+    /// Updates the model with the delivery rate `sample` of an ACK, `in_flight` octets being still in
+    /// flight, and moves through the modes.
+    fn update_model(&mut self, sample: &RateSample, in_flight: u32, mss: u32, now: Instant) {
+        // a round trip ends when a segment sent after it started is acknowledged
+        let round_ended = sample.prior_delivered >= self.round_end;
+        if round_ended {
+            self.round += 1;
+            self.round_end = sample.prior_delivered + sample.delivered;
+        }
+        let rate = sample.rate();
+        while self
+            .bw
+            .front()
+            .is_some_and(|(round, _)| round + BW_ROUNDS <= self.round)
+        {
+            self.bw.pop_front();
+        }
+        match self.bw.back_mut() {
+            Some((round, bw)) if *round == self.round => *bw = (*bw).max(rate),
+            _ => self.bw.push_back((self.round, rate)),
+        }
+
+        if round_ended && !self.filled_pipe {
+            self.check_full_pipe();
+        }
+        match self.mode {
+            Mode::Startup if self.filled_pipe => self.mode = Mode::Drain,
+            Mode::Drain if in_flight as u64 <= self.bdp(1.0) => self.enter_probe_bw(now),
+            Mode::ProbeBw => self.advance_cycle(in_flight, now),
+            Mode::ProbeRtt => {
+                let done = match self.probe_rtt_done {
+                    Some(done) => done,
+                    None if in_flight <= MIN_CWND * mss => {
+                        *self.probe_rtt_done.insert(now + PROBE_RTT_DURATION)
+                    }
+                    None => return,
+                };
+                if now >= done {
+                    if let Some((rtt, _)) = self.min_rtt {
+                        self.min_rtt = Some((rtt, now));
+                    }
+                    if self.filled_pipe {
+                        self.enter_probe_bw(now);
+                    } else {
+                        self.mode = Mode::Startup;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The window after an ACK of `acked` octets, the window being `cwnd` octets. It grows towards its
+    /// target by the octets acknowledged, and only drops to it once the pipe is full.
+    fn next_cwnd(&self, cwnd: u32, acked: u32, mss: u32) -> u32 {
+        let least = MIN_CWND * mss;
+        if self.mode == Mode::ProbeRtt {
+            return cwnd.min(least);
+        }
+        let gain = match self.mode {
+            Mode::Startup | Mode::Drain => HIGH_GAIN,
+            _ => CWND_GAIN,
+        };
+        // without a model yet, the window grows as in slow start
+        let target = match self.bdp(gain) {
+            0 => u32::MAX,
+            bdp => bdp.min(u32::MAX as u64) as u32,
+        };
+        let grown = cwnd.saturating_add(acked);
+        let cwnd = if self.filled_pipe {
+            grown.min(target)
+        } else if cwnd < target {
+            grown
+        } else {
+            cwnd
+        };
+        cwnd.max(least)
+    }
+
+    /// The bandwidth-delay product times `gain`, in octets
+    fn bdp(&self, gain: f64) -> u64 {
+        match self.min_rtt() {
+            Some(rtt) => (self.btl_bw() as f64 * rtt.as_secs_f64() * gain) as u64,
+            None => 0,
+        }
+    }
+
+    fn pacing_gain(&self) -> f64 {
+        match self.mode {
+            Mode::Startup => HIGH_GAIN,
+            Mode::Drain => 1.0 / HIGH_GAIN,
+            Mode::ProbeBw => PACING_GAINS[self.cycle],
+            Mode::ProbeRtt => 1.0,
+        }
+    }
+
+    /// The pipe is full once the bandwidth grew less than 25% for 3 round trips.
+    fn check_full_pipe(&mut self) {
+        let btl_bw = self.btl_bw();
+        if btl_bw as f64 >= self.full_bw as f64 * 1.25 {
+            self.full_bw = btl_bw;
+            self.full_bw_rounds = 0;
+            return;
+        }
+        self.full_bw_rounds += 1;
+        self.filled_pipe = self.full_bw_rounds >= 3;
+    }
+
+    /// ProbeBW starts cruising, the probe comes once the queue of Startup or ProbeRTT is gone.
+    fn enter_probe_bw(&mut self, now: Instant) {
+        self.mode = Mode::ProbeBw;
+        self.cycle = 2;
+        self.cycle_start = Some(now);
+    }
+
+    /// Moves to the next phase after a min RTT, the probe lasting until it put more in flight and the
+    /// drain ending early once the queue is gone.
+    fn advance_cycle(&mut self, in_flight: u32, now: Instant) {
+        let (start, rtt) = match (self.cycle_start, self.min_rtt()) {
+            (Some(start), Some(rtt)) => (start, rtt),
+            _ => return,
+        };
+        let elapsed = now.duration_since(start) > rtt;
+        let in_flight = in_flight as u64;
+        let next = match PACING_GAINS[self.cycle] {
+            gain if gain > 1.0 => elapsed && in_flight >= self.bdp(gain),
+            gain if gain < 1.0 => elapsed || in_flight <= self.bdp(1.0),
+            _ => elapsed,
+        };
+        if next {
+            self.cycle = (self.cycle + 1) % PACING_GAINS.len();
+            self.cycle_start = Some(now);
+        }
+    }
+}
+
+impl CongestionControl for Bbr {
+    fn name(&self) -> &'static str {
+        "bbr"
+    }
+
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> u32 {
+        self.ssthresh
+    }
+
+    fn set_cwnd(&mut self, cwnd: u32) {
+        self.cwnd = cwnd;
+    }
+
+    fn set_ssthresh(&mut self, ssthresh: u32) {
+        self.ssthresh = ssthresh;
+    }
+
+    /// The model sizes the window, slow start included.
+    fn on_ack(&mut self, acked: u32, mss: u32, _now: Instant) {
+        self.cwnd = self.next_cwnd(self.cwnd, acked, mss);
+    }
+
+    fn on_loss(&mut self, in_flight: u32, mss: u32) {
+        self.ssthresh = in_flight.max(2 * mss);
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_rto(&mut self, in_flight: u32, mss: u32) {
+        self.ssthresh = in_flight.max(2 * mss);
+        self.cwnd = mss;
+    }
+
+    fn on_rtt(&mut self, rtt: Duration, now: Instant) {
+        self.record_rtt(rtt, now);
+    }
+
+    fn on_delivery(&mut self, sample: &RateSample, in_flight: u32, mss: u32, now: Instant) {
+        self.update_model(sample, in_flight, mss, now);
+    }
+
+    /// The bottleneck bandwidth times the gain of the mode, none before the first sample.
+    fn pacing_rate(&self) -> Option<u64> {
+        let btl_bw = self.btl_bw();
+        (btl_bw > 0).then(|| (btl_bw as f64 * self.pacing_gain()) as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::bbr::{Bbr, Mode};
+    use crate::tcp::congestion::CongestionControl;
+    use crate::tcp::retransmit::RateSample;
+    use std::time::{Duration, Instant};
+
+    /// An ACK each round trip of 100ms, delivering `rate` octets per second
+    fn round(bbr: &mut Bbr, delivered: &mut u64, rate: u64, in_flight: u32, now: Instant) {
+        let sample = RateSample {
+            prior_delivered: *delivered,
+            delivered: rate / 10,
+            interval: Duration::from_millis(100),
+        };
+        *delivered += rate / 10;
+        bbr.on_rtt(Duration::from_millis(100), now);
+        bbr.on_delivery(&sample, in_flight, 1000, now);
+    }
+
+    #[test]
+    fn test_modes() {
+        let mut now = Instant::now();
+        let mut bbr = Bbr::new(1000);
+        let mut delivered = 0;
+        assert_eq!(bbr.pacing_rate(), None);
+
+        // the rate doubles, then stalls at 1MB/s
+        for rate in [100_000, 200_000, 400_000, 800_000] {
+            round(&mut bbr, &mut delivered, rate, 50_000, now);
+            now += Duration::from_millis(100);
+        }
+        assert_eq!(bbr.mode(), Mode::Startup);
+        assert_eq!(bbr.pacing_rate(), Some((800_000.0 * 2.885) as u64));
+        for _ in 0..4 {
+            round(&mut bbr, &mut delivered, 1_000_000, 300_000, now);
+            now += Duration::from_millis(100);
+        }
+        assert_eq!(bbr.mode(), Mode::Drain);
+        assert_eq!(bbr.btl_bw(), 1_000_000);
+
+        // the queue drains below the BDP of 100KB
+        round(&mut bbr, &mut delivered, 1_000_000, 90_000, now);
+        assert_eq!(bbr.mode(), Mode::ProbeBw);
+        assert_eq!(bbr.pacing_rate(), Some(1_000_000));
+        bbr.set_cwnd(300_000);
+        bbr.on_ack(1000, 1000, now);
+        assert_eq!(bbr.cwnd(), 200_000);
+
+        // the lowest RTT gets old, the window drops to 4 segments for 200ms
+        let later = now + Duration::from_secs(11);
+        bbr.on_rtt(Duration::from_millis(120), later);
+        assert_eq!(bbr.mode(), Mode::ProbeRtt);
+        bbr.on_ack(1000, 1000, later);
+        assert_eq!(bbr.cwnd(), 4000);
+        let sample = RateSample {
+            prior_delivered: delivered,
+            delivered: 4000,
+            interval: Duration::from_millis(100),
+        };
+        bbr.on_delivery(&sample, 4000, 1000, later);
+        bbr.on_delivery(&sample, 4000, 1000, later + Duration::from_millis(200));
+        assert_eq!(bbr.mode(), Mode::ProbeBw);
+        assert_eq!(bbr.min_rtt(), Some(Duration::from_millis(120)));
+    }
+}
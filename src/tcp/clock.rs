@@ -0,0 +1,79 @@
+//! Where the connections read the time from. Timers and RTT measurements all take the time of the
+//! connection's clock, the system clock unless another one was set with `set_clock`. With a
+//! `MockClock` the time only moves when a test advances it, RTOs, timeouts and RTT samples come out the
+//! same on every run without sleeping.
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic clock of the system
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock advanced by hand. Its clones share the time, one of them kept by the test moves the time of
+/// the connections given the others.
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    pub fn new(start: Instant) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(start)),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+
+    /// Moves the time to `at`, never backwards.
+    pub fn set(&self, at: Instant) {
+        let mut now = self.now.lock().unwrap();
+        *now = (*now).max(at);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::clock::{Clock, MockClock};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_mock_clock() {
+        let start = Instant::now();
+        let clock = MockClock::new(start);
+        let shared = clock.clone();
+        assert_eq!(shared.now(), start);
+        clock.advance(Duration::from_millis(200));
+        assert_eq!(shared.now(), start + Duration::from_millis(200));
+        clock.set(start);
+        assert_eq!(shared.now(), start + Duration::from_millis(200));
+        clock.set(start + Duration::from_secs(1));
+        assert_eq!(shared.now(), start + Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,251 @@
+//! The closing of an established connection, https://www.ietf.org/rfc/rfc793.txt page 60. Once the
+//! application closes its side, the data written before is sent first and the FIN follows it, occupying
+//! the sequence number after the last octet. The connection goes on receiving until the FIN of the peer
+//! arrived, after which reads return the end of the stream.
+//!
+//! The side whose FIN went first waits in TIME-WAIT for 2 MSL, the other is done once its FIN is
+//! acknowledged in LAST-ACK. How the close waits for the data to be acknowledged is the `Linger` option:
+//! none flushes in the background, 0 aborts the connection with a RST right away, any other duration
+//! resets it if the data and the FIN aren't acknowledged by then.
+
+use crate::tcp::timewait::MSL;
+use std::time::{Duration, Instant};
+
+/// The states of RFC 793 an established connection goes through to CLOSED.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum CloseState {
+    Established,
+    /// Closed by the application, the FIN is sent or waits for the data before it
+    FinWait1,
+    /// Our FIN was acknowledged, the peer's is still to come
+    FinWait2,
+    /// Both FINs crossed, ours isn't acknowledged yet
+    Closing,
+    TimeWait,
+    /// The peer closed its side, the application hasn't
+    CloseWait,
+    LastAck,
+    Closed,
+}
+
+impl CloseState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CloseState::Established => "ESTABLISHED",
+            CloseState::FinWait1 => "FIN-WAIT-1",
+            CloseState::FinWait2 => "FIN-WAIT-2",
+            CloseState::Closing => "CLOSING",
+            CloseState::TimeWait => "TIME-WAIT",
+            CloseState::CloseWait => "CLOSE-WAIT",
+            CloseState::LastAck => "LAST-ACK",
+            CloseState::Closed => "CLOSED",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Close {
+    /// The application closed its side, nothing more is written
+    closed: bool,
+    /// The sequence number of our FIN once sent
+    fin_seq: Option<u32>,
+    fin_acked: bool,
+    /// Our FIN went before the peer's, this side waits in TIME-WAIT
+    active: bool,
+    /// The FIN of the peer was received
+    peer_fin: bool,
+    /// The connection is reset if the FIN isn't acknowledged by then, see `Linger`
+    linger_until: Option<Instant>,
+    /// When TIME-WAIT was entered
+    time_wait_since: Option<Instant>,
+    /// The Maximum Segment Lifetime, TIME-WAIT lasts twice as long
+    msl: Duration,
+}
+
+impl Default for Close {
+    fn default() -> Self {
+        Self {
+            closed: false,
+            fin_seq: None,
+            fin_acked: false,
+            active: false,
+            peer_fin: false,
+            linger_until: None,
+            time_wait_since: None,
+            msl: MSL,
+        }
+    }
+}
+
+impl Close {
+    pub fn set_msl(&mut self, msl: Duration) {
+        self.msl = msl;
+    }
+
+    pub fn state(&self) -> CloseState {
+        match (self.closed, self.fin_acked, self.peer_fin) {
+            (false, _, false) => CloseState::Established,
+            (false, _, true) => CloseState::CloseWait,
+            (true, false, false) => CloseState::FinWait1,
+            (true, true, false) => CloseState::FinWait2,
+            (true, false, true) if self.active => CloseState::Closing,
+            (true, false, true) => CloseState::LastAck,
+            (true, true, true) if self.active => CloseState::TimeWait,
+            (true, true, true) => CloseState::Closed,
+        }
+    }
+
+    /// Whether the application closed its side, writing is over.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Whether the peer closed its side, reads end once the data before the FIN is read.
+    pub fn has_peer_fin(&self) -> bool {
+        self.peer_fin
+    }
+
+    pub fn is_fin_sent(&self) -> bool {
+        self.fin_seq.is_some()
+    }
+
+    /// Whether our FIN and all the data before it were acknowledged.
+    pub fn is_fin_acked(&self) -> bool {
+        self.fin_acked
+    }
+
+    /// The application closes its side, waiting for the data to be acknowledged until `linger_until`.
+    pub fn on_close(&mut self, linger_until: Option<Instant>) {
+        if !self.closed {
+            self.closed = true;
+            self.linger_until = linger_until;
+        }
+    }
+
+    /// Whether the FIN is to be sent, once `unsent` is 0.
+    pub fn is_fin_due(&self, unsent: usize) -> bool {
+        self.closed && self.fin_seq.is_none() && unsent == 0
+    }
+
+    pub fn on_fin_sent(&mut self, seq: u32) {
+        self.fin_seq = Some(seq);
+        self.active = !self.peer_fin;
+    }
+
+    /// SND.UNA moved to `una`, the FIN is acknowledged once it's past it.
+    pub fn on_ack(&mut self, una: u32, now: Instant) {
+        if self.fin_seq.is_some_and(|seq| seq.wrapping_add(1) == una) && !self.fin_acked {
+            self.fin_acked = true;
+            self.on_both_fins(now);
+        }
+    }
+
+    /// The FIN of the peer was received in order.
+    pub fn on_peer_fin(&mut self, now: Instant) {
+        if !self.peer_fin {
+            self.peer_fin = true;
+            self.on_both_fins(now);
+        }
+    }
+
+    fn on_both_fins(&mut self, now: Instant) {
+        if self.state() == CloseState::TimeWait {
+            self.time_wait_since = Some(now);
+        }
+    }
+
+    /// When TIME-WAIT was entered, none outside of it.
+    pub fn time_wait_since(&self) -> Option<Instant> {
+        self.time_wait_since
+    }
+
+    pub fn msl(&self) -> Duration {
+        self.msl
+    }
+
+    /// Whether the linger time is over with the FIN not acknowledged, the connection is to be reset.
+    pub fn is_linger_expired(&self, now: Instant) -> bool {
+        !self.fin_acked && self.linger_until.is_some_and(|until| until <= now)
+    }
+
+    /// Whether nothing is left of the connection at `now`: the FIN of LAST-ACK was acknowledged, or the
+    /// 2 MSL of TIME-WAIT are over.
+    pub fn is_done(&self, now: Instant) -> bool {
+        match self.state() {
+            CloseState::Closed => true,
+            CloseState::TimeWait => self
+                .time_wait_since
+                .is_some_and(|since| since + 2 * self.msl <= now),
+            _ => false,
+        }
+    }
+
+    /// When the close has to be looked at again: the end of the linger time while the FIN isn't
+    /// acknowledged, or the end of TIME-WAIT.
+    pub fn deadline(&self) -> Option<Instant> {
+        if let Some(since) = self.time_wait_since {
+            return Some(since + 2 * self.msl);
+        }
+        self.linger_until.filter(|_| !self.fin_acked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::close::{Close, CloseState};
+    use crate::tcp::timewait::MSL;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_active_close() {
+        let now = Instant::now();
+        let mut close = Close::default();
+        assert_eq!(close.state(), CloseState::Established);
+        close.on_close(Some(now + Duration::from_secs(5)));
+        assert_eq!(close.state(), CloseState::FinWait1);
+        // the data goes before the FIN
+        assert!(!close.is_fin_due(10));
+        assert!(close.is_fin_due(0));
+        close.on_fin_sent(1000);
+        assert!(!close.is_fin_due(0));
+
+        close.on_ack(1000, now);
+        assert_eq!(close.state(), CloseState::FinWait1);
+        assert!(close.is_linger_expired(now + Duration::from_secs(5)));
+        close.on_ack(1001, now);
+        assert_eq!(close.state(), CloseState::FinWait2);
+        assert!(!close.is_linger_expired(now + Duration::from_secs(5)));
+        assert_eq!(close.deadline(), None);
+
+        close.on_peer_fin(now);
+        assert_eq!(close.state(), CloseState::TimeWait);
+        assert_eq!(close.deadline(), Some(now + 2 * MSL));
+        assert!(!close.is_done(now + MSL));
+        assert!(close.is_done(now + 2 * MSL));
+    }
+
+    #[test]
+    fn test_passive_close() {
+        let now = Instant::now();
+        let mut close = Close::default();
+        close.on_peer_fin(now);
+        assert_eq!(close.state(), CloseState::CloseWait);
+        close.on_close(None);
+        close.on_fin_sent(1000);
+        assert_eq!(close.state(), CloseState::LastAck);
+        close.on_ack(1001, now);
+        assert_eq!(close.state(), CloseState::Closed);
+        assert!(close.is_done(now));
+
+        // the FINs crossed
+        let mut close = Close::default();
+        close.on_close(None);
+        close.on_fin_sent(1000);
+        close.on_peer_fin(now);
+        assert_eq!(close.state(), CloseState::Closing);
+        close.on_ack(1001, now);
+        assert_eq!(close.state(), CloseState::TimeWait);
+        close.set_msl(Duration::from_secs(1));
+        assert!(close.is_done(now + Duration::from_secs(2)));
+    }
+}
@@ -0,0 +1,852 @@
+//! Congestion control, https://www.ietf.org/rfc/rfc5681.txt. The sender keeps at most
+//! min(cwnd, SND.WND) octets in flight. The algorithm sizing cwnd is a `CongestionControl`: Reno, see
+//! `reno`, CUBIC, see `cubic`, or BBR, which models the path and paces the segments out, see `bbr`. It's
+//! picked for new connections by a tunable and can be swapped on a connection at any time.
+//!
+//! The third duplicate ACK in a row is taken as a segment lost, it's retransmitted right away instead of
+//! waiting for the timer, and the sender enters fast recovery, RFC 5681 3.2: the algorithm reduces the
+//! window, which is then inflated by a segment for every duplicate ACK, each of them being a segment
+//! that left the network. RACK deeming segments lost starts the same recovery, without the inflation,
+//! see `rack`.
+//!
+//! The recovery follows NewReno, https://www.ietf.org/rfc/rfc6582.txt: it lasts until everything that
+//! was in flight at the third duplicate ACK is acknowledged. A partial ACK, acknowledging only some of
+//! it, shows the next segment was lost as well, it's retransmitted right away and the window deflated by
+//! what the ACK freed. A burst of losses is then recovered from with a single reduction, instead of one
+//! per segment lost. The full ACK deflates the window back to ssthresh.
+//!
+//! Rather than bursting a window back-to-back at line rate, overflowing the queue of the bottleneck, the
+//! segments are paced out: spread over the smoothed RTT at the window divided by it, times a gain so
+//! the window can still grow, twice in slow start, 1.2 after it, as Linux does. The algorithms with a
+//! rate of their own, BBR, are always paced at it.
+//!
+//! The window only grows while the sender fills it, and a window left unused long enough is reduced,
+//! see `cwv`.
+
+use crate::tcp::bbr::Bbr;
+use crate::tcp::cubic::Cubic;
+use crate::tcp::cwv::Cwv;
+use crate::tcp::frto::{Frto, Verdict};
+use crate::tcp::reno::Reno;
+use crate::tcp::retransmit::RateSample;
+use crate::tcp::wrapping_lt;
+use anyhow::{anyhow, Result};
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+/// The duplicate ACKs that trigger a fast retransmit
+pub const DUP_ACK_THRESHOLD: u32 = 3;
+/// The pacing gain in slow start, the window doubling every round trip
+const PACING_SS_GAIN: f64 = 2.0;
+/// The pacing gain in congestion avoidance
+const PACING_CA_GAIN: f64 = 1.2;
+
+/// The initial window is a few segments, more of the smaller ones, RFC 5681 3.1
+pub fn initial_window(mss: u32) -> u32 {
+    match mss {
+        0..=1095 => 4 * mss,
+        1096..=2190 => 3 * mss,
+        _ => 2 * mss,
+    }
+}
+
+/// The segments an ACK can grow the window by in slow start, L of RFC 3465 2.2
+const ABC_LIMIT: u32 = 2;
+
+/// Appropriate Byte Counting, https://www.ietf.org/rfc/rfc3465.txt: the window grows with the octets
+/// acknowledged rather than with the number of ACKs. A receiver delaying its ACKs, or a stretch ACK
+/// covering many segments, doesn't slow the growth down, and a receiver dividing its ACKs gains nothing
+/// from it.
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct ByteCounter {
+    /// The octets acknowledged in congestion avoidance since the window last grew
+    bytes_acked: u32,
+    /// In the slow start following a retransmission timeout, where an ACK grows the window by a
+    /// segment at most, RFC 3465 2.3
+    after_rto: bool,
+}
+
+impl ByteCounter {
+    /// The window after an ACK of `acked` octets in slow start: it grows by the octets acknowledged, at
+    /// most two segments per ACK, doubling every round trip, RFC 3465 2.2.
+    pub fn slow_start(&mut self, cwnd: u32, acked: u32, mss: u32) -> u32 {
+        let limit = if self.after_rto { 1 } else { ABC_LIMIT };
+        cwnd.saturating_add(acked.min(limit * mss))
+    }
+
+    /// The window after an ACK of `acked` octets in congestion avoidance: it grows by a segment once a
+    /// window worth of octets was acknowledged, RFC 3465 2.1.
+    pub fn congestion_avoidance(&mut self, cwnd: u32, acked: u32, mss: u32) -> u32 {
+        self.after_rto = false;
+        self.bytes_acked = self.bytes_acked.saturating_add(acked);
+        if self.bytes_acked < cwnd {
+            return cwnd;
+        }
+        self.bytes_acked -= cwnd;
+        cwnd.saturating_add(mss)
+    }
+
+    /// The window was reduced, the octets counted towards the growth are dropped.
+    pub fn on_loss(&mut self) {
+        self.bytes_acked = 0;
+    }
+
+    pub fn on_rto(&mut self) {
+        self.bytes_acked = 0;
+        self.after_rto = true;
+    }
+}
+
+/// An algorithm sizing the congestion window. The recovery from the losses detected by duplicate ACKs
+/// is common to all of them and left to `Congestion`, which inflates and deflates the window with
+/// `set_cwnd`.
+pub trait CongestionControl: Debug + Send {
+    fn name(&self) -> &'static str;
+
+    /// The octets in flight allowed
+    fn cwnd(&self) -> u32;
+
+    fn ssthresh(&self) -> u32;
+
+    /// Overrides the window, during a recovery or when taking over from another algorithm.
+    fn set_cwnd(&mut self, cwnd: u32);
+
+    fn set_ssthresh(&mut self, ssthresh: u32);
+
+    /// Grows the window after an ACK of `acked` new octets, outside of a recovery.
+    fn on_ack(&mut self, acked: u32, mss: u32, now: Instant);
+
+    /// Reduces the window after a loss detected by duplicate ACKs, or a congestion reported with ECN,
+    /// `in_flight` octets being sent and not acknowledged. Both ssthresh and cwnd are set.
+    fn on_loss(&mut self, in_flight: u32, mss: u32);
+
+    /// Reduces the window after the retransmission timer expired, `in_flight` octets being sent and not
+    /// acknowledged.
+    fn on_rto(&mut self, in_flight: u32, mss: u32);
+
+    /// An RTT measured from an ACK.
+    fn on_rtt(&mut self, _rtt: Duration, _now: Instant) {}
+
+    /// The delivery rate `sample` of an ACK, `in_flight` octets being left in flight. Goes before
+    /// `on_ack`.
+    fn on_delivery(&mut self, _sample: &RateSample, _in_flight: u32, _mss: u32, _now: Instant) {}
+
+    /// The rate to pace the segments at in octets per second, none when they go out as soon as the
+    /// window allows.
+    fn pacing_rate(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// The congestion control of new connections, a tunable
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Algorithm {
+    Reno,
+    Cubic,
+    Bbr,
+}
+
+impl Algorithm {
+    /// The algorithm for a connection sending segments of `mss` octets.
+    pub fn controller(self, mss: u32) -> Box<dyn CongestionControl> {
+        match self {
+            Algorithm::Reno => Box::new(Reno::new(mss)),
+            Algorithm::Cubic => Box::new(Cubic::new(mss)),
+            Algorithm::Bbr => Box::new(Bbr::new(mss)),
+        }
+    }
+}
+
+impl TryFrom<u64> for Algorithm {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(Algorithm::Reno),
+            1 => Ok(Algorithm::Cubic),
+            2 => Ok(Algorithm::Bbr),
+            _ => Err(anyhow!(
+                "unknown congestion control {value:}, expect 0 (reno), 1 (cubic) or 2 (bbr)"
+            )),
+        }
+    }
+}
+
+impl From<Algorithm> for u64 {
+    fn from(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Reno => 0,
+            Algorithm::Cubic => 1,
+            Algorithm::Bbr => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Congestion {
+    controller: Box<dyn CongestionControl>,
+    /// The duplicate ACKs received in a row
+    dup_acks: u32,
+    /// In fast recovery, from the third duplicate ACK until `recover` is acknowledged
+    recovering: bool,
+    /// SND.NXT when the last recovery started, a new one doesn't start before it's acknowledged
+    recover: Option<u32>,
+    /// The segment presumed lost is to be retransmitted
+    retransmit_pending: bool,
+    /// Whether the last retransmission timeout was spurious
+    frto: Frto,
+    /// cwnd and ssthresh before the current loss episode, a recovery or a timeout, restored if the
+    /// losses turn out spurious
+    prior: Option<(u32, u32)>,
+    /// Whether the segments are paced when the algorithm has no rate of its own
+    pacing: bool,
+    /// The smoothed RTT the window is paced over, RFC 6298 2.3
+    srtt: Option<Duration>,
+    /// The variation of the RTT, RFC 6298 2.3
+    rttvar: Option<Duration>,
+    /// When the pacing lets the next segment out
+    next_send: Option<Instant>,
+    cwv: Cwv,
+}
+
+impl Congestion {
+    pub fn new(controller: Box<dyn CongestionControl>) -> Self {
+        Self {
+            controller,
+            dup_acks: 0,
+            recovering: false,
+            recover: None,
+            retransmit_pending: false,
+            frto: Frto::Off,
+            prior: None,
+            pacing: false,
+            srtt: None,
+            rttvar: None,
+            next_send: None,
+            cwv: Cwv::default(),
+        }
+    }
+
+    pub fn set_pacing(&mut self, pacing: bool) {
+        self.pacing = pacing;
+    }
+
+    /// Switches to `controller`, carrying on from the current window.
+    pub fn set_controller(&mut self, mut controller: Box<dyn CongestionControl>) {
+        controller.set_cwnd(self.cwnd());
+        controller.set_ssthresh(self.ssthresh());
+        self.controller = controller;
+    }
+
+    /// The name of the algorithm
+    pub fn name(&self) -> &'static str {
+        self.controller.name()
+    }
+
+    /// The octets in flight allowed
+    pub fn cwnd(&self) -> u32 {
+        self.controller.cwnd()
+    }
+
+    /// The octets in flight allowed when sending new data, which F-RTO lets beyond the window after a
+    /// timeout, https://www.ietf.org/rfc/rfc5682.txt 2.1 step 2b
+    pub fn send_window(&self) -> u32 {
+        if self.frto.sends_new_data() {
+            return u32::MAX;
+        }
+        self.cwnd()
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn rttvar(&self) -> Option<Duration> {
+        self.rttvar
+    }
+
+    pub fn ssthresh(&self) -> u32 {
+        self.controller.ssthresh()
+    }
+
+    pub fn in_slow_start(&self) -> bool {
+        self.cwnd() < self.ssthresh()
+    }
+
+    pub fn in_recovery(&self) -> bool {
+        self.recovering
+    }
+
+    pub fn on_rtt(&mut self, rtt: Duration, now: Instant) {
+        // RTTVAR <- 3/4 * RTTVAR + 1/4 * |SRTT - R'|, R/2 at first
+        self.rttvar = Some(match (self.rttvar, self.srtt) {
+            (Some(rttvar), Some(srtt)) => rttvar * 3 / 4 + srtt.abs_diff(rtt) / 4,
+            _ => rtt / 2,
+        });
+        // SRTT <- 7/8 * SRTT + 1/8 * R'
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => srtt * 7 / 8 + rtt / 8,
+            None => rtt,
+        });
+        self.controller.on_rtt(rtt, now);
+    }
+
+    /// See `CongestionControl::on_delivery`, goes before `on_ack`.
+    pub fn on_delivery(&mut self, sample: &RateSample, in_flight: u32, mss: u32, now: Instant) {
+        self.controller.on_delivery(sample, in_flight, mss, now);
+    }
+
+    /// The rate the segments are paced at in octets per second, none before an RTT was measured or with
+    /// the pacing off.
+    pub fn pacing_rate(&self) -> Option<u64> {
+        if let Some(rate) = self.controller.pacing_rate() {
+            return Some(rate);
+        }
+        let srtt = self.srtt.filter(|srtt| self.pacing && !srtt.is_zero())?;
+        let gain = if self.in_slow_start() {
+            PACING_SS_GAIN
+        } else {
+            PACING_CA_GAIN
+        };
+        Some((self.cwnd() as f64 * gain / srtt.as_secs_f64()) as u64)
+    }
+
+    /// When the pacing lets the next segment out, none if right away.
+    pub fn send_at(&self) -> Option<Instant> {
+        self.next_send.filter(|_| self.pacing_rate().is_some())
+    }
+
+    pub fn can_send(&self, now: Instant) -> bool {
+        self.send_at().is_none_or(|at| at <= now)
+    }
+
+    /// Spaces the segment following one of `len` octets of new data sent at `now`.
+    pub fn on_send(&mut self, len: u32, now: Instant) {
+        self.frto.on_send();
+        if let Some(rate) = self.pacing_rate().filter(|rate| *rate > 0) {
+            let start = self.next_send.map_or(now, |at| at.max(now));
+            self.next_send = Some(start + Duration::from_secs_f64(len as f64 / rate as f64));
+        }
+    }
+
+    /// Grows the window after an ACK `ack` of `acked` new octets. During a fast recovery the window is
+    /// deflated instead, RFC 6582 3.2 steps 3 and 4. Returns whether the ACK showed the last
+    /// retransmission timeout was spurious, the window being restored to what it was before.
+    pub fn on_ack(&mut self, ack: u32, acked: u32, mss: u32, now: Instant) -> bool {
+        self.dup_acks = 0;
+        if self.frto.on_ack(ack) == Verdict::Spurious && self.undo() {
+            return true;
+        }
+        if !self.recovering {
+            // an application-limited window doesn't grow, RFC 7661 4.3
+            if self
+                .cwv
+                .update(self.cwnd(), now, self.srtt.unwrap_or_default())
+            {
+                self.controller.on_ack(acked, mss, now);
+            }
+        } else if self
+            .recover
+            .is_some_and(|recover| wrapping_lt(ack, recover))
+        {
+            // a partial ACK, the segment following it is lost as well
+            let mut cwnd = self.cwnd().saturating_sub(acked);
+            if acked >= mss {
+                cwnd += mss;
+            }
+            self.controller.set_cwnd(cwnd);
+            self.retransmit_pending = true;
+        } else {
+            self.recovering = false;
+            self.controller.set_cwnd(self.ssthresh());
+        }
+        // the loss episode is over, there is nothing to undo anymore
+        if !self.recovering && self.frto == Frto::Off {
+            self.prior = None;
+        }
+        false
+    }
+
+    /// Records that `flight` octets were in flight when an ACK of new data arrived at `now`.
+    pub fn on_flight(&mut self, flight: u32, now: Instant) {
+        self.cwv.on_ack(flight, now);
+    }
+
+    /// Halves the window when it's been non-validated for too long at `now`, RFC 7661 4.3. Returns
+    /// whether it was.
+    pub fn validate(&mut self, now: Instant, mss: u32) -> bool {
+        let cwnd = self.cwnd();
+        if self.cwv.update(cwnd, now, self.srtt.unwrap_or_default()) || !self.cwv.is_expired(now) {
+            return false;
+        }
+        self.controller
+            .set_ssthresh(self.ssthresh().max(cwnd / 4 * 3));
+        self.controller
+            .set_cwnd((cwnd / 2).max(initial_window(mss)));
+        true
+    }
+
+    /// Undoes the reductions of the current loss episode, its losses found spurious: the window is
+    /// restored to what it was before and the recovery ends. Returns whether there was one to undo.
+    pub fn undo(&mut self) -> bool {
+        let (cwnd, ssthresh) = match self.prior.take() {
+            Some(prior) => prior,
+            None => return false,
+        };
+        self.controller.set_cwnd(cwnd.max(self.cwnd()));
+        self.controller.set_ssthresh(ssthresh);
+        self.recovering = false;
+        self.retransmit_pending = false;
+        self.frto = Frto::Off;
+        true
+    }
+
+    /// The retransmission timer expired with the data from `una` to `nxt` sent and not acknowledged, the
+    /// recovery is over. The window before the first expiry is kept, in case it turns out spurious.
+    pub fn on_timeout(&mut self, una: u32, nxt: u32, mss: u32) {
+        if self.frto.on_timeout(nxt) {
+            self.prior.get_or_insert((self.cwnd(), self.ssthresh()));
+        }
+        self.controller.on_rto(nxt.wrapping_sub(una), mss);
+        self.dup_acks = 0;
+        self.recovering = false;
+        self.retransmit_pending = false;
+    }
+
+    /// Counts a duplicate ACK of `una`, the data up to `nxt` being sent. Returns whether it's the one
+    /// that triggers the fast retransmit, RFC 5681 3.2 steps 2 to 4. Duplicates still acknowledging
+    /// data from before the last recovery don't start another one, RFC 6582 3.2 step 2.
+    pub fn on_dup_ack(&mut self, una: u32, nxt: u32, mss: u32) -> bool {
+        self.dup_acks += 1;
+        // after a timeout the segments sent before it were lost, the next hole goes out right away
+        if self.frto.on_dup_ack() {
+            self.retransmit_pending = true;
+        }
+        if self.recovering {
+            self.controller.set_cwnd(self.cwnd().saturating_add(mss));
+            return false;
+        }
+        if self.dup_acks != DUP_ACK_THRESHOLD
+            || self
+                .recover
+                .is_some_and(|recover| wrapping_lt(una, recover))
+        {
+            return false;
+        }
+        self.enter_recovery(una, nxt, mss);
+        self.controller
+            .set_cwnd(self.ssthresh().saturating_add(DUP_ACK_THRESHOLD * mss));
+        self.retransmit_pending = true;
+        true
+    }
+
+    /// RACK deemed segments lost, the data up to `nxt` being sent. Returns whether a recovery starts,
+    /// the segments lost being retransmitted by RACK itself, https://www.ietf.org/rfc/rfc8985.txt 7.
+    pub fn on_lost(&mut self, una: u32, nxt: u32, mss: u32) -> bool {
+        if self.recovering
+            || self
+                .recover
+                .is_some_and(|recover| wrapping_lt(una, recover))
+        {
+            return false;
+        }
+        self.enter_recovery(una, nxt, mss);
+        true
+    }
+
+    fn enter_recovery(&mut self, una: u32, nxt: u32, mss: u32) {
+        self.prior.get_or_insert((self.cwnd(), self.ssthresh()));
+        let flight = self.cwv.loss_flight(nxt.wrapping_sub(una));
+        self.controller.on_loss(flight, mss);
+        self.recover = Some(nxt);
+        self.recovering = true;
+    }
+
+    /// Whether the segment presumed lost is to be retransmitted, only the first call after the third
+    /// duplicate ACK says so.
+    pub fn take_retransmit(&mut self) -> bool {
+        std::mem::take(&mut self.retransmit_pending)
+    }
+
+    /// The peer reported congestion with ECN, https://www.ietf.org/rfc/rfc3168.txt 6.1.2. Nothing was
+    /// lost, the window is reduced as for a loss but there is nothing to recover.
+    pub fn on_congestion(&mut self, in_flight: u32, mss: u32) {
+        self.controller.on_loss(in_flight.min(self.cwnd()), mss);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::congestion::{
+        initial_window, Algorithm, Congestion, CongestionControl, DUP_ACK_THRESHOLD,
+    };
+    use crate::tcp::cwv::NVP_DURATION;
+    use crate::tcp::retransmit::RateSample;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_initial_window() {
+        assert_eq!(initial_window(536), 2144);
+        assert_eq!(initial_window(1460), 4380);
+        assert_eq!(initial_window(8960), 17920);
+    }
+
+    #[test]
+    fn test_slow_start_and_congestion_avoidance() {
+        let now = Instant::now();
+        let mut reno = Congestion::new(Algorithm::Reno.controller(500));
+        assert_eq!(reno.cwnd(), 2000);
+
+        // the octets acknowledged, two segments per ACK at most
+        reno.on_ack(0, 500, 500, now);
+        reno.on_ack(0, 1500, 500, now);
+        assert_eq!(reno.cwnd(), 3500);
+        reno.on_ack(0, 100, 500, now);
+        assert_eq!(reno.cwnd(), 3600);
+
+        // half of what was in flight, and a single segment
+        reno.on_timeout(0, 8000, 500);
+        assert_eq!((reno.cwnd(), reno.ssthresh()), (500, 4000));
+        reno.on_timeout(0, 8000, 500);
+        assert_eq!(reno.ssthresh(), 4000);
+        // a single segment per ACK after a timeout
+        reno.on_ack(0, 1000, 500, now);
+        assert_eq!(reno.cwnd(), 1000);
+        for _ in 0..6 {
+            reno.on_ack(0, 500, 500, now);
+        }
+        assert_eq!(reno.cwnd(), 4000);
+        assert!(!reno.in_slow_start());
+
+        // a window acknowledged grows it by a segment, however many ACKs it took
+        for _ in 0..7 {
+            reno.on_ack(0, 500, 500, now);
+        }
+        assert_eq!(reno.cwnd(), 4000);
+        reno.on_ack(0, 500, 500, now);
+        assert_eq!(reno.cwnd(), 4500);
+        reno.on_ack(0, 4500, 500, now);
+        assert_eq!(reno.cwnd(), 5000);
+
+        // never below two segments
+        reno.on_timeout(0, 600, 500);
+        assert_eq!(reno.ssthresh(), 1000);
+    }
+
+    #[test]
+    fn test_fast_recovery() {
+        let now = Instant::now();
+        let mut reno = Congestion::new(Algorithm::Reno.controller(500));
+        assert!(!reno.on_dup_ack(1000, 9000, 500));
+        assert!(!reno.on_dup_ack(1000, 9000, 500));
+        // new data acknowledged, the count starts over
+        reno.on_ack(1000, 500, 500, now);
+        assert_eq!(reno.cwnd(), 2500);
+        for _ in 1..DUP_ACK_THRESHOLD {
+            assert!(!reno.on_dup_ack(1000, 9000, 500));
+        }
+        assert!(!reno.take_retransmit());
+
+        assert!(reno.on_dup_ack(1000, 9000, 500));
+        assert!(reno.in_recovery());
+        assert!(reno.take_retransmit());
+        assert!(!reno.take_retransmit());
+        assert_eq!((reno.cwnd(), reno.ssthresh()), (5500, 4000));
+
+        // inflated by every further duplicate
+        reno.on_dup_ack(1000, 9000, 500);
+        assert_eq!(reno.cwnd(), 6000);
+
+        // a partial ACK retransmits the next hole and deflates the window by what it acknowledged, a
+        // segment added back
+        reno.on_ack(3000, 2000, 500, now);
+        assert!(reno.in_recovery());
+        assert!(reno.take_retransmit());
+        assert_eq!(reno.cwnd(), 4500);
+        reno.on_ack(3200, 200, 500, now);
+        assert_eq!(reno.cwnd(), 4300);
+        assert!(reno.take_retransmit());
+
+        // the full ACK ends the recovery with the window at ssthresh
+        reno.on_ack(9000, 5800, 500, now);
+        assert!(!reno.in_recovery());
+        assert!(!reno.take_retransmit());
+        assert_eq!(reno.cwnd(), 4000);
+    }
+
+    #[test]
+    fn test_recover() {
+        let now = Instant::now();
+        let mut reno = Congestion::new(Algorithm::Reno.controller(500));
+        for _ in 0..DUP_ACK_THRESHOLD {
+            reno.on_dup_ack(1000, 9000, 500);
+        }
+        reno.on_ack(9000, 8000, 500, now);
+        assert_eq!(reno.ssthresh(), 4000);
+
+        // the duplicates of an ACK from before the recovery point don't reduce the window again
+        let now = Instant::now();
+        let mut reno = Congestion::new(Algorithm::Reno.controller(500));
+        for _ in 0..DUP_ACK_THRESHOLD {
+            reno.on_dup_ack(1000, 9000, 500);
+        }
+        reno.on_timeout(0, 8000, 500);
+        for _ in 0..DUP_ACK_THRESHOLD {
+            assert!(!reno.on_dup_ack(5000, 9000, 500));
+        }
+        assert!(!reno.in_recovery());
+        reno.on_ack(9000, 4000, 500, now);
+        for _ in 0..DUP_ACK_THRESHOLD {
+            reno.on_dup_ack(9000, 12000, 500);
+        }
+        assert!(reno.in_recovery());
+        assert_eq!(reno.ssthresh(), 1500);
+    }
+
+    #[test]
+    fn test_cubic() {
+        let now = Instant::now();
+        let mut cubic = Congestion::new(Algorithm::Cubic.controller(1000));
+        for _ in 0..DUP_ACK_THRESHOLD {
+            cubic.on_dup_ack(1000, 101_000, 1000);
+        }
+        // β of CUBIC instead of a half, the window deflated to it after the recovery
+        assert_eq!(cubic.ssthresh(), 70_000);
+        cubic.on_ack(101_000, 100_000, 1000, now);
+        assert_eq!(cubic.cwnd(), 70_000);
+
+        // carrying on from the window with another algorithm
+        cubic.set_controller(Algorithm::Reno.controller(1000));
+        assert_eq!(cubic.name(), "reno");
+        for i in 0..70 {
+            cubic.on_ack(102_000 + i * 1000, 1000, 1000, now);
+        }
+        assert_eq!(cubic.cwnd(), 71_000);
+    }
+
+    #[test]
+    fn test_spurious_timeout() {
+        let now = Instant::now();
+        let mut reno = Congestion::new(Algorithm::Reno.controller(500));
+        reno.on_congestion(8000, 500);
+        reno.on_timeout(1000, 9000, 500);
+        assert_eq!(
+            (reno.cwnd(), reno.ssthresh(), reno.send_window()),
+            (500, 4000, 500)
+        );
+
+        // the first ACK lets two new segments out whatever the window, the next shows they weren't
+        // needed
+        assert!(!reno.on_ack(1500, 500, 500, now));
+        assert_eq!(reno.send_window(), u32::MAX);
+        reno.on_send(500, now);
+        reno.on_send(500, now);
+        assert_eq!(reno.send_window(), reno.cwnd());
+        assert!(reno.on_ack(2000, 500, 500, now));
+        assert_eq!((reno.cwnd(), reno.ssthresh()), (1000, 1000));
+
+        // a duplicate ACK instead, the next hole is retransmitted and the window stays collapsed
+        reno.on_timeout(2000, 9000, 500);
+        reno.on_ack(2500, 500, 500, now);
+        assert!(!reno.on_dup_ack(2500, 10_000, 500));
+        assert!(reno.take_retransmit());
+        assert!(!reno.on_ack(3000, 500, 500, now));
+        assert_eq!(reno.cwnd(), 1500);
+    }
+
+    #[test]
+    fn test_undo() {
+        let now = Instant::now();
+        let mut reno = Congestion::new(Algorithm::Reno.controller(500));
+        assert!(!reno.undo());
+        for _ in 0..DUP_ACK_THRESHOLD {
+            reno.on_dup_ack(1000, 9000, 500);
+        }
+        // a timeout within the recovery, the window before both is restored
+        reno.on_timeout(1000, 9000, 500);
+        assert!(reno.undo());
+        assert!(!reno.in_recovery());
+        assert_eq!((reno.cwnd(), reno.ssthresh()), (2000, u32::MAX));
+        assert!(!reno.undo());
+
+        // once the recovery is over there is nothing to undo
+        assert!(reno.on_lost(9000, 17_000, 500));
+        reno.on_ack(17_000, 8000, 500, now);
+        assert!(!reno.undo());
+        assert_eq!(reno.cwnd(), 4000);
+    }
+
+    #[test]
+    fn test_window_validation() {
+        let now = Instant::now();
+        let mut reno = Congestion::new(Algorithm::Reno.controller(500));
+        reno.on_flight(2000, now);
+        reno.on_ack(2000, 2000, 500, now);
+        assert_eq!(reno.cwnd(), 3000);
+
+        // application-limited, the window is kept but doesn't grow
+        let later = now + Duration::from_secs(2);
+        reno.on_flight(500, later);
+        reno.on_ack(2500, 500, 500, later);
+        assert_eq!(reno.cwnd(), 3000);
+        assert!(!reno.validate(later + NVP_DURATION / 2, 500));
+        assert!(reno.validate(later + NVP_DURATION, 500));
+        assert_eq!(reno.cwnd(), 2000);
+        assert!(!reno.validate(later + NVP_DURATION, 500));
+    }
+
+    #[test]
+    fn test_on_lost() {
+        let now = Instant::now();
+        let mut reno = Congestion::new(Algorithm::Reno.controller(500));
+        // RACK retransmits what it deemed lost, the window isn't inflated for it
+        assert!(reno.on_lost(1000, 9000, 500));
+        assert!(reno.in_recovery());
+        assert!(!reno.take_retransmit());
+        assert_eq!((reno.cwnd(), reno.ssthresh()), (4000, 4000));
+        assert!(!reno.on_lost(1000, 9000, 500));
+        for _ in 0..DUP_ACK_THRESHOLD {
+            assert!(!reno.on_dup_ack(1000, 9000, 500));
+        }
+
+        reno.on_ack(9000, 8000, 500, now);
+        assert!(!reno.in_recovery());
+        assert!(!reno.on_lost(5000, 9500, 500));
+        assert!(reno.on_lost(9000, 9500, 500));
+    }
+
+    /// A window that is only changed by the recovery and on a timeout
+    #[derive(Debug)]
+    struct Fixed {
+        cwnd: u32,
+        ssthresh: u32,
+    }
+
+    impl CongestionControl for Fixed {
+        fn name(&self) -> &'static str {
+            "fixed"
+        }
+
+        fn cwnd(&self) -> u32 {
+            self.cwnd
+        }
+
+        fn ssthresh(&self) -> u32 {
+            self.ssthresh
+        }
+
+        fn set_cwnd(&mut self, cwnd: u32) {
+            self.cwnd = cwnd;
+        }
+
+        fn set_ssthresh(&mut self, _ssthresh: u32) {}
+
+        fn on_ack(&mut self, _acked: u32, _mss: u32, _now: Instant) {}
+
+        fn on_loss(&mut self, _in_flight: u32, _mss: u32) {}
+
+        fn on_rto(&mut self, _in_flight: u32, mss: u32) {
+            self.cwnd = mss;
+        }
+    }
+
+    #[test]
+    fn test_custom_controller() {
+        let now = Instant::now();
+        let mut congestion = Congestion::new(Box::new(Fixed {
+            cwnd: 10_000,
+            ssthresh: 10_000,
+        }));
+        congestion.on_ack(1000, 1000, 500, now);
+        assert_eq!(congestion.cwnd(), 10_000);
+        assert_eq!(congestion.pacing_rate(), None);
+
+        // the recovery is the same whatever the algorithm
+        for _ in 0..DUP_ACK_THRESHOLD {
+            congestion.on_dup_ack(1000, 9000, 500);
+        }
+        assert!(congestion.take_retransmit());
+        assert_eq!(congestion.cwnd(), 11_500);
+        congestion.on_ack(9000, 8000, 500, now);
+        assert_eq!(congestion.cwnd(), 10_000);
+
+        // swapped for CUBIC, which carries on from the window
+        congestion.set_controller(Algorithm::Cubic.controller(500));
+        assert_eq!(congestion.name(), "cubic");
+        assert_eq!((congestion.cwnd(), congestion.ssthresh()), (10_000, 10_000));
+    }
+
+    #[test]
+    fn test_pacing() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut bbr = Congestion::new(Algorithm::Bbr.controller(1000));
+        // nothing paced before the first sample
+        bbr.on_send(1000, now);
+        assert!(bbr.can_send(now));
+
+        bbr.on_rtt(ms(10), now);
+        let sample = RateSample {
+            prior_delivered: 0,
+            delivered: 10_000,
+            interval: ms(10),
+        };
+        bbr.on_delivery(&sample, 0, 1000, now);
+        bbr.on_ack(10_000, 10_000, 1000, now);
+        // 1MB/s at the gain of Startup, a segment every 1000 / 2.885 µs
+        assert_eq!(bbr.pacing_rate(), Some(2_885_000));
+        assert_eq!(bbr.cwnd(), 14_000);
+        bbr.on_send(1000, now);
+        bbr.on_send(1000, now);
+        assert!(!bbr.can_send(now + Duration::from_micros(600)));
+        assert!(bbr.can_send(now + Duration::from_micros(700)));
+    }
+
+    #[test]
+    fn test_pacing_window() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut reno = Congestion::new(Algorithm::Reno.controller(1000));
+        reno.set_pacing(true);
+        assert_eq!(reno.pacing_rate(), None);
+
+        // twice the window of 4 segments over the RTT of 100ms in slow start, a segment every 12.5ms
+        reno.on_rtt(ms(100), now);
+        assert_eq!(reno.pacing_rate(), Some(80_000));
+        reno.on_send(1000, now);
+        assert!(!reno.can_send(now + ms(12)));
+        assert!(reno.can_send(now + ms(13)));
+
+        // over the smoothed 110ms, 1.2 times the window in congestion avoidance
+        reno.on_rtt(ms(180), now);
+        reno.on_congestion(8000, 1000);
+        assert_eq!(reno.cwnd(), 2000);
+        assert_eq!(reno.pacing_rate(), Some(21_818));
+
+        reno.set_pacing(false);
+        assert_eq!(reno.pacing_rate(), None);
+        assert!(reno.can_send(now));
+    }
+
+    #[test]
+    fn test_algorithm_codes() {
+        for algorithm in [Algorithm::Reno, Algorithm::Cubic, Algorithm::Bbr] {
+            assert_eq!(
+                Algorithm::try_from(u64::from(algorithm)).unwrap(),
+                algorithm
+            );
+        }
+        assert!(Algorithm::try_from(3).is_err());
+    }
+
+    #[test]
+    fn test_on_congestion() {
+        let now = Instant::now();
+        let mut reno = Congestion::new(Algorithm::Reno.controller(500));
+        reno.on_congestion(10000, 500);
+        // what was in flight beyond the window doesn't count
+        assert_eq!((reno.cwnd(), reno.ssthresh()), (1000, 1000));
+        reno.on_ack(0, 1000, 500, now);
+        assert_eq!(reno.cwnd(), 1500);
+    }
+}
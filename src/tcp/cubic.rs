@@ -0,0 +1,203 @@
+//! The window growth of CUBIC, https://www.ietf.org/rfc/rfc9438.txt. After a reduction the window grows
+//! as a cubic function of the time elapsed, not of the ACKs received: fast while far below the window
+//! where the loss happened, flat around it, and fast again probing beyond it. The growth doesn't depend
+//! on the RTT, so a long path fills its bandwidth-delay product as quickly as a short one.
+//!
+//! Where Reno would grow faster, i.e. on short RTTs, the window follows the estimate of Reno instead, so
+//! CUBIC is never less aggressive than the flows it shares the path with. Slow start is that of Reno and
+//! the recovery that of `congestion`, only the reduction and the congestion avoidance differ. The
+//! computations are in segments, as in the RFC.
+
+use crate::tcp::congestion::{initial_window, ByteCounter, CongestionControl};
+use std::time::{Duration, Instant};
+
+/// C, the scaling of the cubic function, RFC 9438 4.2
+const C: f64 = 0.4;
+/// β, the multiplicative decrease, RFC 9438 4.6
+const BETA: f64 = 0.7;
+/// α, the additive increase of the Reno estimate, matching the average window of Reno, RFC 9438 4.3
+const ALPHA: f64 = 3.0 * (1.0 - BETA) / (1.0 + BETA);
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Cubic {
+    cwnd: u32,
+    ssthresh: u32,
+    /// Slow start is that of Reno
+    abc: ByteCounter,
+    /// W_max, the window before the last reduction
+    w_max: f64,
+    /// The W_max of the reduction before, a lower one shows a new flow taking its share
+    last_w_max: f64,
+    /// The start of the current congestion avoidance, none until the first ACK after a reduction
+    epoch: Option<Instant>,
+    /// K, the time from `epoch` until the window is back to W_max, in seconds
+    k: f64,
+    /// W_est, the window Reno would have
+    w_est: f64,
+    /// The latest RTT measured
+    rtt: Duration,
+}
+
+impl Cubic {
+    pub fn new(mss: u32) -> Self {
+        Self {
+            cwnd: initial_window(mss),
+            ssthresh: u32::MAX,
+            abc: ByteCounter::default(),
+            w_max: 0.0,
+            last_w_max: 0.0,
+            epoch: None,
+            k: 0.0,
+            w_est: 0.0,
+            rtt: Duration::ZERO,
+        }
+    }
+
+    /// Sets ssthresh on congestion, `in_flight` octets being sent, RFC 9438 4.6 and 4.7.
+    fn reduce(&mut self, in_flight: u32, mss: u32) {
+        let cwnd = self.cwnd as f64 / mss as f64;
+        // fast convergence: a flow losing below its previous W_max releases bandwidth to newer flows
+        self.w_max = if cwnd < self.last_w_max {
+            cwnd * (1.0 + BETA) / 2.0
+        } else {
+            cwnd
+        };
+        self.last_w_max = self.w_max;
+        self.epoch = None;
+        self.ssthresh = ((in_flight as f64 * BETA) as u32).max(2 * mss);
+    }
+
+    /// Grows the window after an ACK of `acked` octets in congestion avoidance, RFC 9438 4.2 to 4.5.
+    fn grow(&mut self, acked: u32, mss: u32, now: Instant) {
+        let mss = mss as f64;
+        let cwnd = self.cwnd as f64 / mss;
+        let epoch = *self.epoch.get_or_insert_with(|| {
+            // without a reduction before, the window at the start is as good as W_max
+            self.w_max = self.w_max.max(cwnd);
+            self.k = ((self.w_max - cwnd) / C).cbrt();
+            self.w_est = cwnd;
+            now
+        });
+        let t = now.duration_since(epoch).as_secs_f64();
+
+        self.w_est += ALPHA * (acked as f64 / mss) / cwnd;
+        let next = if self.w_cubic(t) < self.w_est {
+            self.w_est
+        } else {
+            // the window aimed at one RTT from now, growing at most by half per RTT
+            let target = self
+                .w_cubic(t + self.rtt.as_secs_f64())
+                .clamp(cwnd, 1.5 * cwnd);
+            cwnd + (target - cwnd) / cwnd * (acked as f64 / mss)
+        };
+        self.cwnd = (next * mss) as u32;
+    }
+
+    /// W_cubic(t) = C * (t - K)^3 + W_max
+    fn w_cubic(&self, t: f64) -> f64 {
+        C * (t - self.k).powi(3) + self.w_max
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn name(&self) -> &'static str {
+        "cubic"
+    }
+
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> u32 {
+        self.ssthresh
+    }
+
+    fn set_cwnd(&mut self, cwnd: u32) {
+        self.cwnd = cwnd;
+    }
+
+    fn set_ssthresh(&mut self, ssthresh: u32) {
+        self.ssthresh = ssthresh;
+    }
+
+    fn on_ack(&mut self, acked: u32, mss: u32, now: Instant) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd = self.abc.slow_start(self.cwnd, acked, mss);
+        } else {
+            self.grow(acked, mss, now);
+        }
+    }
+
+    fn on_loss(&mut self, in_flight: u32, mss: u32) {
+        self.reduce(in_flight, mss);
+        self.cwnd = self.ssthresh;
+    }
+
+    /// The reduction is that of a loss, the window restarting from a single segment, RFC 9438 4.8.
+    fn on_rto(&mut self, in_flight: u32, mss: u32) {
+        self.reduce(in_flight, mss);
+        self.abc.on_rto();
+        self.cwnd = mss;
+    }
+
+    fn on_rtt(&mut self, rtt: Duration, _now: Instant) {
+        self.rtt = rtt;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::congestion::CongestionControl;
+    use crate::tcp::cubic::Cubic;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_cubic() {
+        let now = Instant::now();
+        let mut cubic = Cubic::new(1000);
+        cubic.on_rtt(Duration::from_millis(100), now);
+        // a loss at 100 segments of 1000 octets
+        cubic.set_cwnd(100_000);
+        cubic.on_loss(100_000, 1000);
+        assert_eq!((cubic.cwnd(), cubic.ssthresh()), (70_000, 70_000));
+
+        // a window of ACKs every RTT of 100ms, concave towards W_max, K = cbrt(30 / 0.4) ~ 4.2 seconds
+        // later, then convex probing beyond it
+        let mut windows = vec![];
+        for rtt in 0..=80 {
+            let now = now + Duration::from_millis(100 * rtt);
+            for _ in 0..cubic.cwnd() / 1000 {
+                cubic.on_ack(1000, 1000, now);
+            }
+            windows.push(cubic.cwnd());
+        }
+        assert!(windows[0] < 75_000);
+        assert!((88_000..98_000).contains(&windows[20]));
+        assert!((99_000..=101_000).contains(&windows[42]));
+        assert!(windows[60] - windows[42] < windows[20] - windows[0]);
+        assert!(windows[80] > 120_000);
+
+        // a loss below the previous W_max, the flow converges faster
+        cubic.set_cwnd(80_000);
+        cubic.on_loss(80_000, 1000);
+        assert_eq!(cubic.w_max, 80.0 * 1.7 / 2.0);
+
+        // a timeout reduces as much, from a single segment
+        cubic.on_rto(80_000, 1000);
+        assert_eq!((cubic.cwnd(), cubic.ssthresh()), (1000, 56_000));
+    }
+
+    #[test]
+    fn test_reno_friendly() {
+        let now = Instant::now();
+        let mut cubic = Cubic::new(1000);
+        cubic.on_rtt(Duration::from_millis(1), now);
+        cubic.set_cwnd(20_000);
+        cubic.on_loss(20_000, 1000);
+        // a window's worth of ACKs in a short RTT, Reno would grow faster than the flat cubic
+        for _ in 0..14 {
+            cubic.on_ack(1000, 1000, now);
+        }
+        assert!(cubic.cwnd() > 14_400);
+    }
+}
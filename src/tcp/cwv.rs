@@ -0,0 +1,140 @@
+//! Congestion window validation, https://www.ietf.org/rfc/rfc7661.txt. A window only means something
+//! while the sender fills it: once the connection is idle or application-limited, the path it was
+//! measured on may have changed, and sending a stale window at once could overflow it.
+//!
+//! pipeACK, the data the path carried recently, is the most data in flight when an ACK arrived over the
+//! sampling period, the longer of 3 RTTs and a second. While it's below half the window, the window is
+//! non-validated: it's kept but doesn't grow, a loss reduces it from pipeACK if that's more than what
+//! was in flight, and after 5 minutes spent so it's halved, RFC 7661 4.3 and 4.4. Before the first
+//! sample the window is the initial one and it's validated.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// How long a non-validated window is kept before it's reduced, NVP duration
+pub const NVP_DURATION: Duration = Duration::from_secs(5 * 60);
+/// The shortest sampling period of pipeACK
+const MIN_SAMPLING_PERIOD: Duration = Duration::from_secs(1);
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Cwv {
+    /// The data in flight at the ACKs of the sampling period with when each came, decreasing, so the
+    /// first one is pipeACK
+    samples: VecDeque<(Instant, u32)>,
+    /// Whether a sample was ever taken, pipeACK is undefined before
+    sampled: bool,
+    /// When the non-validated phase started, or the window was last reduced in it. None while the
+    /// window is validated.
+    nvp_since: Option<Instant>,
+}
+
+impl Cwv {
+    /// Records an ACK at `now`, `flight` octets being in flight when it arrived.
+    pub fn on_ack(&mut self, flight: u32, now: Instant) {
+        while self.samples.back().is_some_and(|(_, f)| *f <= flight) {
+            self.samples.pop_back();
+        }
+        self.samples.push_back((now, flight));
+        self.sampled = true;
+    }
+
+    /// pipeACK at `now`, none before the first sample, 0 once the samples expired.
+    pub fn pipe_ack(&mut self, now: Instant, rtt: Duration) -> Option<u32> {
+        let period = (rtt * 3).max(MIN_SAMPLING_PERIOD);
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > period)
+        {
+            self.samples.pop_front();
+        }
+        self.sampled
+            .then(|| self.samples.front().map_or(0, |(_, flight)| *flight))
+    }
+
+    /// Updates the phase for a window of `cwnd` octets at `now`, returns whether it's validated.
+    pub fn update(&mut self, cwnd: u32, now: Instant, rtt: Duration) -> bool {
+        let validated = self
+            .pipe_ack(now, rtt)
+            .is_none_or(|pipe_ack| pipe_ack >= cwnd / 2);
+        if validated {
+            self.nvp_since = None;
+        } else {
+            self.nvp_since.get_or_insert(now);
+        }
+        validated
+    }
+
+    pub fn is_validated(&self) -> bool {
+        self.nvp_since.is_none()
+    }
+
+    /// Whether the window has been non-validated for the NVP duration at `now`, it's to be reduced.
+    /// The NVP duration starts over.
+    pub fn is_expired(&mut self, now: Instant) -> bool {
+        match self.nvp_since {
+            Some(since) if now.duration_since(since) >= NVP_DURATION => {
+                self.nvp_since = Some(now);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The data in flight a loss reduces the window from, `in_flight` or pipeACK if more in the
+    /// non-validated phase, RFC 7661 4.4
+    pub fn loss_flight(&self, in_flight: u32) -> u32 {
+        match self.samples.front() {
+            Some((_, pipe_ack)) if !self.is_validated() => in_flight.max(*pipe_ack),
+            _ => in_flight,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::cwv::{Cwv, NVP_DURATION};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_pipe_ack() {
+        let now = Instant::now();
+        let rtt = Duration::from_millis(500);
+        let mut cwv = Cwv::default();
+        assert_eq!(cwv.pipe_ack(now, rtt), None);
+        assert!(cwv.update(10_000, now, rtt));
+
+        cwv.on_ack(8000, now);
+        cwv.on_ack(3000, now + Duration::from_millis(100));
+        assert_eq!(
+            cwv.pipe_ack(now + Duration::from_millis(1500), rtt),
+            Some(8000)
+        );
+        assert!(cwv.update(10_000, now + Duration::from_millis(1500), rtt));
+        // the largest sample expires after 3 RTTs
+        let later = now + Duration::from_millis(1550);
+        assert_eq!(cwv.pipe_ack(later, rtt), Some(3000));
+        assert!(!cwv.update(10_000, later, rtt));
+        assert_eq!(cwv.loss_flight(2000), 3000);
+        // idle, nothing was carried
+        assert_eq!(cwv.pipe_ack(later + Duration::from_secs(2), rtt), Some(0));
+    }
+
+    #[test]
+    fn test_nvp() {
+        let now = Instant::now();
+        let rtt = Duration::from_millis(100);
+        let mut cwv = Cwv::default();
+        cwv.on_ack(1000, now);
+        assert!(!cwv.update(10_000, now, rtt));
+        assert!(!cwv.is_validated());
+        assert!(!cwv.is_expired(now + NVP_DURATION / 2));
+        assert!(cwv.is_expired(now + NVP_DURATION));
+        assert!(!cwv.is_expired(now + NVP_DURATION));
+
+        // the window is filled again
+        cwv.on_ack(6000, now + NVP_DURATION);
+        assert!(cwv.update(10_000, now + NVP_DURATION, rtt));
+        assert_eq!(cwv.loss_flight(2000), 2000);
+    }
+}
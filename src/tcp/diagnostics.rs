@@ -0,0 +1,178 @@
+//! Explains what limits the throughput of a connection, computed from the sequence spaces and the
+//! buffers: the peer window, the congestion window, our send buffer, the application not writing or not
+//! reading, or the retransmission timer backing off.
+
+use crate::core::tcb::Synchronized;
+use crate::tcp::retransmit::INITIAL_RTO;
+use crate::tcp::{Connection, ReceiveSequenceSpace, SendSequenceSpace};
+use std::fmt::{Display, Formatter};
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Limit {
+    /// The peer advertised a zero window
+    PeerZeroWindow,
+    /// The peer window doesn't leave room for a full segment of the data waiting
+    PeerWindow,
+    /// The congestion window doesn't leave room for a full segment of the data waiting
+    CongestionWindow,
+    /// The send buffer is full, the application can't write more
+    SendBuffer,
+    /// Everything written is sent, the sender waits for the application
+    ApplicationWrite,
+    /// The receive buffer is filling up because the application doesn't read
+    ApplicationRead,
+    /// Segments were lost and the retransmission timer is backed off
+    RetransmissionBackoff,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            Limit::PeerZeroWindow => "peer_zero_window",
+            Limit::PeerWindow => "peer_window",
+            Limit::CongestionWindow => "congestion_window",
+            Limit::SendBuffer => "send_buffer",
+            Limit::ApplicationWrite => "application_write",
+            Limit::ApplicationRead => "application_read",
+            Limit::RetransmissionBackoff => "retransmission_backoff",
+        };
+        write!(f, "{s:}")
+    }
+}
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Diagnosis {
+    /// SND.WND, the window advertised by the peer
+    pub peer_window: u64,
+    /// SND.NXT - SND.UNA, excluding an unacknowledged SYN
+    pub in_flight: u64,
+    pub cwnd: u64,
+    pub ssthresh: u64,
+    /// Written by the application but not sent yet
+    pub unsent: u64,
+    pub send_buffer: u64,
+    /// RCV.WND, the window we advertised
+    pub window: u64,
+    /// Received but not read by the application yet
+    pub unread: u64,
+    pub receive_buffer: u64,
+    pub rto_ms: u64,
+    /// What holds the connection back, empty when nothing does
+    pub limits: Vec<Limit>,
+}
+
+impl Diagnosis {
+    /// All the measurements with their names, in a stable order.
+    pub fn values(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("peer_window", self.peer_window),
+            ("in_flight", self.in_flight),
+            ("cwnd", self.cwnd),
+            ("ssthresh", self.ssthresh),
+            ("unsent", self.unsent),
+            ("send_buffer", self.send_buffer),
+            ("window", self.window),
+            ("unread", self.unread),
+            ("receive_buffer", self.receive_buffer),
+            ("rto_ms", self.rto_ms),
+        ]
+    }
+}
+
+impl<T> Connection<T>
+where
+    T: AsRef<SendSequenceSpace> + AsRef<ReceiveSequenceSpace> + Synchronized,
+{
+    pub fn diagnose(&self) -> Diagnosis {
+        let snd: &SendSequenceSpace = self.state.as_ref();
+        let rcv: &ReceiveSequenceSpace = self.state.as_ref();
+
+        let syn_in_flight = !T::SYN_ACKED as u32;
+        let in_flight = snd.nxt.wrapping_sub(snd.una).wrapping_sub(syn_in_flight) as usize;
+        let unsent = self.outgoing.len().saturating_sub(in_flight);
+        let unread = self.incoming.len() + self.urgent.len();
+        // SND.UNA + SND.WND - SND.NXT
+        let usable = snd.una.wrapping_add(snd.wnd).wrapping_sub(snd.nxt) as i32;
+        let cwnd = self.congestion.cwnd();
+        let wanted = (unsent as i32).min(self.mss as i32);
+
+        let mut limits = vec![];
+        if snd.wnd == 0 {
+            limits.push(Limit::PeerZeroWindow);
+        } else if unsent > 0 && usable < wanted {
+            limits.push(Limit::PeerWindow);
+        } else if unsent > 0 && (cwnd as i32).saturating_sub(in_flight as i32) < wanted {
+            limits.push(Limit::CongestionWindow);
+        }
+        if self.snd_buf.free(self.outgoing.len()) == 0 {
+            limits.push(Limit::SendBuffer);
+        } else if unsent == 0 {
+            limits.push(Limit::ApplicationWrite);
+        }
+        if unread > 0 && (self.rcv_buf.window(unread) as usize) < self.mss as usize {
+            limits.push(Limit::ApplicationRead);
+        }
+        if self.retransmit.rto() > INITIAL_RTO {
+            limits.push(Limit::RetransmissionBackoff);
+        }
+
+        Diagnosis {
+            peer_window: snd.wnd as u64,
+            in_flight: in_flight as u64,
+            cwnd: cwnd as u64,
+            ssthresh: self.congestion.ssthresh() as u64,
+            unsent: unsent as u64,
+            send_buffer: self.snd_buf.size() as u64,
+            window: rcv.wnd as u64,
+            unread: unread as u64,
+            receive_buffer: self.rcv_buf.size() as u64,
+            rto_ms: self.retransmit.rto().as_millis() as u64,
+            limits,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::diagnostics::Limit;
+    use crate::tcp::established;
+
+    #[test]
+    fn test_diagnose() {
+        let mut conn = established(1, 1000, 101);
+        assert_eq!(conn.diagnose().limits, vec![Limit::ApplicationWrite]);
+        // SND.UNA back at ISS after 4 GiB, the SYN isn't in flight
+        conn.state.snd.iss = conn.state.snd.una;
+        assert_eq!(conn.diagnose().in_flight, 0);
+
+        let mut conn = established(1, 0, 101);
+        conn.outgoing.extend(b"abc");
+        let diagnosis = conn.diagnose();
+        assert_eq!(diagnosis.limits, vec![Limit::PeerZeroWindow]);
+        assert_eq!(diagnosis.unsent, 3);
+
+        // 100 octets sent filling the peer window, more waiting
+        let mut conn = established(1, 100, 101);
+        conn.outgoing.extend(vec![0u8; 300]);
+        conn.state.snd.nxt = 101;
+        let diagnosis = conn.diagnose();
+        assert_eq!(diagnosis.limits, vec![Limit::PeerWindow]);
+        assert_eq!(diagnosis.in_flight, 100);
+        assert_eq!(diagnosis.unsent, 200);
+
+        let size = conn.snd_buf.size() as usize;
+        conn.outgoing.extend(vec![0u8; size - 300]);
+        assert_eq!(
+            conn.diagnose().limits,
+            vec![Limit::PeerWindow, Limit::SendBuffer]
+        );
+
+        // the peer window is open, the initial congestion window is full
+        let mut conn = established(1, 65535, 101);
+        conn.outgoing.extend(vec![0u8; 3000]);
+        conn.state.snd.nxt = 1 + 2144;
+        let diagnosis = conn.diagnose();
+        assert_eq!(diagnosis.limits, vec![Limit::CongestionWindow]);
+        assert_eq!(diagnosis.cwnd, 2144);
+    }
+}
@@ -0,0 +1,96 @@
+//! Explicit Congestion Notification, see https://www.ietf.org/rfc/rfc3168.txt. A peer whose SYN
+//! carries ECE and CWR gets ECE in the SYN-ACK, from then on our data goes out as ECN-capable and
+//! routers mark congestion with CE instead of dropping packets.
+//!
+//! As the receiver, a CE mark is echoed with ECE on every segment until the peer confirms with CWR
+//! that it reduced its rate. As the sender, an ECE halves the congestion window, at most once per
+//! window of data, and the next new data segment carries CWR.
+
+use crate::tcp::wrapping_lt;
+
+/// The ECN codepoints of the ip header
+pub const NOT_ECT: u8 = 0b00;
+pub const ECT_0: u8 = 0b10;
+pub const CE: u8 = 0b11;
+
+#[derive(Debug, Default)]
+pub struct Ecn {
+    /// A CE mark was received, ECE is set until the peer sends CWR
+    echo: bool,
+    /// The peer reported congestion, the next new data segment carries CWR
+    cwr_pending: bool,
+    /// SND.NXT when the window was last reduced, it's reduced again only once that is acknowledged
+    recover: Option<u32>,
+}
+
+impl Ecn {
+    /// Records the ECN codepoint and the CWR flag of a segment received, returns whether it was CE.
+    /// CWR goes first, so a CE mark on the CWR segment itself is echoed again. RFC 3168 6.1.3.
+    pub fn on_receive(&mut self, codepoint: u8, cwr: bool) -> bool {
+        if cwr {
+            self.echo = false;
+        }
+        let ce = codepoint == CE;
+        self.echo |= ce;
+        ce
+    }
+
+    /// Whether the segments sent carry ECE.
+    pub fn echo(&self) -> bool {
+        self.echo
+    }
+
+    /// Reacts to an ECE from the peer. Returns whether the congestion window is to be reduced, which
+    /// only happens once per window of data, RFC 3168 6.1.2.
+    pub fn on_ece(&mut self, una: u32, nxt: u32) -> bool {
+        if self
+            .recover
+            .is_some_and(|recover| wrapping_lt(una, recover))
+        {
+            return false;
+        }
+        self.recover = Some(nxt);
+        self.cwr_pending = true;
+        true
+    }
+
+    /// Whether the next new data segment carries CWR, only the first one after a reduction does.
+    pub fn take_cwr(&mut self) -> bool {
+        std::mem::take(&mut self.cwr_pending)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::ecn::{Ecn, CE, ECT_0};
+
+    #[test]
+    fn test_echo() {
+        let mut ecn = Ecn::default();
+        assert!(!ecn.on_receive(ECT_0, false));
+        assert!(!ecn.echo());
+        assert!(ecn.on_receive(CE, false));
+        ecn.on_receive(ECT_0, false);
+        assert!(ecn.echo());
+        // echoed until CWR, unless CWR comes marked again
+        ecn.on_receive(CE, true);
+        assert!(ecn.echo());
+        ecn.on_receive(ECT_0, true);
+        assert!(!ecn.echo());
+    }
+
+    #[test]
+    fn test_on_ece() {
+        let mut ecn = Ecn::default();
+        assert!(ecn.on_ece(1000, 11000));
+        assert!(ecn.take_cwr());
+        assert!(!ecn.take_cwr());
+
+        // once per window
+        assert!(!ecn.on_ece(5000, 15000));
+        assert!(!ecn.take_cwr());
+        assert!(ecn.on_ece(11000, 15000));
+        assert!(ecn.on_ece(15000, 16000));
+        assert!(ecn.take_cwr());
+    }
+}
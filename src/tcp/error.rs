@@ -0,0 +1,52 @@
+//! Why a packet couldn't be parsed or a handshake couldn't go on, for the caller to choose between
+//! dropping the segment, answering it with a RST or logging it, see https://www.ietf.org/rfc/rfc793.txt
+//! page 64 onwards for what RFC 793 does with each.
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum TcpError {
+    /// The packet isn't ipv4 carrying tcp
+    NotTcp,
+    /// The ip or the tcp header is truncated or malformed
+    Malformed(String),
+    /// The ip header or the segment doesn't match its checksum, naming which
+    BadChecksum(&'static str),
+    /// A segment in LISTEN without a SYN
+    NoSyn,
+    /// An ACK on a connection in LISTEN, to be answered with <SEQ=SEG.ACK><CTL=RST>
+    UnexpectedAck,
+    /// The segment completing a handshake has no ACK
+    NoAck,
+    /// The ACK completing a handshake doesn't acknowledge our SYN, to be answered with
+    /// <SEQ=SEG.ACK><CTL=RST>
+    UnacceptableAck,
+    /// The sequence number falls outside the receive window
+    OutOfWindow,
+    /// The peer aborted the handshake
+    RstReceived,
+    /// The MD5 signature or the TCP-AO MAC is missing, wrong or unexpected
+    BadSignature(String),
+    /// The answer couldn't be built or sent
+    Send(anyhow::Error),
+}
+
+impl fmt::Display for TcpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TcpError::NotTcp => write!(f, "not tcp protocol, skip"),
+            TcpError::Malformed(e) => write!(f, "malformed packet: {e:}"),
+            TcpError::BadChecksum(what) => write!(f, "bad {what:} checksum"),
+            TcpError::NoSyn => write!(f, "syn should be set, invalid payload"),
+            TcpError::UnexpectedAck => write!(f, "ack should not be set, invalid payload"),
+            TcpError::NoAck => write!(f, "no ack received"),
+            TcpError::UnacceptableAck => write!(f, "not valid ack for syn recv"),
+            TcpError::OutOfWindow => write!(f, "segment out of the receive window"),
+            TcpError::RstReceived => write!(f, "reset by the peer"),
+            TcpError::BadSignature(e) => write!(f, "{e:}"),
+            TcpError::Send(e) => write!(f, "sending failed: {e:}"),
+        }
+    }
+}
+
+impl std::error::Error for TcpError {}
@@ -0,0 +1,2145 @@
+//! Data transfer once the connection is synchronized. The incoming segment processing follows the
+//! SEGMENT ARRIVES event of https://www.ietf.org/rfc/rfc793.txt page 69.
+//!
+//! Nothing is sent while processing a segment or a write, both only record what is owed: an ACK in
+//! `ack_pending`, data in `outgoing`. The segments are built by `transmit` afterwards, so an owed ACK
+//! rides on the first data segment instead of going out as a bare ACK followed by the data. The data
+//! stays in `outgoing` until acknowledged, `on_timer` retransmits it when the RTO expires.
+//!
+//! Urgent data follows https://www.ietf.org/rfc/rfc6093.txt: the urgent pointer points to the octet
+//! following the urgent data, and like BSD sockets only the last urgent octet is pulled out of the stream
+//! and delivered out of band through `read_urgent`.
+//!
+//! Segment text beyond RCV.NXT waits in `reassembly` until the hole before it is filled. When SACK was
+//! negotiated the blocks queued there are reported to the peer, and the blocks the peer reports spare
+//! the retransmission of what it already holds, see https://www.ietf.org/rfc/rfc2018.txt. Duplicate
+//! segment text is reported in a D-SACK block, and the peer's D-SACKs reveal our spurious
+//! retransmissions, see https://www.ietf.org/rfc/rfc2883.txt.
+//!
+//! The RSTs and SYNs in the window, and the ACKs too old to be for any data we sent, are answered with a
+//! challenge ACK and dropped, only a RST at exactly RCV.NXT resetting the connection, so a blind attacker
+//! has to guess the sequence numbers exactly, see https://www.ietf.org/rfc/rfc5961.txt. The challenge
+//! ACKs are rate limited by the stack, see `take_challenge_ack`.
+//!
+//! With the Timestamps option, segments failing PAWS are dropped before anything else, see `timestamps`,
+//! except for those without the signature of a connection with an MD5 key or TCP-AO MKTs, see `md5`
+//! and `ao`.
+
+use crate::core::segment::{check_control, check_sequence, Acceptance, Verdict};
+use crate::device::NetworkDevice;
+use crate::ip::DSCP_MAX;
+use crate::stats::Stats;
+use crate::tcp::close::CloseState;
+use crate::tcp::ecn::{ECT_0, NOT_ECT};
+use crate::tcp::markers::AckedMarker;
+use crate::tcp::options::{self, CustomOption, TcpOption};
+use crate::tcp::reassembly::MAX_SACK_BLOCKS;
+use crate::tcp::retransmit::Sent;
+use crate::tcp::sockopt::{KeepAliveAction, OptionName, SocketOption};
+use crate::tcp::state::Established;
+use crate::tcp::timestamps::Timestamps;
+use crate::tcp::timewait::TimeWait;
+use crate::tcp::{
+    arriving, send_segment_with, update_send_window, wrapping_lt, Connection, ReceiveSequenceSpace,
+    MAX_OPTIONS_LEN,
+};
+use crate::wire::SegmentView;
+use anyhow::{anyhow, Result};
+use etherparse::TcpHeader;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::io::{self, IoSlice};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// The peer acknowledged data not sent yet, the connection is reset when `optimistic_ack_reset` is set.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct OptimisticAck {
+    pub ack: u32,
+    /// SND.NXT when the ACK arrived
+    pub nxt: u32,
+}
+
+impl Display for OptimisticAck {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ack {:} of data not sent, SND.NXT {:}",
+            self.ack, self.nxt
+        )
+    }
+}
+
+impl std::error::Error for OptimisticAck {}
+
+impl Connection<Established> {
+    /// Processes a segment arriving on the established connection, what needs to be sent in response is
+    /// left to `transmit`. An error means the connection can not continue, e.g. it was reset by the peer,
+    /// and should be dropped.
+    pub fn on_segment(&mut self, segment: &SegmentView, stats: &mut Stats) -> Result<()> {
+        let (seg, data) = (&segment.tcp, segment.payload);
+        // a segment without the right signature is dropped before anything else looks at it, even a
+        // RST, https://www.ietf.org/rfc/rfc2385.txt section 2.0
+        if let Some(ao) = self.ao.as_mut() {
+            ao.advance(self.state.snd.una, self.state.rcv.nxt);
+        }
+        if !self.verify_signature(segment) {
+            if self.ao.is_some() {
+                stats.ao_rejected += 1;
+            } else {
+                stats.md5_rejected += 1;
+            }
+            log::debug!("segment {:} with a bad signature", seg.sequence_number());
+            return Ok(());
+        }
+        let now = self.clock.now();
+        let timestamps = segment.timestamps();
+        // PAWS goes before the sequence number check, https://www.ietf.org/rfc/rfc7323.txt 5.3
+        if let (Some(ts), false) = (self.timestamps.as_ref(), seg.rst()) {
+            match timestamps {
+                Some((tsval, _)) if ts.is_old(tsval, now) => {
+                    stats.paws_rejected += 1;
+                    log::debug!(
+                        "old duplicate {:} rejected, tsval {tsval:}",
+                        seg.sequence_number()
+                    );
+                    if seg.syn() {
+                        self.challenge_ack = true;
+                    } else {
+                        self.ack_pending = true;
+                    }
+                    return Ok(());
+                }
+                // once negotiated, a non-RST segment without the option should be silently dropped,
+                // https://www.ietf.org/rfc/rfc7323.txt 3.2, but for a SYN, always challenged
+                None => {
+                    self.challenge_ack |= seg.syn();
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+
+        // first check sequence number
+        let header = arriving(seg, data);
+        match check_sequence(&self.state.rcv, &header) {
+            Acceptance::Accept => {}
+            Acceptance::Ack => {
+                self.on_duplicate(header.seq, data.len());
+                self.ack_pending = true;
+                return Ok(());
+            }
+            Acceptance::Challenge => {
+                log::debug!("syn {:} out of the window challenged", header.seq);
+                self.challenge_ack = true;
+                return Ok(());
+            }
+            Acceptance::Drop => return Ok(()),
+        }
+        if let (Some(ts), Some((tsval, _))) = (self.timestamps.as_mut(), timestamps) {
+            ts.on_segment(seg.sequence_number(), tsval, now);
+        }
+        self.socket_options.on_heard(now);
+        let ip = &segment.ip;
+        self.socket_options.on_tos(ip.dcp() << 2 | ip.ecn());
+        self.on_custom_options(segment);
+        if let Some(ecn) = self.ecn.as_mut() {
+            if ecn.on_receive(segment.ip.ecn(), seg.cwr()) {
+                stats.ecn_ce_received += 1;
+                self.ack_pending = true;
+            }
+        }
+
+        // second check the RST bit, fourth the SYN bit, fifth the ACK field
+        self.max_snd_wnd = self.max_snd_wnd.max(self.state.snd.wnd);
+        let verdict = check_control(&self.state.snd, &self.state.rcv, self.max_snd_wnd, &header);
+        let ack = seg.acknowledgment_number();
+        match verdict {
+            Verdict::Reset => return Err(anyhow!("connection reset by peer")),
+            Verdict::Challenge => {
+                let kind = if header.rst {
+                    "rst"
+                } else if header.syn {
+                    "syn"
+                } else {
+                    "ack"
+                };
+                log::debug!("{kind:} {:} challenged", header.seq);
+                self.challenge_ack = true;
+                return Ok(());
+            }
+            Verdict::Drop => return Ok(()),
+            Verdict::Unsent => {
+                // A peer doing so is either broken or acknowledging optimistically to make us send
+                // faster, the segment never advances any state.
+                stats.optimistic_acks += 1;
+                let nxt = self.state.snd.nxt;
+                log::debug!("ack {ack:} beyond SND.NXT {nxt:}");
+                if self.reset_optimistic_ack {
+                    return Err(OptimisticAck { ack, nxt }.into());
+                }
+                self.ack_pending = true;
+                return Ok(());
+            }
+            Verdict::Old | Verdict::Acked(_) => {}
+        }
+        if let Verdict::Acked(acked) = verdict {
+            // If SND.UNA < SEG.ACK =< SND.NXT then, set SND.UNA <- SEG.ACK. Any segments on the
+            // retransmission queue which are thereby entirely acknowledged are removed.
+            let acked = acked as usize;
+            self.outgoing.drain(..acked.min(self.outgoing.len()));
+            self.state.snd.una = ack;
+            self.close.on_ack(ack, now);
+            self.socket_options
+                .on_ack(self.state.snd.una != self.state.snd.nxt, now);
+            let freed = self.retransmit.on_ack(ack, now);
+            log::debug!(
+                "{acked:} bytes acknowledged, {:} segments freed",
+                freed.segments
+            );
+            self.markers.on_ack(ack, now);
+            if freed.divided {
+                stats.ack_divisions += 1;
+                log::debug!("ack {ack:} divides a segment");
+            }
+            // the timestamps measure every ACK of new data, the retransmission queue only the
+            // segments sent once
+            let rtt = match (self.timestamps.as_ref(), timestamps) {
+                (Some(ts), Some((_, tsecr))) => ts.rtt(tsecr, now),
+                _ => freed.rtt,
+            };
+            if let Some(rtt) = rtt {
+                self.congestion.on_rtt(rtt, now);
+            }
+            if let Some(sample) = freed.rate.as_ref() {
+                let in_flight = self.state.snd.nxt.wrapping_sub(ack);
+                self.congestion
+                    .on_delivery(sample, in_flight, self.mss as u32, now);
+            }
+            if self.snd_buf.on_ack(freed.bytes as usize, rtt, now) {
+                log::debug!("send buffer grown to {:}", self.snd_buf.size());
+            }
+            let flight = self.state.snd.nxt.wrapping_sub(ack) + acked as u32;
+            self.congestion.on_flight(flight, now);
+            // Eifel, the retransmission the ACK would be for wasn't needed, RFC 3522
+            let eifel = match (self.timestamps.as_mut(), timestamps) {
+                (Some(ts), Some((_, tsecr))) => ts.is_spurious(tsecr),
+                _ => false,
+            };
+            if eifel && self.congestion.undo() {
+                stats.spurious_recoveries += 1;
+                log::debug!(
+                    "ack {ack:} is for the original transmission, cwnd restored to {:}",
+                    self.congestion.cwnd()
+                );
+            }
+            if self
+                .congestion
+                .on_ack(ack, acked as u32, self.mss as u32, now)
+            {
+                stats.spurious_timeouts += 1;
+                log::debug!(
+                    "retransmission timeout was spurious, cwnd restored to {:}",
+                    self.congestion.cwnd()
+                );
+            }
+            if self.outgoing.is_empty() {
+                // an idle connection doesn't keep the memory of its last burst
+                self.outgoing.shrink_to(self.mss as usize);
+            }
+            if self.state.snd.up && !wrapping_lt(self.state.snd.una, self.state.snd.up_seq) {
+                self.state.snd.up = false;
+            }
+        }
+        // the peer echoes the congestion marked on our data, RFC 3168 6.1.2
+        if let (Some(ecn), true) = (self.ecn.as_mut(), seg.ece()) {
+            let snd = &self.state.snd;
+            if ecn.on_ece(snd.una, snd.nxt) {
+                let in_flight = snd.nxt.wrapping_sub(snd.una);
+                self.congestion.on_congestion(in_flight, self.mss as u32);
+                stats.ecn_reductions += 1;
+                log::debug!(
+                    "congestion reported, cwnd reduced to {:}",
+                    self.congestion.cwnd()
+                );
+            }
+        }
+        // the SACK blocks are looked at on any ACK, duplicate ones included, a run of duplicate ACKs is
+        // precisely when the peer reports the holes
+        if self.sack {
+            let blocks = segment.sack_blocks();
+            // a first block below SEG.ACK or within the second block is a D-SACK, see
+            // https://www.ietf.org/rfc/rfc2883.txt section 4
+            if let Some(&(left, right)) = blocks.first() {
+                let within = |&(l, r): &(u32, u32)| !wrapping_lt(left, l) && !wrapping_lt(r, right);
+                let dsack = !wrapping_lt(ack, right) || blocks.get(1).is_some_and(within);
+                if dsack && self.retransmit.on_dsack(left, right) {
+                    stats.spurious_retransmissions += 1;
+                    log::debug!(
+                        "retransmission of {left:}..{right:} was spurious, rto restored to {:?}",
+                        self.retransmit.rto()
+                    );
+                }
+            }
+            if !blocks.is_empty() {
+                log::debug!("peer holds {blocks:?}");
+                self.retransmit.on_sack(&blocks, now);
+            }
+        }
+        self.detect_loss(now, stats);
+        // an ACK of nothing new, without data nor a window update, means a segment arrived beyond a hole,
+        // https://www.ietf.org/rfc/rfc5681.txt section 2
+        let snd = &self.state.snd;
+        let duplicate = ack == snd.una
+            && snd.nxt != snd.una
+            && data.is_empty()
+            && !seg.fin()
+            && (seg.window_size() as u32) << snd.wnd_shift == snd.wnd;
+        if duplicate
+            && self
+                .congestion
+                .on_dup_ack(snd.una, snd.nxt, self.mss as u32)
+        {
+            stats.fast_retransmissions += 1;
+            log::debug!(
+                "third duplicate ack {ack:}, fast retransmit, cwnd {:}",
+                self.congestion.cwnd()
+            );
+        }
+        // If the ACK is a duplicate (SEG.ACK < SND.UNA), it can be ignored.
+        if update_send_window(
+            &mut self.state.snd,
+            seg.sequence_number(),
+            seg.acknowledgment_number(),
+            seg.window_size(),
+        ) {
+            log::debug!("send window updated to {:}", self.state.snd.wnd);
+        }
+
+        // sixth, check the URG bit: RCV.UP <- max(RCV.UP, SEG.UP)
+        if seg.urg() {
+            let up_seq = seg
+                .sequence_number()
+                .wrapping_add(seg.urgent_pointer() as u32);
+            let rcv = &mut self.state.rcv;
+            // an urgent pointer already passed by RCV.NXT has nothing left to deliver
+            if wrapping_lt(rcv.nxt, up_seq) && (!rcv.up || wrapping_lt(rcv.up_seq, up_seq)) {
+                log::debug!("urgent data signalled up to {up_seq:}");
+                rcv.up = true;
+                rcv.up_seq = up_seq;
+            }
+        }
+
+        // seventh, process the segment text, within the window and the memory left for all the
+        // receive buffers
+        let seq = seg.sequence_number();
+        self.on_duplicate(seq, data.len());
+        let budget = self.rcv_buf.budget();
+        let wnd = self.state.rcv.wnd as usize;
+        let limit = wnd.min(budget);
+        let accepted = if wrapping_lt(self.state.rcv.nxt, seq) {
+            // out of order, queued up to the right edge of the window
+            let offset = seq.wrapping_sub(self.state.rcv.nxt) as usize;
+            let room = wnd.saturating_sub(offset).min(budget);
+            let queued = self.reassembly.insert(seq, &data[..data.len().min(room)]);
+            self.rcv_buf.on_buffer(queued);
+            queued
+        } else {
+            let delivered = deliver(
+                &mut self.state.rcv,
+                &mut self.incoming,
+                &mut self.urgent,
+                seq,
+                data,
+                limit,
+            );
+            self.rcv_buf.on_buffer(delivered);
+            delivered
+        };
+        if budget < wnd && accepted == budget && !data.is_empty() {
+            stats.rcv_mem_declined += 1;
+            log::debug!("receive memory exhausted, {accepted:} bytes accepted");
+        }
+        // the segment might have filled the hole before the queued data, which was accounted in the
+        // receive memory when queued
+        let nxt = self.state.rcv.nxt;
+        while let Some((queued, discarded)) = self.reassembly.pop(self.state.rcv.nxt) {
+            self.rcv_buf.release(discarded);
+            let rcv = &mut self.state.rcv;
+            deliver(
+                rcv,
+                &mut self.incoming,
+                &mut self.urgent,
+                rcv.nxt,
+                &queued,
+                usize::MAX,
+            );
+        }
+        if self.state.rcv.nxt != nxt {
+            log::debug!(
+                "delivered up to {:} from the reassembly queue",
+                self.state.rcv.nxt
+            );
+        }
+        if accepted > 0 {
+            self.rcv_buf
+                .on_receive(self.state.rcv.nxt, self.clock.now());
+        }
+        if !data.is_empty() {
+            // out of order data is acknowledged right away too, the duplicate ACK tells the peer about
+            // the hole, see https://www.ietf.org/rfc/rfc5681.txt 4.2
+            log::debug!("received {:} bytes, {accepted:} new", data.len());
+            self.ack_pending = true;
+        }
+
+        // eighth, check the FIN bit, taken once the data before it was all received, until then the
+        // peer retransmits it
+        let fin_seq = seq.wrapping_add(data.len() as u32);
+        if seg.fin() && fin_seq == self.state.rcv.nxt && !self.close.has_peer_fin() {
+            self.state.rcv.nxt = fin_seq.wrapping_add(1);
+            self.close.on_peer_fin(now);
+            self.ack_pending = true;
+            log::debug!("fin received, {:}", self.close.state().name());
+        }
+
+        Ok(())
+    }
+
+    /// Records the part of the segment text of `len` octets from `seq` received before, either in
+    /// order or queued, to be reported in a D-SACK block.
+    fn on_duplicate(&mut self, seq: u32, len: usize) {
+        if !self.sack || len == 0 {
+            return;
+        }
+        let end = seq.wrapping_add(len as u32);
+        let nxt = self.state.rcv.nxt;
+        let duplicate = if wrapping_lt(seq, nxt) {
+            Some((seq, if wrapping_lt(nxt, end) { nxt } else { end }))
+        } else {
+            self.reassembly.overlap(seq, end)
+        };
+        if let Some((left, right)) = duplicate {
+            log::debug!("duplicate {left:}..{right:} received");
+            self.reassembly.on_duplicate(left, right);
+        }
+    }
+
+    /// Reads the in order data received so far, returns the number of bytes copied into `buf`.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = drain_into(&mut self.incoming, buf);
+        self.on_read(n);
+        n
+    }
+
+    /// Copies the in order data received so far into `buf` without consuming it, like `MSG_PEEK`: the
+    /// next `read` returns the same data. Returns the number of bytes copied.
+    pub fn peek(&self, buf: &mut [u8]) -> usize {
+        let n = self.incoming.len().min(buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.incoming.iter()) {
+            *dst = *src;
+        }
+        n
+    }
+
+    /// The bytes received in order and not read yet, like `FIONREAD`.
+    pub fn available(&self) -> usize {
+        self.incoming.len()
+    }
+
+    /// The bytes the send buffer takes before a write is cut short.
+    pub fn send_room(&self) -> usize {
+        self.snd_buf.free(self.outgoing.len())
+    }
+
+    /// Reads the urgent octets received out of band, returns the number of bytes copied into `buf`.
+    pub fn read_urgent(&mut self, buf: &mut [u8]) -> usize {
+        let n = drain_into(&mut self.urgent, buf);
+        self.on_read(n);
+        n
+    }
+
+    fn on_read(&mut self, n: usize) {
+        self.bytes_read += n as u64;
+        if n > 0 && self.rcv_buf.on_read(n, self.clock.now()) {
+            log::debug!("receive buffer grown to {:}", self.rcv_buf.size());
+        }
+        // the peer is held back by a window too small for a full segment, it learns about the space
+        // the read made with a window update
+        if self.state.rcv.wnd < self.mss as u32 && self.receive_window().is_some() {
+            self.ack_pending = true;
+        }
+    }
+
+    /// The window to advertise when it has to change, the free space of the receive buffer. Unread data
+    /// shrinks it, so a slow reader throttles the peer.
+    ///
+    /// To avoid the silly window syndrome the right edge is only moved forward by at least
+    /// min(MSS, buffer / 2), see https://www.ietf.org/rfc/rfc1122.txt 4.2.3.3:
+    ///     if (RCV.BUFF - RCV.USER - RCV.WND) >= min(Fr * RCV.BUFF, Eff.snd.MSS)
+    ///     then set RCV.WND to RCV.BUFF - RCV.USER
+    /// It's only moved backwards when the receive memory of the process runs out.
+    ///
+    /// With window scaling the window is rounded down to a multiple of the scale, which is all the peer
+    /// gets to see.
+    fn receive_window(&self) -> Option<u32> {
+        let shift = self.state.rcv.wnd_shift;
+        let free = self.rcv_buf.window(self.incoming.len() + self.urgent.len());
+        let free = free.min((u16::MAX as u32) << shift) >> shift << shift;
+        let wnd = self.state.rcv.wnd;
+        let threshold = (self.rcv_buf.size() / 2).min(self.mss as u32);
+        if free < wnd || free - wnd >= threshold {
+            return (free != wnd).then_some(free);
+        }
+        None
+    }
+
+    /// Whether the peer has signalled urgent data, either already in `read_urgent` or still to arrive.
+    pub fn has_urgent(&self) -> bool {
+        !self.urgent.is_empty() || self.state.rcv.up
+    }
+
+    /// Queues `data` to be sent by the next `transmit` as far as the send buffer allows, returns the
+    /// number of bytes queued.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        if self.close.is_closed() {
+            return 0;
+        }
+        let n = data.len().min(self.snd_buf.free(self.outgoing.len()));
+        if n < data.len() {
+            self.snd_buf.on_write_limited();
+        }
+        self.outgoing.extend(&data[..n]);
+        n
+    }
+
+    /// Queues the slices one after the other as far as the send buffer allows, without concatenating
+    /// them first. Returns the number of bytes queued.
+    pub fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> usize {
+        let mut n = 0;
+        for buf in bufs {
+            let written = self.write(buf);
+            n += written;
+            if written < buf.len() {
+                break;
+            }
+        }
+        n
+    }
+
+    /// Queues `data` as urgent data: the segments up to the end of what was queued carry URG with the
+    /// urgent pointer set to the octet following it, see https://www.ietf.org/rfc/rfc6093.txt.
+    /// Returns the number of bytes queued.
+    pub fn write_urgent(&mut self, data: &[u8]) -> usize {
+        let n = self.write(data);
+        if n == 0 {
+            return 0;
+        }
+        // SND.UP <- SND.UNA + the octets queued from SND.UNA
+        self.state.snd.up = true;
+        self.state.snd.up_seq = self.state.snd.una.wrapping_add(self.outgoing.len() as u32);
+        n
+    }
+
+    /// Like `read`, but instead of returning 0 when nothing was received fails with `WouldBlock`, or
+    /// with `TimedOut` once nothing was received for the read timeout. Returns 0 at the end of the
+    /// stream, once the peer closed its side.
+    pub fn try_read(&mut self, buf: &mut [u8], now: Instant) -> io::Result<usize> {
+        if buf.is_empty() || self.is_read_closed() {
+            return Ok(0);
+        }
+        let n = self.read(buf);
+        self.timeouts.on_read(n, now)
+    }
+
+    /// Whether a segment was challenged since the last call, see `on_segment`. The stack then owes the
+    /// peer a challenge ACK, `ack_now` sending it.
+    pub fn take_challenge_ack(&mut self) -> bool {
+        std::mem::take(&mut self.challenge_ack)
+    }
+
+    /// Sends an ACK with the next segment, or alone.
+    pub fn ack_now(&mut self) {
+        self.ack_pending = true;
+    }
+
+    /// Whether the peer closed its side and everything it sent before was read.
+    pub fn is_read_closed(&self) -> bool {
+        self.close.has_peer_fin() && self.incoming.is_empty()
+    }
+
+    /// Like `write`, but instead of returning 0 when the send buffer is full fails with `WouldBlock`,
+    /// or with `TimedOut` once the send buffer stayed full for the write timeout. Fails with
+    /// `BrokenPipe` once the connection is closed.
+    pub fn try_write(&mut self, data: &[u8], now: Instant) -> io::Result<usize> {
+        if self.close.is_closed() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let n = self.write(data);
+        self.timeouts.on_write(n, now)
+    }
+
+    /// Sets how long `try_read` waits for data before failing with `TimedOut`, `None` waits forever.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeouts.set_read(timeout)
+    }
+
+    /// Sets how long `try_write` waits for room in the send buffer before failing with `TimedOut`,
+    /// `None` waits forever.
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeouts.set_write(timeout)
+    }
+
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.timeouts.read()
+    }
+
+    pub fn write_timeout(&self) -> Option<Duration> {
+        self.timeouts.write()
+    }
+
+    /// When `try_read` fails with `TimedOut` if nothing arrives until then, see `Timeouts`.
+    pub fn read_deadline(&self) -> Option<Instant> {
+        self.timeouts.read_deadline()
+    }
+
+    /// When `try_write` fails with `TimedOut` if the send buffer stays full until then.
+    pub fn write_deadline(&self) -> Option<Instant> {
+        self.timeouts.write_deadline()
+    }
+
+    /// Sets a socket option, see `sockopt`. The sizes of the buffers are kept within the min and max of
+    /// the stack, and the receive buffer doesn't take back the window advertised already. Growing the
+    /// send buffer fails with `OutOfMemory` when the send buffers of the stack are full.
+    pub fn set_option(&mut self, option: SocketOption) -> io::Result<()> {
+        let options = &mut self.socket_options;
+        match option {
+            SocketOption::NoDelay(nodelay) => options.set_nodelay(nodelay),
+            SocketOption::KeepAlive(keepalive) => {
+                options.set_keepalive(keepalive, self.clock.now())
+            }
+            SocketOption::Linger(linger) => options.set_linger(linger),
+            SocketOption::UserTimeout(timeout) => options.set_user_timeout(timeout),
+            SocketOption::IpOptions(ip_options) => options.set_ip_options(ip_options),
+            SocketOption::DontFragment(dont_fragment) => options.set_dont_fragment(dont_fragment),
+            SocketOption::Ttl(0) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "ttl 0"));
+            }
+            SocketOption::Ttl(ttl) => options.set_ttl(ttl),
+            SocketOption::Dscp(dscp) if dscp > DSCP_MAX => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("dscp {dscp:} exceeds {DSCP_MAX:}"),
+                ));
+            }
+            SocketOption::Dscp(dscp) => options.set_dscp(dscp),
+            SocketOption::RecvBuffer(size) => {
+                let buffered = (self.incoming.len() + self.urgent.len()) as u32;
+                let size = self.rcv_buf.set_size(size, buffered + self.state.rcv.wnd);
+                log::debug!("receive buffer set to {size:}");
+                // a larger window is advertised right away
+                if self.receive_window().is_some() {
+                    self.ack_pending = true;
+                }
+            }
+            SocketOption::SendBuffer(size) => {
+                if !self.snd_buf.set_size(size) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::OutOfMemory,
+                        "the send buffers of the stack are full",
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The current value of the socket option `name`.
+    pub fn option(&self, name: OptionName) -> SocketOption {
+        let options = &self.socket_options;
+        match name {
+            OptionName::NoDelay => SocketOption::NoDelay(options.nodelay()),
+            OptionName::KeepAlive => SocketOption::KeepAlive(options.keepalive()),
+            OptionName::Linger => SocketOption::Linger(options.linger()),
+            OptionName::RecvBuffer => SocketOption::RecvBuffer(self.rcv_buf.size()),
+            OptionName::SendBuffer => SocketOption::SendBuffer(self.snd_buf.size()),
+            OptionName::UserTimeout => SocketOption::UserTimeout(options.user_timeout()),
+            OptionName::IpOptions => SocketOption::IpOptions(options.ip_options()),
+            OptionName::DontFragment => SocketOption::DontFragment(options.dont_fragment()),
+            OptionName::Ttl => SocketOption::Ttl(options.ttl()),
+            OptionName::Dscp => SocketOption::Dscp(options.dscp()),
+        }
+    }
+
+    /// Places `marker` right after the data written so far, it's reported by `acked_marker` once the
+    /// peer acknowledged all that data.
+    pub fn mark(&mut self, marker: u64) {
+        let seq = self.state.snd.una.wrapping_add(self.outgoing.len() as u32);
+        self.markers
+            .place(marker, seq, self.state.snd.una, self.clock.now());
+    }
+
+    /// The next marker whose data was acknowledged, with the latency from the marker being placed.
+    pub fn acked_marker(&mut self) -> Option<AckedMarker> {
+        self.markers.next_acked()
+    }
+
+    /// The transmit scheduler, sends the queued data as far as the peer and congestion windows allow,
+    /// and no faster than the pacing rate and the rate limit, if any. An owed ACK is carried by the
+    /// first data segment, a bare ACK is only sent when there is no data to carry it.
+    pub fn transmit(&mut self, nic: &dyn NetworkDevice) -> Result<()> {
+        let now = self.clock.now();
+        if self.congestion.validate(now, self.mss as u32) {
+            log::debug!(
+                "congestion window unused for long, reduced to {:}",
+                self.congestion.cwnd()
+            );
+        }
+        if let Some(bucket) = self.rate_limit.as_mut() {
+            bucket.refill(now);
+        }
+        // the window is the free space of the receive buffer, advertised by every segment sent
+        if let Some(wnd) = self.receive_window() {
+            self.state.rcv.wnd = wnd;
+        }
+        if self.ack_pending || self.next_segment().is_some() {
+            self.rcv_buf
+                .on_advertise(self.state.rcv.nxt, self.state.rcv.wnd, now);
+        }
+        // the segment the duplicate ACKs report lost goes first
+        if self.congestion.take_retransmit() {
+            if let Some(sent) = self.retransmit.on_fast_retransmit(now) {
+                log::debug!("fast retransmit of {:}", sent.seq);
+                self.resend(nic, &sent)?;
+            }
+        }
+        while let Some(sent) = self.retransmit.next_lost(now) {
+            log::debug!("retransmit of {:}, deemed lost", sent.seq);
+            self.resend(nic, &sent)?;
+        }
+        while let Some((mut header, payload)) = self.next_segment().filter(|(_, payload)| {
+            self.congestion.can_send(now)
+                && self
+                    .rate_limit
+                    .as_ref()
+                    .is_none_or(|bucket| bucket.can_send(payload.len()))
+        }) {
+            let seq = header.sequence_number;
+            let len = payload.len() as u32;
+            // new data is ECN-capable, retransmissions and bare ACKs are not, RFC 3168 6.1.5 and 6.1.4
+            let ecn = match self.ecn.as_mut() {
+                Some(ecn) => {
+                    header.cwr = ecn.take_cwr();
+                    ECT_0
+                }
+                None => NOT_ECT,
+            };
+            let payload = slices(&self.outgoing, payload);
+            self.send(nic, header, &payload, ecn)?;
+            self.state.snd.nxt = seq.wrapping_add(len);
+            self.retransmit.on_send(seq, len, now);
+            self.congestion.on_send(len, now);
+            self.socket_options.on_send(now);
+            if let Some(bucket) = self.rate_limit.as_mut() {
+                bucket.on_send(len as usize);
+            }
+            self.on_sent();
+            self.ack_pending = false;
+        }
+        let snd = &self.state.snd;
+        let unsent = self
+            .outgoing
+            .len()
+            .saturating_sub(snd.nxt.wrapping_sub(snd.una) as usize);
+        if self.close.is_fin_due(unsent) {
+            // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=FIN,ACK>, retransmitted like data
+            let seq = snd.nxt;
+            let mut header = self.header(seq);
+            header.fin = true;
+            self.send(nic, header, &[], NOT_ECT)?;
+            self.state.snd.nxt = seq.wrapping_add(1);
+            self.retransmit.on_send(seq, 1, now);
+            self.socket_options.on_send(now);
+            self.close.on_fin_sent(seq);
+            log::debug!("fin sent, {:}", self.close.state().name());
+            self.on_sent();
+            self.ack_pending = false;
+        }
+
+        if self.ack_pending {
+            // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
+            self.send(nic, self.header(self.state.snd.nxt), &[], NOT_ECT)?;
+            self.on_sent();
+            self.ack_pending = false;
+        }
+        Ok(())
+    }
+
+    /// Retransmits the earliest segment not acknowledged once the retransmission timer expires, the
+    /// timer is backed off. See https://www.ietf.org/rfc/rfc6298.txt 5.4 to 5.6. The segments the
+    /// pacing or the rate limit held back are sent as well once their time came, and those RACK deems
+    /// lost once the reordering window is over.
+    pub fn on_timer(
+        &mut self,
+        nic: &dyn NetworkDevice,
+        now: Instant,
+        stats: &mut Stats,
+    ) -> Result<()> {
+        if self.close.is_linger_expired(now) {
+            self.reset(nic)?;
+            return Err(anyhow!("linger time over, data unacknowledged"));
+        }
+        if self.keepalive_deadline().is_some_and(|at| at <= now) {
+            self.on_keepalive(nic, now)?;
+        }
+        let mut due = self.send_deadline().is_some_and(|at| at <= now);
+        if self.retransmit.reo_deadline().is_some_and(|at| at <= now) {
+            due |= self.detect_loss(now, stats);
+        }
+        if due {
+            self.transmit(nic)?;
+        }
+        if !self.retransmit.is_expired(now) {
+            return Ok(());
+        }
+        let sent = match self.retransmit.on_timeout(now) {
+            Some(sent) => sent,
+            None => return Ok(()),
+        };
+        let snd = &self.state.snd;
+        self.congestion
+            .on_timeout(snd.una, snd.nxt, self.mss as u32);
+        log::debug!(
+            "retransmission timeout, rto backed off to {:?}, cwnd {:}",
+            self.retransmit.rto(),
+            self.congestion.cwnd()
+        );
+        self.resend(nic, &sent)?;
+        stats.retransmissions += 1;
+        Ok(())
+    }
+
+    /// When the pacing and the rate limit let the next segment out, none when nothing waits for it.
+    pub fn send_deadline(&self) -> Option<Instant> {
+        let (_, payload) = self.next_segment()?;
+        let limit = self
+            .rate_limit
+            .as_ref()
+            .and_then(|bucket| bucket.send_at(payload.len()));
+        self.congestion.send_at().max(limit)
+    }
+
+    /// When the retransmission timer expires, none while nothing is in flight.
+    pub fn rto_deadline(&self) -> Option<Instant> {
+        self.retransmit.deadline()
+    }
+
+    /// When the next timer of the connection is due, the pacing, the rate limit or the reordering window
+    /// of RACK, none when no such timer is armed. The retransmission timer is apart, see `rto_deadline`.
+    pub fn timer_deadline(&self) -> Option<Instant> {
+        match (self.send_deadline(), self.retransmit.reo_deadline()) {
+            (Some(send), Some(reo)) => Some(send.min(reo)),
+            (send, reo) => send.or(reo),
+        }
+    }
+
+    /// Closes the connection: the data written so far is sent, followed by the FIN. Further writes fail.
+    /// The connection is reset if the FIN isn't acknowledged within the `Linger` time, a linger of 0 is
+    /// an abort left to the caller, see `Stack::close`.
+    pub fn close(&mut self) {
+        let linger = self.socket_options.linger();
+        self.close
+            .on_close(linger.map(|linger| self.clock.now() + linger));
+    }
+
+    /// Whether the application closed the connection, writes fail.
+    pub fn is_write_closed(&self) -> bool {
+        self.close.is_closed()
+    }
+
+    /// Whether the FIN and the data before it were acknowledged.
+    pub fn is_flushed(&self) -> bool {
+        self.close.is_fin_acked()
+    }
+
+    pub fn close_state(&self) -> CloseState {
+        self.close.state()
+    }
+
+    /// What a SYN reopening the connection is checked against while it's in TIME-WAIT, see
+    /// `timewait`. None in any other state.
+    pub fn time_wait(&self) -> Option<TimeWait> {
+        let since = self.close.time_wait_since()?;
+        let ts_recent = self.timestamps.as_ref().map(Timestamps::recent);
+        Some(TimeWait::new(
+            self.state.rcv.nxt,
+            ts_recent,
+            since,
+            self.close.msl(),
+        ))
+    }
+
+    /// Whether nothing is left of the connection, it's to be dropped.
+    pub fn is_done(&self) -> bool {
+        self.close.is_done(self.clock.now())
+    }
+
+    /// When the linger time or TIME-WAIT is over, see `close`.
+    pub fn close_deadline(&self) -> Option<Instant> {
+        self.close.deadline()
+    }
+
+    /// When the keep-alive timer expires, which also drops the connection once the user timeout is
+    /// over. None when neither is armed.
+    pub fn keepalive_deadline(&self) -> Option<Instant> {
+        let options = &self.socket_options;
+        match (
+            options.keepalive_deadline(),
+            options.user_timeout_deadline(),
+        ) {
+            (Some(keepalive), Some(user)) => Some(keepalive.min(user)),
+            (keepalive, user) => keepalive.or(user),
+        }
+    }
+
+    /// Drops the connection once the user timeout is over or the keep-alive probes went unanswered,
+    /// probes it otherwise. The probe is an ACK of SND.UNA - 1, out of the window so the peer answers
+    /// it, https://www.ietf.org/rfc/rfc1122.txt 4.2.3.6.
+    fn on_keepalive(&mut self, nic: &dyn NetworkDevice, now: Instant) -> Result<()> {
+        if let Some(at) = self.socket_options.user_timeout_deadline() {
+            if at <= now {
+                return Err(anyhow!("connection timed out, data unacknowledged"));
+            }
+        }
+        if self
+            .socket_options
+            .keepalive_deadline()
+            .is_none_or(|at| at > now)
+        {
+            return Ok(());
+        }
+        match self.socket_options.on_keepalive() {
+            KeepAliveAction::Probe => {
+                log::debug!("keep-alive probe");
+                let seq = self.state.snd.una.wrapping_sub(1);
+                self.send(nic, self.header(seq), &[], NOT_ECT)?;
+                self.on_sent();
+                Ok(())
+            }
+            KeepAliveAction::Drop => Err(anyhow!("connection timed out, keep-alive unanswered")),
+        }
+    }
+
+    /// Marks lost the segments RACK deems lost, the first losses since the last recovery start a new
+    /// one. Returns whether any was.
+    fn detect_loss(&mut self, now: Instant, stats: &mut Stats) -> bool {
+        let lost = self.retransmit.detect_loss(now);
+        if lost == 0 {
+            return false;
+        }
+        stats.rack_losses += lost as u64;
+        let snd = &self.state.snd;
+        if self.congestion.on_lost(snd.una, snd.nxt, self.mss as u32) {
+            log::debug!(
+                "{lost:} segments lost, recovery, cwnd {:}",
+                self.congestion.cwnd()
+            );
+        }
+        true
+    }
+
+    /// Sends the segment `sent` again, as much of it as is not acknowledged yet.
+    fn resend(&mut self, nic: &dyn NetworkDevice, sent: &Sent) -> Result<()> {
+        // the head of the segment might have been acknowledged since it was sent
+        let snd = &self.state.snd;
+        let seq = if wrapping_lt(sent.seq, snd.una) {
+            snd.una
+        } else {
+            sent.seq
+        };
+        let offset = seq.wrapping_sub(snd.una) as usize;
+        let len = sent.end().wrapping_sub(seq) as usize;
+        // the FIN occupies the sequence number after the data
+        let data_len = len.min(self.outgoing.len().saturating_sub(offset));
+        if let (Some(ts), 0) = (self.timestamps.as_mut(), offset) {
+            ts.on_retransmit(self.clock.now());
+        }
+        let mut header = self.data_header(seq, sent.end() == snd.nxt);
+        header.fin = data_len < len;
+        log::debug!("retransmitting {data_len:} bytes from {seq:}");
+
+        self.send(
+            nic,
+            header,
+            &slices(&self.outgoing, offset..offset + data_len),
+            NOT_ECT,
+        )?;
+        self.on_sent();
+        self.ack_pending = false;
+        Ok(())
+    }
+
+    /// Sends a segment built by `header`, signed when the connection has an MD5 key.
+    fn send(
+        &self,
+        nic: &dyn NetworkDevice,
+        header: TcpHeader,
+        payload: &[&[u8]],
+        ecn: u8,
+    ) -> Result<()> {
+        send_segment_with(
+            nic,
+            &self.id,
+            header,
+            payload,
+            self.ip_params(ecn),
+            self.signature().as_ref(),
+        )
+    }
+
+    /// Builds the next data segment from `outgoing`, returns the header and the range of the payload
+    /// within `outgoing`. Every data segment carries the ACK.
+    fn next_segment(&self) -> Option<(TcpHeader, Range<usize>)> {
+        let snd = &self.state.snd;
+        let in_flight = snd.nxt.wrapping_sub(snd.una) as usize;
+        let unsent = self.outgoing.len().saturating_sub(in_flight);
+        // SND.UNA + min(SND.WND, cwnd) - SND.NXT
+        let wnd = snd.wnd.min(self.congestion.send_window());
+        let usable = (wnd as usize).saturating_sub(in_flight);
+        // the options take their room from the payload
+        let mss = (self.mss as usize).saturating_sub(self.options_len());
+        let len = unsent.min(usable).min(mss);
+        if len == 0 {
+            return None;
+        }
+        // Nagle: a segment short of the MSS waits while data is in flight, the ACK clocks it out,
+        // https://www.ietf.org/rfc/rfc1122.txt 4.2.3.4
+        if !self.socket_options.nodelay() && len < mss && in_flight > 0 {
+            return None;
+        }
+
+        // push when the segment drains what the application has written so far
+        let header = self.data_header(snd.nxt, len == unsent);
+        Some((header, in_flight..in_flight + len))
+    }
+
+    /// The header of a data segment starting at `seq`, with URG set while the urgent pointer is ahead.
+    fn data_header(&self, seq: u32, push: bool) -> TcpHeader {
+        let snd = &self.state.snd;
+        let mut header = self.header(seq);
+        header.psh = push;
+        if snd.up && wrapping_lt(seq, snd.up_seq) {
+            if let Ok(pointer) = u16::try_from(snd.up_seq.wrapping_sub(seq)) {
+                header.urg = true;
+                header.urgent_pointer = pointer;
+            }
+        }
+        header
+    }
+
+    fn header(&self, seq: u32) -> TcpHeader {
+        let rcv = &self.state.rcv;
+        let wnd = (rcv.wnd >> rcv.wnd_shift).min(u16::MAX as u32) as u16;
+        let mut header = TcpHeader::new(self.id.dst_port, self.id.src_port, seq, wnd);
+        header.ack = true;
+        header.acknowledgment_number = self.state.rcv.nxt;
+        header.ece = self.ecn.as_ref().is_some_and(|ecn| ecn.echo());
+        let custom = self.custom_options();
+        let options = options::build(&self.options(self.clock.now(), &custom));
+        if let Err(e) = header.set_options_raw(&options) {
+            log::debug!("{e:?}");
+        }
+        if let Some(signature) = self.signature() {
+            if let Err(e) = signature.add_option(&mut header) {
+                log::debug!("{e:}");
+            }
+        }
+        header
+    }
+
+    /// The options of every segment: the timestamps, the `custom` options of the hook, and the SACK
+    /// option reporting the blocks of the reassembly queue. The SACK blocks get the room the others and
+    /// the signature leave, e.g. 3 blocks next to the timestamps.
+    fn options<'a>(&self, now: Instant, custom: &'a [CustomOption]) -> Vec<TcpOption<'a>> {
+        let mut options = vec![];
+        let room = MAX_OPTIONS_LEN - self.signature().map_or(0, |s| s.option_len());
+        if let Some(ts) = self.timestamps.as_ref() {
+            let (tsval, tsecr) = ts.option(now);
+            options.push(TcpOption::Timestamps { tsval, tsecr });
+        }
+        options::append_custom(&mut options, custom, room);
+        let room = room - options::build(&options).len();
+        // two NOPs, the kind and the length, then 8 octets per block
+        let max_blocks = (room.saturating_sub(4) / 8).min(MAX_SACK_BLOCKS);
+        let mut blocks = self.reassembly.sack_blocks();
+        blocks.truncate(max_blocks);
+        if self.sack && !blocks.is_empty() {
+            options.push(TcpOption::Sack(blocks));
+        }
+        options
+    }
+
+    /// The octets of `options`, the signature and the ip options, which every segment carries at the
+    /// expense of its payload.
+    fn options_len(&self) -> usize {
+        let signature = self.signature().map_or(0, |s| s.option_len());
+        let custom = self.custom_options();
+        let ip_options = self.socket_options.ip_options().as_bytes().len();
+        signature + ip_options + options::build(&self.options(self.clock.now(), &custom)).len()
+    }
+
+    /// Bookkeeping once a segment went out: the D-SACK block was reported and the ACK field is
+    /// Last.ACK.sent.
+    fn on_sent(&mut self) {
+        self.reassembly.on_reported();
+        if let Some(ts) = self.timestamps.as_mut() {
+            ts.on_send(self.state.rcv.nxt);
+        }
+    }
+}
+
+/// Appends the new in order part of the segment text starting at `seq` to `incoming` and advances
+/// RCV.NXT, up to `limit` octets, shrinking RCV.WND by as much. The octet right before RCV.UP goes to
+/// `urgent` instead. Out of order data is left to the caller, returns the number of octets accepted.
+fn deliver(
+    rcv: &mut ReceiveSequenceSpace,
+    incoming: &mut VecDeque<u8>,
+    urgent: &mut VecDeque<u8>,
+    seq: u32,
+    data: &[u8],
+    limit: usize,
+) -> usize {
+    if wrapping_lt(rcv.nxt, seq) {
+        return 0;
+    }
+    // skip what was already received, e.g. a retransmission overlapping RCV.NXT
+    let seen = rcv.nxt.wrapping_sub(seq) as usize;
+    if seen >= data.len() {
+        return 0;
+    }
+    let new = &data[seen..];
+    let new = &new[..new.len().min(limit)];
+
+    for &byte in new {
+        if rcv.up && rcv.nxt.wrapping_add(1) == rcv.up_seq {
+            urgent.push_back(byte);
+            rcv.up = false;
+        } else {
+            incoming.push_back(byte);
+        }
+        rcv.nxt = rcv.nxt.wrapping_add(1);
+    }
+    // the right edge of the window stays where it was advertised
+    rcv.wnd = rcv.wnd.saturating_sub(new.len() as u32);
+    new.len()
+}
+
+/// The `range` of the ring buffer, as up to two slices, without moving the data around.
+fn slices(queue: &VecDeque<u8>, range: Range<usize>) -> [&[u8]; 2] {
+    let (front, back) = queue.as_slices();
+    let at = front.len();
+    [
+        &front[range.start.min(at)..range.end.min(at)],
+        &back[range.start.saturating_sub(at)..range.end.saturating_sub(at)],
+    ]
+}
+
+pub(crate) fn drain_into(queue: &mut VecDeque<u8>, buf: &mut [u8]) -> usize {
+    let n = queue.len().min(buf.len());
+    for (dst, src) in buf.iter_mut().zip(queue.drain(..n)) {
+        *dst = src;
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::Stats;
+    use crate::tcp::clock::{Clock, MockClock};
+    use crate::tcp::close::CloseState;
+    use crate::tcp::ecn::{Ecn, CE};
+    use crate::tcp::established::{deliver, slices, OptimisticAck};
+    use crate::tcp::md5;
+    use crate::tcp::options::{CustomOption, OptionHook};
+    use crate::tcp::ratelimit::TokenBucket;
+    use crate::tcp::retransmit::INITIAL_RTO;
+    use crate::tcp::sockopt::{KeepAlive, OptionName, SocketOption};
+    use crate::tcp::state::Established;
+    use crate::tcp::timestamps::Timestamps;
+    use crate::tcp::{established, Connection, ConnectionID, ReceiveSequenceSpace, DEFAULT_MSS};
+    use crate::wire::SegmentView;
+    use crate::TCP_PROTOCOL;
+    use etherparse::{Ipv4Header, TcpHeader, TcpOptionElement};
+    use std::collections::VecDeque;
+    use std::io::{ErrorKind, IoSlice};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    fn rcv(nxt: u32) -> ReceiveSequenceSpace {
+        ReceiveSequenceSpace {
+            up: false,
+            up_seq: 0,
+            wnd: 100,
+            wnd_shift: 0,
+            nxt,
+            irs: nxt.wrapping_sub(1),
+        }
+    }
+
+    #[test]
+    fn test_deliver_in_order() {
+        let mut rcv = rcv(u32::MAX - 1);
+        let (mut incoming, mut urgent) = (VecDeque::new(), VecDeque::new());
+
+        assert_eq!(
+            deliver(
+                &mut rcv,
+                &mut incoming,
+                &mut urgent,
+                u32::MAX - 1,
+                b"abc",
+                1000
+            ),
+            3
+        );
+        assert_eq!(rcv.nxt, 1);
+        // retransmission overlapping the already received data
+        assert_eq!(
+            deliver(
+                &mut rcv,
+                &mut incoming,
+                &mut urgent,
+                u32::MAX,
+                b"bcde",
+                1000
+            ),
+            2
+        );
+        assert_eq!(rcv.nxt, 3);
+        // out of order is left to the reassembly queue
+        assert_eq!(
+            deliver(&mut rcv, &mut incoming, &mut urgent, 10, b"xyz", 1000),
+            0
+        );
+        assert_eq!(rcv.nxt, 3);
+        // beyond the limit is dropped
+        assert_eq!(
+            deliver(&mut rcv, &mut incoming, &mut urgent, 3, b"fgh", 1),
+            1
+        );
+        assert_eq!(rcv.nxt, 4);
+
+        assert_eq!(incoming, b"abcdef".to_vec());
+        assert!(urgent.is_empty());
+    }
+
+    #[test]
+    fn test_deliver_urgent() {
+        let mut rcv = rcv(100);
+        let (mut incoming, mut urgent) = (VecDeque::new(), VecDeque::new());
+
+        // urgent pointer at 104 in a later segment, the last urgent octet is 103
+        rcv.up = true;
+        rcv.up_seq = 104;
+        assert_eq!(
+            deliver(&mut rcv, &mut incoming, &mut urgent, 100, b"ab", 1000),
+            2
+        );
+        assert!(rcv.up);
+        assert_eq!(
+            deliver(&mut rcv, &mut incoming, &mut urgent, 102, b"cdef", 1000),
+            4
+        );
+        assert!(!rcv.up);
+
+        assert_eq!(incoming, b"abcef".to_vec());
+        assert_eq!(urgent, b"d".to_vec());
+    }
+
+    #[test]
+    fn test_next_segment() {
+        let mut conn = established(1000, 1000, 500);
+        assert!(conn.next_segment().is_none());
+
+        let data = vec![7u8; 600];
+        assert_eq!(conn.write(&data), 600);
+
+        // a full sized segment first, the ACK rides along
+        let (header, payload) = conn.next_segment().unwrap();
+        assert_eq!(header.sequence_number, 1000);
+        assert!(header.ack);
+        assert_eq!(header.acknowledgment_number, 500);
+        assert!(!header.psh);
+        assert_eq!(payload, 0..DEFAULT_MSS as usize);
+        conn.state.snd.nxt += DEFAULT_MSS as u32;
+
+        // the rest, pushed
+        let (header, payload) = conn.next_segment().unwrap();
+        assert_eq!(header.sequence_number, 1000 + DEFAULT_MSS as u32);
+        assert!(header.psh);
+        assert_eq!(payload, DEFAULT_MSS as usize..600);
+
+        // segments follow the negotiated MSS
+        conn.mss = 100;
+        conn.write(&data[..200]);
+        let (_, payload) = conn.next_segment().unwrap();
+        assert_eq!(payload, DEFAULT_MSS as usize..DEFAULT_MSS as usize + 100);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let now = Instant::now();
+        let mut conn = established(1000, 1000, 500);
+        conn.set_rate_limit(Some(TokenBucket::new(1000, 100)));
+        conn.write(&[7u8; 600]);
+        assert_eq!(conn.send_deadline(), None);
+
+        // the burst was sent, the next segment waits for the bucket to fill up again
+        let bucket = conn.rate_limit.as_mut().unwrap();
+        bucket.refill(now);
+        bucket.on_send(100);
+        assert_eq!(conn.send_deadline(), Some(now + Duration::from_millis(100)));
+        assert_eq!(conn.timer_deadline(), conn.send_deadline());
+        conn.set_rate_limit(None);
+        assert_eq!(conn.send_deadline(), None);
+    }
+
+    #[test]
+    fn test_next_segment_window_and_urgent() {
+        let mut conn = established(1000, 100, 500);
+        conn.write(b"hello");
+        conn.write_urgent(&[9u8; 200]);
+        assert_eq!(conn.state.snd.up_seq, 1205);
+
+        // bounded by the peer window, the urgent pointer points past the segment
+        let (header, payload) = conn.next_segment().unwrap();
+        assert_eq!(payload, 0..100);
+        assert!(header.urg);
+        assert_eq!(header.urgent_pointer, 205);
+        conn.state.snd.nxt += 100;
+        assert!(conn.next_segment().is_none());
+    }
+
+    fn ack(seq: u32, ack: u32, wnd: u16) -> Vec<u8> {
+        segment(seq, ack, wnd, &[], 0)
+    }
+
+    /// The headers of a segment from the peer, followed by `len` octets of payload to append.
+    fn segment(seq: u32, ack: u32, wnd: u16, options: &[TcpOptionElement], len: u16) -> Vec<u8> {
+        let mut header = TcpHeader::new(40000, 80, seq, wnd);
+        header.ack = true;
+        header.acknowledgment_number = ack;
+        header.set_options(options).unwrap();
+        let ip = Ipv4Header::new(
+            header.header_len() + len,
+            64,
+            TCP_PROTOCOL,
+            [192, 167, 1, 2],
+            [192, 167, 1, 1],
+        );
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        header.write(&mut packet).unwrap();
+        packet
+    }
+
+    #[test]
+    fn test_on_segment_ack() {
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 1000, 500);
+        conn.write(&[7u8; 30]);
+        let now = Instant::now();
+        for seq in [1000, 1010, 1020] {
+            conn.retransmit.on_send(seq, 10, now);
+        }
+        conn.state.snd.nxt = 1030;
+
+        // acknowledges the first segment and half of the second
+        let before = stats.clone();
+        let seg = ack(500, 1015, 1000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(conn.state.snd.una, 1015);
+        assert_eq!(conn.outgoing.len(), 15);
+        assert_eq!(conn.retransmit.len(), 2);
+        assert!(conn.retransmit.is_armed());
+        assert!(!conn.ack_pending);
+        assert_eq!(stats.diff(&before).changed(), vec![("ack_divisions", 1)]);
+
+        // duplicate and old ACKs are ignored
+        for old in [1015, 1001] {
+            let seg = ack(500, old, 1000);
+            conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+                .unwrap();
+            assert_eq!(conn.state.snd.una, 1015);
+            assert_eq!(conn.retransmit.len(), 2);
+        }
+
+        // acknowledging data not sent yet is answered with an ACK
+        let seg = ack(500, 1031, 1000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(conn.state.snd.una, 1015);
+        assert!(conn.ack_pending);
+        assert_eq!(stats.optimistic_acks, 1);
+
+        // or with a reset if configured so, the window is left alone either way
+        conn.reset_optimistic_ack = true;
+        let seg = ack(500, 1031, 0);
+        let err = conn
+            .on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<OptimisticAck>(),
+            Some(&OptimisticAck {
+                ack: 1031,
+                nxt: 1030
+            })
+        );
+        assert_eq!(conn.state.snd.una, 1015);
+        assert_eq!(conn.state.snd.wnd, 1000);
+        conn.reset_optimistic_ack = false;
+
+        // everything acknowledged, the timer stops
+        let seg = ack(500, 1030, 1000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert!(conn.outgoing.is_empty());
+        assert!(conn.retransmit.is_empty());
+        assert!(!conn.retransmit.is_armed());
+        assert!(conn.check_invariants().is_ok());
+        assert_eq!(
+            stats.diff(&before).changed(),
+            vec![("ack_divisions", 1), ("optimistic_acks", 2)]
+        );
+    }
+
+    #[test]
+    fn test_challenge_ack() {
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 1000, 500);
+        let mut on_segment = |conn: &mut Connection<Established>, mut packet: Vec<u8>, flag: u8| {
+            // the flags octet of the tcp header
+            packet[33] |= flag;
+            conn.on_segment(&SegmentView::parse(&packet).unwrap(), &mut stats)
+        };
+        const RST: u8 = 0x04;
+        const SYN: u8 = 0x02;
+
+        // a RST or a SYN in the window, but not at RCV.NXT, is challenged
+        for (seq, flag) in [(510, RST), (500, SYN), (510, SYN)] {
+            assert!(on_segment(&mut conn, ack(seq, 1000, 1000), flag).is_ok());
+            assert!(conn.take_challenge_ack());
+            assert!(!conn.take_challenge_ack());
+        }
+        // out of the window, the RST is dropped and the SYN still challenged
+        assert!(on_segment(&mut conn, ack(700, 1000, 1000), RST).is_ok());
+        assert!(!conn.take_challenge_ack() && !conn.ack_pending);
+        assert!(on_segment(&mut conn, ack(700, 1000, 1000), SYN).is_ok());
+        assert!(conn.take_challenge_ack() && !conn.ack_pending);
+
+        // an ACK below SND.UNA - MAX.SND.WND is challenged, its data dropped
+        let mut old = segment(500, 1000u32.wrapping_sub(1001), 1000, &[], 3);
+        old.extend_from_slice(b"abc");
+        assert!(on_segment(&mut conn, old, 0).is_ok());
+        assert!(conn.take_challenge_ack());
+        assert!(conn.incoming.is_empty());
+        let mut within = segment(500, 1, 1000, &[], 3);
+        within.extend_from_slice(b"abc");
+        assert!(on_segment(&mut conn, within, 0).is_ok());
+        assert!(!conn.take_challenge_ack());
+        assert_eq!(conn.incoming.len(), 3);
+
+        // a RST at RCV.NXT resets the connection
+        assert!(on_segment(&mut conn, ack(503, 1000, 1000), RST).is_err());
+    }
+
+    fn data(seq: u32, payload: &[u8]) -> Vec<u8> {
+        let mut packet = segment(seq, 1000, 1000, &[], payload.len() as u16);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_on_segment_out_of_order() {
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 1000, 500);
+        conn.sack = true;
+
+        // queued behind the hole at 500, reported in a SACK block taking room from the payload
+        for (seq, payload) in [(510, &b"klmno"[..]), (520, b"uv")] {
+            let seg = data(seq, payload);
+            conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+                .unwrap();
+        }
+        assert!(conn.ack_pending);
+        assert!(conn.incoming.is_empty());
+        assert_eq!(conn.state.rcv.nxt, 500);
+        assert!(conn.check_invariants().is_ok());
+        assert_eq!(
+            conn.header(1000)
+                .options_iterator()
+                .last()
+                .unwrap()
+                .unwrap(),
+            TcpOptionElement::SelectiveAcknowledgement((520, 522), [Some((510, 515)), None, None])
+        );
+        conn.write(&[7u8; 600]);
+        let (_, payload) = conn.next_segment().unwrap();
+        assert_eq!(payload, 0..DEFAULT_MSS as usize - 20);
+
+        // the hole is filled, overlapping the queued data
+        let seg = data(500, b"abcdefghijkl");
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(conn.state.rcv.nxt, 515);
+        assert_eq!(conn.state.rcv.wnd, 85);
+        let mut buf = [0u8; 32];
+        let n = conn.read(&mut buf);
+        assert_eq!(&buf[..n], b"abcdefghijklmno");
+        assert!(conn.check_invariants().is_ok());
+        // the overlap is reported as a duplicate
+        assert_eq!(
+            conn.header(1000)
+                .options_iterator()
+                .last()
+                .unwrap()
+                .unwrap(),
+            TcpOptionElement::SelectiveAcknowledgement((510, 512), [Some((520, 522)), None, None])
+        );
+    }
+
+    #[test]
+    fn test_dsack() {
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 1000, 500);
+        conn.sack = true;
+
+        // a retransmission of data received in order, either overlapping RCV.NXT or entirely old
+        for (seq, payload, dsack) in [(500, &b"abc"[..], None), (501, b"bcd", Some((501, 503)))] {
+            let seg = data(seq, payload);
+            conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+                .unwrap();
+            assert_eq!(conn.reassembly.sack_blocks().first().copied(), dsack);
+        }
+        let seg = data(500, b"ab");
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(
+            conn.header(1000)
+                .options_iterator()
+                .last()
+                .unwrap()
+                .unwrap(),
+            TcpOptionElement::SelectiveAcknowledgement((500, 502), [None, None, None])
+        );
+
+        // the peer reports our retransmission was received twice
+        conn.write(&[7u8; 20]);
+        let now = Instant::now();
+        conn.retransmit.on_send(1000, 10, now);
+        conn.retransmit.on_send(1010, 10, now);
+        conn.state.snd.nxt = 1020;
+        conn.retransmit.on_timeout(now);
+        let dsack = TcpOptionElement::SelectiveAcknowledgement((1000, 1010), [None, None, None]);
+        let options = [TcpOptionElement::Noop, TcpOptionElement::Noop, dsack];
+        let seg = segment(504, 1010, 1000, &options, 0);
+        let before = stats.clone();
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(
+            stats.diff(&before).changed(),
+            vec![("spurious_retransmissions", 1)]
+        );
+    }
+
+    #[test]
+    fn test_paws() {
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 1000, 500);
+        let now = Instant::now();
+        conn.timestamps = Some(Timestamps::new(100, 500, now));
+        let timestamped = |seq, tsval, payload: &[u8]| {
+            let options = [
+                TcpOptionElement::Noop,
+                TcpOptionElement::Noop,
+                TcpOptionElement::Timestamp(tsval, 0),
+            ];
+            let mut packet = segment(seq, 1000, 1000, &options, payload.len() as u16);
+            packet.extend_from_slice(payload);
+            packet
+        };
+
+        // an old duplicate, e.g. from before the sequence numbers wrapped around
+        let seg = timestamped(500, 90, b"old");
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(stats.paws_rejected, 1);
+        assert!(conn.ack_pending);
+        assert!(conn.incoming.is_empty());
+        conn.ack_pending = false;
+
+        // the timestamps are mandatory once negotiated
+        let seg = data(500, b"new");
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert!(!conn.ack_pending);
+        assert!(conn.incoming.is_empty());
+        // but for a SYN, challenged with or without the option
+        for mut seg in [timestamped(510, 90, &[]), data(510, &[])] {
+            // the flags octet of the tcp header
+            seg[33] |= 0x02;
+            conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+                .unwrap();
+            assert!(conn.take_challenge_ack() && !conn.ack_pending);
+        }
+
+        let seg = timestamped(500, 120, b"new");
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(conn.incoming.len(), 3);
+        let header = conn.header(1000);
+        match header.options_iterator().last().unwrap().unwrap() {
+            TcpOptionElement::Timestamp(_, tsecr) => assert_eq!(tsecr, 120),
+            option => panic!("unexpected option {option:?}"),
+        }
+
+        // the option takes its room from the payload
+        conn.write(&[7u8; 600]);
+        let (_, payload) = conn.next_segment().unwrap();
+        assert_eq!(payload, 0..DEFAULT_MSS as usize - 12);
+    }
+
+    #[test]
+    fn test_md5() {
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 1000, 500);
+        conn.md5_key = Some(b"secret".to_vec());
+        let signed = |seq, payload: &[u8], key: &[u8]| {
+            let mut header = TcpHeader::new(40000, 80, seq, 1000);
+            header.ack = true;
+            header.acknowledgment_number = 1000;
+            md5::add_option(&mut header).unwrap();
+            let ip = Ipv4Header::new(
+                header.header_len() + payload.len() as u16,
+                64,
+                TCP_PROTOCOL,
+                [192, 167, 1, 2],
+                [192, 167, 1, 1],
+            );
+            md5::sign(&ip, &mut header, &[payload], key).unwrap();
+            let mut packet = vec![];
+            ip.write(&mut packet).unwrap();
+            header.write(&mut packet).unwrap();
+            packet.extend_from_slice(payload);
+            packet
+        };
+
+        // unsigned, or signed with another key, even a RST is dropped
+        for seg in [data(500, b"abc"), signed(500, b"abc", b"guess")] {
+            conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+                .unwrap();
+        }
+        let mut rst = TcpHeader::new(40000, 80, 500, 0);
+        rst.rst = true;
+        let ip = Ipv4Header::new(
+            rst.header_len(),
+            64,
+            TCP_PROTOCOL,
+            [192, 167, 1, 2],
+            [192, 167, 1, 1],
+        );
+        let mut seg = vec![];
+        ip.write(&mut seg).unwrap();
+        rst.write(&mut seg).unwrap();
+        assert!(conn
+            .on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .is_ok());
+        assert_eq!(stats.md5_rejected, 3);
+        assert!(conn.incoming.is_empty());
+
+        let seg = signed(500, b"abc", b"secret");
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(conn.incoming.len(), 3);
+        assert!(conn.header(1000).options().ends_with(&[0; md5::DIGEST_LEN]));
+
+        // the signature takes its room from the payload
+        conn.write(&[7u8; 600]);
+        let (_, payload) = conn.next_segment().unwrap();
+        assert_eq!(payload, 0..DEFAULT_MSS as usize - md5::OPTION_LEN);
+    }
+
+    #[test]
+    fn test_congestion_window() {
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 65535, 500);
+        conn.write(&[7u8; 20000]);
+        let send_all = |conn: &mut Connection<Established>| {
+            let mut sent = 0;
+            while let Some((header, payload)) = conn.next_segment() {
+                let len = payload.len() as u32;
+                conn.retransmit
+                    .on_send(header.sequence_number, len, Instant::now());
+                conn.state.snd.nxt = header.sequence_number.wrapping_add(len);
+                sent += len;
+            }
+            sent
+        };
+
+        // the initial window, then twice as much once each segment is acknowledged
+        assert_eq!(send_all(&mut conn), 4 * DEFAULT_MSS as u32);
+        for i in 1..=4 {
+            let seg = ack(500, 1000 + i * DEFAULT_MSS as u32, 65535);
+            conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+                .unwrap();
+        }
+        assert_eq!(send_all(&mut conn), 8 * DEFAULT_MSS as u32);
+
+        // a single stretch ACK of a whole window grows it by two segments only
+        let seg = ack(500, conn.state.snd.nxt, 65535);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(send_all(&mut conn), 10 * DEFAULT_MSS as u32);
+
+        // a timeout leaves room for the retransmission only
+        let in_flight = conn.state.snd.nxt - conn.state.snd.una;
+        let snd = &conn.state.snd;
+        conn.congestion
+            .on_timeout(snd.una, snd.nxt, DEFAULT_MSS as u32);
+        assert_eq!(conn.congestion.ssthresh(), in_flight / 2);
+        assert_eq!(send_all(&mut conn), 0);
+    }
+
+    #[test]
+    fn test_fast_retransmit() {
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 65535, 500);
+        conn.write(&[7u8; 20000]);
+        let now = Instant::now();
+        let mss = DEFAULT_MSS as u32;
+        for i in 0..4 {
+            conn.retransmit.on_send(1000 + i * mss, mss, now);
+        }
+        conn.state.snd.nxt = 1000 + 4 * mss;
+
+        // the first segment is lost, the other three are duplicate ACKs, the window update isn't
+        let seg = ack(500, 1000, 65000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        for _ in 0..3 {
+            let seg = ack(500, 1000, 65000);
+            conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+                .unwrap();
+        }
+        assert_eq!(stats.fast_retransmissions, 1);
+        assert!(conn.congestion.in_recovery());
+        assert!(conn.congestion.take_retransmit());
+        let sent = conn.retransmit.on_fast_retransmit(now).unwrap();
+        assert_eq!((sent.seq, sent.retransmitted), (1000, true));
+
+        // each further duplicate lets a new segment out
+        assert_eq!(conn.congestion.cwnd(), 2 * mss + 3 * mss);
+        assert!(conn.next_segment().is_some());
+        let seg = ack(500, 1000, 65000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(conn.congestion.cwnd(), 6 * mss);
+
+        // the retransmission arrived, the window is deflated
+        let seg = ack(500, 1000 + 4 * mss, 65000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert!(!conn.congestion.in_recovery());
+        assert_eq!(conn.congestion.cwnd(), 2 * mss);
+        assert_eq!(stats.fast_retransmissions, 1);
+    }
+
+    #[test]
+    fn test_rack() {
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 65535, 500);
+        conn.sack = true;
+        conn.write(&[7u8; 20000]);
+        let now = Instant::now();
+        let sent_at = now - Duration::from_millis(100);
+        let mss = DEFAULT_MSS as u32;
+        for i in 0..4 {
+            let at = sent_at + Duration::from_millis(i as u64 / 2);
+            conn.retransmit.on_send(1000 + i * mss, mss, at);
+        }
+        conn.state.snd.nxt = 1000 + 4 * mss;
+
+        // the last two segments arrived, the first two may still be on their way
+        let right = 1000 + 4 * mss;
+        let sack = TcpOptionElement::SelectiveAcknowledgement((1000 + 2 * mss, right), [None; 3]);
+        let options = [TcpOptionElement::Noop, TcpOptionElement::Noop, sack];
+        let seg = segment(500, 1000, 65535, &options, 0);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(stats.rack_losses, 0);
+        let deadline = conn.timer_deadline().unwrap();
+        assert!(deadline > now && deadline < now + Duration::from_millis(30));
+
+        // the reordering window is over, both are lost and retransmitted in a single recovery
+        assert!(conn.detect_loss(deadline, &mut stats));
+        assert_eq!(stats.rack_losses, 2);
+        assert_eq!(stats.fast_retransmissions, 0);
+        assert!(conn.congestion.in_recovery());
+        assert_eq!(conn.congestion.ssthresh(), 2 * mss);
+        for seq in [1000, 1000 + mss] {
+            assert_eq!(conn.retransmit.next_lost(deadline).unwrap().seq, seq);
+        }
+        assert_eq!(conn.retransmit.next_lost(deadline), None);
+        assert_eq!(conn.timer_deadline(), None);
+    }
+
+    #[test]
+    fn test_ecn() {
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 10000, 500);
+        conn.ecn = Some(Ecn::default());
+
+        // a CE mark is echoed
+        let mut seg = data(500, b"abc");
+        seg[1] |= CE;
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(stats.ecn_ce_received, 1);
+        assert!(conn.header(1000).ece);
+
+        // the peer reports congestion, the data in flight is halved
+        conn.write(&[7u8; 4000]);
+        let now = Instant::now();
+        conn.retransmit.on_send(1000, 2000, now);
+        conn.state.snd.nxt = 3000;
+        let mut seg = ack(503, 1000, 10000);
+        seg[20 + 13] |= 0x40;
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(stats.ecn_reductions, 1);
+        assert!(conn.next_segment().is_none());
+        // the window of 1072 octets grows by a segment once as much is acknowledged
+        let seg = ack(503, 3000, 10000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(conn.congestion.cwnd(), 1072 + DEFAULT_MSS as u32);
+    }
+
+    #[test]
+    fn test_option_hook() {
+        type Received = Arc<Mutex<Vec<(u8, Vec<u8>)>>>;
+        struct Experiment(Received);
+        impl OptionHook for Experiment {
+            fn inbound(&mut self, _: &ConnectionID, kind: u8, data: &[u8]) {
+                self.0.lock().unwrap().push((kind, data.to_vec()));
+            }
+            fn outbound(&self, _: &ConnectionID) -> Vec<CustomOption> {
+                vec![CustomOption {
+                    kind: 253,
+                    data: vec![0xab, 0xcd],
+                }]
+            }
+        }
+
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 1000, 500);
+        let received = Received::default();
+        conn.set_option_hook(Box::new(Experiment(received.clone())));
+
+        let nops = [TcpOptionElement::Noop, TcpOptionElement::Noop];
+        let mut seg = segment(500, 1000, 1000, &nops, 0);
+        // the two NOPs become an experimental option of 254
+        seg[20 + 20..20 + 22].copy_from_slice(&[254, 2]);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![(254, vec![])]);
+
+        let header = conn.header(1000);
+        assert!(header.options().starts_with(&[253, 4, 0xab, 0xcd]));
+    }
+
+    #[test]
+    fn test_write_bounded_by_send_buffer() {
+        let mut conn = established(1000, 1000, 500);
+        let free = conn.snd_buf.size() as usize;
+        assert_eq!(conn.send_room(), free);
+        assert_eq!(conn.write(&vec![1u8; free - 10]), free - 10);
+        assert_eq!(conn.send_room(), 10);
+        assert_eq!(conn.write_urgent(&[2u8; 20]), 10);
+        assert_eq!(conn.state.snd.up_seq, 1000 + free as u32);
+        assert_eq!(conn.write(b"full"), 0);
+        assert_eq!(conn.write_urgent(b"full"), 0);
+        assert_eq!(conn.state.snd.up_seq, 1000 + free as u32);
+    }
+
+    #[test]
+    fn test_markers_acked() {
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 1000, 500);
+        conn.write(b"header");
+        conn.mark(1);
+        conn.write(b"body");
+        conn.mark(2);
+        conn.retransmit.on_send(1000, 10, Instant::now());
+        conn.state.snd.nxt = 1010;
+
+        let seg = ack(500, 1006, 1000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(conn.acked_marker().unwrap().marker, 1);
+        assert!(conn.acked_marker().is_none());
+
+        let seg = ack(500, 1010, 1000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(conn.acked_marker().unwrap().marker, 2);
+    }
+
+    #[test]
+    fn test_clock() {
+        let mut stats = Stats::default();
+        let clock = MockClock::default();
+        let mut conn = established(1000, 1000, 500);
+        conn.set_clock(Arc::new(clock.clone()));
+        conn.write(b"header");
+        conn.mark(1);
+        conn.retransmit.on_send(1000, 6, conn.now());
+        conn.state.snd.nxt = 1006;
+        assert_eq!(conn.rto_deadline(), Some(clock.now() + INITIAL_RTO));
+
+        // the time only moves when the test says so
+        clock.advance(Duration::from_millis(250));
+        let seg = ack(500, 1006, 1000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        let acked = conn.acked_marker().unwrap();
+        assert_eq!(acked.latency, Duration::from_millis(250));
+        assert_eq!(conn.rto_deadline(), None);
+    }
+
+    #[test]
+    fn test_nagle() {
+        let mut conn = established(1000, 4000, 500);
+        conn.set_option(SocketOption::NoDelay(false)).unwrap();
+        assert_eq!(
+            conn.option(OptionName::NoDelay),
+            SocketOption::NoDelay(false)
+        );
+        // nothing in flight, the small segment goes right away
+        conn.write(b"ab");
+        let (_, payload) = conn.next_segment().unwrap();
+        assert_eq!(payload, 0..2);
+        conn.state.snd.nxt += 2;
+
+        // it's in flight, the next small one waits for its ACK, a full sized one doesn't
+        conn.write(b"cd");
+        assert!(conn.next_segment().is_none());
+        conn.write(&vec![7u8; DEFAULT_MSS as usize]);
+        let (_, payload) = conn.next_segment().unwrap();
+        assert_eq!(payload, 2..2 + DEFAULT_MSS as usize);
+
+        conn.set_option(SocketOption::NoDelay(true)).unwrap();
+        conn.state.snd.nxt += DEFAULT_MSS as u32;
+        assert!(conn.next_segment().is_some());
+    }
+
+    #[test]
+    fn test_keepalive_and_user_timeout() {
+        let mut stats = Stats::default();
+        let clock = MockClock::default();
+        let secs = Duration::from_secs;
+        let mut conn = established(1000, 1000, 500);
+        conn.set_clock(Arc::new(clock.clone()));
+        assert_eq!(conn.keepalive_deadline(), None);
+        let keepalive = KeepAlive {
+            idle: secs(60),
+            interval: secs(10),
+            probes: 3,
+        };
+        conn.set_option(SocketOption::KeepAlive(Some(keepalive)))
+            .unwrap();
+        conn.set_option(SocketOption::UserTimeout(Some(secs(30))))
+            .unwrap();
+        assert_eq!(conn.keepalive_deadline(), Some(clock.now() + secs(60)));
+
+        // a segment from the peer restarts the idle time
+        clock.advance(secs(20));
+        let seg = ack(500, 1000, 1000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(conn.keepalive_deadline(), Some(clock.now() + secs(60)));
+
+        // data in flight, the user timeout replaces the keep-alive
+        conn.write(b"data");
+        conn.state.snd.nxt = 1004;
+        conn.socket_options.on_send(clock.now());
+        assert_eq!(conn.keepalive_deadline(), Some(clock.now() + secs(30)));
+        clock.advance(secs(5));
+        let seg = ack(500, 1004, 1000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(conn.keepalive_deadline(), Some(clock.now() + secs(60)));
+        assert_eq!(
+            conn.option(OptionName::UserTimeout),
+            SocketOption::UserTimeout(Some(secs(30)))
+        );
+    }
+
+    #[test]
+    fn test_buffer_sizes() {
+        let mut conn = established(1000, 1 << 20, 500);
+        conn.state.rcv.wnd = 1000;
+        conn.set_option(SocketOption::RecvBuffer(1 << 15)).unwrap();
+        assert_eq!(
+            conn.option(OptionName::RecvBuffer),
+            SocketOption::RecvBuffer(1 << 15)
+        );
+        // the larger window is advertised right away
+        assert!(conn.ack_pending);
+        assert_eq!(conn.receive_window(), Some(1 << 15));
+
+        // the application can't write ahead of the send buffer
+        conn.set_option(SocketOption::SendBuffer(8192)).unwrap();
+        assert_eq!(conn.write(&[7u8; 10000]), 8192);
+        assert_eq!(conn.send_room(), 0);
+    }
+
+    #[test]
+    fn test_close() {
+        let mut stats = Stats::default();
+        let mut conn = established(1000, 1000, 500);
+        let mut buf = [0u8; 8];
+
+        // the FIN comes after the data, which is read before the end of the stream
+        let mut seg = data(500, b"bye");
+        seg[20 + 13] |= 1;
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert_eq!(conn.state.rcv.nxt, 504);
+        assert_eq!(conn.close_state(), CloseState::CloseWait);
+        assert_eq!(conn.try_read(&mut buf, Instant::now()).unwrap(), 3);
+        assert_eq!(conn.try_read(&mut buf, Instant::now()).unwrap(), 0);
+        conn.check_invariants().unwrap();
+
+        conn.close();
+        assert_eq!(conn.write(b"more"), 0);
+        assert_eq!(
+            conn.try_write(b"more", Instant::now()).unwrap_err().kind(),
+            ErrorKind::BrokenPipe
+        );
+        // the FIN sent, acknowledged in LAST-ACK nothing is left
+        conn.close.on_fin_sent(1000);
+        conn.state.snd.nxt = 1001;
+        conn.retransmit.on_send(1000, 1, Instant::now());
+        assert_eq!(conn.close_state(), CloseState::LastAck);
+        conn.check_invariants().unwrap();
+        let seg = ack(504, 1001, 1000);
+        conn.on_segment(&SegmentView::parse(&seg).unwrap(), &mut stats)
+            .unwrap();
+        assert!(conn.is_flushed());
+        assert!(conn.is_done());
+        conn.check_invariants().unwrap();
+    }
+
+    #[test]
+    fn test_slices() {
+        let mut queue = VecDeque::with_capacity(8);
+        queue.extend(b"xxxxxxab");
+        queue.drain(..6);
+        queue.extend(b"cdef");
+        // the ring buffer wraps around after "ab"
+        let (front, _) = queue.as_slices();
+        assert!(front.len() < queue.len());
+
+        let gather = |range| slices(&queue, range).concat();
+        assert_eq!(gather(0..6), b"abcdef");
+        assert_eq!(gather(1..4), b"bcd");
+        assert_eq!(gather(3..5), b"de");
+        assert_eq!(gather(2..2), b"");
+    }
+
+    #[test]
+    fn test_write_vectored() {
+        let mut conn = established(1000, 1000, 500);
+        let free = conn.snd_buf.size() as usize;
+        let body = vec![1u8; free];
+        let bufs = [IoSlice::new(b"header "), IoSlice::new(&body)];
+        assert_eq!(conn.write_vectored(&bufs), free);
+        assert_eq!(
+            conn.outgoing.iter().take(7).copied().collect::<Vec<_>>(),
+            b"header "
+        );
+        assert_eq!(conn.write_vectored(&bufs), 0);
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut conn = established(1000, 1000, 500);
+        conn.incoming.extend(b"GET / HTTP/1.1");
+
+        let mut buf = [0u8; 3];
+        assert_eq!(conn.peek(&mut buf), 3);
+        assert_eq!(&buf, b"GET");
+        assert_eq!(conn.bytes_read, 0);
+        assert_eq!(conn.available(), 14);
+
+        let mut buf = [0u8; 32];
+        let n = conn.read(&mut buf);
+        assert_eq!(&buf[..n], b"GET / HTTP/1.1");
+        assert_eq!(conn.peek(&mut buf), 0);
+    }
+
+    #[test]
+    fn test_receive_window() {
+        let mut conn = established(1000, 1000, 500);
+        let size = conn.rcv_buf.size() as usize;
+        conn.incoming.extend(vec![0u8; size - 240]);
+        conn.rcv_buf.on_buffer(size - 240);
+        conn.state.rcv.wnd = 240;
+        assert_eq!(conn.receive_window(), None);
+
+        // the slow reader frees less than a segment, the window stays closed
+        let mut buf = vec![0u8; 1000];
+        conn.read(&mut buf[..500]);
+        assert_eq!(conn.receive_window(), None);
+        assert!(!conn.ack_pending);
+
+        conn.read(&mut buf);
+        assert_eq!(conn.receive_window(), Some(1740));
+        assert!(conn.ack_pending);
+
+        // a scaled window is advertised in multiples of the scale
+        conn.state.rcv.wnd_shift = 4;
+        assert_eq!(conn.receive_window(), Some(1728));
+    }
+
+    #[test]
+    fn test_try_read_timeout() {
+        let now = Instant::now();
+        let mut conn = established(1000, 1000, 500);
+        conn.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+
+        let mut buf = [0u8; 8];
+        let err = conn.try_read(&mut buf, now).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        let err = conn
+            .try_read(&mut buf, now + Duration::from_secs(1))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+        conn.incoming.extend(b"late");
+        let later = now + Duration::from_secs(2);
+        assert_eq!(conn.try_read(&mut buf, later).unwrap(), 4);
+
+        // without a write timeout a full send buffer blocks forever
+        let size = conn.snd_buf.size() as usize;
+        assert_eq!(conn.try_write(&vec![0u8; size], later).unwrap(), size);
+        let err = conn
+            .try_write(b"x", later + Duration::from_secs(60))
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+    }
+}
@@ -0,0 +1,215 @@
+//! Passive fingerprint of the peer, recorded from the raw characteristics of its SYN. The signature
+//! follows the layout of p0f v3 (https://lcamtuf.coredump.cx/p0f3/README), e.g. for a Linux peer:
+//!
+//! ```text
+//! 4:64+0:0:1460:64240,7:mss,sok,ts,nop,ws:df:0
+//! | |  | | |    |     | |                  |  |
+//! | |  | | |    |     | |                  |  +- payload class, 0 for an empty SYN
+//! | |  | | |    |     | |                  +---- quirks, e.g. df, id+, ecn
+//! | |  | | |    |     | +----------------------- tcp option layout, in the order sent
+//! | |  | | |    |     +------------------------- window scale, * if absent
+//! | |  | | |    +------------------------------- window size
+//! | |  | | +------------------------------------ mss, * if absent
+//! | |  | +-------------------------------------- ip options length
+//! | |  +---------------------------------------- hops travelled, initial ttl - ttl
+//! | +------------------------------------------- guessed initial ttl
+//! +--------------------------------------------- ip version
+//! ```
+
+use crate::wire::{
+    OptionError, SegmentView, OPT_EOL, OPT_MSS, OPT_NOP, OPT_SACK, OPT_SOK, OPT_TS, OPT_WS,
+};
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Fingerprint {
+    pub ttl: u8,
+    pub ip_options_len: usize,
+    pub dont_fragment: bool,
+    pub ip_id: u16,
+    pub ecn: bool,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub window_scale: Option<u8>,
+    /// Kinds of the tcp options in the order they appear in the SYN
+    pub option_kinds: Vec<u8>,
+    /// The TSval of the timestamps option
+    pub timestamp: Option<u32>,
+    /// Bytes following the end of option list, or a truncated option
+    pub options_garbage: bool,
+    pub payload_len: usize,
+}
+
+impl Fingerprint {
+    pub fn from_syn(syn: &SegmentView) -> Self {
+        let (ip_header, tcp_header) = (&syn.ip, &syn.tcp);
+        let mut fingerprint = Fingerprint {
+            ttl: ip_header.ttl(),
+            ip_options_len: ip_header.options().len(),
+            dont_fragment: ip_header.dont_fragment(),
+            ip_id: ip_header.identification(),
+            ecn: tcp_header.ece() || tcp_header.cwr(),
+            window: tcp_header.window_size(),
+            mss: None,
+            window_scale: None,
+            option_kinds: vec![],
+            timestamp: None,
+            options_garbage: false,
+            payload_len: syn.payload.len(),
+        };
+
+        for option in syn.options() {
+            let option = match option {
+                Ok(option) => option,
+                Err(e) => {
+                    if let OptionError::Truncated(kind) = e {
+                        fingerprint.option_kinds.push(kind);
+                    }
+                    fingerprint.options_garbage = true;
+                    break;
+                }
+            };
+            fingerprint.option_kinds.push(option.kind);
+            let value = option.data;
+            match (option.kind, value.len()) {
+                (OPT_MSS, 2) => fingerprint.mss = Some(u16::from_be_bytes([value[0], value[1]])),
+                (OPT_WS, 1) => fingerprint.window_scale = Some(value[0]),
+                (OPT_TS, 8) => {
+                    fingerprint.timestamp =
+                        Some(u32::from_be_bytes([value[0], value[1], value[2], value[3]]))
+                }
+                _ => {}
+            }
+        }
+
+        fingerprint
+    }
+
+    /// The initial ttl of the peer, guessed by rounding up to the usual defaults.
+    pub fn initial_ttl(&self) -> u8 {
+        [32, 64, 128, 255]
+            .into_iter()
+            .find(|ttl| self.ttl <= *ttl)
+            .unwrap_or(255)
+    }
+
+    /// The p0f like signature of the SYN, see the module doc for the layout.
+    pub fn signature(&self) -> String {
+        let or_star = |v: Option<String>| v.unwrap_or_else(|| "*".to_string());
+
+        let layout = self
+            .option_kinds
+            .iter()
+            .map(|kind| match *kind {
+                OPT_EOL => "eol".to_string(),
+                OPT_NOP => "nop".to_string(),
+                OPT_MSS => "mss".to_string(),
+                OPT_WS => "ws".to_string(),
+                OPT_SOK => "sok".to_string(),
+                OPT_SACK => "sack".to_string(),
+                OPT_TS => "ts".to_string(),
+                other => format!("?{other:}"),
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let mut quirks = vec![];
+        if self.dont_fragment {
+            quirks.push("df");
+            if self.ip_id != 0 {
+                quirks.push("id+");
+            }
+        } else if self.ip_id == 0 {
+            quirks.push("id-");
+        }
+        if self.ecn {
+            quirks.push("ecn");
+        }
+        if self.timestamp == Some(0) {
+            quirks.push("ts1-");
+        }
+        if self.window_scale.map(|ws| ws > 14).unwrap_or(false) {
+            quirks.push("exws");
+        }
+        if self.options_garbage {
+            quirks.push("opt+");
+        }
+
+        format!(
+            "4:{:}+{:}:{:}:{:}:{:},{:}:{:}:{:}:{:}",
+            self.initial_ttl(),
+            self.initial_ttl() - self.ttl,
+            self.ip_options_len,
+            or_star(self.mss.map(|v| v.to_string())),
+            self.window,
+            or_star(self.window_scale.map(|v| v.to_string())),
+            layout,
+            quirks.join(","),
+            if self.payload_len == 0 { "0" } else { "+" },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::fingerprint::Fingerprint;
+    use crate::wire::SegmentView;
+    use crate::TCP_PROTOCOL;
+    use etherparse::{Ipv4Header, TcpHeader};
+
+    fn syn(ttl: u8, options: &[u8]) -> Vec<u8> {
+        let mut tcp = TcpHeader::new(40000, 80, 1000, 64240);
+        tcp.syn = true;
+        tcp.set_options_raw(options).unwrap();
+        let mut ip = Ipv4Header::new(
+            tcp.header_len(),
+            ttl,
+            TCP_PROTOCOL,
+            [192, 167, 1, 2],
+            [192, 167, 1, 1],
+        );
+        ip.dont_fragment = true;
+        ip.identification = 0;
+
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet
+    }
+
+    fn fingerprint(packet: &[u8]) -> Fingerprint {
+        Fingerprint::from_syn(&SegmentView::parse(packet).unwrap())
+    }
+
+    #[test]
+    fn test_linux_syn() {
+        // mss 1460, sack permitted, timestamps, nop, window scale 7
+        let packet = syn(
+            62,
+            &[
+                2, 4, 0x05, 0xb4, 4, 2, 8, 10, 0, 0, 0, 1, 0, 0, 0, 0, 1, 3, 3, 7,
+            ],
+        );
+        let fp = fingerprint(&packet);
+        assert_eq!(fp.mss, Some(1460));
+        assert_eq!(fp.window_scale, Some(7));
+        assert_eq!(fp.timestamp, Some(1));
+        assert_eq!(
+            fp.signature(),
+            "4:64+2:0:1460:64240,7:mss,sok,ts,nop,ws:df:0"
+        );
+    }
+
+    #[test]
+    fn test_malformed_options() {
+        // mss claiming 8 bytes in a 4 bytes option list
+        let fp = fingerprint(&syn(128, &[2, 8, 0x05, 0xb4]));
+        assert_eq!(fp.mss, None);
+        assert!(fp.options_garbage);
+        assert_eq!(fp.signature(), "4:128+0:0:*:64240,*:mss:df,opt+:0");
+
+        // junk after the end of option list
+        let fp = fingerprint(&syn(255, &[1, 0, 7, 7]));
+        assert_eq!(fp.option_kinds, vec![1, 0]);
+        assert_eq!(fp.signature(), "4:255+0:0:*:64240,*:nop,eol:df,opt+:0");
+    }
+}
@@ -0,0 +1,144 @@
+//! F-RTO, the detection of spurious retransmission timeouts, https://www.ietf.org/rfc/rfc5682.txt 2.1.
+//! A sudden RTT spike fires the retransmission timer while nothing was lost, the ACKs were only late.
+//! Instead of sending more retransmissions after the timeout, two new segments go out once the first ACK
+//! acknowledges new data. If the next ACK acknowledges new data as well, the segments sent before the
+//! timeout are arriving, the timeout was spurious and the congestion window collapsed for nothing. A
+//! duplicate ACK instead shows segments were lost, and the recovery carries on as after any timeout.
+
+use crate::tcp::wrapping_lt;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum Frto {
+    #[default]
+    Off,
+    /// The timeout retransmission went out, SND.NXT being `recover` at the timeout, RFC 5682 2.1 step 1
+    Retransmitted { recover: u32 },
+    /// The first ACK after the timeout acknowledged new data, `sent` new segments went out since,
+    /// step 2b
+    NewData { sent: u32 },
+    /// The timer expired again, nothing is looked into until new data is acknowledged
+    Backoff,
+}
+
+/// What an ACK after a timeout tells about it
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Verdict {
+    /// Too early to tell
+    Pending,
+    /// The timeout was spurious, step 3b
+    Spurious,
+    /// Nothing can be told, the recovery carries on, step 2a
+    Unknown,
+}
+
+/// The new segments sent to tell a spurious timeout apart
+const NEW_SEGMENTS: u32 = 2;
+
+impl Frto {
+    /// The retransmission timer expired, SND.NXT being `nxt`. Returns whether it's the first expiry,
+    /// only that one is looked into, step 1.
+    pub fn on_timeout(&mut self, nxt: u32) -> bool {
+        let first = *self == Frto::Off;
+        *self = if first {
+            Frto::Retransmitted { recover: nxt }
+        } else {
+            Frto::Backoff
+        };
+        first
+    }
+
+    /// An ACK `ack` of new data.
+    pub fn on_ack(&mut self, ack: u32) -> Verdict {
+        match *self {
+            Frto::Off => Verdict::Pending,
+            Frto::Backoff => {
+                *self = Frto::Off;
+                Verdict::Pending
+            }
+            // all that was sent before the timeout is acknowledged, step 2a
+            Frto::Retransmitted { recover } if !wrapping_lt(ack, recover) => {
+                *self = Frto::Off;
+                Verdict::Unknown
+            }
+            Frto::Retransmitted { .. } => {
+                *self = Frto::NewData { sent: 0 };
+                Verdict::Pending
+            }
+            Frto::NewData { .. } => {
+                *self = Frto::Off;
+                Verdict::Spurious
+            }
+        }
+    }
+
+    /// A duplicate ACK, segments were lost, step 2a or 3a. Returns whether they are beyond the timeout
+    /// retransmission, which was acknowledged, the next of them to be retransmitted right away.
+    pub fn on_dup_ack(&mut self) -> bool {
+        match std::mem::replace(self, Frto::Off) {
+            Frto::NewData { .. } => true,
+            Frto::Backoff => {
+                *self = Frto::Backoff;
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// A new segment was sent.
+    pub fn on_send(&mut self) {
+        if let Frto::NewData { sent } = self {
+            *sent += 1;
+        }
+    }
+
+    /// Whether new segments may go out beyond the congestion window, step 2b
+    pub fn sends_new_data(&self) -> bool {
+        matches!(self, Frto::NewData { sent } if *sent < NEW_SEGMENTS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::frto::{Frto, Verdict};
+
+    #[test]
+    fn test_spurious() {
+        let mut frto = Frto::default();
+        assert!(frto.on_timeout(5000));
+        assert!(!frto.sends_new_data());
+        assert_eq!(frto.on_ack(2000), Verdict::Pending);
+        assert!(frto.sends_new_data());
+        frto.on_send();
+        frto.on_send();
+        assert!(!frto.sends_new_data());
+        assert_eq!(frto.on_ack(3000), Verdict::Spurious);
+        assert_eq!(frto, Frto::Off);
+    }
+
+    #[test]
+    fn test_lost() {
+        // a duplicate ACK after the new segments
+        let mut frto = Frto::default();
+        frto.on_timeout(5000);
+        frto.on_ack(2000);
+        assert!(frto.on_dup_ack());
+        assert_eq!(frto.on_ack(3000), Verdict::Pending);
+        // or right after the timeout, the retransmission is still on its way
+        frto.on_timeout(5000);
+        assert!(!frto.on_dup_ack());
+        assert_eq!(frto, Frto::Off);
+
+        // everything acknowledged by the first ACK
+        frto.on_timeout(5000);
+        assert_eq!(frto.on_ack(5000), Verdict::Unknown);
+
+        // the timer backing off isn't looked into, until new data is acknowledged
+        assert!(frto.on_timeout(5000));
+        assert!(!frto.on_timeout(5000));
+        assert!(!frto.on_timeout(5000));
+        assert!(!frto.on_dup_ack());
+        assert_eq!(frto.on_ack(2000), Verdict::Pending);
+        assert!(!frto.sends_new_data());
+        assert!(frto.on_timeout(5000));
+    }
+}
@@ -1,6 +1,7 @@
 //! This implements the basic 3 way handshake process to establish a tcp connection.
 //! The basic 3-Way handshake for connection synchronization is as follows:
 //!
+//! ```text
 //!       TCP A                                                TCP B
 //!
 //!   1.  CLOSED                                               LISTEN
@@ -12,15 +13,60 @@
 //!   4.  ESTABLISHED --> <SEQ=101><ACK=301><CTL=ACK>       --> ESTABLISHED
 //!
 //!   Other payload sent...
+//! ```
 
+use crate::device::NetworkDevice;
+use crate::stats::Stats;
+use crate::tcp::ao::Ao;
+use crate::tcp::autotune::{ReceiveBuffer, SendBuffer, DEFAULT_SND_BUF};
+use crate::tcp::congestion::Congestion;
+use crate::tcp::ecn::{Ecn, NOT_ECT};
+use crate::tcp::error::TcpError;
+use crate::tcp::established::drain_into;
+use crate::tcp::fingerprint::Fingerprint;
+use crate::tcp::md5;
+use crate::tcp::options::{self, TcpOption};
 use crate::tcp::state::{Established, Listen, SynRecv};
+use crate::tcp::timestamps::Timestamps;
 use crate::tcp::{
-    is_ack_in_window, is_recv_data_in_window, ReceiveSequenceSpace, SendSequenceSpace,
-    DEFAULT_WINDOW_SIZE,
+    is_ack_in_window, is_recv_data_in_window, send_segment_with, Connection, ReceiveSequenceSpace,
+    SendSequenceSpace, Tunables, DEFAULT_MSS, MAX_OPTIONS_LEN, MAX_WND_SHIFT,
 };
-use crate::{Connection, ConnectionID, TCP_PROTOCOL};
-use anyhow::{anyhow, Result};
-use etherparse::{Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
+use crate::wire::SegmentView;
+use etherparse::TcpHeader;
+
+pub mod active;
+pub mod cookie;
+pub mod fastopen;
+
+impl<T> Connection<T> {
+    /// Sets the connection up from the tunables, its MSS the least of `peer_mss` and ours.
+    fn configure(&mut self, tunables: &Tunables, peer_mss: u16) {
+        self.rcv_buf = ReceiveBuffer::new(
+            tunables.window_size as u32,
+            tunables.rcv_buf_min,
+            tunables.rcv_buf_max,
+            tunables.rcv_mem_global_max,
+        );
+        self.snd_buf = SendBuffer::new(
+            DEFAULT_SND_BUF,
+            tunables.snd_buf_min,
+            tunables.snd_buf_max,
+            tunables.snd_buf_global_max,
+        );
+        self.reset_optimistic_ack = tunables.optimistic_ack_reset;
+        self.retransmit
+            .set_bounds(tunables.rto_min, tunables.rto_max);
+        self.close.set_msl(tunables.msl);
+        self.socket_options
+            .set_dont_fragment(tunables.ip_dont_fragment);
+        self.socket_options.set_ttl(tunables.ttl);
+        self.mss = peer_mss.min(tunables.mss);
+        log::debug!("mss {:}, the peer announced {peer_mss:}", self.mss);
+        self.congestion = Congestion::new(tunables.congestion_control.controller(self.mss as u32));
+        self.congestion.set_pacing(tunables.pacing);
+    }
+}
 
 /// Implements the initial SYN response handling
 ///        TCP A                                                TCP B
@@ -31,30 +77,23 @@ use etherparse::{Ipv4Header, Ipv4HeaderSlice, TcpHeader, TcpHeaderSlice};
 ///
 ///   3.  ESTABLISHED <-- <SEQ=300><ACK=101><CTL=SYN,ACK>  <-- SYN-RECEIVED
 impl<'a> Connection<Listen<'a>> {
-    pub fn new(
-        id: ConnectionID,
-        ip_header: Ipv4HeaderSlice<'a>,
-        tcp_header: TcpHeaderSlice<'a>,
-    ) -> Self {
-        Self::from(
-            id,
-            Listen {
-                ip_header,
-                tcp_header,
-            },
-        )
+    pub fn new(syn: SegmentView<'a>) -> Self {
+        Self::from(syn.id(), Listen { syn })
     }
 
     /// Generates the next to be used by subsequent steps. See https://www.ietf.org/rfc/rfc793.txt page 64
     /// for the full description.
-    fn next_state(&self, iss: u32, wnd: u16) -> SynRecv {
+    fn next_state(&self, iss: u32, wnd: u16, snd_shift: u8, rcv_shift: u8) -> SynRecv {
         SynRecv {
-            // SND.NXT is set to ISS+1 and SND.UNA to ISS
+            // SND.NXT is set to ISS+1 and SND.UNA to ISS, SND.WND is what the peer advertised
             snd: SendSequenceSpace {
                 una: iss,
                 nxt: iss.wrapping_add(1),
-                wnd,
+                // the window of a SYN is never scaled
+                wnd: self.state.syn.tcp.window_size() as u32,
+                wnd_shift: snd_shift,
                 up: false,
+                up_seq: 0,
                 wl1: 0,
                 wl2: 0,
                 iss,
@@ -62,32 +101,34 @@ impl<'a> Connection<Listen<'a>> {
             // Set RCV.NXT to SEG.SEQ+1, IRS is set to SEG.SEQ and any other
             // control or text should be queued for processing later.
             rcv: ReceiveSequenceSpace {
-                nxt: self.state.tcp_header.sequence_number().wrapping_add(1),
-                wnd: self.state.tcp_header.window_size(),
+                nxt: self.state.syn.tcp.sequence_number().wrapping_add(1),
+                wnd: wnd as u32,
+                wnd_shift: rcv_shift,
                 up: false,
-                irs: self.state.tcp_header.sequence_number(),
+                up_seq: 0,
+                irs: self.state.syn.tcp.sequence_number(),
             },
         }
     }
 
     /// Performs checks on establish a connection, refer to https://www.ietf.org/rfc/rfc793.txt page 64
     /// for the full pseudocode.
-    fn preflight_checks(&self) -> Result<()> {
-        if self.state.tcp_header.ack() {
+    fn preflight_checks(&self) -> Result<(), TcpError> {
+        if self.state.syn.tcp.ack() {
             // Any acknowledgment is bad if it arrives on a connection still in
             // the LISTEN state.  An acceptable reset segment should be formed
             // for any arriving ACK-bearing segment.  The RST should be
             // formatted as follows:
             //     <SEQ=SEG.ACK><CTL=RST>
-            return Err(anyhow!("ack should not be set, invalid payload"));
+            return Err(TcpError::UnexpectedAck);
         }
-        if !self.state.tcp_header.syn() {
+        if !self.state.syn.tcp.syn() {
             // If the SYN bit is set, check the security.  If the
             // security/compartment on the incoming segment does not exactly
             // match the security/compartment in the TCB then send a reset and
             // return.
             //     <SEQ=SEG.ACK><CTL=RST>
-            return Err(anyhow!("syn should be set, invalid payload"));
+            return Err(TcpError::NoSyn);
         }
 
         // TODO:
@@ -98,13 +139,162 @@ impl<'a> Connection<Listen<'a>> {
         Ok(())
     }
 
-    pub fn syn_ack(self, nic: &tun_tap::Iface) -> Result<Connection<SynRecv>> {
+    /// Checks the signature of the SYN. A peer with TCP-AO MKTs, or else with an MD5 key, must sign its
+    /// SYN accordingly, and only such a peer may, see https://www.ietf.org/rfc/rfc5925.txt 7.3 and
+    /// https://www.ietf.org/rfc/rfc2385.txt section 2.0. A connection never uses both.
+    fn authenticate(
+        &mut self,
+        tunables: &Tunables,
+        iss: u32,
+        stats: &mut Stats,
+    ) -> Result<(), TcpError> {
+        let rejected = |reason: &str| TcpError::BadSignature(reason.to_string());
+        let syn = &self.state.syn;
+        let mkts = tunables.ao_keys.get(self.id.src_addr);
+        if !mkts.is_empty() {
+            let ao = match syn.md5_signature() {
+                Some(_) => Err(rejected("syn with both tcp-ao and an md5 signature")),
+                None => Ao::accept(syn, mkts, iss).map_err(|e| rejected(&e.to_string())),
+            };
+            if ao.is_err() {
+                stats.ao_rejected += 1;
+            }
+            self.ao = Some(ao?);
+            return Ok(());
+        }
+        if syn.authentication().is_some() {
+            stats.ao_rejected += 1;
+            return Err(rejected("syn with tcp-ao, no mkt configured"));
+        }
+
+        let md5_key = tunables.md5_keys.get(self.id.src_addr).map(<[u8]>::to_vec);
+        match &md5_key {
+            Some(key) if !md5::verify(syn, key) => {
+                stats.md5_rejected += 1;
+                return Err(rejected("syn with a missing or wrong md5 signature"));
+            }
+            None if syn.md5_signature().is_some() => {
+                stats.md5_rejected += 1;
+                return Err(rejected("syn with an md5 signature, no key configured"));
+            }
+            _ => {}
+        }
+        self.md5_key = md5_key;
+        Ok(())
+    }
+
+    pub fn syn_ack(
+        self,
+        nic: &dyn NetworkDevice,
+        tunables: &Tunables,
+        stats: &mut Stats,
+    ) -> Result<Connection<SynRecv>, TcpError> {
+        let iss = tunables.iss_policy.iss(&self.id);
+        self.answer(nic, tunables, iss, false, stats)
+    }
+
+    /// Answers the SYN with a SYN-ACK carrying a cookie as its ISS, see `cookie`, and drops the
+    /// connection: `from_cookie` creates it again from the final ACK.
+    pub fn syn_ack_cookie(
+        self,
+        nic: &dyn NetworkDevice,
+        tunables: &Tunables,
+        stats: &mut Stats,
+    ) -> Result<(), TcpError> {
+        let mss = self
+            .state
+            .syn
+            .mss()
+            .unwrap_or(DEFAULT_MSS)
+            .min(tunables.mss);
+        let isn = self.state.syn.tcp.sequence_number();
+        let (iss, _) = cookie::encode(&self.id, isn, mss);
+        self.answer(nic, tunables, iss, true, stats).map(drop)
+    }
+
+    /// The connection in SYN-RECEIVED a SYN-ACK with a cookie was sent for, created from the final ACK
+    /// `ack` returning the cookie. None when it's not one we gave out recently.
+    pub fn from_cookie(ack: SegmentView<'a>, tunables: &Tunables) -> Option<Connection<SynRecv>> {
+        let irs = ack.tcp.sequence_number().wrapping_sub(1);
+        let iss = ack.tcp.acknowledgment_number().wrapping_sub(1);
+        let mss = cookie::decode(&ack.id(), irs, iss)?;
+        let mut conn = Self::new(ack);
+        conn.configure(tunables, mss);
+        let window_size = conn.rcv_buf.window(0).min(u16::MAX as u32) as u16;
+        let mut next_state = conn.next_state(iss, window_size, 0, 0);
+        // the SYN is the one before the ACK
+        next_state.rcv.irs = irs;
+        next_state.rcv.nxt = irs.wrapping_add(1);
+        conn.rcv_buf
+            .on_advertise(next_state.rcv.nxt, window_size as u32, conn.clock.now());
+        Some(conn.transition(|_| next_state))
+    }
+
+    /// Answers the SYN with a SYN-ACK of sequence number `initial_seq_num`. With a `cookie`, it offers
+    /// none of the options a cookie doesn't keep.
+    fn answer(
+        mut self,
+        nic: &dyn NetworkDevice,
+        tunables: &Tunables,
+        initial_seq_num: u32,
+        cookie: bool,
+        stats: &mut Stats,
+    ) -> Result<Connection<SynRecv>, TcpError> {
         self.preflight_checks()?;
+        self.authenticate(tunables, initial_seq_num, stats)?;
+        let syn = self.state.syn.clone();
+        self.on_custom_options(&syn);
 
-        // TODO: replace seq_number with random
-        let initial_seq_num = 0;
-        let window_size = DEFAULT_WINDOW_SIZE;
-        let next_state = self.next_state(initial_seq_num, window_size);
+        let fingerprint = Fingerprint::from_syn(&self.state.syn);
+        log::info!(
+            "syn from {:?}, fingerprint: {:}",
+            self.id,
+            fingerprint.signature()
+        );
+        self.fingerprint = Some(fingerprint);
+
+        // a peer not announcing its MSS can take the default, https://www.ietf.org/rfc/rfc1122.txt 4.2.2.6
+        self.configure(tunables, self.state.syn.mss().unwrap_or(DEFAULT_MSS));
+        // window scaling is only in effect when both sides send the option, the shift of the peer is
+        // capped, see https://www.ietf.org/rfc/rfc7323.txt 2.2 and 2.3
+        let peer_shift = self.state.syn.window_scale().filter(|_| !cookie);
+        let (snd_shift, rcv_shift) = match peer_shift {
+            Some(shift) => (shift.min(MAX_WND_SHIFT), self.rcv_buf.window_shift()),
+            None => (0, 0),
+        };
+        log::debug!("window shift {rcv_shift:}, the peer announced {peer_shift:?}");
+        // the data of a Fast Open SYN is accepted with a valid cookie, as far as the window and the
+        // receive memory allow, see https://www.ietf.org/rfc/rfc7413.txt 4.1.2
+        // no room is left for the cookie next to a signature
+        let fast_open = (tunables.fast_open && self.signature().is_none() && !cookie)
+            .then(|| self.state.syn.fast_open_cookie())
+            .flatten();
+        let valid = fast_open.is_some_and(|cookie| fastopen::is_valid(self.id.src_addr, cookie));
+        let accepted = match fast_open {
+            Some(_) if valid => {
+                let data = self.state.syn.payload;
+                let n = data
+                    .len()
+                    .min(self.rcv_buf.window(0) as usize)
+                    .min(self.rcv_buf.budget());
+                self.incoming.extend(&data[..n]);
+                self.rcv_buf.on_buffer(n);
+                stats.fast_open_accepted += 1;
+                log::debug!("fast open, {n:} of {:} bytes accepted", data.len());
+                n
+            }
+            Some(cookie) if !cookie.is_empty() => {
+                stats.fast_open_rejected += 1;
+                log::debug!("fast open cookie {cookie:?} rejected");
+                0
+            }
+            _ => 0,
+        };
+        // a cookie is given out on request, or to replace an invalid one
+        let give_cookie = fast_open.is_some() && !valid;
+        let window_size = self.rcv_buf.window(accepted).min(u16::MAX as u32) as u16;
+        let mut next_state = self.next_state(initial_seq_num, window_size, snd_shift, rcv_shift);
+        next_state.rcv.nxt = next_state.rcv.nxt.wrapping_add(accepted as u32);
 
         // ISS should be selected and a SYN segment sent of the form:
         //     <SEQ=ISS><ACK=RCV.NXT><CTL=SYN,ACK>
@@ -117,53 +307,113 @@ impl<'a> Connection<Listen<'a>> {
         reply_tcp_header.acknowledgment_number = next_state.rcv.nxt;
         reply_tcp_header.syn = true;
         reply_tcp_header.ack = true;
-        // this field is needed, if no checksum, the other host will not respond with ACK.
-        reply_tcp_header.checksum =
-            reply_tcp_header.calc_checksum_ipv4(&self.state.ip_header.to_header(), &[])?;
-
-        let reply_ip_header = Ipv4Header::new(
-            reply_tcp_header.header_len(),
-            64,
-            TCP_PROTOCOL,
-            self.id.dst_addr.octets(),
-            self.id.src_addr.octets(),
-        );
-
-        // TODO: maybe there are better ways instead of init vec?
-        let mut response = vec![];
-        reply_ip_header.write(&mut response)?;
-        reply_tcp_header.write(&mut response)?;
-
-        nic.send(&response)?;
+        // an ECN-setup SYN has both ECE and CWR, answered with ECE alone, RFC 3168 6.1.1
+        if self.state.syn.tcp.ece() && self.state.syn.tcp.cwr() && !cookie {
+            reply_tcp_header.ece = true;
+            self.ecn = Some(Ecn::default());
+        }
+        let mut options = vec![TcpOption::Mss(tunables.mss)];
+        self.sack = self.state.syn.sack_permitted() && !cookie;
+        if self.sack {
+            options.push(TcpOption::SackPermitted);
+        }
+        if peer_shift.is_some() {
+            options.push(TcpOption::WindowScale(rcv_shift));
+        }
+        // the timestamps are only in effect when both sides send them, RFC 7323 3.2. Next to a signature
+        // they leave no room for a SACK block, they give way to SACK then, as in Linux
+        let timestamps = self
+            .state
+            .syn
+            .timestamps()
+            .filter(|_| (self.md5_key.is_none() || !self.sack) && !cookie);
+        if let Some((tsval, _)) = timestamps {
+            let now = self.clock.now();
+            let timestamps = Timestamps::new(tsval, next_state.rcv.nxt, now);
+            let (tsval, tsecr) = timestamps.option(now);
+            options.push(TcpOption::Timestamps { tsval, tsecr });
+            self.timestamps = Some(timestamps);
+        }
+        let cookie = fastopen::cookie(self.id.src_addr);
+        if give_cookie {
+            options.push(TcpOption::FastOpen(&cookie));
+        }
+        let custom = self.custom_options();
+        let room = MAX_OPTIONS_LEN - self.signature().map_or(0, |s| s.option_len());
+        options::append_custom(&mut options, &custom, room);
+        reply_tcp_header
+            .set_options_raw(&options::build(&options))
+            .map_err(|e| TcpError::Send(anyhow::anyhow!("{e:?}")))?;
+        let signature = self.signature();
+        if let Some(signature) = signature.as_ref() {
+            signature
+                .add_option(&mut reply_tcp_header)
+                .map_err(TcpError::Send)?;
+        }
+        send_segment_with(
+            nic,
+            &self.id,
+            reply_tcp_header,
+            &[],
+            self.ip_params(NOT_ECT),
+            signature.as_ref(),
+        )
+        .map_err(TcpError::Send)?;
+        self.rcv_buf
+            .on_advertise(next_state.rcv.nxt, window_size as u32, self.clock.now());
 
-        let Connection { id, .. } = self;
-        Ok(Connection::from(id, next_state))
+        Ok(self.transition(|_| next_state))
     }
 }
 
 /// Implements the reciving of ACK after Syn Recv
 ///   4.  ESTABLISHED --> <SEQ=101><ACK=301><CTL=ACK>       --> ESTABLISHED
 impl Connection<SynRecv> {
+    /// Reads the data of a Fast Open SYN, which the application gets without waiting for the
+    /// handshake to complete. Returns the number of bytes copied into `buf`.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let n = drain_into(&mut self.incoming, buf);
+        self.bytes_read += n as u64;
+        self.rcv_buf.on_read(n, self.clock.now());
+        n
+    }
+
     pub fn check_ack(
-        self,
-        _nic: &tun_tap::Iface,
-        tcp_header: &TcpHeaderSlice,
-    ) -> Result<Connection<Established>> {
+        mut self,
+        _nic: &dyn NetworkDevice,
+        seg: &SegmentView,
+    ) -> Result<Connection<Established>, TcpError> {
+        if !self.verify_signature(seg) {
+            return Err(TcpError::BadSignature(
+                "ack with a bad signature".to_string(),
+            ));
+        }
+        let tcp_header = &seg.tcp;
+        if !is_recv_data_in_window(&self.state.rcv, tcp_header, None) {
+            return Err(TcpError::OutOfWindow);
+        }
+        // an acceptable RST returns a passive open to LISTEN, the connection is gone
+        if tcp_header.rst() {
+            return Err(TcpError::RstReceived);
+        }
         if !tcp_header.ack() {
-            return Err(anyhow!("no ack received"));
+            return Err(TcpError::NoAck);
         }
 
         if !is_ack_in_window(&self.state.snd, tcp_header.acknowledgment_number()) {
-            return Err(anyhow!("not valid ack for syn recv"));
-        }
-
-        if !is_recv_data_in_window(&self.state.rcv, tcp_header, None) {
-            return Err(anyhow!("not valid ack for syn recv"));
+            return Err(TcpError::UnacceptableAck);
         }
 
-        let Connection { id, state } = self;
-        let next_state = unsafe { std::mem::transmute::<SynRecv, Established>(state) };
+        let mut conn =
+            self.transition(|state| unsafe { std::mem::transmute::<SynRecv, Established>(state) });
+        // Enter ESTABLISHED with SND.WND <- SEG.WND, SND.WL1 <- SEG.SEQ, SND.WL2 <- SEG.ACK,
+        // see https://www.ietf.org/rfc/rfc9293.txt 3.10.7.4
+        conn.state.snd.wnd = (tcp_header.window_size() as u32) << conn.state.snd.wnd_shift;
+        conn.state.snd.wl1 = tcp_header.sequence_number();
+        conn.state.snd.wl2 = tcp_header.acknowledgment_number();
+        // our SYN is acknowledged
+        conn.state.snd.una = tcp_header.acknowledgment_number();
 
-        Ok(Connection::from(id, next_state))
+        Ok(conn)
     }
 }
@@ -0,0 +1,238 @@
+//! The active open, https://www.ietf.org/rfc/rfc9293.txt 3.10.7.3:
+//!
+//! ```text
+//!       TCP A                                                TCP B
+//!
+//!   1.  CLOSED                                               LISTEN
+//!
+//!   2.  SYN-SENT    --> <SEQ=100><CTL=SYN>               --> SYN-RECEIVED
+//!
+//!   3.  ESTABLISHED <-- <SEQ=300><ACK=101><CTL=SYN,ACK>  <-- SYN-RECEIVED
+//!
+//!   4.  ESTABLISHED --> <SEQ=101><ACK=301><CTL=ACK>       --> ESTABLISHED
+//! ```
+//!
+//! The SYN offers the MSS, SACK, window scaling and the timestamps, and is sent again with the RTO
+//! backed off until the SYN-ACK arrives, `SYN_RETRIES` times at most. A SYN of the peer without an
+//! ACK, a simultaneous open, is dropped: the peer answers our SYN with a SYN-ACK all the same, which
+//! completes the handshake on both sides.
+
+use crate::device::NetworkDevice;
+use crate::tcp::congestion::Congestion;
+use crate::tcp::ecn::NOT_ECT;
+use crate::tcp::error::TcpError;
+use crate::tcp::options::{self, TcpOption};
+use crate::tcp::state::{Established, SynSent};
+use crate::tcp::timestamps::Timestamps;
+use crate::tcp::{
+    is_ack_in_window, send_segment_with, Connection, ConnectionID, ReceiveSequenceSpace,
+    SendSequenceSpace, Tunables, DEFAULT_MSS, MAX_WND_SHIFT,
+};
+use crate::wire::SegmentView;
+use anyhow::{anyhow, Result};
+use etherparse::TcpHeader;
+use std::time::{Duration, Instant};
+
+/// The times an unanswered SYN is sent again before the open fails, Linux's `tcp_syn_retries`
+pub const SYN_RETRIES: u32 = 6;
+
+impl Connection<SynSent> {
+    /// Opens the connection `id` with a SYN of sequence number `iss`, signed with the MD5 key of the
+    /// peer when it has one. A peer with TCP-AO MKTs is refused, the traffic keys of an active open
+    /// aren't derived.
+    pub fn connect(
+        nic: &dyn NetworkDevice,
+        id: ConnectionID,
+        tunables: &Tunables,
+        iss: u32,
+    ) -> Result<Self> {
+        if !tunables.ao_keys.get(id.src_addr).is_empty() {
+            return Err(anyhow!("no active open to {:} with tcp-ao", id.src_addr));
+        }
+        let state = SynSent {
+            // SND.UNA is set to ISS and SND.NXT to ISS+1, the window is the peer's SYN-ACK's
+            snd: SendSequenceSpace {
+                up: false,
+                up_seq: 0,
+                wnd: 0,
+                wnd_shift: 0,
+                una: iss,
+                nxt: iss.wrapping_add(1),
+                wl1: 0,
+                wl2: 0,
+                iss,
+            },
+            rcv: ReceiveSequenceSpace {
+                up: false,
+                up_seq: 0,
+                wnd: 0,
+                wnd_shift: 0,
+                nxt: 0,
+                irs: 0,
+            },
+            retries: 0,
+            rto: Duration::ZERO,
+            deadline: Instant::now(),
+        };
+        let mut conn = Self::from(id, state);
+        conn.configure(tunables, tunables.mss);
+        conn.md5_key = tunables.md5_keys.get(conn.id.src_addr).map(<[u8]>::to_vec);
+        // SACK is always offered, next to a signature the timestamps give way to it as in `answer`
+        let now = conn.clock.now();
+        if conn.md5_key.is_none() {
+            conn.timestamps = Some(Timestamps::new(0, 0, now));
+        }
+        // the window of a SYN is never scaled, the shift offered applies from the SYN-ACK on
+        conn.state.rcv.wnd = conn.rcv_buf.window(0).min(u16::MAX as u32);
+        conn.state.rcv.wnd_shift = conn.rcv_buf.window_shift();
+        conn.state.rto = conn.retransmit.rto();
+        conn.send_syn(nic, now)?;
+        Ok(conn)
+    }
+
+    /// Sends the SYN, <SEQ=ISS><CTL=SYN>, and arms the timer sending it again.
+    fn send_syn(&mut self, nic: &dyn NetworkDevice, now: Instant) -> Result<()> {
+        let mut syn = TcpHeader::new(
+            self.id.dst_port,
+            self.id.src_port,
+            self.state.snd.iss,
+            self.state.rcv.wnd as u16,
+        );
+        syn.syn = true;
+        let mut options = vec![
+            TcpOption::Mss(self.mss),
+            TcpOption::SackPermitted,
+            TcpOption::WindowScale(self.state.rcv.wnd_shift),
+        ];
+        if let Some(timestamps) = self.timestamps.as_ref() {
+            let (tsval, tsecr) = timestamps.option(now);
+            options.push(TcpOption::Timestamps { tsval, tsecr });
+        }
+        syn.set_options_raw(&options::build(&options))
+            .map_err(|e| anyhow!("{e:?}"))?;
+        let signature = self.signature();
+        if let Some(signature) = signature.as_ref() {
+            signature.add_option(&mut syn)?;
+        }
+        send_segment_with(
+            nic,
+            &self.id,
+            syn,
+            &[],
+            self.ip_params(NOT_ECT),
+            signature.as_ref(),
+        )?;
+        self.state.deadline = now + self.state.rto;
+        Ok(())
+    }
+
+    /// When the SYN is sent again, or the open fails.
+    pub fn syn_deadline(&self) -> Instant {
+        self.state.deadline
+    }
+
+    /// Whether the SYN went unanswered after it was sent again `SYN_RETRIES` times, the open failed.
+    pub fn is_timed_out(&self, now: Instant) -> bool {
+        self.state.retries >= SYN_RETRIES && now >= self.state.deadline
+    }
+
+    /// Sends the SYN again once its timer expired, the RTO backed off, see
+    /// https://www.ietf.org/rfc/rfc6298.txt 5.5 and 5.6.
+    pub fn on_timer(&mut self, nic: &dyn NetworkDevice, now: Instant) -> Result<()> {
+        if now < self.state.deadline || self.is_timed_out(now) {
+            return Ok(());
+        }
+        self.state.retries += 1;
+        self.state.rto = (self.state.rto * 2).min(self.retransmit.max_rto());
+        log::debug!(
+            "syn to {:?} sent again, rto backed off to {:?}",
+            self.id,
+            self.state.rto
+        );
+        self.send_syn(nic, now)
+    }
+
+    /// Checks a segment received in SYN-SENT, https://www.ietf.org/rfc/rfc9293.txt 3.10.7.3: Ok for a
+    /// SYN-ACK acknowledging our SYN, to `establish` the connection with. `RstReceived` for a RST
+    /// acknowledging it, the peer refused the connection, and `UnacceptableAck` for any other ACK, to
+    /// be answered with <SEQ=SEG.ACK><CTL=RST>. The rest is dropped.
+    pub fn check_syn_ack(&mut self, seg: &SegmentView) -> Result<(), TcpError> {
+        if !self.verify_signature(seg) {
+            return Err(TcpError::BadSignature(
+                "segment in syn-sent with a bad signature".to_string(),
+            ));
+        }
+        let tcp = &seg.tcp;
+        // ISS < SEG.ACK =< SND.NXT, a RST with any other ACK is dropped
+        if tcp.ack() && !is_ack_in_window(&self.state.snd, tcp.acknowledgment_number()) {
+            return Err(match tcp.rst() {
+                true => TcpError::OutOfWindow,
+                false => TcpError::UnacceptableAck,
+            });
+        }
+        // a RST is only acceptable when it acknowledges the SYN
+        if tcp.rst() {
+            return Err(match tcp.ack() {
+                true => TcpError::RstReceived,
+                false => TcpError::NoAck,
+            });
+        }
+        if !tcp.syn() {
+            return Err(TcpError::NoSyn);
+        }
+        // a simultaneous open, see above
+        if !tcp.ack() {
+            return Err(TcpError::NoAck);
+        }
+        Ok(())
+    }
+
+    /// Enters ESTABLISHED on the SYN-ACK `seg` accepted by `check_syn_ack`, with the options both sides
+    /// sent. The ACK completing the handshake is owed, sent by the next `transmit`.
+    pub fn establish(mut self, seg: &SegmentView, tunables: &Tunables) -> Connection<Established> {
+        let tcp = &seg.tcp;
+        let now = self.clock.now();
+        // a peer not announcing its MSS can take the default, https://www.ietf.org/rfc/rfc1122.txt 4.2.2.6
+        let peer_mss = seg.mss().unwrap_or(DEFAULT_MSS);
+        self.mss = self.mss.min(peer_mss);
+        log::debug!("mss {:}, the peer announced {peer_mss:}", self.mss);
+        self.congestion = Congestion::new(tunables.congestion_control.controller(self.mss as u32));
+        self.congestion.set_pacing(tunables.pacing);
+        // window scaling is only in effect when both sides send the option, RFC 7323 2.2 and 2.3
+        let (snd_shift, rcv_shift) = match seg.window_scale() {
+            Some(shift) => (shift.min(MAX_WND_SHIFT), self.state.rcv.wnd_shift),
+            None => (0, 0),
+        };
+        self.sack = seg.sack_permitted();
+        let irs = tcp.sequence_number();
+        let rcv_nxt = irs.wrapping_add(1);
+        match (self.timestamps.as_mut(), seg.timestamps()) {
+            (Some(timestamps), Some((tsval, _))) => timestamps.on_syn_ack(tsval, rcv_nxt, now),
+            _ => self.timestamps = None,
+        }
+        self.rcv_buf.on_advertise(rcv_nxt, self.state.rcv.wnd, now);
+
+        // SND.UNA <- SEG.ACK, and the window of the SYN-ACK, never scaled
+        let ack = tcp.acknowledgment_number();
+        let mut conn = self.transition(|state| Established {
+            snd: SendSequenceSpace {
+                una: ack,
+                wnd: tcp.window_size() as u32,
+                wnd_shift: snd_shift,
+                wl1: irs,
+                wl2: ack,
+                ..state.snd
+            },
+            rcv: ReceiveSequenceSpace {
+                nxt: rcv_nxt,
+                wnd_shift: rcv_shift,
+                irs,
+                ..state.rcv
+            },
+        });
+        conn.max_snd_wnd = conn.state.snd.wnd;
+        // <SEQ=SND.NXT><ACK=RCV.NXT><CTL=ACK>
+        conn.ack_now();
+        conn
+    }
+}
@@ -0,0 +1,148 @@
+//! SYN cookies, https://www.ietf.org/rfc/rfc4987.txt 3.6: a SYN is answered without keeping any state,
+//! what the connection needs being encoded in the ISS of the SYN-ACK, and the connection is created
+//! when the final ACK returns the cookie, as SEG.ACK - 1. As in https://cr.yp.to/syncookies.html, the
+//! cookie is, from the top:
+//!
+//! ```text
+//!   5 bits  the counter of 64 second periods, modulo 32
+//!   3 bits  the index of the MSS in `MSS_TABLE`
+//!  24 bits  a keyed hash of the 4-tuple, the peer's ISN and the counter
+//! ```
+//!
+//! A cookie is valid for one to two periods. Nothing else of the SYN is kept: a connection opened with
+//! a cookie has neither window scaling, SACK, timestamps, ECN nor Fast Open, which the SYN-ACK doesn't
+//! offer. The key is drawn once per process, as the Fast Open one.
+
+use crate::tcp::ConnectionID;
+use anyhow::{anyhow, Result};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// When SYNs are answered with a cookie, as Linux's `tcp_syncookies`
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SynCookies {
+    /// Never, every SYN creates a connection in SYN-RECEIVED
+    Off,
+    /// Once the handshakes in progress on the port reach its backlog, as under a SYN flood
+    Overflow,
+    /// Always
+    Always,
+}
+
+impl TryFrom<u64> for SynCookies {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(SynCookies::Off),
+            1 => Ok(SynCookies::Overflow),
+            2 => Ok(SynCookies::Always),
+            _ => Err(anyhow!(
+                "unknown syn cookies mode {value:}, expect 0 (off), 1 (overflow) or 2 (always)"
+            )),
+        }
+    }
+}
+
+impl From<SynCookies> for u64 {
+    fn from(mode: SynCookies) -> Self {
+        match mode {
+            SynCookies::Off => 0,
+            SynCookies::Overflow => 1,
+            SynCookies::Always => 2,
+        }
+    }
+}
+
+/// The MSS a cookie encodes, the largest not above the one of the connection is taken
+pub const MSS_TABLE: [u16; 8] = [536, 1024, 1220, 1300, 1400, 1440, 1452, 1460];
+
+/// The period of the counter, in seconds
+const PERIOD: u64 = 64;
+
+static SECRET: OnceLock<RandomState> = OnceLock::new();
+
+/// The counter of periods now.
+fn counter() -> u32 {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (since.as_secs() / PERIOD) as u32
+}
+
+fn hash(id: &ConnectionID, peer_isn: u32, counter: u32) -> u32 {
+    let hash = SECRET
+        .get_or_init(RandomState::new)
+        .hash_one((id, peer_isn, counter));
+    hash as u32 & 0xffffff
+}
+
+/// The cookie of the connection `id` opened by a SYN of sequence number `peer_isn`, and the MSS it
+/// encodes, the largest in the table not above `mss`.
+pub fn encode(id: &ConnectionID, peer_isn: u32, mss: u16) -> (u32, u16) {
+    encode_at(id, peer_isn, mss, counter())
+}
+
+fn encode_at(id: &ConnectionID, peer_isn: u32, mss: u16, counter: u32) -> (u32, u16) {
+    let index = MSS_TABLE.iter().rposition(|m| *m <= mss).unwrap_or(0);
+    let cookie = (counter % 32) << 27 | (index as u32) << 24 | hash(id, peer_isn, counter);
+    (cookie, MSS_TABLE[index])
+}
+
+/// The MSS encoded in `cookie` when it's the one of `id` and `peer_isn` given out in this period or
+/// the previous one, none otherwise.
+pub fn decode(id: &ConnectionID, peer_isn: u32, cookie: u32) -> Option<u16> {
+    decode_at(id, peer_isn, cookie, counter())
+}
+
+fn decode_at(id: &ConnectionID, peer_isn: u32, cookie: u32, counter: u32) -> Option<u16> {
+    let valid = [counter, counter.wrapping_sub(1)]
+        .into_iter()
+        .any(|c| cookie >> 27 == c % 32 && cookie & 0xffffff == hash(id, peer_isn, c));
+    valid.then(|| MSS_TABLE[(cookie >> 24 & 7) as usize])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::handshake::cookie::{decode, decode_at, encode, encode_at, SynCookies};
+    use crate::tcp::ConnectionID;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_cookie() {
+        let id = ConnectionID {
+            src_addr: Ipv4Addr::new(192, 167, 1, 2),
+            src_port: 40000,
+            dst_addr: Ipv4Addr::new(192, 167, 1, 1),
+            dst_port: 80,
+        };
+        let (cookie, mss) = encode(&id, 100, 1400);
+        assert_eq!(mss, 1400);
+        assert_eq!(decode(&id, 100, cookie), Some(1400));
+        // another peer, ISN or cookie
+        let other = ConnectionID {
+            src_port: 40001,
+            ..id.clone()
+        };
+        assert_eq!(decode(&other, 100, cookie), None);
+        assert_eq!(decode(&id, 101, cookie), None);
+        assert_eq!(decode(&id, 100, cookie ^ 1), None);
+
+        // the largest MSS of the table not above the connection's
+        assert_eq!(encode(&id, 100, 1459).1, 1452);
+        assert_eq!(encode(&id, 100, 9000).1, 1460);
+        assert_eq!(encode(&id, 100, 100).1, 536);
+
+        // valid for the period it's given out in and the next one
+        let (cookie, _) = encode_at(&id, 100, 1460, 1000);
+        assert_eq!(decode_at(&id, 100, cookie, 1000), Some(1460));
+        assert_eq!(decode_at(&id, 100, cookie, 1001), Some(1460));
+        assert_eq!(decode_at(&id, 100, cookie, 1002), None);
+        assert_eq!(decode_at(&id, 100, cookie, 1032), None);
+
+        assert_eq!(SynCookies::try_from(1).unwrap(), SynCookies::Overflow);
+        assert!(SynCookies::try_from(3).is_err());
+    }
+}
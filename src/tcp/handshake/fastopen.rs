@@ -0,0 +1,52 @@
+//! The cookies of TCP Fast Open, see https://www.ietf.org/rfc/rfc7413.txt. A client asks for a cookie
+//! with an empty Fast Open option in its SYN, and presents it in the SYNs of later connections to have
+//! the data they carry accepted before the handshake completes.
+//!
+//! The cookie is a keyed hash of the client address, the key is drawn once per process: restarting
+//! the stack invalidates every cookie given out, the clients then fall back to a regular handshake.
+
+use crate::tcp::options::{self, TcpOption};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::Ipv4Addr;
+use std::sync::OnceLock;
+
+pub const COOKIE_LEN: usize = 8;
+
+static SECRET: OnceLock<RandomState> = OnceLock::new();
+
+/// The cookie of the client at `addr`, RFC 7413 4.1.2.
+pub fn cookie(addr: Ipv4Addr) -> [u8; COOKIE_LEN] {
+    SECRET
+        .get_or_init(RandomState::new)
+        .hash_one(addr)
+        .to_be_bytes()
+}
+
+/// Whether `cookie` is the one given out to the client at `addr`.
+pub fn is_valid(addr: Ipv4Addr, cookie: &[u8]) -> bool {
+    cookie == self::cookie(addr)
+}
+
+/// The Fast Open option carrying the cookie of `addr`, preceded by two NOPs to keep the options
+/// aligned.
+pub fn option(addr: Ipv4Addr) -> Vec<u8> {
+    options::build(&[TcpOption::FastOpen(&cookie(addr))])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::handshake::fastopen::{cookie, is_valid, option, COOKIE_LEN};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_cookie() {
+        let client = Ipv4Addr::new(192, 167, 1, 2);
+        let other = Ipv4Addr::new(192, 167, 1, 3);
+        assert_eq!(cookie(client), cookie(client));
+        assert!(is_valid(client, &cookie(client)));
+        assert!(!is_valid(other, &cookie(client)));
+        assert!(!is_valid(client, &[]));
+        assert_eq!(option(client).len(), 4 + COOKIE_LEN);
+    }
+}
@@ -0,0 +1,142 @@
+//! A snapshot of the state and the measurements of a connection, what `TCP_INFO` returns on Linux, for
+//! applications and the admin interface to see how a connection is doing.
+
+use crate::tcp::state::Established;
+use crate::tcp::Connection;
+use std::time::Duration;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct TcpInfo {
+    /// The state of RFC 793, see `close`
+    pub state: &'static str,
+    /// The smoothed RTT, none before the first measurement
+    pub srtt: Option<Duration>,
+    pub rttvar: Option<Duration>,
+    pub rto: Duration,
+    pub cwnd: u32,
+    pub ssthresh: u32,
+    /// SND.NXT - SND.UNA
+    pub in_flight: u32,
+    /// The segments retransmitted, after a timeout or deemed lost
+    pub retransmits: u64,
+    /// SND.WND, the window advertised by the peer
+    pub snd_wnd: u32,
+    /// RCV.WND, the window we advertised
+    pub rcv_wnd: u32,
+    pub snd_buf: u32,
+    pub rcv_buf: u32,
+    pub mss: u16,
+    /// The octets the peer acknowledged
+    pub bytes_acked: u64,
+    /// The octets received in order, read or not
+    pub bytes_received: u64,
+    /// The TOS octet of the last segment received, its DSCP and ECN codepoint
+    pub received_tos: Option<u8>,
+    /// The type and code of the last ICMP soft error about the connection, see `ip::icmp`
+    pub icmp_error: Option<(u8, u8)>,
+}
+
+impl TcpInfo {
+    /// All the measurements with their names, in a stable order. The durations are in microseconds,
+    /// 0 when not measured yet.
+    pub fn values(&self) -> Vec<(&'static str, u64)> {
+        let us = |d: Option<Duration>| d.map_or(0, |d| d.as_micros() as u64);
+        vec![
+            ("srtt_us", us(self.srtt)),
+            ("rttvar_us", us(self.rttvar)),
+            ("rto_us", us(Some(self.rto))),
+            ("cwnd", self.cwnd as u64),
+            ("ssthresh", self.ssthresh as u64),
+            ("in_flight", self.in_flight as u64),
+            ("retransmits", self.retransmits),
+            ("snd_wnd", self.snd_wnd as u64),
+            ("rcv_wnd", self.rcv_wnd as u64),
+            ("snd_buf", self.snd_buf as u64),
+            ("rcv_buf", self.rcv_buf as u64),
+            ("mss", self.mss as u64),
+            ("bytes_acked", self.bytes_acked),
+            ("bytes_received", self.bytes_received),
+            ("received_tos", self.received_tos.map_or(0, u64::from)),
+            // the type in the high octet, no error is type 0
+            (
+                "icmp_error",
+                self.icmp_error.map_or(0, |(icmp_type, code)| {
+                    u64::from(icmp_type) << 8 | u64::from(code)
+                }),
+            ),
+        ]
+    }
+}
+
+impl Connection<Established> {
+    pub fn info(&self) -> TcpInfo {
+        let snd = &self.state.snd;
+        TcpInfo {
+            state: self.close_state().name(),
+            srtt: self.congestion.srtt(),
+            rttvar: self.congestion.rttvar(),
+            rto: self.retransmit.rto(),
+            cwnd: self.congestion.cwnd(),
+            ssthresh: self.congestion.ssthresh(),
+            in_flight: snd.nxt.wrapping_sub(snd.una),
+            retransmits: self.retransmit.retransmits(),
+            snd_wnd: snd.wnd,
+            rcv_wnd: self.state.rcv.wnd,
+            snd_buf: self.snd_buf.size(),
+            rcv_buf: self.rcv_buf.size(),
+            mss: self.mss,
+            bytes_acked: self.retransmit.delivered(),
+            bytes_received: self.bytes_read + (self.incoming.len() + self.urgent.len()) as u64,
+            received_tos: self.socket_options.received_tos(),
+            icmp_error: self.socket_options.icmp_error(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::stats::Stats;
+    use crate::tcp::{established, DEFAULT_MSS};
+    use crate::wire::SegmentView;
+    use crate::TCP_PROTOCOL;
+    use etherparse::{Ipv4Header, TcpHeader};
+    use std::time::Instant;
+
+    #[test]
+    fn test_info() {
+        let mut conn = established(1000, 1000, 500);
+        let info = conn.info();
+        assert_eq!(info.state, "ESTABLISHED");
+        assert_eq!(info.srtt, None);
+        assert_eq!(info.mss, DEFAULT_MSS);
+
+        conn.write(&[7u8; 30]);
+        let now = Instant::now();
+        conn.retransmit.on_send(1000, 30, now);
+        conn.state.snd.nxt = 1030;
+        assert_eq!(conn.info().in_flight, 30);
+        conn.retransmit.on_timeout(now);
+        assert_eq!(conn.info().retransmits, 1);
+
+        // the peer acknowledges everything
+        let mut header = TcpHeader::new(40000, 80, 500, 1000);
+        header.ack = true;
+        header.acknowledgment_number = 1030;
+        let ip = Ipv4Header::new(
+            header.header_len(),
+            64,
+            TCP_PROTOCOL,
+            [192, 167, 1, 2],
+            [192, 167, 1, 1],
+        );
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        header.write(&mut packet).unwrap();
+        conn.on_segment(&SegmentView::parse(&packet).unwrap(), &mut Stats::default())
+            .unwrap();
+        let info = conn.info();
+        assert_eq!(info.in_flight, 0);
+        assert_eq!(info.bytes_acked, 30);
+        assert_eq!(info.values()[6], ("retransmits", 1));
+    }
+}
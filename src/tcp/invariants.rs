@@ -0,0 +1,184 @@
+//! Internal consistency checks of a connection, run continuously in soak mode to catch state
+//! corruption as soon as it happens instead of hours later as a stalled transfer.
+
+use crate::core::tcb::Synchronized;
+use crate::tcp::{wrapping_lt, Connection, ReceiveSequenceSpace, SendSequenceSpace};
+use anyhow::{anyhow, Result};
+
+impl<T> Connection<T>
+where
+    T: AsRef<SendSequenceSpace> + AsRef<ReceiveSequenceSpace> + Synchronized,
+{
+    /// Checks the sequence spaces are sane and the buffered data adds up with what was received.
+    pub fn check_invariants(&self) -> Result<()> {
+        check_send_space(self.state.as_ref(), T::SYN_ACKED)?;
+        check_receive_space(self.state.as_ref())?;
+
+        // the data in flight is kept for retransmission, the SYN and the FIN occupy a sequence number
+        // but no data
+        let snd: &SendSequenceSpace = self.state.as_ref();
+        let syn_in_flight = !T::SYN_ACKED as u32;
+        let in_flight = snd.nxt.wrapping_sub(snd.una).wrapping_sub(syn_in_flight) as usize;
+        let fin_in_flight = (self.close.is_fin_sent() && !self.close.is_fin_acked()) as usize;
+        if in_flight - fin_in_flight > self.outgoing.len() {
+            return Err(anyhow!(
+                "{in_flight:} octets in flight but only {:} buffered",
+                self.outgoing.len()
+            ));
+        }
+        // the retransmission timer runs exactly while data is in flight
+        if (in_flight > 0) != self.retransmit.is_armed() {
+            return Err(anyhow!(
+                "{in_flight:} octets in flight but the retransmission timer armed is {:}",
+                self.retransmit.is_armed()
+            ));
+        }
+        // SND.UNA < SND.UP =< SND.UNA + the octets buffered, while urgent data is not acknowledged
+        let buffered_end = snd.una.wrapping_add(self.outgoing.len() as u32);
+        if snd.up && (!wrapping_lt(snd.una, snd.up_seq) || wrapping_lt(buffered_end, snd.up_seq)) {
+            return Err(anyhow!(
+                "expect SND.UNA < SND.UP =< {buffered_end:}, got {:} {:}",
+                snd.una,
+                snd.up_seq
+            ));
+        }
+
+        // everything received in order is either still buffered or was read by the application,
+        // RCV.NXT - IRS - 1 excludes the SYN, and the FIN once received. Both sides are modulo 2^32, the
+        // offsets from IRS wrap along with the octets read
+        let rcv: &ReceiveSequenceSpace = self.state.as_ref();
+        let received = match T::SYN_RECEIVED {
+            true => rcv
+                .nxt
+                .wrapping_sub(rcv.irs)
+                .wrapping_sub(1 + self.close.has_peer_fin() as u32),
+            false => 0,
+        };
+        let accounted =
+            (self.bytes_read as u32).wrapping_add((self.incoming.len() + self.urgent.len()) as u32);
+        if received != accounted {
+            return Err(anyhow!(
+                "received {received:} octets but {accounted:} are buffered or read"
+            ));
+        }
+        // the peer never gets a window beyond the free space of the receive buffer, the out of order
+        // data is charged to the receive memory as well
+        let buffered = self.incoming.len() + self.urgent.len();
+        let queued = self.reassembly.len();
+        if (buffered + queued) as u64 != self.rcv_buf.buffered() {
+            return Err(anyhow!(
+                "{buffered:} octets buffered and {queued:} queued but {:} accounted in the receive memory",
+                self.rcv_buf.buffered()
+            ));
+        }
+        if buffered > self.rcv_buf.size() as usize {
+            return Err(anyhow!(
+                "{buffered:} octets buffered in a receive buffer of {:}",
+                self.rcv_buf.size()
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Checks SND.UNA =< SND.NXT, and SND.UNA = ISS until the SYN is acknowledged. ISS is only compared
+/// with before: past 2^31 octets sent, SND.UNA is behind it modulo 2^32.
+fn check_send_space(snd: &SendSequenceSpace, syn_acked: bool) -> Result<()> {
+    if wrapping_lt(snd.nxt, snd.una) {
+        return Err(anyhow!(
+            "expect SND.UNA =< SND.NXT, got {:} {:}",
+            snd.una,
+            snd.nxt
+        ));
+    }
+    if !syn_acked && snd.una != snd.iss {
+        return Err(anyhow!(
+            "expect SND.UNA = ISS until the SYN is acknowledged, got {:} {:}",
+            snd.una,
+            snd.iss
+        ));
+    }
+    Ok(())
+}
+
+/// Checks the urgent pointer, IRS is checked along with the octets received, see `check_invariants`.
+fn check_receive_space(rcv: &ReceiveSequenceSpace) -> Result<()> {
+    // RCV.NXT < RCV.UP while the urgent octet is not delivered
+    if rcv.up && !wrapping_lt(rcv.nxt, rcv.up_seq) {
+        return Err(anyhow!(
+            "expect RCV.NXT < RCV.UP, got {:} {:}",
+            rcv.nxt,
+            rcv.up_seq
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::state::Established;
+    use crate::tcp::{established, Connection};
+    use std::time::Instant;
+
+    fn connection(una: u32, nxt: u32, irs: u32, rcv_nxt: u32) -> Connection<Established> {
+        let mut conn = established(una, 1000, rcv_nxt);
+        conn.state.snd.nxt = nxt;
+        conn.state.rcv.irs = irs;
+        conn
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        assert!(connection(1, 1, 100, 101).check_invariants().is_ok());
+        // SND.NXT behind SND.UNA
+        assert!(connection(10, 5, 100, 101).check_invariants().is_err());
+        // 3 octets received but nothing buffered
+        assert!(connection(1, 1, 100, 104).check_invariants().is_err());
+
+        let mut conn = connection(1, 1, u32::MAX, 3);
+        conn.incoming.extend(b"ab");
+        conn.bytes_read = 1;
+        // buffered without being accounted in the receive memory
+        assert!(conn.check_invariants().is_err());
+        conn.rcv_buf.on_buffer(2);
+        assert!(conn.check_invariants().is_ok());
+
+        conn.state.rcv.up = true;
+        conn.state.rcv.up_seq = 3;
+        assert!(conn.check_invariants().is_err());
+        conn.state.rcv.up = false;
+
+        // 2 octets in flight, urgent pointer past the queued data
+        conn.state.snd.nxt = 3;
+        assert!(conn.check_invariants().is_err());
+        conn.outgoing.extend(b"abc");
+        // the data in flight without the retransmission timer running
+        assert!(conn.check_invariants().is_err());
+        conn.retransmit.on_send(1, 2, Instant::now());
+        assert!(conn.check_invariants().is_ok());
+        conn.state.snd.up = true;
+        conn.state.snd.up_seq = 5;
+        assert!(conn.check_invariants().is_err());
+    }
+
+    #[test]
+    fn test_check_invariants_wrapped() {
+        // 3 GiB sent and received, SND.UNA is 3 GiB past ISS and RCV.NXT behind IRS modulo 2^32
+        let mut conn = connection(3 << 30, 3 << 30, 100, 101u32.wrapping_add(3 << 30));
+        conn.state.snd.iss = 0;
+        conn.bytes_read = 3 << 30;
+        assert!(conn.check_invariants().is_ok());
+
+        // 4 GiB acknowledged, SND.UNA is back at ISS with nothing in flight
+        let mut conn = connection(0, 0, 100, 102);
+        conn.state.snd.iss = 0;
+        conn.bytes_read = u32::MAX as u64;
+        conn.incoming.extend(b"ab");
+        conn.rcv_buf.on_buffer(2);
+        assert!(conn.check_invariants().is_ok());
+        conn.state.snd.nxt = 2;
+        conn.outgoing.extend(b"ab");
+        conn.retransmit.on_send(0, 2, Instant::now());
+        assert!(conn.check_invariants().is_ok());
+    }
+}
@@ -0,0 +1,149 @@
+//! How the initial send sequence number of a connection is chosen, https://www.ietf.org/rfc/rfc793.txt
+//! page 27. By default as in https://www.ietf.org/rfc/rfc6528.txt 3: the clock of RFC 793 offset by a
+//! keyed hash of the 4-tuple, so the sequence numbers of a connection can't be guessed off path while
+//! the successive incarnations of a 4-tuple still start ahead of each other. Starting every connection
+//! at 0 keeps captures readable, for debugging.
+//!
+//! The key is drawn once per process, a restarted stack picks numbers unrelated to the former ones, the
+//! connections it had being gone anyway.
+
+use crate::tcp::md5;
+use crate::tcp::ConnectionID;
+use anyhow::{anyhow, Result};
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum IssPolicy {
+    /// Every connection starts at 0
+    Zero,
+    /// A 32 bit clock ticking every 4 microseconds, wrapping around every 4.55 hours
+    Clock,
+    /// The clock plus a keyed MD5 of the 4-tuple, RFC 6528
+    #[default]
+    Keyed,
+}
+
+static SECRET: OnceLock<[u8; 16]> = OnceLock::new();
+
+/// The key of the hash, the random keys of two `RandomState`s.
+fn secret() -> &'static [u8; 16] {
+    SECRET.get_or_init(|| {
+        let mut secret = [0u8; 16];
+        for half in secret.chunks_exact_mut(8) {
+            half.copy_from_slice(&RandomState::new().hash_one(0u8).to_be_bytes());
+        }
+        secret
+    })
+}
+
+/// The clock of RFC 793, ticking every 4 microseconds.
+fn clock() -> u32 {
+    let since = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (since.as_micros() / 4) as u32
+}
+
+/// F(localip, localport, remoteip, remoteport, secretkey) of RFC 6528 3, the first 32 bits of the MD5,
+/// the local end being the destination of `id`.
+fn offset(id: &ConnectionID, secret: &[u8]) -> u32 {
+    let digest = md5::md5(&[
+        &id.dst_addr.octets(),
+        &id.dst_port.to_be_bytes(),
+        &id.src_addr.octets(),
+        &id.src_port.to_be_bytes(),
+        secret,
+    ]);
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+impl IssPolicy {
+    /// The initial sequence number of the connection `id`.
+    pub fn iss(&self, id: &ConnectionID) -> u32 {
+        match self {
+            IssPolicy::Zero => 0,
+            IssPolicy::Clock => clock(),
+            IssPolicy::Keyed => clock().wrapping_add(offset(id, secret())),
+        }
+    }
+}
+
+impl TryFrom<u64> for IssPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(IssPolicy::Zero),
+            1 => Ok(IssPolicy::Clock),
+            2 => Ok(IssPolicy::Keyed),
+            _ => Err(anyhow!(
+                "unknown iss policy {value:}, expect 0 (zero), 1 (clock) or 2 (keyed)"
+            )),
+        }
+    }
+}
+
+impl From<IssPolicy> for u64 {
+    fn from(policy: IssPolicy) -> Self {
+        match policy {
+            IssPolicy::Zero => 0,
+            IssPolicy::Clock => 1,
+            IssPolicy::Keyed => 2,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::iss::{offset, IssPolicy};
+    use crate::tcp::ConnectionID;
+    use std::net::Ipv4Addr;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_iss() {
+        let id = ConnectionID {
+            src_addr: Ipv4Addr::new(192, 167, 1, 2),
+            src_port: 40000,
+            dst_addr: Ipv4Addr::new(192, 167, 1, 1),
+            dst_port: 80,
+        };
+        assert_eq!(IssPolicy::Zero.iss(&id), 0);
+        let first = IssPolicy::Clock.iss(&id);
+        thread::sleep(Duration::from_millis(1));
+        let ticks = IssPolicy::Clock.iss(&id).wrapping_sub(first);
+        assert!((250..1 << 20).contains(&ticks));
+        assert_eq!(IssPolicy::try_from(1).unwrap(), IssPolicy::Clock);
+        assert_eq!(IssPolicy::try_from(2).unwrap(), IssPolicy::Keyed);
+        assert!(IssPolicy::try_from(3).is_err());
+    }
+
+    #[test]
+    fn test_keyed() {
+        let id = ConnectionID {
+            src_addr: Ipv4Addr::new(192, 167, 1, 2),
+            src_port: 40000,
+            dst_addr: Ipv4Addr::new(192, 167, 1, 1),
+            dst_port: 80,
+        };
+        let other = ConnectionID {
+            src_port: 40001,
+            ..id
+        };
+        // the offset of a 4-tuple is fixed by the key, those of others unrelated
+        assert_eq!(offset(&id, b"key"), offset(&id, b"key"));
+        assert_ne!(offset(&id, b"key"), offset(&other, b"key"));
+        assert_ne!(offset(&id, b"key"), offset(&id, b"other key"));
+
+        // the successive incarnations of a 4-tuple start ahead, with the clock
+        let first = IssPolicy::Keyed.iss(&id);
+        thread::sleep(Duration::from_millis(1));
+        let ticks = IssPolicy::Keyed.iss(&id).wrapping_sub(first);
+        assert!((250..1 << 20).contains(&ticks));
+        assert_eq!(IssPolicy::default(), IssPolicy::Keyed);
+    }
+}
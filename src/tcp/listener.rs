@@ -0,0 +1,192 @@
+//! The accept queue of a bound port, the connections established there until the application accepts
+//! them. It's bounded by the backlog of `listen(2)`: once full, a SYN or the final ACK of a handshake
+//! for the port overflows it, and is dropped or answered with a RST according to the `OverflowPolicy`,
+//! as Linux does with `tcp_abort_on_overflow`.
+//!
+//! A listener deferring the accept, like `TCP_DEFER_ACCEPT`, only queues a connection once it received
+//! data: a request/response server isn't woken up for a port scanner or an idle probe completing the
+//! handshake and sending nothing. Until then, the connection doesn't count against the backlog.
+
+use crate::tcp::ConnectionID;
+use anyhow::{anyhow, Result};
+use std::collections::VecDeque;
+
+/// The backlog of a port bound without one, `SOMAXCONN`
+pub const DEFAULT_BACKLOG: u32 = 128;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum OverflowPolicy {
+    /// Drop the segment, the peer retransmits it and may find room by then
+    Drop,
+    /// Answer with a RST, the peer learns right away the connection was refused
+    Reset,
+}
+
+impl TryFrom<u64> for OverflowPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u64) -> Result<Self> {
+        match value {
+            0 => Ok(OverflowPolicy::Drop),
+            1 => Ok(OverflowPolicy::Reset),
+            _ => Err(anyhow!(
+                "unknown overflow policy {value:}, expect 0 (drop) or 1 (reset)"
+            )),
+        }
+    }
+}
+
+impl From<OverflowPolicy> for u64 {
+    fn from(policy: OverflowPolicy) -> Self {
+        match policy {
+            OverflowPolicy::Drop => 0,
+            OverflowPolicy::Reset => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Listener {
+    /// The connections the queue holds at most
+    backlog: u32,
+    /// The connections established and not accepted yet, the oldest first
+    queue: VecDeque<ConnectionID>,
+    /// Only queue the connections once they received data
+    defer_accept: bool,
+    /// The connections established without data yet, when deferring the accept
+    deferred: Vec<ConnectionID>,
+}
+
+impl Listener {
+    pub fn new(backlog: u32) -> Self {
+        Self {
+            backlog,
+            queue: VecDeque::new(),
+            defer_accept: false,
+            deferred: Vec::new(),
+        }
+    }
+
+    pub fn defer_accept(&self) -> bool {
+        self.defer_accept
+    }
+
+    /// Only queues the connections once they received data. Switching it off queues the connections
+    /// deferred so far.
+    pub fn set_defer_accept(&mut self, defer: bool) {
+        self.defer_accept = defer;
+        if !defer {
+            self.queue.extend(self.deferred.drain(..));
+        }
+    }
+
+    pub fn backlog(&self) -> u32 {
+        self.backlog
+    }
+
+    /// Bounds the queue to `backlog`, the connections queued beyond it stay until accepted.
+    pub fn set_backlog(&mut self, backlog: u32) {
+        self.backlog = backlog;
+    }
+
+    /// Whether another connection would overflow the queue
+    pub fn is_full(&self) -> bool {
+        self.queue.len() >= self.backlog as usize
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Queues a connection established on the port, `has_data` when it received data already.
+    pub fn push(&mut self, id: ConnectionID, has_data: bool) {
+        if self.defer_accept && !has_data {
+            self.deferred.push(id);
+        } else {
+            self.queue.push_back(id);
+        }
+    }
+
+    /// The connection `id` received data, it's queued if it was deferred.
+    pub fn on_data(&mut self, id: &ConnectionID) {
+        if let Some(i) = self.deferred.iter().position(|deferred| deferred == id) {
+            self.queue.push_back(self.deferred.swap_remove(i));
+        }
+    }
+
+    /// The oldest connection queued for which `alive` holds, the ones gone since they were queued are
+    /// dropped from the queue.
+    pub fn accept(&mut self, alive: impl Fn(&ConnectionID) -> bool) -> Option<ConnectionID> {
+        self.deferred.retain(&alive);
+        while let Some(id) = self.queue.pop_front() {
+            if alive(&id) {
+                return Some(id);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::listener::{Listener, OverflowPolicy};
+    use crate::tcp::ConnectionID;
+    use std::net::Ipv4Addr;
+
+    fn id(port: u16) -> ConnectionID {
+        ConnectionID {
+            src_addr: Ipv4Addr::new(192, 167, 1, 2),
+            src_port: port,
+            dst_addr: Ipv4Addr::new(192, 167, 1, 1),
+            dst_port: 80,
+        }
+    }
+
+    #[test]
+    fn test_backlog() {
+        let mut listener = Listener::new(2);
+        listener.push(id(4000), false);
+        assert!(!listener.is_full());
+        listener.push(id(4001), true);
+        assert!(listener.is_full());
+
+        // the connection 4000 is gone before it's accepted
+        assert_eq!(listener.accept(|id| id.src_port != 4000), Some(id(4001)));
+        assert!(listener.is_empty());
+        assert_eq!(listener.accept(|_| true), None);
+
+        listener.push(id(4002), false);
+        listener.set_backlog(1);
+        assert!(listener.is_full());
+    }
+
+    #[test]
+    fn test_defer_accept() {
+        let mut listener = Listener::new(2);
+        listener.set_defer_accept(true);
+        listener.push(id(4000), false);
+        listener.push(id(4001), true);
+        listener.push(id(4002), false);
+        // the connections without data don't count against the backlog
+        assert_eq!(listener.len(), 1);
+        assert_eq!(listener.accept(|_| true), Some(id(4001)));
+        assert_eq!(listener.accept(|_| true), None);
+
+        listener.on_data(&id(4002));
+        assert_eq!(listener.accept(|_| true), Some(id(4002)));
+
+        listener.set_defer_accept(false);
+        assert_eq!(listener.accept(|_| true), Some(id(4000)));
+    }
+
+    #[test]
+    fn test_overflow_policy() {
+        assert_eq!(OverflowPolicy::try_from(1).unwrap(), OverflowPolicy::Reset);
+        assert_eq!(u64::from(OverflowPolicy::Drop), 0);
+        assert!(OverflowPolicy::try_from(2).is_err());
+    }
+}
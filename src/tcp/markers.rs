@@ -0,0 +1,102 @@
+//! Markers the application attaches to the data it writes, reported back once all the data written
+//! up to the marker is acknowledged by the peer. This measures the latency seen by the application,
+//! from the write to the ACK, including the time the data waited in the send buffer.
+
+use crate::tcp::wrapping_lt;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct AckedMarker {
+    pub marker: u64,
+    /// From the marker being placed to the ACK of the data written up to it
+    pub latency: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct Markers {
+    /// The markers with the sequence number following the data they cover, in sequence order
+    pending: VecDeque<(u64, u32, Instant)>,
+    acked: VecDeque<AckedMarker>,
+}
+
+impl Markers {
+    /// Places `marker` at `seq`, the sequence number following the data written so far. `una` is
+    /// SND.UNA, a marker not covering any unacknowledged data is acknowledged right away.
+    pub fn place(&mut self, marker: u64, seq: u32, una: u32, now: Instant) {
+        if seq == una {
+            self.acked.push_back(AckedMarker {
+                marker,
+                latency: Duration::ZERO,
+            });
+            return;
+        }
+        self.pending.push_back((marker, seq, now));
+    }
+
+    /// Moves the markers acknowledged by `ack` to the acknowledged ones.
+    pub fn on_ack(&mut self, ack: u32, now: Instant) {
+        while let Some((marker, seq, at)) = self.pending.front() {
+            if wrapping_lt(ack, *seq) {
+                break;
+            }
+            self.acked.push_back(AckedMarker {
+                marker: *marker,
+                latency: now.duration_since(*at),
+            });
+            self.pending.pop_front();
+        }
+    }
+
+    /// The next marker acknowledged, in the order they were placed.
+    pub fn next_acked(&mut self) -> Option<AckedMarker> {
+        self.acked.pop_front()
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::markers::{AckedMarker, Markers};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_markers() {
+        let now = Instant::now();
+        let mut markers = Markers::default();
+
+        markers.place(1, 100, 100, now);
+        markers.place(2, u32::MAX, 100, now);
+        markers.place(3, 10, 100, now + Duration::from_millis(5));
+        assert_eq!(
+            markers.next_acked(),
+            Some(AckedMarker {
+                marker: 1,
+                latency: Duration::ZERO
+            })
+        );
+        assert_eq!(markers.next_acked(), None);
+
+        // the first marker is passed, the second only reached partially
+        markers.on_ack(5, now + Duration::from_millis(20));
+        assert_eq!(
+            markers.next_acked(),
+            Some(AckedMarker {
+                marker: 2,
+                latency: Duration::from_millis(20)
+            })
+        );
+        assert_eq!(markers.next_acked(), None);
+        assert_eq!(markers.pending(), 1);
+
+        markers.on_ack(10, now + Duration::from_millis(25));
+        assert_eq!(
+            markers.next_acked().unwrap().latency,
+            Duration::from_millis(20)
+        );
+        assert_eq!(markers.pending(), 0);
+    }
+}
@@ -0,0 +1,265 @@
+//! The TCP MD5 signature option of https://www.ietf.org/rfc/rfc2385.txt, as used between BGP peers.
+//! A key is configured per remote address, every segment of a connection with such a peer carries the
+//! MD5 digest of the segment and the key, and segments with a missing or wrong digest are dropped.
+//!
+//! The digest covers, in order: the pseudo header (source and destination addresses, protocol and
+//! segment length), the fixed tcp header with a zero checksum and without the options, the segment
+//! text and the key.
+
+use crate::tcp::options::{self, TcpOption};
+use crate::wire::{SegmentView, OPT_EOL, OPT_MD5SIG, OPT_NOP};
+use crate::TCP_PROTOCOL;
+use anyhow::{anyhow, Result};
+use etherparse::{Ipv4Header, TcpHeader, TCP_MINIMUM_HEADER_SIZE};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::net::Ipv4Addr;
+
+pub const DIGEST_LEN: usize = 16;
+/// The octets of the option, aligned with two NOPs
+pub const OPTION_LEN: usize = 4 + DIGEST_LEN;
+
+/// The offset of the checksum within the tcp header, zeroed for the digest
+const CHECKSUM: std::ops::Range<usize> = 16..18;
+
+/// The keys by remote address, a connection with any other peer isn't signed.
+#[derive(Default, Clone, PartialEq, Eq)]
+pub struct Md5Keys {
+    keys: HashMap<Ipv4Addr, Vec<u8>>,
+}
+
+impl Md5Keys {
+    /// Sets the key of the peer at `addr`, none to stop signing its connections. Only new connections
+    /// are affected.
+    pub fn set(&mut self, addr: Ipv4Addr, key: Option<Vec<u8>>) {
+        match key {
+            Some(key) => self.keys.insert(addr, key),
+            None => self.keys.remove(&addr),
+        };
+    }
+
+    pub fn get(&self, addr: Ipv4Addr) -> Option<&[u8]> {
+        self.keys.get(&addr).map(|key| key.as_slice())
+    }
+}
+
+/// The keys are secrets, only the addresses are shown
+impl Debug for Md5Keys {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.keys.keys()).finish()
+    }
+}
+
+/// The option with a zero digest, filled in by `sign` once the rest of the segment is known.
+pub fn placeholder() -> Vec<u8> {
+    options::build(&[TcpOption::Md5(&[0; DIGEST_LEN])])
+}
+
+/// Appends the option to those of `header`, which must be aligned to 4 octets.
+pub fn add_option(header: &mut TcpHeader) -> Result<()> {
+    let mut options = header.options().to_vec();
+    options.extend_from_slice(&placeholder());
+    header
+        .set_options_raw(&options)
+        .map_err(|e| anyhow!("{e:?}"))
+}
+
+/// Fills in the digest of the option `add_option` added to `tcp`, sent in `ip` with `payload`.
+pub fn sign(ip: &Ipv4Header, tcp: &mut TcpHeader, payload: &[&[u8]], key: &[u8]) -> Result<()> {
+    let mut options = tcp.options().to_vec();
+    let at = find_digest(&options).ok_or_else(|| anyhow!("no md5 option to sign"))?;
+    let mut fixed = Vec::with_capacity(tcp.header_len() as usize);
+    let mut unsigned = tcp.clone();
+    unsigned.checksum = 0;
+    unsigned.write(&mut fixed)?;
+
+    let len = tcp.header_len() as usize + payload.iter().map(|p| p.len()).sum::<usize>();
+    let digest = digest(
+        ip.source,
+        ip.destination,
+        len,
+        &fixed[..TCP_MINIMUM_HEADER_SIZE],
+        payload,
+        key,
+    );
+    options[at..at + DIGEST_LEN].copy_from_slice(&digest);
+    tcp.set_options_raw(&options).map_err(|e| anyhow!("{e:?}"))
+}
+
+/// Whether the segment carries the digest computed with `key`.
+pub fn verify(seg: &SegmentView, key: &[u8]) -> bool {
+    let signature = match seg.md5_signature() {
+        Some(signature) => signature,
+        None => return false,
+    };
+    let mut fixed = [0u8; TCP_MINIMUM_HEADER_SIZE];
+    fixed.copy_from_slice(&seg.tcp.slice()[..TCP_MINIMUM_HEADER_SIZE]);
+    fixed[CHECKSUM].fill(0);
+    let len = seg.tcp.slice().len() + seg.payload.len();
+    let digest = digest(
+        seg.ip.source(),
+        seg.ip.destination(),
+        len,
+        &fixed,
+        &[seg.payload],
+        key,
+    );
+    signature == digest
+}
+
+/// The offset of the digest within the raw options.
+fn find_digest(options: &[u8]) -> Option<usize> {
+    let mut i = 0;
+    while i + 1 < options.len() {
+        match options[i] {
+            OPT_EOL => return None,
+            OPT_NOP => i += 1,
+            OPT_MD5SIG => return Some(i + 2).filter(|at| at + DIGEST_LEN <= options.len()),
+            _ => i += (options[i + 1] as usize).max(2),
+        }
+    }
+    None
+}
+
+fn digest(
+    src: [u8; 4],
+    dst: [u8; 4],
+    len: usize,
+    fixed: &[u8],
+    payload: &[&[u8]],
+    key: &[u8],
+) -> [u8; DIGEST_LEN] {
+    let len = (len as u16).to_be_bytes();
+    let pseudo = [
+        src[0],
+        src[1],
+        src[2],
+        src[3],
+        dst[0],
+        dst[1],
+        dst[2],
+        dst[3],
+        0,
+        TCP_PROTOCOL,
+        len[0],
+        len[1],
+    ];
+    let mut parts = vec![&pseudo[..], fixed];
+    parts.extend_from_slice(payload);
+    parts.push(key);
+    md5(&parts)
+}
+
+/// MD5 of the concatenated `parts`, see https://www.ietf.org/rfc/rfc1321.txt.
+pub(crate) fn md5(parts: &[&[u8]]) -> [u8; DIGEST_LEN] {
+    const S: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    // K[i] = floor(abs(sin(i + 1)) * 2^32)
+    let k = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect::<Vec<_>>();
+
+    let len = parts.iter().map(|p| p.len()).sum::<usize>();
+    let mut message = Vec::with_capacity(len + 72);
+    for part in parts {
+        message.extend_from_slice(part);
+    }
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((len as u64).wrapping_mul(8)).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for chunk in message.chunks_exact(64) {
+        let m = chunk
+            .chunks_exact(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect::<Vec<_>>();
+        let [mut a, mut b, mut c, mut d] = state;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(k[i]).wrapping_add(m[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(S[i]));
+        }
+        state[0] = state[0].wrapping_add(a);
+        state[1] = state[1].wrapping_add(b);
+        state[2] = state[2].wrapping_add(c);
+        state[3] = state[3].wrapping_add(d);
+    }
+
+    let mut digest = [0u8; DIGEST_LEN];
+    for (out, word) in digest.chunks_exact_mut(4).zip(state) {
+        out.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::md5::{add_option, md5, sign, verify};
+    use crate::wire::SegmentView;
+    use crate::TCP_PROTOCOL;
+    use etherparse::{Ipv4Header, TcpHeader, TcpOptionElement};
+
+    fn hex(digest: [u8; 16]) -> String {
+        digest.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    #[test]
+    fn test_md5() {
+        // the test suite of RFC 1321
+        assert_eq!(hex(md5(&[])), "d41d8cd98f00b204e9800998ecf8427e");
+        assert_eq!(hex(md5(&[b"abc"])), "900150983cd24fb0d6963f7d28e17f72");
+        assert_eq!(
+            hex(md5(&[
+                b"12345678901234567890123456789012345678901234567890",
+                b"123456789012345678901234567890"
+            ])),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let mut tcp = TcpHeader::new(179, 40000, 100, 1000);
+        tcp.ack = true;
+        tcp.set_options(&[TcpOptionElement::MaximumSegmentSize(1460)])
+            .unwrap();
+        add_option(&mut tcp).unwrap();
+        let payload = b"OPEN";
+        let ip = Ipv4Header::new(
+            tcp.header_len() + payload.len() as u16,
+            64,
+            TCP_PROTOCOL,
+            [192, 167, 1, 1],
+            [192, 167, 1, 2],
+        );
+        sign(&ip, &mut tcp, &[payload], b"secret").unwrap();
+        tcp.checksum = tcp.calc_checksum_ipv4(&ip, payload).unwrap();
+
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet.extend_from_slice(payload);
+        let seg = SegmentView::parse(&packet).unwrap();
+        assert!(verify(&seg, b"secret"));
+        assert!(!verify(&seg, b"guess"));
+
+        // tampered with on the way
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        assert!(!verify(&SegmentView::parse(&packet).unwrap(), b"secret"));
+    }
+}
@@ -1,207 +1,875 @@
-use crate::{ETH_HEADER_OFFSET, TCP_PROTOCOL};
+use crate::core::checksum;
+use crate::core::segment::Segment;
+use crate::core::seq::is_seq_acceptable;
+use crate::device::NetworkDevice;
+use crate::ip::fragment::{DEFAULT_FRAGMENT_MEM_MAX, DEFAULT_FRAGMENT_TIMEOUT};
+use crate::ip::icmp::DEFAULT_ICMP_RATE_LIMIT;
+use crate::ip::options::IpOptionsPolicy;
+use crate::ip::{self, IpParams};
+use crate::tcp::anomaly::AnomalyPolicy;
+use crate::tcp::ao::{Ao, AoKeys};
+use crate::tcp::autotune::{
+    ReceiveBuffer, SendBuffer, DEFAULT_RCV_BUF_MAX, DEFAULT_RCV_BUF_MIN,
+    DEFAULT_RCV_MEM_GLOBAL_MAX, DEFAULT_SND_BUF_GLOBAL_MAX, DEFAULT_SND_BUF_MAX,
+    DEFAULT_SND_BUF_MIN,
+};
+use crate::tcp::clock::{Clock, SystemClock};
+use crate::tcp::close::Close;
+use crate::tcp::congestion::{Algorithm, Congestion, CongestionControl};
+use crate::tcp::ecn::{Ecn, NOT_ECT};
+use crate::tcp::fingerprint::Fingerprint;
+use crate::tcp::handshake::cookie::SynCookies;
+use crate::tcp::iss::IssPolicy;
+use crate::tcp::listener::{OverflowPolicy, DEFAULT_BACKLOG};
+use crate::tcp::markers::Markers;
+use crate::tcp::md5::Md5Keys;
+use crate::tcp::options::{CustomOption, OptionHook, TcpOption};
+use crate::tcp::ratelimit::TokenBucket;
+use crate::tcp::reassembly::Reassembly;
+use crate::tcp::retransmit::{Retransmission, MAX_RTO, MIN_RTO};
+use crate::tcp::sockopt::SocketOptions;
+use crate::tcp::timeout::Timeouts;
+use crate::tcp::timestamps::Timestamps;
+use crate::tcp::timewait::MSL;
+use crate::wire::SegmentView;
+use crate::TCP_PROTOCOL;
 use anyhow::anyhow;
 use anyhow::Result;
-use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
-use std::net::Ipv4Addr;
+use etherparse::{Ipv4Header, TcpHeader, TcpHeaderSlice};
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+pub(crate) use crate::core::seq::{is_ack_in_window, update_send_window, wrapping_lt};
+pub use crate::core::tcb::{ConnectionID, ReceiveSequenceSpace, SendSequenceSpace};
+
+pub mod anomaly;
+pub mod ao;
+pub mod autotune;
+pub mod bbr;
+pub mod clock;
+pub mod close;
+pub mod congestion;
+pub mod cubic;
+pub mod cwv;
+pub mod diagnostics;
+pub mod ecn;
+pub mod error;
+pub mod established;
+pub mod fingerprint;
+pub mod frto;
 pub mod handshake;
+pub mod info;
+pub mod invariants;
+pub mod iss;
+pub mod listener;
+pub mod markers;
+pub mod md5;
+pub mod options;
+pub mod ports;
+pub mod queue;
+pub mod rack;
+pub mod ratelimit;
+pub mod reassembly;
+pub mod reno;
+pub mod retransmit;
+pub mod sockopt;
 pub mod state;
+pub mod timeout;
+pub mod timer;
+pub mod timestamps;
+pub mod timewait;
 
 pub const DEFAULT_WINDOW_SIZE: u16 = 64240;
+pub const DEFAULT_TTL: u8 = 64;
+/// The MSS to assume when the peer did not send the option, see https://www.ietf.org/rfc/rfc1122.txt 4.2.2.6
+pub const DEFAULT_MSS: u16 = 536;
+/// The challenge ACKs sent per second by default, Linux's former `tcp_challenge_ack_limit`
+pub const DEFAULT_CHALLENGE_ACK_LIMIT: u64 = 1000;
+/// The connections the stack holds at most by default
+pub const DEFAULT_MAX_CONNECTIONS: u64 = 65536;
+/// The largest shift count of the window scale option, see https://www.ietf.org/rfc/rfc7323.txt 2.3
+pub const MAX_WND_SHIFT: u8 = 14;
+/// The ipv4 and tcp headers without options, see https://www.ietf.org/rfc/rfc6691.txt
+const MSS_OVERHEAD: u16 = 40;
+/// The room for options in a tcp header, the data offset being at most 15 words
+pub const MAX_OPTIONS_LEN: usize = 40;
 
-#[derive(PartialEq, Eq, Debug, Clone, Hash)]
-pub struct ConnectionID {
-    pub src_addr: Ipv4Addr,
-    pub src_port: u16,
-    pub dst_addr: Ipv4Addr,
-    pub dst_port: u16,
+/// Knobs of the stack that can be changed at runtime through the control plane.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Tunables {
+    /// The window advertised to the peer in the SYN-ACK, i.e. the initial size of the receive buffer
+    pub window_size: u16,
+    /// The MSS advertised in the SYN-ACK and the most we send, derived from the MTU of the nic
+    pub mss: u16,
+    /// The bounds of the receive buffer, which grows with the bandwidth-delay product, see `autotune`
+    pub rcv_buf_min: u32,
+    pub rcv_buf_max: u32,
+    /// The cap of the data received but not read yet by all the connections together
+    pub rcv_mem_global_max: u64,
+    /// The bounds of the send buffer of a connection, which grows with the window achieved
+    pub snd_buf_min: u32,
+    pub snd_buf_max: u32,
+    /// The cap of the send buffers of all the connections together
+    pub snd_buf_global_max: u64,
+    /// What to do with segments carrying nonsensical flags, see `anomaly`
+    pub anomaly_policy: AnomalyPolicy,
+    /// What to do with packets carrying ip options, see `ip::options`
+    pub ip_options_policy: IpOptionsPolicy,
+    /// The TTL of the packets sent, of new connections and of those answering no connection
+    pub ttl: u8,
+    /// Set DF on the packets of new connections, see `ip::fragment`
+    pub ip_dont_fragment: bool,
+    /// How long the fragments of a datagram are held for the rest to arrive, see `ip::fragment`
+    pub frag_timeout: Duration,
+    /// The cap of the octets held in fragments of all the datagrams together
+    pub frag_mem_max: u64,
+    /// Answer the datagrams of the protocols the stack doesn't handle with an ICMP destination
+    /// unreachable, see `ip::icmp`
+    pub icmp_unreachable: bool,
+    /// The ICMP errors sent per second at most, 0 for no limit
+    pub icmp_rate_limit: u64,
+    /// Reset connections acknowledging data not sent yet, instead of only ignoring those ACKs
+    pub optimistic_ack_reset: bool,
+    /// Accept the data of SYNs presenting a valid TCP Fast Open cookie, and give out cookies on request
+    pub fast_open: bool,
+    /// The congestion control of new connections, see `congestion`
+    pub congestion_control: Algorithm,
+    /// Pace the segments of new connections over the RTT instead of bursting their window
+    pub pacing: bool,
+    /// The backlog of the ports bound without one, see `listener`
+    pub listen_backlog: u32,
+    /// What to do with a SYN or the final ACK of a handshake overflowing the accept queue of its port
+    pub accept_overflow: OverflowPolicy,
+    /// When SYNs are answered with a cookie instead of a connection, see `handshake::cookie`
+    pub syn_cookies: SynCookies,
+    /// The challenge ACKs all the connections send per second at most, 0 for no limit, see
+    /// `established`
+    pub challenge_ack_limit: u64,
+    /// The connections the stack holds at most, in any state, 0 for no limit
+    pub max_connections: u64,
+    /// Evict the oldest handshake in progress to make room for a SYN once `max_connections` are held
+    pub evict_half_open: bool,
+    /// What to do with a SYN finding no room among the `max_connections`
+    pub connections_overflow: OverflowPolicy,
+    /// The MD5 signature keys by peer address, not listed with the other tunables, see `md5`
+    pub md5_keys: Md5Keys,
+    /// The TCP-AO MKTs by peer address, which take precedence over MD5 keys, see `ao`
+    pub ao_keys: AoKeys,
+    /// How the initial sequence numbers of new connections are chosen, see `iss`
+    pub iss_policy: IssPolicy,
+    /// The Maximum Segment Lifetime, the connections closed first stay in TIME-WAIT for twice as long
+    pub msl: Duration,
+    /// The bounds of the retransmission timeout of new connections
+    pub rto_min: Duration,
+    pub rto_max: Duration,
 }
 
-pub fn parse_connection_id(data: &[u8]) -> Result<(ConnectionID, Ipv4HeaderSlice, TcpHeaderSlice)> {
-    let ipv4_header = Ipv4HeaderSlice::from_slice(&data[ETH_HEADER_OFFSET..])?;
-    let ip_proto = ipv4_header.protocol();
-    if ip_proto != TCP_PROTOCOL {
-        return Err(anyhow!("not tcp protocol, skip"));
+impl Default for Tunables {
+    fn default() -> Self {
+        Self {
+            window_size: DEFAULT_WINDOW_SIZE,
+            mss: DEFAULT_MSS,
+            rcv_buf_min: DEFAULT_RCV_BUF_MIN,
+            rcv_buf_max: DEFAULT_RCV_BUF_MAX,
+            rcv_mem_global_max: DEFAULT_RCV_MEM_GLOBAL_MAX,
+            snd_buf_min: DEFAULT_SND_BUF_MIN,
+            snd_buf_max: DEFAULT_SND_BUF_MAX,
+            snd_buf_global_max: DEFAULT_SND_BUF_GLOBAL_MAX,
+            anomaly_policy: AnomalyPolicy::Drop,
+            ip_options_policy: IpOptionsPolicy::Ignore,
+            ttl: DEFAULT_TTL,
+            ip_dont_fragment: true,
+            frag_timeout: DEFAULT_FRAGMENT_TIMEOUT,
+            frag_mem_max: DEFAULT_FRAGMENT_MEM_MAX as u64,
+            icmp_unreachable: true,
+            icmp_rate_limit: DEFAULT_ICMP_RATE_LIMIT,
+            optimistic_ack_reset: false,
+            fast_open: false,
+            congestion_control: Algorithm::Reno,
+            pacing: true,
+            listen_backlog: DEFAULT_BACKLOG,
+            accept_overflow: OverflowPolicy::Drop,
+            syn_cookies: SynCookies::Off,
+            challenge_ack_limit: DEFAULT_CHALLENGE_ACK_LIMIT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            evict_half_open: true,
+            connections_overflow: OverflowPolicy::Drop,
+            md5_keys: Md5Keys::default(),
+            ao_keys: AoKeys::default(),
+            iss_policy: IssPolicy::default(),
+            msl: MSL,
+            rto_min: MIN_RTO,
+            rto_max: MAX_RTO,
+        }
     }
+}
 
-    let tcp_header_idx = ETH_HEADER_OFFSET + ipv4_header.slice().len();
-    let tcp_header = TcpHeaderSlice::from_slice(&data[tcp_header_idx..])?;
+impl Tunables {
+    /// All the tunables with their names, in a stable order.
+    pub fn values(&self) -> Vec<(&'static str, u64)> {
+        vec![
+            ("window_size", self.window_size as u64),
+            ("mss", self.mss as u64),
+            ("rcv_buf_min", self.rcv_buf_min as u64),
+            ("rcv_buf_max", self.rcv_buf_max as u64),
+            ("rcv_mem_global_max", self.rcv_mem_global_max),
+            ("snd_buf_min", self.snd_buf_min as u64),
+            ("snd_buf_max", self.snd_buf_max as u64),
+            ("snd_buf_global_max", self.snd_buf_global_max),
+            ("anomaly_policy", self.anomaly_policy.into()),
+            ("ip_options_policy", self.ip_options_policy.into()),
+            ("ttl", self.ttl as u64),
+            ("ip_dont_fragment", self.ip_dont_fragment as u64),
+            ("frag_timeout_ms", self.frag_timeout.as_millis() as u64),
+            ("frag_mem_max", self.frag_mem_max),
+            ("icmp_unreachable", self.icmp_unreachable as u64),
+            ("icmp_rate_limit", self.icmp_rate_limit),
+            ("optimistic_ack_reset", self.optimistic_ack_reset as u64),
+            ("fast_open", self.fast_open as u64),
+            ("congestion_control", self.congestion_control.into()),
+            ("pacing", self.pacing as u64),
+            ("listen_backlog", self.listen_backlog as u64),
+            ("accept_overflow", self.accept_overflow.into()),
+            ("syn_cookies", self.syn_cookies.into()),
+            ("challenge_ack_limit", self.challenge_ack_limit),
+            ("max_connections", self.max_connections),
+            ("evict_half_open", self.evict_half_open as u64),
+            ("connections_overflow", self.connections_overflow.into()),
+            ("iss_policy", self.iss_policy.into()),
+            ("msl_ms", self.msl.as_millis() as u64),
+            ("rto_min_ms", self.rto_min.as_millis() as u64),
+            ("rto_max_ms", self.rto_max.as_millis() as u64),
+        ]
+    }
 
-    let id = ConnectionID {
-        src_addr: ipv4_header.source_addr(),
-        src_port: tcp_header.source_port(),
-        dst_addr: ipv4_header.destination_addr(),
-        dst_port: tcp_header.destination_port(),
+    /// Updates the tunable by its name, as listed by `values`.
+    pub fn set(&mut self, name: &str, value: u64) -> Result<()> {
+        match name {
+            "window_size" => {
+                self.window_size = u16::try_from(value)
+                    .map_err(|_| anyhow!("window_size {value:} exceeds u16"))?;
+            }
+            "mss" => {
+                self.mss = u16::try_from(value)
+                    .ok()
+                    .filter(|mss| *mss > 0)
+                    .ok_or_else(|| anyhow!("mss {value:} is not within 1..=65535"))?;
+            }
+            "rcv_buf_min" => set_bounds(name, value, &mut self.rcv_buf_min, self.rcv_buf_max)?,
+            "rcv_buf_max" => set_bounds(name, value, &mut self.rcv_buf_max, self.rcv_buf_min)?,
+            "rcv_mem_global_max" => self.rcv_mem_global_max = value,
+            "snd_buf_min" => set_bounds(name, value, &mut self.snd_buf_min, self.snd_buf_max)?,
+            "snd_buf_max" => set_bounds(name, value, &mut self.snd_buf_max, self.snd_buf_min)?,
+            "snd_buf_global_max" => self.snd_buf_global_max = value,
+            "anomaly_policy" => self.anomaly_policy = AnomalyPolicy::try_from(value)?,
+            "ip_options_policy" => self.ip_options_policy = IpOptionsPolicy::try_from(value)?,
+            "ttl" => {
+                self.ttl = u8::try_from(value)
+                    .ok()
+                    .filter(|ttl| *ttl > 0)
+                    .ok_or_else(|| anyhow!("ttl {value:} is not within 1..=255"))?;
+            }
+            "ip_dont_fragment" => set_flag(name, value, &mut self.ip_dont_fragment)?,
+            "frag_timeout_ms" => self.frag_timeout = Duration::from_millis(value),
+            "frag_mem_max" => self.frag_mem_max = value,
+            "icmp_unreachable" => set_flag(name, value, &mut self.icmp_unreachable)?,
+            "icmp_rate_limit" => self.icmp_rate_limit = value,
+            "optimistic_ack_reset" => set_flag(name, value, &mut self.optimistic_ack_reset)?,
+            "fast_open" => set_flag(name, value, &mut self.fast_open)?,
+            "congestion_control" => self.congestion_control = Algorithm::try_from(value)?,
+            "pacing" => set_flag(name, value, &mut self.pacing)?,
+            "listen_backlog" => {
+                self.listen_backlog = u32::try_from(value)
+                    .map_err(|_| anyhow!("listen_backlog {value:} exceeds u32"))?;
+            }
+            "accept_overflow" => self.accept_overflow = OverflowPolicy::try_from(value)?,
+            "syn_cookies" => self.syn_cookies = SynCookies::try_from(value)?,
+            "challenge_ack_limit" => self.challenge_ack_limit = value,
+            "max_connections" => self.max_connections = value,
+            "evict_half_open" => set_flag(name, value, &mut self.evict_half_open)?,
+            "connections_overflow" => self.connections_overflow = OverflowPolicy::try_from(value)?,
+            "iss_policy" => self.iss_policy = IssPolicy::try_from(value)?,
+            "msl_ms" => self.msl = Duration::from_millis(value),
+            "rto_min_ms" => set_rto_bounds(name, value, &mut self.rto_min, self.rto_max)?,
+            "rto_max_ms" => set_rto_bounds(name, value, &mut self.rto_max, self.rto_min)?,
+            _ => return Err(anyhow!("unknown tunable: {name:}")),
+        }
+        Ok(())
+    }
+
+    /// The ip header fields of the packets answering no connection, e.g. resets.
+    pub fn ip_params(&self) -> IpParams {
+        IpParams {
+            ttl: self.ttl,
+            dont_fragment: self.ip_dont_fragment,
+            ..IpParams::default()
+        }
+    }
+}
+
+/// Sets one of a pair of min/max bounds named `*_min`/`*_max`, keeping min =< max.
+fn set_bounds(name: &str, value: u64, bound: &mut u32, other: u32) -> Result<()> {
+    let value = u32::try_from(value).map_err(|_| anyhow!("{name:} {value:} exceeds u32"))?;
+    let (min, max) = if name.ends_with("_min") {
+        (value, other)
+    } else {
+        (other, value)
     };
+    if min > max {
+        return Err(anyhow!(
+            "{name:} {value:} would set min {min:} above max {max:}"
+        ));
+    }
+    *bound = value;
+    Ok(())
+}
 
-    Ok((id, ipv4_header, tcp_header))
+/// Sets one of the RTO bounds in milliseconds, keeping 0 < min =< max.
+fn set_rto_bounds(name: &str, value: u64, bound: &mut Duration, other: Duration) -> Result<()> {
+    if value == 0 {
+        return Err(anyhow!("{name:} is 0, the RTO would never back off"));
+    }
+    let value = Duration::from_millis(value);
+    let (min, max) = if name.starts_with("rto_min") {
+        (value, other)
+    } else {
+        (other, value)
+    };
+    if min > max {
+        return Err(anyhow!(
+            "{name:} {value:?} would set min {min:?} above max {max:?}"
+        ));
+    }
+    *bound = value;
+    Ok(())
 }
 
-/// Send Sequence Variables
-///
-/// SND.UNA - send unacknowledged
-/// SND.NXT - send next
-/// SND.WND - send window
-/// SND.UP  - send urgent pointer
-/// SND.WL1 - segment sequence number used for last window update
-/// SND.WL2 - segment acknowledgment number used for last window update
-/// ISS     - initial send sequence number
-///
-/// 1         2          3          4
-/// ----------|----------|----------|----------
-///         SND.UNA    SND.NXT    SND.UNA
-///                              +SND.WND
-/// 1 - old sequence numbers which have been acknowledged
-/// 2 - sequence numbers of unacknowledged data
-/// 3 - sequence numbers allowed for new data transmission
-/// 4 - future sequence numbers which are not yet allowed
-#[derive(PartialEq, Eq, Debug)]
-#[repr(C)]
-pub struct SendSequenceSpace {
-    pub up: bool,
-    pub wnd: u16,
-    pub una: u32,
-    pub nxt: u32,
-    pub wl1: u32,
-    pub wl2: u32,
-    pub iss: u32,
-}
-
-/// 1          2          3
-/// ----------|----------|----------
-///        RCV.NXT    RCV.NXT
-///                  +RCV.WND
-/// 1 - old sequence numbers which have been acknowledged
-/// 2 - sequence numbers allowed for new reception
-/// 3 - future sequence numbers which are not yet allowed
-#[derive(PartialEq, Eq, Debug)]
-#[repr(C)]
-pub struct ReceiveSequenceSpace {
-    pub up: bool,
-    pub wnd: u16,
-    pub nxt: u32,
-    pub irs: u32,
+/// Sets a tunable switched on with 1 and off with 0.
+fn set_flag(name: &str, value: u64, flag: &mut bool) -> Result<()> {
+    *flag = match value {
+        0 => false,
+        1 => true,
+        _ => return Err(anyhow!("{name:} is 0 or 1, got {value:}")),
+    };
+    Ok(())
 }
 
 pub struct Connection<T> {
     id: ConnectionID,
     state: T,
+    /// In order data received but not read by the application yet
+    incoming: VecDeque<u8>,
+    /// Urgent octets pulled out of the stream, delivered out of band, see `read_urgent`
+    urgent: VecDeque<u8>,
+    /// Data received out of order, waiting for the holes before it
+    reassembly: Reassembly,
+    /// Octets consumed by the application, both in band and urgent
+    bytes_read: u64,
+    /// Data written by the application from SND.UNA on, i.e. both in flight and not sent yet
+    outgoing: VecDeque<u8>,
+    /// An ACK is owed to the peer, sent by the transmit scheduler
+    ack_pending: bool,
+    /// A challenge ACK is owed to the peer, sent unless the rate limit of the stack holds it back, see
+    /// `take_challenge_ack`
+    challenge_ack: bool,
+    /// The largest window the peer advertised, MAX.SND.WND of https://www.ietf.org/rfc/rfc5961.txt 5.2
+    max_snd_wnd: u32,
+    /// The segments in flight and the retransmission timer
+    retransmit: Retransmission,
+    /// The congestion window, which bounds the data in flight along with the peer window
+    congestion: Congestion,
+    /// The egress rate limit set by the application, see `ratelimit`
+    rate_limit: Option<TokenBucket>,
+    /// The size of the receive buffer, which the window advertised is derived from
+    rcv_buf: ReceiveBuffer,
+    /// The size of the send buffer, which bounds `outgoing`
+    snd_buf: SendBuffer,
+    /// Markers placed by the application in the data written, see `mark`
+    markers: Markers,
+    /// The effective MSS, the largest segment we send: the least of the MSS the peer announced in its
+    /// SYN and ours
+    mss: u16,
+    /// Both sides sent SACK-permitted, the peer is told about the data received out of order and tells
+    /// us about its own
+    sack: bool,
+    /// Both sides sent the Timestamps option, every segment carries it
+    timestamps: Option<Timestamps>,
+    /// Both sides are ECN-capable, see `ecn`
+    ecn: Option<Ecn>,
+    /// The key every segment is signed and verified with, see `md5`
+    md5_key: Option<Vec<u8>>,
+    /// The TCP-AO state, which every segment is authenticated with instead, see `ao`
+    ao: Option<Ao>,
+    /// Reset the connection on an ACK of data not sent yet instead of ignoring it
+    reset_optimistic_ack: bool,
+    /// The read and write timeouts set by the application, see `try_read`
+    timeouts: Timeouts,
+    /// Characteristics of the SYN that opened the connection
+    fingerprint: Option<Fingerprint>,
+    /// Sees the options the stack doesn't know and adds options of the embedder, see `options`
+    option_hook: Option<Box<dyn OptionHook>>,
+    /// The socket options set by the application, see `sockopt`
+    socket_options: SocketOptions,
+    /// How far the connection is closed, see `close`
+    close: Close,
+    /// Where the time is read from, see `clock`
+    clock: Arc<dyn Clock>,
+}
+
+impl<T: Debug> Debug for Connection<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Connection")
+            .field("id", &self.id)
+            .field("state", &self.state)
+            .field("incoming", &self.incoming.len())
+            .field("urgent", &self.urgent.len())
+            .field("reassembly", &self.reassembly)
+            .field("bytes_read", &self.bytes_read)
+            .field("outgoing", &self.outgoing.len())
+            .field("ack_pending", &self.ack_pending)
+            .field("challenge_ack", &self.challenge_ack)
+            .field("max_snd_wnd", &self.max_snd_wnd)
+            .field("retransmit", &self.retransmit)
+            .field("congestion", &self.congestion)
+            .field("rate_limit", &self.rate_limit)
+            .field("rcv_buf", &self.rcv_buf)
+            .field("snd_buf", &self.snd_buf)
+            .field("markers", &self.markers)
+            .field("mss", &self.mss)
+            .field("sack", &self.sack)
+            .field("timestamps", &self.timestamps)
+            .field("ecn", &self.ecn)
+            .field("md5", &self.md5_key.is_some())
+            .field("ao", &self.ao)
+            .field("reset_optimistic_ack", &self.reset_optimistic_ack)
+            .field("timeouts", &self.timeouts)
+            .field("fingerprint", &self.fingerprint)
+            .field("option_hook", &self.option_hook.is_some())
+            .field("socket_options", &self.socket_options)
+            .field("close", &self.close)
+            .field("clock", &self.clock)
+            .finish()
+    }
 }
 
 impl<T> Connection<T> {
     pub fn from(id: ConnectionID, state: T) -> Self {
-        Self { id, state }
+        Self {
+            id,
+            state,
+            incoming: VecDeque::new(),
+            urgent: VecDeque::new(),
+            reassembly: Reassembly::default(),
+            bytes_read: 0,
+            outgoing: VecDeque::new(),
+            ack_pending: false,
+            challenge_ack: false,
+            max_snd_wnd: 0,
+            retransmit: Retransmission::default(),
+            congestion: Congestion::new(Algorithm::Reno.controller(DEFAULT_MSS as u32)),
+            rate_limit: None,
+            rcv_buf: ReceiveBuffer::default(),
+            snd_buf: SendBuffer::default(),
+            markers: Markers::default(),
+            mss: DEFAULT_MSS,
+            sack: false,
+            timestamps: None,
+            ecn: None,
+            md5_key: None,
+            ao: None,
+            reset_optimistic_ack: false,
+            timeouts: Timeouts::default(),
+            fingerprint: None,
+            option_hook: None,
+            socket_options: SocketOptions::default(),
+            close: Close::default(),
+            clock: Arc::new(SystemClock),
+        }
     }
-}
 
-/// Checks the receiving data, i.e. the tcp header + the data received are valid.
-/// See https://www.ietf.org/rfc/rfc793.txt page 24.
-///
-/// When data is received the following comparisons are needed:
-///     RCV.NXT = next sequence number expected on an incoming segments, and is the left or lower edge of the receive window
-///     RCV.NXT+RCV.WND-1 = last sequence number expected on an incoming segment, and is the right or upper edge of the receive window
-///     SEG.SEQ = first sequence number occupied by the incoming segment
-///     SEG.SEQ+SEG.LEN-1 = last sequence number occupied by the incoming segment
-///
-/// Due to zero windows and zero length segments, we have four cases for the acceptability of an incoming segment:
-///
-///     Segment Receive  Test
-///     Length  Window
-///     ------- -------  -------------------------------------------
-///        0       0     SEG.SEQ = RCV.NXT
-///        0      >0     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
-///       >0       0     not acceptable
-///       >0      >0     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
-///                      or RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
-///
-/// A segment is judged to occupy a portion of valid receive sequence space if
-///     RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
-/// or
-///     RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
-/// Note that the above is a *OR* condition.
-pub(crate) fn is_recv_data_in_window(
-    rcv: &ReceiveSequenceSpace,
-    seg: &TcpHeaderSlice,
-    data: Option<&[u8]>,
-) -> bool {
-    // Case 1:
-    if data.is_none() && rcv.wnd == 0 && seg.sequence_number() == rcv.nxt {
-        return true;
+    /// Moves to the next state, the buffers of the connection are carried over.
+    pub(crate) fn transition<U>(self, next: impl FnOnce(T) -> U) -> Connection<U> {
+        Connection {
+            id: self.id,
+            state: next(self.state),
+            incoming: self.incoming,
+            urgent: self.urgent,
+            reassembly: self.reassembly,
+            bytes_read: self.bytes_read,
+            outgoing: self.outgoing,
+            ack_pending: self.ack_pending,
+            challenge_ack: self.challenge_ack,
+            max_snd_wnd: self.max_snd_wnd,
+            retransmit: self.retransmit,
+            congestion: self.congestion,
+            rate_limit: self.rate_limit,
+            rcv_buf: self.rcv_buf,
+            snd_buf: self.snd_buf,
+            markers: self.markers,
+            mss: self.mss,
+            sack: self.sack,
+            timestamps: self.timestamps,
+            ecn: self.ecn,
+            md5_key: self.md5_key,
+            ao: self.ao,
+            reset_optimistic_ack: self.reset_optimistic_ack,
+            timeouts: self.timeouts,
+            fingerprint: self.fingerprint,
+            option_hook: self.option_hook,
+            socket_options: self.socket_options,
+            close: self.close,
+            clock: self.clock,
+        }
+    }
+
+    /// Switches the congestion control of the connection, whatever new connections use.
+    pub fn set_congestion_control(&mut self, algorithm: Algorithm) {
+        self.set_congestion_controller(algorithm.controller(self.mss as u32));
+    }
+
+    /// Switches the connection to a congestion control of the application's own, carrying on from the
+    /// current window.
+    pub fn set_congestion_controller(&mut self, controller: Box<dyn CongestionControl>) {
+        self.congestion.set_controller(controller);
+    }
+
+    /// Caps the rate new data is sent at with a token bucket, whatever the congestion control allows.
+    /// `None` lifts the limit.
+    pub fn set_rate_limit(&mut self, limit: Option<TokenBucket>) {
+        self.rate_limit = limit;
+    }
+
+    /// Makes the connection read the time from `clock`, a `MockClock` in tests. Set before `syn_ack`, the
+    /// handshake uses it as well.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// The time on the connection's clock
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// The fingerprint of the peer, recorded from its SYN.
+    pub fn fingerprint(&self) -> Option<&Fingerprint> {
+        self.fingerprint.as_ref()
     }
 
-    // Case 3:
-    if data.is_some() && rcv.wnd == 0 {
-        return false;
+    /// Installs the hook seeing the options the stack doesn't know on the segments received, and adding
+    /// options to those sent. Installed before `syn_ack`, it sees the options of the SYN as well.
+    pub fn set_option_hook(&mut self, hook: Box<dyn OptionHook>) {
+        self.option_hook = Some(hook);
     }
 
-    // Checking Case 2 and part of Case 4
-    let wnd_edge = rcv.nxt.wrapping_add(rcv.wnd as u32);
+    /// Passes the options of `seg` the stack doesn't know to the hook.
+    pub(crate) fn on_custom_options(&mut self, seg: &SegmentView) {
+        let hook = match self.option_hook.as_mut() {
+            Some(hook) => hook,
+            None => return,
+        };
+        for option in seg.tcp_options().filter_map(|option| option.ok()) {
+            if let TcpOption::Unknown { kind, data } = option {
+                hook.inbound(&self.id, kind, data);
+            }
+        }
+    }
+
+    /// The options the hook adds to the segments sent.
+    pub(crate) fn custom_options(&self) -> Vec<CustomOption> {
+        self.option_hook
+            .as_ref()
+            .map_or(vec![], |hook| hook.outbound(&self.id))
+    }
 
-    // wrapping check: RCV.NXT =< SEG.SEQ < RCV.NXT+RCV.WND
-    if is_wrapping_lte_ls(rcv.nxt, seg.sequence_number(), wnd_edge) {
-        return true;
+    /// Whether a segment received carries the signature the connection requires, if any.
+    pub(crate) fn verify_signature(&mut self, seg: &SegmentView) -> bool {
+        match (self.ao.as_mut(), self.md5_key.as_deref()) {
+            (Some(ao), _) => ao.verify(seg),
+            (None, Some(key)) => md5::verify(seg, key),
+            (None, None) => true,
+        }
     }
 
-    // Case 4:
-    if data.is_some() && rcv.wnd > 0 {
-        // wrapping check: RCV.NXT =< SEG.SEQ+SEG.LEN-1 < RCV.NXT+RCV.WND
-        let mut seg_len = data.map(|s| s.len() as u32).unwrap_or(0);
+    /// The ip header fields of the segments sent with the ECN codepoint `ecn`.
+    pub(crate) fn ip_params(&self, ecn: u8) -> IpParams {
+        IpParams {
+            dscp: self.socket_options.dscp(),
+            ecn,
+            ttl: self.socket_options.ttl(),
+            options: self.socket_options.ip_options(),
+            dont_fragment: self.socket_options.dont_fragment(),
+        }
+    }
 
-        // SEG.LEN = the number of octets occupied by the data in the segment (counting SYN and FIN)
-        // https://www.ietf.org/rfc/rfc793.txt, page 24
-        if seg.syn() {
-            seg_len += 1;
+    /// How the segments sent are signed, if at all.
+    pub(crate) fn signature(&self) -> Option<Signature<'_>> {
+        match (self.ao.as_ref(), self.md5_key.as_deref()) {
+            (Some(ao), _) => Some(Signature::Ao(ao)),
+            (None, Some(key)) => Some(Signature::Md5(key)),
+            (None, None) => None,
         }
-        if seg.fin() {
-            seg_len += 1;
+    }
+}
+
+impl<T: AsRef<SendSequenceSpace>> Connection<T> {
+    /// Aborts the connection, the reset segment is formed as:
+    ///     <SEQ=SND.NXT><CTL=RST>
+    /// See https://www.ietf.org/rfc/rfc793.txt page 62, ABORT Call.
+    pub fn reset(&self, nic: &dyn NetworkDevice) -> Result<()> {
+        let snd = self.state.as_ref();
+        let mut rst = TcpHeader::new(self.id.dst_port, self.id.src_port, snd.nxt, 0);
+        rst.rst = true;
+        let signature = self.signature();
+        if let Some(signature) = signature.as_ref() {
+            signature.add_option(&mut rst)?;
         }
+        send_segment_with(
+            nic,
+            &self.id,
+            rst,
+            &[],
+            self.ip_params(NOT_ECT),
+            signature.as_ref(),
+        )
+    }
 
-        let seg_last_seq = seg.sequence_number().wrapping_add(seg_len).wrapping_sub(1);
+    /// Whether `seq`, the sequence number of a segment quoted by an ICMP error, is in flight,
+    /// SND.UNA =< seq < SND.NXT, https://www.ietf.org/rfc/rfc5927.txt 4.1.
+    pub fn is_in_flight(&self, seq: u32) -> bool {
+        let snd = self.state.as_ref();
+        seq.wrapping_sub(snd.una) < snd.nxt.wrapping_sub(snd.una)
+    }
 
-        return is_wrapping_lte_ls(rcv.nxt, seg_last_seq, wnd_edge);
+    /// Records an ICMP soft error about the connection, see `ip::icmp`.
+    pub fn on_icmp_error(&mut self, icmp_type: u8, code: u8) {
+        self.socket_options.on_icmp_error(icmp_type, code);
     }
+}
+
+/// An established connection from 192.167.1.2:40000 to port 80 for the tests: nothing in flight from
+/// `una` in a send window of `wnd`, and nothing received yet before `rcv_nxt`.
+#[cfg(test)]
+pub(crate) fn established(una: u32, wnd: u32, rcv_nxt: u32) -> Connection<state::Established> {
+    let id = ConnectionID {
+        src_addr: std::net::Ipv4Addr::new(192, 167, 1, 2),
+        src_port: 40000,
+        dst_addr: std::net::Ipv4Addr::new(192, 167, 1, 1),
+        dst_port: 80,
+    };
+    Connection::from(
+        id,
+        state::Established {
+            snd: SendSequenceSpace {
+                up: false,
+                up_seq: 0,
+                wnd,
+                wnd_shift: 0,
+                una,
+                nxt: una,
+                wl1: 0,
+                wl2: 0,
+                iss: una.wrapping_sub(1),
+            },
+            rcv: ReceiveSequenceSpace {
+                up: false,
+                up_seq: 0,
+                wnd: 100,
+                wnd_shift: 0,
+                nxt: rcv_nxt,
+                irs: rcv_nxt.wrapping_sub(1),
+            },
+        },
+    )
+}
 
-    false
+/// How the segments of a connection are signed, a connection never uses both.
+pub(crate) enum Signature<'a> {
+    /// See `md5`
+    Md5(&'a [u8]),
+    /// See `ao`
+    Ao(&'a Ao),
 }
 
-/// Checks if the three numbers a, b, c are: a <= b < c with wrapping
-fn is_wrapping_lte_ls<N: PartialOrd>(a: N, b: N, c: N) -> bool {
-    // case 1:  >>>> a >>>> b >>>> c
-    if a <= b && b < c {
-        return true;
+impl Signature<'_> {
+    /// Appends the option with a blank digest or MAC, filled in by `sign` once the segment is complete.
+    pub fn add_option(&self, header: &mut TcpHeader) -> Result<()> {
+        match self {
+            Signature::Md5(_) => md5::add_option(header),
+            Signature::Ao(ao) => ao.add_option(header),
+        }
     }
 
-    // case 2:  >>>> c >>>> a >>>> b
-    if c < a && a <= b {
-        return true;
+    pub fn sign(&self, ip: &Ipv4Header, tcp: &mut TcpHeader, payload: &[&[u8]]) -> Result<()> {
+        match self {
+            Signature::Md5(key) => md5::sign(ip, tcp, payload, key),
+            Signature::Ao(ao) => ao.sign(ip, tcp, payload),
+        }
     }
 
-    // case 3:  >>>> b >>>> c >>>> a
-    if b < c && c < a {
-        return true;
+    /// The octets of the option
+    pub fn option_len(&self) -> usize {
+        match self {
+            Signature::Md5(_) => md5::OPTION_LEN,
+            Signature::Ao(_) => ao::OPTION_LEN,
+        }
     }
+}
 
-    false
+/// The MSS of the nic, the largest segment fitting its MTU without fragmentation, see
+/// https://www.ietf.org/rfc/rfc6691.txt
+#[cfg(unix)]
+pub fn nic_mss(nic: &tun_tap::Iface) -> Result<u16> {
+    device_mss(nic.name())
 }
 
-/// Checks the ack number is actually within the send window. This also considers the case of usigned int wrapping.
-pub(crate) fn is_ack_in_window(snd: &SendSequenceSpace, ack: u32) -> bool {
-    // SND.UNA < SEG.ACK =< SND.NXT
+/// The MSS of the device `name`, see `nic_mss`.
+#[cfg(unix)]
+pub fn device_mss(name: &str) -> Result<u16> {
+    Ok(device_mtu(name)?.saturating_sub(MSS_OVERHEAD))
+}
 
-    // case 1:   >>>> una >>>> ack >>>> nxt
-    if snd.una < ack && ack <= snd.nxt {
-        return true;
+/// The MTU of the device `name`, the largest ip packet it sends.
+#[cfg(unix)]
+pub fn device_mtu(name: &str) -> Result<u16> {
+    let mut req: libc::ifreq = unsafe { std::mem::zeroed() };
+    let name = name.as_bytes();
+    for (dst, src) in req.ifr_name.iter_mut().zip(name).take(libc::IFNAMSIZ - 1) {
+        *dst = *src as libc::c_char;
     }
 
-    // case 2:   >>>> nxt >>>> una >>>> ack
-    if snd.nxt < snd.una && snd.una < ack {
-        return true;
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let ret = unsafe { libc::ioctl(fd, libc::SIOCGIFMTU, &mut req) };
+    let err = std::io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if ret < 0 {
+        return Err(err.into());
     }
 
-    // case 3:   >>>> ack >>>> nxt >>>> una
-    if ack <= snd.una && snd.nxt < snd.una {
-        return true;
+    let mtu = unsafe { req.ifr_ifru.ifru_mtu };
+    Ok(mtu.clamp(0, u16::MAX as i32) as u16)
+}
+
+/// Answers a segment that belongs to no connection with a reset, see https://www.ietf.org/rfc/rfc793.txt
+/// page 36, Reset Generation:
+///
+/// If the incoming segment has an ACK field, the reset takes its sequence number from the ACK field of
+/// the segment, otherwise the reset has sequence number zero and the ACK field is set to the sum of the
+/// sequence number and segment length of the incoming segment.
+///     <SEQ=SEG.ACK><CTL=RST>
+///     <SEQ=0><ACK=SEG.SEQ+SEG.LEN><CTL=RST,ACK>
+/// A reset is never sent in response to a reset.
+pub fn send_reset(nic: &dyn NetworkDevice, seg: &SegmentView, ip: IpParams) -> Result<()> {
+    if seg.tcp.rst() {
+        return Ok(());
+    }
+    let id = seg.id();
+    let mut rst = TcpHeader::new(id.dst_port, id.src_port, 0, 0);
+    rst.rst = true;
+    if seg.tcp.ack() {
+        rst.sequence_number = seg.tcp.acknowledgment_number();
+    } else {
+        rst.ack = true;
+        rst.acknowledgment_number = seg.tcp.sequence_number().wrapping_add(seg.len());
+    }
+    send_segment(nic, &id, rst, &[], ip)
+}
+
+/// Wraps the tcp header and the payload in an ip packet addressed to the remote end of the connection,
+/// its header filled in from `ip`, fills in the checksum and writes the packet to the nic. The payload
+/// is gathered from its pieces straight into the packet, e.g. the two halves of a ring buffer.
+pub(crate) fn send_segment(
+    nic: &dyn NetworkDevice,
+    id: &ConnectionID,
+    tcp_header: TcpHeader,
+    payload: &[&[u8]],
+    ip: IpParams,
+) -> Result<()> {
+    send_segment_with(nic, id, tcp_header, payload, ip, None)
+}
+
+/// Like `send_segment`, signed with `signature` when there is one, in the option added by
+/// `Signature::add_option`.
+pub(crate) fn send_segment_with(
+    nic: &dyn NetworkDevice,
+    id: &ConnectionID,
+    mut tcp_header: TcpHeader,
+    payload: &[&[u8]],
+    ip: IpParams,
+    signature: Option<&Signature>,
+) -> Result<()> {
+    let payload_len = payload.iter().map(|p| p.len()).sum::<usize>();
+    let mut ip_header = Ipv4Header::new(
+        tcp_header.header_len() + payload_len as u16,
+        ip.ttl,
+        TCP_PROTOCOL,
+        id.dst_addr.octets(),
+        id.src_addr.octets(),
+    );
+    ip_header.differentiated_services_code_point = ip.dscp;
+    ip_header.explicit_congestion_notification = ip.ecn;
+    ip_header.dont_fragment = ip.dont_fragment;
+    ip_header
+        .set_options(ip.options.as_bytes())
+        .map_err(|e| anyhow!("{e:?}"))?;
+    if let Some(signature) = signature {
+        signature.sign(&ip_header, &mut tcp_header, payload)?;
+    }
+
+    let mut packet = Vec::with_capacity(ip_header.header_len() + ip_header.payload_len as usize);
+    ip_header.write(&mut packet)?;
+    let tcp_start = packet.len();
+    tcp_header.write(&mut packet)?;
+    let payload_start = packet.len();
+    for piece in payload {
+        packet.extend_from_slice(piece);
     }
 
-    false
+    // this field is needed, if no checksum, the other host will not respond with ACK. A device
+    // offloading it completes the sum of the pseudo header.
+    tcp_header.checksum = match ip::offloads_checksum(nic, packet.len()) {
+        true => checksum::fold(checksum::pseudo_header(
+            ip_header.source,
+            ip_header.destination,
+            TCP_PROTOCOL,
+            ip_header.payload_len,
+        )),
+        false => tcp_header.calc_checksum_ipv4(&ip_header, &packet[payload_start..])?,
+    };
+    packet[tcp_start + TCP_CHECKSUM_OFFSET..tcp_start + TCP_CHECKSUM_OFFSET + 2]
+        .copy_from_slice(&tcp_header.checksum.to_be_bytes());
+
+    ip::send(nic, &packet)
+}
+
+/// The offset of the checksum within the tcp header, see https://www.ietf.org/rfc/rfc793.txt page 15
+const TCP_CHECKSUM_OFFSET: usize = 16;
+
+/// The fields of the segment `core::segment` checks
+pub(crate) fn arriving(seg: &TcpHeaderSlice, data: &[u8]) -> Segment {
+    Segment {
+        seq: seg.sequence_number(),
+        ack: seg.ack().then(|| seg.acknowledgment_number()),
+        len: data.len() as u32,
+        syn: seg.syn(),
+        fin: seg.fin(),
+        rst: seg.rst(),
+    }
+}
+
+/// Checks the receiving data, i.e. the tcp header + the data received are valid, see
+/// `core::seq::is_seq_acceptable`.
+pub(crate) fn is_recv_data_in_window(
+    rcv: &ReceiveSequenceSpace,
+    seg: &TcpHeaderSlice,
+    data: Option<&[u8]>,
+) -> bool {
+    // SEG.LEN = the number of octets occupied by the data in the segment (counting SYN and FIN)
+    // https://www.ietf.org/rfc/rfc793.txt, page 24
+    let seg_len = data.map(|s| s.len() as u32 + seg.syn() as u32 + seg.fin() as u32);
+    is_seq_acceptable(rcv, seg.sequence_number(), seg_len)
 }
@@ -0,0 +1,349 @@
+//! Typed tcp options. The raw option list of a header is framed by `wire::Options`, here each option
+//! is checked against the length its kind requires and given its meaning, and a list of typed options
+//! is built back into the raw bytes of a header.
+//!
+//! NOPs and the end of option list only matter on the wire: they are skipped when parsing, and
+//! `build` places NOPs itself so that every option ends on a 4 octet boundary, as most stacks do.
+//!
+//! An embedder can see the options the stack doesn't know and send options of its own, e.g. the
+//! experimental kinds 253 and 254 of https://www.ietf.org/rfc/rfc6994.txt, through an `OptionHook`.
+
+use crate::tcp::reassembly::MAX_SACK_BLOCKS;
+use crate::tcp::ConnectionID;
+use crate::wire::{
+    OptionError, Options, RawOption, OPT_AO, OPT_EOL, OPT_FAST_OPEN, OPT_MD5SIG, OPT_MSS, OPT_NOP,
+    OPT_SACK, OPT_SOK, OPT_TS, OPT_WS,
+};
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum TcpOption<'a> {
+    /// https://www.ietf.org/rfc/rfc9293.txt 3.7.1
+    Mss(u16),
+    /// The shift count, https://www.ietf.org/rfc/rfc7323.txt 2.2
+    WindowScale(u8),
+    /// https://www.ietf.org/rfc/rfc2018.txt 2
+    SackPermitted,
+    /// The blocks as (left edge, right edge), https://www.ietf.org/rfc/rfc2018.txt 3
+    Sack(Vec<(u32, u32)>),
+    /// https://www.ietf.org/rfc/rfc7323.txt 3.2
+    Timestamps { tsval: u32, tsecr: u32 },
+    /// The cookie, empty to request one, https://www.ietf.org/rfc/rfc7413.txt 2
+    FastOpen(&'a [u8]),
+    /// The digest, https://www.ietf.org/rfc/rfc2385.txt
+    Md5(&'a [u8]),
+    /// https://www.ietf.org/rfc/rfc5925.txt 2.2
+    Ao {
+        key_id: u8,
+        rnext_key_id: u8,
+        mac: &'a [u8],
+    },
+    /// Any other kind, kept as it is
+    Unknown { kind: u8, data: &'a [u8] },
+}
+
+impl<'a> TcpOption<'a> {
+    /// Gives a raw option its meaning, an error if its length isn't one its kind allows. The end of
+    /// option list and NOPs have none, they are `None`.
+    pub fn from_raw(raw: RawOption<'a>) -> Option<Result<Self, OptionError>> {
+        let data = raw.data;
+        let option = match (raw.kind, data.len()) {
+            (OPT_EOL | OPT_NOP, _) => return None,
+            (OPT_MSS, 2) => TcpOption::Mss(u16::from_be_bytes([data[0], data[1]])),
+            (OPT_WS, 1) => TcpOption::WindowScale(data[0]),
+            (OPT_SOK, 0) => TcpOption::SackPermitted,
+            (OPT_SACK, len) if len > 0 && len % 8 == 0 && len / 8 <= MAX_SACK_BLOCKS => {
+                TcpOption::Sack(
+                    data.chunks_exact(8)
+                        .map(|block| (be_u32(&block[..4]), be_u32(&block[4..])))
+                        .collect(),
+                )
+            }
+            (OPT_TS, 8) => TcpOption::Timestamps {
+                tsval: be_u32(&data[..4]),
+                tsecr: be_u32(&data[4..]),
+            },
+            (OPT_FAST_OPEN, _) => TcpOption::FastOpen(data),
+            (OPT_MD5SIG, 16) => TcpOption::Md5(data),
+            (OPT_AO, len) if len > 2 => TcpOption::Ao {
+                key_id: data[0],
+                rnext_key_id: data[1],
+                mac: &data[2..],
+            },
+            (OPT_MSS | OPT_WS | OPT_SOK | OPT_SACK | OPT_TS | OPT_MD5SIG | OPT_AO, _) => {
+                return Some(Err(OptionError::BadLength(raw.kind)))
+            }
+            (kind, _) => TcpOption::Unknown { kind, data },
+        };
+        Some(Ok(option))
+    }
+
+    pub fn kind(&self) -> u8 {
+        match self {
+            TcpOption::Mss(_) => OPT_MSS,
+            TcpOption::WindowScale(_) => OPT_WS,
+            TcpOption::SackPermitted => OPT_SOK,
+            TcpOption::Sack(_) => OPT_SACK,
+            TcpOption::Timestamps { .. } => OPT_TS,
+            TcpOption::FastOpen(_) => OPT_FAST_OPEN,
+            TcpOption::Md5(_) => OPT_MD5SIG,
+            TcpOption::Ao { .. } => OPT_AO,
+            TcpOption::Unknown { kind, .. } => *kind,
+        }
+    }
+
+    /// Appends the kind, the length and the data of the option to `out`.
+    fn write(&self, out: &mut Vec<u8>) {
+        let start = out.len();
+        out.extend_from_slice(&[self.kind(), 0]);
+        match self {
+            TcpOption::Mss(mss) => out.extend_from_slice(&mss.to_be_bytes()),
+            TcpOption::WindowScale(shift) => out.push(*shift),
+            TcpOption::SackPermitted => {}
+            TcpOption::Sack(blocks) => {
+                for (left, right) in blocks {
+                    out.extend_from_slice(&left.to_be_bytes());
+                    out.extend_from_slice(&right.to_be_bytes());
+                }
+            }
+            TcpOption::Timestamps { tsval, tsecr } => {
+                out.extend_from_slice(&tsval.to_be_bytes());
+                out.extend_from_slice(&tsecr.to_be_bytes());
+            }
+            TcpOption::FastOpen(data) | TcpOption::Md5(data) | TcpOption::Unknown { data, .. } => {
+                out.extend_from_slice(data)
+            }
+            TcpOption::Ao {
+                key_id,
+                rnext_key_id,
+                mac,
+            } => {
+                out.extend_from_slice(&[*key_id, *rnext_key_id]);
+                out.extend_from_slice(mac);
+            }
+        }
+        out[start + 1] = (out.len() - start) as u8;
+    }
+}
+
+/// An option of a kind the stack doesn't know, owned by the embedder sending it.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct CustomOption {
+    pub kind: u8,
+    pub data: Vec<u8>,
+}
+
+impl CustomOption {
+    fn typed(&self) -> TcpOption<'_> {
+        TcpOption::Unknown {
+            kind: self.kind,
+            data: &self.data,
+        }
+    }
+}
+
+/// The extension point for the options the stack doesn't know, installed on a connection with
+/// `Connection::set_option_hook`.
+pub trait OptionHook: Send {
+    /// Called with each option of a kind the stack doesn't know, on the SYN and on every segment
+    /// accepted afterwards.
+    fn inbound(&mut self, id: &ConnectionID, kind: u8, data: &[u8]);
+
+    /// The options to send on every segment, the SYN-ACK included. They come after those of the stack
+    /// and go out as far as there is room left, options of a kind the stack knows are left out.
+    fn outbound(&self, id: &ConnectionID) -> Vec<CustomOption>;
+}
+
+/// Appends the `custom` options to `options`, as long as the options built stay within `room` octets.
+pub fn append_custom<'a>(
+    options: &mut Vec<TcpOption<'a>>,
+    custom: &'a [CustomOption],
+    room: usize,
+) {
+    for option in custom {
+        let raw = RawOption {
+            kind: option.kind,
+            data: &option.data,
+        };
+        if !matches!(
+            TcpOption::from_raw(raw),
+            Some(Ok(TcpOption::Unknown { .. }))
+        ) {
+            log::debug!(
+                "option of kind {:} is the stack's own, left out",
+                option.kind
+            );
+            continue;
+        }
+        options.push(option.typed());
+        if build(options).len() > room {
+            log::debug!("no room for the option of kind {:}, left out", option.kind);
+            options.pop();
+        }
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// The typed options of a raw option list, in the order they appear. The iteration stops after the
+/// first framing error, an option with a bad length is an error of its own.
+pub fn iter(bytes: &[u8]) -> impl Iterator<Item = Result<TcpOption<'_>, OptionError>> {
+    Options::new(bytes).filter_map(|raw| match raw {
+        Ok(raw) => TcpOption::from_raw(raw),
+        Err(e) => Some(Err(e)),
+    })
+}
+
+/// All the typed options of a raw option list, or the first error.
+pub fn parse(bytes: &[u8]) -> Result<Vec<TcpOption<'_>>, OptionError> {
+    iter(bytes).collect()
+}
+
+/// The raw option list of `options`, each preceded by the NOPs aligning its end to 4 octets, so the
+/// list is aligned as a whole and more options can be appended to it as they are.
+pub fn build(options: &[TcpOption]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut option = vec![];
+    for o in options {
+        option.clear();
+        o.write(&mut option);
+        let padding = (4 - option.len() % 4) % 4;
+        out.extend(std::iter::repeat_n(OPT_NOP, padding));
+        out.extend_from_slice(&option);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::options::{append_custom, build, parse, CustomOption, TcpOption};
+    use crate::wire::OptionError;
+
+    #[test]
+    fn test_build() {
+        let cookie = [9u8; 8];
+        let options = [
+            TcpOption::Mss(1460),
+            TcpOption::SackPermitted,
+            TcpOption::WindowScale(7),
+            TcpOption::Timestamps { tsval: 1, tsecr: 2 },
+            TcpOption::FastOpen(&cookie),
+        ];
+        let raw = build(&options);
+        assert_eq!(
+            raw[..20],
+            [2, 4, 5, 180, 1, 1, 4, 2, 1, 3, 3, 7, 1, 1, 8, 10, 0, 0, 0, 1]
+        );
+        assert_eq!(raw.len(), 36);
+        assert_eq!(parse(&raw).unwrap(), options);
+
+        let options = [
+            TcpOption::Sack(vec![(1, 2), (3, 4)]),
+            TcpOption::Unknown {
+                kind: 253,
+                data: &[0xf0, 0x0d, 1],
+            },
+            TcpOption::Ao {
+                key_id: 1,
+                rnext_key_id: 2,
+                mac: &[0; 12],
+            },
+            TcpOption::Md5(&[0; 16]),
+        ];
+        let raw = build(&options);
+        assert_eq!(raw.len() % 4, 0);
+        assert_eq!(raw[20..28], [1, 1, 1, 253, 5, 0xf0, 0x0d, 1]);
+        assert_eq!(parse(&raw).unwrap(), options);
+        assert_eq!(build(&[]), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_append_custom() {
+        let custom = [
+            CustomOption {
+                kind: 253,
+                data: vec![0xf0, 0x0d],
+            },
+            // the stack's own
+            CustomOption {
+                kind: 2,
+                data: vec![5, 180],
+            },
+            CustomOption {
+                kind: 254,
+                data: vec![0; 20],
+            },
+            CustomOption {
+                kind: 254,
+                data: vec![],
+            },
+        ];
+        let mut options = vec![TcpOption::Timestamps { tsval: 1, tsecr: 2 }];
+        append_custom(&mut options, &custom, 24);
+        assert_eq!(
+            options,
+            vec![
+                TcpOption::Timestamps { tsval: 1, tsecr: 2 },
+                TcpOption::Unknown {
+                    kind: 253,
+                    data: &[0xf0, 0x0d]
+                },
+                TcpOption::Unknown {
+                    kind: 254,
+                    data: &[]
+                },
+            ]
+        );
+        assert_eq!(build(&options).len(), 20);
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        // the end of option list may be followed by zeros only
+        assert_eq!(parse(&[1, 1, 0, 0]).unwrap(), vec![]);
+        assert_eq!(parse(&[0, 0, 2, 0]), Err(OptionError::TrailingGarbage));
+        // the length runs past the list, is missing, or is shorter than the kind and length octets
+        assert_eq!(parse(&[2, 4, 5]), Err(OptionError::Truncated(2)));
+        assert_eq!(parse(&[1, 8]), Err(OptionError::Truncated(8)));
+        assert_eq!(parse(&[30, 1, 0, 0]), Err(OptionError::Truncated(30)));
+        assert_eq!(parse(&[30, 0, 0, 0]), Err(OptionError::Truncated(30)));
+
+        // lengths a kind can't have
+        for (raw, kind) in [
+            (&[2, 3, 5, 1][..], 2),
+            (&[3, 4, 7, 1], 3),
+            (&[4, 3, 0, 1], 4),
+            (&[5, 2, 1, 1], 5),
+            (&[5, 6, 0, 0, 0, 1], 5),
+            (&[8, 6, 0, 0, 0, 1], 8),
+            (&[19, 4, 0, 0], 19),
+            (&[29, 4, 1, 2], 29),
+        ] {
+            assert_eq!(parse(raw), Err(OptionError::BadLength(kind)), "{raw:?}");
+        }
+        // 5 SACK blocks don't fit in the options of a header
+        let mut sack = vec![5, 42];
+        sack.extend_from_slice(&[0; 40]);
+        assert_eq!(parse(&sack), Err(OptionError::BadLength(5)));
+
+        // a bad option doesn't stop the iteration, a truncated one does
+        let options = super::iter(&[2, 3, 5, 3, 3, 7, 2, 4, 5]).collect::<Vec<_>>();
+        assert_eq!(
+            options,
+            vec![
+                Err(OptionError::BadLength(2)),
+                Ok(TcpOption::WindowScale(7)),
+                Err(OptionError::Truncated(2)),
+            ]
+        );
+        // unknown kinds of any length are kept
+        assert_eq!(
+            parse(&[254, 2, 1, 1]).unwrap(),
+            vec![TcpOption::Unknown {
+                kind: 254,
+                data: &[]
+            }]
+        );
+    }
+}
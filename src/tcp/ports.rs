@@ -0,0 +1,156 @@
+//! The local ports of the stack. The ports bound, to listen or to open connections from, belong to whoever
+//! bound them. Active opens without a port get an ephemeral one from the dynamic range of
+//! https://www.ietf.org/rfc/rfc6335.txt 6, picked as in https://www.ietf.org/rfc/rfc6056.txt 3.3.3: a
+//! keyed hash of the remote endpoint offsets a counter, so the ports are hard to guess off path while
+//! the ones used with a given peer are still spread out.
+//!
+//! A port is free for a remote endpoint unless it's bound, a connection with that endpoint uses it, or
+//! such a connection is in TIME-WAIT: the 4-tuple can't be reused until its 2 MSL are over.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::BuildHasher;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddrV4;
+use std::ops::RangeInclusive;
+use std::time::Instant;
+
+/// The dynamic ports, the ephemeral ports are taken from
+pub const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+#[derive(Debug, Default)]
+pub struct Ports {
+    /// The key of the hash offsetting the search of a remote endpoint
+    secret: RandomState,
+    /// Bumped after every ephemeral port handed out, `next_ephemeral` of the RFC
+    next: u16,
+    /// The ports bound
+    bound: HashSet<u16>,
+    /// The local ports used with a remote endpoint in TIME-WAIT, until when
+    time_wait: HashMap<(SocketAddrV4, u16), Instant>,
+}
+
+impl Ports {
+    /// Takes `port`, fails with `AddrInUse` when it's bound already.
+    pub fn bind(&mut self, port: u16) -> io::Result<()> {
+        if !self.bound.insert(port) {
+            return Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("port {port:} already bound"),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn unbind(&mut self, port: u16) {
+        self.bound.remove(&port);
+    }
+
+    pub fn is_bound(&self, port: u16) -> bool {
+        self.bound.contains(&port)
+    }
+
+    /// Keeps `port` from being used with `remote` again until `until`, the end of the TIME-WAIT of
+    /// their connection.
+    pub fn hold(&mut self, remote: SocketAddrV4, port: u16, until: Instant) {
+        self.time_wait.insert((remote, port), until);
+    }
+
+    /// Checks the bound `port` may open a connection to `remote`, `in_use` telling whether a connection
+    /// uses the port with `remote`. Fails with `AddrInUse` otherwise.
+    pub fn check(
+        &mut self,
+        remote: SocketAddrV4,
+        port: u16,
+        in_use: impl Fn(u16) -> bool,
+        now: Instant,
+    ) -> io::Result<()> {
+        self.time_wait.retain(|_, until| *until > now);
+        if in_use(port) || self.time_wait.contains_key(&(remote, port)) {
+            return Err(io::Error::new(
+                ErrorKind::AddrInUse,
+                format!("port {port:} already connected to {remote:}"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// An ephemeral port to open a connection to `remote` from, `in_use` telling whether a connection
+    /// uses a port with `remote`. Fails with `AddrNotAvailable` once they are all taken.
+    pub fn allocate(
+        &mut self,
+        remote: SocketAddrV4,
+        in_use: impl Fn(u16) -> bool,
+        now: Instant,
+    ) -> io::Result<u16> {
+        self.time_wait.retain(|_, until| *until > now);
+        let first = *EPHEMERAL_PORTS.start();
+        let count = EPHEMERAL_PORTS.len() as u32;
+        let offset = self.secret.hash_one(remote) as u32;
+        for _ in 0..count {
+            let port = first + (offset.wrapping_add(self.next as u32) % count) as u16;
+            self.next = self.next.wrapping_add(1);
+            if !self.bound.contains(&port)
+                && !in_use(port)
+                && !self.time_wait.contains_key(&(remote, port))
+            {
+                return Ok(port);
+            }
+        }
+        Err(io::Error::new(
+            ErrorKind::AddrNotAvailable,
+            format!("no ephemeral port left for {remote:}"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::ports::{Ports, EPHEMERAL_PORTS};
+    use std::io::ErrorKind;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_allocate() {
+        let now = Instant::now();
+        let remote = SocketAddrV4::new(Ipv4Addr::new(192, 167, 1, 2), 80);
+        let mut ports = Ports::default();
+        let port = ports.allocate(remote, |_| false, now).unwrap();
+        assert!(EPHEMERAL_PORTS.contains(&port));
+        // the next one differs even if the connection isn't open yet
+        assert_ne!(ports.allocate(remote, |_| false, now).unwrap(), port);
+
+        // neither the ports bound nor the ones in use are handed out
+        ports.bind(50000).unwrap();
+        let taken = |p: u16| p != 50000 && p != 50001;
+        assert_eq!(ports.allocate(remote, taken, now).unwrap(), 50001);
+        assert_eq!(
+            ports
+                .allocate(remote, |p| p != 50000, now)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::AddrNotAvailable
+        );
+        assert_eq!(ports.bind(50000).unwrap_err().kind(), ErrorKind::AddrInUse);
+    }
+
+    #[test]
+    fn test_time_wait() {
+        let now = Instant::now();
+        let remote = SocketAddrV4::new(Ipv4Addr::new(192, 167, 1, 2), 80);
+        let other = SocketAddrV4::new(Ipv4Addr::new(192, 167, 1, 3), 80);
+        let mut ports = Ports::default();
+        ports.bind(5000).unwrap();
+        ports.check(remote, 5000, |_| false, now).unwrap();
+        assert!(ports.check(remote, 5000, |p| p == 5000, now).is_err());
+
+        ports.hold(remote, 5000, now + Duration::from_secs(240));
+        assert!(ports.check(remote, 5000, |_| false, now).is_err());
+        // another peer may use the port
+        ports.check(other, 5000, |_| false, now).unwrap();
+        ports
+            .check(remote, 5000, |_| false, now + Duration::from_secs(240))
+            .unwrap();
+    }
+}
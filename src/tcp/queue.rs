@@ -0,0 +1,303 @@
+//! Bounded input queues of the packets of a connection, in front of a stack busy elsewhere: the inbox
+//! of a shard of `multiqueue`, and the backlog the packet thread of `threaded` fills while an
+//! application thread holds the stack. One hot connection can't grow its backlog until the process
+//! runs out of memory.
+//!
+//! Under overload only data segments are shed, the peer retransmits them anyway. Segments driving the
+//! state machine without data, i.e. pure ACKs, SYN and FIN, evict the most recent queued data segment
+//! to make room when the queue is full, and a pure ACK replaces the pure ACK last in the queue, the
+//! later one acknowledging as much or more. Past twice the capacity they are dropped as well. A RST is
+//! never dropped, processing it may end the connection and with it the flood.
+
+use crate::tcp::ConnectionID;
+use crate::wire::SegmentView;
+use std::collections::{HashMap, VecDeque};
+
+/// The packets queued by connection in `multiqueue` and `threaded`
+pub const INPUT_QUEUE_CAPACITY: usize = 256;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum Admission {
+    Queued,
+    /// The data segment was dropped, the queue being full
+    Shed,
+    /// The packet was queued at the expense of a queued data segment
+    Evicted,
+    /// The pure ACK replaced the one last in the queue
+    Coalesced,
+    /// The control segment was dropped, past the hard cap of the queue
+    Dropped,
+}
+
+#[derive(PartialEq, Eq, Debug, Default, Clone)]
+pub struct QueueStats {
+    /// Data segments dropped on arrival because the queue was full
+    pub shed: u64,
+    /// Queued data segments dropped to make room for control segments
+    pub evicted: u64,
+    /// Pure ACKs replaced by a later one while the queue was full
+    pub coalesced: u64,
+    /// Control segments dropped past the hard cap, twice the capacity
+    pub dropped: u64,
+    /// The longest the queue has been
+    pub high_watermark: usize,
+}
+
+impl QueueStats {
+    /// Adds the counters of `other`, the high watermark being the highest of both.
+    pub fn add(&mut self, other: &QueueStats) {
+        self.shed += other.shed;
+        self.evicted += other.evicted;
+        self.coalesced += other.coalesced;
+        self.dropped += other.dropped;
+        self.high_watermark = self.high_watermark.max(other.high_watermark);
+    }
+}
+
+/// What a packet is to the shedding rules
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+enum Kind {
+    /// Data, or a malformed packet the processing drops anyway
+    Data,
+    PureAck,
+    /// SYN or FIN
+    Control,
+    Rst,
+}
+
+pub struct SegmentQueue {
+    /// The packets with what each is
+    packets: VecDeque<(Vec<u8>, Kind)>,
+    capacity: usize,
+    stats: QueueStats,
+}
+
+impl SegmentQueue {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            packets: VecDeque::new(),
+            capacity,
+            stats: QueueStats::default(),
+        }
+    }
+
+    /// Queues the raw ip packet unless the queue is full, see the module doc for the shedding rules.
+    pub fn push(&mut self, packet: Vec<u8>) -> Admission {
+        let kind = kind(&packet);
+        let mut admission = Admission::Queued;
+
+        if self.packets.len() >= self.capacity {
+            if kind == Kind::Data {
+                self.stats.shed += 1;
+                return Admission::Shed;
+            }
+            if let Some(i) = self
+                .packets
+                .iter()
+                .rposition(|(_, kind)| *kind == Kind::Data)
+            {
+                self.packets.remove(i);
+                self.stats.evicted += 1;
+                admission = Admission::Evicted;
+            } else if kind == Kind::PureAck {
+                let last = self.packets.back_mut();
+                if let Some(last) = last.filter(|(_, last)| *last == Kind::PureAck) {
+                    *last = (packet, kind);
+                    self.stats.coalesced += 1;
+                    return Admission::Coalesced;
+                }
+            }
+            if admission == Admission::Queued
+                && kind != Kind::Rst
+                && self.packets.len() >= 2 * self.capacity
+            {
+                self.stats.dropped += 1;
+                return Admission::Dropped;
+            }
+        }
+
+        self.packets.push_back((packet, kind));
+        self.stats.high_watermark = self.stats.high_watermark.max(self.packets.len());
+        admission
+    }
+
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.packets.pop_front().map(|(packet, _)| packet)
+    }
+
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    pub fn stats(&self) -> &QueueStats {
+        &self.stats
+    }
+}
+
+/// The input queues of the connections of a stack, a `SegmentQueue` each. The packets of no connection,
+/// e.g. ICMP, share one.
+#[derive(Default)]
+pub struct Backlog {
+    queues: HashMap<Option<ConnectionID>, SegmentQueue>,
+    capacity: usize,
+}
+
+impl Backlog {
+    /// Input queues of `capacity` packets.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            queues: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Queues the packet of the connection `id`, see `SegmentQueue::push`.
+    pub fn push(&mut self, id: Option<ConnectionID>, packet: Vec<u8>) -> Admission {
+        let capacity = self.capacity;
+        self.queues
+            .entry(id)
+            .or_insert_with(|| SegmentQueue::new(capacity))
+            .push(packet)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    /// Takes the packets queued, in order by connection, and what the queues shed since the last call.
+    pub fn drain(&mut self) -> (Vec<Vec<u8>>, QueueStats) {
+        let mut packets = vec![];
+        let mut stats = QueueStats::default();
+        for (_, mut queue) in self.queues.drain() {
+            stats.add(queue.stats());
+            packets.extend(std::iter::from_fn(|| queue.pop()));
+        }
+        (packets, stats)
+    }
+}
+
+fn kind(packet: &[u8]) -> Kind {
+    match SegmentView::parse(packet) {
+        Ok(seg) if seg.tcp.rst() => Kind::Rst,
+        Ok(seg) if seg.tcp.syn() || seg.tcp.fin() => Kind::Control,
+        Ok(seg) if seg.payload.is_empty() => Kind::PureAck,
+        _ => Kind::Data,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::queue::{Admission, Backlog, QueueStats, SegmentQueue};
+    use crate::tcp::ConnectionID;
+    use crate::TCP_PROTOCOL;
+    use etherparse::{Ipv4Header, TcpHeader};
+    use std::net::Ipv4Addr;
+
+    fn packet(seq: u32, rst: bool, payload: &[u8]) -> Vec<u8> {
+        let mut tcp = TcpHeader::new(40000, 80, seq, 1000);
+        tcp.ack = true;
+        tcp.rst = rst;
+        let ip = Ipv4Header::new(
+            tcp.header_len() + payload.len() as u16,
+            64,
+            TCP_PROTOCOL,
+            [192, 167, 1, 2],
+            [192, 167, 1, 1],
+        );
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_shedding() {
+        let mut queue = SegmentQueue::new(2);
+        assert_eq!(queue.push(packet(1, false, b"a")), Admission::Queued);
+        assert_eq!(queue.push(packet(2, false, b"b")), Admission::Queued);
+
+        // full: data is shed, an ACK evicts the latest data segment
+        assert_eq!(queue.push(packet(3, false, b"c")), Admission::Shed);
+        assert_eq!(queue.push(packet(4, false, b"")), Admission::Evicted);
+        // a RST evicts the remaining data segment
+        assert_eq!(queue.push(packet(5, true, b"")), Admission::Evicted);
+        // nothing left to evict, control segments are still queued
+        assert_eq!(queue.push(packet(6, false, b"")), Admission::Queued);
+        assert_eq!(queue.len(), 3);
+
+        let stats = queue.stats();
+        assert_eq!(stats.shed, 1);
+        assert_eq!(stats.evicted, 2);
+        assert_eq!(stats.high_watermark, 3);
+
+        assert_eq!(queue.pop().unwrap(), packet(4, false, b""));
+        assert_eq!(queue.pop().unwrap(), packet(5, true, b""));
+        assert_eq!(queue.pop().unwrap(), packet(6, false, b""));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_control_cap() {
+        let mut queue = SegmentQueue::new(2);
+        assert_eq!(queue.push(packet(1, false, b"")), Admission::Queued);
+        assert_eq!(queue.push(packet(2, false, b"")), Admission::Queued);
+        // full of pure ACKs, the latest replaces the last one
+        assert_eq!(queue.push(packet(3, false, b"")), Admission::Coalesced);
+        assert_eq!(queue.len(), 2);
+
+        // past the capacity up to twice of it for the rest, the RSTs always
+        assert_eq!(queue.push(packet(4, true, b"")), Admission::Queued);
+        assert_eq!(queue.push(packet(5, true, b"")), Admission::Queued);
+        assert_eq!(queue.push(packet(6, false, b"")), Admission::Dropped);
+        assert_eq!(queue.push(packet(7, true, b"")), Admission::Queued);
+        assert_eq!(queue.len(), 5);
+        assert_eq!((queue.stats().coalesced, queue.stats().dropped), (1, 1));
+        assert_eq!(queue.pop().unwrap(), packet(1, false, b""));
+        assert_eq!(queue.pop().unwrap(), packet(3, false, b""));
+    }
+
+    #[test]
+    fn test_backlog() {
+        let mut backlog = Backlog::new(1);
+        let id = |port| {
+            Some(ConnectionID {
+                src_addr: Ipv4Addr::new(192, 167, 1, 2),
+                src_port: port,
+                dst_addr: Ipv4Addr::new(192, 167, 1, 1),
+                dst_port: 80,
+            })
+        };
+        // a queue each, the hot connection sheds its own data only
+        assert_eq!(
+            backlog.push(id(1), packet(1, false, b"a")),
+            Admission::Queued
+        );
+        assert_eq!(backlog.push(id(1), packet(2, false, b"b")), Admission::Shed);
+        assert_eq!(
+            backlog.push(id(2), packet(1, false, b"c")),
+            Admission::Queued
+        );
+        assert_eq!(
+            backlog.push(None, packet(1, false, b"d")),
+            Admission::Queued
+        );
+
+        let (mut packets, stats) = backlog.drain();
+        packets.sort();
+        let mut expected = vec![
+            packet(1, false, b"a"),
+            packet(1, false, b"c"),
+            packet(1, false, b"d"),
+        ];
+        expected.sort();
+        assert_eq!(packets, expected);
+        assert_eq!((stats.shed, stats.high_watermark), (1, 1));
+        assert!(backlog.is_empty());
+        assert_eq!(backlog.drain().1, QueueStats::default());
+    }
+}
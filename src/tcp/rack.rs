@@ -0,0 +1,152 @@
+//! RACK, time-based loss detection, https://www.ietf.org/rfc/rfc8985.txt. Rather than counting
+//! duplicate ACKs, a segment is deemed lost once a segment sent after it was delivered, cumulatively or
+//! SACKed, and the RTT of that segment plus a reordering window went by since the lost one was sent. A
+//! retransmission is then lost in turn like any segment, and a few segments arriving out of order don't
+//! trigger a spurious retransmission as the third duplicate ACK does.
+//!
+//! The reordering window is a quarter of the lowest RTT, none until the peer was seen to reorder once
+//! three segments were SACKed beyond a hole. Every D-SACK widens it by a quarter of the lowest RTT, up to
+//! the RTT, RFC 8985 6.2 step 4.
+
+use crate::tcp::retransmit::Sent;
+use crate::tcp::wrapping_lt;
+use std::time::{Duration, Instant};
+
+/// The SACKed segments beyond a hole that mark it lost without waiting, as three duplicate ACKs would
+pub const DUP_THRESH: usize = 3;
+/// The D-SACKs widening the reordering window, up to the lowest RTT
+const MAX_REO_WND_STEPS: u32 = 3;
+
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
+pub struct Rack {
+    /// When the latest segment sent that was delivered was sent, and its end, RACK.xmit_ts and
+    /// RACK.end_seq
+    xmit_ts: Option<Instant>,
+    end_seq: u32,
+    /// The RTT of that segment
+    rtt: Duration,
+    min_rtt: Option<Duration>,
+    /// The highest end of a segment delivered, RACK.fack
+    fack: Option<u32>,
+    /// A segment was delivered below one delivered before
+    reordering_seen: bool,
+    /// The D-SACKs that widened the reordering window, each by a quarter of the lowest RTT
+    reo_wnd_steps: u32,
+}
+
+impl Rack {
+    /// Records the delivery of `sent` at `now`, RFC 8985 6.2 steps 2 and 3.
+    pub fn on_delivered(&mut self, sent: &Sent, now: Instant) {
+        let rtt = now.duration_since(sent.sent_at);
+        // delivered sooner than the lowest RTT after its retransmission, it's the original that was
+        if sent.retransmitted && self.min_rtt.is_some_and(|min| rtt < min) {
+            return;
+        }
+        self.min_rtt = Some(self.min_rtt.map_or(rtt, |min| min.min(rtt)));
+
+        match self.fack {
+            Some(fack) if wrapping_lt(sent.end(), fack) => self.reordering_seen = true,
+            _ => self.fack = Some(sent.end()),
+        }
+        if self.sent_after(sent.sent_at, sent.end()) {
+            self.xmit_ts = Some(sent.sent_at);
+            self.end_seq = sent.end();
+            self.rtt = rtt;
+        }
+    }
+
+    /// A D-SACK showed a retransmission was spurious, the peer reorders more than the window allows.
+    pub fn on_dsack(&mut self) {
+        self.reordering_seen = true;
+        self.reo_wnd_steps = (self.reo_wnd_steps + 1).min(MAX_REO_WND_STEPS);
+    }
+
+    /// When `sent` is deemed lost, none while no segment sent after it was delivered. `sacked` is the
+    /// number of segments SACKed.
+    pub fn lost_at(&self, sent: &Sent, sacked: usize) -> Option<Instant> {
+        if !self.xmit_ts.is_some_and(|ts| {
+            ts > sent.sent_at || (ts == sent.sent_at && wrapping_lt(sent.end(), self.end_seq))
+        }) {
+            return None;
+        }
+        Some(sent.sent_at + self.rtt + self.reo_wnd(sacked))
+    }
+
+    fn reo_wnd(&self, sacked: usize) -> Duration {
+        if !self.reordering_seen && sacked >= DUP_THRESH {
+            return Duration::ZERO;
+        }
+        let min_rtt = self.min_rtt.unwrap_or_default();
+        (min_rtt / 4 * (1 + self.reo_wnd_steps)).min(self.rtt)
+    }
+
+    /// Whether a segment sent at `ts` and ending at `end` was sent after the latest one delivered
+    fn sent_after(&self, ts: Instant, end: u32) -> bool {
+        match self.xmit_ts {
+            Some(xmit_ts) => ts > xmit_ts || (ts == xmit_ts && wrapping_lt(self.end_seq, end)),
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::rack::Rack;
+    use crate::tcp::retransmit::Sent;
+    use std::time::{Duration, Instant};
+
+    fn sent(seq: u32, sent_at: Instant) -> Sent {
+        Sent {
+            seq,
+            len: 10,
+            sent_at,
+            retransmitted: false,
+            sacked: false,
+            lost: false,
+            delivered: 0,
+            delivered_at: sent_at,
+        }
+    }
+
+    #[test]
+    fn test_lost_at() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut rack = Rack::default();
+        let first = sent(100, now);
+        let second = sent(110, now + ms(1));
+        assert_eq!(rack.lost_at(&first, 0), None);
+
+        // a later segment delivered after 40ms, the first one is given a quarter of it more
+        rack.on_delivered(&second, now + ms(41));
+        assert_eq!(rack.lost_at(&first, 1), Some(now + ms(50)));
+        assert_eq!(rack.lost_at(&second, 1), None);
+        // enough SACKed beyond it, no reordering seen, it's lost right away
+        assert_eq!(rack.lost_at(&first, 3), Some(now + ms(40)));
+
+        // the first one arrives after all, the peer reorders
+        rack.on_delivered(&first, now + ms(42));
+        assert_eq!(rack.lost_at(&first, 3), Some(now + ms(50)));
+        rack.on_dsack();
+        assert_eq!(rack.lost_at(&first, 3), Some(now + ms(60)));
+        for _ in 0..4 {
+            rack.on_dsack();
+        }
+        assert_eq!(rack.lost_at(&first, 3), Some(now + ms(80)));
+    }
+
+    #[test]
+    fn test_retransmission_delivered() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut rack = Rack::default();
+        rack.on_delivered(&sent(100, now), now + ms(40));
+
+        // acknowledged 10ms after the retransmission, the original was, so what was sent before the
+        // retransmission isn't lost because of it
+        let mut retransmitted = sent(110, now + ms(100));
+        retransmitted.retransmitted = true;
+        rack.on_delivered(&retransmitted, now + ms(110));
+        assert_eq!(rack.lost_at(&sent(120, now + ms(50)), 0), None);
+    }
+}
@@ -0,0 +1,106 @@
+//! Egress rate limiting of a connection with a token bucket. Tokens, octets, accrue at the rate up to
+//! the burst, and a segment of new data goes out once the bucket holds its length, or the burst when the
+//! segment is larger. The limit applies on top of the congestion and peer windows and the pacing, the
+//! transmit scheduler waits for the latest of them. Retransmissions aren't held back, loss recovery would
+//! stall behind new data otherwise, the bucket only caps the rate the application's data is sent at.
+
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct TokenBucket {
+    /// Octets per second
+    rate: u64,
+    /// The most octets sent at once after an idle period
+    burst: u64,
+    tokens: f64,
+    /// When `tokens` was last brought up to date
+    updated: Option<Instant>,
+}
+
+impl TokenBucket {
+    /// A bucket refilling at `rate` octets per second up to `burst` octets, full to begin with.
+    pub fn new(rate: u64, burst: u64) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst as f64,
+            updated: None,
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    pub fn burst(&self) -> u64 {
+        self.burst
+    }
+
+    /// Adds the tokens accrued until `now`.
+    pub fn refill(&mut self, now: Instant) {
+        if let Some(updated) = self.updated.filter(|at| *at < now) {
+            let accrued = now.duration_since(updated).as_secs_f64() * self.rate as f64;
+            self.tokens = (self.tokens + accrued).min(self.burst as f64);
+        }
+        self.updated = Some(self.updated.map_or(now, |at| at.max(now)));
+    }
+
+    /// Whether a segment of `len` octets may go out.
+    pub fn can_send(&self, len: usize) -> bool {
+        self.tokens >= self.needed(len)
+    }
+
+    /// When a segment of `len` octets may go out, none if right away.
+    pub fn send_at(&self, len: usize) -> Option<Instant> {
+        let missing = self.needed(len) - self.tokens;
+        if missing <= 0.0 {
+            return None;
+        }
+        let wait = match self.rate {
+            0 => Duration::MAX,
+            rate => Duration::from_nanos((missing * 1e9 / rate as f64).ceil() as u64),
+        };
+        self.updated.and_then(|at| at.checked_add(wait))
+    }
+
+    /// Spends the tokens of a segment of `len` octets sent.
+    pub fn on_send(&mut self, len: usize) {
+        self.tokens -= len as f64;
+    }
+
+    fn needed(&self, len: usize) -> f64 {
+        len.min(self.burst as usize) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::ratelimit::TokenBucket;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(10_000, 2000);
+        bucket.refill(now);
+        assert!(bucket.can_send(1000));
+        bucket.on_send(1000);
+        bucket.on_send(1000);
+        assert!(!bucket.can_send(1000));
+        assert_eq!(bucket.send_at(1000), Some(now + Duration::from_millis(100)));
+
+        // never more than the burst after an idle period
+        bucket.refill(now + Duration::from_secs(10));
+        assert!(bucket.can_send(2000));
+        bucket.on_send(2000);
+        assert!(!bucket.can_send(1));
+
+        // a segment larger than the burst waits for a full bucket
+        bucket.refill(now + Duration::from_secs(10) + Duration::from_millis(150));
+        assert!(!bucket.can_send(3000));
+        assert_eq!(
+            bucket.send_at(3000),
+            Some(now + Duration::from_secs(10) + Duration::from_millis(200))
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! The out of order segment text received beyond RCV.NXT, waiting for the holes before it to be
+//! filled. The data is kept as disjoint blocks, which are also what the SACK option reports to the
+//! peer, see https://www.ietf.org/rfc/rfc2018.txt. Duplicate segment text is reported once in a
+//! D-SACK block ahead of them, see https://www.ietf.org/rfc/rfc2883.txt.
+
+use crate::tcp::wrapping_lt;
+
+/// The most SACK blocks fitting in the options of a segment without timestamps
+pub const MAX_SACK_BLOCKS: usize = 4;
+
+#[derive(Debug, Default)]
+pub struct Reassembly {
+    /// Neither overlapping nor adjacent, in sequence order
+    blocks: Vec<Block>,
+    /// Ticks on every insert, orders the blocks by the last segment they received
+    clock: u64,
+    /// The octets queued
+    len: usize,
+    /// The duplicate octets received last, until an ACK reported them
+    duplicate: Option<(u32, u32)>,
+}
+
+#[derive(Debug)]
+struct Block {
+    seq: u32,
+    data: Vec<u8>,
+    touched: u64,
+}
+
+impl Block {
+    fn end(&self) -> u32 {
+        self.seq.wrapping_add(self.data.len() as u32)
+    }
+}
+
+impl Reassembly {
+    /// Queues the segment text `data` starting at `seq`, merging it with the blocks it overlaps or
+    /// touches. Returns the number of octets not queued before.
+    pub fn insert(&mut self, seq: u32, data: &[u8]) -> usize {
+        if data.is_empty() {
+            return 0;
+        }
+        let end = seq.wrapping_add(data.len() as u32);
+        self.clock += 1;
+
+        // the blocks from `at` to `until` overlap or touch [seq, end)
+        let at = self
+            .blocks
+            .iter()
+            .position(|b| !wrapping_lt(b.end(), seq))
+            .unwrap_or(self.blocks.len());
+        let until = self.blocks[at..]
+            .iter()
+            .position(|b| wrapping_lt(end, b.seq))
+            .map_or(self.blocks.len(), |i| at + i);
+        let merged = self.blocks.drain(at..until).collect::<Vec<_>>();
+
+        let start = match merged.first() {
+            Some(b) if wrapping_lt(b.seq, seq) => b.seq,
+            _ => seq,
+        };
+        let stop = match merged.last() {
+            Some(b) if wrapping_lt(end, b.end()) => b.end(),
+            _ => end,
+        };
+        let mut block = vec![0u8; stop.wrapping_sub(start) as usize];
+        let offset = seq.wrapping_sub(start) as usize;
+        block[offset..offset + data.len()].copy_from_slice(data);
+        let mut queued = 0;
+        for b in &merged {
+            let offset = b.seq.wrapping_sub(start) as usize;
+            block[offset..offset + b.data.len()].copy_from_slice(&b.data);
+            queued += b.data.len();
+        }
+
+        let new = block.len() - queued;
+        self.len += new;
+        self.blocks.insert(
+            at,
+            Block {
+                seq: start,
+                data: block,
+                touched: self.clock,
+            },
+        );
+        new
+    }
+
+    /// Takes the data that continues at RCV.NXT, once the hole before it is filled. Data before RCV.NXT,
+    /// received again in order meanwhile, is discarded. Returns the data and the octets discarded.
+    pub fn pop(&mut self, nxt: u32) -> Option<(Vec<u8>, usize)> {
+        let mut discarded = 0;
+        while let Some(b) = self.blocks.first() {
+            if wrapping_lt(nxt, b.seq) {
+                break;
+            }
+            let b = self.blocks.remove(0);
+            self.len -= b.data.len();
+            let seen = nxt.wrapping_sub(b.seq) as usize;
+            if seen < b.data.len() {
+                let mut data = b.data;
+                data.drain(..seen);
+                return Some((data, discarded + seen));
+            }
+            discarded += b.data.len();
+        }
+        (discarded > 0).then(|| (vec![], discarded))
+    }
+
+    /// The first part of [seq, end) already queued.
+    pub fn overlap(&self, seq: u32, end: u32) -> Option<(u32, u32)> {
+        self.blocks.iter().find_map(|b| {
+            let left = if wrapping_lt(b.seq, seq) { seq } else { b.seq };
+            let right = if wrapping_lt(end, b.end()) {
+                end
+            } else {
+                b.end()
+            };
+            wrapping_lt(left, right).then_some((left, right))
+        })
+    }
+
+    /// Records the octets [seq, end) received more than once, to be reported as the first block of
+    /// the next ACK. Only the latest duplicate is reported.
+    pub fn on_duplicate(&mut self, seq: u32, end: u32) {
+        self.duplicate = Some((seq, end));
+    }
+
+    /// Forgets the duplicate once an ACK reported it, a D-SACK block is only sent once,
+    /// https://www.ietf.org/rfc/rfc2883.txt section 4.
+    pub fn on_reported(&mut self) {
+        self.duplicate = None;
+    }
+
+    /// The blocks as (left edge, right edge), the one that received the latest segment first as
+    /// required by https://www.ietf.org/rfc/rfc2018.txt section 4. A duplicate goes before all of
+    /// them, followed by the block it belongs to if it was queued, which is the latest.
+    pub fn sack_blocks(&self) -> Vec<(u32, u32)> {
+        let mut blocks = self.blocks.iter().collect::<Vec<_>>();
+        blocks.sort_by_key(|b| std::cmp::Reverse(b.touched));
+        self.duplicate
+            .into_iter()
+            .chain(blocks.into_iter().map(|b| (b.seq, b.end())))
+            .take(MAX_SACK_BLOCKS)
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::reassembly::Reassembly;
+
+    #[test]
+    fn test_insert() {
+        let mut queue = Reassembly::default();
+        assert_eq!(queue.insert(u32::MAX - 1, b"ab"), 2);
+        assert_eq!(queue.insert(10, b"kl"), 2);
+        assert_eq!(queue.insert(4, b"ef"), 2);
+        assert_eq!(
+            queue.sack_blocks(),
+            vec![(4, 6), (10, 12), (u32::MAX - 1, 0)]
+        );
+
+        // fills the gap between two blocks and overlaps them
+        assert_eq!(queue.insert(5, b"fghijk"), 4);
+        assert_eq!(queue.sack_blocks(), vec![(4, 12), (u32::MAX - 1, 0)]);
+        // a duplicate adds nothing but becomes the latest
+        assert_eq!(queue.insert(u32::MAX - 1, b"a"), 0);
+        assert_eq!(queue.sack_blocks(), vec![(u32::MAX - 1, 0), (4, 12)]);
+        assert_eq!(queue.len(), 10);
+    }
+
+    #[test]
+    fn test_pop() {
+        let mut queue = Reassembly::default();
+        queue.insert(10, b"klm");
+        queue.insert(20, b"uv");
+        assert_eq!(queue.pop(8), None);
+
+        // the first octet arrived in order meanwhile
+        assert_eq!(queue.pop(11), Some((b"lm".to_vec(), 1)));
+        assert_eq!(queue.pop(13), None);
+        // entirely received in order
+        assert_eq!(queue.pop(22), Some((vec![], 2)));
+        assert!(queue.is_empty());
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn test_duplicate() {
+        let mut queue = Reassembly::default();
+        queue.insert(10, b"klm");
+        queue.insert(20, b"uv");
+        assert_eq!(queue.overlap(5, 10), None);
+        assert_eq!(queue.overlap(12, 21), Some((12, 13)));
+
+        // reported first, ahead of the block holding it
+        queue.on_duplicate(12, 13);
+        queue.insert(12, b"mn");
+        assert_eq!(queue.sack_blocks(), vec![(12, 13), (10, 14), (20, 22)]);
+        queue.on_reported();
+        assert_eq!(queue.sack_blocks(), vec![(10, 14), (20, 22)]);
+    }
+}
@@ -0,0 +1,76 @@
+//! Reno, https://www.ietf.org/rfc/rfc5681.txt. Below ssthresh the window grows as in slow start, above
+//! it by one segment per window acknowledged, both counted in octets acknowledged rather than in ACKs,
+//! see `ByteCounter`.
+//!
+//! A loss halves the data in flight into ssthresh, the window carrying on from there, and a
+//! retransmission timeout restarts from a single segment.
+
+use crate::tcp::congestion::{initial_window, ByteCounter, CongestionControl};
+use std::time::Instant;
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Reno {
+    cwnd: u32,
+    ssthresh: u32,
+    abc: ByteCounter,
+}
+
+impl Reno {
+    pub fn new(mss: u32) -> Self {
+        Self {
+            cwnd: initial_window(mss),
+            ssthresh: u32::MAX,
+            abc: ByteCounter::default(),
+        }
+    }
+
+    /// The ssthresh after a congestion with `in_flight` octets sent, max (FlightSize / 2, 2*SMSS),
+    /// RFC 5681 equation 4
+    fn reduce(&mut self, in_flight: u32, mss: u32) {
+        self.ssthresh = (in_flight / 2).max(2 * mss);
+        self.abc.on_loss();
+    }
+}
+
+impl CongestionControl for Reno {
+    fn name(&self) -> &'static str {
+        "reno"
+    }
+
+    fn cwnd(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn ssthresh(&self) -> u32 {
+        self.ssthresh
+    }
+
+    fn set_cwnd(&mut self, cwnd: u32) {
+        self.cwnd = cwnd;
+    }
+
+    fn set_ssthresh(&mut self, ssthresh: u32) {
+        self.ssthresh = ssthresh;
+    }
+
+    fn on_ack(&mut self, acked: u32, mss: u32, _now: Instant) {
+        self.cwnd = if self.cwnd < self.ssthresh {
+            self.abc.slow_start(self.cwnd, acked, mss)
+        } else {
+            self.abc.congestion_avoidance(self.cwnd, acked, mss)
+        };
+    }
+
+    fn on_loss(&mut self, in_flight: u32, mss: u32) {
+        self.reduce(in_flight, mss);
+        self.cwnd = self.ssthresh;
+    }
+
+    /// The retransmission doesn't change the data in flight, so a timer backing off again leaves
+    /// ssthresh where the first expiry set it, RFC 5681 equations 4 and 5.
+    fn on_rto(&mut self, in_flight: u32, mss: u32) {
+        self.reduce(in_flight, mss);
+        self.abc.on_rto();
+        self.cwnd = mss;
+    }
+}
@@ -0,0 +1,553 @@
+//! The retransmission queue and timer of a connection, following https://www.ietf.org/rfc/rfc6298.txt.
+//!
+//! The data itself stays in the outgoing buffer of the connection until it's acknowledged, the queue
+//! only records the boundaries of the segments sent and when they were sent. Segments the peer reported
+//! in SACK blocks are skipped when retransmitting, see https://www.ietf.org/rfc/rfc2018.txt.
+//!
+//! A D-SACK reporting the peer received a retransmitted segment twice shows the retransmission was
+//! spurious, the timer only expired because the ACKs were late. The RTO backoff it caused is undone, see
+//! https://www.ietf.org/rfc/rfc3708.txt. The congestion window stays reduced, only the backoff is
+//! undone.
+//!
+//! Every segment also records how much had been delivered when it was sent, so its ACK gives a sample of
+//! the delivery rate, see https://datatracker.ietf.org/doc/html/draft-cheng-iccrg-delivery-rate-estimation.
+//! Only cumulatively acknowledged segments count as delivered, and the samples aren't told apart when the
+//! application didn't write enough to fill the window.
+//!
+//! The segments delivered feed RACK, which marks lost those sent before them, see `rack`. The lost
+//! segments are retransmitted as soon as possible, and again if the retransmission is lost as well.
+
+use crate::tcp::rack::Rack;
+use crate::tcp::wrapping_lt;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The RTO before any RTT is measured, RFC 6298 2.1
+pub const INITIAL_RTO: Duration = Duration::from_secs(1);
+/// The upper bound of the backed off RTO, RFC 6298 2.5
+pub const MAX_RTO: Duration = Duration::from_secs(60);
+/// The lower bound of the RTO, RFC 6298 2.4
+pub const MIN_RTO: Duration = Duration::from_secs(1);
+/// The retransmissions remembered to be recognized in a D-SACK
+const MAX_RETRANSMITTED: usize = 8;
+
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Sent {
+    pub seq: u32,
+    pub len: u32,
+    /// When the segment was last sent
+    pub sent_at: Instant,
+    /// Whether the segment was sent more than once, such segments can't be used for RTT samples
+    pub retransmitted: bool,
+    /// Whether the peer reported the segment received out of order
+    pub sacked: bool,
+    /// Whether RACK deemed the segment lost since it was last sent
+    pub lost: bool,
+    /// The octets delivered when the segment was sent
+    pub delivered: u64,
+    /// When the latest of those octets was delivered
+    pub delivered_at: Instant,
+}
+
+impl Sent {
+    /// The sequence number following the segment
+    pub fn end(&self) -> u32 {
+        self.seq.wrapping_add(self.len)
+    }
+}
+
+/// What an ACK of new data freed from the queue
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct Acked {
+    /// The number of segments fully acknowledged
+    pub segments: usize,
+    /// The octets of the segments fully acknowledged. Growth, e.g. of the send buffer, is only credited
+    /// for these, so a receiver splitting its ACKs of a segment into many (ACK division, see
+    /// https://www.ietf.org/rfc/rfc3465.txt) gains nothing from it.
+    pub bytes: u32,
+    /// The ACK falls within a segment instead of at its end
+    pub divided: bool,
+    /// The RTT measured from the latest segment acknowledged, unless it was retransmitted
+    /// (Karn's algorithm)
+    pub rtt: Option<Duration>,
+    /// The delivery rate measured from the latest segment acknowledged
+    pub rate: Option<RateSample>,
+}
+
+/// The octets delivered over an interval, from a segment being sent to its ACK
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct RateSample {
+    /// The octets delivered when the segment was sent
+    pub prior_delivered: u64,
+    /// The octets delivered since
+    pub delivered: u64,
+    pub interval: Duration,
+}
+
+impl RateSample {
+    /// The delivery rate in octets per second
+    pub fn rate(&self) -> u64 {
+        match self.interval.as_micros() {
+            0 => 0,
+            micros => (self.delivered as u128 * 1_000_000 / micros) as u64,
+        }
+    }
+}
+
+/// A segment retransmitted, with the RTO before the retransmission backed it off
+#[derive(PartialEq, Eq, Debug, Clone)]
+struct Retransmitted {
+    seq: u32,
+    end: u32,
+    rto: Duration,
+}
+
+#[derive(Debug)]
+pub struct Retransmission {
+    /// The segments sent but not fully acknowledged yet, in sequence order
+    queue: VecDeque<Sent>,
+    rto: Duration,
+    /// The bounds of the RTO, see `Tunables::rto_min` and `Tunables::rto_max`
+    min_rto: Duration,
+    max_rto: Duration,
+    /// When the retransmission timer expires, none while nothing is in flight
+    deadline: Option<Instant>,
+    /// The latest retransmissions, the oldest first
+    retransmitted: VecDeque<Retransmitted>,
+    /// The octets acknowledged so far
+    delivered: u64,
+    /// The segments retransmitted so far
+    retransmits: u64,
+    /// When the latest of those octets was acknowledged, or the connection was last idle
+    delivered_at: Option<Instant>,
+    rack: Rack,
+    /// When a segment RACK holds in the reordering window is deemed lost, RACK's reordering timer
+    reo_deadline: Option<Instant>,
+}
+
+impl Default for Retransmission {
+    fn default() -> Self {
+        Self {
+            queue: VecDeque::new(),
+            rto: INITIAL_RTO,
+            min_rto: MIN_RTO,
+            max_rto: MAX_RTO,
+            deadline: None,
+            retransmitted: VecDeque::new(),
+            delivered: 0,
+            retransmits: 0,
+            delivered_at: None,
+            rack: Rack::default(),
+            reo_deadline: None,
+        }
+    }
+}
+
+impl Retransmission {
+    /// Records a segment sent for the first time. The timer is started unless it's running already,
+    /// RFC 6298 5.1.
+    pub fn on_send(&mut self, seq: u32, len: u32, now: Instant) {
+        // the time spent idle isn't part of any delivery interval
+        if self.queue.is_empty() {
+            self.delivered_at = Some(now);
+        }
+        self.queue.push_back(Sent {
+            seq,
+            len,
+            sent_at: now,
+            retransmitted: false,
+            sacked: false,
+            lost: false,
+            delivered: self.delivered,
+            delivered_at: self.delivered_at.unwrap_or(now),
+        });
+        if self.deadline.is_none() {
+            self.deadline = Some(now + self.rto);
+        }
+    }
+
+    /// Drops the segments fully acknowledged by `ack`, which must acknowledge new data, i.e.
+    /// SND.UNA < SEG.ACK =< SND.NXT.
+    pub fn on_ack(&mut self, ack: u32, now: Instant) -> Acked {
+        let mut acked = Acked {
+            segments: 0,
+            bytes: 0,
+            divided: false,
+            rtt: None,
+            rate: None,
+        };
+        while let Some(sent) = self.queue.front() {
+            if wrapping_lt(ack, sent.end()) {
+                acked.divided = wrapping_lt(sent.seq, ack);
+                break;
+            }
+            self.rack.on_delivered(sent, now);
+            acked.segments += 1;
+            acked.bytes += sent.len;
+            acked.rtt = (!sent.retransmitted).then(|| now.duration_since(sent.sent_at));
+            self.delivered += sent.len as u64;
+            acked.rate = Some(RateSample {
+                prior_delivered: sent.delivered,
+                delivered: self.delivered - sent.delivered,
+                interval: now.duration_since(sent.delivered_at),
+            });
+            self.queue.pop_front();
+        }
+        if acked.segments > 0 {
+            self.delivered_at = Some(now);
+        }
+
+        // there is no RTT estimation yet, so the backed off RTO is only undone once new data is acked
+        self.rto = self.initial_rto();
+        // RFC 6298 5.2 when all outstanding data has been acknowledged, turn off the timer, and 5.3
+        // when an ACK is received that acknowledges new data, restart the timer
+        self.deadline = if self.queue.is_empty() {
+            None
+        } else {
+            Some(now + self.rto)
+        };
+        acked
+    }
+
+    /// Marks the segments entirely covered by one of the SACK `blocks` as received by the peer at
+    /// `now`.
+    pub fn on_sack(&mut self, blocks: &[(u32, u32)], now: Instant) {
+        for sent in self.queue.iter_mut() {
+            let sacked = blocks.iter().any(|(left, right)| {
+                !wrapping_lt(sent.seq, *left) && !wrapping_lt(*right, sent.end())
+            });
+            if sacked && !sent.sacked {
+                sent.sacked = true;
+                self.rack.on_delivered(sent, now);
+            }
+        }
+    }
+
+    /// Marks lost the segments RACK deems lost at `now`, RFC 8985 6.2 step 5, and returns how many
+    /// were. The reordering timer is armed for those it may still deem lost later.
+    pub fn detect_loss(&mut self, now: Instant) -> usize {
+        let sacked = self.queue.iter().filter(|sent| sent.sacked).count();
+        let mut lost = 0;
+        self.reo_deadline = None;
+        for sent in self
+            .queue
+            .iter_mut()
+            .filter(|sent| !sent.sacked && !sent.lost)
+        {
+            match self.rack.lost_at(sent, sacked) {
+                Some(at) if at <= now => {
+                    sent.lost = true;
+                    lost += 1;
+                }
+                Some(at) => {
+                    self.reo_deadline = Some(self.reo_deadline.map_or(at, |d| d.min(at)));
+                }
+                None => {}
+            }
+        }
+        lost
+    }
+
+    /// When the reordering timer expires, none if it's not armed
+    pub fn reo_deadline(&self) -> Option<Instant> {
+        self.reo_deadline
+    }
+
+    /// Returns the earliest segment deemed lost and not retransmitted since, to be retransmitted.
+    pub fn next_lost(&mut self, now: Instant) -> Option<Sent> {
+        let at = self
+            .queue
+            .iter()
+            .position(|sent| sent.lost && !sent.sacked)?;
+        self.retransmit_at(at, now)
+    }
+
+    /// Handles a D-SACK block [left, right) from the peer. Returns whether it reports a segment we
+    /// retransmitted, in which case the retransmission was spurious and the RTO is restored to what it
+    /// was before. A D-SACK of a segment never retransmitted was duplicated by the network instead.
+    pub fn on_dsack(&mut self, left: u32, right: u32) -> bool {
+        // the latest retransmission of the segment is the one that was unnecessary
+        let at = self.retransmitted.iter().rposition(|r| {
+            !wrapping_lt(left, r.seq) && !wrapping_lt(r.end, right) && wrapping_lt(left, right)
+        });
+        let retransmitted = match at.and_then(|at| self.retransmitted.remove(at)) {
+            Some(retransmitted) => retransmitted,
+            None => return false,
+        };
+        self.rto = self.rto.min(retransmitted.rto);
+        self.rack.on_dsack();
+        true
+    }
+
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.map(|d| d <= now).unwrap_or(false)
+    }
+
+    /// Handles the expiry of the timer: backs off the RTO, restarts the timer and returns the earliest
+    /// segment not acknowledged, to be retransmitted. See RFC 6298 5.4 to 5.6. The segments the peer
+    /// SACKed are skipped, unless it SACKed all of them.
+    pub fn on_timeout(&mut self, now: Instant) -> Option<Sent> {
+        let sent = match self.retransmit_first(now) {
+            Some(sent) => sent,
+            None => {
+                self.deadline = None;
+                return None;
+            }
+        };
+        self.rto = (self.rto * 2).min(self.max_rto);
+        self.deadline = Some(now + self.rto);
+        Some(sent)
+    }
+
+    /// Returns the earliest segment not acknowledged, retransmitted after duplicate ACKs showed it was
+    /// lost. Unlike a timeout, the RTO and the timer are left alone.
+    pub fn on_fast_retransmit(&mut self, now: Instant) -> Option<Sent> {
+        self.retransmit_first(now)
+    }
+
+    /// Marks the earliest segment not SACKed as retransmitted at `now`.
+    fn retransmit_first(&mut self, now: Instant) -> Option<Sent> {
+        let at = self.queue.iter().position(|sent| !sent.sacked).unwrap_or(0);
+        self.retransmit_at(at, now)
+    }
+
+    /// Marks the segment `at` in the queue as retransmitted at `now`.
+    fn retransmit_at(&mut self, at: usize, now: Instant) -> Option<Sent> {
+        let sent = self.queue.get_mut(at)?;
+        sent.sent_at = now;
+        sent.retransmitted = true;
+        sent.lost = false;
+        sent.delivered = self.delivered;
+        sent.delivered_at = self.delivered_at.unwrap_or(now);
+        self.retransmits += 1;
+
+        if self.retransmitted.len() == MAX_RETRANSMITTED {
+            self.retransmitted.pop_front();
+        }
+        self.retransmitted.push_back(Retransmitted {
+            seq: sent.seq,
+            end: sent.end(),
+            rto: self.rto,
+        });
+        Some(sent.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// When the retransmission timer expires, none while it isn't armed.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rto
+    }
+
+    /// Bounds the RTO within `min..=max`, the initial RTO included.
+    pub fn set_bounds(&mut self, min: Duration, max: Duration) {
+        self.min_rto = min;
+        self.max_rto = max;
+        self.rto = self.initial_rto();
+    }
+
+    /// The upper bound of the backed off RTO
+    pub fn max_rto(&self) -> Duration {
+        self.max_rto
+    }
+
+    fn initial_rto(&self) -> Duration {
+        INITIAL_RTO.clamp(self.min_rto, self.max_rto)
+    }
+
+    /// The octets acknowledged so far
+    pub fn delivered(&self) -> u64 {
+        self.delivered
+    }
+
+    /// The segments retransmitted so far
+    pub fn retransmits(&self) -> u64 {
+        self.retransmits
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::retransmit::{RateSample, Retransmission, INITIAL_RTO, MAX_RTO};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_on_ack() {
+        let now = Instant::now();
+        let mut rtx = Retransmission::default();
+        assert!(!rtx.is_armed());
+
+        rtx.on_send(u32::MAX - 9, 10, now);
+        rtx.on_send(0, 10, now + Duration::from_millis(10));
+        assert!(rtx.is_expired(now + INITIAL_RTO));
+        assert!(!rtx.is_expired(now + INITIAL_RTO - Duration::from_millis(1)));
+
+        // partially acknowledged segments stay, the timer restarts
+        let later = now + Duration::from_millis(500);
+        let acked = rtx.on_ack(5, later);
+        assert_eq!(acked.segments, 1);
+        assert_eq!(acked.bytes, 10);
+        assert!(acked.divided);
+        assert_eq!(acked.rtt, Some(Duration::from_millis(500)));
+        assert_eq!(rtx.len(), 1);
+        assert!(!rtx.is_expired(now + INITIAL_RTO));
+        assert!(rtx.is_expired(later + INITIAL_RTO));
+
+        // everything acknowledged, the timer stops
+        let acked = rtx.on_ack(10, later);
+        assert_eq!(acked.segments, 1);
+        assert_eq!(acked.bytes, 10);
+        assert!(!acked.divided);
+        assert!(rtx.is_empty());
+        assert!(!rtx.is_armed());
+    }
+
+    #[test]
+    fn test_on_timeout() {
+        let mut now = Instant::now();
+        let mut rtx = Retransmission::default();
+        assert!(rtx.on_timeout(now).is_none());
+
+        rtx.on_send(100, 10, now);
+        rtx.on_send(110, 10, now);
+        for _ in 0..10 {
+            now += rtx.rto();
+            assert!(rtx.is_expired(now));
+            let sent = rtx.on_timeout(now).unwrap();
+            assert_eq!(sent.seq, 100);
+            assert!(sent.retransmitted);
+        }
+        assert_eq!(rtx.rto(), MAX_RTO);
+
+        // new data acknowledged, the RTO backoff is undone, the retransmitted segment gives no RTT
+        assert_eq!(rtx.on_ack(110, now).rtt, None);
+        assert_eq!(rtx.rto(), INITIAL_RTO);
+    }
+
+    #[test]
+    fn test_rto_bounds() {
+        let mut now = Instant::now();
+        let mut rtx = Retransmission::default();
+        rtx.set_bounds(Duration::from_millis(200), Duration::from_millis(500));
+        assert_eq!(rtx.rto(), Duration::from_millis(500));
+
+        rtx.on_send(100, 10, now);
+        now += rtx.rto();
+        rtx.on_timeout(now);
+        assert_eq!(rtx.rto(), Duration::from_millis(500));
+
+        rtx.set_bounds(Duration::from_secs(2), MAX_RTO);
+        assert_eq!(rtx.rto(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_on_sack() {
+        let now = Instant::now();
+        let mut rtx = Retransmission::default();
+        for seq in [100, 110, 120, 130] {
+            rtx.on_send(seq, 10, now);
+        }
+
+        // the first block only covers part of a segment
+        rtx.on_sack(&[(105, 120), (130, 140)], now);
+        assert_eq!(rtx.on_timeout(now).unwrap().seq, 100);
+        rtx.on_ack(110, now);
+        // the hole at 120 is retransmitted, not the SACKed segment before it
+        assert_eq!(rtx.on_timeout(now).unwrap().seq, 120);
+    }
+
+    #[test]
+    fn test_detect_loss() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut rtx = Retransmission::default();
+        for (i, seq) in [100, 110, 120, 130].into_iter().enumerate() {
+            rtx.on_send(seq, 10, now + ms(i as u64));
+        }
+
+        // the last two arrive 38ms later, the first two get a quarter of that to arrive out of order
+        rtx.on_sack(&[(120, 140)], now + ms(41));
+        assert_eq!(rtx.detect_loss(now + ms(41)), 0);
+        assert_eq!(
+            rtx.reo_deadline(),
+            Some(now + Duration::from_micros(47_500))
+        );
+        assert_eq!(rtx.detect_loss(now + ms(48)), 1);
+        assert_eq!(
+            rtx.reo_deadline(),
+            Some(now + Duration::from_micros(48_500))
+        );
+
+        let sent = rtx.next_lost(now + ms(48)).unwrap();
+        assert_eq!((sent.seq, sent.retransmitted), (100, true));
+        assert_eq!(rtx.next_lost(now + ms(48)), None);
+        // the retransmission was sent after anything delivered, it's not deemed lost again
+        assert_eq!(rtx.detect_loss(now + ms(49)), 1);
+        assert_eq!(rtx.next_lost(now + ms(49)).unwrap().seq, 110);
+        assert_eq!(rtx.reo_deadline(), None);
+    }
+
+    #[test]
+    fn test_on_dsack() {
+        let now = Instant::now();
+        let mut rtx = Retransmission::default();
+        rtx.on_send(100, 10, now);
+        rtx.on_send(110, 10, now);
+        rtx.on_timeout(now + INITIAL_RTO);
+        rtx.on_timeout(now + INITIAL_RTO * 3);
+        assert_eq!(rtx.rto(), INITIAL_RTO * 4);
+
+        // never retransmitted, duplicated by the network
+        assert!(!rtx.on_dsack(110, 120));
+        assert_eq!(rtx.rto(), INITIAL_RTO * 4);
+        // the second retransmission was spurious, the first is still remembered
+        assert!(rtx.on_dsack(100, 110));
+        assert_eq!(rtx.rto(), INITIAL_RTO * 2);
+        assert!(rtx.on_dsack(100, 105));
+        assert_eq!(rtx.rto(), INITIAL_RTO);
+        assert!(!rtx.on_dsack(100, 110));
+    }
+
+    #[test]
+    fn test_rate_sample() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut rtx = Retransmission::default();
+        rtx.on_send(0, 1000, now);
+        rtx.on_send(1000, 1000, now + ms(10));
+        let acked = rtx.on_ack(1000, now + ms(100));
+        let expected = RateSample {
+            prior_delivered: 0,
+            delivered: 1000,
+            interval: ms(100),
+        };
+        assert_eq!(acked.rate, Some(expected));
+        assert_eq!(acked.rate.unwrap().rate(), 10_000);
+
+        // the sample is taken from the latest segment acknowledged
+        rtx.on_send(2000, 1000, now + ms(100));
+        let acked = rtx.on_ack(3000, now + ms(200)).rate.unwrap();
+        assert_eq!((acked.prior_delivered, acked.delivered), (1000, 2000));
+        assert_eq!(acked.interval, ms(100));
+
+        // idle again, the gap doesn't count
+        rtx.on_send(3000, 1000, now + ms(1000));
+        let acked = rtx.on_ack(4000, now + ms(1050)).rate.unwrap();
+        assert_eq!((acked.delivered, acked.interval), (1000, ms(50)));
+        assert_eq!(rtx.delivered(), 4000);
+    }
+}
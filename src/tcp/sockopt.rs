@@ -0,0 +1,302 @@
+//! The socket options of a connection, what `setsockopt(2)` sets on a kernel socket:
+//!
+//! - `NoDelay` off holds back a small segment while data is in flight, Nagle's algorithm of
+//!   https://www.ietf.org/rfc/rfc896.txt as in https://www.ietf.org/rfc/rfc1122.txt 4.2.3.4. The stack
+//!   always sent right away, so it's on by default, unlike on a kernel socket.
+//! - `KeepAlive` probes a connection once nothing was received for `idle`, every `interval`, and drops it
+//!   after `probes` unanswered ones, https://www.ietf.org/rfc/rfc1122.txt 4.2.3.6.
+//! - `UserTimeout` drops a connection whose data stays unacknowledged for longer,
+//!   https://www.ietf.org/rfc/rfc5482.txt.
+//! - `Linger` is how long a close waits for the data queued to be acknowledged.
+//! - `RecvBuffer` and `SendBuffer` are the sizes of the buffers, see `autotune`.
+//! - `IpOptions` are the options in the ip header of the segments sent, see `ip::options`.
+//! - `Dscp` is the Differentiated Services codepoint of the segments sent, e.g. `ip::DSCP_EF`; the TOS
+//!   of the last segment received is in `TcpInfo`.
+//! - `Ttl` is the time to live of the segments sent, `Tunables::ttl` by default.
+//! - `DontFragment` sets DF on the segments sent, which fail instead of being fragmented when they
+//!   exceed the MTU, see `ip::fragment`.
+
+use crate::ip::options::IpOptions;
+use crate::tcp::DEFAULT_TTL;
+use std::time::{Duration, Instant};
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SocketOption {
+    NoDelay(bool),
+    KeepAlive(Option<KeepAlive>),
+    Linger(Option<Duration>),
+    RecvBuffer(u32),
+    SendBuffer(u32),
+    UserTimeout(Option<Duration>),
+    IpOptions(IpOptions),
+    DontFragment(bool),
+    Ttl(u8),
+    Dscp(u8),
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum OptionName {
+    NoDelay,
+    KeepAlive,
+    Linger,
+    RecvBuffer,
+    SendBuffer,
+    UserTimeout,
+    IpOptions,
+    DontFragment,
+    Ttl,
+    Dscp,
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct KeepAlive {
+    /// How long nothing is received before the first probe
+    pub idle: Duration,
+    /// Between the probes
+    pub interval: Duration,
+    /// The unanswered probes after which the connection is dropped
+    pub probes: u32,
+}
+
+impl Default for KeepAlive {
+    /// The defaults of Linux, the idle time being the 2 hours of RFC 1122
+    fn default() -> Self {
+        Self {
+            idle: Duration::from_secs(2 * 60 * 60),
+            interval: Duration::from_secs(75),
+            probes: 9,
+        }
+    }
+}
+
+/// What the keep-alive timer does once it expires
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum KeepAliveAction {
+    Probe,
+    /// The probes went unanswered, the connection is dead
+    Drop,
+}
+
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    nodelay: bool,
+    keepalive: Option<KeepAlive>,
+    linger: Option<Duration>,
+    user_timeout: Option<Duration>,
+    ip_options: IpOptions,
+    dont_fragment: bool,
+    ttl: u8,
+    dscp: u8,
+    /// The TOS octet of the last segment received, its DSCP and ECN codepoint
+    received_tos: Option<u8>,
+    /// The type and code of the last ICMP soft error about the connection, see `ip::icmp`
+    icmp_error: Option<(u8, u8)>,
+    /// When a segment was last received, the keep-alive idle time counts from then
+    heard: Option<Instant>,
+    /// The keep-alive probes sent since
+    probes: u32,
+    /// Since when the data in flight hasn't been acknowledged, none while nothing is in flight
+    stalled_since: Option<Instant>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            linger: None,
+            user_timeout: None,
+            ip_options: IpOptions::default(),
+            dont_fragment: true,
+            ttl: DEFAULT_TTL,
+            dscp: 0,
+            received_tos: None,
+            icmp_error: None,
+            heard: None,
+            probes: 0,
+            stalled_since: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    pub fn nodelay(&self) -> bool {
+        self.nodelay
+    }
+
+    pub fn keepalive(&self) -> Option<KeepAlive> {
+        self.keepalive
+    }
+
+    pub fn linger(&self) -> Option<Duration> {
+        self.linger
+    }
+
+    pub fn user_timeout(&self) -> Option<Duration> {
+        self.user_timeout
+    }
+
+    pub fn ip_options(&self) -> IpOptions {
+        self.ip_options
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.dont_fragment
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.ttl
+    }
+
+    pub fn dscp(&self) -> u8 {
+        self.dscp
+    }
+
+    pub fn received_tos(&self) -> Option<u8> {
+        self.received_tos
+    }
+
+    pub fn icmp_error(&self) -> Option<(u8, u8)> {
+        self.icmp_error
+    }
+
+    pub fn set_nodelay(&mut self, nodelay: bool) {
+        self.nodelay = nodelay;
+    }
+
+    /// Switches the keep-alive on, the idle time counting from `now` if nothing was received yet.
+    pub fn set_keepalive(&mut self, keepalive: Option<KeepAlive>, now: Instant) {
+        self.keepalive = keepalive;
+        self.heard.get_or_insert(now);
+    }
+
+    pub fn set_linger(&mut self, linger: Option<Duration>) {
+        self.linger = linger;
+    }
+
+    pub fn set_user_timeout(&mut self, timeout: Option<Duration>) {
+        self.user_timeout = timeout;
+    }
+
+    pub fn set_ip_options(&mut self, options: IpOptions) {
+        self.ip_options = options;
+    }
+
+    pub fn set_dont_fragment(&mut self, dont_fragment: bool) {
+        self.dont_fragment = dont_fragment;
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+    }
+
+    pub fn set_dscp(&mut self, dscp: u8) {
+        self.dscp = dscp;
+    }
+
+    /// Records the TOS octet of a segment received.
+    pub fn on_tos(&mut self, tos: u8) {
+        self.received_tos = Some(tos);
+    }
+
+    /// Records an ICMP soft error, which the connection survives, https://www.ietf.org/rfc/rfc1122.txt
+    /// 4.2.3.9.
+    pub fn on_icmp_error(&mut self, icmp_type: u8, code: u8) {
+        self.icmp_error = Some((icmp_type, code));
+    }
+
+    /// Records a segment received, the peer is alive.
+    pub fn on_heard(&mut self, now: Instant) {
+        self.heard = Some(now);
+        self.probes = 0;
+    }
+
+    /// Records data sent, the user timeout starts if nothing was in flight.
+    pub fn on_send(&mut self, now: Instant) {
+        self.stalled_since.get_or_insert(now);
+    }
+
+    /// Records new data acknowledged, the user timeout starts over while data is still `in_flight`.
+    pub fn on_ack(&mut self, in_flight: bool, now: Instant) {
+        self.stalled_since = in_flight.then_some(now);
+    }
+
+    /// When the keep-alive timer expires, none when it's off or while data is in flight, the
+    /// retransmissions tell whether the peer is alive then.
+    pub fn keepalive_deadline(&self) -> Option<Instant> {
+        let keepalive = self.keepalive?;
+        if self.stalled_since.is_some() {
+            return None;
+        }
+        Some(self.heard? + keepalive.idle + keepalive.interval * self.probes)
+    }
+
+    /// The keep-alive timer expired: another probe is due, or the connection is dead.
+    pub fn on_keepalive(&mut self) -> KeepAliveAction {
+        let probes = self.keepalive.map_or(0, |keepalive| keepalive.probes);
+        if self.probes >= probes {
+            return KeepAliveAction::Drop;
+        }
+        self.probes += 1;
+        KeepAliveAction::Probe
+    }
+
+    /// When the data in flight is unacknowledged for longer than the user timeout, none when it's off
+    /// or nothing is in flight.
+    pub fn user_timeout_deadline(&self) -> Option<Instant> {
+        Some(self.stalled_since? + self.user_timeout?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::sockopt::{KeepAlive, KeepAliveAction, SocketOptions};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_keepalive() {
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+        let mut options = SocketOptions::default();
+        assert_eq!(options.keepalive_deadline(), None);
+        let keepalive = KeepAlive {
+            idle: secs(60),
+            interval: secs(10),
+            probes: 2,
+        };
+        options.set_keepalive(Some(keepalive), now);
+        assert_eq!(options.keepalive_deadline(), Some(now + secs(60)));
+
+        assert_eq!(options.on_keepalive(), KeepAliveAction::Probe);
+        assert_eq!(options.keepalive_deadline(), Some(now + secs(70)));
+        // the peer answered the probe
+        options.on_heard(now + secs(65));
+        assert_eq!(options.keepalive_deadline(), Some(now + secs(125)));
+
+        assert_eq!(options.on_keepalive(), KeepAliveAction::Probe);
+        assert_eq!(options.on_keepalive(), KeepAliveAction::Probe);
+        assert_eq!(options.on_keepalive(), KeepAliveAction::Drop);
+
+        // the retransmissions tell while data is in flight
+        options.on_send(now + secs(70));
+        assert_eq!(options.keepalive_deadline(), None);
+    }
+
+    #[test]
+    fn test_user_timeout() {
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+        let mut options = SocketOptions::default();
+        options.set_user_timeout(Some(secs(30)));
+        assert_eq!(options.user_timeout_deadline(), None);
+
+        options.on_send(now);
+        options.on_send(now + secs(10));
+        assert_eq!(options.user_timeout_deadline(), Some(now + secs(30)));
+        // an ACK of new data starts it over
+        options.on_ack(true, now + secs(20));
+        assert_eq!(options.user_timeout_deadline(), Some(now + secs(50)));
+        options.on_ack(false, now + secs(40));
+        assert_eq!(options.user_timeout_deadline(), None);
+    }
+}
@@ -1,59 +1,41 @@
-use crate::tcp::{ReceiveSequenceSpace, SendSequenceSpace};
-use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice};
+use crate::core::tcb::{ReceiveSequenceSpace, SendSequenceSpace, Synchronized};
+use crate::wire::SegmentView;
+use std::time::{Duration, Instant};
 
-/// The initial listen state for a tcp connection
+pub use crate::core::tcb::{Established, SynRecv};
+
+/// The initial listen state for a tcp connection, holding the SYN received
 pub struct Listen<'a> {
-    pub(crate) ip_header: Ipv4HeaderSlice<'a>,
-    pub(crate) tcp_header: TcpHeaderSlice<'a>,
+    pub(crate) syn: SegmentView<'a>,
 }
 
+/// An active open waiting for the SYN-ACK of the peer, see `handshake::active`. Nothing is received
+/// yet, RCV.NXT and IRS are set from the SYN-ACK.
 #[derive(PartialEq, Eq, Debug)]
-#[repr(C)]
-pub struct SynRecv {
-    pub(crate) snd: SendSequenceSpace,
-    pub(crate) rcv: ReceiveSequenceSpace,
+pub struct SynSent {
+    pub snd: SendSequenceSpace,
+    pub rcv: ReceiveSequenceSpace,
+    /// The times the SYN was sent again
+    pub(crate) retries: u32,
+    /// The RTO, backed off on every retransmission of the SYN
+    pub(crate) rto: Duration,
+    /// When the SYN is sent again, or the open fails once it was `SYN_RETRIES` times
+    pub(crate) deadline: Instant,
 }
 
-#[derive(PartialEq, Eq, Debug)]
-#[repr(C)]
-pub struct Established {
-    pub(crate) snd: SendSequenceSpace,
-    pub(crate) rcv: ReceiveSequenceSpace,
+impl AsRef<SendSequenceSpace> for SynSent {
+    fn as_ref(&self) -> &SendSequenceSpace {
+        &self.snd
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use crate::tcp::state::{Established, SynRecv};
-    use crate::tcp::{ReceiveSequenceSpace, SendSequenceSpace};
-
-    #[test]
-    fn test_transmute() {
-        let sr = SynRecv {
-            snd: SendSequenceSpace {
-                up: true,
-                wnd: 10,
-                una: 20,
-                nxt: 30,
-                wl1: 40,
-                wl2: 50,
-                iss: 60,
-            },
-            rcv: ReceiveSequenceSpace {
-                up: true,
-                wnd: 70,
-                nxt: 80,
-                irs: 90,
-            },
-        };
-
-        let tr = unsafe { std::mem::transmute::<SynRecv, Established>(sr) };
-
-        assert_eq!(tr.snd.up, true);
-        assert_eq!(tr.snd.wnd, 10);
-        assert_eq!(tr.snd.una, 20);
-        assert_eq!(tr.snd.nxt, 30);
-        assert_eq!(tr.snd.wl1, 40);
-        assert_eq!(tr.snd.wl2, 50);
-        assert_eq!(tr.snd.iss, 60);
+impl AsRef<ReceiveSequenceSpace> for SynSent {
+    fn as_ref(&self) -> &ReceiveSequenceSpace {
+        &self.rcv
     }
 }
+
+impl Synchronized for SynSent {
+    const SYN_ACKED: bool = false;
+    const SYN_RECEIVED: bool = false;
+}
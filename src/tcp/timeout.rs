@@ -0,0 +1,151 @@
+//! Read and write timeouts of the application API. The connection never blocks, a blocking handle polls
+//! `try_read`/`try_write` between iterations of the event loop. Those return `WouldBlock` while nothing
+//! can be read or written, and `TimedOut` once that lasted longer than the timeout, so a stalled peer
+//! does not hang the application forever.
+
+use std::io::{Error, ErrorKind, Result};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Default)]
+pub struct Timeouts {
+    read: Option<Duration>,
+    write: Option<Duration>,
+    /// When the reads started to find nothing
+    read_since: Option<Instant>,
+    /// When the writes started to find the send buffer full
+    write_since: Option<Instant>,
+}
+
+impl Timeouts {
+    /// Sets the read timeout, `None` waits forever. A zero timeout is rejected like for
+    /// `std::net::TcpStream`.
+    pub fn set_read(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.read = check(timeout)?;
+        Ok(())
+    }
+
+    pub fn set_write(&mut self, timeout: Option<Duration>) -> Result<()> {
+        self.write = check(timeout)?;
+        Ok(())
+    }
+
+    pub fn read(&self) -> Option<Duration> {
+        self.read
+    }
+
+    pub fn write(&self) -> Option<Duration> {
+        self.write
+    }
+
+    /// When a read finding nothing fails with `TimedOut`, none while reads find data or without a
+    /// timeout.
+    pub fn read_deadline(&self) -> Option<Instant> {
+        Some(self.read_since? + self.read?)
+    }
+
+    /// When a write finding the send buffer full fails with `TimedOut`, see `read_deadline`.
+    pub fn write_deadline(&self) -> Option<Instant> {
+        Some(self.write_since? + self.write?)
+    }
+
+    /// Accounts a read copying `n` bytes, see `progress`.
+    pub fn on_read(&mut self, n: usize, now: Instant) -> Result<usize> {
+        progress(n, self.read, &mut self.read_since, now)
+    }
+
+    /// Accounts a write queueing `n` bytes, see `progress`.
+    pub fn on_write(&mut self, n: usize, now: Instant) -> Result<usize> {
+        progress(n, self.write, &mut self.write_since, now)
+    }
+}
+
+fn check(timeout: Option<Duration>) -> Result<Option<Duration>> {
+    if timeout == Some(Duration::ZERO) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "cannot set a 0 duration timeout",
+        ));
+    }
+    Ok(timeout)
+}
+
+/// Passes `n` through when there was progress, which ends the wait. Otherwise starts the wait, and
+/// fails with `TimedOut` once it lasted `timeout`, with `WouldBlock` before.
+fn progress(
+    n: usize,
+    timeout: Option<Duration>,
+    since: &mut Option<Instant>,
+    now: Instant,
+) -> Result<usize> {
+    if n > 0 {
+        *since = None;
+        return Ok(n);
+    }
+    let since = *since.get_or_insert(now);
+    match timeout {
+        Some(timeout) if now.saturating_duration_since(since) >= timeout => {
+            Err(ErrorKind::TimedOut.into())
+        }
+        _ => Err(ErrorKind::WouldBlock.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::timeout::Timeouts;
+    use std::io::ErrorKind;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_timeouts() {
+        let now = Instant::now();
+        let mut timeouts = Timeouts::default();
+        assert!(timeouts.set_read(Some(Duration::ZERO)).is_err());
+
+        // no timeout, waits forever
+        let later = now + Duration::from_secs(3600);
+        assert_eq!(
+            timeouts.on_read(0, later).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+
+        timeouts.set_read(Some(Duration::from_secs(1))).unwrap();
+        timeouts.set_write(Some(Duration::from_secs(2))).unwrap();
+        assert_eq!(
+            timeouts
+                .on_read(0, later + Duration::from_secs(1))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::TimedOut
+        );
+
+        let deadline = later + Duration::from_secs(1);
+        assert_eq!(timeouts.read_deadline(), Some(deadline));
+
+        // progress restarts the wait
+        assert_eq!(timeouts.on_read(3, later).unwrap(), 3);
+        assert_eq!(timeouts.read_deadline(), None);
+        assert_eq!(
+            timeouts.on_read(0, later).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        assert_eq!(
+            timeouts.on_write(0, later).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        assert_eq!(
+            timeouts
+                .on_write(0, later + Duration::from_secs(1))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::WouldBlock
+        );
+        assert_eq!(
+            timeouts
+                .on_write(0, later + Duration::from_secs(2))
+                .unwrap_err()
+                .kind(),
+            ErrorKind::TimedOut
+        );
+    }
+}
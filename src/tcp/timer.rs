@@ -0,0 +1,204 @@
+//! The timers of all the connections in one hashed timer wheel, drained by the main loop. Every timer is
+//! keyed by its connection and kind, scheduling it again moves it. The wheel is a ring of slots, one per
+//! tick, a timer sitting in the slot of its deadline modulo the ring: expiring the timers only looks at
+//! the slots of the ticks gone by, and timers further away than a turn of the ring are left there until
+//! their own turn comes. Cancelled timers are only forgotten, their slot entries are dropped as the wheel
+//! goes by them.
+
+use crate::tcp::ConnectionID;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// The granularity of the wheel, a timer fires at most a tick after its deadline when polled that often
+pub const TICK: Duration = Duration::from_millis(10);
+/// The slots of the ring, 2.56s of timers per turn
+pub const SLOTS: usize = 256;
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Hash)]
+pub enum TimerKind {
+    Retransmission,
+    /// The pacing, the rate limit or the reordering window of RACK, see `timer_deadline`
+    Transmit,
+    DelayedAck,
+    Persist,
+    KeepAlive,
+    /// The end of the linger time or of TIME-WAIT, see `close`
+    TimeWait,
+}
+
+pub type TimerKey = (ConnectionID, TimerKind);
+
+#[derive(Debug)]
+pub struct TimerWheel {
+    tick: Duration,
+    /// The origin of the ticks
+    start: Instant,
+    /// The tick up to which the timers were expired
+    current: u64,
+    slots: Vec<Vec<(TimerKey, Instant)>>,
+    /// The deadline of every timer scheduled, the slot entries not matching it are stale
+    deadlines: HashMap<TimerKey, Instant>,
+}
+
+impl TimerWheel {
+    pub fn new(tick: Duration, slots: usize, now: Instant) -> Self {
+        Self {
+            tick,
+            start: now,
+            current: 0,
+            slots: vec![Vec::new(); slots.max(1)],
+            deadlines: HashMap::new(),
+        }
+    }
+
+    /// Schedules the timer `kind` of `id` at `at`, instead of when it was scheduled before.
+    pub fn schedule(&mut self, id: ConnectionID, kind: TimerKind, at: Instant) {
+        let key = (id, kind);
+        if self.deadlines.get(&key) == Some(&at) {
+            return;
+        }
+        let slot = self.slot(self.tick_of(at).max(self.current));
+        self.slots[slot].push((key.clone(), at));
+        self.deadlines.insert(key, at);
+    }
+
+    /// Cancels the timer `kind` of `id`, returns whether it was scheduled.
+    pub fn cancel(&mut self, id: &ConnectionID, kind: TimerKind) -> bool {
+        self.deadlines.remove(&(id.clone(), kind)).is_some()
+    }
+
+    /// Cancels the timers of `id`, once the connection is gone.
+    pub fn cancel_all(&mut self, id: &ConnectionID) {
+        self.deadlines.retain(|(timer_id, _), _| timer_id != id);
+    }
+
+    /// When the timer `kind` of `id` is due, none if it isn't scheduled.
+    pub fn deadline(&self, id: &ConnectionID, kind: TimerKind) -> Option<Instant> {
+        self.deadlines.get(&(id.clone(), kind)).copied()
+    }
+
+    /// The earliest deadline of the timers scheduled.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.values().min().copied()
+    }
+
+    /// Removes and returns the timers due at `now`, the earliest first.
+    pub fn expire(&mut self, now: Instant) -> Vec<TimerKey> {
+        let target = self.tick_of(now);
+        // a turn of the ring goes by every slot
+        let ticks = (target - self.current.min(target) + 1).min(self.slots.len() as u64);
+        let mut fired = Vec::new();
+        for tick in self.current..self.current + ticks {
+            let slot = self.slot(tick);
+            let deadlines = &mut self.deadlines;
+            self.slots[slot].retain(|(key, at)| {
+                if deadlines.get(key) != Some(at) {
+                    return false;
+                }
+                if *at > now {
+                    return true;
+                }
+                deadlines.remove(key);
+                fired.push((key.clone(), *at));
+                false
+            });
+        }
+        self.current = self.current.max(target);
+        fired.sort_by_key(|(_, at)| *at);
+        fired.into_iter().map(|(key, _)| key).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    fn tick_of(&self, at: Instant) -> u64 {
+        (at.saturating_duration_since(self.start).as_nanos() / self.tick.as_nanos().max(1)) as u64
+    }
+
+    fn slot(&self, tick: u64) -> usize {
+        (tick % self.slots.len() as u64) as usize
+    }
+}
+
+impl Default for TimerWheel {
+    fn default() -> Self {
+        Self::new(TICK, SLOTS, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::timer::{TimerKind, TimerWheel};
+    use crate::tcp::ConnectionID;
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    fn id(port: u16) -> ConnectionID {
+        ConnectionID {
+            src_addr: Ipv4Addr::new(10, 0, 0, 1),
+            src_port: port,
+            dst_addr: Ipv4Addr::new(10, 0, 0, 2),
+            dst_port: 80,
+        }
+    }
+
+    #[test]
+    fn test_timer_wheel() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut wheel = TimerWheel::new(ms(10), 8, now);
+        wheel.schedule(id(1), TimerKind::Retransmission, now + ms(200));
+        wheel.schedule(id(1), TimerKind::DelayedAck, now + ms(40));
+        wheel.schedule(id(2), TimerKind::Retransmission, now + ms(25));
+        assert_eq!(wheel.next_deadline(), Some(now + ms(25)));
+        assert!(wheel.expire(now + ms(20)).is_empty());
+
+        assert_eq!(
+            wheel.expire(now + ms(45)),
+            vec![
+                (id(2), TimerKind::Retransmission),
+                (id(1), TimerKind::DelayedAck)
+            ]
+        );
+        assert_eq!(wheel.len(), 1);
+
+        // moved, the old deadline doesn't fire
+        wheel.schedule(id(1), TimerKind::Retransmission, now + ms(60));
+        assert_eq!(
+            wheel.expire(now + ms(60)),
+            vec![(id(1), TimerKind::Retransmission)]
+        );
+        assert!(wheel.expire(now + ms(300)).is_empty());
+        assert!(wheel.is_empty());
+    }
+
+    #[test]
+    fn test_cancel() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut wheel = TimerWheel::new(ms(10), 8, now);
+        wheel.schedule(id(1), TimerKind::KeepAlive, now + ms(30));
+        wheel.schedule(id(1), TimerKind::Persist, now + ms(30));
+        wheel.schedule(id(2), TimerKind::TimeWait, now + ms(1000));
+        assert!(wheel.cancel(&id(1), TimerKind::KeepAlive));
+        assert!(!wheel.cancel(&id(1), TimerKind::KeepAlive));
+        assert_eq!(
+            wheel.deadline(&id(1), TimerKind::Persist),
+            Some(now + ms(30))
+        );
+        wheel.cancel_all(&id(1));
+        assert!(wheel.expire(now + ms(100)).is_empty());
+
+        // several turns of the ring away, it waits for its own
+        assert!(wheel.expire(now + ms(990)).is_empty());
+        assert_eq!(
+            wheel.expire(now + ms(1000)),
+            vec![(id(2), TimerKind::TimeWait)]
+        );
+    }
+}
@@ -0,0 +1,154 @@
+//! The Timestamps option of https://www.ietf.org/rfc/rfc7323.txt, in effect when both sides sent it
+//! in their SYN. Every segment then carries our clock in TSval and echoes the latest clock of the peer
+//! in TSecr, so any ACK gives an RTT sample, retransmissions included, and PAWS (Protection Against
+//! Wrapped Sequences) tells an old duplicate from new data once the sequence numbers wrapped around.
+//!
+//! The TSecr of the first ACK after a retransmission also tells whether the ACK is for the
+//! retransmission or for the original transmission, in which case the retransmission was spurious, the
+//! Eifel detection of https://www.ietf.org/rfc/rfc3522.txt.
+
+use crate::tcp::wrapping_lt;
+use std::time::{Duration, Instant};
+
+/// A TS.Recent older than this is too old to compare with, the clock of the peer might have wrapped
+/// since, RFC 7323 5.5
+pub const PAWS_IDLE: Duration = Duration::from_secs(24 * 24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct Timestamps {
+    /// Our clock starts with the connection and ticks every millisecond
+    epoch: Instant,
+    /// TS.Recent, the TSval to echo
+    recent: u32,
+    /// When TS.Recent was last updated
+    recent_at: Instant,
+    /// Last.ACK.sent, the ACK field of the last segment sent
+    last_ack_sent: u32,
+    /// The TSval of the first retransmission not acknowledged yet, RetransmitTS of RFC 3522
+    retransmit_ts: Option<u32>,
+}
+
+impl Timestamps {
+    /// Starts echoing the TSval of the SYN, ACKed by `rcv_nxt`.
+    pub fn new(tsval: u32, rcv_nxt: u32, now: Instant) -> Self {
+        Self {
+            epoch: now,
+            recent: tsval,
+            recent_at: now,
+            last_ack_sent: rcv_nxt,
+            retransmit_ts: None,
+        }
+    }
+
+    /// Starts echoing the TSval of the SYN-ACK answering our SYN, ACKed by `rcv_nxt`. Our clock goes on
+    /// from the SYN, sent with TSecr 0 before the peer's clock was known.
+    pub fn on_syn_ack(&mut self, tsval: u32, rcv_nxt: u32, now: Instant) {
+        self.recent = tsval;
+        self.recent_at = now;
+        self.last_ack_sent = rcv_nxt;
+    }
+
+    /// The TSval and TSecr of the next segment sent.
+    pub fn option(&self, now: Instant) -> (u32, u32) {
+        (self.clock(now), self.recent)
+    }
+
+    /// TS.Recent, the latest TSval of the peer.
+    pub fn recent(&self) -> u32 {
+        self.recent
+    }
+
+    /// The PAWS test, RFC 7323 5.3 R1: whether a segment with `tsval` is an old duplicate, i.e. its
+    /// timestamp is behind TS.Recent. A TS.Recent left idle for too long doesn't count.
+    pub fn is_old(&self, tsval: u32, now: Instant) -> bool {
+        wrapping_lt(tsval, self.recent) && now.duration_since(self.recent_at) < PAWS_IDLE
+    }
+
+    /// Records the TSval of a segment accepted, when it starts at or before Last.ACK.sent, so the
+    /// TSval of the segment the peer sent first is echoed when ACKs are delayed. RFC 7323 4.3.
+    pub fn on_segment(&mut self, seq: u32, tsval: u32, now: Instant) {
+        if !wrapping_lt(tsval, self.recent) && !wrapping_lt(self.last_ack_sent, seq) {
+            self.recent = tsval;
+            self.recent_at = now;
+        }
+    }
+
+    /// Records RCV.NXT of the segment just sent as Last.ACK.sent.
+    pub fn on_send(&mut self, rcv_nxt: u32) {
+        self.last_ack_sent = rcv_nxt;
+    }
+
+    /// Records the retransmission of the oldest segment not acknowledged at `now`, before its TSval is
+    /// taken. Only the first one counts until an ACK tells which transmission arrived, RFC 3522 3.2.
+    pub fn on_retransmit(&mut self, now: Instant) {
+        self.retransmit_ts.get_or_insert(self.clock(now));
+    }
+
+    /// Whether an ACK of new data echoing `tsecr` shows the retransmission was spurious, the TSval it
+    /// echoes being older than the retransmission. Only the first ACK after a retransmission tells.
+    pub fn is_spurious(&mut self, tsecr: u32) -> bool {
+        self.retransmit_ts
+            .take()
+            .is_some_and(|ts| wrapping_lt(tsecr, ts))
+    }
+
+    /// The RTT measured from the TSecr of an ACK, which echoes our clock when the segment acked was
+    /// sent. Unlike RTT samples from the retransmission queue, retransmitted segments are measured too.
+    pub fn rtt(&self, tsecr: u32, now: Instant) -> Option<Duration> {
+        let elapsed = self.clock(now).wrapping_sub(tsecr);
+        // an echo from the future is bogus
+        (elapsed as i32 >= 0).then(|| Duration::from_millis(elapsed as u64))
+    }
+
+    fn clock(&self, now: Instant) -> u32 {
+        now.duration_since(self.epoch).as_millis() as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::timestamps::{Timestamps, PAWS_IDLE};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_timestamps() {
+        let now = Instant::now();
+        let mut ts = Timestamps::new(u32::MAX - 10, 100, now);
+        let later = now + Duration::from_millis(30);
+        assert_eq!(ts.option(later), (30, u32::MAX - 10));
+        assert_eq!(ts.rtt(20, later), Some(Duration::from_millis(10)));
+        assert_eq!(ts.rtt(40, later), None);
+
+        // the peer clock wrapped around, still newer
+        assert!(!ts.is_old(5, later));
+        ts.on_segment(100, 5, later);
+        assert_eq!(ts.option(later).1, 5);
+        assert!(ts.is_old(u32::MAX - 10, later));
+        assert!(!ts.is_old(u32::MAX - 10, later + PAWS_IDLE));
+
+        // a segment beyond Last.ACK.sent doesn't update TS.Recent
+        ts.on_segment(200, 8, later);
+        assert_eq!(ts.option(later).1, 5);
+        ts.on_send(200);
+        ts.on_segment(200, 8, later);
+        assert_eq!(ts.option(later).1, 8);
+    }
+
+    #[test]
+    fn test_eifel() {
+        let now = Instant::now();
+        let mut ts = Timestamps::new(0, 100, now);
+        assert!(!ts.is_spurious(0));
+
+        // the ACK echoes the original transmission, 40ms before the retransmission
+        let later = now + Duration::from_millis(50);
+        ts.on_retransmit(later);
+        ts.on_retransmit(later + Duration::from_millis(10));
+        assert!(ts.is_spurious(10));
+        assert!(!ts.is_spurious(10));
+
+        // the ACK echoes the retransmission
+        ts.on_retransmit(later);
+        assert!(!ts.is_spurious(50));
+    }
+}
@@ -0,0 +1,89 @@
+//! What is left of a connection in TIME-WAIT, and whether a new SYN for the same 4-tuple may reopen it
+//! before the 2 MSL are over, see https://www.ietf.org/rfc/rfc6191.txt. Timestamps tell a SYN of a new
+//! incarnation from an old duplicate of the previous one, so a client reconnecting right away from the
+//! same port doesn't have to wait. Without timestamps, the sequence number of the SYN has to be beyond
+//! what the previous incarnation received, as in https://www.ietf.org/rfc/rfc1122.txt 4.2.2.13.
+//!
+//! The connections closed first stay in TIME-WAIT for the 2 MSL, see `close`. A SYN for their 4-tuple
+//! is checked against what `Connection::time_wait` leaves of them: one accepted replaces the connection
+//! with a new handshake, the others are dropped.
+
+use crate::tcp::wrapping_lt;
+use std::time::{Duration, Instant};
+
+/// The Maximum Segment Lifetime, https://www.ietf.org/rfc/rfc793.txt page 28, the default of
+/// `Tunables::msl`
+pub const MSL: Duration = Duration::from_secs(2 * 60);
+
+#[derive(Debug, Clone)]
+pub struct TimeWait {
+    /// RCV.NXT of the previous incarnation, the sequence numbers it received are before it
+    rcv_nxt: u32,
+    /// TS.Recent of the previous incarnation, if it used timestamps
+    ts_recent: Option<u32>,
+    /// When TIME-WAIT was entered
+    since: Instant,
+    /// The Maximum Segment Lifetime of the connection, see `Tunables::msl`
+    msl: Duration,
+}
+
+impl TimeWait {
+    pub fn new(rcv_nxt: u32, ts_recent: Option<u32>, since: Instant, msl: Duration) -> Self {
+        Self {
+            rcv_nxt,
+            ts_recent,
+            since,
+            msl,
+        }
+    }
+
+    /// When the 2 MSL are over.
+    pub fn deadline(&self) -> Instant {
+        self.since + 2 * self.msl
+    }
+
+    /// Whether the 2 MSL are over, the 4-tuple is free again.
+    pub fn is_expired(&self, now: Instant) -> bool {
+        now >= self.deadline()
+    }
+
+    /// Whether a SYN with `seq` and the TSval `tsval`, if the new incarnation would use timestamps,
+    /// reopens the connection, RFC 6191 section 2. Otherwise the SYN is silently dropped.
+    pub fn accepts_syn(&self, seq: u32, tsval: Option<u32>) -> bool {
+        match (tsval, self.ts_recent) {
+            (Some(tsval), Some(recent)) => wrapping_lt(recent, tsval),
+            // nothing to compare the timestamp with, it protects the new incarnation anyway
+            (Some(_), None) => true,
+            (None, _) => !wrapping_lt(seq, self.rcv_nxt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::timewait::{TimeWait, MSL};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_accepts_syn() {
+        let now = Instant::now();
+        let tw = TimeWait::new(1000, Some(u32::MAX - 5), now, MSL);
+        // a newer timestamp, even wrapped around, whatever the sequence number
+        assert!(tw.accepts_syn(10, Some(3)));
+        assert!(!tw.accepts_syn(5000, Some(u32::MAX - 5)));
+        // without timestamps, the sequence number must be new
+        assert!(tw.accepts_syn(1000, None));
+        assert!(!tw.accepts_syn(999, None));
+
+        let tw = TimeWait::new(1000, None, now, MSL);
+        assert!(tw.accepts_syn(10, Some(0)));
+        assert!(!tw.accepts_syn(10, None));
+
+        assert!(!tw.is_expired(now + MSL));
+        assert!(tw.is_expired(now + 2 * MSL));
+        // the tunable rather than the default
+        let tw = TimeWait::new(1000, None, now, Duration::from_secs(1));
+        assert!(!tw.is_expired(now + Duration::from_millis(1999)));
+        assert!(tw.is_expired(now + Duration::from_secs(2)));
+    }
+}
@@ -0,0 +1,628 @@
+//! The stack on a dedicated packet thread, the application reading and writing the connections from its
+//! own threads. The packet thread owns the nic loop: it waits for packets or the next timer, and
+//! processes them with the stack locked. The application holds an `Interface`, sharing the stack behind
+//! the same mutex, and its `TcpStream`s block in a read or a write on a condvar notified every time a
+//! segment arrived. A write moving the next timer wakes the packet thread through an eventfd, so the
+//! paced segments aren't held back until the next packet. A `TcpListener` bound on a port accepts
+//! the connections established there as `TcpStream`s, as `std::net` does. The packets read while an
+//! application thread holds the stack wait in the input queue of their connection, bounded, see
+//! `tcp::queue`, the packet thread going on reading the nic.
+//!
+//! With the `mio` feature, a `TcpStream` is an `Evented` as well: registered with a `mio::Poll`, it's
+//! readable once there is data to read and writable once the send buffer has room, so it's polled
+//! along with regular sockets. Such a stream is set non-blocking.
+//!
+//! Built on mio 0.6, the one tokio 0.1 of `runtime` already depends on, rather than the
+//! `event::Source` of mio 0.7 and later: those dropped the readiness set from user space
+//! (`Registration::new2`), a stream having no fd of its own would take an eventfd each to be polled.
+
+use crate::ctl::{Command, Reply};
+use crate::device::{recv_buffer_size, NetworkDevice, Nic, RecvBatch, BATCH_SIZE};
+use crate::stack::{Readiness, Stack};
+use crate::tcp::info::TcpInfo;
+use crate::tcp::queue::{Backlog, INPUT_QUEUE_CAPACITY};
+use crate::tcp::sockopt::{OptionName, SocketOption};
+use crate::tcp::ConnectionID;
+use crate::wire::SegmentView;
+#[cfg(feature = "mio")]
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::SocketAddrV4;
+use std::os::unix::io::{AsRawFd, RawFd};
+#[cfg(feature = "mio")]
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, TryLockError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+struct Shared {
+    stack: Mutex<Stack>,
+    nic: Nic,
+    /// Notified every time a segment arrived, a blocked read or write may go on
+    arrived: Condvar,
+    /// The eventfd waking the packet thread
+    wake_fd: RawFd,
+    /// The readiness of the streams that may be registered with a `mio::Poll`, by stream
+    #[cfg(feature = "mio")]
+    evented: Mutex<HashMap<usize, (ConnectionID, mio::SetReadiness)>>,
+    #[cfg(feature = "mio")]
+    next_stream: AtomicUsize,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Stack> {
+        // a panic with the stack locked leaves it as it was, the connections carry on
+        self.stack.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The stack, unless an application thread holds it.
+    fn try_lock(&self) -> Option<MutexGuard<'_, Stack>> {
+        match self.stack.try_lock() {
+            Ok(stack) => Some(stack),
+            Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    fn wake_packet_thread(&self) {
+        let one = 1u64;
+        unsafe { libc::write(self.wake_fd, (&one as *const u64).cast(), 8) };
+    }
+
+    /// Runs `io` with the stack locked until it doesn't fail with `WouldBlock`, waiting for a segment
+    /// to arrive in between, unless `nonblocking` is set.
+    fn blocking<T>(
+        &self,
+        nonblocking: bool,
+        io: impl FnMut(&mut Stack, &Nic) -> io::Result<T>,
+    ) -> io::Result<T> {
+        self.blocking_until(nonblocking, |_| None, io)
+    }
+
+    /// Like `blocking`, failing with `TimedOut` once the wait goes past the `deadline` the stack gives
+    /// after `io` would block.
+    fn blocking_until<T>(
+        &self,
+        nonblocking: bool,
+        deadline: impl Fn(&Stack) -> Option<Instant>,
+        mut io: impl FnMut(&mut Stack, &Nic) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut stack = self.lock();
+        loop {
+            let result = match io(&mut stack, &self.nic) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock && !nonblocking => {
+                    let Some(at) = deadline(&stack) else {
+                        stack = self.arrived.wait(stack).unwrap_or_else(|e| e.into_inner());
+                        continue;
+                    };
+                    let now = Instant::now();
+                    if now < at {
+                        let (locked, _) = self
+                            .arrived
+                            .wait_timeout(stack, at - now)
+                            .unwrap_or_else(|e| e.into_inner());
+                        stack = locked;
+                        continue;
+                    }
+                    Err(ErrorKind::TimedOut.into())
+                }
+                result => result,
+            };
+            self.on_changed(&stack);
+            self.wake_packet_thread();
+            return result;
+        }
+    }
+
+    /// After the connections may have changed, with the stack locked: the registered streams get their
+    /// new readiness.
+    fn on_changed(&self, _stack: &Stack) {
+        #[cfg(feature = "mio")]
+        for (id, readiness) in self
+            .evented
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+        {
+            let _ = readiness.set_readiness(ready(_stack.readiness(id)));
+        }
+    }
+}
+
+#[cfg(feature = "mio")]
+fn ready(readiness: Readiness) -> mio::Ready {
+    let mut ready = mio::Ready::empty();
+    if readiness.readable {
+        ready |= mio::Ready::readable();
+    }
+    if readiness.writable {
+        ready |= mio::Ready::writable();
+    }
+    ready
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.wake_fd) };
+    }
+}
+
+/// Starts the packet thread running `stack` on `nic`, and returns the interface to it. The thread runs
+/// until the nic fails.
+pub fn spawn(
+    nic: impl Into<Nic>,
+    stack: Stack,
+) -> io::Result<(Interface, JoinHandle<io::Result<()>>)> {
+    let wake_fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if wake_fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let nic: Nic = nic.into();
+    nic.set_nonblocking(true)?;
+    let shared = Arc::new(Shared {
+        stack: Mutex::new(stack),
+        nic,
+        arrived: Condvar::new(),
+        wake_fd,
+        #[cfg(feature = "mio")]
+        evented: Mutex::new(HashMap::new()),
+        #[cfg(feature = "mio")]
+        next_stream: AtomicUsize::new(0),
+    });
+    let packet_thread = {
+        let shared = shared.clone();
+        thread::Builder::new()
+            .name("mini-tcp-packets".to_string())
+            .spawn(move || run(&shared))?
+    };
+    Ok((Interface { shared }, packet_thread))
+}
+
+/// The loop of the packet thread
+fn run(shared: &Shared) -> io::Result<()> {
+    let nic = &shared.nic;
+    let mut batch = RecvBatch::new(BATCH_SIZE, recv_buffer_size(nic));
+    // the packets read while an application thread held the stack
+    let mut backlog = Backlog::new(INPUT_QUEUE_CAPACITY);
+    loop {
+        let now = Instant::now();
+        let timeout = match shared.try_lock() {
+            Some(mut stack) => {
+                drain(&mut stack, nic, &mut backlog);
+                stack.on_timers(nic, now);
+                shared.on_changed(&stack);
+                let deadline = stack.next_deadline();
+                drop(stack);
+                // a timer may have dropped a connection, its readers and writers fail
+                shared.arrived.notify_all();
+                deadline.map_or(-1, |at| {
+                    // rounded up, the timers aren't due before
+                    let wait = at.saturating_duration_since(now);
+                    (wait.as_micros().div_ceil(1000)).min(i32::MAX as u128) as libc::c_int
+                })
+            }
+            // the timers and the backlog wait for the application thread, a millisecond at most
+            None => 1,
+        };
+
+        let mut fds = [
+            libc::pollfd {
+                fd: nic.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: shared.wake_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if fds[1].revents & libc::POLLIN != 0 {
+            let mut count = 0u64;
+            unsafe { libc::read(shared.wake_fd, (&mut count as *mut u64).cast(), 8) };
+        }
+        if fds[0].revents & libc::POLLIN != 0 {
+            nic.recv_batch(&mut batch)?;
+            let Some(mut stack) = shared.try_lock() else {
+                for packet in batch.packets() {
+                    let id = SegmentView::parse(packet).ok().map(|seg| seg.id());
+                    backlog.push(id, packet.to_vec());
+                }
+                continue;
+            };
+            drain(&mut stack, nic, &mut backlog);
+            stack.on_batch(nic, &batch)?;
+            shared.on_changed(&stack);
+            drop(stack);
+            shared.arrived.notify_all();
+        }
+    }
+}
+
+/// Processes the packets of `backlog`, before those read since so a connection's keep their order.
+fn drain(stack: &mut Stack, nic: &Nic, backlog: &mut Backlog) {
+    if backlog.is_empty() {
+        return;
+    }
+    let (packets, shed) = backlog.drain();
+    stack.on_input_queues(&shed);
+    for packet in packets {
+        stack.on_packet(nic, &packet);
+    }
+}
+
+/// The application's side of a stack run by a packet thread, shared by any number of threads.
+#[derive(Debug, Clone)]
+pub struct Interface {
+    shared: Arc<Shared>,
+}
+
+impl Interface {
+    /// Runs `f` with the stack locked.
+    pub fn with_stack<R>(&self, f: impl FnOnce(&mut Stack) -> R) -> R {
+        let mut stack = self.shared.lock();
+        let r = f(&mut stack);
+        self.shared.on_changed(&stack);
+        drop(stack);
+        self.shared.wake_packet_thread();
+        r
+    }
+
+    /// A stream reading and writing the connection `id`, blocking until it can.
+    pub fn stream(&self, id: ConnectionID) -> TcpStream {
+        #[cfg(feature = "mio")]
+        let source = {
+            let key = self.shared.next_stream.fetch_add(1, Ordering::Relaxed);
+            let (registration, readiness) = mio::Registration::new2();
+            self.shared
+                .evented
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .insert(key, (id.clone(), readiness));
+            Source { key, registration }
+        };
+        TcpStream {
+            shared: self.shared.clone(),
+            id,
+            nonblocking: false,
+            #[cfg(feature = "mio")]
+            source,
+        }
+    }
+
+    /// Accepts the connections to `port` with the listener returned, until it's dropped.
+    pub fn bind(&self, port: u16) -> io::Result<TcpListener> {
+        TcpListener::bind(self, port)
+    }
+
+    /// Runs a command of the control plane.
+    pub fn command(&self, command: Command) -> Reply {
+        self.shared.lock().handle_command(command, &self.shared.nic)
+    }
+}
+
+/// A port of a stack run by a packet thread accepting connections, like `std::net::TcpListener`.
+#[derive(Debug)]
+pub struct TcpListener {
+    interface: Interface,
+    port: u16,
+    /// `accept` fails with `WouldBlock` instead of waiting for a connection
+    nonblocking: bool,
+}
+
+impl TcpListener {
+    /// Accepts the connections to `port` of the stack behind `interface`, until the listener is
+    /// dropped. Fails with `AddrInUse` when another listener has the port.
+    pub fn bind(interface: &Interface, port: u16) -> io::Result<Self> {
+        interface.shared.lock().bind(port)?;
+        Ok(Self {
+            interface: interface.clone(),
+            port,
+            nonblocking: false,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Waits for the next connection established on the port, returns it with the address of the peer.
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddrV4)> {
+        let port = self.port;
+        let id = self
+            .interface
+            .shared
+            .blocking(self.nonblocking, |stack, _| {
+                stack
+                    .accept(port)
+                    .ok_or_else(|| ErrorKind::WouldBlock.into())
+            })?;
+        let peer = SocketAddrV4::new(id.src_addr, id.src_port);
+        Ok((self.interface.stream(id), peer))
+    }
+
+    /// The connections accepted one after the other, forever.
+    pub fn incoming(&self) -> impl Iterator<Item = io::Result<TcpStream>> + '_ {
+        std::iter::repeat_with(|| self.accept().map(|(stream, _)| stream))
+    }
+
+    /// Bounds the connections established and not accepted yet, the SYNs beyond are handled according
+    /// to the `accept_overflow` tunable.
+    pub fn set_backlog(&self, backlog: u32) -> io::Result<()> {
+        self.interface.shared.lock().set_backlog(self.port, backlog)
+    }
+
+    /// Only accepts the connections once they received data, like `TCP_DEFER_ACCEPT`: a server reading
+    /// a request first isn't woken up by connections sending nothing.
+    pub fn set_defer_accept(&self, defer: bool) -> io::Result<()> {
+        self.interface
+            .shared
+            .lock()
+            .set_defer_accept(self.port, defer)
+    }
+
+    /// Makes `accept` fail with `WouldBlock` instead of waiting for a connection.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        self.interface.shared.lock().unbind(self.port);
+    }
+}
+
+/// A connection of a stack run by a packet thread. Reads block until data arrived, writes until there
+/// is room in the send buffer, both fail once the connection is gone.
+#[derive(Debug)]
+pub struct TcpStream {
+    shared: Arc<Shared>,
+    id: ConnectionID,
+    /// Reads and writes fail with `WouldBlock` instead of waiting
+    nonblocking: bool,
+    #[cfg(feature = "mio")]
+    source: Source,
+}
+
+/// The readiness of a stream as a `mio::Poll` sees it
+#[cfg(feature = "mio")]
+#[derive(Debug)]
+struct Source {
+    /// The stream in `Shared::evented`
+    key: usize,
+    registration: mio::Registration,
+}
+
+impl TcpStream {
+    pub fn id(&self) -> &ConnectionID {
+        &self.id
+    }
+
+    pub fn peer_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.id.src_addr, self.id.src_port)
+    }
+
+    pub fn local_addr(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.id.dst_addr, self.id.dst_port)
+    }
+
+    /// Makes reads and writes fail with `WouldBlock` instead of waiting for the connection.
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    /// Whether a read or a write would go on without blocking.
+    pub fn readiness(&self) -> Readiness {
+        self.shared.lock().readiness(&self.id)
+    }
+
+    /// Makes reads fail with `TimedOut` once nothing arrived for `timeout`, `None` waits forever, like
+    /// `std::net::TcpStream::set_read_timeout`.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.shared.lock().set_read_timeout(&self.id, timeout)
+    }
+
+    /// Makes writes fail with `TimedOut` once the send buffer stayed full for `timeout`, `None` waits
+    /// forever.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.shared.lock().set_write_timeout(&self.id, timeout)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        self.shared.lock().read_timeout(&self.id)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        self.shared.lock().write_timeout(&self.id)
+    }
+
+    /// Sets a socket option of the connection, like `setsockopt(2)`.
+    pub fn set_option(&self, option: SocketOption) -> io::Result<()> {
+        self.shared
+            .blocking(true, |stack, nic| stack.set_option(nic, &self.id, option))
+    }
+
+    /// The current value of the socket option `name`, like `getsockopt(2)`.
+    pub fn option(&self, name: OptionName) -> io::Result<SocketOption> {
+        self.shared.lock().option(&self.id, name)
+    }
+
+    /// The state and the measurements of the connection, like `TCP_INFO`.
+    pub fn info(&self) -> io::Result<TcpInfo> {
+        self.shared.lock().info(&self.id)
+    }
+
+    /// Closes the connection, like `close(2)`: the data written is sent and followed by a FIN, the
+    /// clones of the stream see it closed as well. With a `Linger` time, waits for the peer to
+    /// acknowledge them and fails with `TimedOut` once it's over, the connection is reset then. A
+    /// `Linger` of 0 resets it right away.
+    pub fn close(self) -> io::Result<()> {
+        let linger = match self.option(OptionName::Linger)? {
+            SocketOption::Linger(Some(linger)) if !linger.is_zero() => linger,
+            _ => {
+                return self
+                    .shared
+                    .blocking(true, |stack, nic| stack.close(nic, &self.id))
+            }
+        };
+        let deadline = Instant::now() + linger;
+        self.shared
+            .blocking(true, |stack, nic| stack.close(nic, &self.id))?;
+        self.shared.blocking(false, |stack, _| {
+            // the stack resets the connection once the linger time is over
+            if Instant::now() >= deadline {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            match stack.is_flushed(&self.id) {
+                true => Ok(()),
+                false => Err(ErrorKind::WouldBlock.into()),
+            }
+        })
+    }
+}
+
+impl Clone for TcpStream {
+    fn clone(&self) -> Self {
+        let mut stream = Interface {
+            shared: self.shared.clone(),
+        }
+        .stream(self.id.clone());
+        stream.nonblocking = self.nonblocking;
+        stream
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.shared.blocking_until(
+            self.nonblocking,
+            |stack| stack.read_deadline(&self.id),
+            |stack, nic| stack.try_read(nic, &self.id, buf),
+        )
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.shared.blocking_until(
+            self.nonblocking,
+            |stack| stack.write_deadline(&self.id),
+            |stack, nic| stack.try_write(nic, &self.id, data),
+        )
+    }
+
+    /// The data written is sent as soon as the windows allow, there is nothing to flush.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "mio")]
+impl mio::Evented for TcpStream {
+    fn register(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        poll.register(&self.source.registration, token, interest, opts)?;
+        // the readiness so far, the stream may be readable already
+        self.shared.on_changed(&self.shared.lock());
+        Ok(())
+    }
+
+    fn reregister(
+        &self,
+        poll: &mio::Poll,
+        token: mio::Token,
+        interest: mio::Ready,
+        opts: mio::PollOpt,
+    ) -> io::Result<()> {
+        poll.reregister(&self.source.registration, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+        poll.deregister(&self.source.registration)
+    }
+}
+
+#[cfg(feature = "mio")]
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        self.shared
+            .evented
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&self.source.key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::device::MemoryDevice;
+    use crate::stack::Stack;
+    use crate::threaded::spawn;
+    use crate::wire::SegmentView;
+    use crate::TCP_PROTOCOL;
+    use etherparse::{Ipv4Header, TcpHeader};
+    use std::io::{ErrorKind, Read};
+    use std::time::{Duration, Instant};
+
+    fn packet(mut tcp: TcpHeader) -> Vec<u8> {
+        let ip = Ipv4Header::new(
+            tcp.header_len(),
+            64,
+            TCP_PROTOCOL,
+            [192, 167, 1, 2],
+            [192, 167, 1, 1],
+        );
+        tcp.checksum = tcp.calc_checksum_ipv4(&ip, &[]).unwrap();
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        // opening a tun device takes CAP_NET_ADMIN, there is nothing to run on without it
+        let Ok(iface) = tun_tap::Iface::without_packet_info("mt-test%d", tun_tap::Mode::Tun) else {
+            return;
+        };
+        // established before the packet thread runs, the peer never sends anything after
+        let nic = MemoryDevice::new();
+        let mut stack = Stack::default();
+        stack.bind(80).unwrap();
+        let mut syn = TcpHeader::new(40000, 80, 100, 1000);
+        syn.syn = true;
+        stack.on_packet(&nic, &packet(syn));
+        let syn_ack = nic.take_sent().remove(0);
+        let iss = SegmentView::parse(&syn_ack).unwrap().tcp.sequence_number();
+        let mut ack = TcpHeader::new(40000, 80, 101, 1000);
+        ack.ack = true;
+        ack.acknowledgment_number = iss.wrapping_add(1);
+        stack.on_packet(&nic, &packet(ack));
+        let id = stack.accept(80).unwrap();
+
+        let (interface, _) = spawn(iface, stack).unwrap();
+        let mut stream = interface.stream(id);
+        assert!(stream.set_read_timeout(Some(Duration::ZERO)).is_err());
+        let timeout = Duration::from_millis(50);
+        stream.set_read_timeout(Some(timeout)).unwrap();
+        assert_eq!(stream.read_timeout().unwrap(), Some(timeout));
+        let start = Instant::now();
+        let err = stream.read(&mut [0; 8]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(start.elapsed() >= timeout);
+    }
+}
@@ -0,0 +1,365 @@
+//! An io_uring backend for the packet I/O, https://kernel.dk/io_uring.pdf. Instead of a poll and a read
+//! per packet, reads of the tun device are kept queued in the submission ring, a batch of them at once,
+//! along with a timeout for the next timer of the stack. A single `io_uring_enter` submits what was
+//! queued and waits for the first completion, and every packet that arrived meanwhile is reaped from the
+//! completion ring without another syscall.
+//!
+//! The rings are set up with the raw syscalls, the few structures of `linux/io_uring.h` they share with
+//! the kernel are laid out here. Only reads and timeouts go through the ring, the stack sends its
+//! segments straight to the device.
+
+use std::io;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_TIMEOUT: u8 = 11;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// A submission queue entry
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// A completion queue entry
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct KernelTimespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+/// A mapping of the rings shared with the kernel
+#[derive(Debug)]
+struct Mmap {
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+impl Mmap {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { addr, len })
+    }
+
+    /// The field at `offset` into the mapping
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.addr.add(offset as usize) as *mut T }
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr, self.len) };
+    }
+}
+
+/// A completion, the `user_data` of the operation and its result, a length or a negated errno
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct Completion {
+    pub user_data: u64,
+    pub res: i32,
+}
+
+#[derive(Debug)]
+pub struct Uring {
+    fd: RawFd,
+    sq: Mmap,
+    cq: Mmap,
+    sqes: Mmap,
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+    sq_entries: u32,
+    /// The SQEs queued since the last submission
+    queued: u32,
+    /// The delay of the timeout queued, read by the kernel when it's submitted
+    timeout: Box<KernelTimespec>,
+    /// The `io_uring_enter` calls so far
+    enters: u64,
+}
+
+// the mappings are only touched through `&mut self`
+unsafe impl Send for Uring {}
+
+impl Uring {
+    /// Sets up a ring of `entries` submissions, rounded up to a power of two by the kernel.
+    pub fn new(entries: u32) -> io::Result<Self> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                entries,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = fd as RawFd;
+        let close_on_error = |e: io::Error| {
+            unsafe { libc::close(fd) };
+            e
+        };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        let sq = Mmap::new(fd, sq_len, IORING_OFF_SQ_RING).map_err(close_on_error)?;
+        let cq = Mmap::new(fd, cq_len, IORING_OFF_CQ_RING).map_err(close_on_error)?;
+        let sqes = Mmap::new(fd, sqes_len, IORING_OFF_SQES).map_err(close_on_error)?;
+        Ok(Self {
+            fd,
+            sq,
+            cq,
+            sqes,
+            sq_off: params.sq_off,
+            cq_off: params.cq_off,
+            sq_entries: params.sq_entries,
+            queued: 0,
+            timeout: Box::default(),
+            enters: 0,
+        })
+    }
+
+    /// Queues a read of `fd` into `buf`, completed with `user_data`.
+    ///
+    /// # Safety
+    /// `buf` is written by the kernel until the read completes, it must outlive it.
+    pub unsafe fn read(&mut self, fd: RawFd, buf: &mut [u8], user_data: u64) -> io::Result<()> {
+        self.push(Sqe {
+            opcode: IORING_OP_READ,
+            fd,
+            addr: buf.as_mut_ptr() as u64,
+            len: buf.len() as u32,
+            user_data,
+            ..Default::default()
+        })
+    }
+
+    /// Queues a timeout of `after`, completed with `user_data` once it expires, or as soon as another
+    /// operation completes: waiting for it waits for the next completion, `after` at most.
+    pub fn timeout(&mut self, after: Duration, user_data: u64) -> io::Result<()> {
+        *self.timeout = KernelTimespec {
+            tv_sec: after.as_secs() as i64,
+            tv_nsec: after.subsec_nanos() as i64,
+        };
+        let addr = &*self.timeout as *const KernelTimespec as u64;
+        self.push(Sqe {
+            opcode: IORING_OP_TIMEOUT,
+            fd: -1,
+            // completed by the first other completion
+            off: 1,
+            addr,
+            len: 1,
+            user_data,
+            ..Default::default()
+        })
+    }
+
+    /// Submits what was queued and waits for `wait` completions.
+    pub fn submit_and_wait(&mut self, wait: u32) -> io::Result<()> {
+        let flags = if wait > 0 { IORING_ENTER_GETEVENTS } else { 0 };
+        self.enters += 1;
+        let n = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                self.fd,
+                self.queued,
+                wait,
+                flags,
+                ptr::null::<libc::sigset_t>(),
+                0usize,
+            )
+        };
+        if n < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+            return Ok(());
+        }
+        self.queued -= n as u32;
+        Ok(())
+    }
+
+    /// Reaps the completions posted so far.
+    pub fn completions(&mut self) -> Vec<Completion> {
+        let head_ptr = self.cq.at::<AtomicU32>(self.cq_off.head);
+        let tail_ptr = self.cq.at::<AtomicU32>(self.cq_off.tail);
+        let mask = unsafe { *self.cq.at::<u32>(self.cq_off.ring_mask) };
+        let cqes = self.cq.at::<Cqe>(self.cq_off.cqes);
+        let (head, tail) = unsafe {
+            (
+                (*head_ptr).load(Ordering::Relaxed),
+                (*tail_ptr).load(Ordering::Acquire),
+            )
+        };
+        let completions = (head..tail)
+            .map(|at| {
+                let cqe = unsafe { *cqes.add((at & mask) as usize) };
+                Completion {
+                    user_data: cqe.user_data,
+                    res: cqe.res,
+                }
+            })
+            .collect();
+        unsafe { (*head_ptr).store(tail, Ordering::Release) };
+        completions
+    }
+
+    /// The `io_uring_enter` calls so far
+    pub fn enters(&self) -> u64 {
+        self.enters
+    }
+
+    fn push(&mut self, sqe: Sqe) -> io::Result<()> {
+        let head = unsafe { (*self.sq.at::<AtomicU32>(self.sq_off.head)).load(Ordering::Acquire) };
+        let tail_ptr = self.sq.at::<AtomicU32>(self.sq_off.tail);
+        let tail = unsafe { (*tail_ptr).load(Ordering::Relaxed) };
+        if tail.wrapping_sub(head) == self.sq_entries {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "submission ring full",
+            ));
+        }
+        let mask = unsafe { *self.sq.at::<u32>(self.sq_off.ring_mask) };
+        let index = tail & mask;
+        unsafe {
+            *self.sqes.at::<Sqe>(0).add(index as usize) = sqe;
+            *self.sq.at::<u32>(self.sq_off.array).add(index as usize) = index;
+            (*tail_ptr).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.queued += 1;
+        Ok(())
+    }
+}
+
+impl Drop for Uring {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::uring::{Completion, Uring};
+    use std::time::Duration;
+
+    #[test]
+    fn test_read_and_timeout() {
+        let mut ring = match Uring::new(8) {
+            Ok(ring) => ring,
+            // not every kernel or sandbox allows io_uring
+            Err(_) => return,
+        };
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut buf = [0u8; 16];
+        unsafe { ring.read(fds[0], &mut buf, 1).unwrap() };
+        ring.timeout(Duration::from_secs(5), 2).unwrap();
+        assert_eq!(
+            unsafe { libc::write(fds[1], b"hello".as_ptr().cast(), 5) },
+            5
+        );
+
+        // the read completes, and the timeout with it
+        let mut completions = Vec::new();
+        while completions.len() < 2 {
+            ring.submit_and_wait(1).unwrap();
+            completions.extend(ring.completions());
+        }
+        assert!(completions.contains(&Completion {
+            user_data: 1,
+            res: 5
+        }));
+        assert!(completions.iter().any(|c| c.user_data == 2));
+        assert_eq!(&buf[..5], b"hello");
+        unsafe {
+            libc::close(fds[0]);
+            libc::close(fds[1]);
+        }
+    }
+}
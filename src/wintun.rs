@@ -0,0 +1,301 @@
+//! A wintun backend, https://www.wintun.net, to run the stack on Windows, which has no tun device of its
+//! own. A wintun adapter carries ip packets, as a tun device does, through a pair of rings shared with
+//! the driver: the packets are read and written in place, with no syscall unless the stack waits.
+//!
+//! wintun.dll isn't linked but loaded when an adapter is opened, from the directory of the program or
+//! System32, the places Windows searches for it; its API is in wintun.h of the wintun release. Opening
+//! or creating an adapter takes an elevated process. The address of the adapter is set as any other's:
+//!
+//! ```text
+//! netsh interface ip set address name=mini-tcp static 192.167.1.0 255.255.255.0
+//! ```
+
+use crate::device::NetworkDevice;
+use std::ffi::{c_char, c_void};
+use std::io::{self, ErrorKind};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/// The bytes of the rings shared with the driver, a power of two between 128 KiB and 64 MiB
+const RING_CAPACITY: u32 = 0x40_0000;
+
+/// The largest packet an adapter carries
+const MAX_PACKET_SIZE: usize = 0xFFFF;
+
+const LOAD_LIBRARY_SEARCH_APPLICATION_DIR: u32 = 0x200;
+const LOAD_LIBRARY_SEARCH_SYSTEM32: u32 = 0x800;
+const ERROR_NO_MORE_ITEMS: i32 = 259;
+const ERROR_BUFFER_OVERFLOW: i32 = 111;
+const WAIT_OBJECT_0: u32 = 0;
+const WAIT_TIMEOUT: u32 = 0x102;
+const INFINITE: u32 = 0xFFFF_FFFF;
+
+type Handle = *mut c_void;
+
+#[repr(C)]
+struct Guid {
+    data1: u32,
+    data2: u16,
+    data3: u16,
+    data4: [u8; 8],
+}
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn LoadLibraryExW(name: *const u16, file: Handle, flags: u32) -> Handle;
+    fn GetProcAddress(module: Handle, name: *const c_char) -> *mut c_void;
+    fn FreeLibrary(module: Handle) -> i32;
+    fn WaitForSingleObject(handle: Handle, millis: u32) -> u32;
+}
+
+type CreateAdapter = unsafe extern "system" fn(*const u16, *const u16, *const Guid) -> Handle;
+type OpenAdapter = unsafe extern "system" fn(*const u16) -> Handle;
+type CloseAdapter = unsafe extern "system" fn(Handle);
+type StartSession = unsafe extern "system" fn(Handle, u32) -> Handle;
+type EndSession = unsafe extern "system" fn(Handle);
+type GetReadWaitEvent = unsafe extern "system" fn(Handle) -> Handle;
+type ReceivePacket = unsafe extern "system" fn(Handle, *mut u32) -> *mut u8;
+type ReleaseReceivePacket = unsafe extern "system" fn(Handle, *const u8);
+type AllocateSendPacket = unsafe extern "system" fn(Handle, u32) -> *mut u8;
+type SendPacket = unsafe extern "system" fn(Handle, *const u8);
+
+/// The functions of wintun.dll the backend calls
+struct Api {
+    library: Handle,
+    create_adapter: CreateAdapter,
+    open_adapter: OpenAdapter,
+    close_adapter: CloseAdapter,
+    start_session: StartSession,
+    end_session: EndSession,
+    get_read_wait_event: GetReadWaitEvent,
+    receive_packet: ReceivePacket,
+    release_receive_packet: ReleaseReceivePacket,
+    allocate_send_packet: AllocateSendPacket,
+    send_packet: SendPacket,
+}
+
+impl Api {
+    fn load() -> io::Result<Self> {
+        let name = wide("wintun.dll");
+        let flags = LOAD_LIBRARY_SEARCH_APPLICATION_DIR | LOAD_LIBRARY_SEARCH_SYSTEM32;
+        let library = unsafe { LoadLibraryExW(name.as_ptr(), std::ptr::null_mut(), flags) };
+        if library.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let symbol = |name: &[u8]| {
+            let f = unsafe { GetProcAddress(library, name.as_ptr() as *const c_char) };
+            match f.is_null() {
+                true => Err(io::Error::last_os_error()),
+                false => Ok(f),
+            }
+        };
+        let api = (|| -> io::Result<Self> {
+            // the symbols are the functions of wintun.h, of the types above
+            unsafe {
+                Ok(Self {
+                    library,
+                    create_adapter: std::mem::transmute::<*mut c_void, CreateAdapter>(symbol(
+                        b"WintunCreateAdapter\0",
+                    )?),
+                    open_adapter: std::mem::transmute::<*mut c_void, OpenAdapter>(symbol(
+                        b"WintunOpenAdapter\0",
+                    )?),
+                    close_adapter: std::mem::transmute::<*mut c_void, CloseAdapter>(symbol(
+                        b"WintunCloseAdapter\0",
+                    )?),
+                    start_session: std::mem::transmute::<*mut c_void, StartSession>(symbol(
+                        b"WintunStartSession\0",
+                    )?),
+                    end_session: std::mem::transmute::<*mut c_void, EndSession>(symbol(
+                        b"WintunEndSession\0",
+                    )?),
+                    get_read_wait_event: std::mem::transmute::<*mut c_void, GetReadWaitEvent>(
+                        symbol(b"WintunGetReadWaitEvent\0")?,
+                    ),
+                    receive_packet: std::mem::transmute::<*mut c_void, ReceivePacket>(symbol(
+                        b"WintunReceivePacket\0",
+                    )?),
+                    release_receive_packet: std::mem::transmute::<*mut c_void, ReleaseReceivePacket>(
+                        symbol(b"WintunReleaseReceivePacket\0")?,
+                    ),
+                    allocate_send_packet: std::mem::transmute::<*mut c_void, AllocateSendPacket>(
+                        symbol(b"WintunAllocateSendPacket\0")?,
+                    ),
+                    send_packet: std::mem::transmute::<*mut c_void, SendPacket>(symbol(
+                        b"WintunSendPacket\0",
+                    )?),
+                })
+            }
+        })();
+        if api.is_err() {
+            unsafe { FreeLibrary(library) };
+        }
+        api
+    }
+}
+
+/// A wintun adapter and the session the stack reads and writes its packets through.
+pub struct WintunAdapter {
+    api: Api,
+    name: String,
+    adapter: Handle,
+    session: Handle,
+    /// Signaled by the driver once packets are received after the ring was read empty
+    read_event: Handle,
+    /// A packet received while waiting for one, read by the next `recv`
+    pending: Mutex<Option<Vec<u8>>>,
+}
+
+// the functions of a session are thread safe, https://git.zx2c4.com/wintun/about/
+unsafe impl Send for WintunAdapter {}
+unsafe impl Sync for WintunAdapter {}
+
+impl std::fmt::Debug for WintunAdapter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WintunAdapter")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl WintunAdapter {
+    /// Opens the adapter `name`, created when there is none, and starts a session on it.
+    pub fn open(name: &str) -> io::Result<Self> {
+        let api = Api::load()?;
+        let wide_name = wide(name);
+        let mut adapter = unsafe { (api.open_adapter)(wide_name.as_ptr()) };
+        if adapter.is_null() {
+            let tunnel_type = wide("mini-tcp");
+            adapter = unsafe {
+                (api.create_adapter)(wide_name.as_ptr(), tunnel_type.as_ptr(), std::ptr::null())
+            };
+        }
+        if adapter.is_null() {
+            let err = io::Error::last_os_error();
+            unsafe { FreeLibrary(api.library) };
+            return Err(err);
+        }
+        let session = unsafe { (api.start_session)(adapter, RING_CAPACITY) };
+        if session.is_null() {
+            let err = io::Error::last_os_error();
+            unsafe {
+                (api.close_adapter)(adapter);
+                FreeLibrary(api.library);
+            }
+            return Err(err);
+        }
+        let read_event = unsafe { (api.get_read_wait_event)(session) };
+        Ok(Self {
+            api,
+            name: name.to_string(),
+            adapter,
+            session,
+            read_event,
+            pending: Mutex::new(None),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn pending(&self) -> MutexGuard<'_, Option<Vec<u8>>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Reads the next packet of the receive ring into `buf`, truncated to its length. Fails with
+    /// `WouldBlock` when the ring is empty.
+    fn receive(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut len = 0u32;
+        let packet = unsafe { (self.api.receive_packet)(self.session, &mut len) };
+        if packet.is_null() {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(ERROR_NO_MORE_ITEMS) => Err(ErrorKind::WouldBlock.into()),
+                _ => Err(err),
+            };
+        }
+        let n = (len as usize).min(buf.len());
+        unsafe {
+            std::ptr::copy_nonoverlapping(packet, buf.as_mut_ptr(), n);
+            (self.api.release_receive_packet)(self.session, packet);
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for WintunAdapter {
+    fn drop(&mut self) {
+        unsafe {
+            (self.api.end_session)(self.session);
+            (self.api.close_adapter)(self.adapter);
+            FreeLibrary(self.api.library);
+        }
+    }
+}
+
+impl NetworkDevice for WintunAdapter {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(packet) = self.pending().take() {
+            let n = packet.len().min(buf.len());
+            buf[..n].copy_from_slice(&packet[..n]);
+            return Ok(n);
+        }
+        self.receive(buf)
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        if packet.len() > MAX_PACKET_SIZE {
+            return Err(ErrorKind::InvalidInput.into());
+        }
+        let buf = unsafe { (self.api.allocate_send_packet)(self.session, packet.len() as u32) };
+        if buf.is_null() {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(ERROR_BUFFER_OVERFLOW) {
+                // dropped as by a full queue, the connections retransmit it
+                log::debug!("packet dropped, send ring full");
+                return Ok(packet.len());
+            }
+            return Err(err);
+        }
+        unsafe {
+            std::ptr::copy_nonoverlapping(packet.as_ptr(), buf, packet.len());
+            (self.api.send_packet)(self.session, buf);
+        }
+        Ok(packet.len())
+    }
+
+    /// The driver only signals the read event for the packets received once the ring was read empty,
+    /// so a packet is taken first to tell whether one is waiting.
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut pending = self.pending();
+        if pending.is_some() {
+            return Ok(true);
+        }
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        match self.receive(&mut buf) {
+            Ok(n) => {
+                buf.truncate(n);
+                *pending = Some(buf);
+                return Ok(true);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        drop(pending);
+        // rounded up, the timers aren't due before
+        let millis = timeout.map_or(INFINITE, |wait| {
+            wait.as_micros().div_ceil(1000).min(INFINITE as u128 - 1) as u32
+        });
+        match unsafe { WaitForSingleObject(self.read_event, millis) } {
+            WAIT_OBJECT_0 => Ok(true),
+            WAIT_TIMEOUT => Ok(false),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+/// `s` as the nul terminated UTF-16 string of the wide Windows APIs
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
@@ -0,0 +1,377 @@
+//! Parsing of the packets read from the nic. A packet is parsed once into a `SegmentView` borrowing
+//! the raw buffer, which is then passed through the whole pipeline instead of re-slicing the buffer.
+
+use crate::core::checksum;
+use crate::tcp::error::TcpError;
+use crate::tcp::options::{self, TcpOption};
+use crate::tcp::ConnectionID;
+use crate::{ETH_HEADER_OFFSET, TCP_PROTOCOL};
+use etherparse::{Ipv4HeaderSlice, TcpHeaderSlice, TCP_MINIMUM_HEADER_SIZE};
+
+pub const OPT_EOL: u8 = 0;
+pub const OPT_NOP: u8 = 1;
+pub const OPT_MSS: u8 = 2;
+pub const OPT_WS: u8 = 3;
+pub const OPT_SOK: u8 = 4;
+pub const OPT_SACK: u8 = 5;
+pub const OPT_TS: u8 = 8;
+pub const OPT_MD5SIG: u8 = 19;
+pub const OPT_AO: u8 = 29;
+pub const OPT_FAST_OPEN: u8 = 34;
+
+/// A tcp segment in an ipv4 packet, borrowed from the buffer it was read into.
+#[derive(Clone)]
+pub struct SegmentView<'a> {
+    pub ip: Ipv4HeaderSlice<'a>,
+    pub tcp: TcpHeaderSlice<'a>,
+    /// The segment text, bounded by the ip total length so padding is excluded
+    pub payload: &'a [u8],
+}
+
+impl<'a> SegmentView<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, TcpError> {
+        let malformed = |e: etherparse::ReadError| TcpError::Malformed(e.to_string());
+        let ip = Ipv4HeaderSlice::from_slice(&data[ETH_HEADER_OFFSET..]).map_err(malformed)?;
+        if ip.protocol() != TCP_PROTOCOL {
+            return Err(TcpError::NotTcp);
+        }
+
+        let tcp_start = ETH_HEADER_OFFSET + ip.slice().len();
+        let tcp = TcpHeaderSlice::from_slice(&data[tcp_start..]).map_err(malformed)?;
+
+        let start = tcp_start + tcp.slice().len();
+        let end = (ETH_HEADER_OFFSET + ip.total_len() as usize).min(data.len());
+        let payload = if start < end { &data[start..end] } else { &[] };
+
+        Ok(Self { ip, tcp, payload })
+    }
+
+    /// Checks the checksum of the ip header and the one of the segment, over its pseudo header, see
+    /// https://www.ietf.org/rfc/rfc1122.txt 3.2.1.2 and 4.2.2.7: a packet failing either is dropped.
+    pub fn verify_checksums(&self) -> Result<(), TcpError> {
+        self.verify_ip_checksum()?;
+        let len = self.tcp.slice().len() + self.payload.len();
+        let pseudo = checksum::pseudo_header(
+            self.ip.source(),
+            self.ip.destination(),
+            TCP_PROTOCOL,
+            len as u16,
+        );
+        // the header is a multiple of 4 octets, the payload sums on from it
+        let sum = checksum::sum(self.tcp.slice(), pseudo);
+        if !checksum::is_valid(checksum::sum(self.payload, sum)) {
+            return Err(TcpError::BadChecksum("tcp"));
+        }
+        Ok(())
+    }
+
+    /// Checks the checksum of the ip header alone, the device having checked the segment's, see
+    /// `ChecksumOffload::rx`.
+    pub fn verify_ip_checksum(&self) -> Result<(), TcpError> {
+        match checksum::is_valid(checksum::sum(self.ip.slice(), 0)) {
+            true => Ok(()),
+            false => Err(TcpError::BadChecksum("ip header")),
+        }
+    }
+
+    /// The connection the segment belongs to, the source being the remote end.
+    pub fn id(&self) -> ConnectionID {
+        ConnectionID {
+            src_addr: self.ip.source_addr(),
+            src_port: self.tcp.source_port(),
+            dst_addr: self.ip.destination_addr(),
+            dst_port: self.tcp.destination_port(),
+        }
+    }
+
+    /// SEG.LEN, the number of octets occupied by the data in the segment (counting SYN and FIN), see
+    /// https://www.ietf.org/rfc/rfc793.txt page 24.
+    pub fn len(&self) -> u32 {
+        self.payload.len() as u32 + self.tcp.syn() as u32 + self.tcp.fin() as u32
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The typed options of the segment, see `tcp::options`.
+    pub fn tcp_options(&self) -> impl Iterator<Item = Result<TcpOption<'a>, OptionError>> {
+        options::iter(self.options_bytes())
+    }
+
+    /// The first well formed option `f` picks a value from.
+    fn find_option<T>(&self, f: impl FnMut(TcpOption<'a>) -> Option<T>) -> Option<T> {
+        self.tcp_options()
+            .filter_map(|option| option.ok())
+            .find_map(f)
+    }
+
+    /// The MSS option of the segment, the first well formed one if there are several.
+    pub fn mss(&self) -> Option<u16> {
+        self.find_option(|option| match option {
+            TcpOption::Mss(mss) => Some(mss),
+            _ => None,
+        })
+    }
+
+    /// The shift count of the window scale option, see https://www.ietf.org/rfc/rfc7323.txt 2.2.
+    pub fn window_scale(&self) -> Option<u8> {
+        self.find_option(|option| match option {
+            TcpOption::WindowScale(shift) => Some(shift),
+            _ => None,
+        })
+    }
+
+    /// Whether the segment carries the SACK-permitted option, see https://www.ietf.org/rfc/rfc2018.txt.
+    pub fn sack_permitted(&self) -> bool {
+        self.find_option(|option| (option == TcpOption::SackPermitted).then_some(()))
+            .is_some()
+    }
+
+    /// The blocks of the SACK option as (left edge, right edge), empty without the option.
+    pub fn sack_blocks(&self) -> Vec<(u32, u32)> {
+        self.find_option(|option| match option {
+            TcpOption::Sack(blocks) => Some(blocks),
+            _ => None,
+        })
+        .unwrap_or_default()
+    }
+
+    /// The cookie of the Fast Open option, empty when the option requests one, see
+    /// https://www.ietf.org/rfc/rfc7413.txt 2.
+    pub fn fast_open_cookie(&self) -> Option<&'a [u8]> {
+        self.find_option(|option| match option {
+            TcpOption::FastOpen(cookie) => Some(cookie),
+            _ => None,
+        })
+    }
+
+    /// The digest of the MD5 signature option, see https://www.ietf.org/rfc/rfc2385.txt.
+    pub fn md5_signature(&self) -> Option<&'a [u8]> {
+        self.find_option(|option| match option {
+            TcpOption::Md5(digest) => Some(digest),
+            _ => None,
+        })
+    }
+
+    /// KeyID, RNextKeyID and the MAC of the TCP-AO option, see https://www.ietf.org/rfc/rfc5925.txt 2.2.
+    pub fn authentication(&self) -> Option<(u8, u8, &'a [u8])> {
+        self.find_option(|option| match option {
+            TcpOption::Ao {
+                key_id,
+                rnext_key_id,
+                mac,
+            } => Some((key_id, rnext_key_id, mac)),
+            _ => None,
+        })
+    }
+
+    /// TSval and TSecr of the Timestamps option, see https://www.ietf.org/rfc/rfc7323.txt 3.
+    pub fn timestamps(&self) -> Option<(u32, u32)> {
+        self.find_option(|option| match option {
+            TcpOption::Timestamps { tsval, tsecr } => Some((tsval, tsecr)),
+            _ => None,
+        })
+    }
+
+    pub fn options(&self) -> Options<'a> {
+        Options::new(self.options_bytes())
+    }
+
+    fn options_bytes(&self) -> &'a [u8] {
+        // the header slice borrows the packet, unlike the options of the slice
+        &self.tcp.slice()[TCP_MINIMUM_HEADER_SIZE..]
+    }
+}
+
+/// A tcp option as it appears on the wire, `data` excludes the kind and length octets.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub struct RawOption<'a> {
+    pub kind: u8,
+    pub data: &'a [u8],
+}
+
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum OptionError {
+    /// The option of this kind claims more octets than left, or less than its own header
+    Truncated(u8),
+    /// Non zero octets after the end of option list
+    TrailingGarbage,
+    /// The option of this kind has a length the kind doesn't allow
+    BadLength(u8),
+}
+
+/// Iterates the tcp options in the order they appear, up to and including the end of option list.
+/// The iteration stops after the first error.
+pub struct Options<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    /// Garbage follows the end of option list, reported after it
+    garbage: bool,
+}
+
+impl<'a> Options<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self {
+            bytes,
+            pos: 0,
+            garbage: false,
+        }
+    }
+}
+
+impl<'a> Iterator for Options<'a> {
+    type Item = Result<RawOption<'a>, OptionError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.garbage {
+            self.garbage = false;
+            return Some(Err(OptionError::TrailingGarbage));
+        }
+        let bytes = self.bytes;
+        let kind = *bytes.get(self.pos)?;
+        match kind {
+            OPT_EOL => {
+                self.garbage = bytes[self.pos + 1..].iter().any(|b| *b != 0);
+                self.pos = bytes.len();
+                Some(Ok(RawOption { kind, data: &[] }))
+            }
+            OPT_NOP => {
+                self.pos += 1;
+                Some(Ok(RawOption { kind, data: &[] }))
+            }
+            _ => {
+                let len = bytes.get(self.pos + 1).map(|l| *l as usize).unwrap_or(0);
+                if len < 2 || self.pos + len > bytes.len() {
+                    self.pos = bytes.len();
+                    return Some(Err(OptionError::Truncated(kind)));
+                }
+                let data = &bytes[self.pos + 2..self.pos + len];
+                self.pos += len;
+                Some(Ok(RawOption { kind, data }))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tcp::error::TcpError;
+    use crate::wire::{OptionError, RawOption, SegmentView, OPT_EOL, OPT_MSS, OPT_NOP};
+    use crate::TCP_PROTOCOL;
+    use etherparse::{Ipv4Header, TcpHeader};
+
+    fn packet(options: &[u8], payload: &[u8]) -> Vec<u8> {
+        let mut tcp = TcpHeader::new(40000, 80, 1000, 1024);
+        tcp.syn = true;
+        tcp.set_options_raw(options).unwrap();
+        let ip = Ipv4Header::new(
+            tcp.header_len() + payload.len() as u16,
+            64,
+            TCP_PROTOCOL,
+            [192, 167, 1, 2],
+            [192, 167, 1, 1],
+        );
+        tcp.checksum = tcp.calc_checksum_ipv4(&ip, payload).unwrap();
+        let mut packet = vec![];
+        ip.write(&mut packet).unwrap();
+        tcp.write(&mut packet).unwrap();
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let packet = packet(&[OPT_NOP, OPT_NOP, OPT_NOP, OPT_EOL], b"odd");
+        assert!(SegmentView::parse(&packet)
+            .unwrap()
+            .verify_checksums()
+            .is_ok());
+
+        let mut corrupted = packet.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        let err = SegmentView::parse(&corrupted).unwrap().verify_checksums();
+        assert!(matches!(err, Err(TcpError::BadChecksum("tcp"))));
+
+        // the ttl
+        let mut corrupted = packet.clone();
+        corrupted[8] -= 1;
+        let err = SegmentView::parse(&corrupted).unwrap().verify_checksums();
+        assert!(matches!(err, Err(TcpError::BadChecksum("ip header"))));
+    }
+
+    #[test]
+    fn test_parse() {
+        // ethernet padding after the ip packet is not payload
+        let mut data = packet(&[], b"abc");
+        data.extend_from_slice(&[0; 6]);
+        let seg = SegmentView::parse(&data).unwrap();
+        assert_eq!(seg.payload, b"abc");
+        // SYN counts in SEG.LEN
+        assert_eq!(seg.len(), 4);
+        assert_eq!(seg.id().src_port, 40000);
+        assert_eq!(seg.id().dst_port, 80);
+
+        assert!(matches!(
+            SegmentView::parse(&data[..30]),
+            Err(TcpError::Malformed(_))
+        ));
+        // udp
+        data[9] = 17;
+        assert!(matches!(SegmentView::parse(&data), Err(TcpError::NotTcp)));
+    }
+
+    #[test]
+    fn test_options() {
+        let data = packet(&[1, 3, 3, 7], &[]);
+        assert_eq!(SegmentView::parse(&data).unwrap().window_scale(), Some(7));
+
+        let data = packet(&[4, 2, 1, 1, 5, 10, 0, 0, 0, 1, 0, 0, 0, 9, 1, 1], &[]);
+        let seg = SegmentView::parse(&data).unwrap();
+        assert!(seg.sack_permitted());
+        assert_eq!(seg.sack_blocks(), vec![(1, 9)]);
+        assert_eq!(seg.timestamps(), None);
+        assert_eq!(seg.fast_open_cookie(), None);
+
+        let data = packet(&[34, 2, 34, 4, 7, 9, 1, 1], &[]);
+        assert_eq!(
+            SegmentView::parse(&data).unwrap().fast_open_cookie(),
+            Some(&[][..])
+        );
+
+        let data = packet(&[1, 1, 8, 10, 0, 0, 1, 0, 0, 0, 0, 7], &[]);
+        assert_eq!(
+            SegmentView::parse(&data).unwrap().timestamps(),
+            Some((256, 7))
+        );
+
+        let data = packet(&[2, 4, 0x05, 0xb4, 1, 0, 7, 0], &[]);
+        let seg = SegmentView::parse(&data).unwrap();
+        assert_eq!(seg.mss(), Some(1460));
+        assert_eq!(seg.window_scale(), None);
+        let options = seg.options().collect::<Vec<_>>();
+        assert_eq!(
+            options,
+            vec![
+                Ok(RawOption {
+                    kind: OPT_MSS,
+                    data: &[0x05, 0xb4]
+                }),
+                Ok(RawOption {
+                    kind: OPT_NOP,
+                    data: &[]
+                }),
+                Ok(RawOption {
+                    kind: OPT_EOL,
+                    data: &[]
+                }),
+                Err(OptionError::TrailingGarbage),
+            ]
+        );
+
+        let data = packet(&[1, 3, 8, 0], &[]);
+        let seg = SegmentView::parse(&data).unwrap();
+        assert_eq!(seg.options().last(), Some(Err(OptionError::Truncated(3))));
+        assert_eq!(seg.mss(), None);
+    }
+}
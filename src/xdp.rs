@@ -0,0 +1,784 @@
+//! An AF_XDP backend, https://www.kernel.org/doc/html/latest/networking/af_xdp.html, for a queue of a
+//! physical NIC. The frames are received into and sent from a UMEM, a memory area registered with the
+//! driver, and passed back and forth through four rings mapped in userspace: the fill ring gives the
+//! driver the frames to receive into, the rx ring hands them over once received, the tx ring the frames
+//! to send and the completion ring gives them back once sent. Neither a copy into the kernel's socket
+//! buffers nor a syscall per packet: a batch of frames costs one wakeup of the driver, when it asks for
+//! one.
+//!
+//! An XDP program attached to the NIC redirects the frames of the stack to the socket, the ARP for its
+//! address and the ipv4 packets to it, and passes the others to the host. As for `uring`, the structures
+//! of `linux/if_xdp.h` and `linux/bpf.h` are laid out here and the program is assembled by hand, no
+//! libbpf needed. It takes `CAP_NET_ADMIN` and `CAP_BPF`, and a kernel attaching XDP programs with a
+//! BPF link, 5.9 onwards: the program is detached when the socket is dropped.
+//!
+//! The NIC spreads the flows over its queues, only those of the queue bound reach the stack: with more
+//! than one, the others are to be steered to it, `ethtool -N <nic> flow-type tcp4 dst-ip <addr> action
+//! <queue>`, or the NIC left with a single queue, `ethtool -L <nic> combined 1`.
+
+use crate::device::NetworkDevice;
+use crate::ethernet::{Ethernet, Input, MacAddr, ETH_HEADER_LEN};
+use crate::netlink::Applied;
+use crate::stack;
+use std::ffi::CString;
+use std::io::{self, ErrorKind};
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/// The size of a frame of the UMEM, the smallest the drivers take
+pub const FRAME_SIZE: usize = 2048;
+
+/// The frames of the UMEM, half of them receiving, half sending
+pub const FRAMES: usize = 4096;
+
+/// The entries of each ring
+const RING_SIZE: u32 = 2048;
+
+/// The largest MSS a frame holds
+pub const MAX_MSS: u16 = (FRAME_SIZE - ETH_HEADER_LEN - 40) as u16;
+
+const XDP_MMAP_OFFSETS: libc::c_int = 1;
+const XDP_RX_RING: libc::c_int = 2;
+const XDP_TX_RING: libc::c_int = 3;
+const XDP_UMEM_REG: libc::c_int = 4;
+const XDP_UMEM_FILL_RING: libc::c_int = 5;
+const XDP_UMEM_COMPLETION_RING: libc::c_int = 6;
+const XDP_PGOFF_RX_RING: libc::off_t = 0;
+const XDP_PGOFF_TX_RING: libc::off_t = 0x80000000;
+const XDP_UMEM_PGOFF_FILL_RING: libc::off_t = 0x100000000;
+const XDP_UMEM_PGOFF_COMPLETION_RING: libc::off_t = 0x180000000;
+const XDP_USE_NEED_WAKEUP: u16 = 1 << 3;
+const XDP_RING_NEED_WAKEUP: u32 = 1;
+
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_PROG_LOAD: libc::c_int = 5;
+const BPF_LINK_CREATE: libc::c_int = 28;
+const BPF_MAP_TYPE_XSKMAP: u32 = 17;
+const BPF_PROG_TYPE_XDP: u32 = 6;
+const BPF_XDP: u32 = 37;
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+const BPF_FUNC_REDIRECT_MAP: i32 = 51;
+const XDP_PASS: i32 = 2;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct UmemReg {
+    addr: u64,
+    len: u64,
+    chunk_size: u32,
+    headroom: u32,
+    flags: u32,
+    tx_metadata_len: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct RingOffset {
+    producer: u64,
+    consumer: u64,
+    desc: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct MmapOffsets {
+    rx: RingOffset,
+    tx: RingOffset,
+    fr: RingOffset,
+    cr: RingOffset,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct SockaddrXdp {
+    family: u16,
+    flags: u16,
+    ifindex: u32,
+    queue_id: u32,
+    shared_umem_fd: u32,
+}
+
+/// A frame in the rx and the tx rings, its offset in the UMEM and its length
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct Desc {
+    addr: u64,
+    len: u32,
+    options: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct MapUpdateAttr {
+    map_fd: u32,
+    pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+    prog_name: [u8; 16],
+    prog_ifindex: u32,
+    expected_attach_type: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct LinkCreateAttr {
+    prog_fd: u32,
+    target_ifindex: u32,
+    attach_type: u32,
+    flags: u32,
+}
+
+/// A BPF instruction
+#[repr(C)]
+#[derive(PartialEq, Eq, Debug, Default, Clone, Copy)]
+struct Insn {
+    code: u8,
+    /// The destination register in the low nibble, the source in the high one
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl Insn {
+    const fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        Self {
+            code,
+            regs: src << 4 | dst,
+            off,
+            imm,
+        }
+    }
+}
+
+/// The XDP program redirecting to the socket of the `rx_queue_index` in the XSKMAP `map_fd` the ARP
+/// targeting `addr` and the ipv4 packets to it, the other frames passed to the host:
+///
+/// ```text
+///  0: r2 = *(u32 *)(r1 + 0)          ; ctx->data
+///  1: r3 = *(u32 *)(r1 + 4)          ; ctx->data_end
+///  2: r4 = r2
+///  3: r4 += 42                       ; Ethernet and ARP for ipv4
+///  4: if r4 > r3 goto 17
+///  5: r4 = *(u16 *)(r2 + 12)         ; EtherType
+///  6: r5 = *(u32 *)(r2 + 30)         ; ipv4 destination
+///  7: if r4 == ETH_P_IP goto 10
+///  8: if r4 != ETH_P_ARP goto 17
+///  9: r5 = *(u32 *)(r2 + 38)         ; ARP target protocol address
+/// 10: if r5 != addr goto 17
+/// 11: r2 = *(u32 *)(r1 + 16)         ; ctx->rx_queue_index
+/// 12: r1 = map_fd ll
+/// 14: r3 = XDP_PASS                  ; when no socket is bound to the queue
+/// 15: call bpf_redirect_map
+/// 16: exit
+/// 17: r0 = XDP_PASS
+/// 18: exit
+/// ```
+fn program(addr: Ipv4Addr, map_fd: RawFd) -> Vec<Insn> {
+    const LDX_W: u8 = 0x61;
+    const LDX_H: u8 = 0x69;
+    const MOV_X: u8 = 0xbf;
+    const MOV_K: u8 = 0xb7;
+    const ADD_K: u8 = 0x07;
+    const JGT_X: u8 = 0x2d;
+    const JEQ_K: u8 = 0x15;
+    const JNE_K: u8 = 0x55;
+    // on the low 32 bits, the address not being sign extended
+    const JNE32_K: u8 = 0x56;
+    const LD_DW: u8 = 0x18;
+    const CALL: u8 = 0x85;
+    const EXIT: u8 = 0x95;
+    // the loads are in host byte order
+    let ipv4 = u16::from_ne_bytes([0x08, 0x00]) as i32;
+    let arp = u16::from_ne_bytes([0x08, 0x06]) as i32;
+    let addr = u32::from_ne_bytes(addr.octets()) as i32;
+    vec![
+        Insn::new(LDX_W, 2, 1, 0, 0),
+        Insn::new(LDX_W, 3, 1, 4, 0),
+        Insn::new(MOV_X, 4, 2, 0, 0),
+        Insn::new(ADD_K, 4, 0, 0, 42),
+        Insn::new(JGT_X, 4, 3, 12, 0),
+        Insn::new(LDX_H, 4, 2, 12, 0),
+        Insn::new(LDX_W, 5, 2, 30, 0),
+        Insn::new(JEQ_K, 4, 0, 2, ipv4),
+        Insn::new(JNE_K, 4, 0, 8, arp),
+        Insn::new(LDX_W, 5, 2, 38, 0),
+        Insn::new(JNE32_K, 5, 0, 6, addr),
+        Insn::new(LDX_W, 2, 1, 16, 0),
+        Insn::new(LD_DW, 1, BPF_PSEUDO_MAP_FD, 0, map_fd),
+        Insn::default(),
+        Insn::new(MOV_K, 3, 0, 0, XDP_PASS),
+        Insn::new(CALL, 0, 0, 0, BPF_FUNC_REDIRECT_MAP),
+        Insn::new(EXIT, 0, 0, 0, 0),
+        Insn::new(MOV_K, 0, 0, 0, XDP_PASS),
+        Insn::new(EXIT, 0, 0, 0, 0),
+    ]
+}
+
+/// Runs the `bpf` command `cmd` and returns the fd it made, if any.
+fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> io::Result<RawFd> {
+    let ret =
+        unsafe { libc::syscall(libc::SYS_bpf, cmd, attr as *mut T, std::mem::size_of::<T>()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as RawFd)
+}
+
+/// Loads the program, failing with the verifier's log.
+fn load(program: &[Insn]) -> io::Result<OwnedFd> {
+    let license = b"GPL\0";
+    let mut attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_XDP,
+        insn_cnt: program.len() as u32,
+        insns: program.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        ..Default::default()
+    };
+    attr.prog_name[..8].copy_from_slice(b"mini_tcp");
+    match bpf(BPF_PROG_LOAD, &mut attr) {
+        Ok(fd) => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+        Err(e) => {
+            // loaded again for the log of the verifier, only asked for on failure as it's long
+            let mut log = vec![0u8; 1 << 16];
+            attr.log_level = 1;
+            attr.log_size = log.len() as u32;
+            attr.log_buf = log.as_mut_ptr() as u64;
+            let _ = bpf(BPF_PROG_LOAD, &mut attr);
+            let end = log.iter().position(|c| *c == 0).unwrap_or(log.len());
+            let log = String::from_utf8_lossy(&log[..end]);
+            Err(io::Error::new(e.kind(), format!("{e:}: {log:}")))
+        }
+    }
+}
+
+fn setsockopt<T>(fd: &OwnedFd, name: libc::c_int, value: &T) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_XDP,
+            name,
+            (value as *const T).cast(),
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// A mapping of the UMEM or of a ring
+#[derive(Debug)]
+struct Mmap {
+    addr: *mut u8,
+    len: usize,
+}
+
+impl Mmap {
+    /// Maps `len` octets of `fd` at `offset`, or of anonymous memory without a fd.
+    fn new(fd: Option<&OwnedFd>, len: usize, offset: libc::off_t) -> io::Result<Self> {
+        let (fd, flags) = match fd {
+            Some(fd) => (fd.as_raw_fd(), libc::MAP_SHARED | libc::MAP_POPULATE),
+            None => (-1, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS),
+        };
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                fd,
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            addr: addr.cast(),
+            len,
+        })
+    }
+}
+
+impl Drop for Mmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.addr.cast(), self.len) };
+    }
+}
+
+/// A ring shared with the kernel, the producer and the consumer indexes running freely and the
+/// entries at their value masked. Either side is ours, the other the kernel's.
+#[derive(Debug)]
+struct Ring<T> {
+    producer: *const AtomicU32,
+    consumer: *const AtomicU32,
+    flags: *const AtomicU32,
+    entries: *mut T,
+    size: u32,
+}
+
+impl<T: Copy> Ring<T> {
+    /// The ring laid out at `offset` in the mapping at `base`.
+    ///
+    /// # Safety
+    /// The mapping holds the indexes and `size` entries at `offset`.
+    unsafe fn new(base: *mut u8, offset: &RingOffset, size: u32) -> Self {
+        Self {
+            producer: base.add(offset.producer as usize).cast(),
+            consumer: base.add(offset.consumer as usize).cast(),
+            flags: base.add(offset.flags as usize).cast(),
+            entries: base.add(offset.desc as usize).cast(),
+            size,
+        }
+    }
+
+    /// Adds an entry as the producer, fails when the ring is full.
+    fn produce(&mut self, entry: T) -> bool {
+        let (producer, consumer) = unsafe {
+            (
+                (*self.producer).load(Ordering::Relaxed),
+                (*self.consumer).load(Ordering::Acquire),
+            )
+        };
+        if producer.wrapping_sub(consumer) == self.size {
+            return false;
+        }
+        unsafe {
+            *self.entries.add((producer & (self.size - 1)) as usize) = entry;
+            (*self.producer).store(producer.wrapping_add(1), Ordering::Release);
+        }
+        true
+    }
+
+    /// Takes the next entry as the consumer.
+    fn consume(&mut self) -> Option<T> {
+        let (producer, consumer) = unsafe {
+            (
+                (*self.producer).load(Ordering::Acquire),
+                (*self.consumer).load(Ordering::Relaxed),
+            )
+        };
+        if producer == consumer {
+            return None;
+        }
+        unsafe {
+            let entry = *self.entries.add((consumer & (self.size - 1)) as usize);
+            (*self.consumer).store(consumer.wrapping_add(1), Ordering::Release);
+            Some(entry)
+        }
+    }
+
+    /// Whether the driver waits for a syscall to go on with the ring.
+    fn needs_wakeup(&self) -> bool {
+        unsafe { (*self.flags).load(Ordering::Acquire) & XDP_RING_NEED_WAKEUP != 0 }
+    }
+}
+
+/// The rings of the socket and the frames not lent to the driver
+#[derive(Debug)]
+struct Rings {
+    rx: Ring<Desc>,
+    tx: Ring<Desc>,
+    fill: Ring<u64>,
+    completion: Ring<u64>,
+    /// The frames to send from
+    free: Vec<u64>,
+}
+
+/// An AF_XDP socket on a queue of a NIC, carrying the Ethernet frames of the stack as `Nic` does on a
+/// tap device. It never blocks.
+#[derive(Debug)]
+pub struct XdpSocket {
+    // declared first, dropped first: the program is detached before the socket is closed
+    _link: OwnedFd,
+    _program: OwnedFd,
+    _map: OwnedFd,
+    fd: OwnedFd,
+    name: String,
+    ethernet: Ethernet,
+    umem: Mmap,
+    rings: Mutex<Rings>,
+    _ring_maps: [Mmap; 4],
+    /// The syscalls waking the driver so far
+    wakeups: AtomicU64,
+    applied: Option<Applied>,
+}
+
+// the rings and the frames of the UMEM are only touched with the lock held
+unsafe impl Send for XdpSocket {}
+unsafe impl Sync for XdpSocket {}
+
+impl XdpSocket {
+    /// Binds a socket to the queue `queue` of the NIC `interface`, the stack answering to `mac`, the
+    /// NIC's, and to `addr`, and attaches the program redirecting its frames.
+    pub fn open(interface: &str, queue: u32, mac: MacAddr, addr: Ipv4Addr) -> io::Result<Self> {
+        let name =
+            CString::new(interface).map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let umem = Mmap::new(None, FRAMES * FRAME_SIZE, 0)?;
+        let fd = unsafe { libc::socket(libc::AF_XDP, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let reg = UmemReg {
+            addr: umem.addr as u64,
+            len: umem.len as u64,
+            chunk_size: FRAME_SIZE as u32,
+            ..Default::default()
+        };
+        setsockopt(&fd, XDP_UMEM_REG, &reg)?;
+        for ring in [
+            XDP_UMEM_FILL_RING,
+            XDP_UMEM_COMPLETION_RING,
+            XDP_RX_RING,
+            XDP_TX_RING,
+        ] {
+            setsockopt(&fd, ring, &RING_SIZE)?;
+        }
+        let mut off = MmapOffsets::default();
+        let mut len = std::mem::size_of::<MmapOffsets>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_XDP,
+                XDP_MMAP_OFFSETS,
+                (&mut off as *mut MmapOffsets).cast(),
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let descs = |off: &RingOffset| off.desc as usize + RING_SIZE as usize * 16;
+        let addrs = |off: &RingOffset| off.desc as usize + RING_SIZE as usize * 8;
+        let rx_map = Mmap::new(Some(&fd), descs(&off.rx), XDP_PGOFF_RX_RING)?;
+        let tx_map = Mmap::new(Some(&fd), descs(&off.tx), XDP_PGOFF_TX_RING)?;
+        let fill_map = Mmap::new(Some(&fd), addrs(&off.fr), XDP_UMEM_PGOFF_FILL_RING)?;
+        let completion_map = Mmap::new(Some(&fd), addrs(&off.cr), XDP_UMEM_PGOFF_COMPLETION_RING)?;
+        let mut rings = unsafe {
+            Rings {
+                rx: Ring::new(rx_map.addr, &off.rx, RING_SIZE),
+                tx: Ring::new(tx_map.addr, &off.tx, RING_SIZE),
+                fill: Ring::new(fill_map.addr, &off.fr, RING_SIZE),
+                completion: Ring::new(completion_map.addr, &off.cr, RING_SIZE),
+                free: (RING_SIZE as u64..FRAMES as u64)
+                    .map(|i| i * FRAME_SIZE as u64)
+                    .collect(),
+            }
+        };
+        for i in 0..RING_SIZE as u64 {
+            rings.fill.produce(i * FRAME_SIZE as u64);
+        }
+
+        let sockaddr = SockaddrXdp {
+            family: libc::AF_XDP as u16,
+            flags: XDP_USE_NEED_WAKEUP,
+            ifindex,
+            queue_id: queue,
+            shared_umem_fd: 0,
+        };
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                (&sockaddr as *const SockaddrXdp).cast(),
+                std::mem::size_of::<SockaddrXdp>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut create = MapCreateAttr {
+            map_type: BPF_MAP_TYPE_XSKMAP,
+            key_size: 4,
+            value_size: 4,
+            max_entries: queue + 1,
+            map_flags: 0,
+        };
+        let map = unsafe { OwnedFd::from_raw_fd(bpf(BPF_MAP_CREATE, &mut create)?) };
+        let (key, value) = (queue, fd.as_raw_fd() as u32);
+        let mut update = MapUpdateAttr {
+            map_fd: map.as_raw_fd() as u32,
+            key: &key as *const u32 as u64,
+            value: &value as *const u32 as u64,
+            ..Default::default()
+        };
+        bpf(BPF_MAP_UPDATE_ELEM, &mut update)?;
+        let program = load(&program(addr, map.as_raw_fd()))?;
+        let mut link = LinkCreateAttr {
+            prog_fd: program.as_raw_fd() as u32,
+            target_ifindex: ifindex,
+            attach_type: BPF_XDP,
+            flags: 0,
+        };
+        let link = unsafe { OwnedFd::from_raw_fd(bpf(BPF_LINK_CREATE, &mut link)?) };
+
+        Ok(Self {
+            _link: link,
+            _program: program,
+            _map: map,
+            fd,
+            name: interface.to_string(),
+            ethernet: Ethernet::new(mac, Some(addr)),
+            umem,
+            rings: Mutex::new(rings),
+            _ring_maps: [rx_map, tx_map, fill_map, completion_map],
+            wakeups: AtomicU64::new(0),
+            applied: None,
+        })
+    }
+
+    /// Keeps the set up of the NIC until the socket is dropped, see `netlink::apply`.
+    pub fn hold(&mut self, applied: Applied) {
+        self.applied = Some(applied);
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn ethernet(&self) -> &Ethernet {
+        &self.ethernet
+    }
+
+    /// The syscalls waking the driver so far, the only ones the packets cost.
+    pub fn wakeups(&self) -> u64 {
+        self.wakeups.load(Ordering::Relaxed)
+    }
+
+    /// Announces the address of the stack with a gratuitous ARP.
+    pub fn announce(&self) -> io::Result<()> {
+        if let Some(frame) = self.ethernet.announcement() {
+            let mut rings = self.rings();
+            self.transmit(&mut rings, &frame)?;
+            self.wake_tx(&rings);
+        }
+        Ok(())
+    }
+
+    fn rings(&self) -> MutexGuard<'_, Rings> {
+        self.rings.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The frame at `addr` in the UMEM.
+    fn frame(&self, addr: u64, len: usize) -> &[u8] {
+        let addr = addr as usize;
+        assert!(addr + len <= self.umem.len, "frame out of the UMEM");
+        unsafe { std::slice::from_raw_parts(self.umem.addr.add(addr), len) }
+    }
+
+    /// Queues `frame` on the tx ring, in a frame taken back from the driver if none is free.
+    fn transmit(&self, rings: &mut Rings, frame: &[u8]) -> io::Result<()> {
+        if frame.len() > FRAME_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "frame too long"));
+        }
+        while let Some(addr) = rings.completion.consume() {
+            rings.free.push(addr);
+        }
+        let Some(addr) = rings.free.pop() else {
+            return Err(io::Error::new(
+                ErrorKind::WouldBlock,
+                "no frame to send from",
+            ));
+        };
+        // the frame is ours until the completion ring gives it back
+        unsafe {
+            ptr::copy_nonoverlapping(
+                frame.as_ptr(),
+                self.umem.addr.add(addr as usize),
+                frame.len(),
+            )
+        };
+        let desc = Desc {
+            addr,
+            len: frame.len() as u32,
+            options: 0,
+        };
+        if !rings.tx.produce(desc) {
+            rings.free.push(addr);
+            return Err(io::Error::new(ErrorKind::WouldBlock, "tx ring full"));
+        }
+        Ok(())
+    }
+
+    /// Wakes the driver to send the frames queued, when it waits for it.
+    fn wake_tx(&self, rings: &Rings) {
+        if rings.tx.needs_wakeup() {
+            self.wakeups.fetch_add(1, Ordering::Relaxed);
+            // EAGAIN and EBUSY only mean the driver is busy sending, it goes on
+            unsafe {
+                libc::sendto(
+                    self.fd.as_raw_fd(),
+                    ptr::null(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    ptr::null(),
+                    0,
+                )
+            };
+        }
+    }
+
+    /// Sends the packets, a wakeup of the driver for all of them.
+    fn send_frames<'a>(&self, packets: impl Iterator<Item = &'a [u8]>) -> io::Result<()> {
+        let mut rings = self.rings();
+        let mut result = Ok(());
+        for packet in packets {
+            // queued while the destination is resolved
+            if let Some(frame) = self.ethernet.output(packet) {
+                result = self.transmit(&mut rings, &frame);
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+        self.wake_tx(&rings);
+        result
+    }
+}
+
+impl AsRawFd for XdpSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+impl NetworkDevice for XdpSocket {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut rings = self.rings();
+        let Some(desc) = rings.rx.consume() else {
+            return Err(ErrorKind::WouldBlock.into());
+        };
+        let frame = self.frame(desc.addr, desc.len as usize);
+        let n = match self.ethernet.input(frame) {
+            Input::Packet(packet) => {
+                let n = packet.len().min(buf.len());
+                buf[..n].copy_from_slice(&packet[..n]);
+                n
+            }
+            Input::Send(frames) => {
+                for frame in &frames {
+                    self.transmit(&mut rings, frame)?;
+                }
+                self.wake_tx(&rings);
+                0
+            }
+            Input::Drop => 0,
+        };
+        // the frame receives again, its start being the one of its chunk
+        rings.fill.produce(desc.addr & !(FRAME_SIZE as u64 - 1));
+        if rings.fill.needs_wakeup() {
+            self.wakeups.fetch_add(1, Ordering::Relaxed);
+            unsafe {
+                libc::recvfrom(
+                    self.fd.as_raw_fd(),
+                    ptr::null_mut(),
+                    0,
+                    libc::MSG_DONTWAIT,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                )
+            };
+        }
+        Ok(n)
+    }
+
+    fn send(&self, packet: &[u8]) -> io::Result<usize> {
+        self.send_frames(std::iter::once(packet))?;
+        Ok(packet.len())
+    }
+
+    fn send_batch(&self, packets: &[Vec<u8>]) -> io::Result<()> {
+        self.send_frames(packets.iter().map(Vec::as_slice))
+    }
+
+    fn wait_readable(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        stack::wait_readable(self, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::xdp::{program, Ring, RingOffset, BPF_PSEUDO_MAP_FD};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_program() {
+        let program = program(Ipv4Addr::new(192, 167, 1, 1), 7);
+        assert_eq!(program.len(), 19);
+        // the jumps land on the instructions passing the frame
+        for (at, target) in [(4, 17), (7, 10), (8, 17), (10, 17)] {
+            assert_eq!(at + 1 + program[at].off as usize, target);
+        }
+        assert_eq!(program[12].regs, BPF_PSEUDO_MAP_FD << 4 | 1);
+        assert_eq!(program[12].imm, 7);
+        assert_eq!(program[10].imm.to_ne_bytes(), [192, 167, 1, 1]);
+        assert_eq!(program[17].imm, 2);
+    }
+
+    #[test]
+    fn test_ring() {
+        let mut memory = vec![0u64; 16];
+        let offset = RingOffset {
+            producer: 0,
+            consumer: 8,
+            flags: 16,
+            desc: 64,
+        };
+        let base = memory.as_mut_ptr().cast();
+        let (mut producer, mut consumer) = unsafe {
+            (
+                Ring::<u64>::new(base, &offset, 4),
+                Ring::<u64>::new(base, &offset, 4),
+            )
+        };
+        assert_eq!(consumer.consume(), None);
+        for i in 0..4 {
+            assert!(producer.produce(i));
+        }
+        assert!(!producer.produce(4));
+        assert_eq!(consumer.consume(), Some(0));
+        assert!(producer.produce(4));
+        let consumed: Vec<_> = std::iter::from_fn(|| consumer.consume()).collect();
+        assert_eq!(consumed, [1, 2, 3, 4]);
+        assert!(!producer.needs_wakeup());
+    }
+}